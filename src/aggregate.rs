@@ -0,0 +1,597 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use chrono::FixedOffset;
+use serde_json::Value as JsonValue;
+use sqlparser::ast::Expr;
+
+use crate::functions::{evaluate_projection, parse_projection_expr, ColumnType, ProjectionExpr};
+use crate::query::PatternValue;
+
+/// Aggregate functions supported on projections, ie: `SELECT $ip, COUNT(*) FROM mylog GROUP BY $ip`
+pub const AGGREGATE_FUNCTIONS: &[&str] = &["COUNT", "SUM", "AVG", "MIN", "MAX"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// A projection aggregating the lines of a group, `arg` is `None` for `COUNT(*)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateColumn {
+    pub alias: String,
+    pub function: AggregateFunction,
+    pub arg: Option<ProjectionExpr>,
+}
+
+impl AggregateColumn {
+    /// Type of the aggregated values, sums are integers as long as what they add up is
+    pub fn column_type<F>(&self, field_type: &F) -> ColumnType
+    where
+        F: Fn(&str) -> ColumnType,
+    {
+        let arg_type = self.arg.as_ref().map(|arg| arg.column_type(field_type));
+        match (self.function, arg_type) {
+            (AggregateFunction::Count, _) => ColumnType::Int,
+            (AggregateFunction::Avg, _) => ColumnType::Float,
+            (AggregateFunction::Sum, Some(ColumnType::Int)) => ColumnType::Int,
+            (AggregateFunction::Sum, Some(ColumnType::Float)) => ColumnType::Float,
+            (AggregateFunction::Sum, _) => ColumnType::Number,
+            (AggregateFunction::Min, arg_type) | (AggregateFunction::Max, arg_type) => {
+                arg_type.unwrap_or(ColumnType::String)
+            }
+        }
+    }
+}
+
+/// A `GROUP BY` expression, `alias` is the projection it is returned as, if any
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupColumn {
+    pub alias: Option<String>,
+    pub expr: ProjectionExpr,
+}
+
+/// How the matching lines of a query are grouped and aggregated instead of returned one by one
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Aggregation {
+    pub group_by: Vec<GroupColumn>,
+    pub aggregates: Vec<AggregateColumn>,
+}
+
+/// Whether an AST node is a call to an aggregate function
+pub fn is_aggregate_projection(ast: &Expr) -> bool {
+    match ast {
+        Expr::Function(function) => {
+            AGGREGATE_FUNCTIONS.contains(&function.name.to_string().to_uppercase().as_str())
+        }
+        _ => false,
+    }
+}
+
+/// Translates the AST of an aggregate function. The fields its argument reads are added to
+/// `fields` so they get extracted from the lines.
+pub fn parse_aggregate(
+    ast: &Expr,
+    fields: &mut Vec<Expr>,
+) -> Result<(AggregateFunction, Option<ProjectionExpr>), String> {
+    let function = match ast {
+        Expr::Function(function) => function,
+        x => return Err(format!("`{}` is not an aggregate function", x)),
+    };
+    let name = function.name.to_string().to_uppercase();
+    let aggregate = match name.as_str() {
+        "COUNT" => AggregateFunction::Count,
+        "SUM" => AggregateFunction::Sum,
+        "AVG" => AggregateFunction::Avg,
+        "MIN" => AggregateFunction::Min,
+        "MAX" => AggregateFunction::Max,
+        _ => return Err(format!("Unsupported function {}", name)),
+    };
+    if function.distinct {
+        return Err(format!("DISTINCT is not supported on {}", name));
+    }
+    match (aggregate, &function.args[..]) {
+        (AggregateFunction::Count, [Expr::Wildcard]) => Ok((aggregate, None)),
+        (_, [arg]) if is_aggregate_projection(arg) => {
+            Err(format!("Aggregate functions can't be nested on {}", name))
+        }
+        (_, [Expr::Wildcard]) => Err(format!("{} needs a field, ie: {}($4)", name, name)),
+        (_, [arg]) => Ok((aggregate, Some(parse_projection_expr(arg, fields)?))),
+        _ => Err(format!("Invalid number of arguments for {}", name)),
+    }
+}
+
+/// Running state of an aggregate over the lines of a group
+#[derive(Debug, Clone)]
+enum Accumulator {
+    Count(u64),
+    // integer sum as long as every value is an integer
+    Sum {
+        int: i64,
+        float: f64,
+        integers: bool,
+        values: u64,
+    },
+    Avg {
+        sum: f64,
+        values: u64,
+    },
+    Min(Option<JsonValue>),
+    Max(Option<JsonValue>),
+}
+
+impl Accumulator {
+    fn new(function: AggregateFunction) -> Accumulator {
+        match function {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Sum => Accumulator::Sum {
+                int: 0,
+                float: 0.0,
+                integers: true,
+                values: 0,
+            },
+            AggregateFunction::Avg => Accumulator::Avg {
+                sum: 0.0,
+                values: 0,
+            },
+            AggregateFunction::Min => Accumulator::Min(None),
+            AggregateFunction::Max => Accumulator::Max(None),
+        }
+    }
+
+    /// Accounts a value of a line, `None` for lines where it is NULL, which only `COUNT(*)` counts
+    fn add(&mut self, value: Option<JsonValue>, count_line: bool) {
+        match self {
+            Accumulator::Count(count) => {
+                if count_line || value.is_some() {
+                    *count += 1;
+                }
+            }
+            Accumulator::Sum {
+                int,
+                float,
+                integers,
+                values,
+            } => {
+                if let Some(value) = value.as_ref().and_then(number_of) {
+                    match value.as_i64() {
+                        Some(i) if *integers => match int.checked_add(i) {
+                            Some(sum) => *int = sum,
+                            None => {
+                                *integers = false;
+                                *float = *int as f64 + i as f64;
+                            }
+                        },
+                        _ => {
+                            if *integers {
+                                *integers = false;
+                                *float = *int as f64;
+                            }
+                            *float += value.as_f64().unwrap_or(0.0);
+                        }
+                    }
+                    *values += 1;
+                }
+            }
+            Accumulator::Avg { sum, values } => {
+                if let Some(value) = value.as_ref().and_then(number_of) {
+                    *sum += value.as_f64().unwrap_or(0.0);
+                    *values += 1;
+                }
+            }
+            Accumulator::Min(min) => {
+                if let Some(value) = value {
+                    let replace = match min {
+                        Some(current) => compare_values(&value, current) == Ordering::Less,
+                        None => true,
+                    };
+                    if replace {
+                        *min = Some(value);
+                    }
+                }
+            }
+            Accumulator::Max(max) => {
+                if let Some(value) = value {
+                    let replace = match max {
+                        Some(current) => compare_values(&value, current) == Ordering::Greater,
+                        None => true,
+                    };
+                    if replace {
+                        *max = Some(value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The aggregated value, NULL for sums, averages and extremes of groups without any value
+    fn value(&self) -> JsonValue {
+        match self {
+            Accumulator::Count(count) => JsonValue::from(*count),
+            Accumulator::Sum { values: 0, .. } | Accumulator::Avg { values: 0, .. } => {
+                JsonValue::Null
+            }
+            Accumulator::Sum {
+                int,
+                float,
+                integers,
+                ..
+            } => {
+                if *integers {
+                    JsonValue::from(*int)
+                } else {
+                    float_value(*float)
+                }
+            }
+            Accumulator::Avg { sum, values } => float_value(sum / *values as f64),
+            Accumulator::Min(value) | Accumulator::Max(value) => {
+                value.clone().unwrap_or(JsonValue::Null)
+            }
+        }
+    }
+}
+
+fn float_value(f: f64) -> JsonValue {
+    serde_json::Number::from_f64(f)
+        .map(JsonValue::Number)
+        .unwrap_or(JsonValue::Null)
+}
+
+/// The number a value holds, fields read from the lines are numbers written as text
+fn number_of(value: &JsonValue) -> Option<serde_json::Number> {
+    match value {
+        JsonValue::Number(n) => Some(n.clone()),
+        JsonValue::String(s) => {
+            let s = s.trim();
+            match s.parse::<i64>() {
+                Ok(i) => Some(serde_json::Number::from(i)),
+                Err(_) => s.parse::<f64>().ok().and_then(serde_json::Number::from_f64),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Orders numbers, including numbers written as text, by value and before any other value,
+/// which are ordered by their text
+pub fn compare_values(a: &JsonValue, b: &JsonValue) -> Ordering {
+    match (number_of(a), number_of(b)) {
+        (Some(a), Some(b)) => {
+            let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => text_of(a).cmp(&text_of(b)),
+    }
+}
+
+fn text_of(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// The groups of a query as its lines are scanned, in the order they were first seen
+pub struct GroupTable {
+    aggregation: Aggregation,
+    index: HashMap<String, usize>,
+    groups: Vec<(Vec<JsonValue>, Vec<Accumulator>)>,
+    max_groups: usize,
+}
+
+impl GroupTable {
+    /// A table holding up to `max_groups` groups, `0` for no limit
+    pub fn new(aggregation: Aggregation, max_groups: usize) -> GroupTable {
+        GroupTable {
+            aggregation,
+            index: HashMap::new(),
+            groups: Vec::new(),
+            max_groups,
+        }
+    }
+
+    /// Adds a matching line to its group, failing if it would start a group over the limit
+    pub fn add(
+        &mut self,
+        projection_values: &HashMap<String, Option<PatternValue>>,
+        line: &str,
+        timezone: &FixedOffset,
+    ) -> Result<(), usize> {
+        let key: Vec<JsonValue> = self
+            .aggregation
+            .group_by
+            .iter()
+            .map(|group| {
+                evaluate_projection(&group.expr, projection_values, line, timezone)
+                    .unwrap_or(JsonValue::Null)
+            })
+            .collect();
+        let key_text = serde_json::to_string(&key).unwrap();
+        let position = match self.index.get(&key_text) {
+            Some(position) => *position,
+            None => {
+                if self.max_groups > 0 && self.groups.len() >= self.max_groups {
+                    return Err(self.max_groups);
+                }
+                let accumulators = self
+                    .aggregation
+                    .aggregates
+                    .iter()
+                    .map(|aggregate| Accumulator::new(aggregate.function))
+                    .collect();
+                self.groups.push((key, accumulators));
+                self.index.insert(key_text, self.groups.len() - 1);
+                self.groups.len() - 1
+            }
+        };
+        let accumulators = &mut self.groups[position].1;
+        for (aggregate, accumulator) in self.aggregation.aggregates.iter().zip(accumulators) {
+            let value = aggregate
+                .arg
+                .as_ref()
+                .and_then(|arg| evaluate_projection(arg, projection_values, line, timezone));
+            accumulator.add(value, aggregate.arg.is_none());
+        }
+        Ok(())
+    }
+
+    /// The values of every group by output column: the `GROUP BY` projections and the
+    /// aggregates. A query without `GROUP BY` has a single group, even without lines.
+    pub fn rows(&self) -> Vec<HashMap<String, JsonValue>> {
+        let mut rows: Vec<HashMap<String, JsonValue>> = self
+            .groups
+            .iter()
+            .map(|(key, accumulators)| self.row(key, accumulators))
+            .collect();
+        if rows.is_empty() && self.aggregation.group_by.is_empty() {
+            let accumulators: Vec<Accumulator> = self
+                .aggregation
+                .aggregates
+                .iter()
+                .map(|aggregate| Accumulator::new(aggregate.function))
+                .collect();
+            rows.push(self.row(&[], &accumulators));
+        }
+        rows
+    }
+
+    fn row(&self, key: &[JsonValue], accumulators: &[Accumulator]) -> HashMap<String, JsonValue> {
+        let mut row = HashMap::new();
+        for (group, value) in self.aggregation.group_by.iter().zip(key) {
+            if let Some(alias) = &group.alias {
+                row.insert(alias.clone(), value.clone());
+            }
+        }
+        for (aggregate, accumulator) in self.aggregation.aggregates.iter().zip(accumulators) {
+            row.insert(aggregate.alias.clone(), accumulator.value());
+        }
+        row
+    }
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::*;
+    use crate::dialect::MinSQLDialect;
+    use crate::functions::CastType;
+    use serde_json::json;
+    use sqlparser::ast::{SelectItem, SetExpr, Statement};
+    use sqlparser::parser::Parser;
+
+    fn projections_of(sql: &str) -> Vec<Expr> {
+        let dialect = MinSQLDialect {};
+        let ast = Parser::parse_sql(&dialect, sql.to_string()).unwrap();
+        match &ast[0] {
+            Statement::Query(q) => match &q.body {
+                SetExpr::Select(select) => select
+                    .projection
+                    .iter()
+                    .map(|projection| match projection {
+                        SelectItem::UnnamedExpr(expr) => expr.clone(),
+                        SelectItem::ExprWithAlias { expr, .. } => expr.clone(),
+                        _ => panic!("unexpected projection"),
+                    })
+                    .collect(),
+                _ => panic!("unexpected query"),
+            },
+            _ => panic!("unexpected query"),
+        }
+    }
+
+    #[test]
+    fn aggregate_functions() {
+        let projections =
+            projections_of("SELECT COUNT(*), sum($4), AVG(CAST($4 AS INT)), LOWER($1) FROM mylog");
+        assert!(is_aggregate_projection(&projections[0]));
+        assert!(is_aggregate_projection(&projections[1]));
+        assert!(!is_aggregate_projection(&projections[3]));
+        let mut fields = Vec::new();
+        assert_eq!(
+            parse_aggregate(&projections[0], &mut fields),
+            Ok((AggregateFunction::Count, None))
+        );
+        assert_eq!(
+            parse_aggregate(&projections[1], &mut fields),
+            Ok((
+                AggregateFunction::Sum,
+                Some(ProjectionExpr::Field("$4".to_string()))
+            ))
+        );
+        assert_eq!(fields.len(), 1);
+
+        for sql in vec![
+            "SELECT SUM(*) FROM mylog",
+            "SELECT MAX($1, $2) FROM mylog",
+            "SELECT MIN(COUNT(*)) FROM mylog",
+        ] {
+            let projections = projections_of(sql);
+            assert!(parse_aggregate(&projections[0], &mut Vec::new()).is_err());
+        }
+    }
+
+    fn field_values(values: &[(&str, &str)]) -> HashMap<String, Option<PatternValue>> {
+        values
+            .iter()
+            .map(|(field, value)| {
+                (
+                    field.to_string(),
+                    Some(PatternValue::RichData(value.to_string())),
+                )
+            })
+            .collect()
+    }
+
+    fn field(name: &str) -> Option<ProjectionExpr> {
+        Some(ProjectionExpr::Field(name.to_string()))
+    }
+
+    #[test]
+    fn groups_and_accumulators() {
+        let aggregation = Aggregation {
+            group_by: vec![GroupColumn {
+                alias: Some("$ip".to_string()),
+                expr: ProjectionExpr::Field("$ip".to_string()),
+            }],
+            aggregates: vec![
+                AggregateColumn {
+                    alias: "COUNT(*)".to_string(),
+                    function: AggregateFunction::Count,
+                    arg: None,
+                },
+                AggregateColumn {
+                    alias: "bytes".to_string(),
+                    function: AggregateFunction::Sum,
+                    arg: field("$4"),
+                },
+                AggregateColumn {
+                    alias: "avg".to_string(),
+                    function: AggregateFunction::Avg,
+                    arg: field("$4"),
+                },
+                AggregateColumn {
+                    alias: "min".to_string(),
+                    function: AggregateFunction::Min,
+                    arg: field("$4"),
+                },
+                AggregateColumn {
+                    alias: "max".to_string(),
+                    function: AggregateFunction::Max,
+                    arg: field("$4"),
+                },
+            ],
+        };
+        let utc = FixedOffset::east(0);
+        let mut table = GroupTable::new(aggregation.clone(), 0);
+        for (ip, bytes) in vec![
+            ("10.0.0.1", "9"),
+            ("10.0.0.2", "100"),
+            ("10.0.0.1", "10"),
+            ("10.0.0.1", "-"),
+        ] {
+            let values = field_values(&[("$ip", ip), ("$4", bytes)]);
+            table.add(&values, "", &utc).unwrap();
+        }
+        let rows = table.rows();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["$ip"], json!("10.0.0.1"));
+        assert_eq!(rows[0]["COUNT(*)"], json!(3));
+        assert_eq!(rows[0]["bytes"], json!(19));
+        assert_eq!(rows[0]["avg"], json!(9.5));
+        // numbers compare by value and before text
+        assert_eq!(rows[0]["min"], json!("9"));
+        assert_eq!(rows[0]["max"], json!("-"));
+        assert_eq!(rows[1]["$ip"], json!("10.0.0.2"));
+        assert_eq!(rows[1]["COUNT(*)"], json!(1));
+
+        // a group over the limit fails the query
+        let mut table = GroupTable::new(aggregation.clone(), 1);
+        let values = field_values(&[("$ip", "10.0.0.1")]);
+        assert!(table.add(&values, "", &utc).is_ok());
+        let values = field_values(&[("$ip", "10.0.0.2")]);
+        assert_eq!(table.add(&values, "", &utc), Err(1));
+    }
+
+    #[test]
+    fn single_group_without_lines() {
+        let aggregation = Aggregation {
+            group_by: Vec::new(),
+            aggregates: vec![
+                AggregateColumn {
+                    alias: "COUNT(*)".to_string(),
+                    function: AggregateFunction::Count,
+                    arg: None,
+                },
+                AggregateColumn {
+                    alias: "SUM($4)".to_string(),
+                    function: AggregateFunction::Sum,
+                    arg: field("$4"),
+                },
+            ],
+        };
+        let rows = GroupTable::new(aggregation, 0).rows();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["COUNT(*)"], json!(0));
+        assert_eq!(rows[0]["SUM($4)"], JsonValue::Null);
+    }
+
+    #[test]
+    fn aggregate_column_types() {
+        let column = |function, arg| AggregateColumn {
+            alias: String::new(),
+            function,
+            arg,
+        };
+        let field_type = |key: &str| match key {
+            "$date" => ColumnType::Timestamp,
+            _ => ColumnType::String,
+        };
+        let cast = |key: &str| {
+            Some(ProjectionExpr::Cast(
+                Box::new(ProjectionExpr::Field(key.to_string())),
+                CastType::Int,
+            ))
+        };
+        assert_eq!(
+            column(AggregateFunction::Count, None).column_type(&field_type),
+            ColumnType::Int
+        );
+        assert_eq!(
+            column(AggregateFunction::Avg, field("$4")).column_type(&field_type),
+            ColumnType::Float
+        );
+        assert_eq!(
+            column(AggregateFunction::Sum, field("$4")).column_type(&field_type),
+            ColumnType::Number
+        );
+        assert_eq!(
+            column(AggregateFunction::Sum, cast("$4")).column_type(&field_type),
+            ColumnType::Int
+        );
+        assert_eq!(
+            column(AggregateFunction::Max, field("$date")).column_type(&field_type),
+            ColumnType::Timestamp
+        );
+    }
+}
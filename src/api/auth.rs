@@ -19,8 +19,10 @@ use futures::future::Either;
 use futures::stream::Stream;
 use futures::{future, Future};
 use hyper::{header, Body, Chunk, Method, Request, Response};
+use serde_derive::Serialize;
 
-use crate::api::{ListResponse, SafeOutput, ViewSet};
+use crate::api::{json_response, path_pk, ListResponse, SafeOutput, ViewSet};
+use crate::changesets::{commit_changeset, ObjectChange};
 use crate::config::{Config, LogAuth};
 use crate::http::{return_400, return_404, return_500, ResponseFuture};
 use crate::storage::{delete_object_metabucket, put_object_metabucket};
@@ -80,51 +82,13 @@ impl ApiAuth {
                 return Err(return_400("Could not understand request"));
             }
         };
-        //default token
-        let mut new_log_auth: LogAuth = LogAuth {
-            log_name: "".to_string(),
-            api: vec![],
-            expire: "".to_string(),
-            status: "".to_string(),
-        };
-
         let log_auth: serde_json::Value = match serde_json::from_str(&payload) {
             Ok(v) => v,
             Err(_) => {
                 return Err(return_400("Could not parse request"));
             }
         };
-
-        // Validate log name
-        if let Some(serde_json::Value::String(log_name)) = log_auth.get("log_name") {
-            if log_name == "" {
-                return Err(return_400("Log name cannot be empty"));
-            }
-            new_log_auth.log_name = log_name.clone();
-        }
-
-        if let Some(serde_json::Value::Array(api_value)) = log_auth.get("api") {
-            let mut apis: Vec<String> = Vec::new();
-            for v in api_value {
-                if let serde_json::Value::String(api) = v {
-                    // validate the API
-                    if api != "search" && api != "store" {
-                        return Err(return_400(&format!("Unknown API {} provided", api)));
-                    }
-                    apis.push(api.clone());
-                }
-            }
-            new_log_auth.api = apis;
-        }
-
-        if let Some(serde_json::Value::String(expire)) = log_auth.get("expire") {
-            new_log_auth.expire = expire.clone();
-        }
-
-        if let Some(serde_json::Value::String(status)) = log_auth.get("status") {
-            new_log_auth.status = status.clone();
-        }
-        Ok(new_log_auth)
+        log_auth_from_value(&log_auth).map_err(|e| return_400(&e))
     }
 
     fn parse_update_body(
@@ -231,16 +195,22 @@ impl ApiAuth {
                                 ),
                                 token_serialized,
                             )
-                            .map_err(|_| ())
-                            .then(move |_| {
-                                new_log_auth.safe();
-                                let ds_serialized = serde_json::to_string(&new_log_auth).unwrap();
+                            .then(move |v| match v {
+                                Ok(_) => {
+                                    new_log_auth.safe();
+                                    let ds_serialized =
+                                        serde_json::to_string(&new_log_auth).unwrap();
 
-                                let body = Body::from(Chunk::from(ds_serialized));
-                                let mut response = Response::builder();
-                                response.header(header::CONTENT_TYPE, "application/json");
+                                    let body = Body::from(Chunk::from(ds_serialized));
+                                    let mut response = Response::builder();
+                                    response.header(header::CONTENT_TYPE, "application/json");
 
-                                future::ok(response.body(body).unwrap())
+                                    future::ok(response.body(body).unwrap())
+                                }
+                                Err(e) => future::ok(return_500(&format!(
+                                    "Could not save the auth: {}",
+                                    e.reason()
+                                ))),
                             });
                             Either::A(res)
                         }
@@ -307,7 +277,10 @@ impl ApiAuth {
 
                                     future::ok(response.body(body).unwrap())
                                 }
-                                Err(_) => future::ok(return_500("error saving object")),
+                                Err(e) => future::ok(return_500(&format!(
+                                    "Could not save the auth: {}",
+                                    e.reason()
+                                ))),
                             });
                             Either::A(res)
                         }
@@ -339,9 +312,6 @@ impl ApiAuth {
                 Arc::clone(&self.config),
                 format!("minsql/meta/auth/{}/{}", token_access_key, pk),
             )
-            .map_err(|_| {
-                return_500("Error deleting");
-            })
             .then(move |v| match v {
                 Ok(_) => {
                     //remove sensitive data
@@ -352,10 +322,186 @@ impl ApiAuth {
                     response.header(header::CONTENT_TYPE, "application/json");
                     future::ok(response.body(body).unwrap())
                 }
-                Err(_) => future::ok(return_500("error deleting auth from storage")),
+                Err(e) => future::ok(return_500(&format!(
+                    "Could not delete the auth: {}",
+                    e.reason()
+                ))),
             }),
         )
     }
+    /// `POST /api/auth/{token}/bulk` grants the token access to every log on the list of
+    /// `LogAuth` of the body, replacing the grants it already has on them.
+    fn bulk_grant(&self, req: Request<Body>, token_access_key: &str) -> ResponseFuture {
+        let cfg = Arc::clone(&self.config);
+        let token_access_key = token_access_key.to_string();
+        Box::new(req.into_body().concat2().from_err().and_then(move |body| {
+            let cfg_read = cfg.read().unwrap();
+            if cfg_read.tokens.contains_key(&token_access_key) == false {
+                return Either::B(future::ok(return_404()));
+            }
+            let grants = match parse_bulk_grants(&body) {
+                Ok(grants) => grants,
+                Err(e) => return Either::B(future::ok(return_400(&e))),
+            };
+            let changes: Vec<ObjectChange> = grants
+                .iter()
+                .map(|log_auth| ObjectChange {
+                    key: grant_key(&token_access_key, &log_auth.log_name),
+                    after: Some(serde_json::to_string(log_auth).unwrap()),
+                })
+                .collect();
+            drop(cfg_read);
+            // a single write, so either every grant makes it to the configuration or none does
+            Either::A(
+                commit_changeset(Arc::clone(&cfg), changes).then(move |res| {
+                    Ok(match res {
+                        Ok(_) => json_response(&BulkGrantResponse { granted: grants }),
+                        Err(e) => return_500(&e),
+                    })
+                }),
+            )
+        }))
+    }
+
+    /// `POST /api/auth/{token}/bulk/revoke` revokes the grants of the token on every log named
+    /// on the list of the body, all of them must exist.
+    fn bulk_revoke(&self, req: Request<Body>, token_access_key: &str) -> ResponseFuture {
+        let cfg = Arc::clone(&self.config);
+        let token_access_key = token_access_key.to_string();
+        Box::new(req.into_body().concat2().from_err().and_then(move |body| {
+            let cfg_read = cfg.read().unwrap();
+            if cfg_read.tokens.contains_key(&token_access_key) == false {
+                return Either::B(future::ok(return_404()));
+            }
+            let log_names = match parse_bulk_revokes(&body) {
+                Ok(log_names) => log_names,
+                Err(e) => return Either::B(future::ok(return_400(&e))),
+            };
+            let mut revoked: Vec<LogAuth> = Vec::new();
+            for log_name in &log_names {
+                match cfg_read
+                    .auth
+                    .get(&token_access_key)
+                    .and_then(|token_logs| token_logs.get(log_name))
+                {
+                    Some(log_auth) => revoked.push(log_auth.clone()),
+                    None => {
+                        let msg = format!("The token has no access to log {}", log_name);
+                        return Either::B(future::ok(return_400(&msg)));
+                    }
+                }
+            }
+            let changes: Vec<ObjectChange> = revoked
+                .iter()
+                .map(|log_auth| ObjectChange {
+                    key: grant_key(&token_access_key, &log_auth.log_name),
+                    after: None,
+                })
+                .collect();
+            drop(cfg_read);
+            Either::A(
+                commit_changeset(Arc::clone(&cfg), changes).then(move |res| {
+                    Ok(match res {
+                        Ok(_) => json_response(&BulkRevokeResponse { revoked }),
+                        Err(e) => return_500(&e),
+                    })
+                }),
+            )
+        }))
+    }
+}
+
+#[derive(Serialize)]
+struct BulkGrantResponse {
+    granted: Vec<LogAuth>,
+}
+
+#[derive(Serialize)]
+struct BulkRevokeResponse {
+    revoked: Vec<LogAuth>,
+}
+
+/// Reads a `LogAuth` from a create body, the fields missing are left empty
+fn log_auth_from_value(log_auth: &serde_json::Value) -> Result<LogAuth, String> {
+    //default token
+    let mut new_log_auth: LogAuth = LogAuth {
+        log_name: "".to_string(),
+        api: vec![],
+        expire: "".to_string(),
+        status: "".to_string(),
+    };
+
+    // Validate log name
+    if let Some(serde_json::Value::String(log_name)) = log_auth.get("log_name") {
+        if log_name == "" {
+            return Err("Log name cannot be empty".to_string());
+        }
+        new_log_auth.log_name = log_name.clone();
+    }
+
+    if let Some(serde_json::Value::Array(api_value)) = log_auth.get("api") {
+        let mut apis: Vec<String> = Vec::new();
+        for v in api_value {
+            if let serde_json::Value::String(api) = v {
+                // validate the API
+                if api != "search" && api != "store" {
+                    return Err(format!("Unknown API {} provided", api));
+                }
+                apis.push(api.clone());
+            }
+        }
+        new_log_auth.api = apis;
+    }
+
+    if let Some(serde_json::Value::String(expire)) = log_auth.get("expire") {
+        new_log_auth.expire = expire.clone();
+    }
+
+    if let Some(serde_json::Value::String(status)) = log_auth.get("status") {
+        new_log_auth.status = status.clone();
+    }
+    Ok(new_log_auth)
+}
+
+/// Reads the grants of a bulk body, the whole list is refused if any of them is invalid
+fn parse_bulk_grants(body: &[u8]) -> Result<Vec<LogAuth>, String> {
+    let entries = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Array(entries)) => entries,
+        _ => return Err("The body must be a list of log auths".to_string()),
+    };
+    let mut grants: Vec<LogAuth> = Vec::new();
+    for entry in &entries {
+        let log_auth = log_auth_from_value(entry)?;
+        if log_auth.log_name == "" {
+            return Err("Log name cannot be empty".to_string());
+        }
+        if grants.iter().any(|g| g.log_name == log_auth.log_name) {
+            return Err(format!(
+                "Log {} is listed more than once",
+                log_auth.log_name
+            ));
+        }
+        grants.push(log_auth);
+    }
+    Ok(grants)
+}
+
+/// Reads the log names of a bulk revoke body
+fn parse_bulk_revokes(body: &[u8]) -> Result<Vec<String>, String> {
+    let log_names = match serde_json::from_slice::<Vec<String>>(body) {
+        Ok(log_names) => log_names,
+        Err(_) => return Err("The body must be a list of log names".to_string()),
+    };
+    for (i, log_name) in log_names.iter().enumerate() {
+        if log_names[..i].contains(log_name) {
+            return Err(format!("Log {} is listed more than once", log_name));
+        }
+    }
+    Ok(log_names)
+}
+
+fn grant_key(token_access_key: &str, log_name: &str) -> String {
+    format!("minsql/meta/auth/{}/{}", token_access_key, log_name)
 }
 
 impl ViewSet for ApiAuth {
@@ -382,20 +528,64 @@ impl ViewSet for ApiAuth {
 
     /// route request.
     fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
-        match (req.method(), path_parts.get(2), path_parts.get(3)) {
+        let pk = path_pk(&path_parts, 3);
+        match (req.method(), path_parts.get(2), pk) {
             // delegate to proper action
             (&Method::GET, Some(token_access_key), None) => self.list(req, token_access_key),
+            (&Method::POST, Some(token_access_key), Some(ref pk)) if pk == "bulk" => {
+                self.bulk_grant(req, token_access_key)
+            }
+            (&Method::POST, Some(token_access_key), Some(ref pk)) if pk == "bulk/revoke" => {
+                self.bulk_revoke(req, token_access_key)
+            }
             (&Method::POST, Some(token_access_key), None) => self.create(req, token_access_key),
             (&Method::GET, Some(token_access_key), Some(pk)) => {
-                self.retrieve(req, token_access_key, pk)
+                self.retrieve(req, token_access_key, &pk)
             }
             (&Method::PUT, Some(token_access_key), Some(pk)) => {
-                self.update(req, token_access_key, pk)
+                self.update(req, token_access_key, &pk)
             }
             (&Method::DELETE, Some(token_access_key), Some(pk)) => {
-                self.delete(req, token_access_key, pk)
+                self.delete(req, token_access_key, &pk)
             }
             _ => Box::new(future::ok(return_404())),
         }
     }
 }
+
+#[cfg(test)]
+mod auth_api_tests {
+    use super::*;
+
+    #[test]
+    fn bulk_grants() {
+        let body = br#"[{"log_name": "applogs", "api": ["search"]}, {"log_name": "team/service", "api": ["search", "store"], "status": "enabled"}]"#;
+        let grants = parse_bulk_grants(body).unwrap();
+        assert_eq!(grants.len(), 2);
+        assert_eq!(grants[1].log_name, "team/service");
+        assert_eq!(grants[1].api, vec!["search", "store"]);
+    }
+
+    #[test]
+    fn invalid_bulk_grants_refuse_the_whole_list() {
+        assert!(parse_bulk_grants(br#"{"log_name": "applogs"}"#).is_err());
+        assert!(parse_bulk_grants(br#"[{"log_name": "applogs"}, {"api": ["search"]}]"#).is_err());
+        assert!(parse_bulk_grants(
+            br#"[{"log_name": "applogs"}, {"log_name": "x", "api": ["drop"]}]"#
+        )
+        .is_err());
+        assert!(
+            parse_bulk_grants(br#"[{"log_name": "applogs"}, {"log_name": "applogs"}]"#).is_err()
+        );
+    }
+
+    #[test]
+    fn bulk_revokes() {
+        assert_eq!(
+            parse_bulk_revokes(br#"["applogs", "team/service"]"#).unwrap(),
+            vec!["applogs", "team/service"]
+        );
+        assert!(parse_bulk_revokes(br#"["applogs", "applogs"]"#).is_err());
+        assert!(parse_bulk_revokes(br#"[{"log_name": "applogs"}]"#).is_err());
+    }
+}
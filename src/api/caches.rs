@@ -0,0 +1,72 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future;
+use hyper::{header, Body, Method, Request, Response};
+use serde_derive::Serialize;
+
+use crate::caches::{find_cache, registered_caches, CacheControl, CacheStats};
+use crate::constants::APP_JSON;
+use crate::http::{return_404, ResponseFuture};
+
+#[derive(Default)]
+pub struct ApiCaches {}
+
+#[derive(Serialize)]
+struct CachesResponse {
+    // for `DELETE`, the caches as they were right before being cleared
+    caches: Vec<CacheStats>,
+}
+
+impl ApiCaches {
+    pub fn new() -> ApiCaches {
+        ApiCaches {}
+    }
+
+    /// `GET /api/caches` inspects the caches, `DELETE /api/caches` clears all of them and
+    /// `DELETE /api/caches/{name}` a single one.
+    pub fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
+        match (req.method(), path_parts.get(2)) {
+            (&Method::GET, None) => self.respond(registered_caches(), false),
+            (&Method::DELETE, None) => self.respond(registered_caches(), true),
+            (&Method::DELETE, Some(name)) => match find_cache(name) {
+                Some(cache) => self.respond(vec![cache], true),
+                None => Box::new(future::ok(return_404())),
+            },
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn respond(&self, caches: Vec<&'static dyn CacheControl>, clear: bool) -> ResponseFuture {
+        let caches = caches
+            .into_iter()
+            .map(|cache| {
+                let stats = cache.stats();
+                if clear {
+                    cache.clear();
+                }
+                stats
+            })
+            .collect();
+        let output = serde_json::to_string(&CachesResponse { caches }).unwrap();
+        Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_JSON)
+                .body(Body::from(output))
+                .unwrap(),
+        ))
+    }
+}
@@ -0,0 +1,95 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use hyper::{Body, Method, Request};
+use log::error;
+use serde_derive::Serialize;
+
+use crate::api::json_response;
+use crate::config::Config;
+use crate::history::{list_snapshots, rollback_config, valid_snapshot_ts, Snapshot};
+use crate::http::{return_400, return_404, return_500, ResponseFuture};
+
+pub struct ApiConfig {
+    config: Arc<RwLock<Config>>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    snapshots: Vec<Snapshot>,
+}
+
+#[derive(Serialize)]
+struct RollbackResponse {
+    to: String,
+    // configuration objects restored to their version at `to`
+    restored: Vec<String>,
+}
+
+impl ApiConfig {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> ApiConfig {
+        ApiConfig { config: cfg }
+    }
+
+    /// Supports `GET /api/config/history` and `POST /api/config/rollback?to=<ts>`
+    pub fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
+        match (req.method(), path_parts.get(2)) {
+            (&Method::GET, Some(&"history")) => self.history(),
+            (&Method::POST, Some(&"rollback")) => self.rollback(req),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn history(&self) -> ResponseFuture {
+        Box::new(
+            list_snapshots(Arc::clone(&self.config)).then(|res| match res {
+                Ok(snapshots) => Ok(json_response(&HistoryResponse { snapshots })),
+                Err(e) => {
+                    error!("{}", e);
+                    Ok(return_500("error listing the configuration history"))
+                }
+            }),
+        )
+    }
+
+    fn rollback(&self, req: Request<Body>) -> ResponseFuture {
+        let to = req.uri().query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "to")
+                .map(|(_, value)| value.to_string())
+        });
+        let to = match to {
+            Some(ts) if valid_snapshot_ts(&ts) => ts,
+            _ => {
+                return Box::new(future::ok(return_400(
+                    "`to` must be the timestamp of a snapshot",
+                )))
+            }
+        };
+        Box::new(
+            rollback_config(Arc::clone(&self.config), to.clone()).then(move |res| match res {
+                Ok(restored) => Ok(json_response(&RollbackResponse { to, restored })),
+                Err(e) => {
+                    error!("{}", e);
+                    Ok(return_500("error rolling back the configuration"))
+                }
+            }),
+        )
+    }
+}
@@ -21,9 +21,10 @@ use futures::stream::Stream;
 use futures::{future, Future};
 use hyper::{header, Body, Chunk, Request, Response};
 
-use crate::api::{SafeOutput, ViewSet};
-use crate::config::{Config, DataStore};
-use crate::http::{return_400, return_404, return_500, ResponseFuture};
+use crate::api::{claim_update, etag_for, SafeOutput, ViewSet};
+use crate::config::{limit_reached, Config, DataStore};
+use crate::http::{return_400, return_404, return_412, return_500, ResponseFuture};
+use crate::meta::{apply_config_object, remove_config_for_key};
 use crate::storage::{delete_object_metabucket, put_object_metabucket};
 
 pub struct ApiDataStores {
@@ -73,6 +74,14 @@ impl ApiDataStores {
         if datastore.bucket == "" {
             return Err(return_400("Bucket cannot be empty."));
         }
+        // Bandwidth caps
+        if datastore.max_upload_bytes_per_sec == Some(0)
+            || datastore.max_download_bytes_per_sec == Some(0)
+        {
+            return Err(return_400(
+                "Bandwidth caps must be a positive number of bytes per second.",
+            ));
+        }
         let cfg_read = cfg.read().unwrap();
 
         // Validate name
@@ -107,12 +116,46 @@ impl ApiDataStores {
             }
         };
 
-        let datastore: HashMap<String, String> = match serde_json::from_str(&payload) {
-            Ok(v) => v,
-            Err(_) => {
-                return Err(return_400("Could not parse request"));
-            }
-        };
+        let mut datastore: serde_json::Map<String, serde_json::Value> =
+            match serde_json::from_str(&payload) {
+                Ok(v) => v,
+                Err(_) => {
+                    return Err(return_400("Could not parse request"));
+                }
+            };
+
+        // Bandwidth caps, a null value lifts the cap
+        match datastore.remove("max_upload_bytes_per_sec") {
+            Some(serde_json::Value::Null) => current_datastore.max_upload_bytes_per_sec = None,
+            Some(value) => match value.as_u64() {
+                Some(cap) if cap > 0 => current_datastore.max_upload_bytes_per_sec = Some(cap),
+                _ => {
+                    return Err(return_400(
+                        "max_upload_bytes_per_sec must be a positive integer",
+                    ))
+                }
+            },
+            None => (),
+        }
+        match datastore.remove("max_download_bytes_per_sec") {
+            Some(serde_json::Value::Null) => current_datastore.max_download_bytes_per_sec = None,
+            Some(value) => match value.as_u64() {
+                Some(cap) if cap > 0 => current_datastore.max_download_bytes_per_sec = Some(cap),
+                _ => {
+                    return Err(return_400(
+                        "max_download_bytes_per_sec must be a positive integer",
+                    ))
+                }
+            },
+            None => (),
+        }
+        let datastore: HashMap<String, String> =
+            match serde_json::from_value(serde_json::Value::Object(datastore)) {
+                Ok(v) => v,
+                Err(_) => {
+                    return Err(return_400("Could not parse request"));
+                }
+            };
 
         // Validate Access/Secret
         if let Some(access_key) = datastore.get("access_key") {
@@ -149,25 +192,11 @@ impl ApiDataStores {
         }
 
         // Validate name
-        let mut datastore_name: Option<String> = None;
         if let Some(name) = datastore.get("name") {
             if name == "" {
                 return Err(return_400("Datastore name cannot be empty."));
             }
             current_datastore.name = Some(name.clone());
-            datastore_name = Some(name.clone());
-        }
-
-        // if ds name changed, delete previous file
-        if let Some(ds_name) = datastore_name {
-            if ds_name != *pk {
-                let cfg = Arc::clone(&cfg);
-                tokio::spawn({
-                    delete_object_metabucket(cfg, format!("minsql/meta/datastores/{}", pk))
-                        .map(|_| ())
-                        .map_err(|_| ())
-                });
-            }
         }
         Ok(current_datastore)
     }
@@ -188,6 +217,15 @@ impl ViewSet for ApiDataStores {
     }
 
     fn create(&self, req: Request<Body>) -> ResponseFuture {
+        let read_cfg = self.config.read().unwrap();
+        if limit_reached(read_cfg.datastore.len(), read_cfg.server.max_datastores) {
+            let msg = format!(
+                "The limit of {} datastores was reached",
+                read_cfg.server.max_datastores
+            );
+            return Box::new(future::ok(return_400(&msg)));
+        }
+        drop(read_cfg);
         let cfg = Arc::clone(&self.config);
         let cfg2 = Arc::clone(&self.config);
         Box::new(
@@ -207,7 +245,6 @@ impl ViewSet for ApiDataStores {
                                 format!("minsql/meta/datastores/{}", datastore_name),
                                 ds_serialized,
                             )
-                            .map_err(|_| ())
                             .then(move |v| match v {
                                 Ok(_) => {
                                     datastore.safe();
@@ -219,7 +256,10 @@ impl ViewSet for ApiDataStores {
 
                                     future::ok(response.body(body).unwrap())
                                 }
-                                Err(_) => future::ok(return_500("error saving datastore")),
+                                Err(e) => future::ok(return_500(&format!(
+                                    "Could not save the datastore: {}",
+                                    e.reason()
+                                ))),
                             });
                             Either::A(res)
                         }
@@ -231,17 +271,24 @@ impl ViewSet for ApiDataStores {
 
     fn retrieve(&self, _req: Request<Body>, pk: &str) -> ResponseFuture {
         let cfg_read = self.config.read().unwrap();
-        let mut datastore = match cfg_read.datastore.get(pk) {
+        let datastore = match cfg_read.datastore.get(pk) {
             Some(ds) => ds.clone(),
             None => {
                 return Box::new(future::ok(return_404()));
             }
         };
-        datastore.safe();
-        self.build_response(datastore)
+        // the tag is sent back on `If-Match` to update this version of the datastore
+        let etag = etag_for(&datastore);
+        self.build_tagged_response(datastore, &etag)
     }
 
     fn update(&self, req: Request<Body>, pk: &str) -> ResponseFuture {
+        // reject updates based on a stale copy of the datastore
+        let key = format!("minsql/meta/datastores/{}", pk);
+        let claim = match claim_update(&self.config, &req, &key, |c| c.datastore.get(pk)) {
+            Some(claim) => claim,
+            None => return Box::new(future::ok(return_412())),
+        };
         let pk = pk.to_string();
 
         let cfg = Arc::clone(&self.config);
@@ -255,27 +302,73 @@ impl ViewSet for ApiDataStores {
                         Ok(mut current_datastore) => {
                             // everything seems ok, write to datastore
                             let ds_serialized = serde_json::to_string(&current_datastore).unwrap();
+                            let etag = etag_for(&current_datastore);
+                            let ds_name = current_datastore.name.clone().unwrap_or(pk.clone());
 
-                            let res = put_object_metabucket(
-                                cfg2,
-                                format!("minsql/meta/datastores/{}", pk),
-                                ds_serialized.clone(),
-                            )
-                            .map_err(|_| {})
-                            .then(move |v| match v {
-                                Ok(_) => {
-                                    //remove sensitive data
-                                    current_datastore.safe();
-                                    let ds_serialized =
-                                        serde_json::to_string(&current_datastore).unwrap();
-                                    let body = Body::from(Chunk::from(ds_serialized));
-                                    let mut response = Response::builder();
-                                    response.header(header::CONTENT_TYPE, "application/json");
+                            let ds_key = format!("minsql/meta/datastores/{}", ds_name);
+                            let renamed = ds_name != pk;
 
-                                    future::ok(response.body(body).unwrap())
-                                }
-                                Err(_) => future::ok(return_500("error saving datastore")),
-                            });
+                            let cfg3 = Arc::clone(&cfg2);
+                            let apply_cfg = Arc::clone(&cfg2);
+                            let res =
+                                put_object_metabucket(cfg2, ds_key.clone(), ds_serialized.clone())
+                                    .map_err(|e| {
+                                        format!("Could not save the datastore: {}", e.reason())
+                                    })
+                                    .and_then(move |_| {
+                                        // a renamed datastore is only removed under its previous name
+                                        // once saved under the new one
+                                        if ds_name == pk {
+                                            return Either::A(future::ok(()));
+                                        }
+                                        Either::B(
+                                            delete_object_metabucket(
+                                                cfg3,
+                                                format!("minsql/meta/datastores/{}", pk),
+                                            )
+                                            .map(|_| ())
+                                            .map_err(
+                                                |e| {
+                                                    format!(
+                                            "Could not remove the previous datastore: {}",
+                                            e.reason()
+                                        )
+                                                },
+                                            ),
+                                        )
+                                    })
+                                    .then(move |v| {
+                                        // applied before the claim is released, so the next
+                                        // precondition is checked against this version
+                                        if v.is_ok() {
+                                            apply_config_object(
+                                                &apply_cfg,
+                                                &ds_key,
+                                                ds_serialized.as_bytes(),
+                                            );
+                                            if renamed {
+                                                remove_config_for_key(apply_cfg, key);
+                                            }
+                                        }
+                                        drop(claim);
+                                        v
+                                    })
+                                    .then(move |v| match v {
+                                        Ok(_) => {
+                                            //remove sensitive data
+                                            current_datastore.safe();
+                                            let ds_serialized =
+                                                serde_json::to_string(&current_datastore).unwrap();
+                                            let body = Body::from(Chunk::from(ds_serialized));
+                                            let mut response = Response::builder();
+                                            response
+                                                .header(header::CONTENT_TYPE, "application/json");
+                                            response.header(header::ETAG, etag);
+
+                                            future::ok(response.body(body).unwrap())
+                                        }
+                                        Err(e) => future::ok(return_500(&e)),
+                                    });
 
                             Either::A(res)
                         }
@@ -301,9 +394,8 @@ impl ViewSet for ApiDataStores {
 
         let cfg = Arc::clone(&self.config);
         Box::new(
-            delete_object_metabucket(cfg, format!("minsql/meta/datastores/{}", ds_name))
-                .map_err(|_| {})
-                .then(move |v| match v {
+            delete_object_metabucket(cfg, format!("minsql/meta/datastores/{}", ds_name)).then(
+                move |v| match v {
                     Ok(_) => {
                         //remove sensitive data
                         datastore.safe();
@@ -314,8 +406,12 @@ impl ViewSet for ApiDataStores {
 
                         future::ok(response.body(body).unwrap())
                     }
-                    Err(_) => future::ok(return_500("error deleting datastore from storage")),
-                }),
+                    Err(e) => future::ok(return_500(&format!(
+                        "Could not delete the datastore: {}",
+                        e.reason()
+                    ))),
+                },
+            ),
         )
     }
 }
@@ -0,0 +1,100 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future;
+use hyper::{header, Body, Method, Request, Response};
+use serde_derive::Serialize;
+
+use crate::aggregate::AGGREGATE_FUNCTIONS;
+use crate::constants::{APP_JSON, RECEIVED_FIELD, TIME_FIELD, USER_AGENT_SUBFIELDS};
+use crate::filter::operators;
+use crate::functions::{cast_types, SCALAR_FUNCTIONS};
+use crate::http::{return_404, ResponseFuture};
+use crate::hyperscan::SMART_FIELD_PATTERNS;
+use crate::query::PatternType;
+
+#[derive(Default)]
+pub struct ApiDialect {}
+
+/// SQL understood by the query engine, built from the same lists the engine checks queries
+/// against so clients such as autocompletion stay in sync with it.
+#[derive(Serialize, Debug)]
+struct DialectResponse {
+    statements: Vec<&'static str>,
+    clauses: Vec<&'static str>,
+    // fields other than the smart fields, ie: `$line`, `$time` or `$4`
+    fields: Vec<&'static str>,
+    smart_fields: Vec<SmartFieldDialect>,
+    functions: Vec<&'static str>,
+    aggregate_functions: Vec<&'static str>,
+    cast_types: Vec<String>,
+    operators: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct SmartFieldDialect {
+    name: &'static str,
+    subfields: Vec<&'static str>,
+}
+
+impl ApiDialect {
+    pub fn new() -> ApiDialect {
+        ApiDialect {}
+    }
+
+    /// Only `GET /api/dialect` is supported
+    pub fn route(&self, req: Request<Body>) -> ResponseFuture {
+        match req.method() {
+            &Method::GET => self.dialect(),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn dialect(&self) -> ResponseFuture {
+        let output = serde_json::to_string(&dialect_description()).unwrap();
+        Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_JSON)
+                .body(Body::from(output))
+                .unwrap(),
+        ))
+    }
+}
+
+fn dialect_description() -> DialectResponse {
+    let smart_fields = SMART_FIELD_PATTERNS
+        .iter()
+        .map(|field| SmartFieldDialect {
+            name: field.name,
+            subfields: match field.pattern_type {
+                PatternType::UserAgent => USER_AGENT_SUBFIELDS.to_vec(),
+                _ => Vec::new(),
+            },
+        })
+        .collect();
+    DialectResponse {
+        statements: vec!["SELECT"],
+        clauses: vec![
+            "FROM", "WHERE", "GROUP BY", "ORDER BY", "LIMIT", "AS", "ESCAPE",
+        ],
+        fields: vec!["*", "$line", TIME_FIELD, RECEIVED_FIELD, "$N"],
+        smart_fields,
+        functions: SCALAR_FUNCTIONS.to_vec(),
+        aggregate_functions: AGGREGATE_FUNCTIONS.to_vec(),
+        cast_types: cast_types(),
+        operators: operators(),
+    }
+}
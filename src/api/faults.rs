@@ -0,0 +1,67 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::{future, Future, Stream};
+use hyper::{header, Body, Method, Request, Response};
+use log::warn;
+
+use crate::constants::APP_JSON;
+use crate::faults::{current_faults, set_faults, FaultInjection};
+use crate::http::{return_400, return_404, ResponseFuture};
+
+#[derive(Default)]
+pub struct ApiFaults {}
+
+impl ApiFaults {
+    pub fn new() -> ApiFaults {
+        ApiFaults {}
+    }
+
+    /// `GET /api/faults` tells the faults being injected, `PUT /api/faults` replaces them and
+    /// `DELETE /api/faults` stops injecting them.
+    pub fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
+        match (req.method(), path_parts.get(2)) {
+            (&Method::GET, None) => Box::new(future::ok(respond(current_faults()))),
+            (&Method::PUT, None) => {
+                Box::new(req.into_body().concat2().from_err().map(|entire_body| {
+                    let faults: FaultInjection = match serde_json::from_slice(&entire_body) {
+                        Ok(faults) => faults,
+                        Err(_) => return return_400("Could not parse request"),
+                    };
+                    if let Err(e) = faults.validate() {
+                        return return_400(&e);
+                    }
+                    warn!("Injecting faults on the datastores: {:?}", faults);
+                    set_faults(Some(faults));
+                    respond(current_faults())
+                }))
+            }
+            (&Method::DELETE, None) => {
+                set_faults(None);
+                warn!("Stopped injecting faults on the datastores");
+                Box::new(future::ok(respond(None)))
+            }
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+}
+
+fn respond(faults: Option<FaultInjection>) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .body(Body::from(serde_json::to_string(&faults).unwrap()))
+        .unwrap()
+}
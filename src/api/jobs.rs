@@ -0,0 +1,106 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use hyper::{Body, Method, Request};
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+use crate::api::json_response;
+use crate::config::Config;
+use crate::http::{return_400, return_404, return_500, GenericError, ResponseFuture};
+use crate::jobs::{
+    cancel_job, local_job, local_jobs, sort_jobs, stored_job, stored_jobs, CancelError, Job,
+};
+
+pub struct ApiJobs {
+    config: Arc<RwLock<Config>>,
+}
+
+#[derive(Serialize)]
+struct JobsResponse {
+    jobs: Vec<Job>,
+}
+
+impl ApiJobs {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> ApiJobs {
+        ApiJobs { config: cfg }
+    }
+
+    /// `GET /api/jobs` lists the background jobs, `GET /api/jobs/{id}` inspects one and
+    /// `DELETE /api/jobs/{id}` cancels it.
+    pub fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
+        match (req.method(), path_parts.get(2)) {
+            (&Method::GET, None) => self.list(),
+            // ids are uuids, anything else can't be a job
+            (_, Some(id)) if Uuid::parse_str(id).is_err() => Box::new(future::ok(return_404())),
+            (&Method::GET, Some(id)) => self.retrieve(id),
+            (&Method::DELETE, Some(id)) => self.cancel(id),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    /// Jobs stored on the metabucket by any process, with the jobs of this process as they are
+    /// right now.
+    fn list(&self) -> ResponseFuture {
+        Box::new(
+            stored_jobs(Arc::clone(&self.config)).then(|res| -> Result<_, GenericError> {
+                let stored = match res {
+                    Ok(stored) => stored,
+                    Err(e) => return Ok(return_500(&e)),
+                };
+                let mut jobs: HashMap<String, Job> = stored
+                    .into_iter()
+                    .map(|job| (job.id.clone(), job))
+                    .collect();
+                for job in local_jobs() {
+                    jobs.insert(job.id.clone(), job);
+                }
+                let mut jobs: Vec<Job> = jobs.into_iter().map(|(_, job)| job).collect();
+                sort_jobs(&mut jobs);
+                Ok(json_response(&JobsResponse { jobs }))
+            }),
+        )
+    }
+
+    fn retrieve(&self, id: &str) -> ResponseFuture {
+        if let Some(job) = local_job(id) {
+            return Box::new(future::ok(json_response(&job)));
+        }
+        Box::new(
+            stored_job(Arc::clone(&self.config), id).then(|res| -> Result<_, GenericError> {
+                Ok(match res {
+                    Ok(Some(job)) => json_response(&job),
+                    Ok(None) => return_404(),
+                    Err(e) => return_500(&e),
+                })
+            }),
+        )
+    }
+
+    /// Only jobs running on the process answering the request can be cancelled
+    fn cancel(&self, id: &str) -> ResponseFuture {
+        let response = match cancel_job(id) {
+            Ok(job) => json_response(&job),
+            Err(CancelError::NotFound) => return_404(),
+            Err(CancelError::NotRunning) => return_400("The job is not running"),
+        };
+        Box::new(future::ok(response))
+    }
+}
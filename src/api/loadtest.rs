@@ -0,0 +1,93 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future, Stream};
+use hyper::{header, Body, Chunk, Method, Request, Response};
+use tokio::sync::mpsc;
+
+use crate::auth::{Auth, LogAccess};
+use crate::config::Config;
+use crate::constants::{APP_NDJSON, HEADER_JOB_ID};
+use crate::http::{return_400, return_404, HeaderToken, Http, ResponseFuture};
+use crate::ingest::IngestBuffers;
+use crate::jobs::spawn_job;
+use crate::loadtest::{run_load_test, LoadTestSpec};
+
+pub struct ApiLoadTest {
+    config: Arc<RwLock<Config>>,
+    // the lines of the test are stored like any other
+    ingest_buffers: Arc<IngestBuffers>,
+}
+
+impl ApiLoadTest {
+    pub fn new(cfg: Arc<RwLock<Config>>, ingest_buffers: Arc<IngestBuffers>) -> ApiLoadTest {
+        ApiLoadTest {
+            config: cfg,
+            ingest_buffers,
+        }
+    }
+
+    /// `POST /api/loadtest` stores generated lines on a log at a steady rate and runs searches on
+    /// it, streaming its progress and, once it's over, the throughput and latencies it achieved.
+    /// The test keeps running as a job if the client goes away.
+    pub fn route(&self, req: Request<Body>) -> ResponseFuture {
+        if req.method() != Method::POST {
+            return Box::new(future::ok(return_404()));
+        }
+        // searches run as the token of the request, sessions of identity providers have none
+        let access_token =
+            match Http::new(Arc::clone(&self.config)).validate_token_from_header(&req) {
+                HeaderToken::Token(token) => Some(token),
+                _ => None,
+            };
+        let cfg = Arc::clone(&self.config);
+        let ingest_buffers = Arc::clone(&self.ingest_buffers);
+        Box::new(req.into_body().concat2().from_err().map(move |body| {
+            let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default();
+            let spec = match LoadTestSpec::from_json(&body) {
+                Ok(spec) => spec,
+                Err(e) => return return_400(&e),
+            };
+            match cfg.read().unwrap().log.get(&spec.log) {
+                Some(log) if log.scratch => (),
+                Some(_) => {
+                    return return_400("Load tests only store lines on logs flagged `scratch`")
+                }
+                None => return return_404(),
+            }
+            if !spec.queries.is_empty() {
+                let granted = access_token.as_ref().map(|token| {
+                    Auth::new(Arc::clone(&cfg)).log_access(token, &spec.log) == LogAccess::Granted
+                });
+                if granted != Some(true) {
+                    return return_400("Searches need a token with access to the log");
+                }
+            }
+            let log_name = spec.log.clone();
+            let (tx, rx) = mpsc::unbounded_channel::<String>();
+            let job = run_load_test(Arc::clone(&cfg), ingest_buffers, spec, access_token, tx);
+            let job_id = spawn_job(cfg, "loadtest", &log_name, job);
+            let events = rx.map(Chunk::from).map_err(|e| e.to_string());
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_NDJSON)
+                .header(HEADER_JOB_ID, job_id)
+                .body(Body::wrap_stream(events))
+                .unwrap()
+        }))
+    }
+}
@@ -13,24 +13,75 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
+use chrono::{DateTime, Utc};
 use futures::future::Either;
 use futures::{future, Future, Stream};
-use hyper::{header, Body, Chunk, Request, Response};
+use hyper::{header, Body, Chunk, Method, Request, Response};
+use log::error;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 
-use crate::api::{SafeOutput, ViewSet};
-use crate::config::{Config, Log};
-use crate::http::{return_400, return_404, return_500, ResponseFuture};
+use crate::api::{claim_update, etag_for, SafeOutput, ViewSet};
+use crate::compression::Compression;
+use crate::computed::parse_computed_field;
+use crate::config::{limit_reached, Config, Log, LogReport, MultilineRule, RemoteLog, TeeRule};
+use crate::constants::{
+    APP_JSON, APP_NDJSON, ENCODING_BASE64, ESTIMATE_SUFFIX, HEADER_JOB_ID, QUOTA_DELETE_OLDEST,
+    QUOTA_REJECT, STAMP_METADATA, STAMP_PREPEND,
+};
+use crate::federation::valid_remote_endpoint;
+use crate::http::{return_400, return_404, return_412, return_500, GenericError, ResponseFuture};
+use crate::ingest::IngestBuffers;
+use crate::jobs::spawn_job;
+use crate::maintenance::{Maintenance, MaintenanceAction, Progress, ReindexOptions};
+use crate::meta::{apply_config_object, remove_config_for_key};
+use crate::multiline::MultilineJoiner;
+use crate::naming::{new_generation, ObjectNaming};
+use crate::query::Query;
+use crate::reports::validate_report_condition;
+use crate::sinks::validate_sink;
 use crate::storage::{delete_object_metabucket, put_object_metabucket};
+use crate::trash::{
+    deleted_log_keys, latest_deleted_log, remove_deleted_log, write_deleted_log, DeletedLog,
+};
+use crate::webhook::valid_webhook_url;
+
+#[derive(Serialize, Deserialize)]
+struct LogAliases {
+    aliases: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CheckpointResponse {
+    log: String,
+    // sequence of the last batch accepted and of the last one with every batch before it written
+    accepted: u64,
+    flushed: u64,
+    // batches accepted but not written, buffered, being written or failed
+    pending: usize,
+}
+
+#[derive(Deserialize)]
+struct PurgeRequest {
+    // the `WHERE` clause of the lines to remove
+    #[serde(rename = "where")]
+    condition: String,
+}
 
 pub struct ApiLogs {
     config: Arc<RwLock<Config>>,
 }
 
 impl SafeOutput for Log {
-    // Log has nothing to hide
-    fn safe(&mut self) {}
+    fn safe(&mut self) {
+        if let Some(remote) = &mut self.remote {
+            remote.token = "*********".to_string();
+        }
+    }
 }
 
 impl ApiLogs {
@@ -66,9 +117,71 @@ impl ApiLogs {
             return Err(return_400("Commit window is invalid"));
         }
 
+        // Validate line stamping
+        if let Some(stamp) = &log.stamp {
+            if !valid_stamp(stamp) {
+                return Err(return_400("Stamp must be either `prepend` or `metadata`"));
+            }
+        }
+
+        // Validate quota policy
+        if let Some(policy) = &log.quota_policy {
+            if !valid_quota_policy(policy) {
+                return Err(return_400(
+                    "Quota policy must be either `reject` or `delete_oldest`",
+                ));
+            }
+        }
+
+        // Validate encoding
+        if let Some(encoding) = &log.encoding {
+            if encoding != ENCODING_BASE64 {
+                return Err(return_400("Encoding must be `base64`"));
+            }
+        }
+
+        // Validate compression
+        if let Some(compression) = &log.compression {
+            if Compression::parse(compression).is_none() {
+                return Err(return_400("Compression must be either `gzip` or `zstd`"));
+            }
+        }
+
+        // Validate report
+        if let Some(report) = &log.report {
+            validate_report(report)?;
+        }
+
+        // Validate tee rules
+        validate_tee(&log.tee)?;
+
+        // Validate object naming
+        if let Some(template) = &log.object_key {
+            ObjectNaming::parse(template).map_err(|e| return_400(&e))?;
+        }
+
+        // Validate multi-line rule
+        if let Some(rule) = &log.multiline {
+            MultilineJoiner::new(rule).map_err(|e| return_400(&e))?;
+        }
+        validate_multiline_encoding(&log)?;
+
+        // Validate computed fields
+        validate_computed_fields(&log.computed_fields)?;
+
+        // Validate remote server
+        if let Some(remote) = &log.remote {
+            validate_remote(remote)?;
+        }
+
+        // Validate legal hold
+        if let Some(locked_until) = &log.locked_until {
+            validate_locked_until(locked_until)?;
+        }
+
         let cfg_read = cfg.read().unwrap();
         // validate the datastores
-        for ds_name in &log.datastores {
+        for ds_name in log.datastores.iter().chain(log.cold_datastores.iter()) {
             if cfg_read.datastore.contains_key(ds_name) == false {
                 return Err(return_400(&format!(
                     "{} is an invalid datastore name",
@@ -76,6 +189,13 @@ impl ApiLogs {
                 )));
             }
         }
+        validate_tiering(&log)?;
+        validate_replication(&log)?;
+
+        // Validate deduplication
+        if let Some(window) = &log.dedup_window {
+            validate_dedup_window(window)?;
+        }
 
         // Validate name
 
@@ -83,11 +203,16 @@ impl ApiLogs {
             if lg_name == "" {
                 return Err(return_400("Log name cannot be empty."));
             }
+            if !valid_log_name(lg_name) {
+                return Err(return_400("Log name is invalid."));
+            }
             // validate datastore name uniqueness
-            if cfg_read.log.contains_key(lg_name) {
+            if cfg_read.resolve_log_name(lg_name).is_some() {
                 return Err(return_400("Log name already in use"));
             }
+            validate_aliases(&cfg_read, lg_name, &log.aliases).map_err(|e| return_400(&e))?;
         }
+        validate_report_condition(&cfg_read, &log).map_err(|e| return_400(&e))?;
 
         Ok(log)
     }
@@ -131,6 +256,244 @@ impl ApiLogs {
             current_log.commit_window = commit_window.clone();
         }
 
+        if let Some(serde_json::Value::Bool(lossy_decoding)) = log.get("lossy_decoding") {
+            current_log.lossy_decoding = *lossy_decoding;
+        }
+
+        if let Some(serde_json::Value::Bool(bloom_filters)) = log.get("bloom_filters") {
+            current_log.bloom_filters = *bloom_filters;
+        }
+
+        if let Some(serde_json::Value::Bool(scratch)) = log.get("scratch") {
+            current_log.scratch = *scratch;
+        }
+
+        // Deduplication of flushes, an empty value disables it
+        match log.get("dedup_window") {
+            Some(serde_json::Value::String(window)) => {
+                if window == "" {
+                    current_log.dedup_window = None;
+                } else {
+                    validate_dedup_window(window)?;
+                    current_log.dedup_window = Some(window.clone());
+                }
+            }
+            Some(serde_json::Value::Null) => {
+                current_log.dedup_window = None;
+            }
+            _ => (),
+        }
+
+        // Line stamping, an empty value disables it
+        match log.get("stamp") {
+            Some(serde_json::Value::String(stamp)) => {
+                if stamp == "" {
+                    current_log.stamp = None;
+                } else if !valid_stamp(stamp) {
+                    return Err(return_400("Stamp must be either `prepend` or `metadata`"));
+                } else {
+                    current_log.stamp = Some(stamp.clone());
+                }
+            }
+            Some(serde_json::Value::Null) => {
+                current_log.stamp = None;
+            }
+            _ => (),
+        }
+
+        // Storage quotas, a null value removes the quota
+        match log.get("max_bytes") {
+            Some(serde_json::Value::Null) => current_log.max_bytes = None,
+            Some(value) => match value.as_u64() {
+                Some(max_bytes) => current_log.max_bytes = Some(max_bytes),
+                None => return Err(return_400("max_bytes must be a positive integer")),
+            },
+            None => (),
+        }
+        match log.get("max_objects") {
+            Some(serde_json::Value::Null) => current_log.max_objects = None,
+            Some(value) => match value.as_u64() {
+                Some(max_objects) => current_log.max_objects = Some(max_objects),
+                None => return Err(return_400("max_objects must be a positive integer")),
+            },
+            None => (),
+        }
+        match log.get("quota_policy") {
+            Some(serde_json::Value::String(policy)) => {
+                if !valid_quota_policy(policy) {
+                    return Err(return_400(
+                        "Quota policy must be either `reject` or `delete_oldest`",
+                    ));
+                }
+                current_log.quota_policy = Some(policy.clone());
+            }
+            Some(serde_json::Value::Null) => {
+                current_log.quota_policy = None;
+            }
+            _ => (),
+        }
+
+        // Encoding, an empty value disables it
+        match log.get("encoding") {
+            Some(serde_json::Value::String(encoding)) => {
+                if encoding == "" {
+                    current_log.encoding = None;
+                } else if encoding != ENCODING_BASE64 {
+                    return Err(return_400("Encoding must be `base64`"));
+                } else {
+                    current_log.encoding = Some(encoding.clone());
+                }
+            }
+            Some(serde_json::Value::Null) => {
+                current_log.encoding = None;
+            }
+            _ => (),
+        }
+
+        // Compression of the objects written from now on, an empty value disables it
+        match log.get("compression") {
+            Some(serde_json::Value::String(compression)) => {
+                if compression == "" {
+                    current_log.compression = None;
+                } else if Compression::parse(compression).is_none() {
+                    return Err(return_400("Compression must be either `gzip` or `zstd`"));
+                } else {
+                    current_log.compression = Some(compression.clone());
+                }
+            }
+            Some(serde_json::Value::Null) => {
+                current_log.compression = None;
+            }
+            _ => (),
+        }
+
+        // Daily report, a null value disables it
+        match log.get("report") {
+            Some(serde_json::Value::Null) => current_log.report = None,
+            Some(value) => {
+                let report: LogReport = serde_json::from_value(value.clone())
+                    .map_err(|_| return_400("Could not parse report"))?;
+                validate_report(&report)?;
+                current_log.report = Some(report);
+            }
+            None => (),
+        }
+
+        // Objects already stored wouldn't be found under another layout
+        match log.get("object_key") {
+            Some(object_key) if *object_key != serde_json::json!(current_log.object_key) => {
+                return Err(return_400(
+                    "Object key template can only be set when the log is created",
+                ));
+            }
+            _ => (),
+        }
+
+        // Tee rules, a null value removes them
+        match log.get("tee") {
+            Some(serde_json::Value::Null) => current_log.tee = Vec::new(),
+            Some(value) => {
+                let tee: Vec<TeeRule> = serde_json::from_value(value.clone())
+                    .map_err(|_| return_400("Could not parse tee rules"))?;
+                validate_tee(&tee)?;
+                current_log.tee = tee;
+            }
+            None => (),
+        }
+
+        // Legal hold, a null value lifts it
+        match log.get("locked_until") {
+            Some(serde_json::Value::Null) => current_log.locked_until = None,
+            Some(serde_json::Value::String(locked_until)) => {
+                validate_locked_until(locked_until)?;
+                current_log.locked_until = Some(locked_until.clone());
+            }
+            Some(_) => return Err(return_400("locked_until must be an RFC 3339 time")),
+            None => (),
+        }
+
+        // Remote server, a null value makes the log local only. The token is kept if not sent,
+        // as it's never returned.
+        match log.get("remote") {
+            Some(serde_json::Value::Null) => current_log.remote = None,
+            Some(value) => {
+                let mut remote: RemoteLog = serde_json::from_value(value.clone())
+                    .map_err(|_| return_400("Could not parse remote"))?;
+                if remote.token == "" {
+                    if let Some(current) = &current_log.remote {
+                        remote.token = current.token.clone();
+                    }
+                }
+                validate_remote(&remote)?;
+                current_log.remote = Some(remote);
+            }
+            None => (),
+        }
+
+        // Elasticsearch indices, a null value removes them
+        match log.get("es_indices") {
+            Some(serde_json::Value::Null) => current_log.es_indices = Vec::new(),
+            Some(serde_json::Value::Array(indices)) => {
+                let mut es_indices = Vec::new();
+                for index in indices {
+                    match index {
+                        serde_json::Value::String(index) if index != "" => {
+                            es_indices.push(index.clone())
+                        }
+                        _ => return Err(return_400("es_indices must be a list of index names")),
+                    }
+                }
+                current_log.es_indices = es_indices;
+            }
+            Some(_) => return Err(return_400("es_indices must be a list of index names")),
+            None => (),
+        }
+
+        match log.get("loki_labels") {
+            Some(serde_json::Value::Null) => current_log.loki_labels = HashMap::new(),
+            Some(serde_json::Value::Object(labels)) => {
+                let mut loki_labels = HashMap::new();
+                for (name, value) in labels {
+                    match value {
+                        serde_json::Value::String(value) if name != "" => {
+                            loki_labels.insert(name.clone(), value.clone());
+                        }
+                        _ => return Err(return_400("loki_labels must map label names to values")),
+                    }
+                }
+                current_log.loki_labels = loki_labels;
+            }
+            Some(_) => return Err(return_400("loki_labels must map label names to values")),
+            None => (),
+        }
+
+        // Computed fields replace the ones of the log, a null value removes them
+        match log.get("computed_fields") {
+            Some(serde_json::Value::Null) => current_log.computed_fields = HashMap::new(),
+            Some(value) => {
+                let computed_fields: HashMap<String, String> =
+                    serde_json::from_value(value.clone()).map_err(|_| {
+                        return_400("computed_fields must map field names to expressions")
+                    })?;
+                validate_computed_fields(&computed_fields)?;
+                current_log.computed_fields = computed_fields;
+            }
+            None => (),
+        }
+
+        // Multi-line rule, a null value disables it
+        match log.get("multiline") {
+            Some(serde_json::Value::Null) => current_log.multiline = None,
+            Some(value) => {
+                let rule: MultilineRule = serde_json::from_value(value.clone())
+                    .map_err(|_| return_400("Could not parse multiline rule"))?;
+                MultilineJoiner::new(&rule).map_err(|e| return_400(&e))?;
+                current_log.multiline = Some(rule);
+            }
+            None => (),
+        }
+        validate_multiline_encoding(&current_log)?;
+
         let cfg_read = cfg.read().unwrap();
         // validate the datastores
         if let Some(serde_json::Value::Array(datastores_value)) = log.get("datastores") {
@@ -147,32 +510,483 @@ impl ApiLogs {
                     }
                 }
             }
+            // a replica added later wouldn't hold the objects written so far
+            if current_log.replicated
+                && datastores
+                    .iter()
+                    .any(|ds_name| !current_log.datastores.contains(ds_name))
+            {
+                return Err(return_400(
+                    "A replicated log can't take new datastores, they don't hold its objects",
+                ));
+            }
             current_log.datastores = datastores;
         }
+        if let Some(serde_json::Value::Array(datastores_value)) = log.get("cold_datastores") {
+            let mut datastores: Vec<String> = Vec::new();
+            for ds_name_value in datastores_value {
+                if let serde_json::Value::String(ds_name) = ds_name_value {
+                    if cfg_read.datastore.contains_key(ds_name) == false {
+                        return Err(return_400(&format!(
+                            "{} is an invalid datastore name",
+                            &ds_name
+                        )));
+                    } else {
+                        datastores.push(ds_name.clone());
+                    }
+                }
+            }
+            current_log.cold_datastores = datastores;
+        }
+        match log.get("cold_after") {
+            Some(serde_json::Value::String(cold_after)) => {
+                current_log.cold_after = Some(cold_after.clone());
+            }
+            Some(serde_json::Value::Null) => {
+                current_log.cold_after = None;
+            }
+            _ => (),
+        }
+        validate_tiering(&current_log)?;
+        // the objects written so far are on a single datastore, or on all of them
+        if let Some(serde_json::Value::Bool(replicated)) = log.get("replicated") {
+            if *replicated != current_log.replicated {
+                return Err(return_400(
+                    "replicated can only be set when the log is created",
+                ));
+            }
+        }
+        validate_replication(&current_log)?;
 
         // Validate name
-        let mut log_name: Option<String> = None;
         if let Some(serde_json::Value::String(name)) = log.get("name") {
             if name == "" {
                 return Err(return_400("Log name cannot be empty."));
             }
+            if !valid_log_name(name) {
+                return Err(return_400("Log name is invalid."));
+            }
+            if *name != pk && read_cfg.resolve_log_name(name).is_some() {
+                return Err(return_400("Log name already in use"));
+            }
             current_log.name = Some(name.clone());
-            log_name = Some(name.clone());
         }
+        validate_report_condition(&read_cfg, &current_log).map_err(|e| return_400(&e))?;
+        Ok(current_log)
+    }
+
+    /// Estimates what the query on the `query` parameter would read
+    fn estimate(&self, req: &Request<Body>, log_name: &str) -> ResponseFuture {
+        let sql = match self.parse_query_parameters(req).remove("query") {
+            Some(sql) => sql,
+            None => return Box::new(future::ok(return_400("The query parameter is missing"))),
+        };
+        let query_c = Query::new(Arc::clone(&self.config));
+        match query_c.estimate(log_name, sql) {
+            Ok(estimate) => Box::new(estimate.then(|res| -> Result<_, GenericError> {
+                match res {
+                    Ok(estimate) => Ok(Response::builder()
+                        .header(header::CONTENT_TYPE, APP_JSON)
+                        .body(Body::from(serde_json::to_string(&estimate).unwrap()))
+                        .unwrap()),
+                    Err(e) => Ok(return_500(&e)),
+                }
+            })),
+            Err(e) => Box::new(future::ok(return_400(&e))),
+        }
+    }
+
+    /// Runs the maintenance action on the body of the request on a log right away, streaming an
+    /// event for every object it handles. The action keeps running if the client goes away.
+    pub fn maintenance(
+        &self,
+        req: Request<Body>,
+        log_name: &str,
+        ingest_buffers: Arc<IngestBuffers>,
+    ) -> ResponseFuture {
+        if !self.config.read().unwrap().log.contains_key(log_name) {
+            return Box::new(future::ok(return_404()));
+        }
+        let log_name = log_name.to_string();
+        let cfg = Arc::clone(&self.config);
+        Box::new(req.into_body().concat2().from_err().map(move |body| {
+            let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default();
+            let action = body.get("action").and_then(|a| a.as_str());
+            let action = match action.and_then(|a| MaintenanceAction::parse(a)) {
+                Some(action) => action,
+                None => return return_400("The action must be one of compact, expire or reindex"),
+            };
+            let reindex_options = match ReindexOptions::from_json(&body) {
+                Ok(options) => options,
+                Err(e) => return return_400(&e),
+            };
+            // the log may be gone by now
+            let refused = match cfg.read().unwrap().log.get(&log_name) {
+                Some(log) => action.refused_for(log, &Utc::now()),
+                None => return return_404(),
+            };
+            if let Some(reason) = refused {
+                return return_400(&reason);
+            }
+            let (tx, rx) = mpsc::unbounded_channel::<String>();
+            let job = Maintenance::new(Arc::clone(&cfg)).run(
+                &log_name,
+                action,
+                reindex_options,
+                ingest_buffers,
+                Progress::new(tx),
+            );
+            let job_id = spawn_job(cfg, action.name(), &log_name, job);
+            let events = rx.map(Chunk::from).map_err(|e| e.to_string());
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_NDJSON)
+                .header(HEADER_JOB_ID, job_id)
+                .body(Body::wrap_stream(events))
+                .unwrap()
+        }))
+    }
+
+    /// Removes the lines of a log matching the `WHERE` clause on the body of the request from its
+    /// objects, streaming an event for every object it changes. The purge keeps running if the
+    /// client goes away.
+    pub fn purge(
+        &self,
+        req: Request<Body>,
+        log_name: &str,
+        ingest_buffers: Arc<IngestBuffers>,
+    ) -> ResponseFuture {
+        if !self.config.read().unwrap().log.contains_key(log_name) {
+            return Box::new(future::ok(return_404()));
+        }
+        let log_name = log_name.to_string();
+        let cfg = Arc::clone(&self.config);
+        Box::new(req.into_body().concat2().from_err().map(move |body| {
+            let condition = match serde_json::from_slice::<PurgeRequest>(&body) {
+                Ok(purge) => purge.condition,
+                Err(_) => return return_400("Could not parse request"),
+            };
+            // removing every line is deleting the log
+            if condition.trim().is_empty() {
+                return return_400("The where clause cannot be empty");
+            }
+            let held = match cfg.read().unwrap().log.get(&log_name) {
+                Some(log) => log.held_until(&Utc::now()),
+                None => return return_404(),
+            };
+            if let Some(until) = held {
+                let msg = format!("The log is under legal hold until {}", until.to_rfc3339());
+                return return_400(&msg);
+            }
+            let (statement, q_parse) =
+                match Query::new(Arc::clone(&cfg)).plan_purge(&log_name, &condition) {
+                    Ok(plan) => plan,
+                    Err(e) => return return_400(&e),
+                };
+            let (tx, rx) = mpsc::unbounded_channel::<String>();
+            let job = Maintenance::new(Arc::clone(&cfg)).purge(
+                &log_name,
+                statement,
+                q_parse,
+                ingest_buffers,
+                Progress::new(tx),
+            );
+            let job_id = spawn_job(cfg, "purge_lines", &log_name, job);
+            let events = rx.map(Chunk::from).map_err(|e| e.to_string());
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_NDJSON)
+                .header(HEADER_JOB_ID, job_id)
+                .body(Body::wrap_stream(events))
+                .unwrap()
+        }))
+    }
+
+    /// How far the batches accepted for a log by this server were written to its datastores,
+    /// batches are numbered by the `MINSQL-SEQUENCE` header of their response
+    pub fn checkpoint(&self, log_name: &str, ingest_buffers: Arc<IngestBuffers>) -> ResponseFuture {
+        if !self.config.read().unwrap().log.contains_key(log_name) {
+            return Box::new(future::ok(return_404()));
+        }
+        let checkpoint = match ingest_buffers.get(log_name) {
+            Some(ingest_buffer) => ingest_buffer
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .checkpoint(),
+            // logs created while the server runs have no buffer until their first lines
+            None => return Box::new(future::ok(return_404())),
+        };
+        let output = CheckpointResponse {
+            log: log_name.to_string(),
+            accepted: checkpoint.accepted,
+            flushed: checkpoint.flushed,
+            pending: checkpoint.pending,
+        };
+        Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_JSON)
+                .body(Body::from(serde_json::to_string(&output).unwrap()))
+                .unwrap(),
+        ))
+    }
+
+    /// `GET /api/logs/{log}/aliases` lists the other names queries can use for a log and
+    /// `PUT /api/logs/{log}/aliases` replaces them, ie: with the old name of a renamed log.
+    pub fn aliases(&self, req: Request<Body>, log_name: &str) -> ResponseFuture {
+        let log = match self.config.read().unwrap().log.get(log_name) {
+            Some(log) => log.clone(),
+            None => return Box::new(future::ok(return_404())),
+        };
+        match *req.method() {
+            Method::GET => Box::new(future::ok(aliases_response(log.aliases))),
+            Method::PUT => self.update_aliases(req, log),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn update_aliases(&self, req: Request<Body>, mut log: Log) -> ResponseFuture {
+        let cfg = Arc::clone(&self.config);
+        Box::new(req.into_body().concat2().from_err().and_then(move |body| {
+            let aliases = match serde_json::from_slice::<LogAliases>(&body) {
+                Ok(body) => body.aliases,
+                Err(_) => return Either::B(future::ok(return_400("Could not parse request"))),
+            };
+            let log_name = log.name.clone().unwrap_or_default();
+            if let Err(e) = validate_aliases(&cfg.read().unwrap(), &log_name, &aliases) {
+                return Either::B(future::ok(return_400(&e)));
+            }
+            log.aliases = aliases;
+            let log_serialized = serde_json::to_string(&log).unwrap();
+            Either::A(
+                put_object_metabucket(
+                    cfg,
+                    format!("minsql/meta/logs/{}", log_name),
+                    log_serialized,
+                )
+                .then(move |res| match res {
+                    Ok(_) => future::ok(aliases_response(log.aliases)),
+                    Err(e) => future::ok(return_500(&format!(
+                        "Could not save the aliases: {}",
+                        e.reason()
+                    ))),
+                }),
+            )
+        }))
+    }
 
-        // if log name changed, delete previous file
-        if let Some(ds_name) = log_name {
-            if ds_name != pk {
-                let cfg = Arc::clone(&cfg);
-                tokio::spawn({
-                    delete_object_metabucket(cfg, format!("minsql/meta/logs/{}", pk))
-                        .map(|_| ())
-                        .map_err(|_| ())
+    /// Puts a deleted log back from the trash along with its objects, as long as its grace
+    /// period isn't over and no log was created with its name since.
+    pub fn restore(&self, log_name: &str) -> ResponseFuture {
+        let read_cfg = self.config.read().unwrap();
+        if read_cfg.log.contains_key(log_name) {
+            return Box::new(future::ok(return_400(
+                "A log with the same name exists, it has to be deleted first",
+            )));
+        }
+        if limit_reached(read_cfg.log.len(), read_cfg.server.max_logs) {
+            let msg = format!("The limit of {} logs was reached", read_cfg.server.max_logs);
+            return Box::new(future::ok(return_400(&msg)));
+        }
+        let grace_secs = read_cfg.server.deleted_log_grace_secs;
+        drop(read_cfg);
+        let cfg = Arc::clone(&self.config);
+        let log_name = log_name.to_string();
+        Box::new(
+            latest_deleted_log(Arc::clone(&cfg), &log_name).then(move |res| {
+                let (key, deleted) = match res {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => return Either::B(future::ok(return_404())),
+                    Err(e) => return Either::B(future::ok(return_500(&e))),
+                };
+                // the objects may be getting deleted already
+                if deleted.expired(grace_secs, &Utc::now()) {
+                    return Either::B(future::ok(return_400(
+                        "The grace period of the log is over",
+                    )));
+                }
+                let mut log = deleted.log;
+                let log_serialized = serde_json::to_string(&log).unwrap();
+                let remove_cfg = Arc::clone(&cfg);
+                let res = put_object_metabucket(
+                    cfg,
+                    format!("minsql/meta/logs/{}", log_name),
+                    log_serialized,
+                )
+                .map_err(|e| format!("Could not restore the log: {}", e.reason()))
+                .and_then(move |_| remove_deleted_log(remove_cfg, key))
+                .then(move |res| match res {
+                    Ok(_) => {
+                        log.safe();
+                        future::ok(
+                            Response::builder()
+                                .header(header::CONTENT_TYPE, APP_JSON)
+                                .body(Body::from(serde_json::to_string(&log).unwrap()))
+                                .unwrap(),
+                        )
+                    }
+                    Err(e) => future::ok(return_500(&e)),
                 });
+                Either::A(res)
+            }),
+        )
+    }
+}
+
+/// Validates the hot/cold tiering settings of a log
+fn validate_tiering(log: &Log) -> Result<(), Response<Body>> {
+    if let Some(cold_after) = &log.cold_after {
+        if Config::age_to_seconds(cold_after).is_none() {
+            return Err(return_400(
+                "cold_after must be specified in seconds `30s`, minutes `30m`, hours `12h` or days `7d`",
+            ));
+        }
+        if log.cold_datastores.is_empty() {
+            return Err(return_400(
+                "cold_after requires at least one cold datastore",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Replicated logs keep every object on their hot datastores alone, moving them to a cold tier
+/// would leave a copy per replica there
+fn validate_replication(log: &Log) -> Result<(), Response<Body>> {
+    if log.replicated && !log.cold_datastores.is_empty() {
+        return Err(return_400("A replicated log can't have cold datastores"));
+    }
+    Ok(())
+}
+
+/// Rejects multi-line rules on logs with the `base64` encoding, their lines are stored as they
+/// were sent, so continuation lines can't be joined
+fn validate_multiline_encoding(log: &Log) -> Result<(), Response<Body>> {
+    if log.multiline.is_some() && log.encoding.as_ref().map(|e| e.as_str()) == Some(ENCODING_BASE64)
+    {
+        return Err(return_400(
+            "A multi-line rule can't be used along with the `base64` encoding",
+        ));
+    }
+    Ok(())
+}
+
+/// Validates the age identical flushes are skipped within
+fn validate_dedup_window(window: &str) -> Result<(), Response<Body>> {
+    if Config::age_to_seconds(window).is_none() {
+        return Err(return_400(
+            "dedup_window must be specified in seconds `30s`, minutes `30m`, hours `12h` or days `7d`",
+        ));
+    }
+    Ok(())
+}
+
+/// Validates the webhook or sink of a daily report, its error condition is validated against the
+/// log by `validate_report_condition`
+fn validate_report(report: &LogReport) -> Result<(), Response<Body>> {
+    match &report.sink {
+        Some(sink) => {
+            if let Err(e) = validate_sink(sink) {
+                return Err(return_400(&e));
             }
         }
-        Ok(current_log)
+        None => {
+            if !valid_webhook_url(&report.webhook) {
+                return Err(return_400("Report webhook must be an http or https url"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Validates the webhooks and patterns of the tee rules of a log
+fn validate_tee(rules: &[TeeRule]) -> Result<(), Response<Body>> {
+    for rule in rules {
+        if !valid_webhook_url(&rule.webhook) {
+            return Err(return_400("Tee webhook must be an http or https url"));
+        }
+        if Regex::new(&rule.pattern).is_err() {
+            return Err(return_400("Tee pattern is not a valid regex"));
+        }
+    }
+    Ok(())
+}
+
+/// Validates the names and expressions of the computed fields of a log
+fn validate_computed_fields(
+    computed_fields: &HashMap<String, String>,
+) -> Result<(), Response<Body>> {
+    for (name, expression) in computed_fields {
+        parse_computed_field(name, expression).map_err(|e| return_400(&e))?;
+    }
+    Ok(())
+}
+
+fn validate_locked_until(locked_until: &str) -> Result<(), Response<Body>> {
+    match DateTime::parse_from_rfc3339(locked_until) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(return_400("locked_until must be an RFC 3339 time")),
+    }
+}
+
+/// Validates the endpoint and token of a remote server
+fn validate_remote(remote: &RemoteLog) -> Result<(), Response<Body>> {
+    if !valid_remote_endpoint(&remote.endpoint) {
+        return Err(return_400("Remote endpoint must be an http or https url"));
+    }
+    if remote.token.len() != 48 {
+        return Err(return_400("Remote token must be a 48 characters token"));
+    }
+    if remote.log.as_ref().map_or(false, |name| name == "") {
+        return Err(return_400("Remote log name cannot be empty"));
     }
+    Ok(())
+}
+
+/// Whether the log name is valid. Names can be hierarchical, `team/service`, as long as no
+/// part is empty nor `*`, which grants access to the logs under a log, and the first one doesn't
+/// clash with another route.
+pub fn valid_log_name(name: &str) -> bool {
+    match name.split("/").next() {
+        Some("api") | Some("ui") | Some("es") => false,
+        _ => name.split("/").all(|p| !p.is_empty() && p != "*"),
+    }
+}
+
+/// Validates the aliases of a log, they follow the rules of log names and can't be taken by
+/// another log, either as its name or one of its aliases
+fn validate_aliases(cfg: &Config, log_name: &str, aliases: &[String]) -> Result<(), String> {
+    for (i, alias) in aliases.iter().enumerate() {
+        if !valid_log_name(alias) {
+            return Err(format!("{} is an invalid alias", alias));
+        }
+        if aliases[..i].contains(alias) {
+            return Err(format!("{} is listed more than once", alias));
+        }
+        match cfg.resolve_log_name(alias) {
+            Some(ref owner) if owner != log_name || alias == log_name => {
+                return Err(format!("{} is already in use", alias));
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn aliases_response(aliases: Vec<String>) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .body(Body::from(
+            serde_json::to_string(&LogAliases { aliases }).unwrap(),
+        ))
+        .unwrap()
+}
+
+/// Whether the provided line stamping mode is supported
+fn valid_stamp(stamp: &str) -> bool {
+    stamp == STAMP_PREPEND || stamp == STAMP_METADATA
+}
+
+/// Whether the provided quota policy is supported
+fn valid_quota_policy(policy: &str) -> bool {
+    policy == QUOTA_REJECT || policy == QUOTA_DELETE_OLDEST
 }
 
 impl ViewSet for ApiLogs {
@@ -191,6 +1005,12 @@ impl ViewSet for ApiLogs {
     }
 
     fn create(&self, req: Request<Body>) -> ResponseFuture {
+        let read_cfg = self.config.read().unwrap();
+        if limit_reached(read_cfg.log.len(), read_cfg.server.max_logs) {
+            let msg = format!("The limit of {} logs was reached", read_cfg.server.max_logs);
+            return Box::new(future::ok(return_400(&msg)));
+        }
+        drop(read_cfg);
         let cfg = Arc::clone(&self.config);
         Box::new(
             req.into_body()
@@ -199,26 +1019,41 @@ impl ViewSet for ApiLogs {
                 .and_then(move |entire_body| {
                     match ApiLogs::parse_create_body(entire_body.to_vec(), cfg.clone()) {
                         Ok(mut log) => {
-                            let ds_serialized = serde_json::to_string(&log).unwrap();
                             let log_name = log.clone().name.unwrap();
+                            let put_cfg = Arc::clone(&cfg);
 
-                            let res = put_object_metabucket(
-                                cfg,
-                                format!("minsql/meta/logs/{}", log_name),
-                                ds_serialized,
-                            )
-                            .then(move |v| match v {
-                                Ok(_) => {
-                                    log.safe();
-                                    future::ok(
-                                        Response::builder()
-                                            .header(header::CONTENT_TYPE, "application/json")
-                                            .body(Body::from(serde_json::to_string(&log).unwrap()))
-                                            .unwrap(),
+                            let res = deleted_log_keys(cfg, &log_name)
+                                .and_then(move |trashed| {
+                                    // the objects of a log on the trash are kept until its grace
+                                    // period is over, a log created with its name gets keys of
+                                    // its own
+                                    if !trashed.is_empty() {
+                                        log.object_key =
+                                            Some(new_generation(log.object_key.as_ref()));
+                                    }
+                                    let ds_serialized = serde_json::to_string(&log).unwrap();
+                                    put_object_metabucket(
+                                        put_cfg,
+                                        format!("minsql/meta/logs/{}", log_name),
+                                        ds_serialized,
                                     )
-                                }
-                                Err(e) => future::ok(return_500(&format!("I/O Err: {}", e))),
-                            });
+                                    .map(move |_| log)
+                                    .map_err(|e| format!("Could not save the log: {}", e.reason()))
+                                })
+                                .then(move |v| match v {
+                                    Ok(mut log) => {
+                                        log.safe();
+                                        future::ok(
+                                            Response::builder()
+                                                .header(header::CONTENT_TYPE, "application/json")
+                                                .body(Body::from(
+                                                    serde_json::to_string(&log).unwrap(),
+                                                ))
+                                                .unwrap(),
+                                        )
+                                    }
+                                    Err(e) => future::ok(return_500(&e)),
+                                });
                             Either::A(res)
                         }
                         Err(err_resp) => Either::B(future::ok(err_resp)),
@@ -227,19 +1062,34 @@ impl ViewSet for ApiLogs {
         )
     }
 
-    fn retrieve(&self, _req: Request<Body>, pk: &str) -> ResponseFuture {
+    fn retrieve(&self, req: Request<Body>, pk: &str) -> ResponseFuture {
         let cfg_read = self.config.read().unwrap();
-        let mut log = match cfg_read.log.get(pk) {
+        // `GET /api/logs/{log}/estimate`, unless a log is named like that
+        if !cfg_read.log.contains_key(pk) && pk.ends_with(ESTIMATE_SUFFIX) {
+            let log_name = &pk[..pk.len() - ESTIMATE_SUFFIX.len()];
+            if cfg_read.log.contains_key(log_name) {
+                drop(cfg_read);
+                return self.estimate(&req, log_name);
+            }
+        }
+        let log = match cfg_read.log.get(pk) {
             Some(ds) => ds.clone(),
             None => {
                 return Box::new(future::ok(return_404()));
             }
         };
-        log.safe();
-        self.build_response(log)
+        // the tag is sent back on `If-Match` to update this version of the log
+        let etag = etag_for(&log);
+        self.build_tagged_response(log, &etag)
     }
 
     fn update(&self, req: Request<Body>, pk: &str) -> ResponseFuture {
+        // reject updates based on a stale copy of the log
+        let key = format!("minsql/meta/logs/{}", pk);
+        let claim = match claim_update(&self.config, &req, &key, |c| c.log.get(pk)) {
+            Some(claim) => claim,
+            None => return Box::new(future::ok(return_412())),
+        };
         let pk = pk.to_string();
         let cfg = Arc::clone(&self.config);
         Box::new(
@@ -247,28 +1097,71 @@ impl ViewSet for ApiLogs {
                 .concat2()
                 .from_err()
                 .and_then(move |entire_body| {
-                    match ApiLogs::parse_update_body(entire_body.to_vec(), cfg.clone(), pk) {
+                    match ApiLogs::parse_update_body(entire_body.to_vec(), cfg.clone(), pk.clone())
+                    {
                         Ok(mut log) => {
                             let ds_serialized = serde_json::to_string(&log).unwrap();
                             let log_name = log.clone().name.unwrap();
+                            let etag = etag_for(&log);
+                            let log_key = format!("minsql/meta/logs/{}", log_name);
+                            let written = ds_serialized.clone();
 
-                            let res = put_object_metabucket(
-                                cfg,
-                                format!("minsql/meta/logs/{}", log_name),
-                                ds_serialized,
-                            )
-                            .then(move |v| match v {
-                                Ok(_) => {
-                                    log.safe();
-                                    future::ok(
-                                        Response::builder()
-                                            .header(header::CONTENT_TYPE, "application/json")
-                                            .body(Body::from(serde_json::to_string(&log).unwrap()))
-                                            .unwrap(),
+                            let delete_cfg = Arc::clone(&cfg);
+                            let apply_cfg = Arc::clone(&cfg);
+                            let renamed = log_name != pk;
+                            let res = put_object_metabucket(cfg, log_key.clone(), ds_serialized)
+                                .map_err(|e| format!("Could not save the log: {}", e.reason()))
+                                .and_then(move |_| {
+                                    // a renamed log is only removed under its previous name once
+                                    // saved under the new one
+                                    if log_name == pk {
+                                        return Either::A(future::ok(()));
+                                    }
+                                    Either::B(
+                                        delete_object_metabucket(
+                                            delete_cfg,
+                                            format!("minsql/meta/logs/{}", pk),
+                                        )
+                                        .map(|_| ())
+                                        .map_err(|e| {
+                                            format!(
+                                                "Could not remove the previous log: {}",
+                                                e.reason()
+                                            )
+                                        }),
                                     )
-                                }
-                                Err(e) => future::ok(return_500(&format!("I/O Err: {}", e))),
-                            });
+                                })
+                                .then(move |v| {
+                                    // applied before the claim is released, so the next
+                                    // precondition is checked against this version
+                                    if v.is_ok() {
+                                        apply_config_object(
+                                            &apply_cfg,
+                                            &log_key,
+                                            written.as_bytes(),
+                                        );
+                                        if renamed {
+                                            remove_config_for_key(apply_cfg, key);
+                                        }
+                                    }
+                                    drop(claim);
+                                    v
+                                })
+                                .then(move |v| match v {
+                                    Ok(_) => {
+                                        log.safe();
+                                        future::ok(
+                                            Response::builder()
+                                                .header(header::CONTENT_TYPE, "application/json")
+                                                .header(header::ETAG, etag)
+                                                .body(Body::from(
+                                                    serde_json::to_string(&log).unwrap(),
+                                                ))
+                                                .unwrap(),
+                                        )
+                                    }
+                                    Err(e) => future::ok(return_500(&e)),
+                                });
                             Either::A(res)
                         }
                         Err(err_resp) => Either::B(future::ok(err_resp)),
@@ -277,7 +1170,9 @@ impl ViewSet for ApiLogs {
         )
     }
 
-    fn delete(&self, _req: Request<Body>, pk: &str) -> ResponseFuture {
+    /// Moves the log to the trash, where it can be restored from until its grace period is
+    /// over. With `?purge=true` its objects are deleted once it's emptied from the trash.
+    fn delete(&self, req: Request<Body>, pk: &str) -> ResponseFuture {
         let read_cfg = self.config.read().unwrap();
         let mut log = match read_cfg.log.get(pk) {
             Some(v) => v.clone(),
@@ -285,30 +1180,43 @@ impl ViewSet for ApiLogs {
                 return Box::new(future::ok(return_404()));
             }
         };
+        drop(read_cfg);
+
+        // the objects of a held log must stay reachable
+        if let Some(until) = log.held_until(&Utc::now()) {
+            let msg = format!("The log is under legal hold until {}", until.to_rfc3339());
+            return Box::new(future::ok(return_400(&msg)));
+        }
 
         let log_name = match &log.name {
             Some(v) => v.clone(),
             None => "".to_string(),
         };
+        let purge = self.parse_query_parameters(&req).get("purge") == Some(&"true".to_string());
+        let deleted = DeletedLog::new(log.clone(), &Utc::now(), purge);
 
+        let cfg = Arc::clone(&self.config);
         Box::new(
-            delete_object_metabucket(
-                Arc::clone(&self.config),
-                format!("minsql/meta/logs/{}", log_name),
-            )
-            .map_err(|_| {
-                println!("Some error deleting");
-                return_500("Error deleting")
-            })
-            .then(move |_| {
-                //remove sensitive data
-                log.safe();
-                let ds_serialized = serde_json::to_string(&log).unwrap();
-                let body = Body::from(Chunk::from(ds_serialized));
-                let mut response = Response::builder();
-                response.header(header::CONTENT_TYPE, "application/json");
-                future::ok(response.body(body).unwrap())
-            }),
+            write_deleted_log(Arc::clone(&cfg), &deleted)
+                .and_then(move |_| {
+                    delete_object_metabucket(cfg, format!("minsql/meta/logs/{}", log_name))
+                        .map_err(|e| format!("Could not delete the log: {}", e.reason()))
+                })
+                .then(move |res| match res {
+                    Ok(_) => {
+                        //remove sensitive data
+                        log.safe();
+                        let ds_serialized = serde_json::to_string(&log).unwrap();
+                        let body = Body::from(Chunk::from(ds_serialized));
+                        let mut response = Response::builder();
+                        response.header(header::CONTENT_TYPE, "application/json");
+                        future::ok(response.body(body).unwrap())
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        future::ok(return_500(&e))
+                    }
+                }),
         )
     }
 }
@@ -0,0 +1,85 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use futures::future;
+use hyper::{header, Body, Method, Request, Response};
+use serde_derive::Serialize;
+
+use crate::config::{Config, LogAuth};
+use crate::constants::APP_JSON;
+use crate::http::{return_401, return_404, ResponseFuture};
+
+pub struct ApiMe {
+    config: Arc<RwLock<Config>>,
+}
+
+/// What a token is allowed to do, without its secret key.
+#[derive(Serialize, Debug)]
+struct MeResponse {
+    access_key: String,
+    description: Option<String>,
+    enabled: bool,
+    is_admin: bool,
+    api_access: bool,
+    // logs the token is authorized to, sorted by name
+    logs: Vec<LogAuth>,
+}
+
+impl ApiMe {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> ApiMe {
+        ApiMe { config: cfg }
+    }
+
+    /// Only `GET /api/me` is supported, for the token the request was sent with
+    pub fn route(&self, req: Request<Body>, access_token: &str) -> ResponseFuture {
+        match req.method() {
+            &Method::GET => self.me(access_token),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn me(&self, access_token: &str) -> ResponseFuture {
+        let read_cfg = self.config.read().unwrap();
+        let access_key = &access_token[0..16];
+        let token = match read_cfg.tokens.get(access_key) {
+            Some(token) => token,
+            None => return Box::new(future::ok(return_401())),
+        };
+        let mut logs: Vec<LogAuth> = read_cfg
+            .auth
+            .get(access_key)
+            .map(|log_auths| log_auths.values().cloned().collect())
+            .unwrap_or_else(Vec::new);
+        logs.sort_by(|a, b| a.log_name.cmp(&b.log_name));
+        let output = serde_json::to_string(&MeResponse {
+            access_key: token.access_key.clone(),
+            description: token.description.clone(),
+            enabled: token.enabled,
+            is_admin: token.is_admin,
+            api_access: token.api_access,
+            logs,
+        })
+        .unwrap();
+        Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_JSON)
+                .body(Body::from(output))
+                .unwrap(),
+        ))
+    }
+}
@@ -0,0 +1,66 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future;
+use hyper::{header, Body, Method, Request, Response};
+use serde_derive::Serialize;
+
+use crate::constants::APP_JSON;
+use crate::http::{return_404, ResponseFuture};
+use crate::meta::meta_problems;
+
+#[derive(Default)]
+pub struct ApiMeta {}
+
+#[derive(Serialize)]
+struct MetaProblem {
+    key: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct ProblemsResponse {
+    // configuration objects of the metabucket that couldn't be loaded
+    problems: Vec<MetaProblem>,
+}
+
+impl ApiMeta {
+    pub fn new() -> ApiMeta {
+        ApiMeta {}
+    }
+
+    /// Only `GET /api/meta/problems` is supported
+    pub fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
+        match (req.method(), path_parts.get(2)) {
+            (&Method::GET, Some(&"problems")) => self.problems(),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn problems(&self) -> ResponseFuture {
+        let problems = meta_problems()
+            .into_iter()
+            .map(|(key, error)| MetaProblem { key, error })
+            .collect();
+        let output = serde_json::to_string(&ProblemsResponse { problems }).unwrap();
+        Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_JSON)
+                .body(Body::from(output))
+                .unwrap(),
+        ))
+    }
+}
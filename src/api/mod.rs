@@ -14,40 +14,102 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
-use futures::future;
+use futures::future::Either;
+use futures::{future, Future};
 use hyper::{header, Body, Method, Request, Response};
+use lazy_static::lazy_static;
+use log::info;
 use serde::Serialize;
 use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
 
 use crate::api::auth::ApiAuth;
+use crate::api::caches::ApiCaches;
+use crate::api::config::ApiConfig;
 use crate::api::datastores::ApiDataStores;
+use crate::api::dialect::ApiDialect;
+#[cfg(feature = "fault-injection")]
+use crate::api::faults::ApiFaults;
+use crate::api::jobs::ApiJobs;
+use crate::api::loadtest::ApiLoadTest;
 use crate::api::logs::ApiLogs;
+use crate::api::me::ApiMe;
+use crate::api::meta::ApiMeta;
+use crate::api::queries::ApiQueries;
+use crate::api::status::ApiStatus;
 use crate::api::tokens::ApiTokens;
-use crate::config::Config;
-use crate::http::{return_401, return_404, HeaderToken, Http, ResponseFuture};
+use crate::api::usage::ApiUsage;
+use crate::api::version::ApiVersion;
+use crate::config::{AuthProviders, Config};
+use crate::constants::{
+    ALIASES_SUFFIX, APP_JSON, CHECKPOINT_SUFFIX, MAINTENANCE_SUFFIX, PURGE_SUFFIX, RESTORE_SUFFIX,
+};
+use crate::http::{
+    client_ip, return_401, return_404, return_429, HeaderToken, Http, ResponseFuture,
+};
+use crate::identity::{authenticate, credentials_from_request, role_for, Credentials, Role};
+use crate::ingest::IngestBuffers;
+use crate::lockout::{locked_for, lockout_keys, login_lockout_keys, record_failure, record_login};
 
 pub mod auth;
+pub mod caches;
+pub mod config;
 pub mod datastores;
+pub mod dialect;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+pub mod jobs;
+pub mod loadtest;
 pub mod logs;
+pub mod me;
+pub mod meta;
+pub mod queries;
+pub mod status;
 pub mod tokens;
+pub mod usage;
+pub mod version;
 
 pub struct Api {
     config: Arc<RwLock<Config>>,
+    // maintenance of the logs accounts for the objects it changes on them
+    ingest_buffers: Arc<IngestBuffers>,
 }
 
 impl Api {
-    pub fn new(cfg: Arc<RwLock<Config>>) -> Api {
-        Api { config: cfg }
+    pub fn new(cfg: Arc<RwLock<Config>>, ingest_buffers: Arc<IngestBuffers>) -> Api {
+        Api {
+            config: cfg,
+            ingest_buffers,
+        }
     }
 
     /// Routes a request to the proper module, or returns a 404 if nothing is matched.
     pub fn router(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
         // validate access token on headers
         let http_c = Http::new(Arc::clone(&self.config));
-        match http_c.validate_token_from_header(&req) {
+        let header_token = http_c.validate_token_from_header(&req);
+        // without a token, users can log in with the identity providers if there are any
+        if header_token == HeaderToken::NoToken {
+            let providers = self.config.read().unwrap().server.auth_providers.clone();
+            if let (Some(providers), Some(credentials)) =
+                (providers, credentials_from_request(&req))
+            {
+                return self.provider_router(req, providers, credentials);
+            }
+        }
+        match header_token {
             HeaderToken::Token(token) => {
+                // any valid token can inspect its own permissions and the SQL it can search with
+                if path_parts.get(1) == Some(&"me") {
+                    let me = ApiMe::new(Arc::clone(&self.config));
+                    return me.route(req, &token);
+                }
+                if path_parts.get(1) == Some(&"dialect") {
+                    let dialect = ApiDialect::new();
+                    return dialect.route(req);
+                }
                 //validate the token is admin
                 let read_cfg = self.config.read().unwrap();
                 match read_cfg.tokens.get(&token[0..16]) {
@@ -67,30 +129,185 @@ impl Api {
             HeaderToken::NoToken => {
                 return Box::new(future::ok(return_401()));
             }
+            HeaderToken::LockedOut(secs) => {
+                return Box::new(future::ok(return_429(secs)));
+            }
         }
+        self.dispatch(req, path_parts)
+    }
+
+    /// Routes a request authenticated by an identity provider, once the provider accepts its
+    /// credentials and the groups of the user are mapped to a role allowing it.
+    fn provider_router(
+        &self,
+        req: Request<Body>,
+        providers: AuthProviders,
+        credentials: Credentials,
+    ) -> ResponseFuture {
+        // failed logins count towards the lockout of the user, or of the client for bearer tokens
+        let client = client_ip(&req, &self.config.read().unwrap().server.trusted_proxies);
+        let keys = match &credentials {
+            Credentials::Basic { username, .. } => login_lockout_keys(client, username),
+            Credentials::Bearer(_) => lockout_keys(client, ""),
+        };
+        if let Some(wait) = locked_for(&keys) {
+            return Box::new(future::ok(return_429(wait.as_secs() + 1)));
+        }
+        let cfg = Arc::clone(&self.config);
+        let ingest_buffers = Arc::clone(&self.ingest_buffers);
+        Box::new(authenticate(&providers, &credentials).then(move |res| {
+            let identity = match res {
+                Ok(identity) => {
+                    record_login(&keys);
+                    identity
+                }
+                Err(e) => {
+                    info!("Identity provider login failed: {}", e);
+                    record_failure(&keys);
+                    return Either::A(future::ok(return_401()));
+                }
+            };
+            let allowed = match role_for(&providers.role_mapping, &identity.groups) {
+                Some(Role::Admin) => true,
+                Some(Role::Viewer) => req.method() == Method::GET,
+                None => false,
+            };
+            if !allowed {
+                info!(
+                    "{} has no role allowing {} {}",
+                    identity.subject,
+                    req.method(),
+                    req.uri().path()
+                );
+                return Either::A(future::ok(return_401()));
+            }
+            let path = String::from(&req.uri().path()[1..]);
+            let path_parts: Vec<&str> = path.split("/").collect();
+            Either::B(Api::new(cfg, ingest_buffers).dispatch(req, path_parts))
+        }))
+    }
+
+    /// Delegates an authorized request to the module of its path
+    fn dispatch(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
         match path_parts.get(1) {
             // delegate to proper module
             Some(&"auth") => {
                 let auths = ApiAuth::new(Arc::clone(&self.config));
                 auths.route(req, path_parts)
             }
+            Some(&"caches") => {
+                let caches = ApiCaches::new();
+                caches.route(req, path_parts)
+            }
+            Some(&"config") => {
+                let config = ApiConfig::new(Arc::clone(&self.config));
+                config.route(req, path_parts)
+            }
             Some(&"datastores") => {
                 let datastores = ApiDataStores::new(Arc::clone(&self.config));
                 datastores.route(req, path_parts)
             }
+            Some(&"dialect") => {
+                let dialect = ApiDialect::new();
+                dialect.route(req)
+            }
+            #[cfg(feature = "fault-injection")]
+            Some(&"faults") => {
+                let faults = ApiFaults::new();
+                faults.route(req, path_parts)
+            }
+            Some(&"jobs") => {
+                let jobs = ApiJobs::new(Arc::clone(&self.config));
+                jobs.route(req, path_parts)
+            }
+            Some(&"loadtest") => {
+                let loadtest =
+                    ApiLoadTest::new(Arc::clone(&self.config), Arc::clone(&self.ingest_buffers));
+                loadtest.route(req)
+            }
             Some(&"logs") => {
                 let logs = ApiLogs::new(Arc::clone(&self.config));
-                logs.route(req, path_parts)
+                // `POST /api/logs/{log}/maintenance` runs the background jobs of a log right away
+                let is_post = req.method() == Method::POST;
+                let is_get = req.method() == Method::GET;
+                let pk = path_pk(&path_parts, 2);
+                // the suffixed routes don't apply to a log named like them, ie: `team/aliases`
+                let is_log = pk
+                    .as_ref()
+                    .map_or(false, |pk| self.config.read().unwrap().log.contains_key(pk));
+                match pk {
+                    Some(_) if is_log => logs.route(req, path_parts),
+                    Some(ref pk) if is_post && pk.ends_with(MAINTENANCE_SUFFIX) => {
+                        let log_name = &pk[..pk.len() - MAINTENANCE_SUFFIX.len()];
+                        logs.maintenance(req, log_name, Arc::clone(&self.ingest_buffers))
+                    }
+                    // `POST /api/logs/{log}/purge` removes the lines matching a condition
+                    Some(ref pk) if is_post && pk.ends_with(PURGE_SUFFIX) => {
+                        let log_name = &pk[..pk.len() - PURGE_SUFFIX.len()];
+                        logs.purge(req, log_name, Arc::clone(&self.ingest_buffers))
+                    }
+                    // `POST /api/logs/{log}/restore` puts a deleted log back
+                    Some(ref pk) if is_post && pk.ends_with(RESTORE_SUFFIX) => {
+                        logs.restore(&pk[..pk.len() - RESTORE_SUFFIX.len()])
+                    }
+                    // `GET /api/logs/{log}/checkpoint` tells how far accepted batches were written
+                    Some(ref pk) if is_get && pk.ends_with(CHECKPOINT_SUFFIX) => logs.checkpoint(
+                        &pk[..pk.len() - CHECKPOINT_SUFFIX.len()],
+                        Arc::clone(&self.ingest_buffers),
+                    ),
+                    // `GET|PUT /api/logs/{log}/aliases` manages the other names of a log
+                    Some(ref pk) if pk.ends_with(ALIASES_SUFFIX) => {
+                        logs.aliases(req, &pk[..pk.len() - ALIASES_SUFFIX.len()])
+                    }
+                    _ => logs.route(req, path_parts),
+                }
+            }
+            Some(&"meta") => {
+                let meta = ApiMeta::new();
+                meta.route(req, path_parts)
+            }
+            Some(&"queries") => {
+                let queries = ApiQueries::new();
+                queries.route(req, path_parts)
+            }
+            Some(&"status") => {
+                let status = ApiStatus::new(Arc::clone(&self.config));
+                status.route(req)
             }
             Some(&"tokens") => {
                 let auths = ApiTokens::new(Arc::clone(&self.config));
                 auths.route(req, path_parts)
             }
+            Some(&"usage") => {
+                let usage = ApiUsage::new(Arc::clone(&self.config));
+                usage.route(req, path_parts)
+            }
+            Some(&"version") => {
+                let version = ApiVersion::new(Arc::clone(&self.config));
+                version.route(req)
+            }
             _ => Box::new(future::ok(return_404())),
         }
     }
 }
 
+/// Joins the path parts from `start` onwards into a primary key, `None` if there are none.
+pub fn path_pk(path_parts: &[&str], start: usize) -> Option<String> {
+    if path_parts.len() > start {
+        Some(path_parts[start..].join("/"))
+    } else {
+        None
+    }
+}
+
+/// A response with an object as its JSON body
+pub fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .body(Body::from(serde_json::to_string(value).unwrap()))
+        .unwrap()
+}
+
 /// Standard REST behavior.
 pub trait ViewSet {
     // Fulfills a GET operation, which should list items
@@ -106,13 +323,15 @@ pub trait ViewSet {
 
     /// route request.
     fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
-        match (req.method(), path_parts.get(2)) {
+        // primary keys may span several parts, ie: hierarchical log names
+        let pk = path_pk(&path_parts, 2);
+        match (req.method(), pk) {
             // delegate to proper action
             (&Method::GET, None) => self.list(req),
             (&Method::POST, None) => self.create(req),
-            (&Method::GET, Some(pk)) => self.retrieve(req, pk),
-            (&Method::PUT, Some(pk)) => self.update(req, pk),
-            (&Method::DELETE, Some(pk)) => self.delete(req, pk),
+            (&Method::GET, Some(pk)) => self.retrieve(req, &pk),
+            (&Method::PUT, Some(pk)) => self.update(req, &pk),
+            (&Method::DELETE, Some(pk)) => self.delete(req, &pk),
             _ => Box::new(future::ok(return_404())),
         }
     }
@@ -133,6 +352,22 @@ pub trait ViewSet {
         Box::new(future::ok(response.body(body).unwrap()))
     }
 
+    /// Builds a json response for an object `T` along with its `ETag`, computed before the
+    /// sensitive data was cleared.
+    fn build_tagged_response<T>(&self, mut obj: T, etag: &str) -> ResponseFuture
+    where
+        T: Serialize,
+        T: SafeOutput,
+    {
+        obj.safe();
+        let output = serde_json::to_string(&obj).unwrap();
+        let body = Body::from(output);
+        let mut response = Response::builder();
+        response.header(header::CONTENT_TYPE, "application/json");
+        response.header(header::ETAG, etag);
+        Box::new(future::ok(response.body(body).unwrap()))
+    }
+
     /// Takes a list of objects, the request and returns a sublist of items (aka page)
     fn paginate<T>(&self, request: Request<Body>, obj: Vec<T>) -> ListResponse<T>
     where
@@ -176,6 +411,88 @@ pub trait ViewSet {
     }
 }
 
+lazy_static! {
+    // Configuration objects with an update being written, by metabucket key
+    static ref UPDATES_IN_FLIGHT: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Entity tag of a configuration object, a SHA-256 of its JSON with the keys of every map sorted,
+/// so it only changes when the object does and is the same on every node.
+pub fn etag_for<T: Serialize>(obj: &T) -> String {
+    // maps of `serde_json::Value` are sorted by key
+    let canonical = serde_json::to_value(obj).unwrap().to_string();
+    let digest = Sha256::digest(canonical.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", &hex[..32])
+}
+
+/// Whether the `If-Match` header of an update doesn't match the current version of the object,
+/// so the update is based on a stale copy. Updates without the header are always accepted.
+pub fn if_match_fails<T: Serialize>(req: &Request<Body>, current: Option<&T>) -> bool {
+    let if_match = match req.headers().get(header::IF_MATCH) {
+        Some(value) => value.to_str().unwrap_or(""),
+        None => return false,
+    };
+    let etag = match current {
+        Some(obj) => etag_for(obj),
+        None => return true,
+    };
+    !if_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// An update of a configuration object being written, conditional updates of the object are
+/// rejected until it's dropped.
+pub struct UpdateClaim {
+    key: String,
+}
+
+impl Drop for UpdateClaim {
+    fn drop(&mut self) {
+        let mut in_flight = UPDATES_IN_FLIGHT.lock().unwrap();
+        let done = match in_flight.get_mut(&self.key) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if done {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Claims the update of the configuration object stored at `key`, `None` if its `If-Match`
+/// precondition fails. The precondition is checked under the configuration write lock, and fails
+/// as well while another update of the object is being written, since the copy it was based on
+/// is about to be replaced. The writer applies the version it wrote to the configuration before
+/// dropping the claim, so no precondition is ever checked against a replaced version.
+pub fn claim_update<T, F>(
+    cfg: &Arc<RwLock<Config>>,
+    req: &Request<Body>,
+    key: &str,
+    current: F,
+) -> Option<UpdateClaim>
+where
+    T: Serialize,
+    F: for<'a> FnOnce(&'a Config) -> Option<&'a T>,
+{
+    let cfg_write = cfg.write().unwrap();
+    let mut in_flight = UPDATES_IN_FLIGHT.lock().unwrap();
+    if req.headers().contains_key(header::IF_MATCH)
+        && (in_flight.contains_key(key) || if_match_fails(req, current(&cfg_write)))
+    {
+        return None;
+    }
+    *in_flight.entry(key.to_string()).or_insert(0) += 1;
+    Some(UpdateClaim {
+        key: key.to_string(),
+    })
+}
+
 /// Trait that mandates content be cleared of any sensitive information (secret_key, password, etc)
 pub trait SafeOutput {
     /// Clears the struct of any sensitive data.
@@ -205,3 +522,132 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod api_tests {
+    use futures::Stream;
+
+    use crate::config::{Log, Server};
+
+    use super::*;
+
+    fn log(commit_window: &str) -> Log {
+        Log {
+            name: Some("mylog".to_string()),
+            datastores: vec!["ds1".to_string()],
+            commit_window: commit_window.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn update_request(if_match: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+        builder.method(Method::PUT).uri("/api/logs/mylog");
+        if let Some(tag) = if_match {
+            builder.header(header::IF_MATCH, tag);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn logs_named_like_a_route_are_retrieved() {
+        let mut logs = HashMap::new();
+        for name in &["team", "team/aliases", "team/checkpoint"] {
+            let mut log = log("5s");
+            log.name = Some(name.to_string());
+            logs.insert(name.to_string(), log);
+        }
+        let cfg = Arc::new(RwLock::new(Config {
+            server: Server::default(),
+            datastore: HashMap::new(),
+            log: logs,
+            tokens: HashMap::new(),
+            auth: HashMap::new(),
+            patterns: HashMap::new(),
+        }));
+        let api = Api::new(cfg, Arc::new(IngestBuffers::new()));
+        for name in &["team/aliases", "team/checkpoint"] {
+            let uri = format!("/api/logs/{}", name);
+            let req = Request::get(&uri[..]).body(Body::empty()).unwrap();
+            let parts: Vec<&str> = uri[1..].split('/').collect();
+            let response = api.dispatch(req, parts).wait().unwrap();
+            assert_eq!(response.status(), 200);
+            let body = response.into_body().concat2().wait().unwrap();
+            let log: Log = serde_json::from_slice(&body).unwrap();
+            assert_eq!(log.name.as_ref().map(String::as_str), Some(*name));
+        }
+    }
+
+    #[test]
+    fn etag_changes_with_the_object() {
+        assert_eq!(etag_for(&log("5s")), etag_for(&log("5s")));
+        assert_ne!(etag_for(&log("5s")), etag_for(&log("10s")));
+    }
+
+    #[test]
+    fn if_match_preconditions() {
+        let current = log("5s");
+        let etag = etag_for(&current);
+        // updates without preconditions are always accepted
+        assert!(!if_match_fails(&update_request(None), Some(&current)));
+        assert!(!if_match_fails(
+            &update_request(Some(&etag)),
+            Some(&current)
+        ));
+        assert!(!if_match_fails(&update_request(Some("*")), Some(&current)));
+        // stale copy
+        let stale = etag_for(&log("10s"));
+        assert!(if_match_fails(
+            &update_request(Some(&stale)),
+            Some(&current)
+        ));
+        // the object is gone
+        assert!(if_match_fails(&update_request(Some(&etag)), None::<&Log>));
+    }
+
+    #[test]
+    fn etag_ignores_map_order() {
+        let mut first = log("5s");
+        let mut second = log("5s");
+        for (k, v) in &[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")] {
+            first.loki_labels.insert(k.to_string(), v.to_string());
+        }
+        for (k, v) in &[("d", "4"), ("c", "3"), ("b", "2"), ("a", "1")] {
+            second.loki_labels.insert(k.to_string(), v.to_string());
+        }
+        assert_eq!(etag_for(&first), etag_for(&second));
+        assert_eq!(etag_for(&first).len(), 34);
+    }
+
+    #[test]
+    fn conditional_updates_wait_for_the_one_in_flight() {
+        let mut logs = HashMap::new();
+        logs.insert("mylog".to_string(), log("5s"));
+        let cfg = Arc::new(RwLock::new(Config {
+            server: Server::default(),
+            datastore: HashMap::new(),
+            log: logs,
+            tokens: HashMap::new(),
+            auth: HashMap::new(),
+            patterns: HashMap::new(),
+        }));
+        let key = "minsql/meta/logs/mylog";
+        let etag = etag_for(&log("5s"));
+        let claim = claim_update(&cfg, &update_request(Some(&etag)), key, |c| {
+            c.log.get("mylog")
+        });
+        assert!(claim.is_some());
+        // another update based on the same copy
+        assert!(claim_update(&cfg, &update_request(Some(&etag)), key, |c| c
+            .log
+            .get("mylog"))
+        .is_none());
+        // unconditional updates are always accepted
+        assert!(claim_update(&cfg, &update_request(None), key, |c| c.log.get("mylog")).is_some());
+        drop(claim);
+        assert!(claim_update(&cfg, &update_request(Some(&etag)), key, |c| c
+            .log
+            .get("mylog"))
+        .is_some());
+    }
+}
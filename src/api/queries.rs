@@ -0,0 +1,55 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future;
+use hyper::{Body, Method, Request};
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+use crate::api::json_response;
+use crate::http::{return_404, ResponseFuture};
+use crate::queries::{cancel_query, running_queries, RunningQuery};
+
+pub struct ApiQueries {}
+
+#[derive(Serialize)]
+struct QueriesResponse {
+    queries: Vec<RunningQuery>,
+}
+
+impl ApiQueries {
+    pub fn new() -> ApiQueries {
+        ApiQueries {}
+    }
+
+    /// `GET /api/queries` lists the searches running on this process and
+    /// `DELETE /api/queries/{id}` cancels one.
+    pub fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
+        let response = match (req.method(), path_parts.get(2)) {
+            (&Method::GET, None) => json_response(&QueriesResponse {
+                queries: running_queries(),
+            }),
+            // ids are uuids, anything else can't be a query
+            (_, Some(id)) if Uuid::parse_str(id).is_err() => return_404(),
+            (&Method::DELETE, Some(id)) => match cancel_query(id) {
+                Some(query) => json_response(&query),
+                None => return_404(),
+            },
+            _ => return_404(),
+        };
+        Box::new(future::ok(response))
+    }
+}
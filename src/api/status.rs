@@ -0,0 +1,88 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures::future;
+use hyper::{header, Body, Method, Request, Response};
+use serde_derive::Serialize;
+
+use crate::config::Config;
+use crate::constants::APP_JSON;
+use crate::http::{return_404, ResponseFuture};
+use crate::latency::{latency_table, DataStoreLatency};
+
+pub struct ApiStatus {
+    config: Arc<RwLock<Config>>,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    // GET latency of every configured datastore, `None` until it's read from
+    datastores: HashMap<String, Option<DataStoreLatency>>,
+    // configuration objects loaded against their limits
+    config: HashMap<&'static str, ConfigCount>,
+}
+
+#[derive(Serialize)]
+struct ConfigCount {
+    count: usize,
+    // `0` for no limit
+    limit: usize,
+}
+
+impl ApiStatus {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> ApiStatus {
+        ApiStatus { config: cfg }
+    }
+
+    /// Only `GET /api/status` is supported
+    pub fn route(&self, req: Request<Body>) -> ResponseFuture {
+        match req.method() {
+            &Method::GET => self.status(),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn status(&self) -> ResponseFuture {
+        let mut latencies = latency_table();
+        let read_cfg = self.config.read().unwrap();
+        let datastores = read_cfg
+            .datastore
+            .keys()
+            .map(|ds_name| (ds_name.clone(), latencies.remove(ds_name)))
+            .collect();
+        let count = |count, limit| ConfigCount { count, limit };
+        let mut config = HashMap::new();
+        config.insert("logs", count(read_cfg.log.len(), read_cfg.server.max_logs));
+        config.insert(
+            "tokens",
+            count(read_cfg.tokens.len(), read_cfg.server.max_tokens),
+        );
+        config.insert(
+            "datastores",
+            count(read_cfg.datastore.len(), read_cfg.server.max_datastores),
+        );
+        let output = serde_json::to_string(&StatusResponse { datastores, config }).unwrap();
+        Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_JSON)
+                .body(Body::from(output))
+                .unwrap(),
+        ))
+    }
+}
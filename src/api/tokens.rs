@@ -23,9 +23,13 @@ use hyper::{header, Body, Chunk, Request, Response};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
-use crate::api::{SafeOutput, ViewSet};
-use crate::config::{Config, Token};
-use crate::http::{return_400, return_404, return_500, ResponseFuture};
+use crate::api::{claim_update, etag_for, SafeOutput, ViewSet};
+use crate::cidr::Cidr;
+use crate::config::{limit_reached, Config, Token};
+use crate::constants::SMART_FIELDS;
+use crate::http::{return_400, return_404, return_412, return_500, ResponseFuture};
+use crate::meta::apply_config_object;
+use crate::secrets::{hash_secret, is_hashed, verify_secret};
 use crate::storage::{delete_object_metabucket, put_object_metabucket};
 
 pub struct ApiTokens {
@@ -63,6 +67,8 @@ impl ApiTokens {
             is_admin: false,
             enabled: true,
             api_access: false,
+            ip_allowlist: Vec::new(),
+            redact: Vec::new(),
         };
 
         let token: serde_json::Value = match serde_json::from_str(&payload) {
@@ -96,6 +102,14 @@ impl ApiTokens {
             new_token.enabled = enabled.clone();
         }
 
+        if let Some(ip_allowlist) = token.get("ip_allowlist") {
+            new_token.ip_allowlist = parse_ip_allowlist(ip_allowlist)?;
+        }
+
+        if let Some(redact) = token.get("redact") {
+            new_token.redact = parse_redact(redact)?;
+        }
+
         // Validate Access/Secret
         if new_token.access_key == "" || new_token.secret_key == "" {
             // auto generate a token access_key
@@ -123,6 +137,8 @@ impl ApiTokens {
                 "Access/Secret key has an invalid length. (Access 16, Secret 32)",
             ));
         }
+        // only a salted hash of the secret is kept
+        new_token.secret_key = hash_secret(&new_token.secret_key);
 
         let cfg_read = cfg.read().unwrap();
 
@@ -166,7 +182,7 @@ impl ApiTokens {
             }
         }
         if let Some(serde_json::Value::String(secret_key)) = token.get("secret_key") {
-            if *secret_key != current_token.secret_key {
+            if !verify_secret(&current_token.secret_key, secret_key) {
                 return Err(return_400("Secret Key cannot be changed."));
             }
         }
@@ -186,10 +202,69 @@ impl ApiTokens {
         if let Some(serde_json::Value::Bool(enabled)) = token.get("enabled") {
             current_token.enabled = enabled.clone();
         }
+
+        // an empty or null allowlist lets the token be used from anywhere
+        if let Some(ip_allowlist) = token.get("ip_allowlist") {
+            current_token.ip_allowlist = parse_ip_allowlist(ip_allowlist)?;
+        }
+
+        // an empty or null list returns the values as they are
+        if let Some(redact) = token.get("redact") {
+            current_token.redact = parse_redact(redact)?;
+        }
+
+        // legacy plaintext secrets are hashed along with any update
+        if !is_hashed(&current_token.secret_key) {
+            current_token.secret_key = hash_secret(&current_token.secret_key);
+        }
         Ok(current_token)
     }
 }
 
+/// Parses the networks of a token allowlist, in CIDR notation
+fn parse_ip_allowlist(value: &serde_json::Value) -> Result<Vec<String>, Response<Body>> {
+    let networks = match value {
+        serde_json::Value::Null => return Ok(Vec::new()),
+        serde_json::Value::Array(networks) => networks,
+        _ => return Err(return_400("ip_allowlist must be a list of networks")),
+    };
+    let mut allowlist = Vec::new();
+    for network in networks {
+        match network {
+            serde_json::Value::String(network) => {
+                Cidr::parse(network).map_err(|e| return_400(&e))?;
+                allowlist.push(network.trim().to_string());
+            }
+            _ => return Err(return_400("ip_allowlist must be a list of networks")),
+        }
+    }
+    Ok(allowlist)
+}
+
+/// Parses the smart fields a token gets masked
+fn parse_redact(value: &serde_json::Value) -> Result<Vec<String>, Response<Body>> {
+    let fields = match value {
+        serde_json::Value::Null => return Ok(Vec::new()),
+        serde_json::Value::Array(fields) => fields,
+        _ => return Err(return_400("redact must be a list of smart fields")),
+    };
+    let mut redact = Vec::new();
+    for field in fields {
+        match field {
+            serde_json::Value::String(field) if SMART_FIELDS.contains(&&field[..]) => {
+                if !redact.contains(field) {
+                    redact.push(field.clone());
+                }
+            }
+            serde_json::Value::String(field) => {
+                return Err(return_400(&format!("{} is not a smart field", field)));
+            }
+            _ => return Err(return_400("redact must be a list of smart fields")),
+        }
+    }
+    Ok(redact)
+}
+
 impl ViewSet for ApiTokens {
     fn list(&self, req: Request<Body>) -> ResponseFuture {
         let cfg_read = self.config.read().unwrap();
@@ -207,6 +282,15 @@ impl ViewSet for ApiTokens {
     }
 
     fn create(&self, req: Request<Body>) -> ResponseFuture {
+        let read_cfg = self.config.read().unwrap();
+        if limit_reached(read_cfg.tokens.len(), read_cfg.server.max_tokens) {
+            let msg = format!(
+                "The limit of {} tokens was reached",
+                read_cfg.server.max_tokens
+            );
+            return Box::new(future::ok(return_400(&msg)));
+        }
+        drop(read_cfg);
         let cfg = Arc::clone(&self.config);
         let cfg2 = Arc::clone(&self.config);
         Box::new(
@@ -234,7 +318,10 @@ impl ViewSet for ApiTokens {
 
                                     future::ok(response.body(body).unwrap())
                                 }
-                                Err(_) => future::ok(return_500("error saving new token")),
+                                Err(e) => future::ok(return_500(&format!(
+                                    "Could not save the token: {}",
+                                    e.reason()
+                                ))),
                             });
                             Either::A(resp)
                         }
@@ -246,17 +333,24 @@ impl ViewSet for ApiTokens {
 
     fn retrieve(&self, _req: Request<Body>, pk: &str) -> ResponseFuture {
         let cfg_read = self.config.read().unwrap();
-        let mut token = match cfg_read.tokens.get(pk) {
+        let token = match cfg_read.tokens.get(pk) {
             Some(ds) => ds.clone(),
             None => {
                 return Box::new(future::ok(return_404()));
             }
         };
-        token.safe();
-        self.build_response(token)
+        // the tag is sent back on `If-Match` to update this version of the token
+        let etag = etag_for(&token);
+        self.build_tagged_response(token, &etag)
     }
 
     fn update(&self, req: Request<Body>, pk: &str) -> ResponseFuture {
+        // reject updates based on a stale copy of the token
+        let key = format!("minsql/meta/tokens/{}", pk);
+        let claim = match claim_update(&self.config, &req, &key, |c| c.tokens.get(pk)) {
+            Some(claim) => claim,
+            None => return Box::new(future::ok(return_412())),
+        };
         let pk = pk.to_string();
         let cfg = Arc::clone(&self.config);
         let cfg2 = Arc::clone(&self.config);
@@ -269,12 +363,21 @@ impl ViewSet for ApiTokens {
                         Ok(mut current_token) => {
                             // everything seems ok, write to token
                             let ds_serialized = serde_json::to_string(&current_token).unwrap();
+                            let etag = etag_for(&current_token);
                             let res = put_object_metabucket(
-                                cfg2,
-                                format!("minsql/meta/tokens/{}", pk),
+                                Arc::clone(&cfg2),
+                                key.clone(),
                                 ds_serialized.clone(),
                             )
-                            .map_err(|_| {})
+                            .then(move |v| {
+                                // applied before the claim is released, so the next
+                                // precondition is checked against this version
+                                if v.is_ok() {
+                                    apply_config_object(&cfg2, &key, ds_serialized.as_bytes());
+                                }
+                                drop(claim);
+                                v
+                            })
                             .then(move |v| match v {
                                 Ok(_) => {
                                     //remove sensitive data
@@ -285,10 +388,14 @@ impl ViewSet for ApiTokens {
                                     let body = Body::from(Chunk::from(ds_serialized));
                                     let mut response = Response::builder();
                                     response.header(header::CONTENT_TYPE, "application/json");
+                                    response.header(header::ETAG, etag);
 
                                     future::ok(response.body(body).unwrap())
                                 }
-                                Err(_) => future::ok(return_500("error saving token")),
+                                Err(e) => future::ok(return_500(&format!(
+                                    "Could not save the token: {}",
+                                    e.reason()
+                                ))),
                             });
                             Either::A(res)
                         }
@@ -311,9 +418,8 @@ impl ViewSet for ApiTokens {
 
         let cfg = Arc::clone(&self.config);
         Box::new(
-            delete_object_metabucket(cfg, format!("minsql/meta/tokens/{}", token_access_key))
-                .map_err(|_| {})
-                .then(move |v| match v {
+            delete_object_metabucket(cfg, format!("minsql/meta/tokens/{}", token_access_key)).then(
+                move |v| match v {
                     Ok(_) => {
                         //remove sensitive data
                         token.safe();
@@ -324,8 +430,12 @@ impl ViewSet for ApiTokens {
 
                         future::ok(response.body(body).unwrap())
                     }
-                    Err(_) => future::ok(return_500("Error deleting token from storage")),
-                }),
+                    Err(e) => future::ok(return_500(&format!(
+                        "Could not delete the token: {}",
+                        e.reason()
+                    ))),
+                },
+            ),
         )
     }
 }
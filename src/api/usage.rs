@@ -0,0 +1,97 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use hyper::{header, Body, Method, Request, Response};
+use serde_derive::Serialize;
+
+use crate::config::Config;
+use crate::constants::APP_JSON;
+use crate::http::{return_400, return_404, return_500, GenericError, ResponseFuture};
+use crate::usage::{
+    current_period, group_usage, usage_in_period, valid_period, UsageGroup, UsageRow,
+};
+
+pub struct ApiUsage {
+    config: Arc<RwLock<Config>>,
+}
+
+#[derive(Serialize)]
+struct UsageResponse {
+    period: String,
+    group_by: String,
+    usage: Vec<UsageRow>,
+}
+
+impl ApiUsage {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> ApiUsage {
+        ApiUsage { config: cfg }
+    }
+
+    /// `GET /api/usage?group_by=token|log&period=2019-08` reports the bytes scanned and stored
+    /// over a month or a day, `2019-08-01`, by every process. Defaults to the current month per
+    /// log.
+    pub fn route(&self, req: Request<Body>, path_parts: Vec<&str>) -> ResponseFuture {
+        match (req.method(), path_parts.get(2)) {
+            (&Method::GET, None) => self.list(&req),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn list(&self, req: &Request<Body>) -> ResponseFuture {
+        let mut group_by = "log".to_string();
+        let mut period = current_period();
+        if let Some(query) = req.uri().query() {
+            for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+                match &key[..] {
+                    "group_by" => group_by = value.to_string(),
+                    "period" => period = value.to_string(),
+                    _ => (),
+                }
+            }
+        }
+        let group = match UsageGroup::parse(&group_by) {
+            Some(group) => group,
+            None => return Box::new(future::ok(return_400("group_by must be `log` or `token`"))),
+        };
+        if !valid_period(&period) {
+            return Box::new(future::ok(return_400(
+                "period must be a month, `2019-08`, or a day, `2019-08-01`",
+            )));
+        }
+        Box::new(
+            usage_in_period(Arc::clone(&self.config), period.clone()).then(
+                move |res| -> Result<_, GenericError> {
+                    let days = match res {
+                        Ok(days) => days,
+                        Err(e) => return Ok(return_500(&e)),
+                    };
+                    let output = UsageResponse {
+                        period,
+                        group_by,
+                        usage: group_usage(&days, group),
+                    };
+                    Ok(Response::builder()
+                        .header(header::CONTENT_TYPE, APP_JSON)
+                        .body(Body::from(serde_json::to_string(&output).unwrap()))
+                        .unwrap())
+                },
+            ),
+        )
+    }
+}
@@ -0,0 +1,54 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use futures::future;
+use hyper::{header, Body, Method, Request, Response};
+
+use crate::config::Config;
+use crate::constants::APP_JSON;
+use crate::http::{return_404, ResponseFuture};
+use crate::version::build_info;
+
+pub struct ApiVersion {
+    config: Arc<RwLock<Config>>,
+}
+
+impl ApiVersion {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> ApiVersion {
+        ApiVersion { config: cfg }
+    }
+
+    /// Only `GET /api/version` is supported
+    pub fn route(&self, req: Request<Body>) -> ResponseFuture {
+        match req.method() {
+            &Method::GET => self.version(),
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    fn version(&self) -> ResponseFuture {
+        let info = build_info(&self.config.read().unwrap());
+        let output = serde_json::to_string(&info).unwrap();
+        Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, APP_JSON)
+                .body(Body::from(output))
+                .unwrap(),
+        ))
+    }
+}
@@ -14,26 +14,106 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::config::Config;
+use crate::config::{Config, LogAuth};
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
 pub struct Auth {
     config: Arc<RwLock<Config>>,
 }
 
+/// Whether a token may use a log. Logs missing from the configuration are told apart from those
+/// the token is not authorized to, which are answered with `404` and `403` respectively.
+#[derive(Debug, PartialEq)]
+pub enum LogAccess {
+    Granted,
+    Forbidden,
+    UnknownLog,
+}
+
+/// The access key of a token, its first 16 characters, if they're ASCII alphanumerics as every
+/// access key is
+pub fn access_key_of(token: &str) -> Option<&str> {
+    token
+        .get(0..16)
+        .filter(|key| key.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
+/// The grant a token has on a log: the grant on the log itself, otherwise the closest `{parent}/*`
+/// grant of a log it's nested under. A grant on `team` alone doesn't extend to `team/service`.
+fn closest_grant<'a>(grants: &'a HashMap<String, LogAuth>, log_name: &str) -> Option<&'a LogAuth> {
+    if let Some(grant) = grants.get(log_name) {
+        return Some(grant);
+    }
+    let mut parent = log_name;
+    while let Some(idx) = parent.rfind('/') {
+        parent = &parent[..idx];
+        if let Some(grant) = grants.get(&format!("{}/*", parent)) {
+            return Some(grant);
+        }
+    }
+    None
+}
+
 impl Auth {
     pub fn new(cfg: Arc<RwLock<Config>>) -> Auth {
         Auth { config: cfg }
     }
-    /// Checks the configuration hierarchy to validate if a token has access to a log
+    /// Checks the configuration hierarchy to validate if a token has access to a log. Logs
+    /// nested under another are only granted by a wildcard grant, ie: `team/*` grants
+    /// `team/service`, see `closest_grant`.
     pub fn token_has_access_to_log(&self, access_token: &str, log_name: &str) -> bool {
-        if access_token.len() < 16 {
-            return false;
+        let access_key = match access_key_of(access_token) {
+            Some(access_key) => access_key,
+            None => return false,
+        };
+        let cfg = self.config.read().unwrap();
+        match cfg.auth.get(access_key) {
+            Some(grants) => closest_grant(grants, log_name).is_some(),
+            None => false,
         }
+    }
+
+    /// Whether a token may create `log_name` by storing lines to it, its grant on the log or its
+    /// closest wildcard grant on a log it's nested under has to allow `store`
+    pub fn token_can_store_to(&self, access_token: &str, log_name: &str) -> bool {
+        let access_key = match access_key_of(access_token) {
+            Some(access_key) => access_key,
+            None => return false,
+        };
         let cfg = self.config.read().unwrap();
-        match cfg.auth.get(&access_token[0..16]) {
-            Some(val) => return val.contains_key(log_name),
+        match cfg
+            .auth
+            .get(access_key)
+            .and_then(|grants| closest_grant(grants, log_name))
+        {
+            Some(grant) => grant.api.iter().any(|api| api == "store"),
+            None => false,
+        }
+    }
+
+    /// Checks a token against a log named exactly `log_name`, aliases are not resolved
+    pub fn log_access(&self, access_token: &str, log_name: &str) -> LogAccess {
+        if !self.config.read().unwrap().log.contains_key(log_name) {
+            return LogAccess::UnknownLog;
+        }
+        if self.token_has_access_to_log(access_token, log_name) {
+            LogAccess::Granted
+        } else {
+            LogAccess::Forbidden
+        }
+    }
+
+    /// Whether the token is an enabled admin token
+    pub fn token_is_admin(&self, access_token: &str) -> bool {
+        let access_key = match access_key_of(access_token) {
+            Some(access_key) => access_key,
             None => return false,
+        };
+        let cfg = self.config.read().unwrap();
+        match cfg.tokens.get(access_key) {
+            Some(token) => token.is_admin && token.enabled,
+            None => false,
         }
     }
 }
@@ -42,7 +122,7 @@ impl Auth {
 mod auth_tests {
     use std::collections::HashMap;
 
-    use crate::config::{Config, LogAuth, Server};
+    use crate::config::{Config, Log, LogAuth, Server, Token};
 
     use super::*;
 
@@ -66,18 +146,14 @@ mod auth_tests {
 
         let cfg = Config {
             server: Server {
-                address: "".to_string(),
-                metadata_endpoint: "".to_string(),
-                metadata_bucket: "".to_string(),
-                access_key: "".to_string(),
-                secret_key: "".to_string(),
-                pkcs12_cert: None,
-                pkcs12_password: None,
+                query_memory_limit: 1024,
+                ..Server::default()
             },
             datastore: HashMap::new(),
             log: HashMap::new(),
             tokens: Default::default(),
             auth: auth,
+            patterns: HashMap::new(),
         };
         cfg
     }
@@ -127,6 +203,19 @@ mod auth_tests {
         })
     }
 
+    #[test]
+    fn multibyte_token() {
+        run_test_get_auth_config_for(TokenTestCase {
+            valid_token: VALID_TOKEN.to_string(),
+            valid_log_name: "mylog".to_string(),
+
+            token: format!("{}é{}", "T".repeat(15), "T".repeat(31)),
+            log_name: "mylog".to_string(),
+
+            expected: false,
+        })
+    }
+
     #[test]
     fn valid_token_invalid_log() {
         run_test_get_auth_config_for(TokenTestCase {
@@ -139,4 +228,139 @@ mod auth_tests {
             expected: false,
         })
     }
+
+    #[test]
+    fn wildcard_grants_nested_log() {
+        run_test_get_auth_config_for(TokenTestCase {
+            valid_token: VALID_TOKEN.to_string(),
+            valid_log_name: "team/*".to_string(),
+
+            token: VALID_TOKEN.to_string(),
+            log_name: "team/service/api".to_string(),
+
+            expected: true,
+        })
+    }
+
+    #[test]
+    fn parent_log_doesnt_grant_nested_log() {
+        run_test_get_auth_config_for(TokenTestCase {
+            valid_token: VALID_TOKEN.to_string(),
+            valid_log_name: "team".to_string(),
+
+            token: VALID_TOKEN.to_string(),
+            log_name: "team/service".to_string(),
+
+            expected: false,
+        })
+    }
+
+    #[test]
+    fn wildcard_doesnt_grant_parent_log() {
+        run_test_get_auth_config_for(TokenTestCase {
+            valid_token: VALID_TOKEN.to_string(),
+            valid_log_name: "team/*".to_string(),
+
+            token: VALID_TOKEN.to_string(),
+            log_name: "team".to_string(),
+
+            expected: false,
+        })
+    }
+
+    #[test]
+    fn nested_log_doesnt_grant_parent_log() {
+        run_test_get_auth_config_for(TokenTestCase {
+            valid_token: VALID_TOKEN.to_string(),
+            valid_log_name: "team/service".to_string(),
+
+            token: VALID_TOKEN.to_string(),
+            log_name: "team".to_string(),
+
+            expected: false,
+        })
+    }
+
+    #[test]
+    fn log_name_prefix_doesnt_grant_access() {
+        run_test_get_auth_config_for(TokenTestCase {
+            valid_token: VALID_TOKEN.to_string(),
+            valid_log_name: "team".to_string(),
+
+            token: VALID_TOKEN.to_string(),
+            log_name: "teammate".to_string(),
+
+            expected: false,
+        })
+    }
+
+    #[test]
+    fn admin_token() {
+        let mut cfg = get_auth_config_for(VALID_TOKEN.to_string(), "mylog".to_string());
+        let mut token = Token {
+            access_key: VALID_TOKEN[0..16].to_string(),
+            secret_key: VALID_TOKEN[16..].to_string(),
+            description: None,
+            is_admin: true,
+            enabled: true,
+            api_access: true,
+            ip_allowlist: Vec::new(),
+            redact: Vec::new(),
+        };
+        cfg.tokens
+            .insert(VALID_TOKEN[0..16].to_string(), token.clone());
+        let auth_c = Auth::new(Arc::new(RwLock::new(cfg.clone())));
+        assert!(auth_c.token_is_admin(VALID_TOKEN));
+        assert!(!auth_c.token_is_admin("INVALID"));
+
+        token.enabled = false;
+        cfg.tokens.insert(VALID_TOKEN[0..16].to_string(), token);
+        let auth_c = Auth::new(Arc::new(RwLock::new(cfg)));
+        assert!(!auth_c.token_is_admin(VALID_TOKEN));
+    }
+
+    #[test]
+    fn store_grants() {
+        let mut cfg = get_auth_config_for(VALID_TOKEN.to_string(), "team/*".to_string());
+        let auth_c = Auth::new(Arc::new(RwLock::new(cfg.clone())));
+        // searching the logs under the parent isn't enough to create them
+        assert!(auth_c.token_has_access_to_log(VALID_TOKEN, "team/service"));
+        assert!(!auth_c.token_can_store_to(VALID_TOKEN, "team/service"));
+
+        let grants = cfg.auth.get_mut(&VALID_TOKEN[0..16]).unwrap();
+        grants.get_mut("team/*").unwrap().api = vec!["search".to_string(), "store".to_string()];
+        let auth_c = Auth::new(Arc::new(RwLock::new(cfg.clone())));
+        assert!(auth_c.token_can_store_to(VALID_TOKEN, "team/service"));
+        assert!(auth_c.token_can_store_to(VALID_TOKEN, "team/service/eu"));
+        assert!(!auth_c.token_can_store_to(VALID_TOKEN, "teammate"));
+        assert!(!auth_c.token_can_store_to("TOKEN1ñ", "team/service"));
+
+        // storing to the parent itself doesn't extend to the logs under it
+        let grants = cfg.auth.get_mut(&VALID_TOKEN[0..16]).unwrap();
+        let mut grant = grants.remove("team/*").unwrap();
+        grant.log_name = "team".to_string();
+        grants.insert("team".to_string(), grant);
+        let auth_c = Auth::new(Arc::new(RwLock::new(cfg)));
+        assert!(auth_c.token_can_store_to(VALID_TOKEN, "team"));
+        assert!(!auth_c.token_can_store_to(VALID_TOKEN, "team/service"));
+    }
+
+    #[test]
+    fn unknown_and_forbidden_logs() {
+        let mut cfg = get_auth_config_for(VALID_TOKEN.to_string(), "mylog".to_string());
+        cfg.log.insert("mylog".to_string(), Log::default());
+        cfg.log.insert("otherlog".to_string(), Log::default());
+        let auth_c = Auth::new(Arc::new(RwLock::new(cfg)));
+        assert_eq!(auth_c.log_access(VALID_TOKEN, "mylog"), LogAccess::Granted);
+        assert_eq!(
+            auth_c.log_access(VALID_TOKEN, "otherlog"),
+            LogAccess::Forbidden
+        );
+        assert_eq!(auth_c.log_access("INVALID", "mylog"), LogAccess::Forbidden);
+        // unknown logs are not found whatever the token
+        assert_eq!(
+            auth_c.log_access(VALID_TOKEN, "nolog"),
+            LogAccess::UnknownLog
+        );
+    }
 }
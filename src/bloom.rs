@@ -0,0 +1,207 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+
+// Identifies a serialized `BloomFilter`, bump the version if the layout or hashing changes
+const BLOOM_MAGIC: &[u8; 4] = b"MSB1";
+// Size of the n-grams indexed, literals shorter than this can't be checked
+const NGRAM_LEN: usize = 3;
+// Target false positive rate of a single n-gram lookup
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Bloom filter over the byte trigrams of the lines of an object. Since every substring of a line
+/// is made of its trigrams, it can tell an object doesn't contain a literal.
+#[derive(Debug, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `items` entries
+    pub fn with_capacity(items: usize) -> BloomFilter {
+        let items = items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(items * FALSE_POSITIVE_RATE.ln()) / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / items) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; ((num_bits + 63) / 64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    /// Builds the filter of all the trigrams in `lines`
+    pub fn from_lines(lines: &[String]) -> BloomFilter {
+        let mut ngrams: HashSet<&[u8]> = HashSet::new();
+        for line in lines {
+            for ngram in line.as_bytes().windows(NGRAM_LEN) {
+                ngrams.insert(ngram);
+            }
+        }
+        let mut filter = BloomFilter::with_capacity(ngrams.len());
+        for ngram in ngrams {
+            filter.insert(ngram);
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        for bit in self.bit_indexes(item) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.bit_indexes(item)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Whether a line indexed by this filter may contain `literal`. A `false` is definitive.
+    pub fn may_contain_substring(&self, literal: &str) -> bool {
+        literal
+            .as_bytes()
+            .windows(NGRAM_LEN)
+            .all(|ngram| self.contains(ngram))
+    }
+
+    /// Bit positions of an item using double hashing
+    fn bit_indexes<'a>(&'a self, item: &[u8]) -> impl Iterator<Item = u64> + 'a {
+        let h1 = fnv1a(item, 0xcbf2_9ce4_8422_2325);
+        // an odd second hash so it can't collapse all the positions into one
+        let h2 = fnv1a(item, 0x6c62_272e_07bb_0142) | 1;
+        (0..self.num_hashes as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+
+    /// Serializes the filter to be stored next to the object it indexes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.bits.len() * 8);
+        bytes.extend_from_slice(BLOOM_MAGIC);
+        bytes.extend_from_slice(&self.num_hashes.to_le_bytes());
+        bytes.extend_from_slice(&self.num_bits.to_le_bytes());
+        for word in &self.bits {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserializes a filter, returns `None` if the bytes are not a valid filter
+    pub fn from_bytes(bytes: &[u8]) -> Option<BloomFilter> {
+        if bytes.len() < 16 || &bytes[0..4] != BLOOM_MAGIC {
+            return None;
+        }
+        let mut num_hashes = [0; 4];
+        num_hashes.copy_from_slice(&bytes[4..8]);
+        let mut num_bits = [0; 8];
+        num_bits.copy_from_slice(&bytes[8..16]);
+        let num_hashes = u32::from_le_bytes(num_hashes);
+        let num_bits = u64::from_le_bytes(num_bits);
+
+        let words = &bytes[16..];
+        if num_bits == 0 || words.len() % 8 != 0 || (words.len() / 8) as u64 != (num_bits + 63) / 64
+        {
+            return None;
+        }
+        let bits = words
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0; 8];
+                word.copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+        Some(BloomFilter {
+            bits,
+            num_bits,
+            num_hashes,
+        })
+    }
+}
+
+/// FNV-1a, stable across builds unlike the std hashers, which matters as filters are persisted.
+fn fnv1a(data: &[u8], offset_basis: u64) -> u64 {
+    let mut hash = offset_basis;
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// The lines of stored text as a filter indexes them, the same lines a search scans. Empty lines
+/// hold nothing to find.
+pub fn logical_lines(text: &str) -> Vec<String> {
+    text.split('\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Key of the object holding the bloom filter of a log object
+pub fn bloom_key(object_key: &str) -> String {
+    format!("{}.bloom", object_key)
+}
+
+#[cfg(test)]
+mod bloom_tests {
+    use super::*;
+
+    fn sample_filter() -> BloomFilter {
+        BloomFilter::from_lines(&vec![
+            "10.0.0.1 GET /index.html request_id=abc123".to_string(),
+            "10.0.0.2 POST /login request_id=def456".to_string(),
+        ])
+    }
+
+    #[test]
+    fn contains_substrings_of_lines() {
+        let filter = sample_filter();
+        assert!(filter.may_contain_substring("request_id=abc123"));
+        assert!(filter.may_contain_substring("10.0.0.2"));
+        assert!(filter.may_contain_substring("/login"));
+        // too short to check, so it may always be there
+        assert!(filter.may_contain_substring("zz"));
+    }
+
+    #[test]
+    fn rejects_missing_literals() {
+        let filter = sample_filter();
+        assert!(!filter.may_contain_substring("request_id=zzz999"));
+        assert!(!filter.may_contain_substring("DELETE"));
+    }
+
+    #[test]
+    fn trigrams_stay_within_lines() {
+        let lines = logical_lines("10.0.0.1 GET\n\nPOST /login\n");
+        assert_eq!(lines, vec!["10.0.0.1 GET", "POST /login"]);
+        let filter = BloomFilter::from_lines(&lines);
+        assert!(filter.may_contain_substring("POST"));
+        assert!(!filter.may_contain_substring("GET\nPOST"));
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        let filter = sample_filter();
+        let restored = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert_eq!(restored, filter);
+        assert!(BloomFilter::from_bytes(b"not a filter").is_none());
+        assert!(BloomFilter::from_bytes(&filter.to_bytes()[..20]).is_none());
+    }
+}
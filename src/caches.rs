@@ -0,0 +1,242 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_derive::Serialize;
+
+use crate::query::QUERY_PLANS;
+use crate::reports::{REPORT_CONDITIONS, REPORT_REGEXES};
+use crate::storage::OBJECT_LOCK_BUCKETS;
+use crate::tee::TEE_REGEXES;
+
+/// A per-process cache keyed by string, counting its hits and misses
+pub struct Cache<V: Clone> {
+    name: &'static str,
+    entries: Mutex<Entries<V>>,
+    // entries kept at most, the oldest stored is evicted past it
+    capacity: Option<usize>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entries<V> {
+    values: HashMap<String, V>,
+    // keys in the order they were first stored
+    order: VecDeque<String>,
+}
+
+impl<V> Entries<V> {
+    fn store(&mut self, key: String, value: V, capacity: Option<usize>) {
+        if !self.values.contains_key(&key) {
+            if let Some(capacity) = capacity {
+                while self.order.len() >= capacity.max(1) {
+                    if let Some(oldest) = self.order.pop_front() {
+                        self.values.remove(&oldest);
+                    }
+                }
+            }
+            self.order.push_back(key.clone());
+        }
+        self.values.insert(key, value);
+    }
+}
+
+/// What `GET /api/caches` reports for a cache
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    // `None` until the cache is first looked up
+    pub hit_rate: Option<f64>,
+}
+
+/// Caches inspected and cleared by the admin API, whatever they hold
+pub trait CacheControl: Sync {
+    fn name(&self) -> &'static str;
+    fn stats(&self) -> CacheStats;
+    /// Drops the entries and resets the counters
+    fn clear(&self);
+}
+
+impl<V: Clone> Cache<V> {
+    pub fn new(name: &'static str) -> Cache<V> {
+        Cache {
+            name,
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            capacity: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// A cache holding up to `capacity` entries, evicting the oldest stored to take new ones
+    pub fn bounded(name: &'static str, capacity: usize) -> Cache<V> {
+        Cache {
+            capacity: Some(capacity),
+            ..Cache::new(name)
+        }
+    }
+
+    /// Looks up a key, counting the lookup
+    pub fn get(&self, key: &str) -> Option<V> {
+        let value = self.entries.lock().unwrap().values.get(key).cloned();
+        self.count(value.is_some());
+        value
+    }
+
+    pub fn insert(&self, key: String, value: V) {
+        self.entries
+            .lock()
+            .unwrap()
+            .store(key, value, self.capacity);
+    }
+
+    /// Looks up a key, computing and storing its value on a miss
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&self, key: &str, f: F) -> V {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(value) = entries.values.get(key) {
+            self.count(true);
+            return value.clone();
+        }
+        self.count(false);
+        let value = f();
+        entries.store(key.to_string(), value.clone(), self.capacity);
+        value
+    }
+
+    fn count(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<V: Clone + Send> CacheControl for Cache<V> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap().values.len();
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        CacheStats {
+            name: self.name,
+            entries,
+            hits,
+            misses,
+            hit_rate: if lookups == 0 {
+                None
+            } else {
+                Some(hits as f64 / lookups as f64)
+            },
+        }
+    }
+
+    fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.values.clear();
+        entries.order.clear();
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Every per-process cache of the server
+pub fn registered_caches() -> Vec<&'static dyn CacheControl> {
+    vec![
+        &*OBJECT_LOCK_BUCKETS,
+        &*QUERY_PLANS,
+        &*REPORT_CONDITIONS,
+        &*REPORT_REGEXES,
+        &*TEE_REGEXES,
+    ]
+}
+
+/// The cache with a name, `None` if there's no such cache
+pub fn find_cache(name: &str) -> Option<&'static dyn CacheControl> {
+    registered_caches()
+        .into_iter()
+        .find(|cache| cache.name() == name)
+}
+
+#[cfg(test)]
+mod caches_tests {
+    use super::*;
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache: Cache<u32> = Cache::new("test");
+        assert_eq!(cache.stats().hit_rate, None);
+        assert_eq!(cache.get_or_insert_with("a", || 1), 1);
+        assert_eq!(cache.get_or_insert_with("a", || 2), 1);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                name: "test",
+                entries: 1,
+                hits: 2,
+                misses: 2,
+                hit_rate: Some(0.5),
+            }
+        );
+    }
+
+    #[test]
+    fn clear_resets_cache() {
+        let cache: Cache<u32> = Cache::new("test");
+        cache.insert("a".to_string(), 1);
+        assert_eq!(cache.get("a"), Some(1));
+        cache.clear();
+        assert_eq!(cache.get("a"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (0, 0, 1));
+    }
+
+    #[test]
+    fn bounded_caches_evict_the_oldest_entry() {
+        let cache: Cache<u32> = Cache::bounded("test", 2);
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        // storing a key again doesn't evict anything
+        cache.insert("a".to_string(), 3);
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.get_or_insert_with("c", || 4), 4);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(2));
+        assert_eq!(cache.get("c"), Some(4));
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn caches_are_found_by_name() {
+        assert!(find_cache("report_regexes").is_some());
+        assert!(find_cache("tee_regexes").is_some());
+        assert!(find_cache("object_lock_buckets").is_some());
+        assert!(find_cache("query_plans").is_some());
+        assert!(find_cache("results").is_none());
+    }
+}
@@ -0,0 +1,197 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use chrono::Utc;
+use futures::future::Either;
+use futures::{future, stream, Future, Stream};
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::constants::CHANGESETS_PREFIX;
+use crate::history::record_config_change;
+use crate::meta::ds_for_metabucket;
+use crate::storage::{
+    delete_object, get_object_metabucket, list_metabucket_keys, write_object_metabucket,
+};
+
+/// What a changeset leaves a configuration object of the metabucket as, `None` deletes it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ObjectChange {
+    pub key: String,
+    pub after: Option<String>,
+}
+
+/// Changes to several configuration objects committed together, kept under
+/// `minsql/meta/_changesets/<ts>-<id>` until every one of them is applied
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Changeset {
+    changes: Vec<ObjectChange>,
+}
+
+pub fn is_changeset_key(key: &str) -> bool {
+    key.starts_with(CHANGESETS_PREFIX)
+}
+
+fn changeset_key() -> String {
+    format!(
+        "{}{}-{}",
+        CHANGESETS_PREFIX,
+        Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+        Uuid::new_v4()
+    )
+}
+
+/// Changes several configuration objects with a single write. The changeset is written to the
+/// metabucket before any object changes and removed once all of them did, so if it can't be
+/// written nothing changes, and once it is every change makes it: a changeset a failure left
+/// behind is applied again by `replay_changesets` when the configuration is next loaded.
+pub fn commit_changeset(
+    cfg: Arc<RwLock<Config>>,
+    changes: Vec<ObjectChange>,
+) -> impl Future<Item = (), Error = String> {
+    let key = changeset_key();
+    let payload = serde_json::to_string(&Changeset {
+        changes: changes.clone(),
+    })
+    .unwrap();
+    let history_cfg = Arc::clone(&cfg);
+    let write_cfg = Arc::clone(&cfg);
+    // every change is on the history before the changeset commits them, as for single writes
+    stream::iter_ok::<_, String>(changes.clone())
+        .for_each(move |change| {
+            record_config_change(Arc::clone(&history_cfg), change.key, change.after)
+        })
+        .and_then(move |_| {
+            write_object_metabucket(write_cfg, key.clone(), payload)
+                .map(move |_| key)
+                .map_err(|e| format!("Could not write the changeset: {}", e.reason()))
+        })
+        .and_then(move |key| {
+            let err_key = key.clone();
+            apply_changeset(cfg, key, changes).map_err(move |e| {
+                error!("Changeset {} is left to be applied again: {}", err_key, e);
+                format!(
+                    "The changes are committed but not applied yet, they will be once the \
+                     configuration is loaded again: {}",
+                    e
+                )
+            })
+        })
+}
+
+/// Applies the changes of a committed changeset one after the other, then removes it
+fn apply_changeset(
+    cfg: Arc<RwLock<Config>>,
+    key: String,
+    changes: Vec<ObjectChange>,
+) -> impl Future<Item = (), Error = String> {
+    let done_cfg = Arc::clone(&cfg);
+    stream::iter_ok::<_, String>(changes)
+        .for_each(move |change| {
+            let err_key = change.key.clone();
+            match change.after {
+                Some(payload) => Either::A(
+                    write_object_metabucket(Arc::clone(&cfg), change.key, payload)
+                        .map(|_| ())
+                        .map_err(move |e| format!("Could not write {}: {}", err_key, e.reason())),
+                ),
+                None => Either::B(
+                    delete_object(&ds_for_metabucket(Arc::clone(&cfg)), change.key)
+                        .map(|_| ())
+                        .map_err(move |e| format!("Could not delete {}: {}", err_key, e.reason())),
+                ),
+            }
+        })
+        .and_then(move |_| {
+            delete_object(&ds_for_metabucket(done_cfg), key)
+                .map(|_| ())
+                .map_err(|e| format!("Could not remove the changeset: {}", e.reason()))
+        })
+}
+
+/// Applies the changesets a failure left behind, oldest first. Runs before the configuration is
+/// loaded so it's loaded with them.
+pub fn replay_changesets(cfg: Arc<RwLock<Config>>) -> impl Future<Item = (), Error = ()> {
+    let read_cfg = Arc::clone(&cfg);
+    list_metabucket_keys(Arc::clone(&cfg), CHANGESETS_PREFIX.to_string())
+        .map_err(|e| error!("Could not list the changesets to apply: {:?}", e))
+        .and_then(move |mut keys| {
+            keys.sort();
+            stream::iter_ok::<_, ()>(keys).for_each(move |key| {
+                let cfg = Arc::clone(&cfg);
+                let err_key = key.clone();
+                get_object_metabucket(Arc::clone(&read_cfg), key.clone())
+                    .map_err(move |e| error!("Could not read changeset {}: {:?}", err_key, e))
+                    .and_then(move |payload| {
+                        let changeset = match payload.map(|p| serde_json::from_str::<Changeset>(&p))
+                        {
+                            Some(Ok(changeset)) => changeset,
+                            // removed meanwhile, or not a changeset
+                            _ => return Either::A(future::ok(())),
+                        };
+                        info!("Applying changeset {} left behind", key);
+                        let err_key = key.clone();
+                        Either::B(
+                            apply_changeset(cfg, key, changeset.changes).then(move |res| {
+                                if let Err(e) = res {
+                                    error!("Could not apply changeset {}: {}", err_key, e);
+                                }
+                                Ok(())
+                            }),
+                        )
+                    })
+            })
+        })
+}
+
+#[cfg(test)]
+mod changesets_tests {
+    use super::*;
+
+    #[test]
+    fn changesets_sort_as_committed() {
+        let first = changeset_key();
+        let second = changeset_key();
+        assert!(is_changeset_key(&first));
+        assert!(!is_changeset_key("minsql/meta/logs/mylog"));
+        assert!(first[..CHANGESETS_PREFIX.len() + 23] <= second[..CHANGESETS_PREFIX.len() + 23]);
+    }
+
+    #[test]
+    fn changeset_round_trip() {
+        let changeset = Changeset {
+            changes: vec![
+                ObjectChange {
+                    key: "minsql/meta/auth/TOKEN/applogs".to_string(),
+                    after: Some(r#"{"log_name":"applogs","api":["search"]}"#.to_string()),
+                },
+                ObjectChange {
+                    key: "minsql/meta/auth/TOKEN/weblogs".to_string(),
+                    after: None,
+                },
+            ],
+        };
+        let payload = serde_json::to_string(&changeset).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Changeset>(&payload).unwrap(),
+            changeset
+        );
+    }
+}
@@ -0,0 +1,138 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A network in CIDR notation, ie: `10.0.0.0/8`, a bare address is a network of its own
+#[derive(Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(network: &str) -> Result<Cidr, String> {
+        let invalid = || format!("{} is not a valid network", network);
+        let mut parts = network.trim().splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        let unmapped = unmap(addr);
+        // the prefix of a mapped network counts the 96 bits of the mapping
+        let prefix = if addr.is_ipv6() && unmapped.is_ipv4() {
+            prefix.saturating_sub(96)
+        } else {
+            prefix
+        };
+        Ok(Cidr {
+            addr: unmapped,
+            prefix,
+        })
+    }
+
+    /// Whether the address is within the network
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, unmap(*addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::max_value()
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::max_value()
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// IPv4 addresses mapped to IPv6, ie: `::ffff:10.0.0.1` on dual stack sockets, as IPv4
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new(
+                    (s[6] >> 8) as u8,
+                    s[6] as u8,
+                    (s[7] >> 8) as u8,
+                    s[7] as u8,
+                ))
+            } else {
+                addr
+            }
+        }
+        _ => addr,
+    }
+}
+
+/// Whether the address is within any of the networks, networks that don't parse match nothing
+pub fn any_contains(networks: &[String], addr: &IpAddr) -> bool {
+    networks
+        .iter()
+        .filter_map(|network| Cidr::parse(network).ok())
+        .any(|cidr| cidr.contains(addr))
+}
+
+#[cfg(test)]
+mod cidr_tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_networks() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(&ip("10.1.200.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(net.contains(&ip("::ffff:10.1.0.9")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(Cidr::parse("192.168.1.7")
+            .unwrap()
+            .contains(&ip("192.168.1.7")));
+        assert!(!Cidr::parse("192.168.1.7")
+            .unwrap()
+            .contains(&ip("192.168.1.8")));
+    }
+
+    #[test]
+    fn ipv6_networks() {
+        let net = Cidr::parse("fd00::/8").unwrap();
+        assert!(net.contains(&ip("fd12:3456::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+        assert!(!net.contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("example.com/8").is_err());
+        assert!(!any_contains(&["nope".to_string()], &ip("10.0.0.1")));
+    }
+}
@@ -15,3 +15,4 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod take_from_iterable;
+pub mod take_within_budget;
@@ -14,7 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use futures::{stream, StartSend};
+use futures::StartSend;
 use std::ops::Deref;
 use tokio::prelude::{Async, Poll, Sink, Stream};
 
@@ -35,10 +35,7 @@ pub trait TakeFromIterable {
         Self: Sized;
 }
 
-impl<S: Stream, F, U> TakeFromIterable for stream::Map<S, F>
-where
-    F: FnMut(S::Item) -> U,
-{
+impl<S: Stream> TakeFromIterable for S {
     fn take_from_iterable(self, amt: u64) -> IterableTaker<Self>
     where
         Self: Sized,
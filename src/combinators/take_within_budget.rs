@@ -0,0 +1,181 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::prelude::{Async, Poll, Stream};
+
+use crate::try_ready;
+
+/// Rows and bytes of rows a stream may return, `0` for no limit on either
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutputBudget {
+    pub max_rows: usize,
+    pub max_bytes: usize,
+}
+
+impl OutputBudget {
+    pub fn unlimited() -> OutputBudget {
+        OutputBudget::default()
+    }
+}
+
+pub trait TakeWithinBudget {
+    fn take_within_budget(
+        self,
+        budget: OutputBudget,
+        truncated: Arc<AtomicBool>,
+    ) -> BudgetTaker<Self>
+    where
+        Self: Sized;
+}
+
+impl<S> TakeWithinBudget for S
+where
+    S: Stream<Item = Vec<String>>,
+{
+    fn take_within_budget(
+        self,
+        budget: OutputBudget,
+        truncated: Arc<AtomicBool>,
+    ) -> BudgetTaker<Self>
+    where
+        Self: Sized,
+    {
+        BudgetTaker {
+            stream: self,
+            budget,
+            rows: 0,
+            bytes: 0,
+            done: false,
+            truncated,
+        }
+    }
+}
+
+/// A stream combinator which returns batches of rows until the budget is spent, flagging
+/// `truncated` when it ends the stream early. Blank rows are passed through without counting.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct BudgetTaker<S> {
+    stream: S,
+    budget: OutputBudget,
+    rows: usize,
+    bytes: usize,
+    done: bool,
+    truncated: Arc<AtomicBool>,
+}
+
+impl<S> BudgetTaker<S> {
+    /// Whether one more row of `len` bytes fits, its line break included
+    fn fits(&self, len: usize) -> bool {
+        (self.budget.max_rows == 0 || self.rows < self.budget.max_rows)
+            && (self.budget.max_bytes == 0 || self.bytes + len + 1 <= self.budget.max_bytes)
+    }
+}
+
+impl<S> Stream for BudgetTaker<S>
+where
+    S: Stream<Item = Vec<String>>,
+{
+    type Item = Vec<String>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<String>>, S::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        let rows = match try_ready!(self.stream.poll()) {
+            Some(rows) => rows,
+            None => {
+                self.done = true;
+                return Ok(Async::Ready(None));
+            }
+        };
+        let mut kept = Vec::with_capacity(rows.len());
+        for row in rows {
+            if row.is_empty() {
+                kept.push(row);
+                continue;
+            }
+            if !self.fits(row.len()) {
+                self.done = true;
+                self.truncated.store(true, Ordering::SeqCst);
+                break;
+            }
+            self.rows += 1;
+            self.bytes += row.len() + 1;
+            kept.push(row);
+        }
+        Ok(Async::Ready(Some(kept)))
+    }
+}
+
+#[cfg(test)]
+mod take_within_budget_tests {
+    use super::*;
+    use futures::stream;
+
+    fn taken(batches: Vec<Vec<&str>>, budget: OutputBudget) -> (Vec<String>, bool) {
+        let truncated = Arc::new(AtomicBool::new(false));
+        let batches: Vec<Vec<String>> = batches
+            .into_iter()
+            .map(|rows| rows.into_iter().map(|row| row.to_string()).collect())
+            .collect();
+        let rows = stream::iter_ok::<_, ()>(batches)
+            .take_within_budget(budget, Arc::clone(&truncated))
+            .wait()
+            .map(|rows| rows.unwrap())
+            .flatten()
+            .collect();
+        (rows, truncated.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn budget_of_rows_and_bytes() {
+        let batches = vec![vec!["a", "", "bb"], vec!["ccc", "dddd"]];
+        let (rows, truncated) = taken(batches.clone(), OutputBudget::unlimited());
+        assert_eq!(rows, vec!["a", "", "bb", "ccc", "dddd"]);
+        assert!(!truncated);
+
+        let rows_budget = OutputBudget {
+            max_rows: 3,
+            max_bytes: 0,
+        };
+        let (rows, truncated) = taken(batches.clone(), rows_budget);
+        assert_eq!(rows, vec!["a", "", "bb", "ccc"]);
+        assert!(truncated);
+
+        // `a\nbb\n` fits in 6 bytes, `ccc\n` doesn't
+        let bytes_budget = OutputBudget {
+            max_rows: 0,
+            max_bytes: 6,
+        };
+        let (rows, truncated) = taken(batches.clone(), bytes_budget);
+        assert_eq!(rows, vec!["a", "", "bb"]);
+        assert!(truncated);
+
+        // a budget spent exactly by the last row isn't a truncation
+        let exact = OutputBudget {
+            max_rows: 4,
+            max_bytes: 0,
+        };
+        let (rows, truncated) = taken(batches, exact);
+        assert_eq!(rows.len(), 5);
+        assert!(!truncated);
+    }
+}
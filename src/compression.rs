@@ -0,0 +1,137 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io::{self, Read, Write};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::config::Log;
+use crate::constants::{COMPRESSION_GZIP, COMPRESSION_ZSTD, ZSTD_LEVEL};
+
+// Objects are told apart by their first bytes, which can't start a line of UTF-8 text
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How the objects of a log are compressed as they're written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Option<Compression> {
+        match name {
+            COMPRESSION_GZIP => Some(Compression::Gzip),
+            COMPRESSION_ZSTD => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The compression new objects of the log are written with, if any
+    pub fn for_log(log: &Log) -> Option<Compression> {
+        log.compression
+            .as_ref()
+            .and_then(|name| Compression::parse(name))
+    }
+
+    /// The compression an object was written with, from its first bytes
+    pub fn detect(body: &[u8]) -> Option<Compression> {
+        if body.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if body.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn compress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(body, ZSTD_LEVEL),
+        }
+    }
+}
+
+/// The contents of an object as they were written, whether it was compressed or not. Objects
+/// made of several compressed parts one after the other are read whole.
+pub fn decompress(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    match Compression::detect(&body) {
+        Some(Compression::Gzip) => {
+            let mut decoded = Vec::new();
+            MultiGzDecoder::new(&body[..]).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+        Some(Compression::Zstd) => zstd::decode_all(&body[..]),
+        None => Ok(body),
+    }
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use super::*;
+
+    const LINES: &[u8] = b"10.0.0.1 GET /index.html 200\n10.0.0.2 GET /missing 404\n";
+
+    #[test]
+    fn round_trips() {
+        for compression in &[Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(LINES).unwrap();
+            assert_eq!(Compression::detect(&compressed), Some(*compression));
+            assert_eq!(decompress(compressed).unwrap(), LINES.to_vec());
+        }
+    }
+
+    #[test]
+    fn plain_objects_are_read_as_is() {
+        assert_eq!(Compression::detect(LINES), None);
+        assert_eq!(decompress(LINES.to_vec()).unwrap(), LINES.to_vec());
+        assert_eq!(decompress(Vec::new()).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn concatenated_parts() {
+        for compression in &[Compression::Gzip, Compression::Zstd] {
+            let mut body = compression.compress(&LINES[..30]).unwrap();
+            body.extend(compression.compress(&LINES[30..]).unwrap());
+            assert_eq!(decompress(body).unwrap(), LINES.to_vec());
+        }
+    }
+
+    #[test]
+    fn corrupted_objects_fail() {
+        let mut compressed = Compression::Gzip.compress(LINES).unwrap();
+        compressed.truncate(compressed.len() / 2);
+        assert!(decompress(compressed).is_err());
+    }
+
+    #[test]
+    fn log_settings() {
+        assert_eq!(Compression::parse("gzip"), Some(Compression::Gzip));
+        assert_eq!(Compression::parse("zstd"), Some(Compression::Zstd));
+        assert_eq!(Compression::parse("lz4"), None);
+        let mut log = Log::default();
+        assert_eq!(Compression::for_log(&log), None);
+        log.compression = Some("zstd".to_string());
+        assert_eq!(Compression::for_log(&log), Some(Compression::Zstd));
+    }
+}
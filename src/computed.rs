@@ -0,0 +1,252 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use sqlparser::ast::{Expr, SelectItem, SetExpr, Statement};
+use sqlparser::parser::Parser;
+
+use crate::aggregate::is_aggregate_projection;
+use crate::config::Log;
+use crate::dialect::MinSQLDialect;
+use crate::functions::parse_projection_expr;
+
+/// Whether a computed field can be named so, it has to be a plain identifier so it can't be
+/// taken for a field of the lines
+fn valid_computed_field_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses the expression of a computed field, ie: `CAST($9 AS INT)`. It can read the fields of
+/// the lines and transform them with functions, but not aggregate them or read other computed
+/// fields.
+pub fn parse_computed_field(name: &str, expression: &str) -> Result<Expr, String> {
+    if !valid_computed_field_name(name) {
+        return Err(format!(
+            "Computed field `{}` must start with a letter and have only letters, digits or `_`",
+            name
+        ));
+    }
+    let sql = format!("SELECT {} FROM computed", expression);
+    let mut statements = Parser::parse_sql(&MinSQLDialect {}, sql)
+        .map_err(|_| format!("Could not parse computed field `{}`", name))?;
+    let expr = match statements.pop() {
+        Some(Statement::Query(query)) => match query.body {
+            SetExpr::Select(select) if select.projection.len() == 1 => {
+                match select.projection[0].clone() {
+                    SelectItem::UnnamedExpr(expr) => Some(expr),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    let expr = match expr {
+        Some(expr) if statements.is_empty() => expr,
+        _ => {
+            return Err(format!(
+                "Computed field `{}` must be a single expression",
+                name
+            ))
+        }
+    };
+    if is_aggregate_projection(&expr) {
+        return Err(format!(
+            "Computed field `{}` can't use aggregate functions",
+            name
+        ));
+    }
+    let mut fields = Vec::new();
+    parse_projection_expr(&expr, &mut fields)
+        .map_err(|e| format!("Invalid computed field `{}`: {}", name, e))?;
+    if let Some(field) = fields.iter().find(|f| !f.to_string().starts_with('$')) {
+        return Err(format!(
+            "Computed field `{}` can only read fields of the lines, not `{}`",
+            name, field
+        ));
+    }
+    Ok(expr)
+}
+
+/// Replaces the computed fields of the log on a statement with their expressions. Selected
+/// computed fields are named after themselves, other selected expressions using them after the
+/// expression they resolve to unless aliased. Conditions can only use computed fields that are
+/// another name for a field of the lines.
+pub fn resolve_computed_fields(statement: &mut Statement, log: &Log) -> Result<(), String> {
+    if log.computed_fields.is_empty() {
+        return Ok(());
+    }
+    let mut computed: HashMap<String, Expr> = HashMap::new();
+    for (name, expression) in &log.computed_fields {
+        computed.insert(name.clone(), parse_computed_field(name, expression)?);
+    }
+    let query = match statement {
+        Statement::Query(query) => query,
+        _ => return Ok(()),
+    };
+    if let SetExpr::Select(ref mut select) = query.body {
+        for item in select.projection.iter_mut() {
+            let resolved = match &*item {
+                SelectItem::UnnamedExpr(Expr::Identifier(name)) if computed.contains_key(name) => {
+                    SelectItem::ExprWithAlias {
+                        expr: computed[name].clone(),
+                        alias: name.clone(),
+                    }
+                }
+                SelectItem::UnnamedExpr(expr) => {
+                    let mut expr = expr.clone();
+                    substitute(&mut expr, &computed, false)?;
+                    SelectItem::UnnamedExpr(expr)
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    let mut expr = expr.clone();
+                    substitute(&mut expr, &computed, false)?;
+                    SelectItem::ExprWithAlias {
+                        expr,
+                        alias: alias.clone(),
+                    }
+                }
+                _ => continue,
+            };
+            *item = resolved;
+        }
+        for group in select.group_by.iter_mut() {
+            substitute(group, &computed, false)?;
+        }
+        if let Some(selection) = select.selection.as_mut() {
+            substitute(selection, &computed, true)?;
+        }
+    }
+    for order in query.order_by.iter_mut() {
+        substitute(&mut order.expr, &computed, false)?;
+    }
+    Ok(())
+}
+
+/// Replaces the computed fields read by an expression, only the ones that are another name for
+/// a field when `fields_only`
+fn substitute(
+    expr: &mut Expr,
+    computed: &HashMap<String, Expr>,
+    fields_only: bool,
+) -> Result<(), String> {
+    let replacement = match expr {
+        Expr::Identifier(name) => computed.get(name).map(|e| (name.clone(), e.clone())),
+        _ => None,
+    };
+    if let Some((name, replacement)) = replacement {
+        let is_field = match replacement {
+            Expr::Identifier(_) | Expr::CompoundIdentifier(_) => true,
+            _ => false,
+        };
+        if fields_only && !is_field {
+            return Err(format!(
+                "Computed field `{}` can only be selected, grouped or sorted by",
+                name
+            ));
+        }
+        *expr = replacement;
+        return Ok(());
+    }
+    match expr {
+        Expr::Nested(inner)
+        | Expr::UnaryOp { expr: inner, .. }
+        | Expr::IsNull(inner)
+        | Expr::IsNotNull(inner)
+        | Expr::Cast { expr: inner, .. } => substitute(inner, computed, fields_only),
+        Expr::BinaryOp { left, right, .. } => {
+            substitute(left, computed, fields_only)?;
+            substitute(right, computed, fields_only)
+        }
+        Expr::Between {
+            expr: inner,
+            low,
+            high,
+            ..
+        } => {
+            substitute(inner, computed, fields_only)?;
+            substitute(low, computed, fields_only)?;
+            substitute(high, computed, fields_only)
+        }
+        Expr::Function(function) => {
+            for arg in function.args.iter_mut() {
+                substitute(arg, computed, fields_only)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod computed_tests {
+    use super::*;
+
+    fn log_with(fields: &[(&str, &str)]) -> Log {
+        Log {
+            name: Some("mylog".to_string()),
+            computed_fields: fields
+                .iter()
+                .map(|(name, expr)| (name.to_string(), expr.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn resolved(sql: &str, log: &Log) -> Result<String, String> {
+        let mut statement = Parser::parse_sql(&MinSQLDialect {}, sql.to_string())
+            .unwrap()
+            .remove(0);
+        resolve_computed_fields(&mut statement, log)?;
+        Ok(statement.to_string())
+    }
+
+    #[test]
+    fn computed_field_expressions() {
+        assert!(parse_computed_field("latency_ms", "CAST($9 AS INT)").is_ok());
+        assert!(parse_computed_field("client", "$ip").is_ok());
+        assert!(parse_computed_field("$latency", "CAST($9 AS INT)").is_err());
+        assert!(parse_computed_field("9lives", "$9").is_err());
+        assert!(parse_computed_field("hits", "COUNT(*)").is_err());
+        assert!(parse_computed_field("double", "CAST(latency_ms AS INT)").is_err());
+        assert!(parse_computed_field("broken", "CAST($9 AS").is_err());
+        assert!(parse_computed_field("pair", "$1, $2").is_err());
+    }
+
+    #[test]
+    fn statements_read_computed_fields() {
+        let log = log_with(&[("latency_ms", "CAST($9 AS INT)"), ("client", "$ip")]);
+        assert_eq!(
+            resolved("SELECT latency_ms FROM mylog", &log).unwrap(),
+            "SELECT CAST($9 AS int) AS latency_ms FROM mylog"
+        );
+        assert_eq!(
+            resolved(
+                "SELECT client, AVG(latency_ms) FROM mylog WHERE client = '10.0.0.1' GROUP BY client ORDER BY client",
+                &log
+            )
+            .unwrap(),
+            "SELECT $ip AS client, AVG(CAST($9 AS int)) FROM mylog WHERE $ip = '10.0.0.1' GROUP BY $ip ORDER BY $ip"
+        );
+        assert_eq!(
+            resolved("SELECT $ip FROM mylog", &log).unwrap(),
+            "SELECT $ip FROM mylog"
+        );
+        assert!(resolved("SELECT * FROM mylog WHERE latency_ms = '5'", &log).is_err());
+    }
+}
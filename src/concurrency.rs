@@ -0,0 +1,148 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::Mutex;
+
+use futures::future::Either;
+use futures::sync::oneshot;
+use futures::{future, Future};
+
+/// Caps how many operations run at once across the process, the rest wait for a permit in the
+/// order they asked for it.
+pub struct ConcurrencyLimit {
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    // `0` for no limit
+    limit: usize,
+    in_use: usize,
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Allows an operation to run, the next one waiting runs once it's dropped
+pub struct Permit {
+    limit: &'static ConcurrencyLimit,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(limit: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            state: Mutex::new(LimitState {
+                limit,
+                in_use: 0,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Changes the limit, operations already running carry on
+    pub fn set_limit(&'static self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.limit = limit;
+        while state.limit == 0 || state.in_use < state.limit {
+            match state.waiting.pop_front() {
+                Some(waiter) => {
+                    state.in_use += 1;
+                    if let Err(permit) = waiter.send(Permit { limit: self }) {
+                        state.in_use -= 1;
+                        mem::forget(permit);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Resolves once the operation can run
+    pub fn acquire(&'static self) -> impl Future<Item = Permit, Error = ()> {
+        let mut state = self.state.lock().unwrap();
+        if state.limit == 0 || state.in_use < state.limit {
+            state.in_use += 1;
+            return Either::A(future::ok(Permit { limit: self }));
+        }
+        let (tx, rx) = oneshot::channel();
+        state.waiting.push_back(tx);
+        Either::B(rx.map_err(|_| ()))
+    }
+
+    /// Operations running
+    #[cfg(test)]
+    fn in_use(&self) -> usize {
+        self.state.lock().unwrap().in_use
+    }
+
+    /// Hands the permit of a finished operation over to the next one waiting
+    fn release(&'static self) {
+        let mut state = self.state.lock().unwrap();
+        if state.limit == 0 || state.in_use <= state.limit {
+            while let Some(waiter) = state.waiting.pop_front() {
+                match waiter.send(Permit { limit: self }) {
+                    Ok(()) => return,
+                    // the operation stopped waiting, its permit isn't released twice
+                    Err(permit) => mem::forget(permit),
+                }
+            }
+        }
+        state.in_use -= 1;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
+
+#[cfg(test)]
+mod concurrency_tests {
+    use lazy_static::lazy_static;
+
+    use super::*;
+
+    lazy_static! {
+        static ref LIMIT: ConcurrencyLimit = ConcurrencyLimit::new(2);
+        static ref ABANDONED_LIMIT: ConcurrencyLimit = ConcurrencyLimit::new(1);
+    }
+
+    #[test]
+    fn waits_for_a_permit() {
+        let first = LIMIT.acquire().wait().unwrap();
+        let second = LIMIT.acquire().wait().unwrap();
+        let third = LIMIT.acquire();
+        assert_eq!(LIMIT.in_use(), 2);
+        // the permit of the first goes to the third
+        drop(first);
+        let third = third.wait().unwrap();
+        assert_eq!(LIMIT.in_use(), 2);
+        drop(second);
+        drop(third);
+        assert_eq!(LIMIT.in_use(), 0);
+    }
+
+    #[test]
+    fn abandoned_waits_are_skipped() {
+        let first = ABANDONED_LIMIT.acquire().wait().unwrap();
+        let abandoned = ABANDONED_LIMIT.acquire();
+        drop(abandoned);
+        drop(first);
+        assert_eq!(ABANDONED_LIMIT.in_use(), 0);
+        let _second = ABANDONED_LIMIT.acquire().wait().unwrap();
+        assert_eq!(ABANDONED_LIMIT.in_use(), 1);
+    }
+}
@@ -17,12 +17,26 @@
 use std::collections::HashMap;
 use std::env;
 use std::fmt;
+use std::net::SocketAddr;
 
+use chrono::{DateTime, Utc};
 use clap::{App, Arg};
 use log::error;
 use serde_derive::{Deserialize, Serialize};
+use url::Url;
 
-use crate::constants::DEFAULT_SERVER_ADDRESS;
+use crate::cidr::Cidr;
+use crate::constants::{
+    DEFAULT_AUTO_CREATE_COMMIT_WINDOW, DEFAULT_DELETED_LOG_GRACE_SECS,
+    DEFAULT_LDAP_GROUPS_ATTRIBUTE, DEFAULT_MAX_CONCURRENT_LISTINGS, DEFAULT_MAX_DATASTORES,
+    DEFAULT_MAX_LOGS, DEFAULT_MAX_TOKENS, DEFAULT_OIDC_GROUPS_CLAIM, DEFAULT_PREFETCH_DEPTH,
+    DEFAULT_QUERY_MEMORY_LIMIT, DEFAULT_SERVER_ADDRESS, DEFAULT_UNBOUNDED_QUERY_MAX_BYTES,
+    DEFAULT_UNBOUNDED_QUERY_MAX_ROWS, ROLE_ADMIN, ROLE_VIEWER, SECRET_FILE_SUFFIX,
+    TEST_BACKEND_ACCESS_KEY, TEST_BACKEND_BUCKET, TEST_BACKEND_SECRET_KEY,
+};
+use crate::s3stub;
+use crate::secret_files::read_secret_file;
+use crate::secrets::hash_secret;
 
 // environment variables
 pub const METABUCKET_ENDPOINT: &str = "MINSQL_METABUCKET_ENDPOINT";
@@ -33,6 +47,30 @@ pub const PKCS12_CERT: &str = "MINSQL_PKCS12_CERT";
 pub const PKCS12_PASSWORD: &str = "MINSQL_PKCS12_PASSWORD";
 pub const ROOT_ACCESS_KEY: &str = "MINSQL_ROOT_ACCESS_KEY";
 pub const ROOT_SECRET_KEY: &str = "MINSQL_ROOT_SECRET_KEY";
+pub const PREFETCH_DEPTH: &str = "MINSQL_PREFETCH_DEPTH";
+pub const QUERY_MEMORY_LIMIT: &str = "MINSQL_QUERY_MEMORY_LIMIT";
+pub const MAX_LOGS: &str = "MINSQL_MAX_LOGS";
+pub const MAX_TOKENS: &str = "MINSQL_MAX_TOKENS";
+pub const MAX_DATASTORES: &str = "MINSQL_MAX_DATASTORES";
+pub const MAX_CONCURRENT_LISTINGS: &str = "MINSQL_MAX_CONCURRENT_LISTINGS";
+pub const DELETED_LOG_GRACE: &str = "MINSQL_DELETED_LOG_GRACE";
+pub const UNBOUNDED_QUERY_MAX_ROWS: &str = "MINSQL_UNBOUNDED_QUERY_MAX_ROWS";
+pub const UNBOUNDED_QUERY_MAX_BYTES: &str = "MINSQL_UNBOUNDED_QUERY_MAX_BYTES";
+pub const AUTO_CREATE_LOGS: &str = "MINSQL_AUTO_CREATE_LOGS";
+pub const AUTO_CREATE_COMMIT_WINDOW: &str = "MINSQL_AUTO_CREATE_COMMIT_WINDOW";
+pub const TRUSTED_PROXIES: &str = "MINSQL_TRUSTED_PROXIES";
+pub const SIGNING_KEY: &str = "MINSQL_SIGNING_KEY";
+pub const ACCESS_LOG_RULES: &str = "MINSQL_ACCESS_LOG_RULES";
+pub const SYSLOG_TCP_ADDRESS: &str = "MINSQL_SYSLOG_TCP_ADDRESS";
+pub const SYSLOG_UDP_ADDRESS: &str = "MINSQL_SYSLOG_UDP_ADDRESS";
+pub const SYSLOG_LOG: &str = "MINSQL_SYSLOG_LOG";
+pub const OIDC_USERINFO_URL: &str = "MINSQL_OIDC_USERINFO_URL";
+pub const OIDC_GROUPS_CLAIM: &str = "MINSQL_OIDC_GROUPS_CLAIM";
+pub const LDAP_URL: &str = "MINSQL_LDAP_URL";
+pub const LDAP_USER_DN: &str = "MINSQL_LDAP_USER_DN";
+pub const LDAP_GROUPS_ATTRIBUTE: &str = "MINSQL_LDAP_GROUPS_ATTRIBUTE";
+pub const LDAP_ALLOW_PLAINTEXT: &str = "MINSQL_LDAP_ALLOW_PLAINTEXT";
+pub const AUTH_ROLE_MAPPING: &str = "MINSQL_AUTH_ROLE_MAPPING";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -45,9 +83,12 @@ pub struct Config {
     pub tokens: HashMap<String, Token>,
     #[serde(default = "HashMap::new")]
     pub auth: HashMap<String, HashMap<String, LogAuth>>,
+    // Overrides of the built-in smart field patterns, keyed by smart field name, ie: `ip`
+    #[serde(default = "HashMap::new")]
+    pub patterns: HashMap<String, SmartPattern>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Server {
     pub address: String,
     pub metadata_endpoint: String,
@@ -56,6 +97,228 @@ pub struct Server {
     pub secret_key: String,
     pub pkcs12_cert: Option<String>,
     pub pkcs12_password: Option<String>,
+    // Objects fetched ahead of the one being scanned by each datastore reader of a query
+    #[serde(default = "def_prefetch_depth")]
+    pub prefetch_depth: usize,
+    // Bytes of lines a query may buffer before it's failed
+    #[serde(default = "def_query_memory_limit")]
+    pub query_memory_limit: usize,
+    // Logs, tokens and datastores that can be created, `0` for no limit
+    #[serde(default = "def_max_logs")]
+    pub max_logs: usize,
+    #[serde(default = "def_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default = "def_max_datastores")]
+    pub max_datastores: usize,
+    // Listing calls in flight across every datastore, `0` for no limit
+    #[serde(default = "def_max_concurrent_listings")]
+    pub max_concurrent_listings: usize,
+    // Seconds a deleted log can be restored for
+    #[serde(default = "def_deleted_log_grace_secs")]
+    pub deleted_log_grace_secs: u64,
+    // Rows and bytes returned by queries with neither a `LIMIT` nor a `$date` predicate, `0` for
+    // no limit
+    #[serde(default = "def_unbounded_query_max_rows")]
+    pub unbounded_query_max_rows: usize,
+    #[serde(default = "def_unbounded_query_max_bytes")]
+    pub unbounded_query_max_bytes: usize,
+    // Template of the logs created on the first lines stored to an unknown log, unknown logs are
+    // not found without one
+    #[serde(default)]
+    pub auto_create_logs: Option<AutoCreateLogs>,
+    // Proxies trusted to report the client address on `X-Forwarded-For`
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // Key search results are signed with on `MINSQL-SIGN: true`, signing is off without one
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    // Identity providers admin API users can log in with, besides admin tokens
+    #[serde(default)]
+    pub auth_providers: Option<AuthProviders>,
+    // Share of the requests written to the access log by path prefix, every request is without a
+    // rule for it
+    #[serde(default)]
+    pub access_log_rules: Vec<AccessLogRule>,
+    // Syslog listeners and the log their messages are stored on, none are started without it
+    #[serde(default)]
+    pub syslog: Option<SyslogListener>,
+    // Files the secrets above were read from, they're read again when they change
+    #[serde(default)]
+    pub secret_files: SecretFiles,
+}
+
+// Same defaults as a configuration that leaves the fields out
+impl Default for Server {
+    fn default() -> Server {
+        Server {
+            address: String::new(),
+            metadata_endpoint: String::new(),
+            metadata_bucket: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            pkcs12_cert: None,
+            pkcs12_password: None,
+            prefetch_depth: def_prefetch_depth(),
+            query_memory_limit: def_query_memory_limit(),
+            max_logs: def_max_logs(),
+            max_tokens: def_max_tokens(),
+            max_datastores: def_max_datastores(),
+            max_concurrent_listings: def_max_concurrent_listings(),
+            deleted_log_grace_secs: def_deleted_log_grace_secs(),
+            unbounded_query_max_rows: def_unbounded_query_max_rows(),
+            unbounded_query_max_bytes: def_unbounded_query_max_bytes(),
+            auto_create_logs: None,
+            trusted_proxies: Vec::new(),
+            signing_key: None,
+            auth_providers: None,
+            access_log_rules: Vec::new(),
+            syslog: None,
+            secret_files: SecretFiles::default(),
+        }
+    }
+}
+
+/// Files the metabucket keys and the PKCS12 password were read from, when set with the `_FILE`
+/// variant of their environment variable, ie: `MINSQL_METABUCKET_SECRET_KEY_FILE`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SecretFiles {
+    #[serde(default)]
+    pub access_key: Option<String>,
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    #[serde(default)]
+    pub pkcs12_password: Option<String>,
+}
+
+/// Addresses syslog messages are received on, over TCP, UDP or both, and the log they are
+/// stored on
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SyslogListener {
+    pub log: String,
+    #[serde(default)]
+    pub tcp_address: Option<String>,
+    #[serde(default)]
+    pub udp_address: Option<String>,
+}
+
+/// Requests whose path starts with `prefix` are written to the access log with a probability of
+/// `sample`, `0` leaves them all out. The rule with the longest prefix applies.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccessLogRule {
+    pub prefix: String,
+    pub sample: f64,
+}
+
+impl AccessLogRule {
+    /// Parses a `prefix=sample` rule, ie: `/ui=0` or `/api/status=0.01`
+    pub fn parse(rule: &str) -> Result<AccessLogRule, String> {
+        let mut parts = rule.splitn(2, '=');
+        let prefix = parts.next().unwrap_or("").trim();
+        let sample = parts.next().and_then(|s| s.trim().parse::<f64>().ok());
+        match sample {
+            Some(sample) if prefix.starts_with('/') && sample >= 0.0 && sample <= 1.0 => {
+                Ok(AccessLogRule {
+                    prefix: prefix.to_string(),
+                    sample,
+                })
+            }
+            _ => Err(format!(
+                "Invalid access log rule `{}`, expected a path prefix and a share between 0 and 1, ie: `/ui=0`",
+                rule
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AutoCreateLogs {
+    pub datastores: Vec<String>,
+    #[serde(default = "def_auto_create_commit_window")]
+    pub commit_window: String,
+}
+
+impl AutoCreateLogs {
+    /// The log created for `log_name` out of the template
+    pub fn log_for(&self, log_name: &str) -> Log {
+        Log {
+            name: Some(log_name.to_string()),
+            datastores: self.datastores.clone(),
+            commit_window: self.commit_window.clone(),
+            ..Log::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct AuthProviders {
+    pub oidc: Option<OidcProvider>,
+    pub ldap: Option<LdapProvider>,
+    // Role of the members of each group, either `admin` or `viewer`
+    #[serde(default)]
+    pub role_mapping: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OidcProvider {
+    // Endpoint bearer tokens are checked on, it answers with the claims of their user
+    pub userinfo_url: String,
+    pub groups_claim: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LdapProvider {
+    pub url: String,
+    // DN users bind as, `{}` is replaced by the username, ie: `uid={},ou=people,dc=example,dc=com`
+    pub user_dn: String,
+    pub groups_attribute: String,
+    // Whether `ldap://` urls may be used without StartTLS, sending passwords in the clear
+    #[serde(default = "def_false")]
+    pub allow_plaintext: bool,
+}
+
+fn def_prefetch_depth() -> usize {
+    DEFAULT_PREFETCH_DEPTH
+}
+
+fn def_query_memory_limit() -> usize {
+    DEFAULT_QUERY_MEMORY_LIMIT
+}
+
+fn def_max_logs() -> usize {
+    DEFAULT_MAX_LOGS
+}
+
+fn def_max_tokens() -> usize {
+    DEFAULT_MAX_TOKENS
+}
+
+fn def_max_datastores() -> usize {
+    DEFAULT_MAX_DATASTORES
+}
+
+fn def_max_concurrent_listings() -> usize {
+    DEFAULT_MAX_CONCURRENT_LISTINGS
+}
+
+fn def_deleted_log_grace_secs() -> u64 {
+    DEFAULT_DELETED_LOG_GRACE_SECS
+}
+
+fn def_unbounded_query_max_rows() -> usize {
+    DEFAULT_UNBOUNDED_QUERY_MAX_ROWS
+}
+
+fn def_unbounded_query_max_bytes() -> usize {
+    DEFAULT_UNBOUNDED_QUERY_MAX_BYTES
+}
+
+fn def_auto_create_commit_window() -> String {
+    DEFAULT_AUTO_CREATE_COMMIT_WINDOW.to_string()
+}
+
+/// Whether `count` objects already reached `limit`, a `0` limit is never reached
+pub fn limit_reached(count: usize, limit: usize) -> bool {
+    limit > 0 && count >= limit
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -66,13 +329,176 @@ pub struct DataStore {
     pub secret_key: String,
     pub bucket: String,
     pub prefix: String,
+    // Bytes per second written to and read from the datastore, uncapped without them
+    #[serde(default)]
+    pub max_upload_bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    pub max_download_bytes_per_sec: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Log {
     pub name: Option<String>,
     pub datastores: Vec<String>,
     pub commit_window: String,
+    // Lines with invalid UTF-8 are decoded replacing the invalid sequences instead of dropped
+    #[serde(default = "def_false")]
+    pub lossy_decoding: bool,
+    // Server side receive time stamping of ingested lines, either `prepend` or `metadata`
+    #[serde(default)]
+    pub stamp: Option<String>,
+    // Storage quotas, enforced as data is flushed to the datastores
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_objects: Option<u64>,
+    // What to do once over quota, either `reject` (the default) or `delete_oldest`
+    #[serde(default)]
+    pub quota_policy: Option<String>,
+    // Every object is written to all the `datastores` of the log, searches read each one from the
+    // fastest healthy datastore and fall back to the others when it fails
+    #[serde(default = "def_false")]
+    pub replicated: bool,
+    // Datastores of the cold tier, objects older than `cold_after` are moved there from the
+    // hot tier, which are the `datastores` of the log
+    #[serde(default)]
+    pub cold_datastores: Vec<String>,
+    #[serde(default)]
+    pub cold_after: Option<String>,
+    // Stores a bloom filter next to every object so queries can skip objects without a literal
+    #[serde(default = "def_false")]
+    pub bloom_filters: bool,
+    // Joins the lines of a multi-line record, ie: a stack trace, into a single line at ingest
+    #[serde(default)]
+    pub multiline: Option<MultilineRule>,
+    // `base64` to store every line wrapped in base64, for lines with arbitrary bytes
+    #[serde(default)]
+    pub encoding: Option<String>,
+    // `gzip` or `zstd` to compress the objects written for the log
+    #[serde(default)]
+    pub compression: Option<String>,
+    // Daily summary of the data ingested on the log, delivered to a webhook
+    #[serde(default)]
+    pub report: Option<LogReport>,
+    // Elasticsearch index names stored on the log by the `_bulk` endpoint, besides its own name.
+    // A trailing `*` matches any suffix, ie: `filebeat-*`
+    #[serde(default)]
+    pub es_indices: Vec<String>,
+    // Loki stream labels the log takes pushes of, a stream matching all of them is stored on the
+    // log, ie: `{"job": "varlogs"}`
+    #[serde(default)]
+    pub loki_labels: HashMap<String, String>,
+    // Another MinSQL server holding the log, queries on the log are forwarded to it and its
+    // results merged with those of the local datastores
+    #[serde(default)]
+    pub remote: Option<RemoteLog>,
+    // Legal hold, until this RFC 3339 time no object of the log is deleted or moved
+    #[serde(default)]
+    pub locked_until: Option<String>,
+    // Lines matching any of these rules are forwarded to a webhook as they're ingested
+    #[serde(default)]
+    pub tee: Vec<TeeRule>,
+    // Layout of the objects under `minsql/{log}/`, ie: `{partition}/{year}/{month}/{day}/{hour}/{uuid}`
+    #[serde(default)]
+    pub object_key: Option<String>,
+    // Other names queries can use for the log, ie: the names it had before being renamed
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    // Flushes identical to one written within this age, ie: `10m`, are not written again, for
+    // shippers re-sending batches after reconnecting
+    #[serde(default)]
+    pub dedup_window: Option<String>,
+    // Fields queries on the log can read by name, computed from the fields of the lines, ie:
+    // `{"latency_ms": "CAST($9 AS INT)"}`
+    #[serde(default)]
+    pub computed_fields: HashMap<String, String>,
+    // The lines of the log are disposable, load tests only store lines on scratch logs
+    #[serde(default = "def_false")]
+    pub scratch: bool,
+}
+
+/// Forwards a copy of the lines of a log matching `pattern` to a webhook, in batches
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TeeRule {
+    // regex the lines are matched against
+    pub pattern: String,
+    pub webhook: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RemoteLog {
+    // ie: `https://minsql.eu-west.example.com:9999`
+    pub endpoint: String,
+    #[serde(default)]
+    pub token: String,
+    // name of the log on the remote server, the same as the local one if not set
+    #[serde(default)]
+    pub log: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogReport {
+    // where the report is POSTed, unless it has a `sink`
+    #[serde(default)]
+    pub webhook: String,
+    // lines matching this `WHERE` clause are counted as errors, ie: `$line LIKE '%" 5__ %'`
+    #[serde(default)]
+    pub error_condition: Option<String>,
+    #[serde(default)]
+    pub sink: Option<OutputSinkConfig>,
+}
+
+/// Where a scheduled output is delivered, see `sinks::sink_for`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct OutputSinkConfig {
+    // `webhook`, `s3` or `log`
+    #[serde(rename = "type")]
+    pub kind: String,
+    // url POSTed to by a `webhook` sink
+    #[serde(default)]
+    pub url: Option<String>,
+    // datastore and key prefix an `s3` sink writes its objects under
+    #[serde(default)]
+    pub datastore: Option<String>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // log a `log` sink stores its outputs on, one line each
+    #[serde(default)]
+    pub log: Option<String>,
+}
+
+/// How to tell the lines continuing a record from the ones starting a new one, a line continues
+/// the record if it matches either setting.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MultilineRule {
+    // lines matching this regex continue the previous record
+    #[serde(default)]
+    pub continuation: Option<String>,
+    // lines starting with a space or a tab continue the previous record
+    #[serde(default = "def_false")]
+    pub indented: bool,
+}
+
+impl Log {
+    /// Names of all the datastores holding data of the log, the hot tier first
+    pub fn all_datastores(&self) -> Vec<String> {
+        self.datastores
+            .iter()
+            .chain(self.cold_datastores.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// End of the legal hold of the log, if it's held at `now`
+    pub fn held_until(&self, now: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let until = DateTime::parse_from_rfc3339(self.locked_until.as_ref()?).ok()?;
+        let until = until.with_timezone(&Utc);
+        if until > *now {
+            Some(until)
+        } else {
+            None
+        }
+    }
 }
 
 // To circumvent serde(default=false) limitation https://github.com/serde-rs/serde/issues/1030
@@ -95,6 +521,17 @@ pub struct Token {
     pub enabled: bool,
     #[serde(default = "def_true")]
     pub api_access: bool,
+    // Networks the token can be used from, ie: `10.0.0.0/8`, any if empty
+    #[serde(default)]
+    pub ip_allowlist: Vec<String>,
+    // Smart fields masked on the results of the token, ie: `$email`
+    #[serde(default)]
+    pub redact: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmartPattern {
+    pub expression: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -113,12 +550,25 @@ impl Config {
             log: HashMap::new(),
             auth: HashMap::new(),
             tokens: HashMap::new(),
+            patterns: HashMap::new(),
         }
     }
 
     pub fn get_log(&self, logname: &String) -> Option<&Log> {
         self.log.get(&logname[..])
     }
+
+    /// Name of the log a query on `name` reads, either the log with that name or the log having
+    /// it as an alias
+    pub fn resolve_log_name(&self, name: &str) -> Option<String> {
+        if self.log.contains_key(name) {
+            return Some(name.to_string());
+        }
+        self.log
+            .iter()
+            .find(|(_, log)| log.aliases.iter().any(|alias| alias == name))
+            .map(|(log_name, _)| log_name.clone())
+    }
     /// Translates a string duration to an unsigned integer
     /// for example, "5s" returns 5
     /// "10m" returns 600
@@ -150,6 +600,24 @@ impl Config {
             _ => None,
         }
     }
+
+    /// Translates an age to seconds, it can be specified in seconds, minutes, hours or days
+    /// for example, "12h" returns 43200
+    /// "7d" returns 604800
+    pub fn age_to_seconds(age: &str) -> Option<u64> {
+        let (unit_index, unit) = age.char_indices().last()?;
+        let multiplier = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        age[..unit_index]
+            .parse::<u64>()
+            .ok()
+            .map(|val| val * multiplier)
+    }
 }
 
 #[derive(Debug)]
@@ -173,6 +641,13 @@ impl fmt::Display for ConfigurationError {
 
 // Loads the configuration file from command arguments and the environment.
 pub fn load_configuration() -> Result<Config, ConfigurationError> {
+    load_configuration_from(env::args())
+}
+
+/// `load_configuration` with the given arguments, the first one being the name of the command
+pub fn load_configuration_from<I: IntoIterator<Item = String>>(
+    args: I,
+) -> Result<Config, ConfigurationError> {
     //load arguments
     let matches = App::new("MinSQL")
         .version("1.0")
@@ -186,65 +661,138 @@ pub fn load_configuration() -> Result<Config, ConfigurationError> {
                 .help("Server binding address, i.e.: 0.0.0.0:9000")
                 .required(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("test-backend")
+                .long("test-backend")
+                .help("Runs on an in memory S3 stub instead of the metabucket, for tests"),
+        )
+        .get_matches_from(args);
 
     // Server address, safe to unwrap since it has a default value.
     let address = matches.value_of("address").unwrap().to_string();
 
-    // Check for configuration on the environment, else return error.
+    // The metabucket is taken from the environment, unless the server runs on the test backend
+    let (metadata_endpoint, metadata_bucket, access_key, secret_key, mut secret_files) =
+        if matches.is_present("test-backend") {
+            let addr = s3stub::start().map_err(|e| {
+                ConfigurationError::new(&format!("Could not start the test backend. {}", e))
+            })?;
+            (
+                format!("http://{}", addr),
+                TEST_BACKEND_BUCKET.to_string(),
+                TEST_BACKEND_ACCESS_KEY.to_string(),
+                TEST_BACKEND_SECRET_KEY.to_string(),
+                SecretFiles::default(),
+            )
+        } else {
+            metabucket_from_env()?
+        };
 
-    let metadata_endpoint: String = match env::var(METABUCKET_ENDPOINT) {
-        Ok(val) => val,
-        Err(e) => {
-            return Err(ConfigurationError::new(&format!(
-                "No meta bucket endpoint environment variable `{}` set. {}",
-                METABUCKET_ENDPOINT, e
-            )));
-        }
+    // Certificates are optional.
+
+    let pkcs12_cert: Option<String> = match env::var(PKCS12_CERT) {
+        Ok(val) => Some(val),
+        Err(_) => None,
     };
 
-    let metadata_bucket: String = match env::var(METABUCKET_NAME) {
-        Ok(val) => val,
-        Err(e) => {
-            return Err(ConfigurationError::new(&format!(
-                "No meta bucket name environment variable `{}` set. {}",
-                METABUCKET_NAME, e
-            )));
-        }
+    let (pkcs12_password, pkcs12_password_file) = match secret_from_env(PKCS12_PASSWORD)? {
+        Some((val, file)) => (Some(val), file),
+        None => (None, None),
     };
 
-    let access_key: String = match env::var(METABUCKET_ACCESS_KEY) {
-        Ok(val) => val,
-        Err(e) => {
-            return Err(ConfigurationError::new(&format!(
-                "No meta bucket endpoint environment variable `{}` set. {}",
-                METABUCKET_ACCESS_KEY, e
-            )));
-        }
+    let prefetch_depth: usize = match env::var(PREFETCH_DEPTH) {
+        Ok(val) => match val.parse::<usize>() {
+            Ok(depth) => depth,
+            Err(e) => {
+                return Err(ConfigurationError::new(&format!(
+                    "Invalid prefetch depth on environment variable `{}`. {}",
+                    PREFETCH_DEPTH, e
+                )));
+            }
+        },
+        Err(_) => DEFAULT_PREFETCH_DEPTH,
     };
 
-    let secret_key: String = match env::var(METABUCKET_SECRET_KEY) {
-        Ok(val) => val,
-        Err(e) => {
-            return Err(ConfigurationError::new(&format!(
-                "No meta bucket endpoint environment variable `{}` set. {}",
-                METABUCKET_SECRET_KEY, e
-            )));
-        }
+    let query_memory_limit: usize = match env::var(QUERY_MEMORY_LIMIT) {
+        Ok(val) => match val.parse::<usize>() {
+            Ok(limit) => limit,
+            Err(e) => {
+                return Err(ConfigurationError::new(&format!(
+                    "Invalid query memory limit on environment variable `{}`. {}",
+                    QUERY_MEMORY_LIMIT, e
+                )));
+            }
+        },
+        Err(_) => DEFAULT_QUERY_MEMORY_LIMIT,
     };
 
-    // Certificates are optional.
+    let max_logs = limit_from_env(MAX_LOGS, DEFAULT_MAX_LOGS)?;
+    let max_tokens = limit_from_env(MAX_TOKENS, DEFAULT_MAX_TOKENS)?;
+    let max_datastores = limit_from_env(MAX_DATASTORES, DEFAULT_MAX_DATASTORES)?;
+    let max_concurrent_listings =
+        limit_from_env(MAX_CONCURRENT_LISTINGS, DEFAULT_MAX_CONCURRENT_LISTINGS)?;
 
-    let pkcs12_cert: Option<String> = match env::var(PKCS12_CERT) {
-        Ok(val) => Some(val),
-        Err(_) => None,
+    let deleted_log_grace_secs = match env::var(DELETED_LOG_GRACE) {
+        Ok(val) => Config::age_to_seconds(&val).ok_or_else(|| {
+            ConfigurationError::new(&format!(
+                "Invalid grace period on environment variable `{}`, expected seconds `30s`, minutes `30m`, hours `12h` or days `7d`",
+                DELETED_LOG_GRACE
+            ))
+        })?,
+        Err(_) => DEFAULT_DELETED_LOG_GRACE_SECS,
     };
 
-    let pkcs12_password: Option<String> = match env::var(PKCS12_PASSWORD) {
+    let unbounded_query_max_rows =
+        limit_from_env(UNBOUNDED_QUERY_MAX_ROWS, DEFAULT_UNBOUNDED_QUERY_MAX_ROWS)?;
+    let unbounded_query_max_bytes =
+        limit_from_env(UNBOUNDED_QUERY_MAX_BYTES, DEFAULT_UNBOUNDED_QUERY_MAX_BYTES)?;
+
+    let auto_create_logs = auto_create_logs_from_env()?;
+
+    let trusted_proxies: Vec<String> = match env::var(TRUSTED_PROXIES) {
+        Ok(val) => {
+            let proxies: Vec<String> = val
+                .split(',')
+                .map(|p| p.trim().to_string())
+                .filter(|p| !p.is_empty())
+                .collect();
+            for proxy in &proxies {
+                if let Err(e) = Cidr::parse(proxy) {
+                    return Err(ConfigurationError::new(&format!(
+                        "Invalid trusted proxy on environment variable `{}`. {}",
+                        TRUSTED_PROXIES, e
+                    )));
+                }
+            }
+            proxies
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let signing_key: Option<String> = match env::var(SIGNING_KEY) {
         Ok(val) => Some(val),
         Err(_) => None,
     };
 
+    let auth_providers = auth_providers_from_env()?;
+
+    let access_log_rules: Vec<AccessLogRule> = match env::var(ACCESS_LOG_RULES) {
+        Ok(val) => val
+            .split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(AccessLogRule::parse)
+            .collect::<Result<Vec<AccessLogRule>, String>>()
+            .map_err(|e| {
+                ConfigurationError::new(&format!(
+                    "{} on environment variable `{}`",
+                    e, ACCESS_LOG_RULES
+                ))
+            })?,
+        Err(_) => Vec::new(),
+    };
+
+    let syslog = syslog_from_env()?;
+
     let server = Server {
         address,
         metadata_endpoint,
@@ -253,6 +801,22 @@ pub fn load_configuration() -> Result<Config, ConfigurationError> {
         secret_key,
         pkcs12_cert,
         pkcs12_password,
+        prefetch_depth,
+        query_memory_limit,
+        max_logs,
+        max_tokens,
+        max_datastores,
+        max_concurrent_listings,
+        deleted_log_grace_secs,
+        unbounded_query_max_rows,
+        unbounded_query_max_bytes,
+        auto_create_logs,
+        trusted_proxies,
+        signing_key,
+        auth_providers,
+        access_log_rules,
+        syslog,
+        secret_files,
     };
 
     let mut configuration = Config::new(server);
@@ -272,11 +836,14 @@ pub fn load_configuration() -> Result<Config, ConfigurationError> {
             root_username.clone().unwrap(),
             Token {
                 access_key: root_username.clone().unwrap(),
-                secret_key: root_password.clone().unwrap(),
+                // kept hashed like any other token secret
+                secret_key: hash_secret(&root_password.clone().unwrap()),
                 is_admin: true,
                 enabled: true,
                 description: None,
                 api_access: false,
+                ip_allowlist: Vec::new(),
+                redact: Vec::new(),
             },
         );
     }
@@ -292,9 +859,250 @@ pub fn load_configuration() -> Result<Config, ConfigurationError> {
     Ok(configuration)
 }
 
+/// Endpoint, bucket, access key and secret key of the metabucket, from the environment. The keys
+/// can be read from files instead, see `secret_from_env`.
+fn metabucket_from_env() -> Result<(String, String, String, String, SecretFiles), ConfigurationError>
+{
+    let metadata_endpoint: String = match env::var(METABUCKET_ENDPOINT) {
+        Ok(val) => val,
+        Err(e) => {
+            return Err(ConfigurationError::new(&format!(
+                "No meta bucket endpoint environment variable `{}` set. {}",
+                METABUCKET_ENDPOINT, e
+            )));
+        }
+    };
+
+    let metadata_bucket: String = match env::var(METABUCKET_NAME) {
+        Ok(val) => val,
+        Err(e) => {
+            return Err(ConfigurationError::new(&format!(
+                "No meta bucket name environment variable `{}` set. {}",
+                METABUCKET_NAME, e
+            )));
+        }
+    };
+
+    let (access_key, access_key_file) = match secret_from_env(METABUCKET_ACCESS_KEY)? {
+        Some(secret) => secret,
+        None => {
+            return Err(ConfigurationError::new(&format!(
+                "No meta bucket access key environment variable `{}` or `{}{}` set.",
+                METABUCKET_ACCESS_KEY, METABUCKET_ACCESS_KEY, SECRET_FILE_SUFFIX
+            )));
+        }
+    };
+
+    let (secret_key, secret_key_file) = match secret_from_env(METABUCKET_SECRET_KEY)? {
+        Some(secret) => secret,
+        None => {
+            return Err(ConfigurationError::new(&format!(
+                "No meta bucket secret key environment variable `{}` or `{}{}` set.",
+                METABUCKET_SECRET_KEY, METABUCKET_SECRET_KEY, SECRET_FILE_SUFFIX
+            )));
+        }
+    };
+
+    let secret_files = SecretFiles {
+        access_key: access_key_file,
+        secret_key: secret_key_file,
+        pkcs12_password: None,
+    };
+    Ok((
+        metadata_endpoint,
+        metadata_bucket,
+        access_key,
+        secret_key,
+        secret_files,
+    ))
+}
+
+/// Reads a secret from the environment variable `name`, or from the file named on `name_FILE`,
+/// ie: a mounted Kubernetes secret. Returns the file it was read from as well, `None` if neither
+/// is set.
+fn secret_from_env(name: &str) -> Result<Option<(String, Option<String>)>, ConfigurationError> {
+    let file_var = format!("{}{}", name, SECRET_FILE_SUFFIX);
+    match (env::var(name), env::var(&file_var)) {
+        (Ok(_), Ok(_)) => Err(ConfigurationError::new(&format!(
+            "Only one of the environment variables `{}` and `{}` can be set",
+            name, file_var
+        ))),
+        (Ok(val), Err(_)) => Ok(Some((val, None))),
+        (Err(_), Ok(path)) => match read_secret_file(&path) {
+            Ok(val) => Ok(Some((val, Some(path)))),
+            Err(e) => Err(ConfigurationError::new(&format!(
+                "Could not read the file on environment variable `{}`. {}",
+                file_var, e
+            ))),
+        },
+        (Err(_), Err(_)) => Ok(None),
+    }
+}
+
+/// Reads the identity providers of the admin API from the environment, `None` if there are none
+/// Syslog listeners, from the environment. An address requires the log messages are stored on
+/// and the other way around.
+fn syslog_from_env() -> Result<Option<SyslogListener>, ConfigurationError> {
+    let mut addresses = Vec::new();
+    for var in &[SYSLOG_TCP_ADDRESS, SYSLOG_UDP_ADDRESS] {
+        let address = match env::var(var) {
+            Ok(val) => {
+                if val.parse::<SocketAddr>().is_err() {
+                    return Err(ConfigurationError::new(&format!(
+                        "Invalid address on environment variable `{}`, expected ie: `0.0.0.0:5514`",
+                        var
+                    )));
+                }
+                Some(val)
+            }
+            Err(_) => None,
+        };
+        addresses.push(address);
+    }
+    let udp_address = addresses.pop().unwrap();
+    let tcp_address = addresses.pop().unwrap();
+    match env::var(SYSLOG_LOG) {
+        Ok(log) if tcp_address.is_some() || udp_address.is_some() => Ok(Some(SyslogListener {
+            log,
+            tcp_address,
+            udp_address,
+        })),
+        Ok(_) => Err(ConfigurationError::new(&format!(
+            "The environment variable `{}` requires `{}` or `{}`",
+            SYSLOG_LOG, SYSLOG_TCP_ADDRESS, SYSLOG_UDP_ADDRESS
+        ))),
+        Err(_) if tcp_address.is_some() || udp_address.is_some() => {
+            Err(ConfigurationError::new(&format!(
+                "The environment variable `{}` is required with `{}` or `{}`",
+                SYSLOG_LOG, SYSLOG_TCP_ADDRESS, SYSLOG_UDP_ADDRESS
+            )))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+fn auth_providers_from_env() -> Result<Option<AuthProviders>, ConfigurationError> {
+    let oidc = match env::var(OIDC_USERINFO_URL) {
+        Ok(userinfo_url) => {
+            match Url::parse(&userinfo_url) {
+                Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => (),
+                _ => {
+                    return Err(ConfigurationError::new(&format!(
+                        "Invalid url on environment variable `{}`",
+                        OIDC_USERINFO_URL
+                    )));
+                }
+            }
+            Some(OidcProvider {
+                userinfo_url,
+                groups_claim: env::var(OIDC_GROUPS_CLAIM)
+                    .unwrap_or_else(|_| DEFAULT_OIDC_GROUPS_CLAIM.to_string()),
+            })
+        }
+        Err(_) => None,
+    };
+    let ldap = match env::var(LDAP_URL) {
+        Ok(url) => {
+            let user_dn = env::var(LDAP_USER_DN).unwrap_or_default();
+            if !user_dn.contains("{}") {
+                return Err(ConfigurationError::new(&format!(
+                    "The environment variable `{}` is required with `{}`, and has to contain `{{}}` for the username",
+                    LDAP_USER_DN, LDAP_URL
+                )));
+            }
+            Some(LdapProvider {
+                url,
+                user_dn,
+                groups_attribute: env::var(LDAP_GROUPS_ATTRIBUTE)
+                    .unwrap_or_else(|_| DEFAULT_LDAP_GROUPS_ATTRIBUTE.to_string()),
+                allow_plaintext: env::var(LDAP_ALLOW_PLAINTEXT)
+                    .map(|v| v == "true")
+                    .unwrap_or(false),
+            })
+        }
+        Err(_) => None,
+    };
+    if oidc.is_none() && ldap.is_none() {
+        return Ok(None);
+    }
+    let mut role_mapping = HashMap::new();
+    let mapping = env::var(AUTH_ROLE_MAPPING).unwrap_or_default();
+    for pair in mapping
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+    {
+        // the group is split at the last `=`, groups given as DNs contain some
+        let mut parts = pair.rsplitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(role), Some(group)) if role == ROLE_ADMIN || role == ROLE_VIEWER => {
+                role_mapping.insert(group.trim().to_string(), role.to_string());
+            }
+            _ => {
+                return Err(ConfigurationError::new(&format!(
+                    "Invalid role mapping `{}` on environment variable `{}`, expected `<group>=admin` or `<group>=viewer`",
+                    pair, AUTH_ROLE_MAPPING
+                )));
+            }
+        }
+    }
+    Ok(Some(AuthProviders {
+        oidc,
+        ldap,
+        role_mapping,
+    }))
+}
+
+/// Reads the template of auto created logs, enabled by listing their datastores on
+/// `MINSQL_AUTO_CREATE_LOGS`, ie: `ds1,ds2`
+fn auto_create_logs_from_env() -> Result<Option<AutoCreateLogs>, ConfigurationError> {
+    let datastores: Vec<String> = match env::var(AUTO_CREATE_LOGS) {
+        Ok(val) => val
+            .split(',')
+            .map(|ds| ds.trim().to_string())
+            .filter(|ds| !ds.is_empty())
+            .collect(),
+        Err(_) => return Ok(None),
+    };
+    if datastores.is_empty() {
+        return Ok(None);
+    }
+    let commit_window = match env::var(AUTO_CREATE_COMMIT_WINDOW) {
+        Ok(val) => {
+            if val != "0" && (val.is_empty() || Config::commit_window_to_seconds(&val).is_none()) {
+                return Err(ConfigurationError::new(&format!(
+                    "Invalid commit window on environment variable `{}`, expected `0`, seconds `5s` or minutes `5m`",
+                    AUTO_CREATE_COMMIT_WINDOW
+                )));
+            }
+            val
+        }
+        Err(_) => def_auto_create_commit_window(),
+    };
+    Ok(Some(AutoCreateLogs {
+        datastores,
+        commit_window,
+    }))
+}
+
+/// Reads a limit, ie: on configuration objects, from the environment variable `name`
+fn limit_from_env(name: &str, default: usize) -> Result<usize, ConfigurationError> {
+    match env::var(name) {
+        Ok(val) => val.parse::<usize>().map_err(|e| {
+            ConfigurationError::new(&format!(
+                "Invalid limit on environment variable `{}`. {}",
+                name, e
+            ))
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
 #[cfg(test)]
 mod config_tests {
-    use crate::config::Config;
+    use std::{env, fs};
+
+    use crate::config::{limit_reached, secret_from_env, AutoCreateLogs, Config, Server};
 
     #[test]
     fn parse_interval() {
@@ -316,4 +1124,82 @@ mod config_tests {
             None
         );
     }
+
+    #[test]
+    fn parse_age() {
+        assert_eq!(Config::age_to_seconds("30s"), Some(30));
+        assert_eq!(Config::age_to_seconds("12h"), Some(43200));
+        assert_eq!(Config::age_to_seconds("7d"), Some(604800));
+        assert_eq!(Config::age_to_seconds("7 days"), None);
+        assert_eq!(Config::age_to_seconds("d"), None);
+        assert_eq!(Config::age_to_seconds(""), None);
+    }
+
+    #[test]
+    fn limits() {
+        assert!(!limit_reached(999, 1000));
+        assert!(limit_reached(1000, 1000));
+        // no limit
+        assert!(!limit_reached(1_000_000, 0));
+    }
+
+    #[test]
+    fn access_log_rules() {
+        assert_eq!(
+            AccessLogRule::parse("/api/status=0.01"),
+            Ok(AccessLogRule {
+                prefix: "/api/status".to_string(),
+                sample: 0.01,
+            })
+        );
+        assert_eq!(AccessLogRule::parse(" /ui = 0 ").unwrap().sample, 0.0);
+        assert!(AccessLogRule::parse("/ui").is_err());
+        assert!(AccessLogRule::parse("ui=0").is_err());
+        assert!(AccessLogRule::parse("/ui=2").is_err());
+    }
+
+    #[test]
+    fn auto_created_log() {
+        let template: AutoCreateLogs = serde_json::from_str(r#"{"datastores":["ds1"]}"#).unwrap();
+        let log = template.log_for("team/service");
+        assert_eq!(log.name, Some("team/service".to_string()));
+        assert_eq!(log.datastores, vec!["ds1".to_string()]);
+        assert_eq!(log.commit_window, "5s");
+    }
+
+    #[test]
+    fn secrets_from_files() {
+        let path = env::temp_dir().join(format!("minsql-test-secret-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "minio123\n").unwrap();
+        env::set_var("MINSQL_TEST_SECRET_FILE", &path);
+        assert_eq!(
+            secret_from_env("MINSQL_TEST_SECRET").unwrap(),
+            Some(("minio123".to_string(), Some(path.clone())))
+        );
+        // the secret can't be set both ways
+        env::set_var("MINSQL_TEST_SECRET", "minio");
+        assert!(secret_from_env("MINSQL_TEST_SECRET").is_err());
+        env::remove_var("MINSQL_TEST_SECRET_FILE");
+        assert_eq!(
+            secret_from_env("MINSQL_TEST_SECRET").unwrap(),
+            Some(("minio".to_string(), None))
+        );
+        env::remove_var("MINSQL_TEST_SECRET");
+        assert_eq!(secret_from_env("MINSQL_TEST_SECRET").unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn server_defaults() {
+        let server: Server = serde_json::from_str(
+            r#"{"address":"","metadata_endpoint":"","metadata_bucket":"","access_key":"","secret_key":""}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&server).unwrap(),
+            serde_json::to_value(&Server::default()).unwrap()
+        );
+        assert_eq!(Server::default().prefetch_depth, 1);
+    }
 }
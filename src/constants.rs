@@ -16,6 +16,57 @@
 
 // Server Defaults
 pub const DEFAULT_SERVER_ADDRESS: &str = "0.0.0.0:9999";
+// Objects fetched ahead of the one being scanned on each datastore while querying
+pub const DEFAULT_PREFETCH_DEPTH: usize = 1;
+// Bytes of lines a query may have read but not scanned yet
+pub const DEFAULT_QUERY_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+// Configuration objects that can be created, `0` lifts the limit
+pub const DEFAULT_MAX_LOGS: usize = 1000;
+pub const DEFAULT_MAX_TOKENS: usize = 1000;
+pub const DEFAULT_MAX_DATASTORES: usize = 100;
+// Listing calls in flight across every datastore, `0` lifts the cap
+pub const DEFAULT_MAX_CONCURRENT_LISTINGS: usize = 16;
+// Deleted logs can be restored for this long before they are emptied from the trash
+pub const DEFAULT_DELETED_LOG_GRACE_SECS: u64 = 7 * 24 * 60 * 60;
+// Output of queries with neither a `LIMIT` nor a `$date` predicate, `0` lifts the limit
+pub const DEFAULT_UNBOUNDED_QUERY_MAX_ROWS: usize = 100_000;
+pub const DEFAULT_UNBOUNDED_QUERY_MAX_BYTES: usize = 100 * 1024 * 1024;
+// Commit window of the logs created on their first lines, unless configured otherwise
+pub const DEFAULT_AUTO_CREATE_COMMIT_WINDOW: &str = "5s";
+// Groups a query with `GROUP BY` may hold before it's failed
+pub const AGGREGATE_MAX_GROUPS: usize = 100_000;
+// Rows a query with `ORDER BY` but no `LIMIT` may hold while sorting before it's failed
+pub const ORDER_BY_MAX_ROWS: usize = 1_000_000;
+
+// Metabucket of the in memory S3 stub the server runs on with `--test-backend`, datastores
+// can use any bucket of its endpoint
+pub const TEST_BACKEND_BUCKET: &str = "minsql-meta";
+pub const TEST_BACKEND_ACCESS_KEY: &str = "minsqltest";
+pub const TEST_BACKEND_SECRET_KEY: &str = "minsqltest";
+// Keys per page of the listings of the stub, as S3 does
+pub const S3STUB_MAX_KEYS: usize = 1000;
+
+// Layout of the objects of a log under `minsql/{log}/`, unless the log sets its own
+pub const DEFAULT_OBJECT_KEY: &str = "{year}/{month}/{day}/{hour}/{uuid}";
+// Partition of the lines of an ingest or the objects of a search, for logs partitioning objects
+pub const HEADER_PARTITION: &str = "MINSQL-PARTITION";
+// UTC offset times are read and bucketed on by a search, ie: `+02:00`
+pub const HEADER_TIMEZONE: &str = "MINSQL-TIMEZONE";
+// Rows of a search come with the byte ranges of `$line` that matched its conditions
+pub const HEADER_HIGHLIGHT: &str = "MINSQL-HIGHLIGHT";
+// Id a client saves a search under, its plan is cached for the next runs of the search
+pub const HEADER_SAVED_SEARCH: &str = "MINSQL-SAVED-SEARCH";
+pub const SAVED_SEARCH_MAX_LEN: usize = 128;
+// Plans of saved searches kept per process, the oldest cached is evicted past it
+pub const QUERY_PLANS_MAX_ENTRIES: usize = 1024;
+pub const PARTITION_MAX_LEN: usize = 128;
+// Partition of the lines sent without one to a log partitioning its objects
+pub const DEFAULT_PARTITION: &str = "default";
+
+// Time the lines were stored at, at the granularity of the time segments of the object keys
+pub const TIME_FIELD: &str = "$time";
+// Time the server received the lines at, on logs stamping them
+pub const RECEIVED_FIELD: &str = "$received";
 
 // Smart Fields
 pub const SF_IP: &str = "$ip";
@@ -25,29 +76,257 @@ pub const SF_QUOTED: &str = "$quoted";
 pub const SF_URL: &str = "$url";
 pub const SF_PHONE: &str = "$phone";
 pub const SF_USER_AGENT: &str = "$user_agent";
+pub const SF_KV: &str = "$kv";
+pub const SF_JSON: &str = "$json";
+pub const SMART_FIELDS: &[&str] = &[
+    SF_IP,
+    SF_EMAIL,
+    SF_DATE,
+    SF_QUOTED,
+    SF_URL,
+    SF_PHONE,
+    SF_USER_AGENT,
+    SF_KV,
+    SF_JSON,
+];
+// Subfields of `$user_agent`, ie: `$user_agent.os`
+pub const USER_AGENT_SUBFIELDS: &[&str] = &[
+    "name",
+    "category",
+    "browser_type",
+    "os",
+    "os_version",
+    "version",
+    "vendor",
+];
 
 pub const SMART_FIELDS_RAW_RE: &str =
-    r"((\$(ip|email|date|url|quoted|phone|user_agent))([0-9]+)*)\b";
+    r"((\$(ip|email|date|url|quoted|phone|user_agent|kv|json))([0-9]+)*)\b";
+
+// Compression of the objects of a log
+pub const COMPRESSION_GZIP: &str = "gzip";
+pub const COMPRESSION_ZSTD: &str = "zstd";
+pub const ZSTD_LEVEL: i32 = 3;
+
+// Line stamping modes for ingested lines
+pub const STAMP_PREPEND: &str = "prepend";
+pub const STAMP_METADATA: &str = "metadata";
+// Object metadata recording the range of times the lines of an object were received at
+pub const RECEIVED_FIRST_METADATA: &str = "minsql-received-first";
+pub const RECEIVED_LAST_METADATA: &str = "minsql-received-last";
+
+// Encoding of a log storing every line wrapped in base64
+pub const ENCODING_BASE64: &str = "base64";
+// Object metadata recording the encoding the lines of an object were written with
+pub const ENCODING_METADATA: &str = "minsql-encoding";
+
+// Replaces the line breaks within a multi-line record so it's stored as a single line
+pub const MULTILINE_JOINER: &str = "\\n";
+
+// Behaviors of a log over its storage quota
+pub const QUOTA_REJECT: &str = "reject";
+pub const QUOTA_DELETE_OLDEST: &str = "delete_oldest";
+
+// Kinds of sinks scheduled outputs are delivered to
+pub const SINK_WEBHOOK: &str = "webhook";
+pub const SINK_S3: &str = "s3";
+pub const SINK_LOG: &str = "log";
+
+// Escape character of `LIKE` patterns, an `ESCAPE` clause is rewritten to it
+pub const LIKE_ESCAPE: char = '\\';
+
+// Value of the `MINSQL-CONSISTENCY` header that flushes the log before searching it
+pub const CONSISTENCY_STRONG: &str = "strong";
+
+// Configuration objects on the metabucket, and the history of their changes
+pub const META_PREFIX: &str = "minsql/meta/";
+pub const CONFIG_HISTORY_PREFIX: &str = "minsql/meta/_history/";
+// Snapshots kept on the history, the oldest are removed past it
+pub const CONFIG_HISTORY_MAX_SNAPSHOTS: usize = 1000;
+// Changes to several configuration objects committed together, until they are all applied
+pub const CHANGESETS_PREFIX: &str = "minsql/meta/_changesets/";
+// State of the background jobs, kept on the metabucket along with the configuration
+pub const JOBS_PREFIX: &str = "minsql/meta/_jobs/";
+// Deleted logs waiting for their grace period to be over
+pub const DELETED_LOGS_PREFIX: &str = "minsql/meta/_trash/logs/";
+// Locks taken on a deleted log while its objects are purged, so a single process purges it, and
+// how long until a lock left by a process that stopped is ignored
+pub const PURGE_LOCKS_PREFIX: &str = "minsql/meta/_locks/purge/";
+pub const PURGE_LOCK_TTL_SECS: i64 = 6 * 60 * 60;
+// First segment of the object keys of a log created with the name of a log still on the trash
+pub const GENERATION_SEGMENT_PREFIX: &str = "gen-";
+// Bytes scanned and stored per day, as `{day}/{process}` since every process keeps its own
+pub const USAGE_PREFIX: &str = "minsql/meta/_usage/";
+// How often the usage of this process is written to the metabucket
+pub const USAGE_PERSIST_SECS: u64 = 60;
+
+// Headers carrying the values of query placeholders, ie: `MINSQL-PARAM-target_ip`
+pub const PARAM_HEADER_PREFIX: &str = "minsql-param-";
+
+// Failed token validations in a row before a client address or access key is locked out, for
+// `LOCKOUT_BASE_SECS` doubling on every further failure up to `LOCKOUT_MAX_SECS`. Failures
+// older than `LOCKOUT_RESET_SECS` are forgiven.
+pub const LOCKOUT_THRESHOLD: u32 = 5;
+pub const LOCKOUT_BASE_SECS: u64 = 1;
+pub const LOCKOUT_MAX_SECS: u64 = 15 * 60;
+pub const LOCKOUT_RESET_SECS: u64 = 15 * 60;
+// Client addresses and access keys tracked at most before the idle ones are forgotten
+pub const LOCKOUT_MAX_TRACKED: usize = 100_000;
+
+// Token secrets are stored as `$sha256$<salt>$<hex digest>`
+pub const SECRET_HASH_PREFIX: &str = "$sha256$";
+pub const SECRET_SALT_LEN: usize = 16;
+// Secrets of the server can be read from the file named on their environment variable with this
+// suffix, the files are checked for changes this often
+pub const SECRET_FILE_SUFFIX: &str = "_FILE";
+pub const SECRET_FILES_POLL_SECS: u64 = 10;
+
+// Elasticsearch version reported to shippers by the `_bulk` compatible endpoint
+pub const ES_COMPATIBLE_VERSION: &str = "7.3.0";
+// Object lock mode of the objects written for a log under legal hold, `GOVERNANCE` lets
+// privileged datastore users still remove them
+pub const OBJECT_LOCK_MODE: &str = "GOVERNANCE";
+
+// Label naming the log of a Loki stream that matches no log's `loki_labels`
+pub const LOKI_LOG_LABEL: &str = "job";
+
+// Syslog frames longer than this close the TCP connection that sent them
+pub const SYSLOG_MAX_FRAME_BYTES: usize = 64 * 1024;
+// Priority of the messages sent without one, `user.notice`
+pub const SYSLOG_DEFAULT_PRIORITY: u8 = 13;
+// Name the bytes stored from syslog messages are accounted to on the usage, as no token sends them
+pub const SYSLOG_USAGE_TOKEN: &str = "syslog";
+
+// Roles identity provider groups can be mapped to on the admin API
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLE_VIEWER: &str = "viewer";
+pub const DEFAULT_OIDC_GROUPS_CLAIM: &str = "groups";
+pub const DEFAULT_LDAP_GROUPS_ATTRIBUTE: &str = "memberOf";
+// Bounds on the exchanges with the LDAP server, so a slow or hostile one can't hold a login
+pub const LDAP_TIMEOUT_SECS: u64 = 10;
+pub const LDAP_MAX_MESSAGE_BYTES: usize = 1 << 20;
+// LDAP logins bound at once, each takes a thread while it blocks, the rest wait their turn
+pub const LDAP_MAX_CONCURRENT_LOGINS: usize = 8;
+
+// Signature of the manifest of signed search results
+pub const SIGNING_ALGORITHM: &str = "HMAC-SHA256";
+// Headers of a count only search
+pub const HEADER_MATCHED_LINES: &str = "X-MinSQL-Matched-Lines";
+pub const HEADER_OBJECTS_SCANNED: &str = "X-MinSQL-Objects-Scanned";
+pub const HEADER_OBJECTS_TOTAL: &str = "X-MinSQL-Objects-Total";
+pub const HEADER_ELAPSED_MS: &str = "X-MinSQL-Elapsed-Ms";
+// Progress events interleaved with the results start with it
+pub const PROGRESS_LINE_PREFIX: &str = "{\"$progress\"";
+// The stats sent after the results of a search cut by the unbounded query limits start with it
+pub const STATS_LINE_PREFIX: &str = "{\"$stats\"";
+// Source the buffered lines of a log are profiled under
+pub const PROFILE_BUFFERED_SOURCE: &str = "buffered";
+// Paged searches, the rows of a page and the token a follow-up search resumes from
+pub const HEADER_PAGE_SIZE: &str = "MINSQL-PAGE-SIZE";
+pub const HEADER_CONTINUATION: &str = "MINSQL-CONTINUATION";
+pub const SEARCH_MAX_PAGE_SIZE: usize = 10_000;
+// Suffix of the marker left by compaction where an object was, and how many markers a
+// continuation token follows, one per compaction its object went through
+pub const MOVED_SUFFIX: &str = ".moved";
+pub const MOVED_MAX_HOPS: usize = 16;
+
+// Wait before restarting a background task that failed
+pub const SUPERVISOR_RESTART_DELAY_SECS: u64 = 5;
+// Finished jobs kept around for `GET /api/jobs` before the oldest ones are forgotten
+pub const JOBS_MAX_FINISHED: usize = 100;
+// Header of the response of a maintenance action carrying the id of its job
+pub const HEADER_JOB_ID: &str = "MINSQL-JOB-ID";
+
+// Request tracing, the request id is echoed back on every response and a W3C trace context is
+// passed along to remote servers
+pub const HEADER_REQUEST_ID: &str = "X-Request-ID";
+pub const HEADER_TRACEPARENT: &str = "traceparent";
+// Longest request id taken from a client, longer ones are replaced by a generated one
+pub const REQUEST_ID_MAX_LEN: usize = 128;
+
+// How often a strongly consistent search checks whether the flushes of its log are done
+pub const FLUSH_WAIT_POLL_MILLIS: u64 = 50;
+// Bytes buffered for a log that get it flushed before its commit window is over
+pub const INGEST_BUFFER_FLUSH_BYTES: u64 = 5 * 1024 * 1024;
+// Bodies sent with `MINSQL-STREAM: true` are stored in batches of whole lines of about this size
+pub const HEADER_STREAM: &str = "MINSQL-STREAM";
+pub const STREAM_BATCH_BYTES: usize = 1024 * 1024;
+
+// Assumptions of the query estimates, the GET latency of datastores not read yet and how fast
+// objects are scanned
+pub const ESTIMATE_DEFAULT_LATENCY_MS: u64 = 50;
+pub const ESTIMATE_SCAN_BYTES_PER_SEC: u64 = 100 * 1024 * 1024;
+// Path of the query estimates of a log, under `/api/logs/{log}`
+pub const ESTIMATE_SUFFIX: &str = "/estimate";
+
+// Period of the log reports, and how many of the most frequent values they list
+pub const REPORT_INTERVAL_SECS: u64 = 24 * 60 * 60;
+pub const REPORT_TOP_VALUES: usize = 10;
+// Distinct values counted per field of a log report, bounding its memory
+pub const REPORT_MAX_DISTINCT: usize = 10_000;
+
+// Lines forwarded by tee rules are POSTed once a batch reaches this many lines, or on the
+// next tick of the tee task
+pub const TEE_BATCH_LINES: usize = 500;
+pub const TEE_INTERVAL_MILLIS: u64 = 1000;
+// Batches POSTed to tee webhooks at once, further full batches are dropped until some are done
+pub const TEE_MAX_IN_FLIGHT: usize = 16;
+// Batches of ingested lines a client watching a log can fall behind before it's dropped, and
+// how often watchers without new lines get a heartbeat
+pub const WATCH_QUEUE_BATCHES: usize = 64;
+pub const WATCH_HEARTBEAT_SECS: u64 = 15;
+
+// How often objects are checked for moving from the hot to the cold tier
+pub const TIERING_INTERVAL_SECS: u64 = 60 * 60;
+
+// Path of the maintenance actions of a log, under `/api/logs/{log}`
+pub const MAINTENANCE_SUFFIX: &str = "/maintenance";
+// Largest object written when compacting the objects of a log
+pub const COMPACT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+// Where a reindex saves how far it got on each datastore, and every how many objects
+pub const REINDEX_PREFIX: &str = "minsql/meta/_reindex/";
+pub const REINDEX_CURSOR_EVERY: u64 = 100;
+
+// Path restoring a deleted log, under `/api/logs/{log}`
+pub const RESTORE_SUFFIX: &str = "/restore";
+// How often the trash is checked for logs past their grace period
+pub const PURGE_INTERVAL_SECS: u64 = 60 * 60;
+
+// Path of the aliases of a log, under `/api/logs/{log}`
+pub const ALIASES_SUFFIX: &str = "/aliases";
+
+// Path removing the lines of a log matching a condition, under `/api/logs/{log}`
+pub const PURGE_SUFFIX: &str = "/purge";
+
+// Path of how far the accepted batches of a log were written, under `/api/logs/{log}`, and the
+// header returning the sequence of an accepted batch
+pub const CHECKPOINT_SUFFIX: &str = "/checkpoint";
+pub const HEADER_SEQUENCE: &str = "MINSQL-SEQUENCE";
+
+// Objects `minsql preflight` writes, reads back and deletes to probe a datastore
+pub const PREFLIGHT_PREFIX: &str = "minsql/_preflight/";
+
+// Corpus generated by `minsql gen-fixtures` when not told otherwise
+pub const FIXTURES_DEFAULT_LINES: usize = 10_000;
+pub const FIXTURES_DEFAULT_SEED: u64 = 1;
+
+// Bounds of the load tests of `POST /api/loadtest`, lines are stored this many times a second and
+// the generated lines are reused past the size of the pool
+pub const LOADTEST_MAX_LINES_PER_SEC: u64 = 100_000;
+pub const LOADTEST_MAX_QUERIES_PER_SEC: u64 = 50;
+pub const LOADTEST_MAX_DURATION_SECS: u64 = 3600;
+pub const LOADTEST_TICKS_PER_SEC: u64 = 10;
+pub const LOADTEST_POOL_LINES: u64 = 10_000;
+// Name the bytes stored by load tests are accounted to on the usage
+pub const LOADTEST_USAGE_TOKEN: &str = "loadtest";
 
 // MIME Types
 pub const UNKNOWN_CONTENT_TYPE: &str = "text/plain";
 pub const IMAGE_JPEG: &str = "image/jpeg";
 pub const APP_JAVASCRIPT: &str = "application/javascript";
 pub const APP_JSON: &str = "application/json";
+pub const APP_NDJSON: &str = "application/x-ndjson";
+pub const TEXT_CSV: &str = "text/csv";
 pub const TEXT_HTML: &str = "text/html";
-
-bitflags! {
-    // ScanFlags determine which regex should be evaluated
-    // If you are adding new values make sure to add the next power of 2 as
-    // they are evaluated using a bitwise operation
-    pub struct ScanFlags: u32 {
-        const NONE = 1;
-        const IP = 2;
-        const EMAIL = 4;
-        const DATE = 8;
-        const QUOTED = 16;
-        const URL = 32;
-        const PHONE = 64;
-        const USER_AGENT = 128;
-    }
-}
+pub const TEXT_EVENT_STREAM: &str = "text/event-stream";
+pub const TEXT_PLAIN_METRICS: &str = "text/plain; version=0.0.4";
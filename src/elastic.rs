@@ -0,0 +1,398 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use futures::{future, Future, Stream};
+use hyper::{header, Body, Method, Request, Response};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::constants::{APP_JSON, ES_COMPATIBLE_VERSION};
+use crate::http::{return_400, return_404, ResponseFuture};
+use crate::ingest::{Ingest, IngestBuffers};
+use crate::trace::request_trace;
+
+/// A document of a `_bulk` request, along with what happened to it
+#[derive(Debug, PartialEq)]
+struct BulkItem {
+    action: String,
+    index: String,
+    id: String,
+    // the document as a single line, `None` if the action can't be stored
+    document: Option<String>,
+    status: u16,
+    error: Option<(String, String)>,
+}
+
+impl BulkItem {
+    fn fail(&mut self, status: u16, kind: &str, reason: &str) {
+        self.status = status;
+        self.error = Some((kind.to_string(), reason.to_string()));
+        self.document = None;
+    }
+
+    /// The item of the `_bulk` response, as Elasticsearch sends it
+    fn response(&self) -> serde_json::Value {
+        let mut result = json!({
+            "_index": self.index,
+            "_type": "_doc",
+            "_id": self.id,
+            "status": self.status,
+        });
+        match &self.error {
+            Some((kind, reason)) => {
+                result["error"] = json!({"type": kind, "reason": reason});
+            }
+            None => {
+                result["result"] = json!("created");
+            }
+        }
+        let mut item = serde_json::Map::new();
+        item.insert(self.action.clone(), result);
+        serde_json::Value::Object(item)
+    }
+}
+
+pub struct Elastic {
+    config: Arc<RwLock<Config>>,
+}
+
+impl Elastic {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> Elastic {
+        Elastic { config: cfg }
+    }
+
+    /// Routes the Elasticsearch compatible endpoints under `/es/`, shippers check the version of
+    /// the cluster before sending `_bulk` requests.
+    pub fn route(
+        &self,
+        req: Request<Body>,
+        path_parts: Vec<&str>,
+        access_token: String,
+        log_ingest_buffers: Arc<IngestBuffers>,
+    ) -> ResponseFuture {
+        let parts: Vec<&str> = path_parts[1..]
+            .iter()
+            .cloned()
+            .filter(|p| !p.is_empty())
+            .collect();
+        match (req.method(), &parts[..]) {
+            (&Method::GET, []) | (&Method::HEAD, []) => Box::new(future::ok(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, APP_JSON)
+                    .body(Body::from(
+                        json!({
+                            "name": "minsql",
+                            "tagline": "You Know, for Search",
+                            "version": {"number": ES_COMPATIBLE_VERSION},
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )),
+            (&Method::POST, ["_bulk"]) | (&Method::PUT, ["_bulk"]) => {
+                self.bulk(req, None, access_token, log_ingest_buffers)
+            }
+            (&Method::POST, [index, "_bulk"]) | (&Method::PUT, [index, "_bulk"]) => {
+                let index = index.to_string();
+                self.bulk(req, Some(index), access_token, log_ingest_buffers)
+            }
+            _ => Box::new(future::ok(return_404())),
+        }
+    }
+
+    /// Stores the documents of a `_bulk` request on the logs their indices map to, every document
+    /// as a line of JSON. Only `index` and `create` actions are supported.
+    fn bulk(
+        &self,
+        req: Request<Body>,
+        default_index: Option<String>,
+        access_token: String,
+        log_ingest_buffers: Arc<IngestBuffers>,
+    ) -> ResponseFuture {
+        let start = Instant::now();
+        let cfg = Arc::clone(&self.config);
+        let trace = request_trace(&req);
+        Box::new(
+            req.into_body()
+                .concat2()
+                .from_err()
+                .and_then(move |entire_body| {
+                    let payload = match String::from_utf8(entire_body.to_vec()) {
+                        Ok(payload) => payload,
+                        Err(_) => {
+                            return future::Either::A(future::ok(return_400(
+                                "Could not understand request",
+                            )))
+                        }
+                    };
+                    let mut items = match parse_bulk(&payload, default_index.as_ref()) {
+                        Ok(items) => items,
+                        Err(e) => return future::Either::A(future::ok(return_400(&e))),
+                    };
+                    // group the documents by log, keeping their order
+                    let mut log_items: Vec<(String, Vec<usize>)> = Vec::new();
+                    let log_names: Vec<Option<String>> = {
+                        let read_cfg = cfg.read().unwrap();
+                        items
+                            .iter()
+                            .map(|item| log_for_index(&read_cfg, &item.index))
+                            .collect()
+                    };
+                    let auth_c = Auth::new(Arc::clone(&cfg));
+                    for (i, (item, log_name)) in items.iter_mut().zip(log_names).enumerate() {
+                        if item.document.is_none() {
+                            continue;
+                        }
+                        let log_name = match log_name {
+                            Some(log_name) => log_name,
+                            None => {
+                                let reason = format!("no such index [{}]", item.index);
+                                item.fail(404, "index_not_found_exception", &reason);
+                                continue;
+                            }
+                        };
+                        if !auth_c.token_has_access_to_log(&access_token, &log_name) {
+                            let reason = format!("not authorized to index [{}]", item.index);
+                            item.fail(403, "security_exception", &reason);
+                            continue;
+                        }
+                        match log_items.iter_mut().find(|(name, _)| *name == log_name) {
+                            Some((_, indexes)) => indexes.push(i),
+                            None => log_items.push((log_name, vec![i])),
+                        }
+                    }
+                    let ingest_c = Ingest::new(Arc::clone(&cfg));
+                    let stores = log_items
+                        .into_iter()
+                        .map(|(log_name, indexes)| {
+                            let lines: Vec<&str> = indexes
+                                .iter()
+                                .filter_map(|i| items[*i].document.as_ref())
+                                .map(|document| document.as_str())
+                                .collect();
+                            let body = lines.join("\n") + "\n";
+                            ingest_c
+                                .store_payload(
+                                    body.as_bytes(),
+                                    Arc::clone(&log_ingest_buffers),
+                                    log_name,
+                                    &access_token,
+                                    None,
+                                    false,
+                                    trace.as_ref(),
+                                )
+                                .map(move |response| (indexes, response.status()))
+                        })
+                        .collect::<Vec<_>>();
+                    future::Either::B(future::join_all(stores).map(move |stored| {
+                        for (indexes, status) in stored {
+                            if status.is_success() {
+                                continue;
+                            }
+                            for i in indexes {
+                                let reason = format!("could not store the document: {}", status);
+                                items[i].fail(status.as_u16(), "store_exception", &reason);
+                            }
+                        }
+                        bulk_response(&items, start)
+                    }))
+                }),
+        )
+    }
+}
+
+/// Parses the action and document line pairs of a `_bulk` request
+fn parse_bulk(payload: &str, default_index: Option<&String>) -> Result<Vec<BulkItem>, String> {
+    let mut items = Vec::new();
+    let mut lines = payload.lines().filter(|line| !line.trim().is_empty());
+    while let Some(action_line) = lines.next() {
+        let action: serde_json::Value = serde_json::from_str(action_line)
+            .map_err(|_| format!("Malformed action line: {}", action_line))?;
+        let (name, meta) = match action.as_object().and_then(|a| a.iter().next()) {
+            Some((name, meta)) => (name.clone(), meta),
+            None => return Err(format!("Malformed action line: {}", action_line)),
+        };
+        let index = match (meta.get("_index"), default_index) {
+            (Some(serde_json::Value::String(index)), _) => index.clone(),
+            (_, Some(index)) => index.clone(),
+            _ => return Err(format!("The action has no index: {}", action_line)),
+        };
+        let id = match meta.get("_id") {
+            Some(serde_json::Value::String(id)) => id.clone(),
+            Some(id) if id.is_number() => id.to_string(),
+            _ => Uuid::new_v4().to_string(),
+        };
+        let mut item = BulkItem {
+            action: name.clone(),
+            index,
+            id,
+            document: None,
+            status: 201,
+            error: None,
+        };
+        match name.as_str() {
+            "index" | "create" => {
+                let source = lines
+                    .next()
+                    .ok_or_else(|| format!("The {} action has no document", name))?;
+                // stored as a single line, whatever its layout on the request
+                match serde_json::from_str::<serde_json::Value>(source) {
+                    Ok(document) => item.document = Some(document.to_string()),
+                    Err(e) => item.fail(400, "mapper_parsing_exception", &e.to_string()),
+                }
+            }
+            "update" => {
+                // logs are append only
+                lines.next();
+                item.fail(
+                    400,
+                    "action_request_validation_exception",
+                    "update is not supported",
+                );
+            }
+            "delete" => {
+                item.fail(
+                    400,
+                    "action_request_validation_exception",
+                    "delete is not supported",
+                );
+            }
+            _ => return Err(format!("Unknown action {}", name)),
+        }
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// The log an index is stored on, the log of the same name or the first with a matching
+/// `es_indices` entry
+fn log_for_index(cfg: &Config, index: &str) -> Option<String> {
+    if cfg.log.contains_key(index) {
+        return Some(index.to_string());
+    }
+    let mut names: Vec<&String> = cfg.log.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .find(|name| {
+            cfg.log[*name].es_indices.iter().any(|pattern| {
+                if pattern.ends_with('*') {
+                    index.starts_with(&pattern[..pattern.len() - 1])
+                } else {
+                    pattern == index
+                }
+            })
+        })
+        .cloned()
+}
+
+fn bulk_response(items: &[BulkItem], start: Instant) -> Response<Body> {
+    let body = json!({
+        "took": start.elapsed().as_millis() as u64,
+        "errors": items.iter().any(|item| item.error.is_some()),
+        "items": items.iter().map(|item| item.response()).collect::<Vec<_>>(),
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod elastic_tests {
+    use super::*;
+    use crate::config::{Log, Server};
+
+    #[test]
+    fn parse_bulk_actions() {
+        let payload = r#"{"index": {"_index": "filebeat-7.3.0", "_id": "1"}}
+{"message": "GET /info.php",
+ "host": "web1"}
+"#;
+        // documents have to be on a single line
+        assert!(parse_bulk(payload, None).is_err());
+        let payload = r#"{"index": {"_index": "filebeat-7.3.0", "_id": "1"}}
+{"message": "GET /info.php", "host": "web1"}
+{"create": {"_id": 2}}
+{"message": "GET /index.html"}
+{"delete": {"_index": "filebeat-7.3.0", "_id": "1"}}
+{"update": {"_index": "filebeat-7.3.0", "_id": "1"}}
+{"doc": {"host": "web2"}}
+"#;
+        let default_index = "weblogs".to_string();
+        let items = parse_bulk(payload, Some(&default_index)).unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0].index, "filebeat-7.3.0");
+        assert_eq!(items[0].id, "1");
+        assert_eq!(
+            items[0].document,
+            Some(r#"{"host":"web1","message":"GET /info.php"}"#.to_string())
+        );
+        assert_eq!(items[1].action, "create");
+        assert_eq!(items[1].index, "weblogs");
+        assert_eq!(items[1].id, "2");
+        assert_eq!(items[2].status, 400);
+        assert!(items[2].document.is_none());
+        assert_eq!(items[3].action, "update");
+        assert!(items[3].error.is_some());
+        // without a default index every action needs one
+        assert!(parse_bulk(r#"{"index": {}}"#, None).is_err());
+    }
+
+    #[test]
+    fn map_indices_to_logs() {
+        let mut cfg = Config::new(Server::default());
+        cfg.log.insert("weblogs".to_string(), Log::default());
+        cfg.log.insert(
+            "beats".to_string(),
+            Log {
+                es_indices: vec!["filebeat-*".to_string(), "metrics".to_string()],
+                ..Default::default()
+            },
+        );
+        assert_eq!(log_for_index(&cfg, "weblogs"), Some("weblogs".to_string()));
+        assert_eq!(
+            log_for_index(&cfg, "filebeat-7.3.0-2019.08.01"),
+            Some("beats".to_string())
+        );
+        assert_eq!(log_for_index(&cfg, "metrics"), Some("beats".to_string()));
+        assert_eq!(log_for_index(&cfg, "metricbeat-7.3.0"), None);
+    }
+
+    #[test]
+    fn bulk_response_items() {
+        let mut item = BulkItem {
+            action: "index".to_string(),
+            index: "weblogs".to_string(),
+            id: "1".to_string(),
+            document: Some("{}".to_string()),
+            status: 201,
+            error: None,
+        };
+        assert_eq!(item.response()["index"]["result"], "created");
+        item.fail(404, "index_not_found_exception", "no such index [weblogs]");
+        assert_eq!(item.response()["index"]["status"], 404);
+        assert_eq!(
+            item.response()["index"]["error"]["type"],
+            "index_not_found_exception"
+        );
+    }
+}
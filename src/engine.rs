@@ -0,0 +1,302 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The MinSQL query engine, for services scanning logs without running the MinSQL server.
+//!
+//! A `QueryEngine` runs the SQL of `/search` over the lines handed to it by a `LogSource`, the
+//! storage backend of the embedding service, and streams back the same JSON rows. Only the
+//! `log` and `patterns` of the `Config` are used: the logs that can be queried, their encoding,
+//! and the overrides of the smart field patterns.
+//!
+//! ```ignore
+//! struct Files;
+//!
+//! impl LogSource for Files {
+//!     fn read_lines(&self, log_name: &str) -> LineStream {
+//!         let lines = std::fs::read_to_string(format!("/var/log/{}", log_name))
+//!             .map(|text| text.lines().map(String::from).collect())
+//!             .map_err(|e| e.to_string());
+//!         Box::new(futures::stream::once(lines))
+//!     }
+//! }
+//!
+//! let engine = QueryEngine::new(config, Files);
+//! let rows = engine.query("SELECT $ip FROM nginx WHERE $ip = '10.0.0.1' LIMIT 10")?;
+//! ```
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures::future::Either;
+use futures::{stream, Stream};
+
+pub use crate::config::{Config, Log, Server, SmartPattern};
+use crate::constants::ENCODING_BASE64;
+use crate::query::{
+    aggregate_lines, decode_lines, evaluate_lines, group_rows, scan_lines, sorted_rows,
+    statement_log_name, Query, QueryError,
+};
+
+/// Batches of lines read from a log
+pub type LineStream = Box<dyn Stream<Item = Vec<String>, Error = String> + Send>;
+
+/// Rows of a query, as JSON
+pub type RowStream = Box<dyn Stream<Item = String, Error = String> + Send>;
+
+/// Storage backend the engine reads the lines of the logs from
+pub trait LogSource: Send + Sync {
+    /// Streams all the lines stored for a log, in batches. Lines of logs with the `base64`
+    /// encoding are returned as stored, the engine unwraps them.
+    fn read_lines(&self, log_name: &str) -> LineStream;
+}
+
+pub struct QueryEngine {
+    config: Arc<RwLock<Config>>,
+    source: Arc<dyn LogSource>,
+}
+
+impl QueryEngine {
+    pub fn new<S: LogSource + 'static>(config: Config, source: S) -> QueryEngine {
+        QueryEngine {
+            config: Arc::new(RwLock::new(config)),
+            source: Arc::new(source),
+        }
+    }
+
+    /// Runs the statements of `sql` one after the other, streaming their rows. Statements on
+    /// logs missing from the configuration or that can't be parsed fail before anything is read.
+    pub fn query(&self, sql: &str) -> Result<RowStream, String> {
+        let query_c = Query::new(Arc::clone(&self.config));
+        let ast = query_c
+            .parse_query(sql.to_string())
+            .map_err(|e| e.to_string())?;
+        if let Some(e) = query_c.validate_logs(&ast) {
+            return Err(e.to_string());
+        }
+        let mut results: RowStream = Box::new(stream::empty());
+        for statement in ast {
+            let log_name = statement_log_name(&statement).map_err(|e| format!("{:?}", e))?;
+            let log_name = query_c.resolve_log_name(log_name);
+            let base64_lines = self
+                .config
+                .read()
+                .unwrap()
+                .log
+                .get(&log_name)
+                .and_then(|log| log.encoding.clone())
+                .map_or(false, |encoding| encoding == ENCODING_BASE64);
+            let (statement, query_data) = query_c
+                .plan_statement(statement, log_name, false)
+                .map_err(|e| format!("{:?}", e))?;
+            let limit = query_data.limit();
+            let sorter = query_data.row_sorter(limit.map(|limit| limit as usize));
+            // aggregating queries return their groups once every line is read
+            let groups = query_data
+                .group_table()
+                .map(|table| Arc::new(Mutex::new(table)));
+            let final_groups = groups.clone();
+            let source = self.source.read_lines(query_data.log_name());
+            let query_data = Arc::new(Mutex::new(query_data));
+            let final_query_data = Arc::clone(&query_data);
+            let rows = source
+                .and_then(move |lines| {
+                    let lines = if base64_lines {
+                        decode_lines(lines)
+                    } else {
+                        lines
+                    };
+                    let mut query_data = query_data.lock().unwrap();
+                    let pattern_match_results = scan_lines(&mut query_data, &lines);
+                    match &groups {
+                        Some(groups) => aggregate_lines(
+                            &statement,
+                            &query_data,
+                            lines,
+                            pattern_match_results,
+                            &mut groups.lock().unwrap(),
+                        )
+                        .map(|_| Vec::new())
+                        .map_err(|e| e.to_string()),
+                        None => Ok(evaluate_lines(
+                            &statement,
+                            &query_data,
+                            lines,
+                            pattern_match_results,
+                        )),
+                    }
+                })
+                .chain(match final_groups {
+                    Some(groups) => Either::A(stream::once(Ok(())).map(move |_| {
+                        group_rows(&final_query_data.lock().unwrap(), &groups.lock().unwrap())
+                    })),
+                    None => Either::B(stream::empty()),
+                });
+            let rows = sorted_rows(rows.map_err(QueryError::Underlying), sorter)
+                .map_err(|e| match e {
+                    QueryError::Underlying(e) => e,
+                    e => e.to_string(),
+                })
+                .map(stream::iter_ok)
+                .flatten();
+            let rows: RowStream = match limit {
+                Some(limit) => Box::new(rows.take(limit)),
+                None => Box::new(rows),
+            };
+            results = Box::new(results.chain(rows));
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod engine_tests {
+    use std::collections::HashMap;
+
+    use futures::Future;
+
+    use super::*;
+
+    struct MemorySource {
+        logs: HashMap<String, Vec<String>>,
+    }
+
+    impl LogSource for MemorySource {
+        fn read_lines(&self, log_name: &str) -> LineStream {
+            let lines = self.logs.get(log_name).cloned().unwrap_or_default();
+            // two batches, so rows are gathered across them
+            let half = lines.len() / 2;
+            Box::new(stream::iter_ok(vec![
+                lines[..half].to_vec(),
+                lines[half..].to_vec(),
+            ]))
+        }
+    }
+
+    fn engine() -> QueryEngine {
+        let mut log = HashMap::new();
+        log.insert(
+            "weblogs".to_string(),
+            Log {
+                name: Some("weblogs".to_string()),
+                commit_window: "5s".to_string(),
+                ..Default::default()
+            },
+        );
+        let config = Config {
+            server: Server::default(),
+            datastore: HashMap::new(),
+            log,
+            tokens: HashMap::new(),
+            auth: HashMap::new(),
+            patterns: HashMap::new(),
+        };
+        let mut logs = HashMap::new();
+        logs.insert(
+            "weblogs".to_string(),
+            vec![
+                "10.0.0.1 GET /index.html".to_string(),
+                "10.0.0.2 GET /login".to_string(),
+                "10.0.0.1 POST /login".to_string(),
+                "10.0.0.3 GET /about".to_string(),
+            ],
+        );
+        QueryEngine::new(config, MemorySource { logs })
+    }
+
+    #[test]
+    fn query_filters_lines() {
+        let rows = engine()
+            .query("SELECT $1, $3 FROM weblogs WHERE $1 = '10.0.0.1'")
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                "{\"$1\":\"10.0.0.1\",\"$3\":\"/index.html\"}",
+                "{\"$1\":\"10.0.0.1\",\"$3\":\"/login\"}"
+            ]
+        );
+    }
+
+    #[test]
+    fn query_stops_at_limit() {
+        let rows = engine()
+            .query("SELECT $3 FROM weblogs LIMIT 3")
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+    }
+
+    #[test]
+    fn query_groups_lines() {
+        let rows = engine()
+            .query("SELECT $1 AS ip, COUNT(*) AS hits FROM weblogs GROUP BY $1")
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                "{\"hits\":2,\"ip\":\"10.0.0.1\"}",
+                "{\"hits\":1,\"ip\":\"10.0.0.2\"}",
+                "{\"hits\":1,\"ip\":\"10.0.0.3\"}"
+            ]
+        );
+
+        let rows = engine()
+            .query("SELECT COUNT(*), MAX($3) FROM weblogs WHERE $2 = 'GET'")
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(rows, vec!["{\"COUNT(*)\":3,\"MAX($3)\":\"/login\"}"]);
+    }
+
+    #[test]
+    fn query_sorts_rows() {
+        let rows = engine()
+            .query("SELECT $1, $3 FROM weblogs ORDER BY $3 DESC, $1 LIMIT 3")
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                "{\"$1\":\"10.0.0.1\",\"$3\":\"/login\"}",
+                "{\"$1\":\"10.0.0.2\",\"$3\":\"/login\"}",
+                "{\"$1\":\"10.0.0.1\",\"$3\":\"/index.html\"}"
+            ]
+        );
+
+        let rows = engine()
+            .query("SELECT $1 AS ip, COUNT(*) AS hits FROM weblogs GROUP BY $1 ORDER BY hits DESC")
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(rows[0], "{\"hits\":2,\"ip\":\"10.0.0.1\"}");
+    }
+
+    #[test]
+    fn query_unknown_log() {
+        assert!(engine().query("SELECT * FROM applogs").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::future::Either;
+use futures::{future, Async, Future, Poll, Stream};
+use lazy_static::lazy_static;
+use rand::Rng;
+use rusoto_core::ByteStream;
+use serde_derive::{Deserialize, Serialize};
+use tokio::timer::Delay;
+
+use crate::try_ready;
+
+lazy_static! {
+    // Faults injected on the datastore operations, none until set on `/api/faults`
+    static ref FAULTS: RwLock<Option<FaultInjection>> = RwLock::new(None);
+}
+
+/// Faults injected on the operations of the datastores, to test how failures are handled
+/// without breaking a real datastore. Every rate is the share of the operations getting the
+/// fault, between 0 and 1.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FaultInjection {
+    // datastores getting the faults, all of them if empty
+    #[serde(default)]
+    pub datastores: Vec<String>,
+    // operations are held this long before they start
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub latency_rate: f64,
+    // operations fail as if the datastore answered with an error
+    #[serde(default)]
+    pub error_rate: f64,
+    // reads end halfway through their first chunk, as if the connection was cut
+    #[serde(default)]
+    pub truncate_rate: f64,
+}
+
+impl FaultInjection {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, rate) in &[
+            ("latency_rate", self.latency_rate),
+            ("error_rate", self.error_rate),
+            ("truncate_rate", self.truncate_rate),
+        ] {
+            if !(*rate >= 0.0 && *rate <= 1.0) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        Ok(())
+    }
+
+    fn applies_to(&self, ds_name: &str) -> bool {
+        self.datastores.is_empty() || self.datastores.iter().any(|ds| ds == ds_name)
+    }
+}
+
+/// Sets the faults injected from now on, `None` stops injecting them
+pub fn set_faults(faults: Option<FaultInjection>) {
+    *FAULTS.write().unwrap() = faults;
+}
+
+pub fn current_faults() -> Option<FaultInjection> {
+    FAULTS.read().unwrap().clone()
+}
+
+fn faults_for(ds_name: &str) -> Option<FaultInjection> {
+    FAULTS
+        .read()
+        .unwrap()
+        .as_ref()
+        .filter(|faults| faults.applies_to(ds_name))
+        .cloned()
+}
+
+/// Resolves once an operation on `ds_name` can start, after the injected latency, or fails
+/// with the injected error
+pub fn inject(ds_name: &str) -> impl Future<Item = (), Error = String> {
+    let faults = match faults_for(ds_name) {
+        Some(faults) => faults,
+        None => return Either::A(future::ok(())),
+    };
+    let mut rng = rand::thread_rng();
+    let delayed = faults.latency_ms > 0 && rng.gen::<f64>() < faults.latency_rate;
+    let failed = rng.gen::<f64>() < faults.error_rate;
+    let ds_name = ds_name.to_string();
+    let outcome = move || {
+        if failed {
+            Err(format!("Injected fault on datastore {}", ds_name))
+        } else {
+            Ok(())
+        }
+    };
+    if delayed {
+        let until = Instant::now() + Duration::from_millis(faults.latency_ms);
+        Either::B(Either::A(Delay::new(until).then(move |_| outcome())))
+    } else {
+        Either::B(Either::B(future::result(outcome())))
+    }
+}
+
+/// Truncates the body of an object read from `ds_name` at the injected rate
+pub fn truncate(ds_name: &str, body: ByteStream) -> ByteStream {
+    let truncated = match faults_for(ds_name) {
+        Some(faults) => rand::thread_rng().gen::<f64>() < faults.truncate_rate,
+        None => false,
+    };
+    if truncated {
+        ByteStream::new(Truncated {
+            inner: body,
+            done: false,
+        })
+    } else {
+        body
+    }
+}
+
+/// A body ending halfway through its first chunk
+struct Truncated<S> {
+    inner: S,
+    done: bool,
+}
+
+impl<S> Stream for Truncated<S>
+where
+    S: Stream<Item = Bytes, Error = io::Error>,
+{
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        let chunk = try_ready!(self.inner.poll());
+        self.done = true;
+        Ok(Async::Ready(
+            chunk.map(|chunk| chunk.slice_to(chunk.len() / 2)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod faults_tests {
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn fault_rates() {
+        let faults: FaultInjection =
+            serde_json::from_str(r#"{"datastores":["ds1"],"error_rate":0.5}"#).unwrap();
+        assert!(faults.validate().is_ok());
+        assert!(faults.applies_to("ds1"));
+        assert!(!faults.applies_to("ds2"));
+        assert!(FaultInjection::default().applies_to("ds2"));
+        let faults = FaultInjection {
+            truncate_rate: 1.5,
+            ..Default::default()
+        };
+        assert!(faults.validate().is_err());
+    }
+
+    #[test]
+    fn injected_faults() {
+        set_faults(Some(FaultInjection {
+            datastores: vec!["broken".to_string()],
+            error_rate: 1.0,
+            truncate_rate: 1.0,
+            ..Default::default()
+        }));
+        assert!(inject("broken").wait().is_err());
+        assert!(inject("healthy").wait().is_ok());
+        let body = || {
+            ByteStream::new(stream::iter_ok(vec![
+                Bytes::from(&b"first\nline"[..]),
+                Bytes::from(&b"second"[..]),
+            ]))
+        };
+        let read = truncate("broken", body()).concat2().wait().unwrap();
+        assert_eq!(&read[..], b"first");
+        let read = truncate("healthy", body()).concat2().wait().unwrap();
+        assert_eq!(&read[..], b"first\nlinesecond");
+        set_faults(None);
+        assert!(inject("broken").wait().is_ok());
+    }
+}
@@ -0,0 +1,173 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex};
+
+use futures::future::Either;
+use futures::{stream, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use sqlparser::ast::{ObjectName, SetExpr, Statement, TableFactor};
+use url::Url;
+
+use crate::config::RemoteLog;
+use crate::constants::{PROGRESS_LINE_PREFIX, STATS_LINE_PREFIX};
+
+lazy_static! {
+    // Shared so connections to the same remote servers are reused
+    static ref REMOTE_CLIENT: Option<Client<HttpsConnector<HttpConnector>>> =
+        HttpsConnector::new(1)
+            .map(|https| Client::builder().build(https))
+            .ok();
+}
+
+/// Whether the endpoint of a remote log can be reached, only `http` and `https` are supported
+pub fn valid_remote_endpoint(endpoint: &str) -> bool {
+    match Url::parse(endpoint) {
+        Ok(url) => url.scheme() == "http" || url.scheme() == "https",
+        Err(_) => false,
+    }
+}
+
+/// The statement as it's sent to the remote server, querying the log of the remote instead of
+/// the local one
+pub fn remote_statement(statement: &Statement, remote_log: &str) -> Statement {
+    let mut statement = statement.clone();
+    if let Statement::Query(ref mut query) = statement {
+        if let SetExpr::Select(ref mut select) = query.body {
+            if let Some(table) = select.from.get_mut(0) {
+                if let TableFactor::Table { ref mut name, .. } = table.relation {
+                    *name = ObjectName(vec![format!("\"{}\"", remote_log)]);
+                }
+            }
+        }
+    }
+    statement
+}
+
+/// Runs a query on the remote server of a log via its `/search` endpoint, streaming back the
+/// rows it returns. `headers` are passed along so the rows are shaped as the local ones.
+pub fn remote_search(
+    remote: &RemoteLog,
+    query: String,
+    headers: Vec<(&'static str, String)>,
+) -> impl Stream<Item = Vec<String>, Error = String> {
+    let client = match REMOTE_CLIENT.as_ref() {
+        Some(client) => client,
+        None => return Either::B(stream::once(Err("Could not set up TLS".to_string()))),
+    };
+    let url = format!("{}/search", remote.endpoint.trim_end_matches('/'));
+    let mut builder = Request::builder();
+    builder
+        .method(Method::POST)
+        .uri(&url[..])
+        .header("MINSQL-TOKEN", &remote.token[..]);
+    for (name, value) in headers {
+        builder.header(name, &value[..]);
+    }
+    let req = match builder.body(Body::from(query)) {
+        Ok(req) => req,
+        Err(e) => {
+            let e = format!("Invalid remote {}: {}", url, e);
+            return Either::B(stream::once(Err(e)));
+        }
+    };
+    let url_c = url.clone();
+    let rows = client
+        .request(req)
+        .map_err(move |e| format!("Could not reach remote {}: {}", url_c, e))
+        .and_then(move |resp| {
+            if resp.status().is_success() {
+                Ok(resp.into_body())
+            } else {
+                Err(format!("Remote {} answered {}", url, resp.status()))
+            }
+        })
+        .map(|body| {
+            // rows may be split across chunks, the partial row is kept until the rest arrives
+            let pending = Arc::new(Mutex::new(Vec::new()));
+            let last = Arc::clone(&pending);
+            body.map_err(|e| format!("Could not read from remote: {}", e))
+                .map(move |chunk| {
+                    let mut pending = pending.lock().unwrap();
+                    pending.extend_from_slice(&chunk);
+                    match pending.iter().rposition(|b| *b == b'\n') {
+                        Some(end) => {
+                            let complete: Vec<u8> = pending.drain(..=end).collect();
+                            rows_of(&complete)
+                        }
+                        None => Vec::new(),
+                    }
+                })
+                .chain(stream::once(Ok(())).map(move |_| rows_of(&last.lock().unwrap())))
+        })
+        .flatten_stream();
+    Either::A(rows)
+}
+
+/// The rows of complete lines of remote results, leaving out blank lines, progress events and
+/// stats
+fn rows_of(lines: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(lines)
+        .split('\n')
+        .filter(|line| {
+            !line.is_empty()
+                && !line.starts_with(PROGRESS_LINE_PREFIX)
+                && !line.starts_with(STATS_LINE_PREFIX)
+        })
+        .map(|line| line.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod federation_tests {
+    use super::*;
+    use crate::dialect::MinSQLDialect;
+    use sqlparser::parser::Parser;
+
+    #[test]
+    fn remote_statement_queries_remote_log() {
+        let dialect = MinSQLDialect {};
+        let statement = Parser::parse_sql(
+            &dialect,
+            "SELECT $ip FROM mylog WHERE $ip = '10.0.0.1' LIMIT 10".to_string(),
+        )
+        .unwrap()
+        .remove(0);
+        let remote = remote_statement(&statement, "team/weblogs");
+        assert_eq!(
+            remote.to_string(),
+            "SELECT $ip FROM \"team/weblogs\" WHERE $ip = '10.0.0.1' LIMIT 10"
+        );
+    }
+
+    #[test]
+    fn rows_of_results() {
+        let output = b"{\"$ip\":\"10.0.0.1\"}\n\n{\"$progress\": {\"done\": false}}\n{\"$ip\":\"10.0.0.2\"}\n";
+        assert_eq!(
+            rows_of(output),
+            vec!["{\"$ip\":\"10.0.0.1\"}", "{\"$ip\":\"10.0.0.2\"}"]
+        );
+    }
+
+    #[test]
+    fn remote_endpoints() {
+        assert!(valid_remote_endpoint("https://minsql.eu-west.example.com"));
+        assert!(!valid_remote_endpoint("minsql.eu-west.example.com"));
+    }
+}
@@ -16,9 +16,45 @@
 
 use std::collections::HashMap;
 
+use chrono::{Duration, FixedOffset};
+
+use crate::constants::{LIKE_ESCAPE, RECEIVED_FIELD, SF_DATE, TIME_FIELD};
+use crate::functions::parse_time;
+use crate::naming::TimeRange;
 use crate::query::PatternValue;
 use log::info;
-use sqlparser::ast::{BinaryOperator, Expr, SetExpr, Statement, Value};
+use sqlparser::ast::{BinaryOperator, Expr, SetExpr, Statement, UnaryOperator, Value};
+
+// Operators joining the conditions of the `WHERE` clause
+const CONNECTIVES: &[BinaryOperator] = &[BinaryOperator::And, BinaryOperator::Or];
+// Comparisons of a field with a literal, as `evaluate` applies them
+const FIELD_COMPARISONS: &[BinaryOperator] = &[
+    BinaryOperator::Eq,
+    BinaryOperator::NotEq,
+    BinaryOperator::Like,
+    BinaryOperator::NotLike,
+];
+// Comparisons of `$time` with a time, which bound the objects read, see `time_range`
+const TIME_COMPARISONS: &[BinaryOperator] = &[
+    BinaryOperator::Gt,
+    BinaryOperator::GtEq,
+    BinaryOperator::Lt,
+    BinaryOperator::LtEq,
+];
+// Conditions other than binary operators `evaluate` and `time_range` handle, `BETWEEN` being
+// only for `$time` and `$received`
+const PREDICATES: &[&str] = &["NOT", "IS NULL", "IS NOT NULL", "BETWEEN"];
+
+/// Operators supported on the `WHERE` clause, binary ones spelled as the parser writes them
+pub fn operators() -> Vec<String> {
+    CONNECTIVES
+        .iter()
+        .chain(FIELD_COMPARISONS)
+        .chain(TIME_COMPARISONS)
+        .map(|op| op.to_string())
+        .chain(PREDICATES.iter().map(|p| p.to_string()))
+        .collect()
+}
 
 pub fn line_fails_query_conditions(
     line: &String,
@@ -48,6 +84,19 @@ pub fn evaluate(
         Expr::Nested(nested_ast) => {
             return evaluate(&nested_ast, projection_values, line);
         }
+        // the objects read were written or received on the time range already, see `time_range`
+        // and `received_range`
+        Expr::BinaryOp { left: field, .. } | Expr::Between { expr: field, .. }
+            if is_time_field(field) || is_received_field(field) =>
+        {
+            return true;
+        }
+        Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => {
+            return !evaluate(&expr, projection_values, line);
+        }
         Expr::IsNotNull(ast) => {
             let identifier = match get_identifier_from_ast(&ast) {
                 Some(v) => v,
@@ -56,12 +105,7 @@ pub fn evaluate(
                     return false;
                 }
             };
-            if projection_values.contains_key(&identifier[..]) == false
-                || projection_values[&identifier].is_none()
-            {
-                return false;
-            }
-            return true;
+            return field_value(&identifier, projection_values, line).is_some();
         }
         Expr::IsNull(ast) => {
             let identifier = match get_identifier_from_ast(&ast) {
@@ -71,10 +115,7 @@ pub fn evaluate(
                     return false;
                 }
             };
-            if !projection_values[&identifier].is_none() {
-                return false;
-            }
-            return true;
+            return field_value(&identifier, projection_values, line).is_none();
         }
         Expr::BinaryOp { left, op, right } => {
             let identifier = left.to_string();
@@ -89,159 +130,20 @@ pub fn evaluate(
                     let right_eval = evaluate(&right, projection_values, line);
                     return left_eval || right_eval;
                 }
-                BinaryOperator::Eq => {
-                    if identifier != "$line"
-                        && projection_values.contains_key(&identifier[..]) == false
-                    {
-                        return false;
-                    }
-
-                    // TODO: Optimize this op_value preparation, don't do it in the loop
-                    let op_value = match **right {
-                        Expr::Identifier(ref right_value) => {
-                            // Did they used double quotes for the value?
-                            let mut str_id = right_value.to_string();
-                            if str_id.starts_with("\"") {
-                                str_id = str_id[1..][..str_id.len() - 2].to_string();
-                            }
-                            str_id
-                        }
-                        Expr::Value(ref right_value) => match right_value {
-                            Value::SingleQuotedString(s) => s.to_string(),
-                            _ => right_value.to_string(),
-                        },
-                        _ => "".to_string(),
-                    };
-
-                    if let Some(ref s) = projection_values.get(&identifier).unwrap() {
-                        match s {
-                            PatternValue::LineData(ld) => {
-                                return &line[ld.from as usize..ld.to as usize] == &op_value;
-                            }
-                            PatternValue::RichData(rd) => {
-                                return rd == &op_value;
-                            }
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                BinaryOperator::NotEq => {
-                    if identifier != "$line"
-                        && projection_values.contains_key(&identifier[..]) == false
-                    {
-                        return false;
-                    }
-                    // TODO: Optimize this op_value preparation, don't do it in the loop
-                    let op_value = match **right {
-                        Expr::Identifier(ref right_value) => {
-                            // Did they used double quotes for the value?
-                            let mut str_id = right_value.to_string();
-                            if str_id.starts_with("\"") {
-                                str_id = str_id[1..][..str_id.len() - 2].to_string();
-                            }
-                            str_id
-                        }
-                        Expr::Value(ref right_value) => match right_value {
-                            Value::SingleQuotedString(s) => s.to_string(),
-                            _ => right_value.to_string(),
-                        },
-                        _ => "".to_string(),
-                    };
-                    if let Some(ref s) = projection_values.get(&identifier).unwrap() {
-                        match s {
-                            PatternValue::LineData(ld) => {
-                                return &line[ld.from as usize..ld.to as usize] != &op_value;
-                            }
-                            PatternValue::RichData(rd) => {
-                                return rd != &op_value;
-                            }
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                BinaryOperator::Like => {
-                    if identifier != "$line"
-                        && projection_values.contains_key(&identifier[..]) == false
-                    {
-                        return false;
-                    }
-                    // TODO: Optimize this op_value preparation, don't do it in the loop
-                    let op_value = match **right {
-                        Expr::Identifier(ref right_value) => {
-                            // Did they used double quotes for the value?
-                            let mut str_id = right_value.to_string();
-                            if str_id.starts_with("\"") {
-                                str_id = str_id[1..][..str_id.len() - 2].to_string();
-                            }
-                            str_id
-                        }
-                        Expr::Value(ref right_value) => match right_value {
-                            Value::SingleQuotedString(s) => s.to_string(),
-                            _ => right_value.to_string(),
-                        },
-                        _ => "".to_string(),
+                op if FIELD_COMPARISONS.contains(op) => {
+                    // a field without a value is NULL, which fails any comparison
+                    let value = match field_value(&identifier, projection_values, line) {
+                        Some(v) => v,
+                        None => return false,
                     };
-                    // TODO: Add support for wildcards ie: LIKE 'server_.domain.com' where _ is a single character wildcard
-                    if identifier == "$line" {
-                        return line.contains(&op_value[..]);
-                    } else {
-                        if let Some(ref s) = projection_values.get(&identifier).unwrap() {
-                            match s {
-                                PatternValue::LineData(ld) => {
-                                    return line[ld.from as usize..ld.to as usize]
-                                        .contains(&op_value);
-                                }
-                                PatternValue::RichData(rd) => {
-                                    return rd.contains(&op_value);
-                                }
-                            }
-                        } else {
-                            return false;
-                        }
-                    }
-                }
-                BinaryOperator::NotLike => {
-                    if identifier != "$line"
-                        && projection_values.contains_key(&identifier[..]) == false
-                    {
-                        return false;
-                    }
                     // TODO: Optimize this op_value preparation, don't do it in the loop
-                    let op_value = match **right {
-                        Expr::Identifier(ref right_value) => {
-                            // Did they used double quotes for the value?
-                            let mut str_id = right_value.to_string();
-                            if str_id.starts_with("\"") {
-                                str_id = str_id[1..][..str_id.len() - 2].to_string();
-                            }
-                            str_id
-                        }
-                        Expr::Value(ref right_value) => match right_value {
-                            Value::SingleQuotedString(s) => s.to_string(),
-                            _ => right_value.to_string(),
-                        },
-                        _ => "".to_string(),
+                    let op_value = literal_value(&right);
+                    return match op {
+                        BinaryOperator::Eq => value == op_value,
+                        BinaryOperator::NotEq => value != op_value,
+                        BinaryOperator::Like => like_matches(value, &op_value),
+                        _ => !like_matches(value, &op_value),
                     };
-                    // TODO: Add support for wildcards ie: LIKE 'server_.domain.com' where _ is a single character wildcard
-                    if identifier == "$line" {
-                        return !line.contains(&op_value[..]);
-                    } else {
-                        if let Some(ref s) = projection_values.get(&identifier).unwrap() {
-                            match s {
-                                PatternValue::LineData(ld) => {
-                                    return !line[ld.from as usize..ld.to as usize]
-                                        .contains(&op_value);
-                                }
-                                PatternValue::RichData(rd) => {
-                                    return !rd.contains(&op_value);
-                                }
-                            }
-                        } else {
-                            return false;
-                        }
-                    }
                 }
                 xop => {
                     info!("Unhandled operator {:?}", xop);
@@ -256,6 +158,543 @@ pub fn evaluate(
     };
 }
 
+/// Byte ranges of the line that made it match the conditions of the query, merged and in order.
+/// Only conditions that hold and read a value from the line add ranges, negated ones never do.
+pub fn matched_spans(
+    line: &String,
+    query: &Statement,
+    projection_values: &HashMap<String, Option<PatternValue>>,
+) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    if let Statement::Query(ref q) = query {
+        if let SetExpr::Select(ref select) = q.body {
+            if let Some(selection) = &select.selection {
+                collect_spans(&selection, projection_values, line, &mut spans);
+            }
+        }
+    }
+    spans.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (from, to) in spans {
+        match merged.last_mut() {
+            Some(last) if from <= last.1 => last.1 = last.1.max(to),
+            _ => merged.push((from, to)),
+        }
+    }
+    merged
+}
+
+fn collect_spans(
+    ast_node: &Expr,
+    projection_values: &HashMap<String, Option<PatternValue>>,
+    line: &String,
+    spans: &mut Vec<(usize, usize)>,
+) {
+    if !evaluate(ast_node, projection_values, line) {
+        return;
+    }
+    match ast_node {
+        Expr::Nested(nested_ast) => collect_spans(&nested_ast, projection_values, line, spans),
+        Expr::IsNotNull(ast) => {
+            if let Some(span) = get_identifier_from_ast(&ast)
+                .and_then(|identifier| field_span(&identifier, projection_values, line))
+            {
+                spans.push(span);
+            }
+        }
+        Expr::BinaryOp { left, op, right } => match op {
+            BinaryOperator::And | BinaryOperator::Or => {
+                collect_spans(&left, projection_values, line, spans);
+                collect_spans(&right, projection_values, line, spans);
+            }
+            BinaryOperator::Eq | BinaryOperator::Like => {
+                let (from, to) = match field_span(&left.to_string(), projection_values, line) {
+                    Some(span) => span,
+                    None => return,
+                };
+                if let BinaryOperator::Eq = op {
+                    spans.push((from, to));
+                    return;
+                }
+                let tokens = like_tokens(&literal_value(&right));
+                // a pattern without wildcards is equal to the whole field
+                if plain_literal(&tokens).is_some() {
+                    spans.push((from, to));
+                    return;
+                }
+                // the literal parts of a pattern with wildcards, wherever they are in the field
+                for literal in like_literals(&tokens) {
+                    for (i, _) in line[from..to].match_indices(&literal[..]) {
+                        spans.push((from + i, from + i + literal.len()));
+                    }
+                }
+            }
+            _ => (),
+        },
+        _ => (),
+    }
+}
+
+/// Where the value of a field is on the line, `None` if it isn't read from it
+fn field_span(
+    identifier: &str,
+    projection_values: &HashMap<String, Option<PatternValue>>,
+    line: &String,
+) -> Option<(usize, usize)> {
+    if identifier == "$line" {
+        return Some((0, line.len()));
+    }
+    match projection_values.get(identifier) {
+        Some(Some(PatternValue::LineData(ld)))
+            if line.get(ld.from as usize..ld.to as usize).is_some() =>
+        {
+            Some((ld.from as usize, ld.to as usize))
+        }
+        _ => None,
+    }
+}
+
+/// Value of a field on a line, `None` when the field is NULL, including fields that were never
+/// extracted for the line such as positional columns past its end.
+fn field_value<'a>(
+    identifier: &str,
+    projection_values: &'a HashMap<String, Option<PatternValue>>,
+    line: &'a String,
+) -> Option<&'a str> {
+    if identifier == "$line" {
+        return Some(&line[..]);
+    }
+    match projection_values.get(identifier) {
+        Some(Some(PatternValue::LineData(ld))) => line.get(ld.from as usize..ld.to as usize),
+        Some(Some(PatternValue::RichData(rd))) => Some(&rd[..]),
+        _ => None,
+    }
+}
+
+/// The value a field is compared against
+fn literal_value(ast: &Expr) -> String {
+    match ast {
+        Expr::Identifier(ref right_value) => {
+            // Did they used double quotes for the value?
+            let mut str_id = right_value.to_string();
+            if str_id.starts_with("\"") {
+                str_id = str_id[1..][..str_id.len() - 2].to_string();
+            }
+            str_id
+        }
+        Expr::Value(ref right_value) => match right_value {
+            Value::SingleQuotedString(s) => s.to_string(),
+            _ => right_value.to_string(),
+        },
+        _ => "".to_string(),
+    }
+}
+
+/// Collects the literals every matching line must contain, which is the case for equality and
+/// `LIKE` conditions over the line or its fields joined by `AND`, for the latter the parts of the
+/// pattern between wildcards. Subfields are excluded since their values may not appear verbatim
+/// in the line.
+pub fn required_literals(ast_node: &Expr) -> Vec<String> {
+    match ast_node {
+        Expr::Nested(nested_ast) => required_literals(&nested_ast),
+        Expr::BinaryOp { left, op, right } => match (op, &**left, &**right) {
+            (BinaryOperator::And, _, _) => {
+                let mut literals = required_literals(&left);
+                literals.extend(required_literals(&right));
+                literals
+            }
+            (
+                BinaryOperator::Eq,
+                Expr::Identifier(_),
+                Expr::Value(Value::SingleQuotedString(s)),
+            ) => vec![s.to_string()],
+            (
+                BinaryOperator::Like,
+                Expr::Identifier(_),
+                Expr::Value(Value::SingleQuotedString(s)),
+            ) => like_literals(&like_tokens(s)),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Whether the conditions read the `$date` smart field, which narrows a query to a time range
+pub fn has_date_predicate(ast_node: &Expr) -> bool {
+    reads_field(ast_node, SF_DATE)
+}
+
+/// Whether the conditions read a field or its subfields
+fn reads_field(ast_node: &Expr, field: &str) -> bool {
+    match ast_node {
+        Expr::Nested(nested_ast) => reads_field(&nested_ast, field),
+        Expr::UnaryOp { expr, .. } => reads_field(&expr, field),
+        Expr::IsNull(ast) | Expr::IsNotNull(ast) => reads_field(&ast, field),
+        Expr::BinaryOp { left, right, .. } => {
+            reads_field(&left, field) || reads_field(&right, field)
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => reads_field(&expr, field) || reads_field(&low, field) || reads_field(&high, field),
+        _ => match get_identifier_from_ast(ast_node) {
+            Some(identifier) => {
+                identifier == field || identifier.starts_with(&format!("{}.", field))
+            }
+            None => false,
+        },
+    }
+}
+
+fn is_time_field(ast: &Expr) -> bool {
+    get_identifier_from_ast(ast).map_or(false, |identifier| identifier == TIME_FIELD)
+}
+
+fn is_received_field(ast: &Expr) -> bool {
+    get_identifier_from_ast(ast).map_or(false, |identifier| identifier == RECEIVED_FIELD)
+}
+
+/// Range of times the lines matching the conditions were stored at, from the comparisons of
+/// `$time` joined by `AND`, ie: `$time BETWEEN '2019-07-24 09:00:00' AND '2019-07-24 12:00:00'`.
+/// Only objects written on the range are read, so `$time` can't be compared anywhere else.
+pub fn time_range(ast_node: &Expr, timezone: &FixedOffset) -> Result<Option<TimeRange>, String> {
+    field_range(ast_node, TIME_FIELD, timezone)
+}
+
+/// Range of times the lines matching the conditions were received at by the server, from the
+/// comparisons of `$received` joined by `AND`. Only objects holding lines received on the range
+/// are read, see the `stamp` option of logs, so `$received` can't be compared anywhere else.
+pub fn received_range(
+    ast_node: &Expr,
+    timezone: &FixedOffset,
+) -> Result<Option<TimeRange>, String> {
+    field_range(ast_node, RECEIVED_FIELD, timezone)
+}
+
+/// Range of times `field` is compared with by the conditions, see `time_range`
+fn field_range(
+    ast_node: &Expr,
+    field: &str,
+    timezone: &FixedOffset,
+) -> Result<Option<TimeRange>, String> {
+    let is_field =
+        |ast: &Expr| get_identifier_from_ast(ast).map_or(false, |identifier| identifier == field);
+    match ast_node {
+        Expr::Nested(nested_ast) => field_range(&nested_ast, field, timezone),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => Ok(
+            match (
+                field_range(&left, field, timezone)?,
+                field_range(&right, field, timezone)?,
+            ) {
+                (Some(a), Some(b)) => Some(a.intersect(b)),
+                (a, b) => a.or(b),
+            },
+        ),
+        Expr::BinaryOp { left, op, right } if is_field(left) && TIME_COMPARISONS.contains(op) => {
+            let time = time_literal(right, field, timezone)?;
+            let range = match op {
+                BinaryOperator::Gt => TimeRange {
+                    from: Some(time + Duration::nanoseconds(1)),
+                    until: None,
+                },
+                BinaryOperator::GtEq => TimeRange {
+                    from: Some(time),
+                    until: None,
+                },
+                BinaryOperator::Lt => TimeRange {
+                    from: None,
+                    until: Some(time),
+                },
+                BinaryOperator::LtEq => TimeRange {
+                    from: None,
+                    until: Some(time + Duration::nanoseconds(1)),
+                },
+                _ => return Err(misplaced_time_field(field)),
+            };
+            Ok(Some(range))
+        }
+        Expr::Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        } if is_field(expr) => Ok(Some(TimeRange {
+            from: Some(time_literal(low, field, timezone)?),
+            until: Some(time_literal(high, field, timezone)? + Duration::nanoseconds(1)),
+        })),
+        _ if reads_field(ast_node, field) => Err(misplaced_time_field(field)),
+        _ => Ok(None),
+    }
+}
+
+fn misplaced_time_field(field: &str) -> String {
+    format!(
+        "`{}` can only be compared with >, >=, <, <= or BETWEEN on conditions joined by AND",
+        field
+    )
+}
+
+/// Time a comparison of `field` is against, times without an offset are on the query's `timezone`
+fn time_literal(
+    ast: &Expr,
+    field: &str,
+    timezone: &FixedOffset,
+) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    match ast {
+        Expr::Value(Value::SingleQuotedString(s)) => parse_time(s, timezone)
+            .map(|time| time.with_timezone(&chrono::Utc))
+            .ok_or_else(|| format!("Invalid time `{}` compared with `{}`", s, field)),
+        x => Err(format!(
+            "`{}` can only be compared with a quoted time, not `{}`",
+            field, x
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LikeToken {
+    Literal(char),
+    // `_`
+    One,
+    // `%`
+    Any,
+}
+
+/// Splits a `LIKE` pattern into its wildcards and literal characters. A backslash takes `%`, `_`
+/// or another backslash literally and is a literal backslash before anything else.
+fn like_tokens(pattern: &str) -> Vec<LikeToken> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            LIKE_ESCAPE => match chars.peek() {
+                Some(&next) if next == '%' || next == '_' || next == LIKE_ESCAPE => {
+                    chars.next();
+                    LikeToken::Literal(next)
+                }
+                _ => LikeToken::Literal(c),
+            },
+            '%' => LikeToken::Any,
+            '_' => LikeToken::One,
+            c => LikeToken::Literal(c),
+        });
+    }
+    tokens
+}
+
+/// The text a pattern without wildcards matches
+fn plain_literal(tokens: &[LikeToken]) -> Option<String> {
+    tokens
+        .iter()
+        .map(|token| match token {
+            LikeToken::Literal(c) => Some(*c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The runs of literal characters between the wildcards of a pattern
+fn like_literals(tokens: &[LikeToken]) -> Vec<String> {
+    let mut literals = vec![String::new()];
+    for token in tokens {
+        match token {
+            LikeToken::Literal(c) => literals.last_mut().unwrap().push(*c),
+            _ => literals.push(String::new()),
+        }
+    }
+    literals.retain(|literal| !literal.is_empty());
+    literals
+}
+
+/// Whether a value matches a `LIKE` pattern as a whole, `%` stands for any characters and `_`
+/// for exactly one, so `GET%` matches values starting with `GET`. Patterns without wildcards
+/// match values equal to them.
+pub fn like_matches(value: &str, pattern: &str) -> bool {
+    let tokens = like_tokens(pattern);
+    if let Some(literal) = plain_literal(&tokens) {
+        return value == literal;
+    }
+    let value: Vec<char> = value.chars().collect();
+    let (mut v, mut t) = (0, 0);
+    // the last `%` seen and where in the value it started matching, to backtrack to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match tokens.get(t) {
+            Some(LikeToken::Any) => {
+                backtrack = Some((t, v));
+                t += 1;
+                continue;
+            }
+            Some(LikeToken::One) => {
+                v += 1;
+                t += 1;
+                continue;
+            }
+            Some(LikeToken::Literal(c)) if *c == value[v] => {
+                v += 1;
+                t += 1;
+                continue;
+            }
+            _ => (),
+        }
+        match backtrack {
+            // the `%` takes one more character
+            Some((any, from)) => {
+                backtrack = Some((any, from + 1));
+                t = any + 1;
+                v = from + 1;
+            }
+            None => return false,
+        }
+    }
+    tokens[t..].iter().all(|token| *token == LikeToken::Any)
+}
+
+/// Rewrites the `LIKE 'pattern' ESCAPE 'c'` clauses of a query, which the parser doesn't take,
+/// into patterns escaped with a backslash. Fails with the reason and the offset of the `ESCAPE`
+/// when its escape character isn't a single character or escapes anything but `%`, `_` or itself.
+pub fn rewrite_like_escapes(sql: &str) -> Result<String, (String, usize)> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut rewritten = String::with_capacity(sql.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '"' {
+            let end = quoted_end(&chars, i, '"');
+            rewritten.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        if c != '\'' {
+            rewritten.push(c);
+            i += 1;
+            continue;
+        }
+        let end = quoted_end(&chars, i, '\'');
+        let after_like = last_word(&rewritten).eq_ignore_ascii_case("LIKE");
+        let escape = if after_like {
+            escape_clause(&chars, end)
+        } else {
+            None
+        };
+        match escape {
+            Some((escape, keyword, clause_end)) => {
+                let pattern = unquote(&chars[i..end]);
+                let escaped = with_backslash_escapes(&pattern, &escape)
+                    .map_err(|reason| (reason, keyword))?;
+                rewritten.push_str(&format!("'{}'", escaped.replace('\'', "''")));
+                i = clause_end;
+            }
+            None => {
+                rewritten.extend(&chars[i..end]);
+                i = end;
+            }
+        }
+    }
+    Ok(rewritten)
+}
+
+/// Index past the quote closing the quoted text starting at `start`, a doubled quote is part of
+/// the text
+fn quoted_end(chars: &[char], start: usize, quote: char) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        if chars[i] == quote {
+            if chars.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// The text of a quoted string, which may be missing its closing quote
+fn unquote(quoted: &[char]) -> String {
+    let end = if quoted.len() > 1 && quoted[quoted.len() - 1] == '\'' {
+        quoted.len() - 1
+    } else {
+        quoted.len()
+    };
+    let text: String = quoted[1..end].iter().collect();
+    text.replace("''", "'")
+}
+
+fn last_word(sql: &str) -> &str {
+    let trimmed = sql.trim_end();
+    let start = trimmed
+        .rfind(|c: char| !c.is_alphanumeric() && c != '_')
+        .map_or(0, |i| i + 1);
+    &trimmed[start..]
+}
+
+/// The `ESCAPE 'c'` clause starting at `start`, if any, along with the index of its keyword and
+/// the index past it
+fn escape_clause(chars: &[char], start: usize) -> Option<(String, usize, usize)> {
+    let skip_blanks = |mut i: usize| {
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    };
+    let keyword = skip_blanks(start);
+    let word: String = chars.iter().skip(keyword).take(6).collect();
+    if !word.eq_ignore_ascii_case("ESCAPE") {
+        return None;
+    }
+    match chars.get(keyword + 6) {
+        Some(c) if c.is_alphanumeric() || *c == '_' => return None,
+        _ => (),
+    }
+    let quote = skip_blanks(keyword + 6);
+    if chars.get(quote) != Some(&'\'') {
+        return None;
+    }
+    let end = quoted_end(chars, quote, '\'');
+    Some((unquote(&chars[quote..end]), keyword, end))
+}
+
+fn with_backslash_escapes(pattern: &str, escape: &str) -> Result<String, String> {
+    let mut escape_chars = escape.chars();
+    let escape = match (escape_chars.next(), escape_chars.next()) {
+        (Some(escape), None) => escape,
+        _ => return Err("ESCAPE takes a single character".to_string()),
+    };
+    let mut escaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == escape {
+            match chars.next() {
+                Some(next)
+                    if next == '%' || next == '_' || (next == escape && next == LIKE_ESCAPE) =>
+                {
+                    escaped.push(LIKE_ESCAPE);
+                    escaped.push(next);
+                }
+                // other escape characters are literal once escaped
+                Some(next) if next == escape => escaped.push(next),
+                _ => {
+                    return Err(format!(
+                        "The escape character {} must be followed by %, _ or itself",
+                        escape
+                    ))
+                }
+            }
+        } else if c == LIKE_ESCAPE {
+            escaped.push(LIKE_ESCAPE);
+            escaped.push(LIKE_ESCAPE);
+        } else {
+            escaped.push(c);
+        }
+    }
+    Ok(escaped)
+}
+
 /// Extracts an `Expr` identifier as a `String`
 pub fn get_identifier_from_ast(ast: &Expr) -> Option<String> {
     match ast {
@@ -284,6 +723,7 @@ mod filter_tests {
                 name: Some(log_name.clone()),
                 datastores: Vec::new(),
                 commit_window: "5s".to_string(),
+                ..Default::default()
             },
         );
 
@@ -303,18 +743,14 @@ mod filter_tests {
 
         let cfg = Config {
             server: Server {
-                address: "".to_string(),
-                metadata_endpoint: "".to_string(),
-                metadata_bucket: "".to_string(),
-                access_key: "".to_string(),
-                secret_key: "".to_string(),
-                pkcs12_cert: None,
-                pkcs12_password: None,
+                query_memory_limit: 1024,
+                ..Server::default()
             },
             datastore: HashMap::new(),
             tokens: HashMap::new(),
             log: log_map,
             auth: auth,
+            patterns: HashMap::new(),
         };
         cfg
     }
@@ -393,6 +829,290 @@ mod filter_tests {
         assert_eq!(identifier, None);
     }
 
+    #[test]
+    fn required_literals_of_conjunctions() {
+        let (query, _) = setup_select(
+            "SELECT * FROM mylog WHERE $ip='192.168.0.1' AND ($line LIKE 'GET' AND $user_agent.name='Chrome')".to_string(),
+            &"".to_string(),
+        );
+        if let Statement::Query(ref q) = query {
+            if let SetExpr::Select(ref select) = q.body {
+                let literals = required_literals(select.selection.as_ref().unwrap());
+                assert_eq!(literals, vec!["192.168.0.1".to_string(), "GET".to_string()]);
+                return;
+            }
+        }
+        panic!("unexpected query");
+    }
+
+    #[test]
+    fn required_literals_of_disjunctions() {
+        let (query, _) = setup_select(
+            "SELECT * FROM mylog WHERE $ip='192.168.0.1' OR $line LIKE 'GET'".to_string(),
+            &"".to_string(),
+        );
+        if let Statement::Query(ref q) = query {
+            if let SetExpr::Select(ref select) = q.body {
+                assert!(required_literals(select.selection.as_ref().unwrap()).is_empty());
+                return;
+            }
+        }
+        panic!("unexpected query");
+    }
+
+    #[test]
+    fn select_like_wildcards() {
+        let line = "GET /search?q=50%25off HTTP/1.1".to_string();
+        let cases = vec![
+            ("$line LIKE 'GET /%?q=%'", true),
+            ("$line LIKE 'GET _search%'", true),
+            ("$line LIKE 'GET __search%'", false),
+            ("$line LIKE 'POST%'", false),
+            // patterns match the whole line
+            ("$line LIKE 'GET%'", true),
+            ("$line LIKE 'search%'", false),
+            ("$line LIKE '%HTTP/1.1'", true),
+            ("$line LIKE '%HTTP/1.0'", false),
+            ("$line LIKE 'GET /search'", false),
+            // literal percent signs, escaped with a backslash or the `ESCAPE` character
+            (r"$line LIKE '%50\%25%'", true),
+            (r"$line LIKE '%60\%25%'", false),
+            ("$line LIKE '%50!%25%' ESCAPE '!'", true),
+            ("$line NOT LIKE '%60!%25%' escape '!'", true),
+            ("$line LIKE '%50!_25%' ESCAPE '!'", false),
+            (
+                "$line LIKE 'GET /search?q=50!%25off HTTP/1.1' ESCAPE '!'",
+                true,
+            ),
+        ];
+        for (condition, expected_pass) in cases {
+            run_test(FilterTestCase {
+                query_stmt: format!("SELECT * FROM mylog WHERE {}", condition),
+                line: line.clone(),
+                expected_pass,
+            });
+        }
+    }
+
+    #[test]
+    fn like_patterns() {
+        assert!(like_matches("server1.domain.com", "server_.domain%"));
+        assert!(!like_matches("server.domain.com", "server_.domain%"));
+        assert!(like_matches("abcabd", "a%d"));
+        assert!(like_matches("a%b", r"%\%%"));
+        assert!(!like_matches("ab", r"%\%%"));
+        // an escaped underscore only matches itself
+        assert!(like_matches("user_id=4", r"user\_id%"));
+        assert!(!like_matches("userXid=4", r"user\_id%"));
+        assert!(like_matches("userXid=4", "user_id%"));
+        // a backslash before anything else is literal
+        assert!(like_matches(r"C:\Windows", r"C:\W%"));
+        assert!(like_matches("anything", "%"));
+        assert!(!like_matches("", "_"));
+        assert!(like_matches("x", "_"));
+        assert!(!like_matches("xy", "_"));
+    }
+
+    #[test]
+    fn like_patterns_are_anchored() {
+        // prefixes
+        assert!(like_matches("GET /index.html", "GET%"));
+        assert!(!like_matches("POST /GET", "GET%"));
+        // suffixes
+        assert!(like_matches("access.log", "%.log"));
+        assert!(!like_matches("access.log.gz", "%.log"));
+        // literals match the whole value only
+        assert!(like_matches("GET", "GET"));
+        assert!(!like_matches("GET /", "GET"));
+        assert!(!like_matches("a GET", "GET"));
+        assert!(like_matches("a GET b", "%GET%"));
+        assert!(like_matches("abab", "a%b"));
+        assert!(!like_matches("abac", "a%b"));
+    }
+
+    #[test]
+    fn like_escape_clauses() {
+        assert_eq!(
+            rewrite_like_escapes("SELECT * FROM mylog WHERE $line LIKE '50!%' ESCAPE '!'"),
+            Ok(r"SELECT * FROM mylog WHERE $line LIKE '50\%'".to_string())
+        );
+        // the escape character is literal when escaped, backslashes always are
+        assert_eq!(
+            rewrite_like_escapes(r"SELECT * FROM t WHERE $1 LIKE 'a!!b\c' ESCAPE '!' AND $2 = 'x'"),
+            Ok(r"SELECT * FROM t WHERE $1 LIKE 'a!b\\c' AND $2 = 'x'".to_string())
+        );
+        // quoted text and literals that aren't patterns are left alone
+        let sql = "SELECT * FROM t WHERE $1 = 'ESCAPE' AND $line LIKE 'it''s' escaped";
+        assert_eq!(rewrite_like_escapes(sql), Ok(sql.to_string()));
+        assert_eq!(
+            rewrite_like_escapes("SELECT * FROM t WHERE $1 LIKE 'a!b' ESCAPE '!'"),
+            Err((
+                "The escape character ! must be followed by %, _ or itself".to_string(),
+                36
+            ))
+        );
+        assert!(rewrite_like_escapes("SELECT * FROM t WHERE $1 LIKE 'a' ESCAPE '!!'").is_err());
+    }
+
+    #[test]
+    fn required_literals_of_like_patterns() {
+        let (query, _) = setup_select(
+            "SELECT * FROM mylog WHERE $line LIKE 'GET /%.php_x' AND $line LIKE '%'".to_string(),
+            &"".to_string(),
+        );
+        if let Statement::Query(ref q) = query {
+            if let SetExpr::Select(ref select) = q.body {
+                let literals = required_literals(select.selection.as_ref().unwrap());
+                assert_eq!(
+                    literals,
+                    vec!["GET /".to_string(), ".php".to_string(), "x".to_string()]
+                );
+                return;
+            }
+        }
+        panic!("unexpected query");
+    }
+
+    #[test]
+    fn date_predicates() {
+        for (sql, expected) in vec![
+            ("SELECT * FROM mylog WHERE $date IS NOT NULL", true),
+            (
+                "SELECT * FROM mylog WHERE $ip = '10.0.0.1' AND ($date LIKE '2019-08-%')",
+                true,
+            ),
+            ("SELECT * FROM mylog WHERE NOT $date = '2019-08-01'", true),
+            ("SELECT * FROM mylog WHERE $line LIKE '%$date%'", false),
+            ("SELECT * FROM mylog WHERE $ip IS NOT NULL", false),
+        ] {
+            let (query, _) = setup_select(sql.to_string(), &"".to_string());
+            let select = match query {
+                Statement::Query(ref q) => match q.body {
+                    SetExpr::Select(ref select) => select.clone(),
+                    _ => panic!("unexpected query"),
+                },
+                _ => panic!("unexpected query"),
+            };
+            assert_eq!(
+                has_date_predicate(select.selection.as_ref().unwrap()),
+                expected,
+                "{}",
+                sql
+            );
+        }
+    }
+
+    fn selection_of(sql: &str) -> Expr {
+        let (query, _) = setup_select(sql.to_string(), &"".to_string());
+        match query {
+            Statement::Query(ref q) => match q.body {
+                SetExpr::Select(ref select) => select.selection.clone().unwrap(),
+                _ => panic!("unexpected query"),
+            },
+            _ => panic!("unexpected query"),
+        }
+    }
+
+    #[test]
+    fn time_ranges() {
+        use chrono::{TimeZone, Utc};
+
+        let utc = FixedOffset::east(0);
+
+        let range = time_range(&selection_of(
+            "SELECT * FROM mylog WHERE $time BETWEEN '2019-07-24 09:00:00' AND '2019-07-24T12:00:00Z' AND $ip IS NOT NULL",
+        ), &utc)
+        .unwrap()
+        .unwrap();
+        assert_eq!(range.from, Some(Utc.ymd(2019, 7, 24).and_hms(9, 0, 0)));
+        assert_eq!(
+            range.until,
+            Some(Utc.ymd(2019, 7, 24).and_hms(12, 0, 0) + Duration::nanoseconds(1))
+        );
+        let range = time_range(&selection_of(
+            "SELECT * FROM mylog WHERE $time >= '2019-07-24 09:00:00' AND ($time < '2019-07-25 00:00:00')",
+        ), &utc)
+        .unwrap()
+        .unwrap();
+        assert_eq!(range.from, Some(Utc.ymd(2019, 7, 24).and_hms(9, 0, 0)));
+        assert_eq!(range.until, Some(Utc.ymd(2019, 7, 25).and_hms(0, 0, 0)));
+        assert_eq!(
+            time_range(
+                &selection_of("SELECT * FROM mylog WHERE $ip IS NOT NULL"),
+                &utc
+            ),
+            Ok(None)
+        );
+        // times without an offset are on the query's time zone, the ones with one keep it
+        let range = time_range(
+            &selection_of(
+                "SELECT * FROM mylog WHERE $time BETWEEN '2019-07-24 09:00:00' AND '2019-07-24T12:00:00Z'",
+            ),
+            &FixedOffset::east(2 * 3600),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(range.from, Some(Utc.ymd(2019, 7, 24).and_hms(7, 0, 0)));
+        assert_eq!(
+            range.until,
+            Some(Utc.ymd(2019, 7, 24).and_hms(12, 0, 0) + Duration::nanoseconds(1))
+        );
+        for sql in vec![
+            "SELECT * FROM mylog WHERE $time > '2019-07-24 09:00:00' OR $ip IS NOT NULL",
+            "SELECT * FROM mylog WHERE NOT $time > '2019-07-24 09:00:00'",
+            "SELECT * FROM mylog WHERE $time = '2019-07-24 09:00:00'",
+            "SELECT * FROM mylog WHERE $time > 'yesterday'",
+        ] {
+            assert!(time_range(&selection_of(sql), &utc).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn time_conditions_pass_every_line() {
+        run_test(FilterTestCase {
+            query_stmt:
+                "SELECT * FROM mylog WHERE $time > '2019-07-24 09:00:00' AND $ip='192.168.0.1'"
+                    .to_string(),
+            line: "192.168.0.1 \"quoted\"".to_string(),
+            expected_pass: true,
+        });
+        run_test(FilterTestCase {
+            query_stmt:
+                "SELECT * FROM mylog WHERE $received <= '2019-07-24 09:00:00' AND $ip='192.168.0.1'"
+                    .to_string(),
+            line: "192.168.0.1 \"quoted\"".to_string(),
+            expected_pass: true,
+        });
+    }
+
+    #[test]
+    fn received_ranges() {
+        use chrono::{TimeZone, Utc};
+
+        let utc = FixedOffset::east(0);
+        let sql = "SELECT * FROM mylog WHERE $received >= '2019-07-24 09:00:00' AND $time < '2019-07-25 00:00:00'";
+        // each field has a range of its own
+        let range = received_range(&selection_of(sql), &utc).unwrap().unwrap();
+        assert_eq!(range.from, Some(Utc.ymd(2019, 7, 24).and_hms(9, 0, 0)));
+        assert_eq!(range.until, None);
+        let range = time_range(&selection_of(sql), &utc).unwrap().unwrap();
+        assert_eq!(range.from, None);
+        assert_eq!(range.until, Some(Utc.ymd(2019, 7, 25).and_hms(0, 0, 0)));
+        assert_eq!(
+            received_range(
+                &selection_of("SELECT * FROM mylog WHERE $time > '2019-07-24 09:00:00'"),
+                &utc
+            ),
+            Ok(None)
+        );
+        for sql in vec![
+            "SELECT * FROM mylog WHERE $received > '2019-07-24 09:00:00' OR $ip IS NOT NULL",
+            "SELECT * FROM mylog WHERE $received = '2019-07-24 09:00:00'",
+        ] {
+            assert!(received_range(&selection_of(sql), &utc).is_err(), "{}", sql);
+        }
+    }
+
     #[test]
     fn select_eq() {
         run_test(FilterTestCase {
@@ -432,7 +1152,7 @@ mod filter_tests {
     #[test]
     fn select_line_like() {
         run_test(FilterTestCase {
-            query_stmt: "SELECT * FROM mylog WHERE $line LIKE 'uo'".to_string(),
+            query_stmt: "SELECT * FROM mylog WHERE $line LIKE '%uo%'".to_string(),
             line: "192.168.0.2 \"quoted\"".to_string(),
             expected_pass: true,
         });
@@ -441,7 +1161,7 @@ mod filter_tests {
     #[test]
     fn select_line_like_fail() {
         run_test(FilterTestCase {
-            query_stmt: "SELECT * FROM mylog WHERE $line LIKE 'zz'".to_string(),
+            query_stmt: "SELECT * FROM mylog WHERE $line LIKE '%zz%'".to_string(),
             line: "192.168.0.2 \"quoted\"".to_string(),
             expected_pass: false,
         });
@@ -450,7 +1170,7 @@ mod filter_tests {
     #[test]
     fn select_line_not_like() {
         run_test(FilterTestCase {
-            query_stmt: "SELECT * FROM mylog WHERE $line NOT LIKE 'zz'".to_string(),
+            query_stmt: "SELECT * FROM mylog WHERE $line NOT LIKE '%zz%'".to_string(),
             line: "192.168.0.2 \"quoted\"".to_string(),
             expected_pass: true,
         });
@@ -459,7 +1179,7 @@ mod filter_tests {
     #[test]
     fn select_line_not_like_fail() {
         run_test(FilterTestCase {
-            query_stmt: "SELECT * FROM mylog WHERE $line NOT LIKE 'uo'".to_string(),
+            query_stmt: "SELECT * FROM mylog WHERE $line NOT LIKE '%uo%'".to_string(),
             line: "192.168.0.2 \"quoted\"".to_string(),
             expected_pass: false,
         });
@@ -610,4 +1330,171 @@ mod filter_tests {
             expected_pass: true,
         });
     }
+
+    #[test]
+    fn select_not_nested_or() {
+        run_test(FilterTestCase {
+            query_stmt: "SELECT * FROM mylog WHERE NOT ($ip='1.2.3.4' OR $ip='5.6.7.8')"
+                .to_string(),
+            line: "192.168.0.1 \"quoted\"".to_string(),
+            expected_pass: true,
+        });
+    }
+
+    #[test]
+    fn select_not_nested_or_fail() {
+        run_test(FilterTestCase {
+            query_stmt: "SELECT * FROM mylog WHERE NOT ($ip='1.2.3.4' OR $ip='5.6.7.8')"
+                .to_string(),
+            line: "5.6.7.8 \"quoted\"".to_string(),
+            expected_pass: false,
+        });
+    }
+
+    #[test]
+    fn select_double_not() {
+        run_test(FilterTestCase {
+            query_stmt:
+                "SELECT * FROM mylog WHERE NOT (NOT $quoted='quoted') AND $ip='192.168.0.1'"
+                    .to_string(),
+            line: "192.168.0.1 \"quoted\"".to_string(),
+            expected_pass: true,
+        });
+    }
+
+    #[test]
+    fn select_not_combined_with_and() {
+        run_test(FilterTestCase {
+            query_stmt: "SELECT * FROM mylog WHERE $ip='192.168.0.1' AND NOT $quoted='quoted'"
+                .to_string(),
+            line: "192.168.0.1 \"quoted\"".to_string(),
+            expected_pass: false,
+        });
+    }
+
+    #[test]
+    fn required_literals_skip_negations() {
+        let (query, _) = setup_select(
+            "SELECT * FROM mylog WHERE $ip='192.168.0.1' AND NOT $line LIKE 'GET'".to_string(),
+            &"".to_string(),
+        );
+        if let Statement::Query(ref q) = query {
+            if let SetExpr::Select(ref select) = q.body {
+                let literals = required_literals(select.selection.as_ref().unwrap());
+                assert_eq!(literals, vec!["192.168.0.1".to_string()]);
+                return;
+            }
+        }
+        panic!("unexpected query");
+    }
+
+    #[test]
+    fn select_positional_is_null_on_short_line() {
+        run_test(FilterTestCase {
+            query_stmt: "SELECT * FROM mylog WHERE $3 IS NULL".to_string(),
+            line: "192.168.0.1 GET".to_string(),
+            expected_pass: true,
+        });
+        run_test(FilterTestCase {
+            query_stmt: "SELECT * FROM mylog WHERE $3 IS NULL".to_string(),
+            line: "192.168.0.1 GET /index.html".to_string(),
+            expected_pass: false,
+        });
+    }
+
+    #[test]
+    fn select_positional_comparisons_on_short_line() {
+        for query_stmt in &[
+            "SELECT * FROM mylog WHERE $3 = '/index.html'",
+            "SELECT * FROM mylog WHERE $3 != '/index.html'",
+            "SELECT * FROM mylog WHERE $3 LIKE '%index%'",
+            "SELECT * FROM mylog WHERE $3 NOT LIKE '%index%'",
+            "SELECT * FROM mylog WHERE $3 IS NOT NULL",
+        ] {
+            run_test(FilterTestCase {
+                query_stmt: query_stmt.to_string(),
+                line: "192.168.0.1 GET".to_string(),
+                expected_pass: false,
+            });
+        }
+    }
+
+    #[test]
+    fn select_positional_on_mixed_length_lines() {
+        let query_stmt = "SELECT * FROM mylog WHERE $3 = '/index.html' OR $3 IS NULL";
+        for (line, expected_pass) in &[
+            ("192.168.0.1", true),
+            ("192.168.0.1 GET", true),
+            ("192.168.0.1 GET /index.html", true),
+            ("192.168.0.1 GET /about.html 200", false),
+        ] {
+            run_test(FilterTestCase {
+                query_stmt: query_stmt.to_string(),
+                line: line.to_string(),
+                expected_pass: *expected_pass,
+            });
+        }
+    }
+
+    #[test]
+    fn unextracted_fields_are_null() {
+        let line = "192.168.0.1 GET".to_string();
+        let projection_values: HashMap<String, Option<PatternValue>> = HashMap::new();
+        assert!(evaluate(
+            &Expr::IsNull(Box::new(Expr::Identifier("$3".to_string()))),
+            &projection_values,
+            &line
+        ));
+        assert!(!evaluate(
+            &Expr::BinaryOp {
+                left: Box::new(Expr::Identifier("$3".to_string())),
+                op: BinaryOperator::NotEq,
+                right: Box::new(Expr::Value(Value::SingleQuotedString("GET".to_string()))),
+            },
+            &projection_values,
+            &line
+        ));
+    }
+
+    #[test]
+    fn select_line_eq() {
+        run_test(FilterTestCase {
+            query_stmt: "SELECT * FROM mylog WHERE $line = '192.168.0.1 GET'".to_string(),
+            line: "192.168.0.1 GET".to_string(),
+            expected_pass: true,
+        });
+    }
+
+    #[test]
+    fn spans_of_the_matching_conditions() {
+        let line = "192.168.0.1 GET /index.html 200 GET".to_string();
+        let query_stmt =
+            "SELECT * FROM mylog WHERE $ip = '192.168.0.1' AND ($line LIKE '%GET%' OR $4 = '404') AND $2 != 'POST'";
+        let (query, projection_values) = setup_select(query_stmt.to_string(), &line);
+        // the `!=` and the failing `OR` branch don't add ranges
+        assert_eq!(
+            matched_spans(&line, &query, &projection_values),
+            vec![(0, 11), (12, 15), (32, 35)]
+        );
+
+        let query_stmt = "SELECT * FROM mylog WHERE $3 LIKE '%index%' AND $line LIKE '%.html 2%'";
+        let (query, projection_values) = setup_select(query_stmt.to_string(), &line);
+        // overlapping ranges are merged
+        assert_eq!(
+            matched_spans(&line, &query, &projection_values),
+            vec![(17, 29)]
+        );
+
+        // a pattern without wildcards matches the whole field
+        let query_stmt = "SELECT * FROM mylog WHERE $3 LIKE '/index.html'";
+        let (query, projection_values) = setup_select(query_stmt.to_string(), &line);
+        assert_eq!(
+            matched_spans(&line, &query, &projection_values),
+            vec![(16, 27)]
+        );
+
+        let query_stmt = "SELECT * FROM mylog WHERE $4 = '404'";
+        let (query, projection_values) = setup_select(query_stmt.to_string(), &line);
+        assert!(matched_spans(&line, &query, &projection_values).is_empty());
+    }
 }
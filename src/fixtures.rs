@@ -0,0 +1,359 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Synthetic log corpora with known densities of smart fields, to check the smart field
+//! patterns find what they should and to benchmark them as they evolve. The same spec always
+//! generates the same lines.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use clap::{App, Arg};
+
+use crate::constants::{FIXTURES_DEFAULT_LINES, FIXTURES_DEFAULT_SEED};
+
+// Smart fields the generator can place on lines
+pub const FIXTURE_FIELDS: [&str; 6] = ["ip", "email", "date", "url", "phone", "user_agent"];
+
+const USER_AGENTS: [&str; 3] = [
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/75.0.3770.100 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_14_5) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/12.1.1 Safari/605.1.15",
+    "Mozilla/5.0 (X11; Linux x86_64; rv:68.0) Gecko/20100101 Firefox/68.0",
+];
+const PATHS: [&str; 4] = ["/index.html", "/login", "/api/v1/items", "/static/app.js"];
+const METHODS: [&str; 3] = ["GET", "POST", "PUT"];
+
+/// What to generate: how many lines and the share of them, from 0 to 1, holding each smart field
+#[derive(Debug, Clone)]
+pub struct FixtureSpec {
+    pub lines: usize,
+    pub seed: u64,
+    // keyed by smart field name without the `$`, ie: `ip`
+    pub densities: BTreeMap<String, f64>,
+}
+
+impl Default for FixtureSpec {
+    fn default() -> FixtureSpec {
+        FixtureSpec {
+            lines: FIXTURES_DEFAULT_LINES,
+            seed: FIXTURES_DEFAULT_SEED,
+            densities: BTreeMap::new(),
+        }
+    }
+}
+
+/// Generated lines, along with how many of them hold each smart field
+#[derive(Debug)]
+pub struct Fixtures {
+    pub lines: Vec<String>,
+    pub counts: BTreeMap<String, usize>,
+}
+
+/// SplitMix64, so corpora don't change with the version of a random number crate
+struct FixtureRng {
+    state: u64,
+}
+
+impl FixtureRng {
+    fn new(seed: u64) -> FixtureRng {
+        FixtureRng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Whether an event of probability `p` happens
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Generates the lines of a spec, access log like lines with the requested smart fields
+/// sprinkled in. Fields without a density are never placed.
+pub fn generate_fixtures(spec: &FixtureSpec) -> Fixtures {
+    let mut rng = FixtureRng::new(spec.seed);
+    let mut counts: BTreeMap<String, usize> = FIXTURE_FIELDS
+        .iter()
+        .map(|field| (field.to_string(), 0))
+        .collect();
+    let density = |field: &str| spec.densities.get(field).cloned().unwrap_or(0.0);
+    let mut lines = Vec::with_capacity(spec.lines);
+    for i in 0..spec.lines {
+        let mut parts: Vec<String> = Vec::new();
+        let mut placed = |field: &str, rng: &mut FixtureRng| {
+            let hit = rng.chance(density(field));
+            if hit {
+                *counts.get_mut(field).unwrap() += 1;
+            }
+            hit
+        };
+        if placed("ip", &mut rng) {
+            parts.push(format!(
+                "10.{}.{}.{}",
+                rng.below(256),
+                rng.below(256),
+                rng.below(254) + 1
+            ));
+        } else {
+            parts.push("-".to_string());
+        }
+        if placed("date", &mut rng) {
+            // the date pattern leaves out October
+            let month = [1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 12][rng.below(11) as usize];
+            parts.push(format!("[2019-{:02}-{:02}]", month, rng.below(28) + 1));
+        }
+        let method = METHODS[rng.below(METHODS.len() as u64) as usize];
+        let path = PATHS[rng.below(PATHS.len() as u64) as usize];
+        parts.push(format!("{} {}", method, path));
+        parts.push(format!(
+            "status={}",
+            [200, 201, 404, 500][rng.below(4) as usize]
+        ));
+        parts.push(format!("seq={}", i % 100_000));
+        if placed("url", &mut rng) {
+            parts.push(format!(
+                "referer=https://www.example.com/page/{}",
+                rng.below(1000)
+            ));
+        }
+        if placed("email", &mut rng) {
+            parts.push(format!(
+                "user=user{}@example{}.com",
+                rng.below(10_000),
+                rng.below(10)
+            ));
+        }
+        if placed("phone", &mut rng) {
+            parts.push(format!(
+                "phone={}-{}-{}",
+                rng.below(800) + 200,
+                rng.below(900) + 100,
+                rng.below(9000) + 1000
+            ));
+        }
+        if placed("user_agent", &mut rng) {
+            let agent = USER_AGENTS[rng.below(USER_AGENTS.len() as u64) as usize];
+            parts.push(format!("\"{}\"", agent));
+        }
+        lines.push(parts.join(" "));
+    }
+    Fixtures { lines, counts }
+}
+
+/// Parses a `field=density` argument
+fn parse_density(arg: &str) -> Result<(String, f64), String> {
+    let mut parts = arg.splitn(2, '=');
+    let field = parts.next().unwrap_or_default();
+    if !FIXTURE_FIELDS.contains(&field) {
+        return Err(format!(
+            "Unknown field `{}`, expected one of {}",
+            field,
+            FIXTURE_FIELDS.join(", ")
+        ));
+    }
+    match parts.next().map(|d| d.parse::<f64>()) {
+        Some(Ok(density)) if density >= 0.0 && density <= 1.0 => Ok((field.to_string(), density)),
+        _ => Err(format!(
+            "The density of `{}` must be between 0 and 1",
+            field
+        )),
+    }
+}
+
+/// `minsql gen-fixtures`, writes a corpus to a file or stdout and the counts of every field to
+/// stderr.
+pub fn gen_fixtures_command<I: IntoIterator<Item = String>>(args: I) -> Result<(), String> {
+    let matches = App::new("minsql gen-fixtures")
+        .about("Generates a synthetic log corpus with known densities of smart fields")
+        .arg(
+            Arg::with_name("lines")
+                .takes_value(true)
+                .short("n")
+                .long("lines")
+                .help("Lines to generate"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .takes_value(true)
+                .long("seed")
+                .help("Seed of the generator, the same seed generates the same lines"),
+        )
+        .arg(
+            Arg::with_name("density")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .short("d")
+                .long("density")
+                .help("Share of the lines holding a field, i.e.: ip=0.5"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .takes_value(true)
+                .short("o")
+                .long("output")
+                .help("File to write the lines to, stdout if not set"),
+        )
+        .get_matches_from_safe(args)
+        .map_err(|e| e.to_string())?;
+
+    let mut spec = FixtureSpec::default();
+    if let Some(lines) = matches.value_of("lines") {
+        spec.lines = lines
+            .parse()
+            .map_err(|e| format!("Invalid number of lines. {}", e))?;
+    }
+    if let Some(seed) = matches.value_of("seed") {
+        spec.seed = seed.parse().map_err(|e| format!("Invalid seed. {}", e))?;
+    }
+    for arg in matches.values_of("density").into_iter().flatten() {
+        let (field, density) = parse_density(arg)?;
+        spec.densities.insert(field, density);
+    }
+
+    let fixtures = generate_fixtures(&spec);
+    let out: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?),
+        None => Box::new(io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+    for line in &fixtures.lines {
+        writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+    }
+    out.flush().map_err(|e| e.to_string())?;
+    for (field, count) in &fixtures.counts {
+        eprintln!("${}: {} lines", field, count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod fixtures_tests {
+    use std::collections::HashMap;
+
+    use futures::{stream, Future, Stream};
+
+    use super::*;
+    use crate::engine::{Config, LineStream, Log, LogSource, QueryEngine, Server};
+
+    fn spec(densities: &[(&str, f64)]) -> FixtureSpec {
+        FixtureSpec {
+            lines: 500,
+            seed: 7,
+            densities: densities
+                .iter()
+                .map(|(field, density)| (field.to_string(), *density))
+                .collect(),
+        }
+    }
+
+    struct FixtureSource {
+        lines: Vec<String>,
+    }
+
+    impl LogSource for FixtureSource {
+        fn read_lines(&self, _log_name: &str) -> LineStream {
+            Box::new(stream::once(Ok(self.lines.clone())))
+        }
+    }
+
+    /// Lines the smart field is found on by the query engine
+    fn found_on(fixtures: &Fixtures, field: &str) -> usize {
+        let mut log = HashMap::new();
+        log.insert(
+            "fixtures".to_string(),
+            Log {
+                name: Some("fixtures".to_string()),
+                commit_window: "5s".to_string(),
+                ..Default::default()
+            },
+        );
+        let config = Config {
+            server: Server::default(),
+            datastore: HashMap::new(),
+            log,
+            tokens: HashMap::new(),
+            auth: HashMap::new(),
+            patterns: HashMap::new(),
+        };
+        let source = FixtureSource {
+            lines: fixtures.lines.clone(),
+        };
+        QueryEngine::new(config, source)
+            .query(&format!("SELECT ${} FROM fixtures", field))
+            .unwrap()
+            .collect()
+            .wait()
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn same_seed_same_lines() {
+        let a = generate_fixtures(&spec(&[("ip", 0.5), ("email", 0.2)]));
+        let b = generate_fixtures(&spec(&[("ip", 0.5), ("email", 0.2)]));
+        assert_eq!(a.lines, b.lines);
+        let mut other = spec(&[("ip", 0.5), ("email", 0.2)]);
+        other.seed = 8;
+        assert_ne!(a.lines, generate_fixtures(&other).lines);
+    }
+
+    #[test]
+    fn densities_are_followed() {
+        let fixtures = generate_fixtures(&spec(&[("ip", 1.0), ("url", 0.3)]));
+        assert_eq!(fixtures.counts["ip"], 500);
+        assert_eq!(fixtures.counts["email"], 0);
+        // roughly 150 of the 500 lines
+        assert!(fixtures.counts["url"] > 100 && fixtures.counts["url"] < 200);
+    }
+
+    #[test]
+    fn patterns_find_placed_fields() {
+        let fixtures = generate_fixtures(&spec(&[
+            ("ip", 0.4),
+            ("email", 0.3),
+            ("date", 0.5),
+            ("url", 0.2),
+            ("phone", 0.1),
+            ("user_agent", 0.25),
+        ]));
+        for field in FIXTURE_FIELDS.iter() {
+            assert_eq!(
+                found_on(&fixtures, field),
+                fixtures.counts[*field],
+                "${}",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn density_arguments() {
+        assert_eq!(parse_density("ip=0.5"), Ok(("ip".to_string(), 0.5)));
+        assert!(parse_density("ip=2").is_err());
+        assert!(parse_density("mac=0.5").is_err());
+    }
+}
@@ -0,0 +1,619 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::mem;
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
+use serde_derive::Serialize;
+use serde_json::Value as JsonValue;
+use sqlparser::ast::{DataType, Expr, Value};
+
+use crate::config::Config;
+use crate::query::PatternValue;
+
+/// Scalar functions supported on projections, ie: `SELECT LOWER($quoted) FROM mylog`
+pub const SCALAR_FUNCTIONS: &[&str] = &["CAST", "LOWER", "SUBSTR", "COALESCE", "TIME_BUCKET"];
+
+/// Type a value is converted to by `CAST`
+#[derive(Debug, Clone, PartialEq)]
+pub enum CastType {
+    Int,
+    Float,
+    Boolean,
+    Text,
+}
+
+// SQL types accepted by `CAST` along with what they convert to, ie: `CAST($4 AS INT)`. Types are
+// told apart by their kind, lengths and precisions don't matter.
+const CAST_TYPES: &[(DataType, CastType)] = &[
+    (DataType::SmallInt, CastType::Int),
+    (DataType::Int, CastType::Int),
+    (DataType::BigInt, CastType::Int),
+    (DataType::Float(None), CastType::Float),
+    (DataType::Real, CastType::Float),
+    (DataType::Double, CastType::Float),
+    (DataType::Decimal(None, None), CastType::Float),
+    (DataType::Boolean, CastType::Boolean),
+    (DataType::Text, CastType::Text),
+    (DataType::Varchar(None), CastType::Text),
+    (DataType::Char(None), CastType::Text),
+];
+
+/// SQL types accepted by `CAST`, spelled as the parser writes them
+pub fn cast_types() -> Vec<String> {
+    CAST_TYPES
+        .iter()
+        .map(|(data_type, _)| data_type.to_string().to_uppercase())
+        .collect()
+}
+
+fn cast_type(data_type: &DataType) -> Option<CastType> {
+    CAST_TYPES
+        .iter()
+        .find(|(t, _)| mem::discriminant(t) == mem::discriminant(data_type))
+        .map(|(_, cast_type)| cast_type.clone())
+}
+
+/// Expression of a projection using scalar functions, evaluated on every output line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProjectionExpr {
+    // the value of a field extracted from the line, by the key it was extracted under
+    Field(String),
+    // the whole line, `$line`
+    Line,
+    Literal(JsonValue),
+    Cast(Box<ProjectionExpr>, CastType),
+    Lower(Box<ProjectionExpr>),
+    // 0 based start in characters and an optional length
+    Substr(Box<ProjectionExpr>, usize, Option<usize>),
+    // the first argument with a value
+    Coalesce(Vec<ProjectionExpr>),
+    // the start of the bucket of a timestamp, buckets being this many seconds long
+    TimeBucket(Box<ProjectionExpr>, u64),
+}
+
+/// Type of the values of an output column, as told to clients on `MINSQL-COLUMN-TYPES: true`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    String,
+    Int,
+    Float,
+    // an integer or a float, depending on the values
+    Number,
+    Boolean,
+    Ip,
+    Timestamp,
+}
+
+impl ProjectionExpr {
+    /// Type of the values of the expression, `field_type` tells the type of the fields it reads
+    pub fn column_type<F>(&self, field_type: &F) -> ColumnType
+    where
+        F: Fn(&str) -> ColumnType,
+    {
+        match self {
+            ProjectionExpr::Field(key) => field_type(key),
+            ProjectionExpr::Line => ColumnType::String,
+            ProjectionExpr::Literal(JsonValue::Number(n)) if n.is_f64() => ColumnType::Float,
+            ProjectionExpr::Literal(JsonValue::Number(_)) => ColumnType::Int,
+            ProjectionExpr::Literal(JsonValue::Bool(_)) => ColumnType::Boolean,
+            ProjectionExpr::Literal(_) => ColumnType::String,
+            ProjectionExpr::Cast(_, CastType::Int) => ColumnType::Int,
+            ProjectionExpr::Cast(_, CastType::Float) => ColumnType::Float,
+            ProjectionExpr::Cast(_, CastType::Boolean) => ColumnType::Boolean,
+            ProjectionExpr::Cast(_, CastType::Text) => ColumnType::String,
+            ProjectionExpr::Lower(_) | ProjectionExpr::Substr(_, _, _) => ColumnType::String,
+            // the arguments may have different types, any of them can be returned
+            ProjectionExpr::Coalesce(exprs) => {
+                let mut types = exprs.iter().map(|e| e.column_type(field_type));
+                let first = types.next().unwrap_or(ColumnType::String);
+                if types.all(|t| t == first) {
+                    first
+                } else {
+                    ColumnType::String
+                }
+            }
+            ProjectionExpr::TimeBucket(_, _) => ColumnType::Timestamp,
+        }
+    }
+}
+
+/// A projection computed with scalar functions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComputedColumn {
+    pub alias: String,
+    pub expr: ProjectionExpr,
+}
+
+/// Whether an AST node is a projection computed by a function
+pub fn is_computed_projection(ast: &Expr) -> bool {
+    match ast {
+        Expr::Cast { .. } | Expr::Function(_) => true,
+        _ => false,
+    }
+}
+
+/// Translates the AST of a function projection. The fields it reads are added to `fields` so
+/// they get extracted from the lines.
+pub fn parse_projection_expr(ast: &Expr, fields: &mut Vec<Expr>) -> Result<ProjectionExpr, String> {
+    match ast {
+        Expr::Nested(inner) => parse_projection_expr(inner, fields),
+        Expr::Identifier(ref identifier) if identifier == "$line" => Ok(ProjectionExpr::Line),
+        Expr::Identifier(ref identifier) => {
+            fields.push(ast.clone());
+            Ok(ProjectionExpr::Field(identifier.clone()))
+        }
+        Expr::CompoundIdentifier(ref identifier) => {
+            fields.push(ast.clone());
+            Ok(ProjectionExpr::Field(identifier.join(".")))
+        }
+        Expr::Value(value) => match value {
+            Value::SingleQuotedString(s) => Ok(ProjectionExpr::Literal(JsonValue::from(s.clone()))),
+            Value::Long(l) => Ok(ProjectionExpr::Literal(JsonValue::from(*l))),
+            Value::Boolean(b) => Ok(ProjectionExpr::Literal(JsonValue::from(*b))),
+            Value::Null => Ok(ProjectionExpr::Literal(JsonValue::Null)),
+            v => Err(format!("Unsupported value `{}` on function", v)),
+        },
+        Expr::Cast { expr, data_type } => {
+            let cast_type = cast_type(data_type)
+                .ok_or_else(|| format!("Unsupported type `{}` on CAST", data_type))?;
+            Ok(ProjectionExpr::Cast(
+                Box::new(parse_projection_expr(expr, fields)?),
+                cast_type,
+            ))
+        }
+        Expr::Function(function) => {
+            let name = function.name.to_string().to_uppercase();
+            let args = &function.args;
+            match (name.as_str(), args.len()) {
+                ("LOWER", 1) => Ok(ProjectionExpr::Lower(Box::new(parse_projection_expr(
+                    &args[0], fields,
+                )?))),
+                ("SUBSTR", 2) | ("SUBSTR", 3) => {
+                    let start = long_argument(&args[1], &name)?;
+                    let length = match args.get(2) {
+                        Some(arg) => Some(long_argument(arg, &name)?),
+                        None => None,
+                    };
+                    Ok(ProjectionExpr::Substr(
+                        Box::new(parse_projection_expr(&args[0], fields)?),
+                        start,
+                        length,
+                    ))
+                }
+                ("TIME_BUCKET", 2) => {
+                    let bucket_secs = match &args[1] {
+                        Expr::Value(Value::SingleQuotedString(s)) => Config::age_to_seconds(s),
+                        _ => None,
+                    };
+                    match bucket_secs {
+                        Some(secs) if secs > 0 => Ok(ProjectionExpr::TimeBucket(
+                            Box::new(parse_projection_expr(&args[0], fields)?),
+                            secs,
+                        )),
+                        _ => Err("Buckets are `30s`, `5m`, `1h` or `1d` long, ie: \
+                                  TIME_BUCKET($1, '5m')"
+                            .to_string()),
+                    }
+                }
+                ("COALESCE", n) if n > 0 => {
+                    let mut exprs = Vec::new();
+                    for arg in args {
+                        exprs.push(parse_projection_expr(arg, fields)?);
+                    }
+                    Ok(ProjectionExpr::Coalesce(exprs))
+                }
+                _ => {
+                    if SCALAR_FUNCTIONS.contains(&name.as_str()) {
+                        Err(format!("Invalid number of arguments for {}", name))
+                    } else {
+                        Err(format!("Unsupported function {}", name))
+                    }
+                }
+            }
+        }
+        x => Err(format!("Unsupported expression `{}` on function", x)),
+    }
+}
+
+/// Reads a non negative integer argument of a function
+fn long_argument(ast: &Expr, function: &str) -> Result<usize, String> {
+    match ast {
+        Expr::Value(Value::Long(l)) => Ok(*l as usize),
+        _ => Err(format!("{} expects integer arguments", function)),
+    }
+}
+
+/// Evaluates a projection on a line, `None` when it has no value. Times are read and bucketed
+/// on `timezone`.
+pub fn evaluate_projection(
+    expr: &ProjectionExpr,
+    projection_values: &HashMap<String, Option<PatternValue>>,
+    line: &str,
+    timezone: &FixedOffset,
+) -> Option<JsonValue> {
+    match expr {
+        ProjectionExpr::Field(key) => match projection_values.get(key) {
+            Some(Some(PatternValue::LineData(ld))) => Some(JsonValue::from(
+                line.get(ld.from as usize..ld.to as usize)?.to_string(),
+            )),
+            Some(Some(PatternValue::RichData(rd))) => Some(JsonValue::from(rd.clone())),
+            _ => None,
+        },
+        ProjectionExpr::Line => Some(JsonValue::from(line.to_string())),
+        ProjectionExpr::Literal(value) => {
+            if value.is_null() {
+                None
+            } else {
+                Some(value.clone())
+            }
+        }
+        ProjectionExpr::Cast(inner, cast_type) => {
+            let value = evaluate_projection(inner, projection_values, line, timezone)?;
+            cast_value(&value, cast_type)
+        }
+        ProjectionExpr::Lower(inner) => {
+            let value = evaluate_projection(inner, projection_values, line, timezone)?;
+            Some(JsonValue::from(value_to_string(&value).to_lowercase()))
+        }
+        ProjectionExpr::Substr(inner, start, length) => {
+            let value = evaluate_projection(inner, projection_values, line, timezone)?;
+            let text = value_to_string(&value);
+            let chars = text.chars().skip(*start);
+            let substr: String = match length {
+                Some(length) => chars.take(*length).collect(),
+                None => chars.collect(),
+            };
+            Some(JsonValue::from(substr))
+        }
+        ProjectionExpr::Coalesce(exprs) => exprs
+            .iter()
+            .filter_map(|e| evaluate_projection(e, projection_values, line, timezone))
+            .next(),
+        ProjectionExpr::TimeBucket(inner, bucket_secs) => {
+            let value = evaluate_projection(inner, projection_values, line, timezone)?;
+            let time = parse_time(value_to_string(&value).trim(), timezone)?;
+            let bucket = time_bucket(&time.with_timezone(timezone), *bucket_secs);
+            Some(JsonValue::from(bucket.to_rfc3339()))
+        }
+    }
+}
+
+/// Parses a UTC offset, ie: `+02:00`, `-0530` or `UTC`. Time zone names such as
+/// `Europe/Madrid` are not supported, since their offset depends on the date.
+pub fn parse_timezone(timezone: &str) -> Result<FixedOffset, String> {
+    let timezone = timezone.trim();
+    if timezone.eq_ignore_ascii_case("UTC") || timezone == "Z" {
+        return Ok(FixedOffset::east(0));
+    }
+    let invalid = || {
+        format!(
+            "Invalid timezone {}, it must be an offset like +02:00 or UTC",
+            timezone
+        )
+    };
+    let sign = match timezone.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        Some(c) if c.is_ascii_alphabetic() => {
+            return Err(format!(
+                "Time zone names like {} are not supported, send its current offset like +02:00 instead",
+                timezone
+            ));
+        }
+        _ => return Err(invalid()),
+    };
+    let digits: String = timezone[1..].chars().filter(|c| *c != ':').collect();
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+    let minutes: i32 = digits[2..].parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+/// Reads a timestamp on a line, times without an offset are taken as being on `timezone`
+pub fn parse_time(text: &str, timezone: &FixedOffset) -> Option<DateTime<FixedOffset>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time);
+    }
+    // as written by web servers, ie: `24/Jul/2017:00:16:46 +0000`
+    if let Ok(time) = DateTime::parse_from_str(text, "%d/%b/%Y:%H:%M:%S %z") {
+        return Some(time);
+    }
+    [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S%.f",
+    ]
+    .iter()
+    .filter_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+    .next()
+    .and_then(|naive| timezone.from_local_datetime(&naive).single())
+}
+
+/// Start of the bucket holding `time`, buckets are aligned on the midnight of its offset so a
+/// day is a local day
+fn time_bucket(time: &DateTime<FixedOffset>, bucket_secs: u64) -> DateTime<FixedOffset> {
+    let local_secs = time.naive_local().timestamp();
+    let start = local_secs - local_secs.rem_euclid(bucket_secs as i64);
+    time.offset()
+        .from_local_datetime(&NaiveDateTime::from_timestamp(start, 0))
+        .unwrap()
+}
+
+/// Converts a value to a type, `None` if it can't be represented on it
+fn cast_value(value: &JsonValue, cast_type: &CastType) -> Option<JsonValue> {
+    let text = value_to_string(value);
+    let text = text.trim();
+    match cast_type {
+        CastType::Int => text.parse::<i64>().ok().map(JsonValue::from),
+        CastType::Float => text
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(JsonValue::Number),
+        CastType::Boolean => match text.to_lowercase().as_str() {
+            "true" | "t" | "1" => Some(JsonValue::Bool(true)),
+            "false" | "f" | "0" => Some(JsonValue::Bool(false)),
+            _ => None,
+        },
+        CastType::Text => Some(JsonValue::from(text.to_string())),
+    }
+}
+
+fn value_to_string(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod functions_tests {
+    use crate::dialect::MinSQLDialect;
+    use sqlparser::ast::{SelectItem, SetExpr, Statement};
+    use sqlparser::parser::Parser;
+
+    use super::*;
+
+    fn projection_of(sql: &str) -> Expr {
+        let dialect = MinSQLDialect {};
+        let ast = Parser::parse_sql(&dialect, sql.to_string()).unwrap();
+        match &ast[0] {
+            Statement::Query(q) => match &q.body {
+                SetExpr::Select(select) => match &select.projection[0] {
+                    SelectItem::UnnamedExpr(expr) => expr.clone(),
+                    SelectItem::ExprWithAlias { expr, .. } => expr.clone(),
+                    _ => panic!("unexpected projection"),
+                },
+                _ => panic!("unexpected query"),
+            },
+            _ => panic!("unexpected query"),
+        }
+    }
+
+    fn evaluate_sql(sql: &str, values: &[(&str, &str)], line: &str) -> Option<JsonValue> {
+        let mut fields = Vec::new();
+        let expr = parse_projection_expr(&projection_of(sql), &mut fields).unwrap();
+        let projection_values: HashMap<String, Option<PatternValue>> = values
+            .iter()
+            .map(|(k, v)| (k.to_string(), Some(PatternValue::RichData(v.to_string()))))
+            .collect();
+        evaluate_projection(&expr, &projection_values, line, &FixedOffset::east(0))
+    }
+
+    #[test]
+    fn cast_to_types() {
+        let values = [("$4", "200"), ("$5", "0.25"), ("$6", "true"), ("$7", "x")];
+        assert_eq!(
+            evaluate_sql("SELECT CAST($4 AS INT) FROM mylog", &values, ""),
+            Some(JsonValue::from(200))
+        );
+        assert_eq!(
+            evaluate_sql("SELECT CAST($5 AS DOUBLE) FROM mylog", &values, ""),
+            Some(JsonValue::from(0.25))
+        );
+        assert_eq!(
+            evaluate_sql("SELECT CAST($6 AS BOOLEAN) FROM mylog", &values, ""),
+            Some(JsonValue::from(true))
+        );
+        // values that don't fit the type are null
+        assert_eq!(
+            evaluate_sql("SELECT CAST($7 AS INT) FROM mylog", &values, ""),
+            None
+        );
+    }
+
+    #[test]
+    fn listed_cast_types_parse() {
+        for cast_type in cast_types() {
+            let sql = format!("SELECT CAST($4 AS {}) FROM mylog", cast_type);
+            let mut fields = Vec::new();
+            assert!(parse_projection_expr(&projection_of(&sql), &mut fields).is_ok());
+        }
+    }
+
+    #[test]
+    fn lower_and_substr() {
+        let values = [("$quoted", "GET /Index.html")];
+        assert_eq!(
+            evaluate_sql("SELECT LOWER($quoted) FROM mylog", &values, ""),
+            Some(JsonValue::from("get /index.html"))
+        );
+        assert_eq!(
+            evaluate_sql("SELECT SUBSTR($line, 0, 5) FROM mylog", &[], "10.0.0.1 GET"),
+            Some(JsonValue::from("10.0."))
+        );
+        assert_eq!(
+            evaluate_sql("SELECT SUBSTR($line, 9) FROM mylog", &[], "10.0.0.1 GET"),
+            Some(JsonValue::from("GET"))
+        );
+    }
+
+    #[test]
+    fn coalesce_missing_values() {
+        assert_eq!(
+            evaluate_sql("SELECT COALESCE($email, 'anonymous') FROM mylog", &[], ""),
+            Some(JsonValue::from("anonymous"))
+        );
+        assert_eq!(
+            evaluate_sql(
+                "SELECT COALESCE($email, 'anonymous') FROM mylog",
+                &[("$email", "a@b.com")],
+                ""
+            ),
+            Some(JsonValue::from("a@b.com"))
+        );
+    }
+
+    #[test]
+    fn time_buckets_on_timezone() {
+        let bucket = |sql: &str, value: &str, timezone: &str| {
+            let mut fields = Vec::new();
+            let expr = parse_projection_expr(&projection_of(sql), &mut fields).unwrap();
+            let mut values = HashMap::new();
+            values.insert(
+                "$1".to_string(),
+                Some(PatternValue::RichData(value.to_string())),
+            );
+            evaluate_projection(&expr, &values, "", &parse_timezone(timezone).unwrap())
+        };
+        let five_minutes = "SELECT TIME_BUCKET($1, '5m') FROM mylog";
+        assert_eq!(
+            bucket(five_minutes, "2019-07-24T09:33:10.120Z", "UTC"),
+            Some(JsonValue::from("2019-07-24T09:30:00+00:00"))
+        );
+        // times are returned on the timezone of the query
+        assert_eq!(
+            bucket(five_minutes, "24/Jul/2017:00:16:46 +0000", "+02:00"),
+            Some(JsonValue::from("2017-07-24T02:15:00+02:00"))
+        );
+        // times without an offset are read on it
+        assert_eq!(
+            bucket(five_minutes, "2019-07-24 23:59:59", "-0530"),
+            Some(JsonValue::from("2019-07-24T23:55:00-05:30"))
+        );
+        assert_eq!(bucket(five_minutes, "yesterday", "UTC"), None);
+
+        // days start at the local midnight
+        let day = "SELECT TIME_BUCKET($1, '1d') FROM mylog";
+        assert_eq!(
+            bucket(day, "2019-07-23T23:00:00Z", "UTC"),
+            Some(JsonValue::from("2019-07-23T00:00:00+00:00"))
+        );
+        assert_eq!(
+            bucket(day, "2019-07-23T23:00:00Z", "+02:00"),
+            Some(JsonValue::from("2019-07-24T00:00:00+02:00"))
+        );
+
+        let mut fields = Vec::new();
+        assert!(parse_projection_expr(
+            &projection_of("SELECT TIME_BUCKET($1, '5 minutes') FROM mylog"),
+            &mut fields
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn timezones() {
+        assert_eq!(parse_timezone("UTC"), Ok(FixedOffset::east(0)));
+        assert_eq!(parse_timezone("+02:00"), Ok(FixedOffset::east(7200)));
+        assert_eq!(parse_timezone("-0530"), Ok(FixedOffset::west(19800)));
+        assert!(parse_timezone("Europe/Madrid")
+            .unwrap_err()
+            .contains("names like Europe/Madrid are not supported"));
+        assert!(parse_timezone("+25:00").is_err());
+        assert!(parse_timezone("").is_err());
+    }
+
+    #[test]
+    fn fields_read_by_functions() {
+        let mut fields = Vec::new();
+        parse_projection_expr(
+            &projection_of("SELECT COALESCE(LOWER($ip), $user_agent.name, $line) FROM mylog"),
+            &mut fields,
+        )
+        .unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                Expr::Identifier("$ip".to_string()),
+                Expr::CompoundIdentifier(vec!["$user_agent".to_string(), "name".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_functions() {
+        let mut fields = Vec::new();
+        assert!(
+            parse_projection_expr(&projection_of("SELECT SUM($4) FROM mylog"), &mut fields)
+                .is_err()
+        );
+        assert!(
+            parse_projection_expr(&projection_of("SELECT LOWER() FROM mylog"), &mut fields)
+                .is_err()
+        );
+        assert!(parse_projection_expr(
+            &projection_of("SELECT SUBSTR($line, $4) FROM mylog"),
+            &mut fields
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn column_types_of_functions() {
+        let type_of = |sql: &str| {
+            let mut fields = Vec::new();
+            let expr = parse_projection_expr(&projection_of(sql), &mut fields).unwrap();
+            expr.column_type(&|key: &str| match key {
+                "$ip" => ColumnType::Ip,
+                _ => ColumnType::String,
+            })
+        };
+        assert_eq!(
+            type_of("SELECT CAST($4 AS INT) FROM mylog"),
+            ColumnType::Int
+        );
+        assert_eq!(
+            type_of("SELECT CAST($4 AS DOUBLE) FROM mylog"),
+            ColumnType::Float
+        );
+        assert_eq!(type_of("SELECT LOWER($ip) FROM mylog"), ColumnType::String);
+        assert_eq!(
+            type_of("SELECT COALESCE($ip, $1) FROM mylog"),
+            ColumnType::String
+        );
+        assert_eq!(
+            type_of("SELECT COALESCE($ip, $ip2) FROM mylog"),
+            ColumnType::String
+        );
+        assert_eq!(
+            type_of("SELECT COALESCE($ip, '') FROM mylog"),
+            ColumnType::String
+        );
+        assert_eq!(
+            type_of("SELECT TIME_BUCKET($1, '5m') FROM mylog"),
+            ColumnType::Timestamp
+        );
+    }
+}
@@ -0,0 +1,395 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use chrono::{NaiveDateTime, Utc};
+use futures::{future, stream, Future, Stream};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::changesets::{commit_changeset, ObjectChange};
+use crate::config::Config;
+use crate::constants::{CONFIG_HISTORY_MAX_SNAPSHOTS, CONFIG_HISTORY_PREFIX, META_PREFIX};
+use crate::meta::ds_for_metabucket;
+use crate::secrets::{hash_secret, is_hashed};
+use crate::storage::{
+    delete_object, get_object_metabucket, list_metabucket_keys, write_object_metabucket,
+};
+
+// Snapshot timestamps sort in the order they were taken, ie: `20190716T101112.123456Z`
+const SNAPSHOT_TS_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+/// A change done to a configuration object of the metabucket, stored under
+/// `minsql/meta/_history/<ts>/...` so the configuration can be rolled back. `None` stands for a
+/// missing object.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigChange {
+    pub key: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// A snapshot of the history, the configuration object changed at `ts`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub ts: String,
+    pub key: String,
+}
+
+pub fn valid_snapshot_ts(ts: &str) -> bool {
+    NaiveDateTime::parse_from_str(ts, SNAPSHOT_TS_FORMAT).is_ok()
+}
+
+/// Whether changes to an object of the metabucket are recorded, only configuration objects are.
+fn is_recorded_key(key: &str) -> bool {
+    key.starts_with(META_PREFIX) && !is_history_key(key)
+}
+
+pub fn is_history_key(key: &str) -> bool {
+    key.starts_with(CONFIG_HISTORY_PREFIX)
+}
+
+fn history_key(ts: &str, key: &str) -> String {
+    format!(
+        "{}{}/{}",
+        CONFIG_HISTORY_PREFIX,
+        ts,
+        key.trim_start_matches(META_PREFIX)
+    )
+}
+
+/// Splits a history key into the snapshot it belongs to
+fn parse_history_key(history_key: &str) -> Option<Snapshot> {
+    let rest = history_key.trim_start_matches(CONFIG_HISTORY_PREFIX);
+    let mut parts = rest.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(ts), Some(key)) if valid_snapshot_ts(ts) && !key.is_empty() => Some(Snapshot {
+            ts: ts.to_string(),
+            key: format!("{}{}", META_PREFIX, key),
+        }),
+        _ => None,
+    }
+}
+
+/// Snapshots to undo to go back to the configuration at `to`, the first change done to every
+/// object after it holds the version the object had at `to`.
+fn snapshots_to_revert(mut snapshots: Vec<Snapshot>, to: &str) -> Vec<Snapshot> {
+    snapshots.sort_by(|a, b| a.ts.cmp(&b.ts));
+    let mut first_changes: HashMap<String, Snapshot> = HashMap::new();
+    for snapshot in snapshots.into_iter().filter(|s| s.ts.as_str() > to) {
+        first_changes
+            .entry(snapshot.key.clone())
+            .or_insert(snapshot);
+    }
+    let mut reverts: Vec<Snapshot> = first_changes.into_iter().map(|(_, s)| s).collect();
+    reverts.sort_by(|a, b| a.key.cmp(&b.key));
+    reverts
+}
+
+/// Snapshots past the newest `max`, to remove from the history
+fn snapshots_to_prune(mut snapshots: Vec<Snapshot>, max: usize) -> Vec<Snapshot> {
+    if snapshots.len() <= max {
+        return Vec::new();
+    }
+    snapshots.sort_by(|a, b| a.ts.cmp(&b.ts).then_with(|| a.key.cmp(&b.key)));
+    let excess = snapshots.len() - max;
+    snapshots.truncate(excess);
+    snapshots
+}
+
+/// A version of a token as the history keeps it, a plaintext secret is replaced by its hash so
+/// the history never holds one. The hash still verifies the secret if the version is restored.
+fn without_plaintext_secret(key: &str, version: Option<String>) -> Option<String> {
+    let payload = version?;
+    if !key.starts_with(&format!("{}tokens/", META_PREFIX)) {
+        return Some(payload);
+    }
+    let mut token: serde_json::Value = match serde_json::from_str(&payload) {
+        Ok(token) => token,
+        Err(_) => return Some(payload),
+    };
+    let hashed = match token.get("secret_key").and_then(|s| s.as_str()) {
+        Some(secret) if !is_hashed(secret) => hash_secret(secret),
+        _ => return Some(payload),
+    };
+    token["secret_key"] = serde_json::Value::String(hashed);
+    Some(token.to_string())
+}
+
+/// Removes the oldest snapshots past `CONFIG_HISTORY_MAX_SNAPSHOTS`. Failures are only logged,
+/// the next change prunes again.
+fn prune_history(cfg: Arc<RwLock<Config>>) -> impl Future<Item = (), Error = String> {
+    list_snapshots(Arc::clone(&cfg)).then(move |res| {
+        let snapshots = match res {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                error!("Could not prune the configuration history: {}", e);
+                return future::Either::A(future::ok(()));
+            }
+        };
+        let ds = ds_for_metabucket(cfg);
+        future::Either::B(
+            stream::iter_ok::<_, String>(snapshots_to_prune(
+                snapshots,
+                CONFIG_HISTORY_MAX_SNAPSHOTS,
+            ))
+            .for_each(move |snapshot| {
+                let snapshot_key = history_key(&snapshot.ts, &snapshot.key);
+                delete_object(&ds, snapshot_key.clone()).then(move |res| {
+                    if let Err(e) = res {
+                        error!("Could not remove snapshot {}: {:?}", snapshot_key, e);
+                    }
+                    Ok(())
+                })
+            }),
+        )
+    })
+}
+
+/// Records the change about to be done to `key` along with its current version. It's recorded
+/// before the change is done, so a change that fails is rolled back to the same version. Token
+/// secrets are recorded hashed, and the history is pruned to its newest snapshots.
+pub fn record_config_change(
+    cfg: Arc<RwLock<Config>>,
+    key: String,
+    after: Option<String>,
+) -> impl Future<Item = (), Error = String> {
+    if !is_recorded_key(&key) {
+        return future::Either::A(future::ok(()));
+    }
+    let write_cfg = Arc::clone(&cfg);
+    let prune_cfg = Arc::clone(&cfg);
+    let err_key = key.clone();
+    future::Either::B(
+        get_object_metabucket(cfg, key.clone())
+            .map_err(move |e| format!("Could not read the current version of {}: {:?}", err_key, e))
+            .and_then(move |before| {
+                let snapshot_key =
+                    history_key(&Utc::now().format(SNAPSHOT_TS_FORMAT).to_string(), &key);
+                let change = ConfigChange {
+                    before: without_plaintext_secret(&key, before),
+                    after: without_plaintext_secret(&key, after),
+                    key,
+                };
+                write_object_metabucket(
+                    write_cfg,
+                    snapshot_key,
+                    serde_json::to_string(&change).unwrap(),
+                )
+                .map(|_| ())
+                .map_err(|e| format!("Could not write the configuration history: {:?}", e))
+            })
+            .and_then(move |_| prune_history(prune_cfg)),
+    )
+}
+
+/// Rewrites the snapshots of a token recorded with its plaintext secret, before the history
+/// hashed secrets, with the secret hashed. Done once a legacy secret is rehashed, so the history
+/// no longer holds it either.
+pub fn scrub_token_history(
+    cfg: Arc<RwLock<Config>>,
+    access_key: String,
+) -> impl Future<Item = (), Error = String> {
+    let token_key = format!("{}tokens/{}", META_PREFIX, access_key);
+    let read_cfg = Arc::clone(&cfg);
+    list_snapshots(Arc::clone(&cfg)).and_then(move |snapshots| {
+        let snapshot_keys: Vec<String> = snapshots
+            .into_iter()
+            .filter(|s| s.key == token_key)
+            .map(|s| history_key(&s.ts, &s.key))
+            .collect();
+        stream::iter_ok::<_, String>(snapshot_keys).for_each(move |snapshot_key| {
+            let write_cfg = Arc::clone(&cfg);
+            let err_key = snapshot_key.clone();
+            get_object_metabucket(Arc::clone(&read_cfg), snapshot_key.clone())
+                .map_err(move |e| format!("Could not read snapshot {}: {:?}", err_key, e))
+                .and_then(move |payload| {
+                    let change = match payload.map(|p| serde_json::from_str::<ConfigChange>(&p)) {
+                        Some(Ok(change)) => change,
+                        _ => return future::Either::A(future::ok(())),
+                    };
+                    let scrubbed = ConfigChange {
+                        before: without_plaintext_secret(&change.key, change.before.clone()),
+                        after: without_plaintext_secret(&change.key, change.after.clone()),
+                        key: change.key.clone(),
+                    };
+                    if scrubbed == change {
+                        return future::Either::A(future::ok(()));
+                    }
+                    future::Either::B(
+                        write_object_metabucket(
+                            write_cfg,
+                            snapshot_key.clone(),
+                            serde_json::to_string(&scrubbed).unwrap(),
+                        )
+                        .map(|_| ())
+                        .map_err(move |e| {
+                            format!("Could not rewrite snapshot {}: {:?}", snapshot_key, e)
+                        }),
+                    )
+                })
+        })
+    })
+}
+
+/// Every snapshot of the history, oldest first
+pub fn list_snapshots(
+    cfg: Arc<RwLock<Config>>,
+) -> impl Future<Item = Vec<Snapshot>, Error = String> {
+    list_metabucket_keys(cfg, CONFIG_HISTORY_PREFIX.to_string())
+        .map(|keys| {
+            let mut snapshots: Vec<Snapshot> =
+                keys.iter().filter_map(|k| parse_history_key(k)).collect();
+            snapshots.sort_by(|a, b| a.ts.cmp(&b.ts).then_with(|| a.key.cmp(&b.key)));
+            snapshots
+        })
+        .map_err(|e| format!("Could not list the configuration history: {:?}", e))
+}
+
+/// Restores every configuration object changed after `to` to the version it had at `to`, returns
+/// the keys restored. The restores are committed as a single changeset, so either all of them or
+/// none are done, and they're recorded on the history as well, so they can be undone.
+pub fn rollback_config(
+    cfg: Arc<RwLock<Config>>,
+    to: String,
+) -> impl Future<Item = Vec<String>, Error = String> {
+    let read_cfg = Arc::clone(&cfg);
+    list_snapshots(Arc::clone(&cfg)).and_then(move |snapshots| {
+        stream::iter_ok::<_, String>(snapshots_to_revert(snapshots, &to))
+            .and_then(move |snapshot| {
+                let snapshot_key = history_key(&snapshot.ts, &snapshot.key);
+                let err_key = snapshot_key.clone();
+                get_object_metabucket(Arc::clone(&read_cfg), snapshot_key.clone())
+                    .map_err(move |e| format!("Could not read snapshot {}: {:?}", err_key, e))
+                    .and_then(move |payload| {
+                        match payload.map(|p| serde_json::from_str::<ConfigChange>(&p)) {
+                            Some(Ok(change)) => Ok(ObjectChange {
+                                key: change.key,
+                                after: change.before,
+                            }),
+                            _ => Err(format!("Snapshot {} is not valid", snapshot_key)),
+                        }
+                    })
+            })
+            .collect()
+            .and_then(move |changes: Vec<ObjectChange>| {
+                let keys = changes.iter().map(|c| c.key.clone()).collect();
+                commit_changeset(cfg, changes)
+                    .map(move |_| keys)
+                    .map_err(|e| format!("Could not restore: {}", e))
+            })
+    })
+}
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+
+    fn snapshot(ts: &str, key: &str) -> Snapshot {
+        Snapshot {
+            ts: ts.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn history_keys_round_trip() {
+        let key = history_key(
+            "20190716T101112.123456Z",
+            "minsql/meta/auth/TOKEN/team/service",
+        );
+        assert_eq!(
+            key,
+            "minsql/meta/_history/20190716T101112.123456Z/auth/TOKEN/team/service"
+        );
+        assert!(is_history_key(&key));
+        assert!(!is_recorded_key(&key));
+        assert_eq!(
+            parse_history_key(&key),
+            Some(snapshot(
+                "20190716T101112.123456Z",
+                "minsql/meta/auth/TOKEN/team/service"
+            ))
+        );
+        assert_eq!(
+            parse_history_key("minsql/meta/_history/yesterday/logs/mylog"),
+            None
+        );
+    }
+
+    #[test]
+    fn revert_first_change_after_target() {
+        let snapshots = vec![
+            snapshot("20190716T100000.000000Z", "minsql/meta/datastores/ds1"),
+            snapshot("20190716T110000.000000Z", "minsql/meta/datastores/ds1"),
+            snapshot("20190716T120000.000000Z", "minsql/meta/datastores/ds1"),
+            snapshot("20190716T130000.000000Z", "minsql/meta/logs/mylog"),
+            snapshot("20190716T090000.000000Z", "minsql/meta/tokens/TOKEN"),
+        ];
+        let reverts = snapshots_to_revert(snapshots, "20190716T100000.000000Z");
+        assert_eq!(
+            reverts,
+            vec![
+                snapshot("20190716T110000.000000Z", "minsql/meta/datastores/ds1"),
+                snapshot("20190716T130000.000000Z", "minsql/meta/logs/mylog"),
+            ]
+        );
+    }
+
+    #[test]
+    fn prune_oldest_snapshots() {
+        let snapshots = vec![
+            snapshot("20190716T120000.000000Z", "minsql/meta/logs/mylog"),
+            snapshot("20190716T100000.000000Z", "minsql/meta/datastores/ds1"),
+            snapshot("20190716T110000.000000Z", "minsql/meta/datastores/ds1"),
+        ];
+        assert_eq!(snapshots_to_prune(snapshots.clone(), 3), vec![]);
+        assert_eq!(
+            snapshots_to_prune(snapshots, 1),
+            vec![
+                snapshot("20190716T100000.000000Z", "minsql/meta/datastores/ds1"),
+                snapshot("20190716T110000.000000Z", "minsql/meta/datastores/ds1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn token_secrets_recorded_hashed() {
+        let token = r#"{"access_key":"TOKEN","secret_key":"plaintextsecret"}"#.to_string();
+        let recorded =
+            without_plaintext_secret("minsql/meta/tokens/TOKEN", Some(token.clone())).unwrap();
+        let recorded: serde_json::Value = serde_json::from_str(&recorded).unwrap();
+        let secret = recorded["secret_key"].as_str().unwrap();
+        assert!(is_hashed(secret));
+        assert!(crate::secrets::verify_secret(secret, "plaintextsecret"));
+        // already hashed secrets and other objects are recorded as they are
+        let hashed = recorded.to_string();
+        assert_eq!(
+            without_plaintext_secret("minsql/meta/tokens/TOKEN", Some(hashed.clone())),
+            Some(hashed)
+        );
+        assert_eq!(
+            without_plaintext_secret("minsql/meta/logs/mylog", Some(token.clone())),
+            Some(token)
+        );
+        assert_eq!(
+            without_plaintext_secret("minsql/meta/tokens/TOKEN", None),
+            None
+        );
+    }
+}
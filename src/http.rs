@@ -16,20 +16,35 @@
 
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, RwLock};
 
 use futures::{future, Future};
-use hyper::{Body, Method, Request, Response, StatusCode};
-use log::info;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use log::{error, info};
+use rand::Rng;
 use serde_derive::Serialize;
 
+use crate::api::logs::valid_log_name;
 use crate::api::Api;
-use crate::auth::Auth;
-use crate::config::Config;
-use crate::constants::{APP_JAVASCRIPT, APP_JSON, IMAGE_JPEG, TEXT_HTML, UNKNOWN_CONTENT_TYPE};
-use crate::ingest::{Ingest, IngestBuffer};
+use crate::auth::{Auth, LogAccess};
+use crate::cidr::any_contains;
+use crate::config::{AccessLogRule, Config, Token};
+use crate::constants::{
+    APP_JAVASCRIPT, APP_JSON, IMAGE_JPEG, TEXT_HTML, TEXT_PLAIN_METRICS, UNKNOWN_CONTENT_TYPE,
+};
+use crate::elastic::Elastic;
+use crate::history::scrub_token_history;
+use crate::identity::{credentials_from_request, Credentials};
+use crate::ingest::{Ingest, IngestBuffers};
+use crate::lockout::{auth_metrics, locked_for, lockout_keys, record_failure, record_success};
+use crate::loki::Loki;
 use crate::query::Query;
+use crate::secrets::{hash_secret, is_hashed, verify_secret};
+use crate::storage::put_object_metabucket;
+use crate::supervisor;
+use crate::trace::{with_trace_headers, RequestTrace};
 
 pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
 pub type ResponseFuture = Box<Future<Item = Response<Body>, Error = GenericError> + Send>;
@@ -37,6 +52,7 @@ pub type ResponseFuture = Box<Future<Item = Response<Body>, Error = GenericError
 static INDEX_BODY: &[u8] = b"MinSQL";
 static NOTFOUND_BODY: &str = "Not Found";
 static UNAUTHORIZED_BODY: &str = "Unauthorized";
+static FORBIDDEN_BODY: &str = "Forbidden";
 
 pub struct Http {
     config: Arc<RwLock<Config>>,
@@ -47,11 +63,31 @@ impl Http {
         Http { config: cfg }
     }
 
+    /// Entry point of every request. The request is traced, see `RequestTrace`, before being
+    /// routed and its id is echoed back on the response.
     pub fn request_router(
         &self,
-        req: Request<Body>,
-        log_ingest_buffers: Arc<HashMap<String, Mutex<IngestBuffer>>>,
+        mut req: Request<Body>,
+        log_ingest_buffers: Arc<IngestBuffers>,
     ) -> ResponseFuture {
+        let trace = RequestTrace::from_request(&req);
+        let sample = access_log_sample(
+            &self.config.read().unwrap().server.access_log_rules,
+            req.uri().path(),
+        );
+        if sample >= 1.0 || (sample > 0.0 && rand::thread_rng().gen::<f64>() < sample) {
+            info!(
+                "{} {} {}",
+                trace.log_context(),
+                req.method(),
+                req.uri().path()
+            );
+        }
+        req.extensions_mut().insert(trace.clone());
+        with_trace_headers(trace, self.route(req, log_ingest_buffers))
+    }
+
+    fn route(&self, req: Request<Body>, log_ingest_buffers: Arc<IngestBuffers>) -> ResponseFuture {
         let cfg = self.config.read().unwrap();
 
         let request_path_no_slash = String::from(&req.uri().path()[1..]);
@@ -62,7 +98,7 @@ impl Http {
             // delegate anything starting with /api/ to the api router
             (_, _, Some(&"ui")) => serve_static_content(req),
             (_, _, Some(&"api")) => {
-                let api = Api::new(Arc::clone(&self.config));
+                let api = Api::new(Arc::clone(&self.config), log_ingest_buffers);
                 api.router(req, parts)
             }
             (&Method::GET, "/", _) => {
@@ -70,11 +106,63 @@ impl Http {
                 Box::new(future::ok(Response::new(body)))
             }
 
+            (&Method::GET, "/metrics", _) => Box::new(future::ok(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, TEXT_PLAIN_METRICS)
+                    .body(Body::from(auth_metrics()))
+                    .unwrap(),
+            )),
+
             (&Method::POST, "/search", _) => match self.extract_auth_token(&req) {
                 Ok(tok) => {
                     let cfg = Arc::clone(&self.config);
                     let query_c = Query::new(cfg);
-                    query_c.api_log_search(req, &tok)
+                    query_c.api_log_search(req, &tok, log_ingest_buffers)
+                }
+                Err(err_resp) => err_resp,
+            },
+
+            (&Method::HEAD, "/search", _) => match self.extract_auth_token(&req) {
+                Ok(tok) => {
+                    let cfg = Arc::clone(&self.config);
+                    let query_c = Query::new(cfg);
+                    query_c.api_log_search(req, &tok, log_ingest_buffers)
+                }
+                Err(err_resp) => err_resp,
+            },
+
+            (&Method::POST, "/watch", _) => match self.extract_auth_token(&req) {
+                Ok(tok) => {
+                    let cfg = Arc::clone(&self.config);
+                    let query_c = Query::new(cfg);
+                    query_c.api_log_watch(req, &tok)
+                }
+                Err(err_resp) => err_resp,
+            },
+
+            (&Method::POST, "/search/verify", _) => match self.extract_auth_token(&req) {
+                Ok(_) => {
+                    let cfg = Arc::clone(&self.config);
+                    let query_c = Query::new(cfg);
+                    query_c.api_verify_results(req)
+                }
+                Err(err_resp) => err_resp,
+            },
+
+            // Elasticsearch compatible ingestion, for shippers that speak its `_bulk` API
+            (_, _, Some(&"es")) => match self.extract_auth_token(&req) {
+                Ok(tok) => {
+                    let elastic = Elastic::new(Arc::clone(&self.config));
+                    elastic.route(req, parts, tok, log_ingest_buffers)
+                }
+                Err(err_resp) => err_resp,
+            },
+
+            // Grafana Loki compatible ingestion, for promtail agents
+            (&Method::POST, "/loki/api/v1/push", _) => match self.extract_agent_auth_token(&req) {
+                Ok(tok) => {
+                    let loki = Loki::new(Arc::clone(&self.config));
+                    loki.push(req, tok, log_ingest_buffers)
                 }
                 Err(err_resp) => err_resp,
             },
@@ -83,25 +171,45 @@ impl Http {
                 match self.requested_log_from_request(&req) {
                     None => Box::new(future::ok(return_404())),
                     Some(name) => {
-                        // Does log exist in config?
-                        if cfg.get_log(&name).is_none() {
-                            info!("Attempted access of unknown log {}", name);
-                            return Box::new(future::ok(return_404()));
-                        }
-
+                        let auto_create_logs = cfg.server.auto_create_logs.clone();
+                        drop(cfg);
                         let access_token = match self.extract_auth_token(&req) {
                             Ok(tok) => tok,
                             Err(err_resp) => return err_resp,
                         };
 
-                        // Does the provided token have access to this log?
-                        let cfg = Arc::clone(&self.config);
-                        let auth_c = Auth::new(cfg);
-                        if !auth_c.token_has_access_to_log(&access_token, &name) {
-                            return Box::new(future::ok(return_401()));
+                        // Does the log exist and does the provided token have access to it?
+                        let auth_c = Auth::new(Arc::clone(&self.config));
+                        match (auth_c.log_access(&access_token, &name), auto_create_logs) {
+                            (LogAccess::Granted, _) => (),
+                            (LogAccess::Forbidden, _) => {
+                                return Box::new(future::ok(return_403()));
+                            }
+                            // admin tokens, or tokens allowed to store to a parent log, create it
+                            (LogAccess::UnknownLog, Some(ref template))
+                                if auth_c.token_is_admin(&access_token)
+                                    || auth_c.token_can_store_to(&access_token, &name) =>
+                            {
+                                if !valid_log_name(&name) {
+                                    return Box::new(future::ok(return_400(
+                                        "Log name is invalid.",
+                                    )));
+                                }
+                                let ingest_c = Ingest::new(Arc::clone(&self.config));
+                                return ingest_c.api_log_auto_create(
+                                    req,
+                                    log_ingest_buffers,
+                                    template.log_for(&name),
+                                    access_token,
+                                );
+                            }
+                            (LogAccess::UnknownLog, _) => {
+                                info!("Attempted access of unknown log {}", name);
+                                return Box::new(future::ok(return_404()));
+                            }
                         }
                         let ingest_c = Ingest::new(Arc::clone(&self.config));
-                        ingest_c.api_log_store(req, log_ingest_buffers, name)
+                        ingest_c.api_log_store(req, log_ingest_buffers, name, access_token)
                     }
                 }
             }
@@ -111,54 +219,104 @@ impl Http {
     }
 
     fn extract_auth_token(&self, req: &Request<Body>) -> Result<String, ResponseFuture> {
-        match self.validate_token_from_header(&req) {
-            HeaderToken::NoToken => Err(Box::new(future::ok(return_401()))),
-            HeaderToken::InvalidToken => Err(Box::new(future::ok(return_400("Invalid token")))),
-            HeaderToken::Token(tok) => Ok(tok),
+        token_or_response(self.validate_token_from_header(&req))
+    }
+
+    /// Like `extract_auth_token`, but agents that can't set the `MINSQL-TOKEN` header may send
+    /// the token on the `Authorization` header, as a bearer token or as the basic auth password.
+    fn extract_agent_auth_token(&self, req: &Request<Body>) -> Result<String, ResponseFuture> {
+        if req.headers().contains_key("MINSQL-TOKEN") {
+            return self.extract_auth_token(req);
         }
+        let validation = match credentials_from_request(req) {
+            Some(Credentials::Bearer(token)) => self.validate_access_key(req, &token),
+            Some(Credentials::Basic { password, .. }) => self.validate_access_key(req, &password),
+            None => HeaderToken::NoToken,
+        };
+        token_or_response(validation)
     }
 
     /// Returns a `HeaderToken` with the details regarding the presence/validity of the auth token
-    /// in the request.
+    /// in the request. Failures are counted by client address and access key, which are locked
+    /// out for a while after too many.
     pub fn validate_token_from_header(&self, req: &Request<Body>) -> HeaderToken {
         let access_key_result = match req.headers().get("MINSQL-TOKEN") {
             Some(val) => val.to_str(),
             None => return HeaderToken::NoToken,
         };
-        let access_key = match access_key_result {
-            Ok(val) => val,
-            Err(_) => return HeaderToken::InvalidToken,
-        };
-        if access_key.len() != 48 {
-            return HeaderToken::InvalidToken;
+        match access_key_result {
+            Ok(access_key) => self.validate_access_key(req, access_key),
+            Err(_) => HeaderToken::InvalidToken,
         }
+    }
+
+    /// Validates an access key sent by the client of a request
+    fn validate_access_key(&self, req: &Request<Body>, access_key: &str) -> HeaderToken {
         let cfg = self.config.read().unwrap();
-        match cfg.tokens.get(&access_key[0..16]) {
-            Some(token) => {
-                if &token.secret_key == &access_key[16..48] {
-                    HeaderToken::Token(access_key.to_string())
-                } else {
-                    HeaderToken::InvalidToken
+        let client = client_ip(req, &cfg.server.trusted_proxies);
+        let keys = lockout_keys(client, access_key);
+        if let Some(wait) = locked_for(&keys) {
+            return HeaderToken::LockedOut(wait.as_secs() + 1);
+        }
+        let validation = validate_token(&cfg, access_key, client);
+        match &validation {
+            HeaderToken::Token(_) => {
+                record_success(client, access_key);
+                let legacy = !is_hashed(&cfg.tokens[&access_key[0..16]].secret_key);
+                drop(cfg);
+                if legacy {
+                    self.rehash_legacy_secret(access_key);
                 }
             }
-            None => HeaderToken::InvalidToken,
+            _ => record_failure(&keys),
         }
+        validation
+    }
+
+    /// Replaces the plaintext secret of a token, stored before secrets were hashed, with its hash
+    /// once it was used successfully.
+    fn rehash_legacy_secret(&self, access_key: &str) {
+        let token = match rehash_token_secret(&self.config, access_key) {
+            Some(token) => token,
+            None => return,
+        };
+        info!(
+            "Hashing the plaintext secret of token {}",
+            &token.access_key
+        );
+        let token_serialized = serde_json::to_string(&token).unwrap();
+        let access_key = token.access_key.clone();
+        let scrub_key = token.access_key.clone();
+        let history_cfg = Arc::clone(&self.config);
+        supervisor::spawn_isolated(
+            format!("Secret rehash of {}", &access_key),
+            put_object_metabucket(
+                Arc::clone(&self.config),
+                format!("minsql/meta/tokens/{}", &access_key),
+                token_serialized,
+            )
+            .map_err(|e| format!("Could not store the hashed secret: {:?}", e))
+            // snapshots recorded before the history hashed secrets still hold the plaintext one
+            .and_then(move |_| scrub_token_history(history_cfg, scrub_key))
+            .map_err(move |e| error!("Could not rehash the secret of {}: {}", access_key, e)),
+        );
     }
 
+    /// Extracts the log name from a `/{log}/store` path. Log names can be hierarchical, so
+    /// `/team/service/store` maps to the log `team/service`.
     pub fn requested_log_from_request(&self, req: &Request<Body>) -> Option<String> {
         let request_path_no_slash = String::from(&req.uri().path()[1..]);
         let path_split = request_path_no_slash.split("/");
         let parts: Vec<&str> = path_split.collect();
-        if parts.len() != 2 {
-            None
-        } else {
-            let logname = parts[0].to_string();
-            let method = parts[1].to_string();
-            if method != "store" {
-                None
-            } else {
-                Some(logname)
+        match parts.split_last() {
+            Some((&"store", log_parts)) if !log_parts.is_empty() => {
+                if log_parts.iter().any(|p| p.is_empty()) {
+                    None
+                } else {
+                    Some(log_parts.join("/"))
+                }
             }
+            _ => None,
         }
     }
 }
@@ -168,6 +326,29 @@ struct ErrorResponse {
     message: String,
 }
 
+/// Returns whether a header is present on the request with a value of `true`
+pub fn bool_header(req: &Request<Body>, header: &str) -> bool {
+    match req.headers().get(header) {
+        Some(val) => match val.to_str() {
+            Ok(v) => v.to_lowercase() == "true",
+            Err(e) => {
+                error!("Could not parse {} header: {}", header, e);
+                false
+            }
+        },
+        None => false,
+    }
+}
+
+fn token_or_response(validation: HeaderToken) -> Result<String, ResponseFuture> {
+    match validation {
+        HeaderToken::NoToken => Err(Box::new(future::ok(return_401()))),
+        HeaderToken::InvalidToken => Err(Box::new(future::ok(return_400("Invalid token")))),
+        HeaderToken::LockedOut(secs) => Err(Box::new(future::ok(return_429(secs)))),
+        HeaderToken::Token(tok) => Ok(tok),
+    }
+}
+
 pub fn return_500(message: &str) -> Response<Body> {
     Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -199,6 +380,44 @@ pub fn return_401() -> Response<Body> {
         .unwrap()
 }
 
+/// The token is valid but not authorized to what was requested
+pub fn return_403() -> Response<Body> {
+    let obj = ErrorResponse {
+        message: FORBIDDEN_BODY.to_string(),
+    };
+    let output = serde_json::to_string(&obj).unwrap();
+    let body = Body::from(output);
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(body)
+        .unwrap()
+}
+
+pub fn return_429(retry_after_secs: u64) -> Response<Body> {
+    let obj = ErrorResponse {
+        message: "Too many failed attempts, try again later".to_string(),
+    };
+    let output = serde_json::to_string(&obj).unwrap();
+    let body = Body::from(output);
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::RETRY_AFTER, retry_after_secs.to_string())
+        .body(body)
+        .unwrap()
+}
+
+pub fn return_412() -> Response<Body> {
+    let obj = ErrorResponse {
+        message: "The object was modified, fetch it again before updating it".to_string(),
+    };
+    let output = serde_json::to_string(&obj).unwrap();
+    let body = Body::from(output);
+    Response::builder()
+        .status(StatusCode::PRECONDITION_FAILED)
+        .body(body)
+        .unwrap()
+}
+
 pub fn return_400(message: &str) -> Response<Body> {
     let obj = ErrorResponse {
         message: format!("Bad request: {}", &message),
@@ -217,6 +436,81 @@ pub enum HeaderToken {
     NoToken,
     InvalidToken,
     Token(String),
+    // too many failures from the client or for the access key, seconds to wait
+    LockedOut(u64),
+}
+
+/// Validates a token against the configuration, and its allowlist against the client address
+fn validate_token(cfg: &Config, access_key: &str, client: Option<IpAddr>) -> HeaderToken {
+    // keys are sliced by byte below, anything but ASCII alphanumerics is no token
+    if access_key.len() != 48 || !access_key.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return HeaderToken::InvalidToken;
+    }
+    match cfg.tokens.get(&access_key[0..16]) {
+        Some(token) => {
+            if !verify_secret(&token.secret_key, &access_key[16..48]) {
+                return HeaderToken::InvalidToken;
+            }
+            // tokens with an allowlist can only be used from within its networks
+            if !token.ip_allowlist.is_empty() {
+                match client {
+                    Some(ip) if any_contains(&token.ip_allowlist, &ip) => (),
+                    _ => return HeaderToken::InvalidToken,
+                }
+            }
+            HeaderToken::Token(access_key.to_string())
+        }
+        None => HeaderToken::InvalidToken,
+    }
+}
+
+/// Hashes the secret of a token in the configuration if it's still in plaintext, returning the
+/// updated token to persist.
+fn rehash_token_secret(config: &Arc<RwLock<Config>>, access_key: &str) -> Option<Token> {
+    let mut cfg = config.write().unwrap();
+    match cfg.tokens.get_mut(&access_key[0..16]) {
+        // it may have been rehashed by a concurrent request meanwhile
+        Some(token) if !is_hashed(&token.secret_key) => {
+            token.secret_key = hash_secret(&token.secret_key);
+            Some(token.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Address of the client of a connection, set on the extensions of its requests
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Address of the client of a request. Requests coming from a trusted proxy are attributed to the
+/// last address on `X-Forwarded-For` that isn't a trusted proxy itself.
+pub fn client_ip(req: &Request<Body>, trusted_proxies: &[String]) -> Option<IpAddr> {
+    let mut client = req.extensions().get::<ClientAddr>()?.0.ip();
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in forwarded.iter().rev() {
+        if !any_contains(trusted_proxies, &client) {
+            break;
+        }
+        // a forged or garbled chain can't be attributed to anyone
+        client = hop.trim().parse().ok()?;
+    }
+    Some(client)
+}
+
+/// Share of the requests to `path` written to the access log, from the rule with the longest
+/// prefix of the path, all of them without one
+fn access_log_sample(rules: &[AccessLogRule], path: &str) -> f64 {
+    rules
+        .iter()
+        .filter(|rule| path.starts_with(&rule.prefix[..]))
+        .max_by_key(|rule| rule.prefix.len())
+        .map_or(1.0, |rule| rule.sample)
 }
 
 /// Serves content from the `static` folder
@@ -287,6 +581,7 @@ fn serve_static_content(req: Request<Body>) -> ResponseFuture {
 #[cfg(test)]
 mod http_tests {
     use crate::config::{Config, LogAuth, Server, Token};
+    use crate::constants::LOCKOUT_THRESHOLD;
 
     use super::*;
 
@@ -313,28 +608,26 @@ mod http_tests {
             token[0..16].to_string(),
             Token {
                 access_key: token[0..16].to_string(),
-                secret_key: token[16..48].to_string(),
+                secret_key: hash_secret(&token[16..48]),
                 description: None,
                 is_admin: false,
                 enabled: true,
                 api_access: false,
+                ip_allowlist: Vec::new(),
+                redact: Vec::new(),
             },
         );
 
         Config {
             server: Server {
-                address: "".to_string(),
-                metadata_endpoint: "".to_string(),
-                metadata_bucket: "".to_string(),
-                access_key: "".to_string(),
-                secret_key: "".to_string(),
-                pkcs12_cert: None,
-                pkcs12_password: None,
+                query_memory_limit: 1024,
+                ..Server::default()
             },
             datastore: HashMap::new(),
             tokens: tokens,
             log: HashMap::new(),
             auth: auth,
+            patterns: HashMap::new(),
         }
     }
 
@@ -372,6 +665,20 @@ mod http_tests {
         }
     }
 
+    #[test]
+    fn access_log_rule_of_longest_prefix() {
+        let rules = vec![
+            AccessLogRule::parse("/ui=0").unwrap(),
+            AccessLogRule::parse("/api=0.5").unwrap(),
+            AccessLogRule::parse("/api/status=0.01").unwrap(),
+        ];
+        assert_eq!(access_log_sample(&rules, "/ui/assets/app.js"), 0.0);
+        assert_eq!(access_log_sample(&rules, "/api/status"), 0.01);
+        assert_eq!(access_log_sample(&rules, "/api/logs"), 0.5);
+        assert_eq!(access_log_sample(&rules, "/search"), 1.0);
+        assert_eq!(access_log_sample(&[], "/ui"), 1.0);
+    }
+
     #[test]
     fn valid_token_header() {
         run_test_validate_token_from_header(ValidTokenHeaderTest {
@@ -407,4 +714,140 @@ mod http_tests {
             expected_token: Some("TOKEN2".to_string()),
         })
     }
+
+    fn run_test_requested_log(path: &str, expected: Option<&str>) {
+        let cfg = get_auth_config_for(VALID_TOKEN.to_string(), "mylog".to_string());
+        let http_c = Http::new(Arc::new(RwLock::new(cfg)));
+        let req = Request::builder()
+            .method("PUT")
+            .uri(path)
+            .body(Body::from("test"))
+            .unwrap();
+        assert_eq!(
+            http_c.requested_log_from_request(&req),
+            expected.map(|s| s.to_string())
+        );
+    }
+
+    fn request_from(remote: &str, forwarded: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/search")
+            .header("MINSQL-TOKEN", VALID_TOKEN);
+        if let Some(forwarded) = forwarded {
+            req.header("X-Forwarded-For", forwarded);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ClientAddr(remote.parse().unwrap()));
+        req
+    }
+
+    #[test]
+    fn client_ip_behind_trusted_proxies() {
+        let proxies = vec!["10.0.0.0/8".to_string()];
+        let ip = |addr: &str| -> Option<IpAddr> { Some(addr.parse().unwrap()) };
+        // a direct client can't forge its address
+        let req = request_from("203.0.113.5:4000", Some("192.168.1.1"));
+        assert_eq!(client_ip(&req, &proxies), ip("203.0.113.5"));
+        // the proxies on the chain are skipped
+        let req = request_from("10.0.0.1:4000", Some("198.51.100.7, 10.0.0.2"));
+        assert_eq!(client_ip(&req, &proxies), ip("198.51.100.7"));
+        // only the address after the last untrusted hop is believed
+        let req = request_from("10.0.0.1:4000", Some("192.168.1.1, 198.51.100.7"));
+        assert_eq!(client_ip(&req, &proxies), ip("198.51.100.7"));
+        let req = request_from("10.0.0.1:4000", Some("garbage"));
+        assert_eq!(client_ip(&req, &proxies), None);
+        let req = request_from("10.0.0.1:4000", None);
+        assert_eq!(client_ip(&req, &proxies), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn token_ip_allowlist() {
+        let mut cfg = get_auth_config_for(VALID_TOKEN.to_string(), "mylog".to_string());
+        cfg.tokens
+            .get_mut(&VALID_TOKEN[0..16])
+            .unwrap()
+            .ip_allowlist = vec!["192.168.0.0/16".to_string()];
+        let http_c = Http::new(Arc::new(RwLock::new(cfg)));
+        assert_eq!(
+            http_c.validate_token_from_header(&request_from("192.168.4.4:4000", None)),
+            HeaderToken::Token(VALID_TOKEN.to_string())
+        );
+        assert_eq!(
+            http_c.validate_token_from_header(&request_from("10.4.4.4:4000", None)),
+            HeaderToken::InvalidToken
+        );
+    }
+
+    #[test]
+    fn repeated_failures_lock_out() {
+        // a token of its own, so that other tests aren't locked out
+        let token = "LOCKOUTLOCKOUTLOCKOUTLOCKOUTLOCKOUTLOCKOUTLOCKOU";
+        let cfg = get_auth_config_for(token.to_string(), "mylog".to_string());
+        let http_c = Http::new(Arc::new(RwLock::new(cfg)));
+        let wrong_secret = format!("{}{}", &token[0..16], "x".repeat(32));
+        let req_with = |token: &str| {
+            let mut req = request_from("172.30.9.9:4000", None);
+            req.headers_mut()
+                .insert("MINSQL-TOKEN", token.parse().unwrap());
+            req
+        };
+        for _ in 0..LOCKOUT_THRESHOLD {
+            assert_eq!(
+                http_c.validate_token_from_header(&req_with(&wrong_secret)),
+                HeaderToken::InvalidToken
+            );
+        }
+        // even the right secret is rejected until the lockout is over
+        match http_c.validate_token_from_header(&req_with(token)) {
+            HeaderToken::LockedOut(secs) => assert!(secs >= 1),
+            other => panic!("expected a lockout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multibyte_passwords_are_invalid_tokens() {
+        let cfg = get_auth_config_for(VALID_TOKEN.to_string(), "mylog".to_string());
+        // 48 bytes, but the 16th one is in the middle of a character
+        let password = format!("{}é{}", "a".repeat(15), "b".repeat(31));
+        assert_eq!(password.len(), 48);
+        assert_eq!(
+            validate_token(&cfg, &password, None),
+            HeaderToken::InvalidToken
+        );
+    }
+
+    #[test]
+    fn legacy_secrets_are_rehashed() {
+        let mut cfg = get_auth_config_for(VALID_TOKEN.to_string(), "mylog".to_string());
+        cfg.tokens.get_mut(&VALID_TOKEN[0..16]).unwrap().secret_key =
+            VALID_TOKEN[16..48].to_string();
+        let config = Arc::new(RwLock::new(cfg));
+        let token = rehash_token_secret(&config, VALID_TOKEN).unwrap();
+        assert!(is_hashed(&token.secret_key));
+        assert_eq!(
+            validate_token(&config.read().unwrap(), VALID_TOKEN, None),
+            HeaderToken::Token(VALID_TOKEN.to_string())
+        );
+        // once is enough
+        assert!(rehash_token_secret(&config, VALID_TOKEN).is_none());
+    }
+
+    #[test]
+    fn requested_log_simple() {
+        run_test_requested_log("/mylog/store", Some("mylog"));
+    }
+
+    #[test]
+    fn requested_log_hierarchical() {
+        run_test_requested_log("/team/service/store", Some("team/service"));
+    }
+
+    #[test]
+    fn requested_log_invalid() {
+        run_test_requested_log("/store", None);
+        run_test_requested_log("/mylog/search", None);
+        run_test_requested_log("/team//store", None);
+    }
 }
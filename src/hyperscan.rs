@@ -1,9 +1,15 @@
-use crate::constants;
-use crate::constants::{SF_DATE, SF_EMAIL, SF_IP, SF_PHONE, SF_QUOTED, SF_URL, SF_USER_AGENT};
+use crate::config::SmartPattern;
+use crate::constants::{
+    SF_DATE, SF_EMAIL, SF_IP, SF_JSON, SF_KV, SF_PHONE, SF_QUOTED, SF_URL, SF_USER_AGENT,
+};
 use crate::query::{PatternType, QueryParsing};
 use hyperscan::*;
-use log::debug;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use regex::{Regex, RegexBuilder};
 use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
@@ -15,78 +21,355 @@ pub const P_DATE: usize = 4;
 pub const P_PHONE: usize = 5;
 pub const P_USER_AGENT: usize = 6;
 pub const P_URL: usize = 7;
+pub const P_KV: usize = 8;
+pub const P_JSON: usize = 9;
 
-pub fn build_hs_db(flags: &constants::ScanFlags) -> Option<BlockDatabase> {
-    let pattern_list: HashMap<usize, String> = [
-        (P_TEST, "test".to_string()),
-        (P_EMAIL, "([\\w\\.!#$%&'*+\\-=?\\^_`{|}~]+@([\\w\\d-]+\\.)+[\\w]{2,4})".to_string()),
-        (P_IP, "(((25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9][0-9]|[0-9])\\.){3}(25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9][0-9]|[0-9]))".to_string()),
-        // TODO: This Regex is not stopping on the first quote mark, probably collision algorithm is doing something odd
-        (P_QUOTED, "((\"(.*?)\")|'(.*?)')".to_string()),
-        (P_DATE, "((19[789]\\d|2\\d{3})[-/](0[1-9]|1[1-2])[-/](0[1-9]|[1-2][0-9]|3[0-1]*))|((0[1-9]|[1-2][0-9]|3[0-1]*)[-/](Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec|(0[1-9]|1[1-2]))[-/](19[789]\\d|2\\d{3}))".to_string()),
-        (P_PHONE, "[\\(]?(\\d{3})[\\)-]?[- ]?(\\d{3})[- ]?(\\d{4})".to_string()),
-        (P_USER_AGENT, "\"((Mozilla|Links).*? \\(.*?\\)( .*?[0-9]{1,3}\\.[0-9]{1,3}\\.?[0-9]{0,3})?)\"".to_string()),
-        (P_URL, "(https?|ftp)://[^\\s/$.?#].[^()\\]\\[\\s]*".to_string()),
-    ].iter().cloned().collect();
-
-    let mut patterns: Vec<Pattern> = Vec::new();
-
-    if flags.contains(constants::ScanFlags::IP) {
-        patterns.push(Pattern {
-            expression: pattern_list.get(&P_IP).unwrap().clone(),
-            id: P_IP.clone(),
-            flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
-        });
+/// A smart field found on the lines with a pattern
+pub struct SmartField {
+    // as queried, ie: `$ip`
+    pub name: &'static str,
+    // id of the pattern, which is also its flag on `ScanFlags`
+    pub id: usize,
+    pub pattern_type: PatternType,
+    // built-in expression, patterns set on the metabucket replace it
+    pub expression: &'static str,
+    // the value leaves out the first and last characters of the match, ie: the quotes
+    pub trimmed: bool,
+}
+
+/// Every smart field scanned for. Adding one only takes a pattern id and an entry here.
+pub const SMART_FIELD_PATTERNS: &[SmartField] = &[
+    SmartField {
+        name: SF_EMAIL,
+        id: P_EMAIL,
+        pattern_type: PatternType::Email,
+        expression: "([\\w\\.!#$%&'*+\\-=?\\^_`{|}~]+@([\\w\\d-]+\\.)+[\\w]{2,4})",
+        trimmed: false,
+    },
+    SmartField {
+        name: SF_IP,
+        id: P_IP,
+        pattern_type: PatternType::IP,
+        expression: "(((25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9][0-9]|[0-9])\\.){3}(25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9][0-9]|[0-9]))",
+        trimmed: false,
+    },
+    // TODO: This Regex is not stopping on the first quote mark, probably collision algorithm is doing something odd
+    SmartField {
+        name: SF_QUOTED,
+        id: P_QUOTED,
+        pattern_type: PatternType::Quoted,
+        expression: "((\"(.*?)\")|'(.*?)')",
+        trimmed: true,
+    },
+    SmartField {
+        name: SF_DATE,
+        id: P_DATE,
+        pattern_type: PatternType::Date,
+        expression: "((19[789]\\d|2\\d{3})[-/](0[1-9]|1[1-2])[-/](0[1-9]|[1-2][0-9]|3[0-1]*))|((0[1-9]|[1-2][0-9]|3[0-1]*)[-/](Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec|(0[1-9]|1[1-2]))[-/](19[789]\\d|2\\d{3}))",
+        trimmed: false,
+    },
+    SmartField {
+        name: SF_PHONE,
+        id: P_PHONE,
+        pattern_type: PatternType::Phone,
+        expression: "[\\(]?(\\d{3})[\\)-]?[- ]?(\\d{3})[- ]?(\\d{4})",
+        trimmed: false,
+    },
+    SmartField {
+        name: SF_USER_AGENT,
+        id: P_USER_AGENT,
+        pattern_type: PatternType::UserAgent,
+        expression: "\"((Mozilla|Links).*? \\(.*?\\)( .*?[0-9]{1,3}\\.[0-9]{1,3}\\.?[0-9]{0,3})?)\"",
+        trimmed: true,
+    },
+    SmartField {
+        name: SF_URL,
+        id: P_URL,
+        pattern_type: PatternType::Url,
+        expression: "(https?|ftp)://[^\\s/$.?#].[^()\\]\\[\\s]*",
+        trimmed: false,
+    },
+    // a `key=value` pair, as logfmt writes them, its subfields are read with `kv_value`
+    SmartField {
+        name: SF_KV,
+        id: P_KV,
+        pattern_type: PatternType::Kv,
+        expression: "[a-z_][a-z0-9_.\\-]*=(\"(\\\\.|[^\"\\\\])*\"|[^\\s\"]*)",
+        trimmed: false,
+    },
+    // a line holding a JSON object, only its start is matched as the line is parsed on demand
+    SmartField {
+        name: SF_JSON,
+        id: P_JSON,
+        pattern_type: PatternType::Json,
+        expression: "^\\s*\\{",
+        trimmed: false,
+    },
+];
+
+fn smart_field_for_id(id: usize) -> Option<&'static SmartField> {
+    SMART_FIELD_PATTERNS.iter().find(|field| field.id == id)
+}
+
+fn smart_field_for_type(pattern_type: &PatternType) -> Option<&'static SmartField> {
+    SMART_FIELD_PATTERNS
+        .iter()
+        .find(|field| field.pattern_type == *pattern_type)
+}
+
+/// Built-in expressions of the smart field patterns
+pub fn default_patterns() -> HashMap<usize, String> {
+    let mut patterns: HashMap<usize, String> = SMART_FIELD_PATTERNS
+        .iter()
+        .map(|field| (field.id, field.expression.to_string()))
+        .collect();
+    patterns.insert(P_TEST, "test".to_string());
+    patterns
+}
+
+/// Maps a smart field name, without the `$`, to its pattern id
+pub fn pattern_id_for_name(name: &str) -> Option<usize> {
+    SMART_FIELD_PATTERNS
+        .iter()
+        .find(|field| &field.name[1..] == name)
+        .map(|field| field.id)
+}
+
+/// Value of `key` on a line of `key=value` pairs, ie: `level=error msg="boom" user=42`. Quoted
+/// values are unescaped, words that are not pairs are skipped and the first pair with the key wins.
+pub fn kv_value(line: &str, key: &str) -> Option<String> {
+    let mut rest = line;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or_else(|| rest.len());
+        let pair_key = &rest[..key_end];
+        rest = &rest[key_end..];
+        if !rest.starts_with('=') {
+            continue;
+        }
+        rest = &rest[1..];
+        let (value, after) = if rest.starts_with('"') {
+            quoted_value(&rest[1..])
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or_else(|| rest.len());
+            (rest[..end].to_string(), &rest[end..])
+        };
+        if pair_key == key {
+            return Some(value);
+        }
+        rest = after;
     }
-    if flags.contains(constants::ScanFlags::EMAIL) {
-        patterns.push(Pattern {
-            expression: pattern_list.get(&P_EMAIL).unwrap().clone(),
-            id: P_EMAIL.clone(),
-            flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
-        });
+}
+
+/// A quoted value up to its closing quote, unescaped, along with what follows it
+fn quoted_value(rest: &str) -> (String, &str) {
+    let mut value = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return (value, &rest[i + 1..]),
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            c => value.push(c),
+        }
     }
-    if flags.contains(constants::ScanFlags::DATE) {
-        patterns.push(Pattern {
-            expression: pattern_list.get(&P_DATE).unwrap().clone(),
-            id: P_DATE.clone(),
-            flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
-        });
+    // a value missing its closing quote runs to the end of the line
+    (value, "")
+}
+
+/// The smart field patterns a query scans its lines for, as a set of pattern ids
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanFlags {
+    // bit `id % 64` of word `id / 64` is set when pattern `id` is scanned for, there are no
+    // trailing empty words so equal sets compare equal
+    words: Vec<u64>,
+}
+
+impl ScanFlags {
+    pub fn empty() -> ScanFlags {
+        ScanFlags::default()
     }
-    if flags.contains(constants::ScanFlags::QUOTED) {
-        patterns.push(Pattern {
-            expression: pattern_list.get(&P_QUOTED).unwrap().clone(),
-            id: P_QUOTED.clone(),
-            flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
-        });
+
+    /// Every smart field
+    pub fn all() -> ScanFlags {
+        let mut flags = ScanFlags::empty();
+        for field in SMART_FIELD_PATTERNS {
+            flags.insert(field.id);
+        }
+        flags
     }
-    if flags.contains(constants::ScanFlags::URL) {
-        patterns.push(Pattern {
-            expression: pattern_list.get(&P_URL).unwrap().clone(),
-            id: P_URL.clone(),
-            flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
-        });
+
+    /// The flag of a smart field, ie: `$ip`, empty if it's not one
+    pub fn for_field(name: &str) -> ScanFlags {
+        let mut flags = ScanFlags::empty();
+        if let Some(field) = SMART_FIELD_PATTERNS.iter().find(|field| field.name == name) {
+            flags.insert(field.id);
+        }
+        flags
     }
-    if flags.contains(constants::ScanFlags::PHONE) {
-        patterns.push(Pattern {
-            expression: pattern_list.get(&P_PHONE).unwrap().clone(),
-            id: P_PHONE.clone(),
-            flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
-        });
+
+    pub fn insert(&mut self, id: usize) {
+        let word = id / 64;
+        if self.words.len() <= word {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1u64 << (id % 64);
     }
-    if flags.contains(constants::ScanFlags::USER_AGENT) {
-        patterns.push(Pattern {
-            expression: pattern_list.get(&P_USER_AGENT).unwrap().clone(),
-            id: P_USER_AGENT.clone(),
-            flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
-        });
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.words
+            .get(id / 64)
+            .map_or(false, |word| word & (1u64 << (id % 64)) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Adds the flags of `other`
+    pub fn union(&mut self, other: &ScanFlags) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other_word) in self.words.iter_mut().zip(&other.words) {
+            *word |= *other_word;
+        }
+    }
+
+    /// The pattern ids of the set, in ascending order
+    pub fn ids<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            (0..64)
+                .filter(move |bit| word & (1u64 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+}
+
+extern "C" {
+    // part of the Hyperscan library linked by the `hyperscan` crate
+    fn hs_version() -> *const c_char;
+}
+
+/// Version and build date of the Hyperscan library the server runs with
+pub fn hyperscan_version() -> String {
+    unsafe { CStr::from_ptr(hs_version()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+lazy_static! {
+    // whether Hyperscan runs on this CPU, checked once by compiling the built-in patterns
+    static ref HYPERSCAN_SUPPORTED: bool = probe_hyperscan();
+}
+
+fn probe_hyperscan() -> bool {
+    let patterns: Vec<Pattern> = default_patterns()
+        .into_iter()
+        .map(|(id, expression)| hs_pattern(id, expression))
+        .collect();
+    let res_db: Result<BlockDatabase, _> = patterns.build();
+    res_db.is_ok()
+}
+
+/// Whether the smart field patterns are matched with Hyperscan, else with the slower regex engine
+pub fn hyperscan_supported() -> bool {
+    *HYPERSCAN_SUPPORTED
+}
+
+fn hs_pattern(id: usize, expression: String) -> Pattern {
+    Pattern {
+        expression,
+        id,
+        flags: CompileFlags(HS_FLAG_CASELESS | HS_FLAG_SOM_LEFTMOST),
     }
+}
 
+/// A smart field expression compiled for the regex engine, matching as Hyperscan does
+pub fn regex_pattern(expression: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(expression).case_insensitive(true).build()
+}
+
+/// Whether an expression compiles as a smart field pattern
+pub fn valid_pattern_expression(expression: &str) -> bool {
+    if !hyperscan_supported() {
+        return regex_pattern(expression).is_ok();
+    }
+    let patterns = vec![hs_pattern(P_TEST, expression.to_string())];
     let res_db: Result<BlockDatabase, _> = patterns.build();
+    res_db.is_ok()
+}
+
+/// Compiled smart field patterns of a query
+#[derive(Debug)]
+pub enum PatternDb {
+    Hyperscan(BlockDatabase),
+    // used when Hyperscan can't compile the patterns, ie: on CPUs it doesn't support
+    Regex(Vec<(usize, Regex)>),
+}
 
-    match res_db {
-        Ok(db) => Some(db),
-        Err(_) => None,
+/// Expressions of the patterns for the `flags`, the built-in expressions are replaced by the ones
+/// in `overrides` when present.
+fn selected_patterns(
+    flags: &ScanFlags,
+    overrides: &HashMap<String, SmartPattern>,
+) -> Vec<(usize, String)> {
+    let mut pattern_list = default_patterns();
+    for (name, smart_pattern) in overrides {
+        if let Some(id) = pattern_id_for_name(name) {
+            pattern_list.insert(id, smart_pattern.expression.clone());
+        }
     }
+    flags
+        .ids()
+        .filter_map(|id| Some((id, pattern_list.get(&id)?.clone())))
+        .collect()
+}
+
+/// Compiles the patterns for the `flags` with Hyperscan, falling back to the regex engine if
+/// Hyperscan can't compile them. `None` if no pattern is needed or they don't compile at all.
+pub fn build_hs_db(
+    flags: &ScanFlags,
+    overrides: &HashMap<String, SmartPattern>,
+) -> Option<PatternDb> {
+    let selected = selected_patterns(flags, overrides);
+    if selected.is_empty() {
+        return None;
+    }
+    if hyperscan_supported() {
+        let patterns: Vec<Pattern> = selected
+            .iter()
+            .map(|(id, expression)| hs_pattern(*id, expression.clone()))
+            .collect();
+        let res_db: Result<BlockDatabase, _> = patterns.build();
+        match res_db {
+            Ok(db) => return Some(PatternDb::Hyperscan(db)),
+            Err(e) => debug!("Hyperscan could not compile the patterns: {:?}", e),
+        }
+    }
+    build_regex_db(&selected)
+}
+
+fn build_regex_db(selected: &[(usize, String)]) -> Option<PatternDb> {
+    let mut regexes = Vec::new();
+    for (id, expression) in selected {
+        match regex_pattern(expression) {
+            Ok(re) => regexes.push((*id, re)),
+            Err(e) => {
+                error!("Could not compile pattern {}: {}", expression, e);
+                return None;
+            }
+        }
+    }
+    Some(PatternDb::Regex(regexes))
 }
 
 #[derive(Debug, Clone)]
@@ -99,27 +382,35 @@ pub struct HSPatternMatch {
 pub struct HSLineScanner<'a> {
     //    pub inner: Arc<Mutex<LineScannerData>>,
     pub lines: &'a Vec<String>,
-    pub line_matches: HashMap<u16, HashMap<u16, Vec<HSPatternMatch>>>,
 }
 
-pub type HSPatternMatchResults = Arc<RwLock<HashMap<u16, Vec<HSPatternMatch>>>>;
+/// Smart field matches of a scanned batch keyed by the index of the line within the batch. It
+/// only lives as long as the batch, each line takes its matches out of it as it's evaluated.
+pub type HSPatternMatchResults = Arc<RwLock<HashMap<usize, Vec<HSPatternMatch>>>>;
 
 impl<'a> HSLineScanner<'a> {
     pub fn new(lines: &Vec<String>) -> HSLineScanner {
-        HSLineScanner {
-            lines: lines,
-            line_matches: HashMap::new(),
-        }
+        HSLineScanner { lines: lines }
     }
 
-    pub fn scan(&mut self, db: &mut BlockDatabase) -> HSPatternMatchResults {
+    pub fn scan(&mut self, db: &mut PatternDb) -> HSPatternMatchResults {
         let now = Instant::now();
 
+        let pattern_match_results: HSPatternMatchResults = Arc::new(RwLock::new(HashMap::new()));
+        match db {
+            PatternDb::Hyperscan(db) => self.scan_hs(db, &pattern_match_results),
+            PatternDb::Regex(regexes) => self.scan_regex(regexes, &pattern_match_results),
+        }
+
+        debug!("scan completed in {:?}", now.elapsed());
+
+        pattern_match_results
+    }
+
+    fn scan_hs(&self, db: &mut BlockDatabase, pattern_match_results: &HSPatternMatchResults) {
         let line_total = self.lines.len();
         let scratch = db.alloc().unwrap();
 
-        let pattern_match_results: HSPatternMatchResults = Arc::new(RwLock::new(HashMap::new()));
-
         for i in 0..line_total {
             db.scan_mut(
                 &self.lines[i][..],
@@ -128,37 +419,52 @@ impl<'a> HSLineScanner<'a> {
                 Some(callback_block),
                 Some(&mut HSScanPair {
                     line: &self.lines[i],
-                    line_index: i as u16,
+                    line_index: i,
                     pattern_match_results: Arc::clone(&pattern_match_results),
                 }),
             )
             .unwrap();
         }
+    }
 
-        debug!("scan completed in {:?}", now.elapsed());
-
-        pattern_match_results
+    /// Matches of the regex engine, which are the leftmost non-overlapping ones and so need none
+    /// of the collision handling of the Hyperscan matches
+    fn scan_regex(
+        &self,
+        regexes: &[(usize, Regex)],
+        pattern_match_results: &HSPatternMatchResults,
+    ) {
+        let mut line_map = pattern_match_results.write().unwrap();
+        for (i, line) in self.lines.iter().enumerate() {
+            for (id, re) in regexes {
+                for m in re.find_iter(line) {
+                    line_map
+                        .entry(i)
+                        .or_insert_with(Vec::new)
+                        .push(HSPatternMatch {
+                            pattern: pattern_type_for_id(*id),
+                            from: m.start() as u64,
+                            to: m.end() as u64,
+                        });
+                }
+            }
+        }
     }
 }
 
 struct HSScanPair<'a> {
     pub line: &'a String,
     pub pattern_match_results: HSPatternMatchResults,
-    pub line_index: u16,
+    pub line_index: usize,
+}
+
+fn pattern_type_for_id(id: usize) -> PatternType {
+    smart_field_for_id(id).map_or(PatternType::Unknown, |field| field.pattern_type.clone())
 }
 
 fn callback_block(id: u32, from: u64, to: u64, _flags: u32, context: &mut HSScanPair) -> u32 {
     // figure out the pattern
-    let pattern_type = match id as usize {
-        P_IP => PatternType::IP,
-        P_EMAIL => PatternType::Email,
-        P_DATE => PatternType::Date,
-        P_QUOTED => PatternType::Quoted,
-        P_URL => PatternType::Url,
-        P_PHONE => PatternType::Phone,
-        P_USER_AGENT => PatternType::UserAgent,
-        _ => PatternType::Unknown,
-    };
+    let pattern_type = pattern_type_for_id(id as usize);
 
     //  Get the patterns matched for this line, else insert new map
     let mut line_map = context.pattern_match_results.write().unwrap();
@@ -253,38 +559,17 @@ fn callback_block(id: u32, from: u64, to: u64, _flags: u32, context: &mut HSScan
     0
 }
 
-pub fn alloc_result_map(
-    flags: &constants::ScanFlags,
-) -> HashMap<String, Vec<Option<HSPatternMatch>>> {
-    let mut results: HashMap<String, Vec<Option<HSPatternMatch>>> = HashMap::new();
-
-    if flags.contains(constants::ScanFlags::IP) {
-        results.insert(SF_IP.to_string(), Vec::new());
-    }
-    if flags.contains(constants::ScanFlags::EMAIL) {
-        results.insert(SF_EMAIL.to_string(), Vec::new());
-    }
-    if flags.contains(constants::ScanFlags::DATE) {
-        results.insert(SF_DATE.to_string(), Vec::new());
-    }
-    if flags.contains(constants::ScanFlags::QUOTED) {
-        results.insert(SF_QUOTED.to_string(), Vec::new());
-    }
-    if flags.contains(constants::ScanFlags::URL) {
-        results.insert(SF_URL.to_string(), Vec::new());
-    }
-    if flags.contains(constants::ScanFlags::PHONE) {
-        results.insert(SF_PHONE.to_string(), Vec::new());
-    }
-    if flags.contains(constants::ScanFlags::USER_AGENT) {
-        results.insert(SF_USER_AGENT.to_string(), Vec::new());
-    }
-    results
+pub fn alloc_result_map(flags: &ScanFlags) -> HashMap<String, Vec<Option<HSPatternMatch>>> {
+    SMART_FIELD_PATTERNS
+        .iter()
+        .filter(|field| flags.contains(field.id))
+        .map(|field| (field.name.to_string(), Vec::new()))
+        .collect()
 }
 
 pub fn found_patterns_in_line(
     pattern_match_results: HSPatternMatchResults,
-    line_index: &u16,
+    line_index: &usize,
     query_data: &QueryParsing,
 ) -> HashMap<String, Vec<Option<HSPatternMatch>>> {
     // Retain only the lines with matches
@@ -292,49 +577,144 @@ pub fn found_patterns_in_line(
     let mut found_vals: HashMap<String, Vec<Option<HSPatternMatch>>> =
         alloc_result_map(&query_data.scan_flags);
     // only the lines reported in pattern_match_results have the desired projections
-    if read_match_hold.contains_key(line_index) {
-        let patterns = read_match_hold.remove(line_index).unwrap();
-
+    if let Some(patterns) = read_match_hold.remove(line_index) {
         for pat in patterns.into_iter() {
-            match &pat.pattern {
-                PatternType::IP => {
-                    found_vals.get_mut(SF_IP).unwrap().push(Some(pat));
-                }
-                PatternType::Email => {
-                    found_vals.get_mut(SF_EMAIL).unwrap().push(Some(pat));
-                }
-                PatternType::Date => {
-                    found_vals.get_mut(SF_DATE).unwrap().push(Some(pat));
-                }
-                PatternType::Quoted => {
-                    found_vals
-                        .get_mut(SF_QUOTED)
-                        .unwrap()
-                        .push(Some(HSPatternMatch {
-                            pattern: pat.pattern,
-                            from: pat.from + 1,
-                            to: pat.to - 1,
-                        }));
-                }
-                PatternType::Url => {
-                    found_vals.get_mut(SF_URL).unwrap().push(Some(pat));
-                }
-                PatternType::Phone => {
-                    found_vals.get_mut(SF_PHONE).unwrap().push(Some(pat));
-                }
-                PatternType::UserAgent => {
-                    found_vals
-                        .get_mut(SF_USER_AGENT)
-                        .unwrap()
-                        .push(Some(HSPatternMatch {
-                            pattern: pat.pattern,
-                            from: pat.from + 1,
-                            to: pat.to - 1,
-                        }));
-                }
-                _ => (),
+            let field = match smart_field_for_type(&pat.pattern) {
+                Some(field) => field,
+                None => continue,
+            };
+            let found = match found_vals.get_mut(field.name) {
+                Some(found) => found,
+                None => continue,
+            };
+            if field.trimmed {
+                found.push(Some(HSPatternMatch {
+                    pattern: pat.pattern,
+                    from: pat.from + 1,
+                    to: pat.to - 1,
+                }));
+            } else {
+                found.push(Some(pat));
             }
         }
     }
     found_vals
 }
+
+#[cfg(test)]
+mod hyperscan_tests {
+    use super::*;
+
+    fn matches(db: &mut PatternDb, lines: &Vec<String>) -> Vec<(usize, String, u64, u64)> {
+        let results = HSLineScanner::new(lines).scan(db);
+        let results = results.read().unwrap();
+        let mut matches: Vec<(usize, String, u64, u64)> = results
+            .iter()
+            .flat_map(|(i, found)| {
+                found
+                    .iter()
+                    .map(move |m| (*i, format!("{:?}", m.pattern), m.from, m.to))
+            })
+            .collect();
+        matches.sort();
+        matches
+    }
+
+    #[test]
+    fn regex_fallback_matches_hyperscan() {
+        let mut flags = ScanFlags::empty();
+        for id in &[P_IP, P_EMAIL, P_QUOTED, P_URL] {
+            flags.insert(*id);
+        }
+        let selected = selected_patterns(&flags, &HashMap::new());
+        let lines: Vec<String> = vec![
+            "192.168.1.100 - frank@example.com \"GET /index.html\"".to_string(),
+            "'single' and \"double\" quotes from 10.0.0.12".to_string(),
+            "fetched https://min.io/docs?page=2 for user".to_string(),
+            "nothing to see here".to_string(),
+        ];
+
+        let mut regex_db = build_regex_db(&selected).unwrap();
+        let found = matches(&mut regex_db, &lines);
+        assert!(found.contains(&(0, "IP".to_string(), 0, 13)));
+        assert!(found.contains(&(1, "Quoted".to_string(), 0, 8)));
+        assert!(!found.iter().any(|(i, _, _, _)| *i == 3));
+
+        if hyperscan_supported() {
+            let mut hs_db = build_hs_db(&flags, &HashMap::new()).unwrap();
+            assert_eq!(matches(&mut hs_db, &lines), found);
+        }
+    }
+
+    #[test]
+    fn no_patterns_no_db() {
+        assert!(build_hs_db(&ScanFlags::empty(), &HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn scan_flags_past_a_word() {
+        let mut flags = ScanFlags::for_field(SF_IP);
+        assert!(flags.contains(P_IP));
+        assert!(!flags.contains(P_EMAIL));
+        assert!(!flags.contains(200));
+        let mut wide = ScanFlags::empty();
+        wide.insert(200);
+        wide.insert(P_URL);
+        flags.union(&wide);
+        assert!(flags.contains(200));
+        assert_eq!(flags.ids().collect::<Vec<usize>>(), vec![P_IP, P_URL, 200]);
+        // patterns without an expression are left out of the database
+        assert_eq!(
+            selected_patterns(&flags, &HashMap::new())
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<usize>>(),
+            vec![P_IP, P_URL]
+        );
+        assert!(ScanFlags::for_field("$line").is_empty());
+        assert_eq!(ScanFlags::for_field(SF_DATE), {
+            let mut date = ScanFlags::empty();
+            date.insert(P_DATE);
+            date
+        });
+    }
+
+    #[test]
+    fn kv_values() {
+        let line = "ts=2019-07-24T09:30:00Z level=error GET /index msg=\"boom at \\\"db\\\"\" user=42 empty=";
+        assert_eq!(kv_value(line, "level"), Some("error".to_string()));
+        assert_eq!(kv_value(line, "msg"), Some("boom at \"db\"".to_string()));
+        assert_eq!(kv_value(line, "user"), Some("42".to_string()));
+        assert_eq!(kv_value(line, "empty"), Some("".to_string()));
+        assert_eq!(kv_value(line, "GET"), None);
+        assert_eq!(kv_value(line, "missing"), None);
+        // the first pair wins, an unterminated quote runs to the end
+        assert_eq!(kv_value("a=1 a=2", "a"), Some("1".to_string()));
+        assert_eq!(kv_value("a=\"one two", "a"), Some("one two".to_string()));
+
+        let flags = ScanFlags::for_field(SF_KV);
+        let selected = selected_patterns(&flags, &HashMap::new());
+        let mut regex_db = build_regex_db(&selected).unwrap();
+        let found = matches(&mut regex_db, &vec![line.to_string()]);
+        assert!(found.contains(&(0, "Kv".to_string(), 24, 35)));
+        if hyperscan_supported() {
+            let mut hs_db = build_hs_db(&flags, &HashMap::new()).unwrap();
+            assert!(matches(&mut hs_db, &vec![line.to_string()]).contains(&(
+                0,
+                "Kv".to_string(),
+                24,
+                35
+            )));
+        }
+    }
+
+    #[test]
+    fn registry_covers_the_smart_fields() {
+        for name in crate::constants::SMART_FIELDS {
+            let id = pattern_id_for_name(&name[1..]).unwrap();
+            assert!(default_patterns().contains_key(&id));
+            assert!(ScanFlags::all().contains(id));
+            assert!(alloc_result_map(&ScanFlags::all()).contains_key(*name));
+        }
+    }
+}
@@ -0,0 +1,365 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::thread;
+
+use futures::future::Either;
+use futures::stream::Stream;
+use futures::sync::oneshot;
+use futures::{future, Future};
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+
+use crate::concurrency::ConcurrencyLimit;
+use crate::config::{AuthProviders, LdapProvider, OidcProvider};
+use crate::constants::{LDAP_MAX_CONCURRENT_LOGINS, ROLE_ADMIN, ROLE_VIEWER};
+use crate::ldap::LdapConnection;
+
+lazy_static! {
+    // Threads blocking on LDAP binds, bounded so a flood of logins can't spawn without limit
+    static ref LDAP_LOGINS: ConcurrencyLimit = ConcurrencyLimit::new(LDAP_MAX_CONCURRENT_LOGINS);
+    // Shared so connections to the identity provider are reused
+    static ref PROVIDER_CLIENT: Option<Client<HttpsConnector<HttpConnector>>> =
+        HttpsConnector::new(1)
+            .map(|https| Client::builder().build(https))
+            .ok();
+}
+
+pub type IdentityFuture = Box<dyn Future<Item = Identity, Error = String> + Send>;
+
+/// A user authenticated by an identity provider, along with the groups it belongs to
+#[derive(Debug, PartialEq)]
+pub struct Identity {
+    pub subject: String,
+    pub groups: Vec<String>,
+}
+
+/// What the admin API can be used for, granted to the members of the groups mapped to it
+#[derive(Debug, PartialEq)]
+pub enum Role {
+    Admin,
+    // reads only, `GET` requests
+    Viewer,
+}
+
+/// Credentials sent on the `Authorization` header
+#[derive(Debug, PartialEq)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+/// An identity provider the admin API can authenticate users against
+pub trait AuthProvider {
+    /// Authenticates the credentials, `None` if the provider doesn't take that kind of credentials
+    fn authenticate(&self, credentials: &Credentials) -> Option<IdentityFuture>;
+}
+
+/// Parses the credentials of the `Authorization` header, either `Basic` or `Bearer`
+pub fn credentials_from_request(req: &Request<Body>) -> Option<Credentials> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    let param = parts.next()?.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(Credentials::Bearer(param.to_string()));
+    }
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(param).ok()?).ok()?;
+    let mut user_pass = decoded.splitn(2, ':');
+    Some(Credentials::Basic {
+        username: user_pass.next()?.to_string(),
+        password: user_pass.next()?.to_string(),
+    })
+}
+
+/// Authenticates the credentials against the first configured provider that takes them
+pub fn authenticate(providers: &AuthProviders, credentials: &Credentials) -> IdentityFuture {
+    let mut configured: Vec<Box<dyn AuthProvider>> = Vec::new();
+    if let Some(oidc) = &providers.oidc {
+        configured.push(Box::new(oidc.clone()));
+    }
+    if let Some(ldap) = &providers.ldap {
+        configured.push(Box::new(ldap.clone()));
+    }
+    configured
+        .iter()
+        .filter_map(|provider| provider.authenticate(credentials))
+        .next()
+        .unwrap_or_else(|| {
+            Box::new(future::err(
+                "No identity provider takes these credentials".to_string(),
+            ))
+        })
+}
+
+/// The role of an identity, from the groups mapped to a role. Admin wins if several apply.
+pub fn role_for(role_mapping: &HashMap<String, String>, groups: &[String]) -> Option<Role> {
+    let roles: Vec<&str> = groups
+        .iter()
+        .filter_map(|group| role_mapping.get(group))
+        .map(|role| role.as_str())
+        .collect();
+    if roles.contains(&ROLE_ADMIN) {
+        Some(Role::Admin)
+    } else if roles.contains(&ROLE_VIEWER) {
+        Some(Role::Viewer)
+    } else {
+        None
+    }
+}
+
+impl AuthProvider for OidcProvider {
+    /// Bearer tokens issued by the provider are checked on its userinfo endpoint, which answers
+    /// with the claims of the user they were issued to
+    fn authenticate(&self, credentials: &Credentials) -> Option<IdentityFuture> {
+        let access_token = match credentials {
+            Credentials::Bearer(access_token) => access_token,
+            _ => return None,
+        };
+        let client = match PROVIDER_CLIENT.as_ref() {
+            Some(client) => client,
+            None => return Some(Box::new(future::err("Could not set up TLS".to_string()))),
+        };
+        let req = match Request::builder()
+            .uri(&self.userinfo_url[..])
+            .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
+            .body(Body::empty())
+        {
+            Ok(req) => req,
+            Err(e) => {
+                return Some(Box::new(future::err(format!(
+                    "Invalid userinfo url: {}",
+                    e
+                ))))
+            }
+        };
+        let groups_claim = self.groups_claim.clone();
+        Some(Box::new(
+            client
+                .request(req)
+                .map_err(|e| format!("Could not reach the identity provider: {}", e))
+                .and_then(|resp| {
+                    if !resp.status().is_success() {
+                        let msg = format!("The identity provider answered {}", resp.status());
+                        return Either::B(future::err(msg));
+                    }
+                    Either::A(
+                        resp.into_body()
+                            .concat2()
+                            .map_err(|e| format!("Could not read the user info: {}", e)),
+                    )
+                })
+                .and_then(move |body| {
+                    let claims: serde_json::Value = serde_json::from_slice(&body)
+                        .map_err(|_| "Could not parse the user info".to_string())?;
+                    identity_from_claims(&claims, &groups_claim)
+                }),
+        ))
+    }
+}
+
+/// The subject of the user info claims and the groups on its groups claim, either a list or a
+/// single group
+fn identity_from_claims(
+    claims: &serde_json::Value,
+    groups_claim: &str,
+) -> Result<Identity, String> {
+    let subject = match claims.get("sub") {
+        Some(serde_json::Value::String(sub)) => sub.clone(),
+        _ => return Err("The user info has no subject".to_string()),
+    };
+    let groups = match claims.get(groups_claim) {
+        Some(serde_json::Value::Array(groups)) => groups
+            .iter()
+            .filter_map(|group| group.as_str())
+            .map(|group| group.to_string())
+            .collect(),
+        Some(serde_json::Value::String(group)) => vec![group.clone()],
+        _ => Vec::new(),
+    };
+    Ok(Identity { subject, groups })
+}
+
+impl AuthProvider for LdapProvider {
+    /// Users log in with their username and password, which are bound as the entry of `user_dn`.
+    /// The bind blocks, so it's done on a thread of its own, up to `LDAP_MAX_CONCURRENT_LOGINS`
+    /// at once.
+    fn authenticate(&self, credentials: &Credentials) -> Option<IdentityFuture> {
+        let (username, password) = match credentials {
+            Credentials::Basic { username, password } => (username.clone(), password.clone()),
+            _ => return None,
+        };
+        // an empty password is an unauthenticated bind, which servers accept for any entry
+        if username.is_empty() || password.is_empty() {
+            return Some(Box::new(future::err("Invalid credentials".to_string())));
+        }
+        let provider = self.clone();
+        Some(Box::new(
+            LDAP_LOGINS
+                .acquire()
+                .map_err(|_| "The LDAP login was interrupted".to_string())
+                .and_then(move |permit| {
+                    let (tx, rx) = oneshot::channel();
+                    thread::spawn(move || {
+                        let _ = tx.send(ldap_login(&provider, &username, &password));
+                        drop(permit);
+                    });
+                    rx.map_err(|_| "The LDAP login was interrupted".to_string())
+                })
+                .and_then(|res| res),
+        ))
+    }
+}
+
+fn ldap_login(provider: &LdapProvider, username: &str, password: &str) -> Result<Identity, String> {
+    let dn = provider.user_dn.replace("{}", &escape_dn_value(username));
+    let mut ldap = LdapConnection::connect(&provider.url, provider.allow_plaintext)
+        .map_err(|e| format!("Could not reach the LDAP server: {}", e))?;
+    if !ldap
+        .simple_bind(&dn, password)
+        .map_err(|e| format!("Could not bind to the LDAP server: {}", e))?
+    {
+        return Err("Invalid credentials".to_string());
+    }
+    let groups = ldap
+        .read_attribute(&dn, &provider.groups_attribute)
+        .map_err(|e| format!("Could not read the groups of {}: {}", dn, e))?
+        .iter()
+        .flat_map(|group| group_names(group))
+        .collect();
+    ldap.unbind();
+    Ok(Identity {
+        subject: dn,
+        groups,
+    })
+}
+
+/// A group given as a DN can be mapped either by its DN or by its name, ie:
+/// `cn=admins,ou=groups,dc=example,dc=com` or `admins`
+fn group_names(group: &str) -> Vec<String> {
+    let mut names = vec![group.to_string()];
+    if let Some(first) = group.split(',').next() {
+        let mut rdn = first.splitn(2, '=');
+        if let (Some(_), Some(name)) = (rdn.next(), rdn.next()) {
+            names.push(name.trim().to_string());
+        }
+    }
+    names
+}
+
+/// Escapes a username to be used as an attribute value of a DN, as of RFC 4514
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | ' ' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    if escaped.ends_with(' ') && !escaped.ends_with("\\ ") {
+        escaped.pop();
+        escaped.push_str("\\ ");
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod identity_tests {
+    use super::*;
+
+    fn request_with(authorization: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/api/logs")
+            .header(header::AUTHORIZATION, authorization)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_credentials() {
+        assert_eq!(
+            credentials_from_request(&request_with("Basic YWxpY2U6czNjcjN0OjI=")),
+            Some(Credentials::Basic {
+                username: "alice".to_string(),
+                password: "s3cr3t:2".to_string()
+            })
+        );
+        assert_eq!(
+            credentials_from_request(&request_with("Bearer eyJhbGciOi")),
+            Some(Credentials::Bearer("eyJhbGciOi".to_string()))
+        );
+        assert_eq!(credentials_from_request(&request_with("Basic !!")), None);
+        assert_eq!(credentials_from_request(&request_with("Digest abc")), None);
+    }
+
+    #[test]
+    fn map_groups_to_roles() {
+        let mut mapping = HashMap::new();
+        mapping.insert("minsql-admins".to_string(), "admin".to_string());
+        mapping.insert("ops".to_string(), "viewer".to_string());
+        let groups = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(role_for(&mapping, &groups(&["ops"])), Some(Role::Viewer));
+        assert_eq!(
+            role_for(&mapping, &groups(&["ops", "minsql-admins"])),
+            Some(Role::Admin)
+        );
+        assert_eq!(role_for(&mapping, &groups(&["everyone"])), None);
+    }
+
+    #[test]
+    fn oidc_claims() {
+        let claims = serde_json::json!({"sub": "248289761001", "groups": ["ops", 3, "dev"]});
+        assert_eq!(
+            identity_from_claims(&claims, "groups"),
+            Ok(Identity {
+                subject: "248289761001".to_string(),
+                groups: vec!["ops".to_string(), "dev".to_string()],
+            })
+        );
+        let claims = serde_json::json!({"sub": "248289761001", "role": "ops"});
+        assert_eq!(
+            identity_from_claims(&claims, "role").unwrap().groups,
+            vec!["ops"]
+        );
+        assert!(identity_from_claims(&serde_json::json!({}), "groups").is_err());
+    }
+
+    #[test]
+    fn ldap_names() {
+        assert_eq!(escape_dn_value("alice"), "alice");
+        assert_eq!(escape_dn_value("a,dmin=x"), "a\\,dmin\\=x");
+        assert_eq!(escape_dn_value("#bob "), "\\#bob\\ ");
+        assert_eq!(
+            group_names("cn=admins,ou=groups,dc=example,dc=com"),
+            vec!["cn=admins,ou=groups,dc=example,dc=com", "admins"]
+        );
+        assert_eq!(group_names("admins"), vec!["admins"]);
+    }
+}
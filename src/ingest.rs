@@ -14,29 +14,87 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::mem;
 use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
 
-use futures::future::Either;
-use futures::{Future, Stream};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::{Either, Loop};
+use futures::{future, stream, Future, Stream};
 use hyper::header;
 use hyper::Body;
 use hyper::Request;
 use hyper::Response;
 use hyper::StatusCode;
 use log::{error, info};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::timer::{Delay, Interval};
 
-use crate::config::Config;
-use crate::http::ResponseFuture;
-use crate::storage::write_to_datastore;
-use std::time::Instant;
+use crate::bloom::bloom_key;
+use crate::config::{limit_reached, Config, DataStore, Log};
+use crate::constants::{
+    APP_JSON, DEFAULT_PARTITION, ENCODING_BASE64, FLUSH_WAIT_POLL_MILLIS, HEADER_SEQUENCE,
+    HEADER_STREAM, INGEST_BUFFER_FLUSH_BYTES, QUOTA_DELETE_OLDEST, RECEIVED_FIRST_METADATA,
+    RECEIVED_LAST_METADATA, STAMP_PREPEND, STREAM_BATCH_BYTES,
+};
+use crate::http::{bool_header, return_400, return_500, GenericError, ResponseFuture};
+use crate::multiline::MultilineJoiner;
+use crate::naming::{partition_header, ObjectNaming};
+use crate::reports::record_ingested;
+use crate::signing::hex;
+use crate::storage::{
+    delete_object, list_msl_bucket_objects, put_object_metabucket, write_to_datastore, LogObject,
+    StoredObject,
+};
+use crate::supervisor;
+use crate::tee::tee_lines;
+use crate::trace::{request_trace, RequestTrace};
+use crate::usage::record_stored;
+use crate::watch::publish_lines;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct IngestBuffer {
     total_bytes: u64,
-    data: Vec<String>,
+    // payloads along with their partition, for logs partitioning their objects
+    data: Vec<(Option<String>, String)>,
+    // server side receive time of the oldest and newest data in the buffer
+    first_received: Option<DateTime<Utc>>,
+    last_received: Option<DateTime<Utc>>,
+    // data already stored for the log on its datastores, used to enforce quotas
+    stored_bytes: u64,
+    stored_objects: u64,
+    // flushes that took data out of the buffer and are still writing it
+    flushes_in_progress: u64,
+    // whether the oldest objects of the log are being deleted to bring it back within quota
+    evicting: bool,
+    // sequence of the last batch accepted for the log, seeded from the clock so it keeps
+    // increasing across restarts
+    last_sequence: u64,
+    // sequences of the batches in `data`
+    buffered_sequences: Vec<u64>,
+    // accepted batches not on the datastores yet, a failed write leaves its batches here
+    unflushed: BTreeSet<u64>,
+    // manifest of the chunks flushed within the dedup window of the log, by their digest
+    recent_chunks: HashMap<String, ChunkEntry>,
+}
+
+/// A chunk flushed for a log, identical chunks flushed after it are skipped
+#[derive(Debug, Clone)]
+struct ChunkEntry {
+    object: StoredObject,
+    written_at: DateTime<Utc>,
+}
+
+/// How far the batches accepted for a log have been written to its datastores. Every batch up to
+/// `flushed` is stored.
+#[derive(Debug, PartialEq)]
+pub struct Checkpoint {
+    pub accepted: u64,
+    pub flushed: u64,
+    pub pending: usize,
 }
 
 impl IngestBuffer {
@@ -44,7 +102,123 @@ impl IngestBuffer {
         IngestBuffer {
             total_bytes: 0,
             data: Vec::new(),
+            first_received: None,
+            last_received: None,
+            stored_bytes: 0,
+            stored_objects: 0,
+            flushes_in_progress: 0,
+            evicting: false,
+            last_sequence: sequence_seed(),
+            buffered_sequences: Vec::new(),
+            unflushed: BTreeSet::new(),
+            recent_chunks: HashMap::new(),
+        }
+    }
+
+    /// Assigns the next sequence to an accepted batch, it counts as unflushed until
+    /// `mark_flushed`
+    pub fn next_sequence(&mut self) -> u64 {
+        self.last_sequence += 1;
+        self.unflushed.insert(self.last_sequence);
+        self.last_sequence
+    }
+
+    pub fn mark_flushed(&mut self, sequences: &[u64]) {
+        for sequence in sequences {
+            self.unflushed.remove(sequence);
+        }
+    }
+
+    /// The latest sequence with every batch up to it written, batches may be written out of order
+    /// by concurrent flushes
+    pub fn checkpoint(&self) -> Checkpoint {
+        let flushed = match self.unflushed.iter().next() {
+            Some(first) => first - 1,
+            None => self.last_sequence,
+        };
+        Checkpoint {
+            accepted: self.last_sequence,
+            flushed,
+            pending: self.unflushed.len(),
+        }
+    }
+
+    /// Lines received but not flushed to the datastores yet, only those of a partition if one
+    /// is given
+    pub fn buffered_lines(&self, partition: Option<&str>) -> Vec<String> {
+        self.data
+            .iter()
+            .filter(
+                |(payload_partition, _)| match (payload_partition, partition) {
+                    (Some(payload_partition), Some(partition)) => payload_partition == partition,
+                    _ => true,
+                },
+            )
+            .flat_map(|(_, payload)| payload.lines())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    /// The object an identical chunk was flushed to within the last `window_secs`, forgetting the
+    /// chunks flushed before that
+    fn duplicate_chunk(
+        &mut self,
+        digest: &str,
+        now: &DateTime<Utc>,
+        window_secs: u64,
+    ) -> Option<StoredObject> {
+        let window = chrono::Duration::seconds(window_secs as i64);
+        self.recent_chunks
+            .retain(|_, entry| *now - entry.written_at < window);
+        self.recent_chunks
+            .get(digest)
+            .map(|entry| entry.object.clone())
+    }
+
+    fn record_chunk(&mut self, digest: String, object: StoredObject, written_at: DateTime<Utc>) {
+        self.recent_chunks
+            .insert(digest, ChunkEntry { object, written_at });
+    }
+
+    /// Accounts for stored objects merged into a single object of `written_bytes`
+    pub fn record_compacted(&mut self, merged_bytes: u64, merged_objects: u64, written_bytes: u64) {
+        self.stored_bytes = self.stored_bytes.saturating_sub(merged_bytes) + written_bytes;
+        self.stored_objects = self.stored_objects.saturating_sub(merged_objects) + 1;
+    }
+}
+
+/// The ingest buffers of every log. Logs created while the server runs get theirs when added.
+pub struct IngestBuffers {
+    buffers: RwLock<HashMap<String, Arc<Mutex<IngestBuffer>>>>,
+}
+
+impl IngestBuffers {
+    pub fn new() -> IngestBuffers {
+        IngestBuffers {
+            buffers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, log_name: &str) -> Option<Arc<Mutex<IngestBuffer>>> {
+        self.buffers.read().unwrap().get(log_name).map(Arc::clone)
+    }
+
+    pub fn contains_key(&self, log_name: &str) -> bool {
+        self.buffers.read().unwrap().contains_key(log_name)
+    }
+
+    /// Adds an empty buffer for the log, returns false if it had one already
+    pub fn insert(&self, log_name: &str) -> bool {
+        let mut buffers = self.buffers.write().unwrap();
+        if buffers.contains_key(log_name) {
+            return false;
         }
+        buffers.insert(
+            log_name.to_string(),
+            Arc::new(Mutex::new(IngestBuffer::new())),
+        );
+        true
     }
 }
 
@@ -61,124 +235,578 @@ impl Ingest {
     pub fn api_log_store(
         &self,
         req: Request<Body>,
-        log_ingest_buffers: Arc<HashMap<String, Mutex<IngestBuffer>>>,
+        log_ingest_buffers: Arc<IngestBuffers>,
         requested_log: String,
+        access_token: String,
     ) -> ResponseFuture {
-        let locked_cfg = Arc::clone(&self.config);
-        let flush_cfg = Arc::clone(&self.config);
-
-        // make a clone of the config for the closure
-        let cfg = Arc::clone(&self.config);
-        let ingest_c = Ingest::new(cfg);
+        let ingest_c = Ingest::new(Arc::clone(&self.config));
+        // with durable acks the data is committed before answering and the response lists the
+        // objects holding it
+        let durable_ack = bool_header(&req, "MINSQL-DURABLE-ACK");
+        // logs partitioning their objects store the lines under the partition they are sent with
+        let partition = match partition_header(&req) {
+            Ok(partition) => partition,
+            Err(e) => return Box::new(future::ok(return_400(&e))),
+        };
+        let trace = request_trace(&req);
+        // streamed bodies are stored in batches as they arrive instead of once they are whole
+        if bool_header(&req, HEADER_STREAM) {
+            return Box::new(ingest_c.store_stream(
+                req.into_body(),
+                log_ingest_buffers,
+                requested_log,
+                access_token,
+                partition,
+                durable_ack,
+                trace,
+            ));
+        }
         Box::new(
             req.into_body()
                 .concat2() // Concatenate all chunks in the body
                 .from_err()
                 .and_then(move |entire_body| {
-                    // Read the body from the request
-                    let payload: String = match String::from_utf8(entire_body.to_vec()) {
-                        Ok(str) => str,
-                        Err(err) => panic!("Couldn't convert buffer to string: {}", err),
-                    };
-                    let cfg = locked_cfg.read().unwrap();
-                    let log = cfg.get_log(&requested_log).unwrap();
-                    // if the commit window is 0s, commit immediately
-                    if log.commit_window == "0" {
-                        let cfg = Arc::clone(&ingest_c.config);
-                        let plen = payload.len() as i64;
-                        let response_body =
-                            write_to_datastore(cfg, &requested_log, vec![payload], plen).then(
-                                |res| -> Result<Response<Body>, _> {
-                                    match res {
-                                        Ok(_) => {
-                                            // Send response that the request has been received successfully
-                                            let response = Response::builder()
-                                                .status(StatusCode::OK)
-                                                .header(header::CONTENT_TYPE, "text/plain")
-                                                .body(Body::from("ok"))
-                                                .unwrap();
-                                            Ok(response)
-                                        }
-                                        Err(e) => {
-                                            error!("{:?}", e);
-                                            let response = Response::builder()
-                                                .status(StatusCode::INSUFFICIENT_STORAGE)
-                                                .header(header::CONTENT_TYPE, "text/plain")
-                                                .body(Body::from("fail"))
-                                                .unwrap();
-                                            Ok(response)
-                                        }
-                                    }
-                                },
-                            );
-                        Either::A(response_body)
-                    } else {
-                        // buffer the message
-                        let log_name = log.name.clone().unwrap();
-                        let ingest_buffer = log_ingest_buffers.get(&log_name[..]).unwrap();
-                        let mut protected_data = ingest_buffer.lock().unwrap();
-                        let total_bytes: u64;
-
-                        protected_data.total_bytes += payload.len() as u64;
-                        protected_data.data.push(payload.clone());
-                        total_bytes = protected_data.total_bytes.clone();
-
-                        drop(protected_data);
-                        // if we are above storage threshold, we will flush the data
-                        if total_bytes > 5 * 1024 * 1024 {
-                            info!("Buffer above 5MB, flushing.");
-                            let cfg = Arc::clone(&flush_cfg);
-                            let ingest_c = Ingest::new(cfg);
-                            hyper::rt::spawn({
-                                ingest_c.flush_buffer(&log_name, log_ingest_buffers)
-                            });
-                        }
-
-                        let response = Response::builder()
-                            .status(StatusCode::OK)
-                            .header(header::CONTENT_TYPE, "text/plain")
-                            .body(Body::from("ok."))
-                            .unwrap();
-                        Either::B(futures::future::ok(response))
-                        //                        Ok(response)
-                    }
+                    ingest_c.store_payload(
+                        &entire_body,
+                        log_ingest_buffers,
+                        requested_log,
+                        &access_token,
+                        partition,
+                        durable_ack,
+                        trace.as_ref(),
+                    )
                 }),
         )
     }
 
+    /// Creates `log` out of the auto create template for lines stored to an unknown log, then
+    /// stores them on it
+    pub fn api_log_auto_create(
+        &self,
+        req: Request<Body>,
+        log_ingest_buffers: Arc<IngestBuffers>,
+        log: Log,
+        access_token: String,
+    ) -> ResponseFuture {
+        let log_name = log.name.clone().unwrap();
+        let read_cfg = self.config.read().unwrap();
+        if limit_reached(read_cfg.log.len(), read_cfg.server.max_logs) {
+            let msg = format!("The limit of {} logs was reached", read_cfg.server.max_logs);
+            return Box::new(future::ok(return_400(&msg)));
+        }
+        if let Some(ds) = log
+            .datastores
+            .iter()
+            .find(|ds| !read_cfg.datastore.contains_key(&ds[..]))
+        {
+            error!(
+                "Could not auto create {}, unknown datastore {}",
+                log_name, ds
+            );
+            return Box::new(future::ok(return_500("Could not create the log")));
+        }
+        drop(read_cfg);
+        info!("Auto creating log {}", log_name);
+        let cfg = Arc::clone(&self.config);
+        let ingest_c = Ingest::new(Arc::clone(&self.config));
+        Box::new(
+            put_object_metabucket(
+                Arc::clone(&self.config),
+                format!("minsql/meta/logs/{}", log_name),
+                serde_json::to_string(&log).unwrap(),
+            )
+            .then(move |res| match res {
+                Ok(_) => {
+                    // the metabucket monitor loads it as well, though not before these lines
+                    cfg.write()
+                        .unwrap()
+                        .log
+                        .entry(log_name.clone())
+                        .or_insert(log);
+                    Either::A(ingest_c.api_log_store(
+                        req,
+                        log_ingest_buffers,
+                        log_name,
+                        access_token,
+                    ))
+                }
+                Err(e) => Either::B(future::ok(return_500(&format!(
+                    "Could not create the log: {}",
+                    e.reason()
+                )))),
+            }),
+        )
+    }
+
+    /// Stores a payload of lines on a log, buffering it or committing it right away as the log is
+    /// configured, and acknowledges it with a `StoreAck`. Lines received through other protocols
+    /// are stored through it as well. Logs partitioning their objects store lines sent without a
+    /// partition on the default one. The stored bytes are accounted to the log and to
+    /// `access_token`. Objects committed right away carry the trace of the request that sent them
+    /// in their metadata.
+    pub fn store_payload(
+        &self,
+        entire_body: &[u8],
+        log_ingest_buffers: Arc<IngestBuffers>,
+        requested_log: String,
+        access_token: &str,
+        partition: Option<String>,
+        durable_ack: bool,
+        trace: Option<&RequestTrace>,
+    ) -> impl Future<Item = Response<Body>, Error = GenericError> + Send {
+        self.store_batch(
+            entire_body,
+            log_ingest_buffers,
+            requested_log,
+            access_token,
+            partition,
+            durable_ack,
+            trace,
+        )
+        .map(|stored| match stored {
+            Ok((ack, sequence)) => store_ack_response(&ack, sequence),
+            Err(refused) => refused,
+        })
+    }
+
+    /// Stores a batch of lines as `store_payload` does, resolving to its ack and sequence or to
+    /// the response refusing it
+    fn store_batch(
+        &self,
+        entire_body: &[u8],
+        log_ingest_buffers: Arc<IngestBuffers>,
+        requested_log: String,
+        access_token: &str,
+        partition: Option<String>,
+        durable_ack: bool,
+        trace: Option<&RequestTrace>,
+    ) -> impl Future<Item = Result<(StoreAck, u64), Response<Body>>, Error = GenericError> + Send
+    {
+        let locked_cfg = Arc::clone(&self.config);
+        let flush_cfg = Arc::clone(&self.config);
+        let ingest_c = Ingest::new(Arc::clone(&self.config));
+        let received = Utc::now();
+        let cfg = locked_cfg.read().unwrap();
+        let log = cfg.get_log(&requested_log).unwrap();
+        // refuse new data while the log is over quota, unless old data gets evicted
+        // logs created while the server runs get their buffer along with their first lines
+        let log_name = log.name.clone().unwrap();
+        if log_ingest_buffers.insert(&log_name) {
+            self.start_flush_loop(
+                &log_name,
+                &log.commit_window,
+                Arc::clone(&log_ingest_buffers),
+            );
+        }
+        if !evicts_oldest(log, &received) {
+            let ingest_buffer = log_ingest_buffers.get(&log_name[..]).unwrap();
+            let protected_data = ingest_buffer.lock().unwrap();
+            let stored_bytes = protected_data.stored_bytes + protected_data.total_bytes;
+            let stored_objects = protected_data.stored_objects;
+            drop(protected_data);
+            if quota_reached(log, stored_bytes, stored_objects) {
+                let response = Response::builder()
+                    .status(StatusCode::INSUFFICIENT_STORAGE)
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(Body::from("Log is over its storage quota"))
+                    .unwrap();
+                return Either::B(futures::future::ok(Err(response)));
+            }
+        }
+        let partition = if ObjectNaming::for_log(log).has_partition() {
+            Some(partition.unwrap_or_else(|| DEFAULT_PARTITION.to_string()))
+        } else {
+            None
+        };
+        let stamp_prepend = log.stamp.as_ref().map(|s| s.as_str()) == Some(STAMP_PREPEND);
+        let base64_lines = log.encoding.as_ref().map(|s| s.as_str()) == Some(ENCODING_BASE64);
+        let payload = if base64_lines {
+            // the lines are wrapped as they were sent, the stamp along with them
+            let received = if stamp_prepend { Some(&received) } else { None };
+            encode_lines(entire_body, received)
+        } else {
+            // Read the body from the request
+            let payload: String = match String::from_utf8(entire_body.to_vec()) {
+                Ok(str) => str,
+                Err(_) => {
+                    return Either::B(futures::future::ok(Err(return_400(
+                        "Could not understand request",
+                    ))));
+                }
+            };
+            // join multi-line records before stamping so continuation lines keep their shape
+            let payload = match &log.multiline {
+                Some(rule) => match MultilineJoiner::new(rule) {
+                    Ok(joiner) => joiner.join(&payload),
+                    Err(e) => {
+                        error!("Ignoring multiline rule of {}: {}", requested_log, e);
+                        payload
+                    }
+                },
+                None => payload,
+            };
+            if stamp_prepend {
+                stamp_lines(&payload, &received)
+            } else {
+                payload
+            }
+        };
+        if let Some(report) = &log.report {
+            if base64_lines {
+                let text = String::from_utf8_lossy(entire_body);
+                record_ingested(&cfg, log, report, &text);
+            } else {
+                record_ingested(&cfg, log, report, &payload);
+            }
+        }
+        if !log.tee.is_empty() {
+            if base64_lines {
+                tee_lines(
+                    &requested_log,
+                    &log.tee,
+                    &String::from_utf8_lossy(entire_body),
+                );
+            } else {
+                tee_lines(&requested_log, &log.tee, &payload);
+            }
+        }
+        // clients watching the log get the lines before they're flushed
+        if base64_lines {
+            publish_lines(&log_name, &String::from_utf8_lossy(entire_body));
+        } else {
+            publish_lines(&log_name, &payload);
+        }
+        let mut ack = StoreAck {
+            lines: payload.lines().count(),
+            bytes: entire_body.len(),
+            buffered_bytes: 0,
+            buffer_flush_bytes: INGEST_BUFFER_FLUSH_BYTES,
+            objects: Vec::new(),
+        };
+        // if the commit window is 0s or a durable ack was requested, commit immediately
+        if log.commit_window == "0" || durable_ack {
+            let cfg = Arc::clone(&ingest_c.config);
+            let usage_cfg = Arc::clone(&ingest_c.config);
+            let usage_buffers = Arc::clone(&log_ingest_buffers);
+            let usage_log = requested_log.clone();
+            let usage_token = access_token.to_string();
+            let plen = payload.len() as i64;
+            // stamped objects record when their lines were received, `$received` prunes on it
+            let mut metadata = match log.stamp {
+                Some(_) => Some(received_metadata(&received, &received)),
+                None => None,
+            };
+            if let Some(trace) = trace {
+                metadata
+                    .get_or_insert_with(HashMap::new)
+                    .extend(trace.metadata());
+            }
+            let trace_context = trace.map(|trace| trace.log_context()).unwrap_or_default();
+            let sequence = log_ingest_buffers
+                .get(&requested_log[..])
+                .unwrap()
+                .lock()
+                .unwrap()
+                .next_sequence();
+            let sequence_buffers = Arc::clone(&log_ingest_buffers);
+            let sequence_log = requested_log.clone();
+            let response_body = write_to_datastore(
+                cfg,
+                &requested_log,
+                vec![payload],
+                partition.as_ref().map(|p| p.as_str()),
+                plen,
+                metadata,
+            )
+            .then(
+                move |res| -> Result<Result<(StoreAck, u64), Response<Body>>, GenericError> {
+                    match res {
+                        Ok(stored) => {
+                            if let Some(ingest_buffer) = sequence_buffers.get(&sequence_log[..]) {
+                                let mut protected_data = ingest_buffer
+                                    .lock()
+                                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                                protected_data.mark_flushed(&[sequence]);
+                                ack.buffered_bytes = protected_data.total_bytes;
+                            }
+                            record_stored(&usage_log, &usage_token, plen as u64);
+                            track_stored(usage_cfg, &usage_log, usage_buffers, plen as u64);
+                            ack.objects.push(stored);
+                            Ok(Ok((ack, sequence)))
+                        }
+                        Err(e) => {
+                            error!("{} {:?}", trace_context, e);
+                            let response = Response::builder()
+                                .status(StatusCode::INSUFFICIENT_STORAGE)
+                                .header(header::CONTENT_TYPE, "text/plain")
+                                .body(Body::from("fail"))
+                                .unwrap();
+                            Ok(Err(response))
+                        }
+                    }
+                },
+            );
+            Either::A(response_body)
+        } else {
+            // buffer the message
+            let ingest_buffer = log_ingest_buffers.get(&log_name[..]).unwrap();
+            let mut protected_data = ingest_buffer.lock().unwrap();
+            let total_bytes: u64;
+
+            record_stored(&log_name, access_token, payload.len() as u64);
+            let sequence = protected_data.next_sequence();
+            protected_data.buffered_sequences.push(sequence);
+            protected_data.total_bytes += payload.len() as u64;
+            protected_data.data.push((partition, payload));
+            if protected_data.first_received.is_none() {
+                protected_data.first_received = Some(received);
+            }
+            protected_data.last_received = Some(received);
+            total_bytes = protected_data.total_bytes.clone();
+
+            drop(protected_data);
+            ack.buffered_bytes = total_bytes;
+            // if we are above storage threshold, we will flush the data
+            if total_bytes > INGEST_BUFFER_FLUSH_BYTES {
+                info!("Buffer above 5MB, flushing.");
+                let cfg = Arc::clone(&flush_cfg);
+                let ingest_c = Ingest::new(cfg);
+                supervisor::spawn_isolated(
+                    format!("Flushing {}", &log_name),
+                    ingest_c.flush_buffer(&log_name, log_ingest_buffers),
+                );
+            }
+
+            Either::B(futures::future::ok(Ok((ack, sequence))))
+        }
+    }
+
+    /// Stores a body as its chunks arrive, in batches of whole lines of about
+    /// `STREAM_BATCH_BYTES`, so a payload never has to be held whole. The batches are stored one
+    /// after the other and acknowledged together, the ones stored before a batch is refused stay
+    /// stored and the rest of the body is discarded.
+    fn store_stream(
+        &self,
+        body: Body,
+        log_ingest_buffers: Arc<IngestBuffers>,
+        requested_log: String,
+        access_token: String,
+        partition: Option<String>,
+        durable_ack: bool,
+        trace: Option<RequestTrace>,
+    ) -> impl Future<Item = Response<Body>, Error = GenericError> + Send {
+        // records of multi-line logs are not cut across batches
+        let joiner = match self.config.read().unwrap().get_log(&requested_log) {
+            Some(Log {
+                multiline: Some(rule),
+                ..
+            }) => MultilineJoiner::new(rule).ok(),
+            _ => None,
+        };
+        let batcher = LineBatcher::new(STREAM_BATCH_BYTES, joiner);
+        let cfg = Arc::clone(&self.config);
+        let store = Arc::new(move |state: StreamState, batch: Vec<u8>| {
+            if state.refused.is_some() {
+                return Either::A(future::ok(state));
+            }
+            let stored = Ingest::new(Arc::clone(&cfg)).store_batch(
+                &batch,
+                Arc::clone(&log_ingest_buffers),
+                requested_log.clone(),
+                &access_token,
+                partition.clone(),
+                durable_ack,
+                trace.as_ref(),
+            );
+            Either::B(stored.map(move |stored| state.taking(stored)))
+        });
+        let store_chunk = Arc::clone(&store);
+        body.from_err::<GenericError>()
+            .fold(StreamState::new(batcher), move |mut state, chunk| {
+                let store = Arc::clone(&store_chunk);
+                match state.batcher.push(&chunk) {
+                    Some(batch) => Either::A(store(state, batch)),
+                    None => Either::B(future::ok(state)),
+                }
+            })
+            .and_then(move |mut state| {
+                // the lines after the last cut, an empty body is stored as an empty batch
+                match state.batcher.finish() {
+                    Some(batch) => Either::A(store(state, batch)),
+                    None if state.sequence.is_none() => Either::A(store(state, Vec::new())),
+                    None => Either::B(future::ok(state)),
+                }
+            })
+            .map(|state| match (state.refused, state.sequence) {
+                (Some(refused), _) => refused,
+                (None, Some(sequence)) => store_ack_response(&state.ack, sequence),
+                (None, None) => unreachable!("a stream stores at least one batch"),
+            })
+    }
+
+    /// Flushes the `IngestBuffer` of `log_name` every `commit_window`, unless the window is 0 and
+    /// lines are committed right away. An invalid window defaults to 5 seconds.
+    pub fn start_flush_loop(
+        &self,
+        log_name: &str,
+        commit_window: &str,
+        ingest_buffers: Arc<IngestBuffers>,
+    ) {
+        if commit_window == "0" {
+            return;
+        }
+        info!(
+            "Starting flusing loop for {} at {}",
+            log_name, commit_window
+        );
+        let window = Duration::from_secs(
+            Config::commit_window_to_seconds(commit_window)
+                .or_else(|| Some(5 as u64))
+                .unwrap(),
+        );
+        let cfg = Arc::clone(&self.config);
+        let log_name = log_name.to_string();
+        // The loop is restarted if it ever fails, else the log would stop being flushed
+        let context = format!("Flushing loop for {}", &log_name);
+        supervisor::spawn_supervised(context, move || {
+            let ingest_buffers = Arc::clone(&ingest_buffers);
+            let log_name = log_name.clone();
+            let ingest_c = Ingest::new(Arc::clone(&cfg));
+            Interval::new(Instant::now(), window)
+                .map_err(|e| error!("interval errored; err={:?}", e))
+                .for_each(move |_| ingest_c.flush_buffer(&log_name, Arc::clone(&ingest_buffers)))
+        });
+    }
+
     /// Flushes an `IngestBuffer` for a given `log_name` to MinIO
     pub fn flush_buffer(
         &self,
         log_name: &String,
-        ingest_buffers: Arc<HashMap<String, Mutex<IngestBuffer>>>,
+        ingest_buffers: Arc<IngestBuffers>,
     ) -> impl Future<Item = (), Error = ()> {
         let start = Instant::now();
         let ingest_buffer = ingest_buffers.get(&log_name[..]).unwrap();
-        let mut flushed_data: Vec<String> = Vec::new();
-        // lock the ingest_buffer and access it's protected data.s
-        let mut protected_data = ingest_buffer.lock().unwrap();
-        let mut total_bytes: u64 = 0;
+        let mut flushed_data: Vec<(Option<String>, String)> = Vec::new();
+        let mut flushed_sequences: Vec<u64> = Vec::new();
+        // lock the ingest_buffer and access it's protected data.s, a panic while it was held
+        // shouldn't stop the log from ever being flushed again
+        let mut protected_data = ingest_buffer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut first_received: Option<DateTime<Utc>> = None;
+        let mut last_received: Option<DateTime<Utc>> = None;
 
         if protected_data.total_bytes > 0 {
             // Swap memory and release lock
             mem::swap(&mut protected_data.data, &mut flushed_data);
-            total_bytes = protected_data.total_bytes;
+            mem::swap(
+                &mut protected_data.buffered_sequences,
+                &mut flushed_sequences,
+            );
             protected_data.total_bytes = 0;
+            first_received = protected_data.first_received.take();
+            last_received = protected_data.last_received.take();
+            protected_data.flushes_in_progress += 1;
+        } else {
+            // batches without lines have nothing to write
+            let empty = mem::replace(&mut protected_data.buffered_sequences, Vec::new());
+            protected_data.mark_flushed(&empty);
         }
         drop(protected_data);
         let data_len = flushed_data.len();
         if data_len > 0 {
-            // Write the data to object storage
-            let cfg = Arc::clone(&self.config);
-            let res = write_to_datastore(cfg, &log_name, flushed_data, total_bytes as i64)
-                .then(|we| {
-                    if let Err(e) = &we {
-                        error!("Problem flushing data out!! {:?}", e);
-                    };
-                    we
+            // Record the receive time range as metadata of the object if the log stamps its lines
+            let stamped = match self.config.read().unwrap().get_log(log_name) {
+                Some(log) => log.stamp.is_some(),
+                None => false,
+            };
+            let metadata = match (stamped, first_received, last_received) {
+                (true, Some(first), Some(last)) => Some(received_metadata(&first, &last)),
+                _ => None,
+            };
+            // Write the data to object storage, an object per partition
+            let mut partitions: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+            for (partition, payload) in flushed_data {
+                partitions
+                    .entry(partition)
+                    .or_insert_with(Vec::new)
+                    .push(payload);
+            }
+            let dedup_window = match self.config.read().unwrap().get_log(log_name) {
+                Some(log) => log
+                    .dedup_window
+                    .as_ref()
+                    .and_then(|window| Config::age_to_seconds(window)),
+                None => None,
+            };
+            let writes: Vec<_> = partitions
+                .into_iter()
+                .map(|(partition, payloads)| {
+                    // chunks identical to a recent one are already stored
+                    let digest = dedup_window.map(|_| chunk_digest(&partition, &payloads));
+                    if let (Some(digest), Some(window_secs)) = (&digest, dedup_window) {
+                        let duplicate = ingest_buffer
+                            .lock()
+                            .unwrap_or_else(|poisoned| poisoned.into_inner())
+                            .duplicate_chunk(digest, &Utc::now(), window_secs);
+                        if let Some(object) = duplicate {
+                            info!(
+                                "Skipping flush of {}, identical to {} on {}",
+                                &log_name, object.key, object.datastore
+                            );
+                            return Either::A(future::ok(true));
+                        }
+                    }
+                    let bytes: u64 = payloads.iter().map(|p| p.len() as u64).sum();
+                    let usage_cfg = Arc::clone(&self.config);
+                    let usage_log = log_name.clone();
+                    let usage_buffers = Arc::clone(&ingest_buffers);
+                    let chunk_log = log_name.clone();
+                    let chunk_buffers = Arc::clone(&ingest_buffers);
+                    Either::B(
+                        write_to_datastore(
+                            Arc::clone(&self.config),
+                            &log_name,
+                            payloads,
+                            partition.as_ref().map(|p| p.as_str()),
+                            bytes as i64,
+                            metadata.clone(),
+                        )
+                        .map(move |stored| {
+                            if let (Some(digest), Some(ingest_buffer)) =
+                                (digest, chunk_buffers.get(&chunk_log[..]))
+                            {
+                                ingest_buffer
+                                    .lock()
+                                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                    .record_chunk(digest, stored, Utc::now());
+                            }
+                            track_stored(usage_cfg, &usage_log, usage_buffers, bytes)
+                        })
+                        // a failed write doesn't stop the other partitions from being written
+                        .then(|we| {
+                            if let Err(e) = &we {
+                                error!("Problem flushing data out!! {:?}", e);
+                            };
+                            Ok::<_, ()>(we.is_ok())
+                        }),
+                    )
                 })
-                .map(|_| ())
-                .map_err(|_| ());
+                .collect();
+            let done_buffers = Arc::clone(&ingest_buffers);
+            let done_log = log_name.clone();
+            let res = future::join_all(writes).map(move |written| {
+                if let Some(ingest_buffer) = done_buffers.get(&done_log[..]) {
+                    let mut protected_data = ingest_buffer
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    protected_data.flushes_in_progress -= 1;
+                    // the batches of a failed write hold the checkpoint back
+                    if written.iter().all(|ok| *ok) {
+                        protected_data.mark_flushed(&flushed_sequences);
+                    }
+                }
+            });
             //TODO: Remove this line later on
             let duration = start.elapsed();
             info!(
@@ -190,4 +818,673 @@ impl Ingest {
             Either::B(futures::future::ok(()))
         }
     }
+
+    /// Flushes the `IngestBuffer` of `log_name` and waits for any flush of it already underway,
+    /// so everything acknowledged so far is on the datastores once it resolves.
+    pub fn flush_and_wait(
+        &self,
+        log_name: &String,
+        ingest_buffers: Arc<IngestBuffers>,
+    ) -> impl Future<Item = (), Error = ()> {
+        let log_name = log_name.clone();
+        self.flush_buffer(&log_name, Arc::clone(&ingest_buffers))
+            .and_then(move |_| {
+                future::loop_fn((), move |_| {
+                    let flushing = match ingest_buffers.get(&log_name[..]) {
+                        Some(ingest_buffer) => {
+                            ingest_buffer
+                                .lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                .flushes_in_progress
+                                > 0
+                        }
+                        None => false,
+                    };
+                    if !flushing {
+                        return Either::A(future::ok(Loop::Break(())));
+                    }
+                    let wait = Duration::from_millis(FLUSH_WAIT_POLL_MILLIS);
+                    Either::B(
+                        Delay::new(Instant::now() + wait)
+                            .map(|_| Loop::Continue(()))
+                            .map_err(|_| ()),
+                    )
+                })
+            })
+    }
+
+    /// Loads how much data each log already holds on its datastores so quotas can be enforced
+    pub fn load_usage(&self, ingest_buffers: Arc<IngestBuffers>) {
+        let read_cfg = self.config.read().unwrap();
+        for (log_name, log) in &read_cfg.log {
+            for ds_name in &log.all_datastores() {
+                let ds = match read_cfg.datastore.get(ds_name) {
+                    Some(ds) => ds,
+                    None => continue,
+                };
+                let ingest_buffers = Arc::clone(&ingest_buffers);
+                let log_name = log_name.clone();
+                let err_log_name = log_name.clone();
+                let task = list_msl_bucket_objects(&log_name, ds, &ObjectNaming::for_log(log))
+                    .map_err(move |e| {
+                        error!("Could not load storage usage of {}: {:?}", err_log_name, e)
+                    })
+                    .fold((0 as u64, 0 as u64), |(bytes, objects), obj| {
+                        future::ok::<_, ()>((bytes + obj.size, objects + 1))
+                    })
+                    .map(move |(bytes, objects)| {
+                        if let Some(ingest_buffer) = ingest_buffers.get(&log_name[..]) {
+                            let mut protected_data = ingest_buffer.lock().unwrap();
+                            protected_data.stored_bytes += bytes;
+                            protected_data.stored_objects += objects;
+                        }
+                    });
+                hyper::rt::spawn(task);
+            }
+        }
+    }
+}
+
+/// Acknowledgment of a payload stored on a log
+#[derive(Serialize)]
+struct StoreAck {
+    lines: usize,
+    bytes: usize,
+    // bytes buffered for the log once the payload was taken, and how many get them flushed
+    buffered_bytes: u64,
+    buffer_flush_bytes: u64,
+    // objects the lines were committed to, empty while they are buffered
+    objects: Vec<StoredObject>,
+}
+
+/// Acknowledgment of the batches of a streamed body so far, `sequence` is the one of the last
+/// batch stored and `refused` the response refusing a batch, which ends the stream
+struct StreamState {
+    batcher: LineBatcher,
+    ack: StoreAck,
+    sequence: Option<u64>,
+    refused: Option<Response<Body>>,
+}
+
+impl StreamState {
+    fn new(batcher: LineBatcher) -> StreamState {
+        StreamState {
+            batcher,
+            ack: StoreAck {
+                lines: 0,
+                bytes: 0,
+                buffered_bytes: 0,
+                buffer_flush_bytes: INGEST_BUFFER_FLUSH_BYTES,
+                objects: Vec::new(),
+            },
+            sequence: None,
+            refused: None,
+        }
+    }
+
+    /// Adds the outcome of storing a batch
+    fn taking(mut self, stored: Result<(StoreAck, u64), Response<Body>>) -> StreamState {
+        match stored {
+            Ok((ack, sequence)) => {
+                self.ack.lines += ack.lines;
+                self.ack.bytes += ack.bytes;
+                self.ack.buffered_bytes = ack.buffered_bytes;
+                self.ack.objects.extend(ack.objects);
+                self.sequence = Some(sequence);
+            }
+            Err(refused) => self.refused = Some(refused),
+        }
+        self
+    }
+}
+
+/// Cuts a body arriving in chunks into batches of whole lines, every batch but the last of at
+/// least `batch_bytes`. With a multiline rule batches are only cut before a line starting a
+/// record.
+pub struct LineBatcher {
+    pending: Vec<u8>,
+    batch_bytes: usize,
+    joiner: Option<MultilineJoiner>,
+}
+
+impl LineBatcher {
+    pub fn new(batch_bytes: usize, joiner: Option<MultilineJoiner>) -> LineBatcher {
+        LineBatcher {
+            pending: Vec::new(),
+            batch_bytes,
+            joiner,
+        }
+    }
+
+    /// Takes the next chunk of the body, returning a batch once enough whole lines arrived
+    pub fn push(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        self.pending.extend_from_slice(chunk);
+        if self.pending.len() < self.batch_bytes {
+            return None;
+        }
+        let cut = self.cut()?;
+        let rest = self.pending.split_off(cut);
+        Some(mem::replace(&mut self.pending, rest))
+    }
+
+    /// The bytes after the last batch, if any
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(mem::replace(&mut self.pending, Vec::new()))
+    }
+
+    /// Where the pending bytes can be cut, the latest line break followed by a line that doesn't
+    /// continue the record before it
+    fn cut(&self) -> Option<usize> {
+        let mut end = self.pending.len();
+        while let Some(line_break) = self.pending[..end].iter().rposition(|b| *b == b'\n') {
+            let joiner = match &self.joiner {
+                Some(joiner) => joiner,
+                None => return Some(line_break + 1),
+            };
+            // a line still arriving can't be told apart yet
+            let next_line = &self.pending[line_break + 1..end];
+            if end < self.pending.len() {
+                let next_line = String::from_utf8_lossy(next_line);
+                if next_line.is_empty() || !joiner.continues(&next_line) {
+                    return Some(line_break + 1);
+                }
+            }
+            end = line_break;
+        }
+        None
+    }
+}
+
+/// Builds the response acknowledging a payload, numbered with the sequence of its batch
+fn store_ack_response(ack: &StoreAck, sequence: u64) -> Response<Body> {
+    let output = serde_json::to_string(ack).unwrap();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .header(HEADER_SEQUENCE, sequence.to_string())
+        .body(Body::from(output))
+        .unwrap()
+}
+
+/// First sequence of the batches of a log, the microseconds since the epoch, so a restarted server
+/// doesn't hand out sequences it already did
+fn sequence_seed() -> u64 {
+    let now = Utc::now();
+    now.timestamp() as u64 * 1_000_000 + u64::from(now.timestamp_subsec_micros())
+}
+
+/// Whether storing more data on the log would go over any of its quotas
+fn quota_reached(log: &Log, stored_bytes: u64, stored_objects: u64) -> bool {
+    log.max_bytes.map_or(false, |max| stored_bytes >= max)
+        || log.max_objects.map_or(false, |max| stored_objects >= max)
+}
+
+/// Whether the oldest objects of the log are deleted once it's over quota, which a legal hold
+/// prevents
+pub fn evicts_oldest(log: &Log, now: &DateTime<Utc>) -> bool {
+    log.quota_policy.as_ref().map(|s| s.as_str()) == Some(QUOTA_DELETE_OLDEST)
+        && log.held_until(now).is_none()
+}
+
+/// Whether the log holds more data than any of its quotas allow
+fn quota_exceeded(log: &Log, stored_bytes: u64, stored_objects: u64) -> bool {
+    log.max_bytes.map_or(false, |max| stored_bytes > max)
+        || log.max_objects.map_or(false, |max| stored_objects > max)
+}
+
+/// Records a new object written for a log and evicts its oldest objects if the log is over
+/// quota and configured to do so.
+fn track_stored(
+    cfg: Arc<RwLock<Config>>,
+    log_name: &String,
+    ingest_buffers: Arc<IngestBuffers>,
+    bytes: u64,
+) {
+    let (stored_bytes, stored_objects) = match ingest_buffers.get(&log_name[..]) {
+        Some(ingest_buffer) => {
+            let mut protected_data = ingest_buffer.lock().unwrap();
+            protected_data.stored_bytes += bytes;
+            protected_data.stored_objects += 1;
+            (protected_data.stored_bytes, protected_data.stored_objects)
+        }
+        None => return,
+    };
+    let read_cfg = cfg.read().unwrap();
+    let log = match read_cfg.get_log(log_name) {
+        Some(log) => log,
+        None => return,
+    };
+    if evicts_oldest(log, &Utc::now()) && quota_exceeded(log, stored_bytes, stored_objects) {
+        info!("{} is over quota, deleting oldest objects.", log_name);
+        let log = log.clone();
+        let datastores: Vec<DataStore> = log
+            .all_datastores()
+            .iter()
+            .filter_map(|ds_name| read_cfg.datastore.get(ds_name).cloned())
+            .collect();
+        hyper::rt::spawn(
+            delete_oldest_objects(log, log_name.clone(), datastores, ingest_buffers).map(|_| ()),
+        );
+    }
+}
+
+/// Deletes the oldest objects of a log across all of its datastores until the log is back
+/// within its quotas, returning the deleted objects along with their datastore. Only one
+/// eviction runs per log at a time, nothing is deleted while another one is running.
+pub fn delete_oldest_objects(
+    log: Log,
+    log_name: String,
+    datastores: Vec<DataStore>,
+    ingest_buffers: Arc<IngestBuffers>,
+) -> impl Future<Item = Vec<(DataStore, LogObject)>, Error = ()> {
+    let ingest_buffer = match ingest_buffers.get(&log_name[..]) {
+        Some(ingest_buffer) => ingest_buffer,
+        None => return Either::B(future::ok(Vec::new())),
+    };
+    {
+        let mut protected_data = ingest_buffer.lock().unwrap();
+        if protected_data.evicting {
+            info!("Oldest objects of {} are being deleted already.", log_name);
+            return Either::B(future::ok(Vec::new()));
+        }
+        protected_data.evicting = true;
+    }
+    let naming = ObjectNaming::for_log(&log);
+    let listings = datastores.into_iter().map(|ds| {
+        let list_ds = ds.clone();
+        list_msl_bucket_objects(&log_name, &list_ds, &naming)
+            .map(move |obj| (ds.clone(), obj))
+            .collect()
+    });
+    let evict = future::join_all(listings)
+        .map_err(|e| error!("Could not list objects to enforce quota: {:?}", e))
+        .and_then(move |listed| {
+            let mut objects: Vec<(DataStore, LogObject)> = listed.into_iter().flatten().collect();
+            objects.sort_by(|a, b| a.1.last_modified.cmp(&b.1.last_modified));
+
+            let (mut stored_bytes, mut stored_objects) = match ingest_buffers.get(&log_name[..]) {
+                Some(ingest_buffer) => {
+                    let protected_data = ingest_buffer.lock().unwrap();
+                    (protected_data.stored_bytes, protected_data.stored_objects)
+                }
+                None => (0, 0),
+            };
+            let mut evicted: Vec<(DataStore, LogObject)> = Vec::new();
+            for (ds, obj) in objects {
+                if !quota_exceeded(&log, stored_bytes, stored_objects) {
+                    break;
+                }
+                stored_bytes = stored_bytes.saturating_sub(obj.size);
+                stored_objects = stored_objects.saturating_sub(1);
+                evicted.push((ds, obj));
+            }
+
+            stream::iter_ok::<_, ()>(evicted)
+                .and_then(|(ds, obj)| {
+                    let bloom_ds = ds.clone();
+                    delete_object(&ds, obj.key.clone())
+                        .map_err(|e| error!("Could not delete object to enforce quota: {:?}", e))
+                        .and_then(move |_| {
+                            // the bloom filter may not exist, deleting it is best effort
+                            delete_object(&bloom_ds, bloom_key(&obj.key))
+                                .then(move |_| Ok((bloom_ds, obj)))
+                        })
+                })
+                .fold(Vec::new(), move |mut deleted, (ds, obj)| {
+                    if let Some(ingest_buffer) = ingest_buffers.get(&log_name[..]) {
+                        let mut protected_data = ingest_buffer.lock().unwrap();
+                        protected_data.stored_bytes =
+                            protected_data.stored_bytes.saturating_sub(obj.size);
+                        protected_data.stored_objects =
+                            protected_data.stored_objects.saturating_sub(1);
+                    }
+                    deleted.push((ds, obj));
+                    Ok::<_, ()>(deleted)
+                })
+        })
+        .then(move |res| {
+            ingest_buffer.lock().unwrap().evicting = false;
+            res
+        });
+    Either::A(evict)
+}
+
+/// Prepends the server side receive time to every line of the payload
+fn stamp_lines(payload: &str, received: &DateTime<Utc>) -> String {
+    let stamp = received.to_rfc3339_opts(SecondsFormat::Millis, true);
+    payload
+        .split('\n')
+        .map(|line| {
+            if line.is_empty() {
+                line.to_string()
+            } else {
+                format!("{} {}", stamp, line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Receive time a line was prefixed with by `stamp_lines`, `None` for a line without a stamp
+pub fn received_at(line: &str) -> Option<DateTime<Utc>> {
+    let stamp = line.splitn(2, ' ').next()?;
+    DateTime::parse_from_rfc3339(stamp)
+        .ok()
+        .map(|received| received.with_timezone(&Utc))
+}
+
+/// Wraps every line of the body in base64, so lines with arbitrary bytes are stored as text.
+/// Lines are prefixed with the receive time before being wrapped if `received` is set.
+fn encode_lines(body: &[u8], received: Option<&DateTime<Utc>>) -> String {
+    let stamp = received.map(|r| r.to_rfc3339_opts(SecondsFormat::Millis, true) + " ");
+    body.split(|b| *b == b'\n')
+        .map(|line| {
+            if line.is_empty() {
+                return String::new();
+            }
+            match &stamp {
+                Some(stamp) => base64::encode(&[stamp.as_bytes(), line].concat()),
+                None => base64::encode(line),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Hex SHA-256 identifying a flushed chunk, its payloads along with the partition they go to
+fn chunk_digest(partition: &Option<String>, payloads: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(partition) = partition {
+        hasher.input(partition.as_bytes());
+    }
+    hasher.input(b"\0");
+    for payload in payloads {
+        hasher.input(payload.as_bytes());
+    }
+    hex(&hasher.result())
+}
+
+/// Builds the object metadata recording the receive time range of the data in an object
+fn received_metadata(first: &DateTime<Utc>, last: &DateTime<Utc>) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        RECEIVED_FIRST_METADATA.to_string(),
+        first.to_rfc3339_opts(SecondsFormat::Millis, true),
+    );
+    metadata.insert(
+        RECEIVED_LAST_METADATA.to_string(),
+        last.to_rfc3339_opts(SecondsFormat::Millis, true),
+    );
+    metadata
+}
+
+#[cfg(test)]
+mod ingest_tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::config::MultilineRule;
+
+    #[test]
+    fn stamp_multiple_lines() {
+        let received = Utc.ymd(2019, 7, 1).and_hms_milli(10, 30, 0, 5);
+        let stamped = stamp_lines("first line\nsecond line\n", &received);
+        assert_eq!(
+            stamped,
+            "2019-07-01T10:30:00.005Z first line\n2019-07-01T10:30:00.005Z second line\n"
+        );
+        assert_eq!(received_at(stamped.lines().next().unwrap()), Some(received));
+        assert_eq!(received_at("first line"), None);
+    }
+
+    #[test]
+    fn encode_lines_as_base64() {
+        let body = b"plain line\n\xff\xfe binary\n";
+        assert_eq!(encode_lines(body, None), "cGxhaW4gbGluZQ==\n//4gYmluYXJ5\n");
+
+        let received = Utc.ymd(2019, 7, 1).and_hms_milli(10, 30, 0, 5);
+        let encoded = encode_lines(b"line", Some(&received));
+        assert_eq!(
+            base64::decode(&encoded).unwrap(),
+            b"2019-07-01T10:30:00.005Z line".to_vec()
+        );
+    }
+
+    #[test]
+    fn buffered_lines_split_payloads() {
+        let mut buffer = IngestBuffer::new();
+        buffer
+            .data
+            .push((None, "first line\nsecond line\n".to_string()));
+        buffer.data.push((None, "third line".to_string()));
+        assert_eq!(
+            buffer.buffered_lines(None),
+            vec!["first line", "second line", "third line"]
+        );
+    }
+
+    #[test]
+    fn buffered_lines_of_partition() {
+        let mut buffer = IngestBuffer::new();
+        buffer
+            .data
+            .push((Some("acme".to_string()), "acme line\n".to_string()));
+        buffer
+            .data
+            .push((Some("globex".to_string()), "globex line\n".to_string()));
+        assert_eq!(buffer.buffered_lines(Some("acme")), vec!["acme line"]);
+        assert_eq!(buffer.buffered_lines(None).len(), 2);
+    }
+
+    #[test]
+    fn checkpoint_waits_for_every_earlier_batch() {
+        let mut buffer = IngestBuffer::new();
+        let first = buffer.next_sequence();
+        let second = buffer.next_sequence();
+        let third = buffer.next_sequence();
+        assert!(first < second && second < third);
+        assert_eq!(buffer.checkpoint().flushed, first - 1);
+
+        // written out of order, the first batch still holds the checkpoint
+        buffer.mark_flushed(&[second, third]);
+        assert_eq!(
+            buffer.checkpoint(),
+            Checkpoint {
+                accepted: third,
+                flushed: first - 1,
+                pending: 1,
+            }
+        );
+        buffer.mark_flushed(&[first]);
+        assert_eq!(buffer.checkpoint().flushed, third);
+        assert_eq!(buffer.checkpoint().pending, 0);
+    }
+
+    #[test]
+    fn identical_chunks_within_the_window() {
+        let payloads = vec!["first line\n".to_string(), "second line\n".to_string()];
+        let digest = chunk_digest(&None, &payloads);
+        assert_eq!(digest.len(), 64);
+        assert_eq!(digest, chunk_digest(&None, &payloads));
+        assert_ne!(digest, chunk_digest(&Some("eu".to_string()), &payloads));
+        assert_ne!(digest, chunk_digest(&None, &payloads[..1]));
+
+        let mut buffer = IngestBuffer::new();
+        let written_at = Utc.ymd(2019, 7, 1).and_hms(10, 30, 0);
+        assert!(buffer.duplicate_chunk(&digest, &written_at, 600).is_none());
+        buffer.record_chunk(
+            digest.clone(),
+            StoredObject {
+                datastore: "ds1".to_string(),
+                key: "minsql/mylog/2019/7/1/10/a.log".to_string(),
+            },
+            written_at,
+        );
+        let later = written_at + chrono::Duration::seconds(599);
+        assert_eq!(
+            buffer.duplicate_chunk(&digest, &later, 600).unwrap().key,
+            "minsql/mylog/2019/7/1/10/a.log"
+        );
+        // past the window the chunk is forgotten
+        let later = written_at + chrono::Duration::seconds(600);
+        assert!(buffer.duplicate_chunk(&digest, &later, 600).is_none());
+        assert!(buffer.recent_chunks.is_empty());
+    }
+
+    #[test]
+    fn batches_of_whole_lines() {
+        let mut batcher = LineBatcher::new(8, None);
+        assert_eq!(batcher.push(b"first li"), None);
+        assert_eq!(batcher.push(b"ne\nsecond"), Some(b"first line\n".to_vec()));
+        assert_eq!(batcher.push(b" line"), None);
+        assert_eq!(batcher.push(b"\nthird"), Some(b"second line\n".to_vec()));
+        assert_eq!(batcher.finish(), Some(b"third".to_vec()));
+        assert_eq!(batcher.finish(), None);
+    }
+
+    #[test]
+    fn batches_keep_multiline_records() {
+        let joiner = MultilineJoiner::new(&MultilineRule {
+            continuation: None,
+            indented: true,
+        })
+        .unwrap();
+        let mut batcher = LineBatcher::new(8, Some(joiner));
+        // the second line may still be continued by the one arriving
+        assert_eq!(batcher.push(b"Error: boom\n  at main\n"), None);
+        assert_eq!(
+            batcher.push(b"  at run\nInfo: ok\nInfo"),
+            Some(b"Error: boom\n  at main\n  at run\n".to_vec())
+        );
+        assert_eq!(batcher.finish(), Some(b"Info: ok\nInfo".to_vec()));
+    }
+
+    #[test]
+    fn stream_acks_add_up() {
+        let state = StreamState::new(LineBatcher::new(8, None));
+        let batch = |lines: usize, objects: Vec<StoredObject>| StoreAck {
+            lines,
+            bytes: lines * 10,
+            buffered_bytes: lines as u64 * 10,
+            buffer_flush_bytes: INGEST_BUFFER_FLUSH_BYTES,
+            objects,
+        };
+        let object = StoredObject {
+            datastore: "ds1".to_string(),
+            key: "minsql/mylog/2019/7/1/10/a.log".to_string(),
+        };
+        let state = state
+            .taking(Ok((batch(2, vec![object.clone()]), 7)))
+            .taking(Ok((batch(3, vec![object]), 8)));
+        assert_eq!(state.ack.lines, 5);
+        assert_eq!(state.ack.bytes, 50);
+        assert_eq!(state.ack.buffered_bytes, 30);
+        assert_eq!(state.ack.objects.len(), 2);
+        assert_eq!(state.sequence, Some(8));
+        let state = state.taking(Err(return_400("refused")));
+        assert!(state.refused.is_some());
+    }
+
+    #[test]
+    fn store_ack_lists_objects() {
+        let ack = StoreAck {
+            lines: 2,
+            bytes: 64,
+            buffered_bytes: 0,
+            buffer_flush_bytes: INGEST_BUFFER_FLUSH_BYTES,
+            objects: vec![StoredObject {
+                datastore: "ds1".to_string(),
+                key: "minsql/mylog/2019/7/1/10/a.log".to_string(),
+            }],
+        };
+        let response = store_ack_response(&ack, 42);
+        assert_eq!(response.headers()[HEADER_SEQUENCE], "42");
+        assert_eq!(
+            serde_json::to_string(&ack).unwrap(),
+            r#"{"lines":2,"bytes":64,"buffered_bytes":0,"buffer_flush_bytes":5242880,"objects":[{"datastore":"ds1","key":"minsql/mylog/2019/7/1/10/a.log"}]}"#
+        );
+    }
+
+    #[test]
+    fn quota_reached_and_exceeded() {
+        let log = Log {
+            max_bytes: Some(100),
+            max_objects: Some(10),
+            ..Default::default()
+        };
+        assert!(!quota_reached(&log, 99, 9));
+        assert!(quota_reached(&log, 100, 9));
+        assert!(quota_reached(&log, 50, 10));
+        assert!(!quota_exceeded(&log, 100, 10));
+        assert!(quota_exceeded(&log, 101, 10));
+        assert!(quota_exceeded(&log, 0, 11));
+        // without quotas a log is never over quota
+        assert!(!quota_reached(
+            &Log::default(),
+            u64::max_value(),
+            u64::max_value()
+        ));
+    }
+
+    #[test]
+    fn legal_hold_stops_eviction() {
+        let mut log = Log {
+            quota_policy: Some(QUOTA_DELETE_OLDEST.to_string()),
+            locked_until: Some("2019-08-01T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+        let before = Utc.ymd(2019, 7, 31).and_hms(12, 0, 0);
+        let after = Utc.ymd(2019, 8, 1).and_hms(0, 0, 1);
+        assert!(!evicts_oldest(&log, &before));
+        assert!(evicts_oldest(&log, &after));
+        log.locked_until = Some("not a time".to_string());
+        assert!(evicts_oldest(&log, &before));
+        log.quota_policy = None;
+        assert!(!evicts_oldest(&log, &after));
+    }
+
+    #[test]
+    fn one_eviction_per_log() {
+        let ingest_buffers = Arc::new(IngestBuffers::new());
+        ingest_buffers.insert("mylog");
+        let evicting = || {
+            ingest_buffers
+                .get("mylog")
+                .unwrap()
+                .lock()
+                .unwrap()
+                .evicting
+        };
+        let evict = || {
+            delete_oldest_objects(
+                Log::default(),
+                "mylog".to_string(),
+                Vec::new(),
+                Arc::clone(&ingest_buffers),
+            )
+            .wait()
+        };
+
+        ingest_buffers
+            .get("mylog")
+            .unwrap()
+            .lock()
+            .unwrap()
+            .evicting = true;
+        assert_eq!(evict().unwrap().len(), 0);
+        // the running eviction still holds the flag
+        assert!(evicting());
+
+        ingest_buffers
+            .get("mylog")
+            .unwrap()
+            .lock()
+            .unwrap()
+            .evicting = false;
+        assert_eq!(evict().unwrap().len(), 0);
+        assert!(!evicting());
+    }
 }
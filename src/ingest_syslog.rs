@@ -0,0 +1,686 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use bytes::BytesMut;
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Utc};
+use futures::future::Either;
+use futures::{future, Future, Stream};
+use log::{error, info, warn};
+use serde_derive::Serialize;
+use tokio::net::{TcpListener, UdpFramed, UdpSocket};
+use tokio_codec::{Decoder, FramedRead};
+
+use crate::config::Config;
+use crate::constants::{SYSLOG_DEFAULT_PRIORITY, SYSLOG_MAX_FRAME_BYTES, SYSLOG_USAGE_TOKEN};
+use crate::ingest::{Ingest, IngestBuffers};
+use crate::supervisor;
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// A syslog message, stored as one line of JSON. Fields the sender left out, or sent as `-`, are
+/// left out as well.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SyslogMessage {
+    pub facility: &'static str,
+    pub severity: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub procid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msgid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub structured_data: Option<String>,
+    pub message: String,
+}
+
+impl SyslogMessage {
+    fn new(priority: u8, message: &str) -> SyslogMessage {
+        SyslogMessage {
+            facility: FACILITIES[(priority / 8) as usize],
+            severity: SEVERITIES[(priority % 8) as usize],
+            timestamp: None,
+            hostname: None,
+            app_name: None,
+            procid: None,
+            msgid: None,
+            structured_data: None,
+            message: message.trim_start_matches('\u{feff}').to_string(),
+        }
+    }
+}
+
+/// Parses a syslog frame, either RFC 5424 or RFC 3164. Senders are loose with RFC 3164, so parts
+/// that can't be read are kept on the message rather than refusing it, and frames without a
+/// priority get `user.notice`. RFC 3164 timestamps have no year, they are taken to be of the last
+/// year before `received`.
+pub fn parse_syslog(frame: &str, received: &DateTime<Utc>) -> SyslogMessage {
+    let (priority, rest) = match parse_priority(frame) {
+        Some((priority, rest)) => (priority, rest),
+        None => (SYSLOG_DEFAULT_PRIORITY, frame),
+    };
+    if rest.starts_with("1 ") {
+        if let Some(message) = parse_rfc5424(priority, &rest[2..]) {
+            return message;
+        }
+    }
+    parse_rfc3164(priority, rest, received)
+}
+
+/// The `<PRI>` a frame starts with, and what follows it
+fn parse_priority(frame: &str) -> Option<(u8, &str)> {
+    if !frame.starts_with('<') {
+        return None;
+    }
+    let end = frame.bytes().take(5).position(|b| b == b'>')?;
+    let digits = &frame[1..end];
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match digits.parse::<u8>() {
+        Ok(priority) if priority < 192 => Some((priority, &frame[end + 1..])),
+        _ => None,
+    }
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, after the version
+fn parse_rfc5424(priority: u8, header: &str) -> Option<SyslogMessage> {
+    let mut parts = header.splitn(6, ' ');
+    let mut fields = Vec::new();
+    for _ in 0..5 {
+        let field = parts.next()?;
+        if field.is_empty() {
+            return None;
+        }
+        fields.push(if field == "-" {
+            None
+        } else {
+            Some(field.to_string())
+        });
+    }
+    if let Some(ref timestamp) = fields[0] {
+        DateTime::parse_from_rfc3339(timestamp).ok()?;
+    }
+    let (structured_data, text) = split_structured_data(parts.next()?)?;
+    let mut message = SyslogMessage::new(priority, text);
+    message.msgid = fields.pop().unwrap();
+    message.procid = fields.pop().unwrap();
+    message.app_name = fields.pop().unwrap();
+    message.hostname = fields.pop().unwrap();
+    message.timestamp = fields.pop().unwrap();
+    message.structured_data = structured_data;
+    Some(message)
+}
+
+/// Splits the structured data elements, ie: `[exampleSDID@32473 iut="3"]`, from the message
+/// after them. Values may hold escaped `"`, `\` and `]`.
+fn split_structured_data(rest: &str) -> Option<(Option<String>, &str)> {
+    let end = if rest.starts_with('-') {
+        1
+    } else {
+        let mut end = 0;
+        let bytes = rest.as_bytes();
+        while end < bytes.len() && bytes[end] == b'[' {
+            let mut quoted = false;
+            let mut escaped = false;
+            let mut closed = false;
+            end += 1;
+            while end < bytes.len() {
+                let b = bytes[end];
+                end += 1;
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    quoted = !quoted;
+                } else if b == b']' && !quoted {
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                return None;
+            }
+        }
+        end
+    };
+    if end == 0 {
+        return None;
+    }
+    let text = match &rest[end..] {
+        "" => "",
+        text if text.starts_with(' ') => &text[1..],
+        _ => return None,
+    };
+    let structured_data = match &rest[..end] {
+        "-" => None,
+        elements => Some(elements.to_string()),
+    };
+    Some((structured_data, text))
+}
+
+/// `Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG`, the hostname is often left out by local senders
+fn parse_rfc3164(priority: u8, rest: &str, received: &DateTime<Utc>) -> SyslogMessage {
+    let timestamp = match (rest.get(..15), rest.get(15..16)) {
+        (Some(stamp), Some(" ")) => rfc3164_timestamp(stamp, received),
+        _ => None,
+    };
+    let timestamp = match timestamp {
+        Some(timestamp) => timestamp,
+        None => return SyslogMessage::new(priority, rest),
+    };
+    let mut content = &rest[16..];
+    let mut hostname = None;
+    if let Some(space) = content.find(' ') {
+        let token = &content[..space];
+        if !token.is_empty() && !token.ends_with(':') && !token.contains('[') {
+            hostname = Some(token.to_string());
+            content = &content[space + 1..];
+        }
+    }
+    let (app_name, procid, text) = match split_tag(content) {
+        Some((app_name, procid, text)) => (Some(app_name), procid, text),
+        None => (None, None, content),
+    };
+    let mut message = SyslogMessage::new(priority, text);
+    message.timestamp = Some(timestamp.to_rfc3339());
+    message.hostname = hostname;
+    message.app_name = app_name;
+    message.procid = procid;
+    message
+}
+
+fn rfc3164_timestamp(stamp: &str, received: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let at_year = |year: i32| {
+        NaiveDateTime::parse_from_str(&format!("{} {}", year, stamp), "%Y %b %e %H:%M:%S")
+            .ok()
+            .map(|naive| DateTime::<Utc>::from_utc(naive, Utc))
+    };
+    let timestamp = at_year(received.year())?;
+    // a message sent on the last day of the year may be received on the first of the next
+    if timestamp > *received + Duration::days(1) {
+        return at_year(received.year() - 1);
+    }
+    Some(timestamp)
+}
+
+/// Splits the `TAG[PID]: ` of the content from the text after it
+fn split_tag(content: &str) -> Option<(String, Option<String>, &str)> {
+    let end = content.find(|c: char| c == ':' || c == '[' || c == ' ')?;
+    let app_name = &content[..end];
+    if app_name.is_empty() {
+        return None;
+    }
+    let mut rest = &content[end..];
+    let mut procid = None;
+    if rest.starts_with('[') {
+        let close = rest.find(']')?;
+        procid = Some(rest[1..close].to_string());
+        rest = &rest[close + 1..];
+    }
+    if !rest.starts_with(':') {
+        return None;
+    }
+    let text = if rest.starts_with(": ") {
+        &rest[2..]
+    } else {
+        &rest[1..]
+    };
+    Some((app_name.to_string(), procid, text))
+}
+
+/// Frames of a syslog TCP connection, either octet counted, `<length> <frame>`, or ended by a
+/// newline as RFC 6587 describes. Each decode takes every whole frame buffered so they are
+/// stored together.
+#[derive(Default)]
+pub struct SyslogFrames {}
+
+impl SyslogFrames {
+    fn next_frame(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        loop {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            let frame = if buf[0].is_ascii_digit() {
+                let space = match buf.iter().take(7).position(|b| *b == b' ') {
+                    Some(space) => space,
+                    None if buf.len() < 7 => return Ok(None),
+                    None => return Err(invalid_frame("Invalid octet count")),
+                };
+                let len = String::from_utf8_lossy(&buf[..space])
+                    .parse::<usize>()
+                    .map_err(|_| invalid_frame("Invalid octet count"))?;
+                if len > SYSLOG_MAX_FRAME_BYTES {
+                    return Err(invalid_frame("Frame too long"));
+                }
+                if buf.len() < space + 1 + len {
+                    return Ok(None);
+                }
+                let frame = buf.split_to(space + 1 + len);
+                String::from_utf8_lossy(&frame[space + 1..]).into_owned()
+            } else {
+                match buf.iter().position(|b| *b == b'\n') {
+                    Some(newline) => {
+                        let frame = buf.split_to(newline + 1);
+                        String::from_utf8_lossy(&frame[..newline]).into_owned()
+                    }
+                    None if buf.len() > SYSLOG_MAX_FRAME_BYTES => {
+                        return Err(invalid_frame("Frame too long"))
+                    }
+                    None => return Ok(None),
+                }
+            };
+            let frame = frame.trim_end_matches(|c: char| c == '\n' || c == '\r' || c == '\0');
+            // senders may follow octet counted frames with a newline
+            if !frame.is_empty() {
+                return Ok(Some(frame.to_string()));
+            }
+        }
+    }
+}
+
+impl Decoder for SyslogFrames {
+    type Item = Vec<String>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<String>>, io::Error> {
+        let mut frames = Vec::new();
+        while let Some(frame) = self.next_frame(buf)? {
+            frames.push(frame);
+        }
+        Ok(if frames.is_empty() {
+            None
+        } else {
+            Some(frames)
+        })
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<String>>, io::Error> {
+        let mut frames = self.decode(buf)?.unwrap_or_default();
+        // the last newline ended frame may come without its newline, an octet counted frame cut
+        // short is dropped
+        if !buf.is_empty() && !buf[0].is_ascii_digit() {
+            let frame = String::from_utf8_lossy(&buf[..]).into_owned();
+            frames.push(
+                frame
+                    .trim_end_matches(|c: char| c == '\r' || c == '\0')
+                    .to_string(),
+            );
+        }
+        buf.clear();
+        Ok(if frames.is_empty() {
+            None
+        } else {
+            Some(frames)
+        })
+    }
+}
+
+fn invalid_frame(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Syslog UDP datagrams, a message each
+pub struct SyslogDatagrams {}
+
+impl Decoder for SyslogDatagrams {
+    type Item = Vec<String>;
+    type Error = io::Error;
+
+    // never `None` nor an error, either would end the stream of datagrams
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<String>>, io::Error> {
+        let datagram = buf.take();
+        let frame = String::from_utf8_lossy(&datagram[..]);
+        let frame = frame.trim_end_matches(|c: char| c == '\n' || c == '\r' || c == '\0');
+        if frame.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        Ok(Some(vec![frame.to_string()]))
+    }
+}
+
+/// Starts the syslog listeners configured, storing the messages they receive on their log
+/// through the same buffers and flush loops as the lines stored over HTTP
+pub fn start_syslog_listeners(cfg: Arc<RwLock<Config>>, ingest_buffers: Arc<IngestBuffers>) {
+    let listener = match cfg.read().unwrap().server.syslog.clone() {
+        Some(listener) => listener,
+        None => return,
+    };
+    if let Some(address) = listener.tcp_address {
+        let cfg = Arc::clone(&cfg);
+        let ingest_buffers = Arc::clone(&ingest_buffers);
+        let log_name = listener.log.clone();
+        let context = format!("Syslog TCP listener on {}", &address);
+        supervisor::spawn_supervised(context, move || {
+            let cfg = Arc::clone(&cfg);
+            let ingest_buffers = Arc::clone(&ingest_buffers);
+            let log_name = log_name.clone();
+            let socket = match address
+                .parse::<SocketAddr>()
+                .map_err(|e| e.to_string())
+                .and_then(|addr| TcpListener::bind(&addr).map_err(|e| e.to_string()))
+            {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("Could not listen for syslog on tcp://{}: {}", address, e);
+                    return Either::A(future::err(()));
+                }
+            };
+            info!("Listening for syslog on tcp://{}", address);
+            Either::B(
+                socket
+                    .incoming()
+                    .map_err(|e| error!("Could not accept a syslog connection: {}", e))
+                    .for_each(move |stream| {
+                        let peer = stream
+                            .peer_addr()
+                            .map(|addr| addr.to_string())
+                            .unwrap_or_default();
+                        let cfg = Arc::clone(&cfg);
+                        let ingest_buffers = Arc::clone(&ingest_buffers);
+                        let log_name = log_name.clone();
+                        let closed_peer = peer.clone();
+                        let connection = FramedRead::new(stream, SyslogFrames::default())
+                            .map_err(move |e| {
+                                warn!("Closing the syslog connection of {}: {}", closed_peer, e)
+                            })
+                            .for_each(move |frames| {
+                                store_frames(
+                                    Arc::clone(&cfg),
+                                    Arc::clone(&ingest_buffers),
+                                    &log_name,
+                                    frames,
+                                )
+                            });
+                        supervisor::spawn_isolated(
+                            format!("Syslog connection of {}", peer),
+                            connection,
+                        );
+                        Ok(())
+                    }),
+            )
+        });
+    }
+    if let Some(address) = listener.udp_address {
+        let log_name = listener.log;
+        let context = format!("Syslog UDP listener on {}", &address);
+        supervisor::spawn_supervised(context, move || {
+            let cfg = Arc::clone(&cfg);
+            let ingest_buffers = Arc::clone(&ingest_buffers);
+            let log_name = log_name.clone();
+            let socket = match address
+                .parse::<SocketAddr>()
+                .map_err(|e| e.to_string())
+                .and_then(|addr| UdpSocket::bind(&addr).map_err(|e| e.to_string()))
+            {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("Could not listen for syslog on udp://{}: {}", address, e);
+                    return Either::A(future::err(()));
+                }
+            };
+            info!("Listening for syslog on udp://{}", address);
+            Either::B(
+                UdpFramed::new(socket, SyslogDatagrams {})
+                    .map_err(|e| error!("Could not receive a syslog datagram: {}", e))
+                    .for_each(move |(frames, _)| {
+                        store_frames(
+                            Arc::clone(&cfg),
+                            Arc::clone(&ingest_buffers),
+                            &log_name,
+                            frames,
+                        )
+                    }),
+            )
+        });
+    }
+}
+
+/// Stores syslog frames on `log_name` as lines of JSON. Messages for a log that doesn't exist,
+/// or that refuses them, are dropped since syslog has no way to tell the sender.
+fn store_frames(
+    cfg: Arc<RwLock<Config>>,
+    ingest_buffers: Arc<IngestBuffers>,
+    log_name: &str,
+    frames: Vec<String>,
+) -> impl Future<Item = (), Error = ()> {
+    if frames.is_empty() {
+        return Either::A(future::ok(()));
+    }
+    let resolved = cfg.read().unwrap().resolve_log_name(log_name);
+    let log_name = match resolved {
+        Some(log_name) => log_name,
+        None => {
+            warn!(
+                "Dropping {} syslog messages, there is no log {}",
+                frames.len(),
+                log_name
+            );
+            return Either::A(future::ok(()));
+        }
+    };
+    let received = Utc::now();
+    let mut body = String::new();
+    for frame in &frames {
+        body.push_str(&serde_json::to_string(&parse_syslog(frame, &received)).unwrap());
+        body.push('\n');
+    }
+    let count = frames.len();
+    let refused_log = log_name.clone();
+    Either::B(
+        Ingest::new(cfg)
+            .store_payload(
+                body.as_bytes(),
+                ingest_buffers,
+                log_name,
+                SYSLOG_USAGE_TOKEN,
+                None,
+                false,
+                None,
+            )
+            .map(move |response| {
+                if !response.status().is_success() {
+                    warn!(
+                        "Dropping {} syslog messages refused by {}: {}",
+                        count,
+                        refused_log,
+                        response.status()
+                    );
+                }
+            })
+            .map_err(|e| error!("Could not store syslog messages: {}", e)),
+    )
+}
+
+#[cfg(test)]
+mod ingest_syslog_tests {
+    use super::*;
+
+    fn received() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2019-10-12T08:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parse_rfc5424_messages() {
+        let message = parse_syslog(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut=\"3\" eventID=\"1011\"][examplePriority@32473 class=\"high\\]\"] \u{feff}An application event",
+            &received(),
+        );
+        assert_eq!(message.facility, "local4");
+        assert_eq!(message.severity, "notice");
+        assert_eq!(
+            message.timestamp,
+            Some("2003-10-11T22:14:15.003Z".to_string())
+        );
+        assert_eq!(message.hostname, Some("mymachine.example.com".to_string()));
+        assert_eq!(message.app_name, Some("evntslog".to_string()));
+        assert_eq!(message.procid, None);
+        assert_eq!(message.msgid, Some("ID47".to_string()));
+        assert_eq!(
+            message.structured_data,
+            Some("[exampleSDID@32473 iut=\"3\" eventID=\"1011\"][examplePriority@32473 class=\"high\\]\"]".to_string())
+        );
+        assert_eq!(message.message, "An application event");
+
+        let message = parse_syslog("<34>1 - - su - - -", &received());
+        assert_eq!(message.severity, "crit");
+        assert_eq!(message.app_name, Some("su".to_string()));
+        assert_eq!(message.timestamp, None);
+        assert_eq!(message.structured_data, None);
+        assert_eq!(message.message, "");
+    }
+
+    #[test]
+    fn parse_rfc3164_messages() {
+        let message = parse_syslog(
+            "<34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed for lonvick on /dev/pts/8",
+            &received(),
+        );
+        assert_eq!(message.facility, "auth");
+        assert_eq!(message.severity, "crit");
+        assert_eq!(
+            message.timestamp,
+            Some("2019-10-11T22:14:15+00:00".to_string())
+        );
+        assert_eq!(message.hostname, Some("mymachine".to_string()));
+        assert_eq!(message.app_name, Some("su".to_string()));
+        assert_eq!(message.procid, Some("230".to_string()));
+        assert_eq!(
+            message.message,
+            "'su root' failed for lonvick on /dev/pts/8"
+        );
+
+        // local senders leave the hostname out, and a date ahead is of the year before
+        let message = parse_syslog("<13>Dec 31 23:59:59 cron: job done", &received());
+        assert_eq!(
+            message.timestamp,
+            Some("2018-12-31T23:59:59+00:00".to_string())
+        );
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.app_name, Some("cron".to_string()));
+        assert_eq!(message.message, "job done");
+
+        let message = parse_syslog("<13>Oct  1 08:00:00 host just text", &received());
+        assert_eq!(message.hostname, Some("host".to_string()));
+        assert_eq!(message.app_name, None);
+        assert_eq!(message.message, "just text");
+    }
+
+    #[test]
+    fn parse_loose_messages() {
+        let message = parse_syslog("no priority here", &received());
+        assert_eq!(message.facility, "user");
+        assert_eq!(message.severity, "notice");
+        assert_eq!(message.message, "no priority here");
+
+        let message = parse_syslog("<999>1 not a header", &received());
+        assert_eq!(message.facility, "user");
+        assert_eq!(message.message, "<999>1 not a header");
+
+        // a broken RFC 5424 header is kept on the message
+        let message = parse_syslog("<14>1 yesterday host app - - -", &received());
+        assert_eq!(message.severity, "info");
+        assert_eq!(message.timestamp, None);
+        assert_eq!(message.message, "1 yesterday host app - - -");
+    }
+
+    #[test]
+    fn messages_as_json_lines() {
+        let message = parse_syslog("<14>1 - host app 42 - - line\nbreak", &received());
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"facility":"user","severity":"info","hostname":"host","app_name":"app","procid":"42","message":"line\nbreak"}"#
+        );
+    }
+
+    #[test]
+    fn tcp_frames() {
+        let mut codec = SyslogFrames::default();
+        let mut buf = BytesMut::from(&b"<13>first\r\n10 <13>second\n\n<13>thi"[..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(vec!["<13>first".to_string(), "<13>second".to_string()])
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"rd\n10 <13>fou");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(vec!["<13>third".to_string()])
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"rth<13>fifth");
+        assert_eq!(
+            codec.decode_eof(&mut buf).unwrap(),
+            Some(vec!["<13>fourth".to_string(), "<13>fifth".to_string()])
+        );
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"99999 <13>huge"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"1234567890"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn udp_datagrams() {
+        let mut codec = SyslogDatagrams {};
+        let mut buf = BytesMut::from(&b"<13>one message\nwith two lines\n"[..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(vec!["<13>one message\nwith two lines".to_string()])
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,350 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, RwLock};
+
+use chrono::{SecondsFormat, Utc};
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::Future;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::constants::{JOBS_MAX_FINISHED, JOBS_PREFIX};
+use crate::meta::ds_for_metabucket;
+use crate::storage::{
+    delete_object, get_object_metabucket, list_metabucket_keys, write_object_metabucket,
+};
+use crate::supervisor::{self, panic_message};
+
+lazy_static! {
+    // Jobs started by this process, keyed by id
+    static ref JOBS: Mutex<HashMap<String, RegisteredJob>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// A background job as reported by `GET /api/jobs`, and as stored on the metabucket
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: String,
+    // what the job does, ie: `compact` or `tiering`
+    pub kind: String,
+    // what it does it on, usually a log
+    pub target: String,
+    pub state: JobState,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    // why the job failed, if it panicked
+    pub error: Option<String>,
+}
+
+struct RegisteredJob {
+    job: Job,
+    // `None` once the job is finished or asked to stop
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CancelError {
+    // not a job of this process
+    NotFound,
+    NotRunning,
+}
+
+/// Runs `task` as a job that can be inspected and cancelled from the admin API, and returns its
+/// id. The state of the job is stored on the metabucket when it starts and once it's done.
+pub fn spawn_job<F>(cfg: Arc<RwLock<Config>>, kind: &str, target: &str, task: F) -> String
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let (job, cancel_rx) = register(kind, target);
+    let id = job.id.clone();
+    info!("Starting job {}, {} of {}", id, kind, target);
+    let started = write_job(Arc::clone(&cfg), job);
+    let finish_id = id.clone();
+    let run = AssertUnwindSafe(task)
+        .catch_unwind()
+        .select2(cancel_rx)
+        .then(move |res| {
+            let (state, error) = match res {
+                Ok(Either::A((Ok(()), _))) => (JobState::Succeeded, None),
+                Ok(Either::A((Err(()), _))) => (JobState::Failed, None),
+                Err(Either::A((panic, _))) => (JobState::Failed, Some(panic_message(&*panic))),
+                // dropping the task stops it
+                Ok(Either::B(_)) | Err(Either::B(_)) => (JobState::Cancelled, None),
+            };
+            Ok(finish(&finish_id, state, error))
+        });
+    // the final state is written after the initial one, even for jobs that end right away
+    let done = run
+        .join(started)
+        .and_then(move |(finished, _)| match finished {
+            Some((job, evicted)) => {
+                info!("Job {} is {:?}", job.id, job.state);
+                for evicted_id in evicted {
+                    forget_job(Arc::clone(&cfg), evicted_id);
+                }
+                Either::A(write_job(cfg, job))
+            }
+            None => Either::B(future::ok(())),
+        });
+    supervisor::spawn_isolated(format!("Job {}", id), done);
+    id
+}
+
+/// Asks a job of this process to stop, the job is reported as cancelled once it does
+pub fn cancel_job(id: &str) -> Result<Job, CancelError> {
+    let mut jobs = JOBS.lock().unwrap();
+    let registered = jobs.get_mut(id).ok_or(CancelError::NotFound)?;
+    match registered.cancel.take() {
+        Some(cancel) => {
+            info!("Cancelling job {}", id);
+            let _ = cancel.send(());
+            Ok(registered.job.clone())
+        }
+        None => Err(CancelError::NotRunning),
+    }
+}
+
+/// Jobs of this process, the most recently started first
+pub fn local_jobs() -> Vec<Job> {
+    let mut jobs: Vec<Job> = JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|registered| registered.job.clone())
+        .collect();
+    sort_jobs(&mut jobs);
+    jobs
+}
+
+pub fn local_job(id: &str) -> Option<Job> {
+    JOBS.lock()
+        .unwrap()
+        .get(id)
+        .map(|registered| registered.job.clone())
+}
+
+/// Jobs stored on the metabucket, which includes the jobs of other processes. Objects that
+/// can't be read are skipped.
+pub fn stored_jobs(cfg: Arc<RwLock<Config>>) -> impl Future<Item = Vec<Job>, Error = String> {
+    list_metabucket_keys(Arc::clone(&cfg), JOBS_PREFIX.to_string())
+        .map_err(|e| format!("Could not list the jobs: {:?}", e))
+        .and_then(move |keys| {
+            let reads = keys.into_iter().map(move |key| {
+                get_object_metabucket(Arc::clone(&cfg), key)
+                    .map(|payload| payload.and_then(|p| serde_json::from_str::<Job>(&p).ok()))
+                    .or_else(|_| Ok(None))
+            });
+            future::join_all(reads).map(|jobs| jobs.into_iter().filter_map(|j| j).collect())
+        })
+}
+
+/// A job stored on the metabucket, `None` if there's no such job
+pub fn stored_job(
+    cfg: Arc<RwLock<Config>>,
+    id: &str,
+) -> impl Future<Item = Option<Job>, Error = String> {
+    get_object_metabucket(cfg, job_key(id))
+        .map(|payload| payload.and_then(|p| serde_json::from_str::<Job>(&p).ok()))
+        .map_err(|e| format!("Could not read the job: {:?}", e))
+}
+
+/// Orders jobs with the most recently started first
+pub fn sort_jobs(jobs: &mut Vec<Job>) {
+    jobs.sort_by(|a, b| b.started_at.cmp(&a.started_at).then(a.id.cmp(&b.id)));
+}
+
+fn job_key(id: &str) -> String {
+    format!("{}{}", JOBS_PREFIX, id)
+}
+
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Adds a running job to the registry along with the receiver it's cancelled through
+fn register(kind: &str, target: &str) -> (Job, oneshot::Receiver<()>) {
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    let job = Job {
+        id: Uuid::new_v4().to_string(),
+        kind: kind.to_string(),
+        target: target.to_string(),
+        state: JobState::Running,
+        started_at: now_rfc3339(),
+        finished_at: None,
+        error: None,
+    };
+    JOBS.lock().unwrap().insert(
+        job.id.clone(),
+        RegisteredJob {
+            job: job.clone(),
+            cancel: Some(cancel_tx),
+        },
+    );
+    (job, cancel_rx)
+}
+
+/// Records how a job ended, returning it along with the finished jobs forgotten to make room
+fn finish(id: &str, state: JobState, error: Option<String>) -> Option<(Job, Vec<String>)> {
+    let mut jobs = JOBS.lock().unwrap();
+    let job = {
+        let registered = jobs.get_mut(id)?;
+        registered.cancel = None;
+        registered.job.state = state;
+        registered.job.finished_at = Some(now_rfc3339());
+        registered.job.error = error;
+        registered.job.clone()
+    };
+    let evicted = oldest_finished(&jobs, JOBS_MAX_FINISHED);
+    for evicted_id in &evicted {
+        jobs.remove(evicted_id);
+    }
+    Some((job, evicted))
+}
+
+/// Finished jobs past the `max` most recently finished ones
+fn oldest_finished(jobs: &HashMap<String, RegisteredJob>, max: usize) -> Vec<String> {
+    let mut finished: Vec<&Job> = jobs
+        .values()
+        .map(|registered| &registered.job)
+        .filter(|job| job.finished_at.is_some())
+        .collect();
+    if finished.len() <= max {
+        return Vec::new();
+    }
+    finished.sort_by(|a, b| a.finished_at.cmp(&b.finished_at).then(a.id.cmp(&b.id)));
+    finished[..finished.len() - max]
+        .iter()
+        .map(|job| job.id.clone())
+        .collect()
+}
+
+fn write_job(cfg: Arc<RwLock<Config>>, job: Job) -> impl Future<Item = (), Error = ()> {
+    let id = job.id.clone();
+    // built once polled, jobs are often spawned while holding the configuration
+    future::lazy(move || {
+        write_object_metabucket(cfg, job_key(&job.id), serde_json::to_string(&job).unwrap())
+    })
+    .map(|_| ())
+    .or_else(move |e| {
+        error!("Could not store the state of job {}: {:?}", id, e);
+        Ok(())
+    })
+}
+
+/// Deletes a job forgotten by the registry from the metabucket
+fn forget_job(cfg: Arc<RwLock<Config>>, id: String) {
+    let datastore = ds_for_metabucket(cfg);
+    supervisor::spawn_isolated(
+        format!("Removal of job {}", id),
+        delete_object(&datastore, job_key(&id))
+            .map(|_| ())
+            .map_err(move |e| error!("Could not remove job {}: {:?}", id, e)),
+    );
+}
+
+#[cfg(test)]
+mod jobs_tests {
+    use super::*;
+
+    fn finished_job(id: &str, finished_at: &str) -> RegisteredJob {
+        RegisteredJob {
+            job: Job {
+                id: id.to_string(),
+                kind: "compact".to_string(),
+                target: "mylog".to_string(),
+                state: JobState::Succeeded,
+                started_at: "2019-07-24T09:00:00.000Z".to_string(),
+                finished_at: Some(finished_at.to_string()),
+                error: None,
+            },
+            cancel: None,
+        }
+    }
+
+    #[test]
+    fn cancel_running_job() {
+        let (job, mut cancel_rx) = register("compact", "mylog");
+        assert_eq!(local_job(&job.id).unwrap().state, JobState::Running);
+        assert_eq!(cancel_job(&job.id).unwrap().id, job.id);
+        assert_eq!(cancel_rx.try_recv(), Ok(Some(())));
+        // it was already asked to stop
+        assert_eq!(cancel_job(&job.id), Err(CancelError::NotRunning));
+
+        let (finished, _) = finish(&job.id, JobState::Cancelled, None).unwrap();
+        assert_eq!(finished.state, JobState::Cancelled);
+        assert!(finished.finished_at.is_some());
+        assert_eq!(cancel_job(&job.id), Err(CancelError::NotRunning));
+        assert_eq!(cancel_job("unknown"), Err(CancelError::NotFound));
+    }
+
+    #[test]
+    fn oldest_finished_jobs_are_evicted() {
+        let mut jobs = HashMap::new();
+        jobs.insert(
+            "a".to_string(),
+            finished_job("a", "2019-07-24T09:00:03.000Z"),
+        );
+        jobs.insert(
+            "b".to_string(),
+            finished_job("b", "2019-07-24T09:00:01.000Z"),
+        );
+        jobs.insert(
+            "c".to_string(),
+            finished_job("c", "2019-07-24T09:00:02.000Z"),
+        );
+        let (running, _) = register("reindex", "mylog");
+        jobs.insert(
+            running.id.clone(),
+            RegisteredJob {
+                job: running,
+                cancel: None,
+            },
+        );
+        assert_eq!(oldest_finished(&jobs, 3), Vec::<String>::new());
+        assert_eq!(
+            oldest_finished(&jobs, 1),
+            vec!["b".to_string(), "c".to_string()]
+        );
+    }
+
+    #[test]
+    fn jobs_sorted_by_start() {
+        let mut jobs = vec![
+            finished_job("a", "2019-07-24T09:00:03.000Z").job,
+            finished_job("b", "2019-07-24T09:00:03.000Z").job,
+        ];
+        jobs[0].started_at = "2019-07-24T08:00:00.000Z".to_string();
+        sort_jobs(&mut jobs);
+        assert_eq!(jobs[0].id, "b");
+        let stored: Job = serde_json::from_str(&serde_json::to_string(&jobs[0]).unwrap()).unwrap();
+        assert_eq!(stored, jobs[0]);
+    }
+}
@@ -0,0 +1,193 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde_derive::Serialize;
+
+// Weight of the newest sample on the moving average
+const LATENCY_SMOOTHING: f64 = 0.2;
+// Failed requests in a row after which a datastore is reported unhealthy
+const UNHEALTHY_AFTER_ERRORS: u64 = 3;
+
+lazy_static! {
+    static ref DATASTORE_LATENCY: Mutex<HashMap<String, DataStoreLatency>> =
+        Mutex::new(HashMap::new());
+}
+
+/// GET latency observed on a datastore, measured up to the response headers, and whether its
+/// requests are failing.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DataStoreLatency {
+    // exponential moving average of the successful requests
+    pub avg_ms: f64,
+    pub last_ms: u64,
+    pub samples: u64,
+    pub errors: u64,
+    pub consecutive_errors: u64,
+    pub healthy: bool,
+}
+
+impl Default for DataStoreLatency {
+    fn default() -> DataStoreLatency {
+        DataStoreLatency {
+            avg_ms: 0.0,
+            last_ms: 0,
+            samples: 0,
+            errors: 0,
+            consecutive_errors: 0,
+            healthy: true,
+        }
+    }
+}
+
+impl DataStoreLatency {
+    fn record(&mut self, elapsed: Duration, ok: bool) {
+        if !ok {
+            self.record_error();
+            return;
+        }
+        self.consecutive_errors = 0;
+        self.healthy = true;
+        let ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        self.avg_ms = if self.samples == 0 {
+            ms as f64
+        } else {
+            LATENCY_SMOOTHING * ms as f64 + (1.0 - LATENCY_SMOOTHING) * self.avg_ms
+        };
+        self.last_ms = ms;
+        self.samples += 1;
+    }
+
+    fn record_error(&mut self) {
+        self.errors += 1;
+        self.consecutive_errors += 1;
+        if self.consecutive_errors >= UNHEALTHY_AFTER_ERRORS {
+            self.healthy = false;
+        }
+    }
+}
+
+/// Records the outcome of a GET against a datastore
+pub fn record_get_latency(ds_name: &str, elapsed: Duration, ok: bool) {
+    let mut table = DATASTORE_LATENCY.lock().unwrap();
+    table
+        .entry(ds_name.to_string())
+        .or_insert_with(DataStoreLatency::default)
+        .record(elapsed, ok);
+}
+
+/// Records a failed request against a datastore that isn't timed, ie: listing its objects
+pub fn record_datastore_error(ds_name: &str) {
+    let mut table = DATASTORE_LATENCY.lock().unwrap();
+    table
+        .entry(ds_name.to_string())
+        .or_insert_with(DataStoreLatency::default)
+        .record_error();
+}
+
+/// Snapshot of the latency of every datastore read so far
+pub fn latency_table() -> HashMap<String, DataStoreLatency> {
+    DATASTORE_LATENCY.lock().unwrap().clone()
+}
+
+/// Orders the datastores replicating a log to read from it: healthy ones first, then the fastest on
+/// average. Datastores never read from yet go first, so their latency gets measured.
+pub fn fastest_replicas(ds_names: &[String]) -> Vec<String> {
+    let table = DATASTORE_LATENCY.lock().unwrap();
+    let mut replicas: Vec<(bool, f64, &String)> = ds_names
+        .iter()
+        .map(|ds_name| match table.get(ds_name) {
+            Some(latency) => (!latency.healthy, latency.avg_ms, ds_name),
+            None => (false, 0.0, ds_name),
+        })
+        .collect();
+    // stable, so replicas as fast as each other keep the order of the log
+    replicas.sort_by(|a, b| {
+        (a.0, a.1)
+            .partial_cmp(&(b.0, b.1))
+            .unwrap_or(Ordering::Equal)
+    });
+    replicas
+        .into_iter()
+        .map(|(_, _, ds_name)| ds_name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod latency_tests {
+    use super::*;
+
+    #[test]
+    fn moving_average_of_successful_gets() {
+        let mut latency = DataStoreLatency::default();
+        latency.record(Duration::from_millis(100), true);
+        assert!((latency.avg_ms - 100.0).abs() < 1e-9);
+        latency.record(Duration::from_millis(200), true);
+        assert!((latency.avg_ms - 120.0).abs() < 1e-9);
+        assert_eq!(latency.last_ms, 200);
+        // errors are counted but don't move the average
+        latency.record(Duration::from_millis(5000), false);
+        assert!((latency.avg_ms - 120.0).abs() < 1e-9);
+        assert_eq!(latency.samples, 2);
+        assert_eq!(latency.errors, 1);
+    }
+
+    #[test]
+    fn replicas_by_health_and_latency() {
+        let names =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+        record_get_latency("replica-slow", Duration::from_millis(300), true);
+        record_get_latency("replica-fast", Duration::from_millis(20), true);
+        record_get_latency("replica-down", Duration::from_millis(5), true);
+        for _ in 0..UNHEALTHY_AFTER_ERRORS {
+            record_datastore_error("replica-down");
+        }
+        assert_eq!(
+            fastest_replicas(&names(&[
+                "replica-down",
+                "replica-slow",
+                "replica-fast",
+                "replica-new"
+            ])),
+            names(&[
+                "replica-new",
+                "replica-fast",
+                "replica-slow",
+                "replica-down"
+            ])
+        );
+    }
+
+    #[test]
+    fn unhealthy_after_consecutive_errors() {
+        let mut latency = DataStoreLatency::default();
+        latency.record_error();
+        latency.record_error();
+        assert!(latency.healthy);
+        latency.record_error();
+        assert!(!latency.healthy);
+        // a single success recovers it
+        latency.record(Duration::from_millis(10), true);
+        assert!(latency.healthy);
+        assert_eq!(latency.consecutive_errors, 0);
+        assert_eq!(latency.errors, 3);
+    }
+}
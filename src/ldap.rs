@@ -0,0 +1,481 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use native_tls::{TlsConnector, TlsStream};
+use url::Url;
+
+use crate::constants::{LDAP_MAX_MESSAGE_BYTES, LDAP_TIMEOUT_SECS};
+
+// BER tags of the LDAP messages and operations used to log users in, as of RFC 4511
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_BOOLEAN: u8 = 0x01;
+const OP_BIND_REQUEST: u8 = 0x60;
+const OP_BIND_RESPONSE: u8 = 0x61;
+const OP_UNBIND_REQUEST: u8 = 0x42;
+const OP_SEARCH_REQUEST: u8 = 0x63;
+const OP_SEARCH_RESULT_ENTRY: u8 = 0x64;
+const OP_SEARCH_RESULT_DONE: u8 = 0x65;
+const OP_EXTENDED_REQUEST: u8 = 0x77;
+const OP_EXTENDED_RESPONSE: u8 = 0x78;
+const EXTENDED_REQUEST_NAME: u8 = 0x80;
+const AUTH_SIMPLE: u8 = 0x80;
+const FILTER_PRESENT: u8 = 0x87;
+// Extended operation upgrading an `ldap://` connection to TLS, as of RFC 4511 section 4.14
+const STARTTLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+
+/// A blocking connection to an LDAP server, enough to bind as a user and read its entry
+pub struct LdapConnection {
+    stream: Stream,
+    last_id: i64,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl LdapConnection {
+    /// Connects to an `ldap://` or `ldaps://` url, ie: `ldaps://ldap.example.com`. `ldap://`
+    /// connections are upgraded to TLS with StartTLS before anything is sent on them, unless
+    /// `allow_plaintext` is set.
+    pub fn connect(url: &str, allow_plaintext: bool) -> Result<LdapConnection, String> {
+        let url = Url::parse(url).map_err(|e| format!("Invalid LDAP url {}: {}", url, e))?;
+        let tls = match url.scheme() {
+            "ldap" => false,
+            "ldaps" => true,
+            scheme => return Err(format!("Unsupported LDAP scheme {}", scheme)),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| "The LDAP url has no host".to_string())?
+            .to_string();
+        let port = url.port().unwrap_or(if tls { 636 } else { 389 });
+        let timeout = Duration::from_secs(LDAP_TIMEOUT_SECS);
+        let addr = (&host[..], port)
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| format!("Could not resolve {}", host))?;
+        let tcp = TcpStream::connect_timeout(&addr, timeout).map_err(|e| e.to_string())?;
+        tcp.set_read_timeout(Some(timeout))
+            .and_then(|_| tcp.set_write_timeout(Some(timeout)))
+            .map_err(|e| e.to_string())?;
+        if tls {
+            return Ok(LdapConnection {
+                stream: Stream::Tls(tls_connect(&host, tcp)?),
+                last_id: 0,
+            });
+        }
+        let plain = LdapConnection {
+            stream: Stream::Plain(tcp),
+            last_id: 0,
+        };
+        if allow_plaintext {
+            Ok(plain)
+        } else {
+            plain.start_tls(&host)
+        }
+    }
+
+    /// Upgrades a plain connection to TLS, failing if the server doesn't support StartTLS
+    fn start_tls(mut self, host: &str) -> Result<LdapConnection, String> {
+        let id = self.send(&start_tls_request())?;
+        let (tag, value) = self.receive(id)?;
+        if tag != OP_EXTENDED_RESPONSE {
+            return Err(format!("Unexpected response {:#x} to StartTLS", tag));
+        }
+        match result_code(&value)? {
+            0 => (),
+            code => {
+                return Err(format!(
+                    "The server refused StartTLS with result code {}",
+                    code
+                ))
+            }
+        }
+        match self.stream {
+            Stream::Plain(tcp) => Ok(LdapConnection {
+                stream: Stream::Tls(tls_connect(host, tcp)?),
+                last_id: self.last_id,
+            }),
+            Stream::Tls(_) => Err("The LDAP connection already uses TLS".to_string()),
+        }
+    }
+
+    /// Binds as `dn` with its password, `false` if the server refused the credentials
+    pub fn simple_bind(&mut self, dn: &str, password: &str) -> Result<bool, String> {
+        let request = encode(
+            OP_BIND_REQUEST,
+            &[
+                integer(TAG_INTEGER, 3),
+                encode(TAG_OCTET_STRING, dn.as_bytes()),
+                encode(AUTH_SIMPLE, password.as_bytes()),
+            ]
+            .concat(),
+        );
+        let id = self.send(&request)?;
+        let (tag, value) = self.receive(id)?;
+        if tag != OP_BIND_RESPONSE {
+            return Err(format!("Unexpected response {:#x} to a bind", tag));
+        }
+        Ok(result_code(&value)? == 0)
+    }
+
+    /// Values of an attribute of the entry `dn`, its name is matched regardless of its case
+    pub fn read_attribute(&mut self, dn: &str, attribute: &str) -> Result<Vec<String>, String> {
+        let request = encode(
+            OP_SEARCH_REQUEST,
+            &[
+                encode(TAG_OCTET_STRING, dn.as_bytes()),
+                // base object scope, never dereferencing aliases, without size or time limits
+                integer(TAG_ENUMERATED, 0),
+                integer(TAG_ENUMERATED, 0),
+                integer(TAG_INTEGER, 0),
+                integer(TAG_INTEGER, 0),
+                encode(TAG_BOOLEAN, &[0]),
+                encode(FILTER_PRESENT, b"objectClass"),
+                encode(
+                    TAG_SEQUENCE,
+                    &encode(TAG_OCTET_STRING, attribute.as_bytes()),
+                ),
+            ]
+            .concat(),
+        );
+        let id = self.send(&request)?;
+        let mut values = Vec::new();
+        loop {
+            let (tag, value) = self.receive(id)?;
+            match tag {
+                OP_SEARCH_RESULT_ENTRY => values.extend(entry_values(&value, attribute)?),
+                OP_SEARCH_RESULT_DONE => {
+                    return match result_code(&value)? {
+                        0 => Ok(values),
+                        code => Err(format!("The search failed with result code {}", code)),
+                    };
+                }
+                // references to other servers are not followed
+                _ => (),
+            }
+        }
+    }
+
+    /// Ends the session, the server closes the connection
+    pub fn unbind(mut self) {
+        let _ = self.send(&encode(OP_UNBIND_REQUEST, &[]));
+    }
+
+    fn send(&mut self, operation: &[u8]) -> Result<i64, String> {
+        self.last_id += 1;
+        let message = encode(
+            TAG_SEQUENCE,
+            &[integer(TAG_INTEGER, self.last_id), operation.to_vec()].concat(),
+        );
+        self.stream
+            .write_all(&message)
+            .and_then(|_| self.stream.flush())
+            .map_err(|e| e.to_string())?;
+        Ok(self.last_id)
+    }
+
+    /// The next operation answering the message `id`, its tag and contents
+    fn receive(&mut self, id: i64) -> Result<(u8, Vec<u8>), String> {
+        loop {
+            let message = read_message(&mut self.stream)?;
+            let parts = elements(&message)?;
+            if parts.len() < 2 || parts[0].0 != TAG_INTEGER {
+                return Err("Malformed LDAP message".to_string());
+            }
+            // unsolicited notifications have the id 0, ie: the server disconnecting
+            let message_id = decode_integer(parts[0].1)?;
+            if message_id == 0 {
+                return Err("The LDAP server ended the session".to_string());
+            }
+            if message_id == id {
+                return Ok((parts[1].0, parts[1].1.to_vec()));
+            }
+        }
+    }
+}
+
+fn tls_connect(host: &str, tcp: TcpStream) -> Result<TlsStream<TcpStream>, String> {
+    let connector = TlsConnector::new().map_err(|e| e.to_string())?;
+    connector.connect(host, tcp).map_err(|e| e.to_string())
+}
+
+/// The extended request starting TLS, which only carries its name
+fn start_tls_request() -> Vec<u8> {
+    encode(
+        OP_EXTENDED_REQUEST,
+        &encode(EXTENDED_REQUEST_NAME, STARTTLS_OID.as_bytes()),
+    )
+}
+
+/// A BER element of a tag with its contents
+fn encode(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = (len as u64)
+            .to_be_bytes()
+            .iter()
+            .skip_while(|b| **b == 0)
+            .cloned()
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+/// A non negative integer in its shortest form
+fn integer(tag: u8, n: i64) -> Vec<u8> {
+    let mut bytes: Vec<u8> = n
+        .to_be_bytes()
+        .iter()
+        .skip_while(|b| **b == 0)
+        .cloned()
+        .collect();
+    // a leading bit set would read as a negative number
+    match bytes.first() {
+        Some(b) if b & 0x80 == 0 => (),
+        _ => bytes.insert(0, 0),
+    }
+    encode(tag, &bytes)
+}
+
+/// A two's complement integer, at most 8 bytes long so it fits
+fn decode_integer(value: &[u8]) -> Result<i64, String> {
+    if value.is_empty() || value.len() > 8 {
+        return Err("Unsupported LDAP integer".to_string());
+    }
+    let sign = if value[0] & 0x80 != 0 { -1 } else { 0 };
+    Ok(value.iter().fold(sign, |n, b| (n << 8) | i64::from(*b)))
+}
+
+/// The length of an element and the bytes it takes, from the byte after its tag
+fn decode_length(data: &[u8]) -> Result<(usize, usize), String> {
+    let first = *data
+        .first()
+        .ok_or_else(|| "Truncated LDAP message".to_string())?;
+    if first < 0x80 {
+        return Ok((first as usize, 1));
+    }
+    let count = (first & 0x7f) as usize;
+    if count == 0 || count > 4 || data.len() < 1 + count {
+        return Err("Unsupported LDAP message length".to_string());
+    }
+    let len = data[1..=count]
+        .iter()
+        .fold(0usize, |n, b| (n << 8) | *b as usize);
+    Ok((len, 1 + count))
+}
+
+/// The elements one after the other in `data`, as tags and contents
+fn elements(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, String> {
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let (len, header) = decode_length(&data[1..])?;
+        let start = 1 + header;
+        if data.len() < start + len {
+            return Err("Truncated LDAP message".to_string());
+        }
+        out.push((data[0], &data[start..start + len]));
+        data = &data[start + len..];
+    }
+    Ok(out)
+}
+
+/// Reads the contents of the next LDAP message, which is a sequence
+fn read_message<R: Read>(stream: &mut R) -> Result<Vec<u8>, String> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).map_err(|e| e.to_string())?;
+    if header[0] != TAG_SEQUENCE {
+        return Err("Malformed LDAP message".to_string());
+    }
+    let len = if header[1] < 0x80 {
+        header[1] as usize
+    } else {
+        let mut bytes = vec![0u8; (header[1] & 0x7f) as usize];
+        if bytes.is_empty() || bytes.len() > 4 {
+            return Err("Unsupported LDAP message length".to_string());
+        }
+        stream.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+        bytes.iter().fold(0usize, |n, b| (n << 8) | *b as usize)
+    };
+    if len > LDAP_MAX_MESSAGE_BYTES {
+        return Err(format!("LDAP message of {} bytes is too large", len));
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).map_err(|e| e.to_string())?;
+    Ok(message)
+}
+
+/// The result code of an `LDAPResult`, `0` being success
+fn result_code(value: &[u8]) -> Result<i64, String> {
+    match elements(value)?.first() {
+        Some((TAG_ENUMERATED, code)) => decode_integer(code),
+        _ => Err("Malformed LDAP result".to_string()),
+    }
+}
+
+/// Values of an attribute on a search result entry
+fn entry_values(value: &[u8], attribute: &str) -> Result<Vec<String>, String> {
+    let parts = elements(value)?;
+    let attributes = match parts.get(1) {
+        Some((TAG_SEQUENCE, attributes)) => elements(attributes)?,
+        _ => return Err("Malformed LDAP entry".to_string()),
+    };
+    let mut values = Vec::new();
+    for (_, partial) in attributes {
+        let partial = elements(partial)?;
+        match (partial.first(), partial.get(1)) {
+            (Some((TAG_OCTET_STRING, name)), Some((TAG_SET, vals)))
+                if String::from_utf8_lossy(name).eq_ignore_ascii_case(attribute) =>
+            {
+                for (_, val) in elements(vals)? {
+                    values.push(String::from_utf8_lossy(val).to_string());
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod ldap_tests {
+    use super::*;
+
+    #[test]
+    fn ber_encoding() {
+        assert_eq!(integer(TAG_INTEGER, 3), vec![0x02, 0x01, 0x03]);
+        assert_eq!(integer(TAG_INTEGER, 0), vec![0x02, 0x01, 0x00]);
+        assert_eq!(integer(TAG_INTEGER, 128), vec![0x02, 0x02, 0x00, 0x80]);
+        let long = encode(TAG_OCTET_STRING, &[b'a'; 300]);
+        assert_eq!(&long[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(
+            elements(&long).unwrap(),
+            vec![(TAG_OCTET_STRING, &[b'a'; 300][..])]
+        );
+        assert!(elements(&long[..100]).is_err());
+    }
+
+    #[test]
+    fn start_tls_encoding() {
+        let request = start_tls_request();
+        assert_eq!(&request[..4], &[0x77, 0x18, 0x80, 0x16]);
+        assert_eq!(&request[4..], b"1.3.6.1.4.1.1466.20037");
+    }
+
+    #[test]
+    fn read_messages() {
+        // a bind response with the result code 49, invalid credentials
+        let result = [
+            integer(TAG_ENUMERATED, 49),
+            encode(TAG_OCTET_STRING, b""),
+            encode(TAG_OCTET_STRING, b""),
+        ]
+        .concat();
+        let message = encode(
+            TAG_SEQUENCE,
+            &[integer(TAG_INTEGER, 1), encode(OP_BIND_RESPONSE, &result)].concat(),
+        );
+        let contents = read_message(&mut &message[..]).unwrap();
+        let parts = elements(&contents).unwrap();
+        assert_eq!(decode_integer(parts[0].1), Ok(1));
+        assert_eq!(parts[1].0, OP_BIND_RESPONSE);
+        assert_eq!(result_code(parts[1].1), Ok(49));
+
+        let huge = [TAG_SEQUENCE, 0x84, 0x7f, 0xff, 0xff, 0xff];
+        assert!(read_message(&mut &huge[..]).is_err());
+
+        assert_eq!(decode_integer(&[0x7f, 0xff]), Ok(32767));
+        assert_eq!(decode_integer(&[0xff]), Ok(-1));
+        assert!(decode_integer(&[]).is_err());
+        assert!(decode_integer(&[1; 9]).is_err());
+    }
+
+    #[test]
+    fn entry_attribute_values() {
+        let groups = [
+            encode(TAG_OCTET_STRING, b"cn=admins,ou=groups,dc=example,dc=com"),
+            encode(TAG_OCTET_STRING, b"cn=ops,ou=groups,dc=example,dc=com"),
+        ]
+        .concat();
+        let attribute = |name: &[u8], values: &[u8]| {
+            encode(
+                TAG_SEQUENCE,
+                &[encode(TAG_OCTET_STRING, name), encode(TAG_SET, values)].concat(),
+            )
+        };
+        let entry = [
+            encode(TAG_OCTET_STRING, b"uid=alice,ou=people,dc=example,dc=com"),
+            encode(
+                TAG_SEQUENCE,
+                &[
+                    attribute(b"cn", &encode(TAG_OCTET_STRING, b"Alice")),
+                    attribute(b"memberof", &groups),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        assert_eq!(
+            entry_values(&entry, "memberOf").unwrap(),
+            vec![
+                "cn=admins,ou=groups,dc=example,dc=com",
+                "cn=ops,ou=groups,dc=example,dc=com"
+            ]
+        );
+        assert!(entry_values(&entry, "mail").unwrap().is_empty());
+    }
+}
@@ -13,44 +13,97 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
-#[allow(unused)]
-#[macro_use]
-extern crate bitflags;
-
-use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Read};
+use std::net::SocketAddr;
 use std::process;
-use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
 use std::time::Instant;
 
+use crate::changesets::replay_changesets;
 use crate::config::Config;
-use crate::ingest::{Ingest, IngestBuffer};
+use crate::hyperscan::hyperscan_supported;
+use crate::ingest::{Ingest, IngestBuffers};
+use crate::ingest_syslog::start_syslog_listeners;
 use crate::meta::Meta;
+use crate::reports::Reports;
+use crate::secret_files::{start_secret_files_task, tls_acceptor};
+use crate::storage::set_max_concurrent_listings;
+use crate::tee::start_tee_task;
+use crate::tiering::Tiering;
+use crate::trash::Trash;
+use crate::usage::start_usage_task;
+use crate::version::{banner, build_info};
+use crate::watch::start_watch_task;
 use futures::{future, Future, Stream};
-use hyper::server::conn::Http;
-use hyper::service::service_fn;
-use hyper::Server;
-use log::{error, info};
-use native_tls::{Identity, TlsAcceptor};
-use tokio::net::TcpListener;
-use tokio::timer::Interval;
-
+use hyper::server::conn::{AddrStream, Http};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use log::{error, info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tls::TlsStream;
+
+mod aggregate;
 mod api;
 mod auth;
+mod bloom;
+mod caches;
+mod changesets;
+mod cidr;
 mod combinators;
+mod compression;
+mod computed;
+mod concurrency;
 mod config;
 mod constants;
 mod dialect;
+mod elastic;
+pub mod engine;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod federation;
 mod filter;
+pub mod fixtures;
+mod functions;
+mod history;
 mod http;
 mod hyperscan;
+mod identity;
 mod ingest;
+mod ingest_syslog;
+mod jobs;
+mod latency;
+mod ldap;
+mod loadtest;
+mod lockout;
+mod loki;
+mod maintenance;
 mod meta;
+mod multiline;
+mod naming;
+mod pagination;
+mod params;
+pub mod preflight;
+mod profile;
+mod queries;
 mod query;
+mod reports;
+mod s3stub;
+mod secret_files;
+mod secrets;
+mod signing;
+mod sinks;
 mod storage;
+mod supervisor;
+mod tee;
+mod throttle;
+mod tiering;
+mod trace;
+mod trash;
+mod usage;
+mod version;
+mod watch;
+mod webhook;
 
 pub struct Bootstrap {}
 
@@ -80,7 +133,17 @@ impl MinSQL {
     }
 
     pub fn run(&self) {
-        info!("Starting MinSQL");
+        info!(
+            "Starting {}",
+            banner(&build_info(&self.config.read().unwrap()))
+        );
+        if cfg!(feature = "fault-injection") {
+            warn!("Built with fault injection, faults set on /api/faults are injected on the datastores");
+        }
+        if !hyperscan_supported() {
+            warn!("Hyperscan is not supported on this machine, smart fields are extracted with the regex engine instead, which is slower");
+        }
+        set_max_concurrent_listings(self.config.read().unwrap().server.max_concurrent_listings);
         // make sure all datastores shown are reachable
         let cfg_valid_ds = Arc::clone(&self.config);
         self.validate_datastore_reachability(cfg_valid_ds);
@@ -89,8 +152,11 @@ impl MinSQL {
         // initial load of configuraiton
         let start = Instant::now();
         tokio::run(future::lazy(|| {
-            let meta_c = Meta::new(meta_cfg);
-            meta_c.load_config_from_metabucket()
+            // changesets a failure left behind are applied first, so they are loaded
+            replay_changesets(Arc::clone(&meta_cfg)).then(move |_| {
+                let meta_c = Meta::new(meta_cfg);
+                meta_c.load_config_from_metabucket()
+            })
         }));
         let duration = start.elapsed();
         info!("Loading configuration from metabucket took: {:?}", duration);
@@ -103,15 +169,13 @@ impl MinSQL {
 
         info!("Starting MinSQL Server");
         // initialize ingest buffers
-        let mut log_ingest_buffers_map: HashMap<String, Mutex<IngestBuffer>> = HashMap::new();
+        let log_ingest_buffers: Arc<IngestBuffers> = Arc::new(IngestBuffers::new());
 
         // for each log, initialize an ingest buffer
         for (log_name, _) in &self.config.read().unwrap().log {
-            log_ingest_buffers_map.insert(log_name.clone(), Mutex::new(IngestBuffer::new()));
+            log_ingest_buffers.insert(log_name);
         }
 
-        let log_ingest_buffers: Arc<HashMap<String, Mutex<IngestBuffer>>> =
-            Arc::new(log_ingest_buffers_map);
         // create a referece to the hashmap that we will share across intervals below
         let ingest_buffer_interval = Arc::clone(&log_ingest_buffers);
 
@@ -119,13 +183,18 @@ impl MinSQL {
 
         let service_cfg = Arc::clone(&self.config);
         // Hyper Service Function that will serve each request as a new task
-        let new_service = move || {
+        // a new one is made for every connection, along with the address of the client
+        let new_service = move |remote_addr: Option<SocketAddr>| {
             let log_ingest_buffers = Arc::clone(&log_ingest_buffers);
             let inner_service_cfg = Arc::clone(&service_cfg);
 
             let http_c = http::Http::new(inner_service_cfg);
             // Move a clone of `configuration` into the `service_fn`.
-            service_fn(move |req| {
+            service_fn(move |mut req: Request<Body>| {
+                // the client address is checked against the allowlists of the tokens
+                if let Some(remote_addr) = remote_addr {
+                    req.extensions_mut().insert(http::ClientAddr(remote_addr));
+                }
                 let log_ingest_buffers = Arc::clone(&log_ingest_buffers);
                 http_c.request_router(req, log_ingest_buffers)
             })
@@ -142,18 +211,33 @@ impl MinSQL {
                     .read_to_end(&mut der)
                     .expect("Could not read file");
 
-                let cert = Identity::from_pkcs12(&der, &pkcs12_pass[..]).unwrap();
-
-                let tls_cx = TlsAcceptor::builder(cert).build().unwrap();
-                let tls_cx = tokio_tls::TlsAcceptor::from(tls_cx);
+                let tls_cx = tls_acceptor(&der, &pkcs12_pass[..]).unwrap();
+                // swapped for a new one when the certificate or its password change
+                let tls_cx = Arc::new(RwLock::new(tls_cx));
+                let secrets_cfg = Arc::clone(&self.config);
+                let secrets_tls = Arc::clone(&tls_cx);
 
                 // Instance responsable for flushing ingestion buffers
                 let minsql_c = MinSQL::new(Arc::clone(&self.config));
                 let meta_c = Meta::new(Arc::clone(&self.config));
+                let tiering_c = Tiering::new(Arc::clone(&self.config));
+                let reports_c = Reports::new(Arc::clone(&self.config));
+                let trash_c = Trash::new(Arc::clone(&self.config));
+                let usage_cfg = Arc::clone(&self.config);
+                let syslog_cfg = Arc::clone(&self.config);
 
                 hyper::rt::run(future::lazy(move || {
                     meta_c.monitor_metabucket();
+                    minsql_c.load_storage_usage(Arc::clone(&ingest_buffer_interval));
+                    start_syslog_listeners(syslog_cfg, Arc::clone(&ingest_buffer_interval));
                     minsql_c.start_ingestion_flush_task(ingest_buffer_interval);
+                    tiering_c.start_lifecycle_task();
+                    reports_c.start_report_task();
+                    trash_c.start_purge_task();
+                    start_usage_task(usage_cfg);
+                    start_tee_task();
+                    start_watch_task();
+                    start_secret_files_task(secrets_cfg, Some(secrets_tls));
 
                     let srv = TcpListener::bind(&addr).expect("Error binding local port");
                     // Use lower lever hyper API to be able to intercept client connection
@@ -161,11 +245,14 @@ impl MinSQL {
                     let server = http_proto
                         .serve_incoming(
                             srv.incoming().and_then(move |socket| {
+                                let tls_cx = tls_cx.read().unwrap().clone();
                                 tls_cx
                                     .accept(socket)
                                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
                             }),
-                            new_service,
+                            make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                                new_service(conn.get_ref().get_ref().peer_addr().ok())
+                            }),
                         )
                         .then(|res| match res {
                             Ok(conn) => Ok(Some(conn)),
@@ -176,9 +263,10 @@ impl MinSQL {
                         })
                         .for_each(|conn_opt| {
                             if let Some(conn) = conn_opt {
+                                // connection errors only end that connection
                                 hyper::rt::spawn(
-                                    conn.and_then(|c| c.map_err(|e| panic!("Hyper error {}", e)))
-                                        .map_err(|e| eprintln!("Connection error {}", e)),
+                                    conn.map_err(|e| eprintln!("Connection error {}", e))
+                                        .and_then(|c| c.map_err(|e| error!("Hyper error {}", e))),
                                 );
                             }
 
@@ -194,13 +282,30 @@ impl MinSQL {
                 // Instance responsable for flushing ingestion buffers
                 let minsql_c = MinSQL::new(Arc::clone(&self.config));
                 let meta_c = Meta::new(Arc::clone(&self.config));
+                let tiering_c = Tiering::new(Arc::clone(&self.config));
+                let reports_c = Reports::new(Arc::clone(&self.config));
+                let trash_c = Trash::new(Arc::clone(&self.config));
+                let usage_cfg = Arc::clone(&self.config);
+                let syslog_cfg = Arc::clone(&self.config);
+                let secrets_cfg = Arc::clone(&self.config);
                 // HTTP server
                 hyper::rt::run(future::lazy(move || {
                     meta_c.monitor_metabucket();
+                    minsql_c.load_storage_usage(Arc::clone(&ingest_buffer_interval));
+                    start_syslog_listeners(syslog_cfg, Arc::clone(&ingest_buffer_interval));
                     minsql_c.start_ingestion_flush_task(ingest_buffer_interval);
+                    tiering_c.start_lifecycle_task();
+                    reports_c.start_report_task();
+                    trash_c.start_purge_task();
+                    start_usage_task(usage_cfg);
+                    start_tee_task();
+                    start_watch_task();
+                    start_secret_files_task(secrets_cfg, None);
 
                     let server = Server::bind(&addr)
-                        .serve(new_service)
+                        .serve(make_service_fn(move |conn: &AddrStream| {
+                            new_service(Some(conn.remote_addr()))
+                        }))
                         .map_err(|e| eprintln!("server error: {}", e));
                     info!("Listening on http://{}", addr);
                     server
@@ -209,42 +314,20 @@ impl MinSQL {
             _ => panic!("PKCS12 cert or password is missing"),
         }
     }
-    fn start_ingestion_flush_task(&self, ingest_buffer: Arc<HashMap<String, Mutex<IngestBuffer>>>) {
+    /// Loads the storage usage of every log, required to enforce storage quotas
+    fn load_storage_usage(&self, ingest_buffer: Arc<IngestBuffers>) {
+        let ingest_c = Ingest::new(Arc::clone(&self.config));
+        ingest_c.load_usage(ingest_buffer);
+    }
+
+    fn start_ingestion_flush_task(&self, ingest_buffer: Arc<IngestBuffers>) {
         let read_cfg = self.config.read().unwrap();
+        let ingest_c = Ingest::new(Arc::clone(&self.config));
 
         // for each log, start an interval to flush data at window speed, as long as the
         // commit window is not 0
         for (log_name, log) in &read_cfg.log {
-            let ingest_buffer2 = Arc::clone(&ingest_buffer);
-            if log.commit_window != "0" {
-                // What the flush spawn will take with him
-                let cfg = Arc::clone(&self.config);
-                let ingest_c = Ingest::new(cfg);
-
-                let log_name = log_name.clone();
-                info!(
-                    "Starting flusing loop for {} at {}",
-                    &log_name, &log.commit_window
-                );
-                // Start task to repeat every `commit_window` seconds, if the commit window is
-                // invalid, default to 5 seconds.
-                let task = Interval::new(
-                    Instant::now(),
-                    Duration::from_secs(
-                        Config::commit_window_to_seconds(&log.commit_window)
-                            .or_else(|| Some(5 as u64))
-                            .unwrap(),
-                    ),
-                )
-                .map_err(|e| panic!("interval errored; err={:?}", e))
-                .for_each(move |_| {
-                    let ingest_buffer3 = Arc::clone(&ingest_buffer2);
-                    let log_name = log_name.clone();
-                    ingest_c.flush_buffer(&log_name, ingest_buffer3)
-                });
-
-                hyper::rt::spawn(task);
-            }
+            ingest_c.start_flush_loop(log_name, &log.commit_window, Arc::clone(&ingest_buffer));
         }
     }
 
@@ -0,0 +1,402 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::future::Either;
+use futures::{future, Future, Stream};
+use hyper::{Body, Request};
+use log::{error, info};
+use serde_derive::Serialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio::timer::Interval;
+
+use crate::config::Config;
+use crate::constants::{
+    FIXTURES_DEFAULT_SEED, LOADTEST_MAX_DURATION_SECS, LOADTEST_MAX_LINES_PER_SEC,
+    LOADTEST_MAX_QUERIES_PER_SEC, LOADTEST_POOL_LINES, LOADTEST_TICKS_PER_SEC,
+    LOADTEST_USAGE_TOKEN,
+};
+use crate::fixtures::{generate_fixtures, FixtureSpec, FIXTURE_FIELDS};
+use crate::ingest::{Ingest, IngestBuffers};
+use crate::query::Query;
+
+/// What a load test sends: lines stored on a scratch log at a steady rate, along with searches
+/// run on it, for as long as it lasts
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadTestSpec {
+    pub log: String,
+    pub lines_per_sec: u64,
+    pub duration_secs: u64,
+    // share of the lines, from 0 to 1, holding each smart field, as `minsql gen-fixtures` takes it
+    pub densities: BTreeMap<String, f64>,
+    // searches run in turns, none are run without them
+    pub queries: Vec<String>,
+    pub queries_per_sec: u64,
+}
+
+impl LoadTestSpec {
+    /// Reads the spec from the body of a load test request, ie:
+    /// `{"log": "scratch", "lines_per_sec": 5000, "duration_secs": 60, "queries": ["SELECT $ip FROM scratch LIMIT 10"]}`
+    pub fn from_json(body: &serde_json::Value) -> Result<LoadTestSpec, String> {
+        let log = body
+            .get("log")
+            .and_then(|log| log.as_str())
+            .filter(|log| !log.is_empty())
+            .ok_or_else(|| "`log` must name the log the lines are stored on".to_string())?
+            .to_string();
+        let rate = |name: &str, default: u64, max: u64| match body.get(name) {
+            None => Ok(default),
+            Some(value) => match value.as_u64() {
+                Some(rate) if rate > 0 && rate <= max => Ok(rate),
+                _ => Err(format!("`{}` must be between 1 and {}", name, max)),
+            },
+        };
+        let lines_per_sec = rate("lines_per_sec", 1000, LOADTEST_MAX_LINES_PER_SEC)?;
+        let duration_secs = rate("duration_secs", 10, LOADTEST_MAX_DURATION_SECS)?;
+        let queries_per_sec = rate("queries_per_sec", 1, LOADTEST_MAX_QUERIES_PER_SEC)?;
+        let mut densities = BTreeMap::new();
+        if let Some(fields) = body.get("fields") {
+            let fields = fields
+                .as_object()
+                .ok_or_else(|| "`fields` must map smart fields to their density".to_string())?;
+            for (field, density) in fields {
+                if !FIXTURE_FIELDS.contains(&field.as_str()) {
+                    return Err(format!(
+                        "Unknown field `{}`, expected one of {}",
+                        field,
+                        FIXTURE_FIELDS.join(", ")
+                    ));
+                }
+                match density.as_f64() {
+                    Some(density) if density >= 0.0 && density <= 1.0 => {
+                        densities.insert(field.clone(), density);
+                    }
+                    _ => return Err(format!("The density of `{}` must be from 0 to 1", field)),
+                }
+            }
+        }
+        let queries = match body.get("queries") {
+            None => Vec::new(),
+            Some(queries) => queries
+                .as_array()
+                .and_then(|queries| {
+                    queries
+                        .iter()
+                        .map(|query| query.as_str().map(|query| query.to_string()))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .ok_or_else(|| "`queries` must be a list of searches".to_string())?,
+        };
+        Ok(LoadTestSpec {
+            log,
+            lines_per_sec,
+            duration_secs,
+            densities,
+            queries,
+            queries_per_sec,
+        })
+    }
+}
+
+/// Latency percentiles of the calls of a load test, in milliseconds
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct LatencyPercentiles {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// Nearest-rank percentiles of the latencies, all `0` without any
+pub fn latency_percentiles(mut samples: Vec<u64>) -> LatencyPercentiles {
+    if samples.is_empty() {
+        return LatencyPercentiles::default();
+    }
+    samples.sort();
+    let rank = |p: f64| {
+        let rank = (p * samples.len() as f64).ceil() as usize;
+        samples[rank.max(1) - 1]
+    };
+    LatencyPercentiles {
+        p50: rank(0.50),
+        p95: rank(0.95),
+        p99: rank(0.99),
+        max: samples[samples.len() - 1],
+    }
+}
+
+/// Calls made by a load test and how long each one took
+#[derive(Default)]
+struct CallStats {
+    done: u64,
+    failed: u64,
+    // lines of the batches stored, not counted for searches
+    lines: u64,
+    latencies_ms: Vec<u64>,
+}
+
+impl CallStats {
+    fn record(&mut self, started: Instant, ok: bool, lines: u64) {
+        let elapsed = started.elapsed();
+        self.latencies_ms
+            .push(elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()));
+        if ok {
+            self.done += 1;
+            self.lines += lines;
+        } else {
+            self.failed += 1;
+        }
+    }
+}
+
+/// Stores the lines of a spec on its log and runs its searches with `access_token`, sending a
+/// progress event every second to `progress` and the report of the test once it's over. Lines
+/// are stored a batch at a time, a batch taking longer than its share of a second lowers the
+/// achieved rate rather than piling up calls, and so do slow searches.
+pub fn run_load_test(
+    cfg: Arc<RwLock<Config>>,
+    ingest_buffers: Arc<IngestBuffers>,
+    spec: LoadTestSpec,
+    access_token: Option<String>,
+    progress: mpsc::UnboundedSender<String>,
+) -> impl Future<Item = (), Error = ()> {
+    info!(
+        "Starting load test of {} at {} lines per second for {}s",
+        spec.log, spec.lines_per_sec, spec.duration_secs
+    );
+    let pool = generate_fixtures(&FixtureSpec {
+        lines: spec.lines_per_sec.min(LOADTEST_POOL_LINES) as usize,
+        seed: FIXTURES_DEFAULT_SEED,
+        densities: spec.densities.clone(),
+    })
+    .lines;
+    let ingest_stats = Arc::new(Mutex::new(CallStats::default()));
+    let query_stats = Arc::new(Mutex::new(CallStats::default()));
+    let started = Instant::now();
+
+    let ticks = spec.duration_secs * LOADTEST_TICKS_PER_SEC;
+    let every = Duration::from_millis(1000 / LOADTEST_TICKS_PER_SEC);
+    let store_cfg = Arc::clone(&cfg);
+    let search_buffers = Arc::clone(&ingest_buffers);
+    let store_stats = Arc::clone(&ingest_stats);
+    let events_query_stats = Arc::clone(&query_stats);
+    let log_name = spec.log.clone();
+    let lines_per_sec = spec.lines_per_sec;
+    let events = progress.clone();
+    let mut sent = 0u64;
+    let mut next_line = 0usize;
+    let ingest = Interval::new(Instant::now(), every)
+        .take(ticks)
+        .map_err(|e| error!("load test interval errored; err={:?}", e))
+        .zip(futures::stream::iter_ok(1..=ticks))
+        .for_each(move |(_, tick)| {
+            // the lines due by the end of this tick, so rates below the tick rate are kept too
+            let due = lines_per_sec * tick / LOADTEST_TICKS_PER_SEC;
+            let count = due - sent;
+            sent = due;
+            if tick % LOADTEST_TICKS_PER_SEC == 0 {
+                let ingested = store_stats.lock().unwrap().lines;
+                let searched = events_query_stats.lock().unwrap().done;
+                let _ = events.clone().try_send(
+                    json!({
+                        "elapsed_secs": tick / LOADTEST_TICKS_PER_SEC,
+                        "lines_stored": ingested,
+                        "queries_run": searched,
+                    })
+                    .to_string()
+                        + "\n",
+                );
+            }
+            if count == 0 {
+                return Either::A(future::ok(()));
+            }
+            let mut payload = String::new();
+            for _ in 0..count {
+                payload.push_str(&pool[next_line]);
+                payload.push('\n');
+                next_line = (next_line + 1) % pool.len();
+            }
+            let stats = Arc::clone(&store_stats);
+            let call_started = Instant::now();
+            Either::B(
+                Ingest::new(Arc::clone(&store_cfg))
+                    .store_payload(
+                        payload.as_bytes(),
+                        Arc::clone(&ingest_buffers),
+                        log_name.clone(),
+                        LOADTEST_USAGE_TOKEN,
+                        None,
+                        false,
+                        None,
+                    )
+                    .then(move |res| {
+                        let ok = res.map_or(false, |response| response.status().is_success());
+                        stats.lock().unwrap().record(call_started, ok, count);
+                        Ok(())
+                    }),
+            )
+        });
+
+    let searches = match access_token {
+        Some(access_token) if !spec.queries.is_empty() => {
+            let stats = Arc::clone(&query_stats);
+            let queries = spec.queries.clone();
+            let every = Duration::from_millis(1000 / spec.queries_per_sec);
+            let mut turn = 0;
+            Either::A(
+                Interval::new(Instant::now(), every)
+                    .take(spec.duration_secs * spec.queries_per_sec)
+                    .map_err(|e| error!("load test interval errored; err={:?}", e))
+                    .for_each(move |_| {
+                        let sql = queries[turn % queries.len()].clone();
+                        turn += 1;
+                        let stats = Arc::clone(&stats);
+                        let call_started = Instant::now();
+                        let search = run_search(
+                            Arc::clone(&cfg),
+                            sql,
+                            &access_token,
+                            Arc::clone(&search_buffers),
+                        );
+                        search.then(move |res| {
+                            stats
+                                .lock()
+                                .unwrap()
+                                .record(call_started, res.unwrap_or(false), 0);
+                            Ok(())
+                        })
+                    }),
+            )
+        }
+        _ => Either::B(future::ok(())),
+    };
+
+    let log_name = spec.log.clone();
+    ingest.join(searches).map(move |_| {
+        let elapsed = started.elapsed();
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+        let per_sec = |count: u64| {
+            if elapsed_secs > 0.0 {
+                count as f64 / elapsed_secs
+            } else {
+                0.0
+            }
+        };
+        let ingest = mem::replace(&mut *ingest_stats.lock().unwrap(), CallStats::default());
+        let queries = mem::replace(&mut *query_stats.lock().unwrap(), CallStats::default());
+        info!(
+            "Done with the load test of {}, {} lines stored in {:.1}s",
+            log_name, ingest.lines, elapsed_secs
+        );
+        let _ = progress.clone().try_send(
+            json!({
+                "action": "loadtest",
+                "done": true,
+                "log": log_name,
+                "elapsed_secs": elapsed_secs,
+                "ingest": {
+                    "lines": ingest.lines,
+                    "lines_per_sec": per_sec(ingest.lines),
+                    "batches": ingest.done,
+                    "failed_batches": ingest.failed,
+                    "latency_ms": latency_percentiles(ingest.latencies_ms),
+                },
+                "queries": {
+                    "run": queries.done,
+                    "failed": queries.failed,
+                    "queries_per_sec": per_sec(queries.done),
+                    "latency_ms": latency_percentiles(queries.latencies_ms),
+                },
+            })
+            .to_string()
+                + "\n",
+        );
+    })
+}
+
+/// Runs a search as `/search` would and reads all of its rows, whether it succeeded
+fn run_search(
+    cfg: Arc<RwLock<Config>>,
+    sql: String,
+    access_token: &str,
+    ingest_buffers: Arc<IngestBuffers>,
+) -> impl Future<Item = bool, Error = ()> {
+    let req = Request::post("/search").body(Body::from(sql)).unwrap();
+    Query::new(cfg)
+        .api_log_search(req, &access_token.to_string(), ingest_buffers)
+        .and_then(|response| {
+            let ok = response.status().is_success();
+            response.into_body().concat2().from_err().map(move |body| {
+                // errors past the first row are sent as the last line
+                let last = body.split(|b| *b == b'\n').filter(|l| !l.is_empty()).last();
+                ok && !last.map_or(false, |line| line.starts_with(b"{\"error\""))
+            })
+        })
+        .map_err(|e| error!("Load test search failed: {}", e))
+}
+
+#[cfg(test)]
+mod loadtest_tests {
+    use super::*;
+
+    #[test]
+    fn spec_from_json() {
+        let spec = LoadTestSpec::from_json(&json!({
+            "log": "scratch",
+            "lines_per_sec": 5000,
+            "fields": {"ip": 0.8},
+            "queries": ["SELECT $ip FROM scratch LIMIT 10"],
+        }))
+        .unwrap();
+        assert_eq!(spec.lines_per_sec, 5000);
+        assert_eq!(spec.duration_secs, 10);
+        assert_eq!(spec.queries_per_sec, 1);
+        assert_eq!(spec.densities.get("ip"), Some(&0.8));
+        assert_eq!(spec.queries.len(), 1);
+
+        assert!(LoadTestSpec::from_json(&json!({})).is_err());
+        assert!(LoadTestSpec::from_json(&json!({"log": "scratch", "lines_per_sec": 0})).is_err());
+        assert!(
+            LoadTestSpec::from_json(&json!({"log": "scratch", "duration_secs": 100_000})).is_err()
+        );
+        assert!(LoadTestSpec::from_json(&json!({"log": "scratch", "fields": {"ssn": 1}})).is_err());
+        assert!(LoadTestSpec::from_json(&json!({"log": "scratch", "queries": [1]})).is_err());
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        assert_eq!(
+            latency_percentiles(Vec::new()),
+            LatencyPercentiles::default()
+        );
+        let percentiles = latency_percentiles((1..=100).rev().collect());
+        assert_eq!(
+            percentiles,
+            LatencyPercentiles {
+                p50: 50,
+                p95: 95,
+                p99: 99,
+                max: 100,
+            }
+        );
+        assert_eq!(latency_percentiles(vec![7]).p50, 7);
+    }
+}
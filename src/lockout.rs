@@ -0,0 +1,319 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::constants::{
+    LOCKOUT_BASE_SECS, LOCKOUT_MAX_SECS, LOCKOUT_MAX_TRACKED, LOCKOUT_RESET_SECS, LOCKOUT_THRESHOLD,
+};
+
+lazy_static! {
+    static ref AUTH_FAILURES: Mutex<LockoutTable> = Mutex::new(LockoutTable::default());
+}
+
+static AUTH_FAILURES_TOTAL: AtomicUsize = AtomicUsize::new(0);
+static AUTH_LOCKOUTS_TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Failed token validations of a client address or an access key
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed token validations by client address and by access key. Past `LOCKOUT_THRESHOLD`
+/// failures in a row they are locked out, for twice as long on every further failure.
+#[derive(Default, Debug)]
+struct LockoutTable {
+    failures: HashMap<String, Failures>,
+}
+
+impl LockoutTable {
+    /// Time left for the longest lockout of the keys, if any is locked out
+    fn locked_for(&self, keys: &[String], now: Instant) -> Option<Duration> {
+        keys.iter()
+            .filter_map(|key| self.failures.get(key))
+            .filter_map(|f| f.locked_until)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| until - now)
+    }
+
+    fn record_failure(&mut self, keys: &[String], now: Instant) {
+        if self.failures.len() >= LOCKOUT_MAX_TRACKED {
+            self.prune(now);
+        }
+        for key in keys {
+            // usernames are whatever clients send, past the limit only their addresses are
+            // tracked, which lock them out all the same
+            if key.starts_with("user:")
+                && self.failures.len() >= LOCKOUT_MAX_TRACKED
+                && !self.failures.contains_key(key)
+            {
+                continue;
+            }
+            let failures = self.failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            // failures long ago are forgiven
+            if now - failures.last_failure > Duration::from_secs(LOCKOUT_RESET_SECS) {
+                failures.count = 0;
+            }
+            failures.count += 1;
+            failures.last_failure = now;
+            if failures.count >= LOCKOUT_THRESHOLD {
+                failures.locked_until = Some(now + lockout_duration(failures.count));
+            }
+        }
+    }
+
+    fn record_success(&mut self, keys: &[String]) {
+        for key in keys {
+            self.failures.remove(key);
+        }
+    }
+
+    /// Forgets the keys that are neither locked out nor failed recently
+    fn prune(&mut self, now: Instant) {
+        let reset = Duration::from_secs(LOCKOUT_RESET_SECS);
+        self.failures.retain(|_, f| {
+            f.locked_until.map_or(false, |until| until > now) || now - f.last_failure <= reset
+        });
+    }
+
+    fn locked_count(&self, now: Instant) -> usize {
+        self.failures
+            .values()
+            .filter(|f| f.locked_until.map_or(false, |until| until > now))
+            .count()
+    }
+}
+
+/// Lockout after `count` failures in a row, doubling from `LOCKOUT_BASE_SECS` up to
+/// `LOCKOUT_MAX_SECS`
+fn lockout_duration(count: u32) -> Duration {
+    let doublings = count.saturating_sub(LOCKOUT_THRESHOLD).min(31);
+    let secs = LOCKOUT_BASE_SECS.saturating_mul(1 << doublings);
+    Duration::from_secs(secs.min(LOCKOUT_MAX_SECS))
+}
+
+/// Keys failures are tracked under for a request, its client address and its access key as used
+/// from that address. Failures of an access key only lock out the client they come from, else
+/// anyone could lock the owner of a token out by failing with its access key.
+pub fn lockout_keys(client: Option<IpAddr>, access_key: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    if let Some(ip) = client {
+        keys.push(format!("ip:{}", ip));
+    }
+    if access_key.len() >= 16 && access_key.is_char_boundary(16) {
+        match client {
+            Some(ip) => keys.push(format!("token:{}@{}", &access_key[0..16], ip)),
+            None => keys.push(format!("token:{}", &access_key[0..16])),
+        }
+    }
+    keys
+}
+
+/// Keys the failed logins of a user with an identity provider are tracked under, the client
+/// address, so a client can't guess passwords across usernames, and the username as used from
+/// it, so one user failing doesn't lock out the others
+pub fn login_lockout_keys(client: Option<IpAddr>, username: &str) -> Vec<String> {
+    match client {
+        Some(ip) => vec![format!("ip:{}", ip), format!("user:{}@{}", username, ip)],
+        None => vec![format!("user:{}", username)],
+    }
+}
+
+/// Time left for the lockout of a request, counting it as rejected if there is one
+pub fn locked_for(keys: &[String]) -> Option<Duration> {
+    let locked = AUTH_FAILURES
+        .lock()
+        .unwrap()
+        .locked_for(keys, Instant::now());
+    if locked.is_some() {
+        AUTH_LOCKOUTS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    }
+    locked
+}
+
+pub fn record_failure(keys: &[String]) {
+    AUTH_FAILURES_TOTAL.fetch_add(1, Ordering::Relaxed);
+    AUTH_FAILURES
+        .lock()
+        .unwrap()
+        .record_failure(keys, Instant::now());
+}
+
+/// Clears the failures of an access key from a client once its secret is right. Those of the
+/// client address are kept, else a client holding a valid token could keep guessing the secrets
+/// of others.
+pub fn record_success(client: Option<IpAddr>, access_key: &str) {
+    let keys: Vec<String> = lockout_keys(client, access_key)
+        .into_iter()
+        .filter(|key| key.starts_with("token:"))
+        .collect();
+    AUTH_FAILURES.lock().unwrap().record_success(&keys);
+}
+
+/// Clears the failed logins of a user once it logged in. Those of the client address are kept,
+/// as for tokens, else a client able to log in as one user could keep guessing the others.
+pub fn record_login(keys: &[String]) {
+    let keys: Vec<String> = keys
+        .iter()
+        .filter(|key| key.starts_with("user:"))
+        .cloned()
+        .collect();
+    AUTH_FAILURES.lock().unwrap().record_success(&keys);
+}
+
+/// Authentication counters in the Prometheus text format
+pub fn auth_metrics() -> String {
+    let locked = AUTH_FAILURES.lock().unwrap().locked_count(Instant::now());
+    format!(
+        "# HELP minsql_auth_failures_total Failed token validations.\n\
+         # TYPE minsql_auth_failures_total counter\n\
+         minsql_auth_failures_total {}\n\
+         # HELP minsql_auth_lockouts_total Requests rejected while locked out.\n\
+         # TYPE minsql_auth_lockouts_total counter\n\
+         minsql_auth_lockouts_total {}\n\
+         # HELP minsql_auth_locked Client addresses and access keys locked out.\n\
+         # TYPE minsql_auth_locked gauge\n\
+         minsql_auth_locked {}\n",
+        AUTH_FAILURES_TOTAL.load(Ordering::Relaxed),
+        AUTH_LOCKOUTS_TOTAL.load(Ordering::Relaxed),
+        locked
+    )
+}
+
+#[cfg(test)]
+mod lockout_tests {
+    use super::*;
+
+    #[test]
+    fn lockout_after_threshold() {
+        let mut table = LockoutTable::default();
+        let keys = lockout_keys(Some("10.0.0.1".parse().unwrap()), "abcdefghijklmnop");
+        assert_eq!(keys, vec!["ip:10.0.0.1", "token:abcdefghijklmnop@10.0.0.1"]);
+        let start = Instant::now();
+        for _ in 0..LOCKOUT_THRESHOLD - 1 {
+            table.record_failure(&keys, start);
+        }
+        assert_eq!(table.locked_for(&keys, start), None);
+        table.record_failure(&keys, start);
+        assert_eq!(
+            table.locked_for(&keys, start),
+            Some(Duration::from_secs(LOCKOUT_BASE_SECS))
+        );
+        // further failures double it
+        table.record_failure(&keys, start);
+        assert_eq!(
+            table.locked_for(&keys, start),
+            Some(Duration::from_secs(LOCKOUT_BASE_SECS * 2))
+        );
+        assert_eq!(table.locked_count(start), 2);
+        // the lockout of either key applies
+        assert!(table.locked_for(&keys[1..], start).is_some());
+        table.record_success(&keys[1..]);
+        assert!(table.locked_for(&keys[1..], start).is_none());
+        assert!(table.locked_for(&keys, start).is_some());
+        // and it expires
+        let later = start + Duration::from_secs(LOCKOUT_BASE_SECS * 2 + 1);
+        assert_eq!(table.locked_for(&keys, later), None);
+    }
+
+    #[test]
+    fn failures_only_lock_out_their_client() {
+        let mut table = LockoutTable::default();
+        let attacker = lockout_keys(Some("10.0.0.3".parse().unwrap()), "abcdefghijklmnop");
+        let owner = lockout_keys(Some("10.0.0.4".parse().unwrap()), "abcdefghijklmnop");
+        let start = Instant::now();
+        for _ in 0..LOCKOUT_THRESHOLD {
+            table.record_failure(&attacker, start);
+        }
+        assert!(table.locked_for(&attacker, start).is_some());
+        assert_eq!(table.locked_for(&owner, start), None);
+    }
+
+    #[test]
+    fn logins_locked_out_by_username() {
+        let mut table = LockoutTable::default();
+        let client = Some("10.0.0.5".parse().unwrap());
+        let alice = login_lockout_keys(client, "alice");
+        assert_eq!(alice, vec!["ip:10.0.0.5", "user:alice@10.0.0.5"]);
+        let start = Instant::now();
+        for _ in 0..LOCKOUT_THRESHOLD {
+            table.record_failure(&alice[1..], start);
+        }
+        assert!(table.locked_for(&alice, start).is_some());
+        assert_eq!(
+            table.locked_for(&login_lockout_keys(client, "bob"), start),
+            None
+        );
+    }
+
+    #[test]
+    fn logins_across_usernames_lock_out_the_client() {
+        let mut table = LockoutTable::default();
+        let client = Some("10.0.0.6".parse().unwrap());
+        let start = Instant::now();
+        for n in 0..LOCKOUT_THRESHOLD {
+            let keys = login_lockout_keys(client, &format!("user{}", n));
+            assert_eq!(table.locked_for(&keys, start), None);
+            table.record_failure(&keys, start);
+        }
+        let keys = login_lockout_keys(client, "someone-else");
+        assert!(table.locked_for(&keys, start).is_some());
+        // other clients can still log in as any of them
+        let other = login_lockout_keys(Some("10.0.0.7".parse().unwrap()), "user0");
+        assert_eq!(table.locked_for(&other, start), None);
+    }
+
+    #[test]
+    fn lockout_durations_are_capped() {
+        assert_eq!(
+            lockout_duration(LOCKOUT_THRESHOLD),
+            Duration::from_secs(LOCKOUT_BASE_SECS)
+        );
+        assert_eq!(
+            lockout_duration(LOCKOUT_THRESHOLD + 100),
+            Duration::from_secs(LOCKOUT_MAX_SECS)
+        );
+    }
+
+    #[test]
+    fn old_failures_are_forgiven() {
+        let mut table = LockoutTable::default();
+        let keys = vec!["ip:10.0.0.2".to_string()];
+        let start = Instant::now();
+        for _ in 0..LOCKOUT_THRESHOLD - 1 {
+            table.record_failure(&keys, start);
+        }
+        let later = start + Duration::from_secs(LOCKOUT_RESET_SECS + 1);
+        table.record_failure(&keys, later);
+        assert_eq!(table.locked_for(&keys, later), None);
+        table.prune(later + Duration::from_secs(LOCKOUT_RESET_SECS + 1));
+        assert!(table.failures.is_empty());
+    }
+}
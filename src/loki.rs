@@ -0,0 +1,632 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use futures::{future, Future, Stream};
+use hyper::{header, Body, Request, Response, StatusCode};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::constants::{APP_JSON, LOKI_LOG_LABEL};
+use crate::http::{return_400, return_403, ResponseFuture};
+use crate::ingest::{Ingest, IngestBuffers};
+use crate::trace::request_trace;
+
+/// A stream of a push, its labels and its entries in order
+#[derive(Debug, PartialEq)]
+struct PushStream {
+    labels: HashMap<String, String>,
+    entries: Vec<PushEntry>,
+}
+
+/// A line of a stream along with its timestamp, as RFC 3339 on UTC
+#[derive(Debug, PartialEq)]
+struct PushEntry {
+    timestamp: String,
+    line: String,
+}
+
+/// An entry as it's stored, one line of JSON with the labels of its stream
+#[derive(Serialize)]
+struct StoredEntry<'a> {
+    timestamp: &'a str,
+    labels: BTreeMap<&'a str, &'a str>,
+    line: &'a str,
+}
+
+/// Push body sent as JSON, either with `stream` and `values` or, by older agents, with `labels`
+/// and `entries`
+#[derive(Deserialize)]
+struct JsonPush {
+    streams: Vec<JsonStream>,
+}
+
+#[derive(Deserialize)]
+struct JsonStream {
+    #[serde(default)]
+    stream: Option<HashMap<String, String>>,
+    #[serde(default)]
+    values: Vec<(String, String)>,
+    #[serde(default)]
+    labels: Option<String>,
+    #[serde(default)]
+    entries: Vec<JsonEntry>,
+}
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    ts: String,
+    line: String,
+}
+
+pub struct Loki {
+    config: Arc<RwLock<Config>>,
+}
+
+impl Loki {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> Loki {
+        Loki { config: cfg }
+    }
+
+    /// Stores the entries of a Loki push on the logs its streams map to. The whole push is refused
+    /// if any of its streams maps to no log or to one the token has no access to. When only some
+    /// of the logs store their entries the push is answered as a success listing the ones that
+    /// failed, agents retry failed pushes whole and would store the rest twice.
+    pub fn push(
+        &self,
+        req: Request<Body>,
+        access_token: String,
+        log_ingest_buffers: Arc<IngestBuffers>,
+    ) -> ResponseFuture {
+        let cfg = Arc::clone(&self.config);
+        let is_json = match req.headers().get(header::CONTENT_TYPE) {
+            Some(value) => value.to_str().unwrap_or("").starts_with(APP_JSON),
+            None => false,
+        };
+        let trace = request_trace(&req);
+        Box::new(
+            req.into_body()
+                .concat2()
+                .from_err()
+                .and_then(move |entire_body| {
+                    let parsed = if is_json {
+                        parse_json_push(&entire_body)
+                    } else {
+                        parse_protobuf_push(&entire_body)
+                    };
+                    let streams = match parsed {
+                        Ok(streams) => streams,
+                        Err(e) => return future::Either::A(future::ok(return_400(&e))),
+                    };
+                    // group the entries by log, keeping their order
+                    let mut log_lines: Vec<(String, Vec<String>)> = Vec::new();
+                    let log_names: Vec<Option<String>> = {
+                        let read_cfg = cfg.read().unwrap();
+                        streams
+                            .iter()
+                            .map(|stream| log_for_labels(&read_cfg, &stream.labels))
+                            .collect()
+                    };
+                    let auth_c = Auth::new(Arc::clone(&cfg));
+                    for (stream, log_name) in streams.into_iter().zip(log_names) {
+                        let log_name = match log_name {
+                            Some(log_name) => log_name,
+                            None => {
+                                return future::Either::A(future::ok(return_400(&format!(
+                                    "No log takes the stream {}",
+                                    format_labels(&stream.labels)
+                                ))))
+                            }
+                        };
+                        if !auth_c.token_has_access_to_log(&access_token, &log_name) {
+                            return future::Either::A(future::ok(return_403()));
+                        }
+                        let lines = stored_lines(&stream);
+                        match log_lines.iter_mut().find(|(name, _)| *name == log_name) {
+                            Some((_, log_lines)) => log_lines.extend(lines),
+                            None => log_lines.push((log_name, lines)),
+                        }
+                    }
+                    let ingest_c = Ingest::new(Arc::clone(&cfg));
+                    let stores = log_lines
+                        .into_iter()
+                        .filter(|(_, lines)| !lines.is_empty())
+                        .map(|(log_name, lines)| {
+                            let body = lines.join("\n") + "\n";
+                            ingest_c
+                                .store_payload(
+                                    body.as_bytes(),
+                                    Arc::clone(&log_ingest_buffers),
+                                    log_name.clone(),
+                                    &access_token,
+                                    None,
+                                    false,
+                                    trace.as_ref(),
+                                )
+                                .map(move |response| (log_name, response))
+                        })
+                        .collect::<Vec<_>>();
+                    future::Either::B(future::join_all(stores).map(push_response))
+                }),
+        )
+    }
+}
+
+/// Loki answers successful pushes with no content. A push none of whose logs stored anything
+/// gets the response of the first failure, so it's retried, one some did store gets the logs
+/// that failed instead.
+fn push_response(stored: Vec<(String, Response<Body>)>) -> Response<Body> {
+    let stored_count = stored
+        .iter()
+        .filter(|(_, response)| response.status().is_success())
+        .count();
+    let mut failed: Vec<(String, Response<Body>)> = stored
+        .into_iter()
+        .filter(|(_, response)| !response.status().is_success())
+        .collect();
+    if failed.is_empty() {
+        return Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+    }
+    if stored_count == 0 {
+        return failed.remove(0).1;
+    }
+    let failed: Vec<serde_json::Value> = failed
+        .iter()
+        .map(|(log_name, response)| {
+            error!(
+                "Loki push partially stored, {} failed with {}",
+                log_name,
+                response.status()
+            );
+            json!({"log": log_name, "status": response.status().as_u16()})
+        })
+        .collect();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .body(Body::from(json!({ "failed": failed }).to_string()))
+        .unwrap()
+}
+
+/// The entries of a stream as they're stored, with their timestamp and the labels of the stream
+fn stored_lines(stream: &PushStream) -> Vec<String> {
+    let labels: BTreeMap<&str, &str> = stream
+        .labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    stream
+        .entries
+        .iter()
+        .map(|entry| {
+            serde_json::to_string(&StoredEntry {
+                timestamp: &entry.timestamp,
+                labels: labels.clone(),
+                line: &entry.line,
+            })
+            .unwrap()
+        })
+        .collect()
+}
+
+/// A timestamp given in nanoseconds since the epoch, as the JSON pushes send them
+fn timestamp_from_nanos(nanos: i64) -> Result<String, String> {
+    let (mut seconds, mut rest) = (nanos / 1_000_000_000, nanos % 1_000_000_000);
+    if rest < 0 {
+        seconds -= 1;
+        rest += 1_000_000_000;
+    }
+    format_timestamp(seconds, rest as u32)
+}
+
+fn format_timestamp(seconds: i64, nanos: u32) -> Result<String, String> {
+    match Utc.timestamp_opt(seconds, nanos).single() {
+        Some(timestamp) => Ok(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        None => Err("Malformed timestamp".to_string()),
+    }
+}
+
+fn parse_json_push(body: &[u8]) -> Result<Vec<PushStream>, String> {
+    let push: JsonPush =
+        serde_json::from_slice(body).map_err(|e| format!("Malformed push: {}", e))?;
+    push.streams
+        .into_iter()
+        .map(|stream| {
+            let labels = match (stream.stream, stream.labels) {
+                (Some(labels), _) => labels,
+                (None, Some(labels)) => parse_labels(&labels)?,
+                (None, None) => return Err("A stream has no labels".to_string()),
+            };
+            let mut entries = Vec::new();
+            for (ts, line) in stream.values {
+                let nanos = ts
+                    .parse::<i64>()
+                    .map_err(|_| format!("Malformed timestamp {}", ts))?;
+                entries.push(PushEntry {
+                    timestamp: timestamp_from_nanos(nanos)?,
+                    line,
+                });
+            }
+            for entry in stream.entries {
+                let timestamp = DateTime::parse_from_rfc3339(&entry.ts)
+                    .map_err(|_| format!("Malformed timestamp {}", entry.ts))?;
+                entries.push(PushEntry {
+                    timestamp: timestamp
+                        .with_timezone(&Utc)
+                        .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    line: entry.line,
+                });
+            }
+            Ok(PushStream { labels, entries })
+        })
+        .collect()
+}
+
+/// Parses the snappy compressed protobuf push promtail sends by default, whose messages are
+/// `PushRequest { repeated Stream streams = 1; }`,
+/// `Stream { string labels = 1; repeated Entry entries = 2; }` and
+/// `Entry { Timestamp timestamp = 1; string line = 2; }`. Unknown fields are skipped.
+fn parse_protobuf_push(body: &[u8]) -> Result<Vec<PushStream>, String> {
+    let decoded = snap::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| format!("Could not decompress the push: {}", e))?;
+    let mut streams = Vec::new();
+    let mut request = ProtoReader::new(&decoded);
+    while let Some((field, value)) = request.next_field()? {
+        if let (1, ProtoValue::Bytes(stream)) = (field, value) {
+            streams.push(parse_protobuf_stream(stream)?);
+        }
+    }
+    Ok(streams)
+}
+
+fn parse_protobuf_stream(message: &[u8]) -> Result<PushStream, String> {
+    let mut labels = None;
+    let mut entries = Vec::new();
+    let mut stream = ProtoReader::new(message);
+    while let Some((field, value)) = stream.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(value)) => labels = Some(parse_labels(&proto_string(value)?)?),
+            (2, ProtoValue::Bytes(entry)) => entries.push(parse_protobuf_entry(entry)?),
+            _ => (),
+        }
+    }
+    match labels {
+        Some(labels) => Ok(PushStream { labels, entries }),
+        None => Err("A stream has no labels".to_string()),
+    }
+}
+
+/// Parses an entry and its `Timestamp { int64 seconds = 1; int32 nanos = 2; }`
+fn parse_protobuf_entry(message: &[u8]) -> Result<PushEntry, String> {
+    let (mut seconds, mut nanos) = (0, 0);
+    let mut line = String::new();
+    let mut entry = ProtoReader::new(message);
+    while let Some((field, value)) = entry.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(timestamp)) => {
+                let mut timestamp = ProtoReader::new(timestamp);
+                while let Some((field, value)) = timestamp.next_field()? {
+                    match (field, value) {
+                        (1, ProtoValue::Varint(value)) => seconds = value as i64,
+                        (2, ProtoValue::Varint(value)) => nanos = value as i64,
+                        _ => (),
+                    }
+                }
+            }
+            (2, ProtoValue::Bytes(value)) => line = proto_string(value)?,
+            _ => (),
+        }
+    }
+    if nanos < 0 || nanos >= 1_000_000_000 {
+        return Err("Malformed timestamp".to_string());
+    }
+    Ok(PushEntry {
+        timestamp: format_timestamp(seconds, nanos as u32)?,
+        line,
+    })
+}
+
+fn proto_string(value: &[u8]) -> Result<String, String> {
+    String::from_utf8(value.to_vec()).map_err(|_| "A string is not valid UTF-8".to_string())
+}
+
+#[derive(Debug, PartialEq)]
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Reads the fields of a protobuf message, only as much of the wire format as pushes use
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(buf: &'a [u8]) -> ProtoReader<'a> {
+        ProtoReader { buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or("Truncated protobuf message")?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Malformed protobuf varint".to_string())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < len {
+            return Err("Truncated protobuf message".to_string());
+        }
+        let value = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(value)
+    }
+
+    /// The number and value of the next field, `None` at the end of the message
+    fn next_field(&mut self) -> Result<Option<(u64, ProtoValue<'a>)>, String> {
+        if self.pos == self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                ProtoValue::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                ProtoValue::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                ProtoValue::Fixed
+            }
+            wire_type => return Err(format!("Unsupported protobuf wire type {}", wire_type)),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// Parses a Prometheus style label set, ie: `{job="varlogs", filename="/var/log/syslog"}`
+fn parse_labels(labels: &str) -> Result<HashMap<String, String>, String> {
+    let malformed = || format!("Malformed labels: {}", labels);
+    let trimmed = labels.trim();
+    if !trimmed.starts_with('{') || !trimmed.ends_with('}') {
+        return Err(malformed());
+    }
+    let mut parsed = HashMap::new();
+    let mut chars = trimmed[1..trimmed.len() - 1].chars().peekable();
+    loop {
+        while chars
+            .peek()
+            .map_or(false, |c| c.is_whitespace() || *c == ',')
+        {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Ok(parsed);
+        }
+        let name: String = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim()
+            .to_string();
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if name.is_empty() || chars.next() != Some('"') {
+            return Err(malformed());
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some('\\') => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(c) => value.push(c),
+                    None => return Err(malformed()),
+                },
+                Some('"') => break,
+                Some(c) => value.push(c),
+                None => return Err(malformed()),
+            }
+        }
+        parsed.insert(name, value);
+    }
+}
+
+fn format_labels(labels: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}={:?}", name, value))
+        .collect();
+    pairs.sort();
+    format!("{{{}}}", pairs.join(", "))
+}
+
+/// The log a stream is stored on, the first whose `loki_labels` all match the stream, else the
+/// log named by its `job` label
+fn log_for_labels(cfg: &Config, labels: &HashMap<String, String>) -> Option<String> {
+    let mut names: Vec<&String> = cfg.log.keys().collect();
+    names.sort();
+    let matching = names.into_iter().find(|name| {
+        let loki_labels = &cfg.log[*name].loki_labels;
+        !loki_labels.is_empty()
+            && loki_labels
+                .iter()
+                .all(|(label, value)| labels.get(label) == Some(value))
+    });
+    match matching {
+        Some(name) => Some(name.clone()),
+        None => labels
+            .get(LOKI_LOG_LABEL)
+            .filter(|name| cfg.log.contains_key(*name))
+            .cloned(),
+    }
+}
+
+#[cfg(test)]
+mod loki_tests {
+    use super::*;
+    use crate::config::{Log, Server};
+
+    #[test]
+    fn parse_label_sets() {
+        let labels = parse_labels(r#"{job="varlogs", filename="/var/log/\"x\".log"}"#).unwrap();
+        assert_eq!(labels["job"], "varlogs");
+        assert_eq!(labels["filename"], r#"/var/log/"x".log"#);
+        assert!(parse_labels("{}").unwrap().is_empty());
+        assert!(parse_labels(r#"{job="varlogs"#).is_err());
+        assert!(parse_labels(r#"job="varlogs""#).is_err());
+        assert!(parse_labels(r#"{job=varlogs}"#).is_err());
+    }
+
+    #[test]
+    fn parse_json_pushes() {
+        let body = br#"{"streams": [
+            {"stream": {"job": "varlogs"}, "values": [["1565000000000000000", "line 1"], ["1565000000000000001", "line 2"]]},
+            {"labels": "{job=\"nginx\"}", "entries": [{"ts": "2019-08-05T10:00:00Z", "line": "line 3"}]}
+        ]}"#;
+        let streams = parse_json_push(body).unwrap();
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].labels["job"], "varlogs");
+        assert_eq!(
+            streams[0].entries,
+            vec![
+                PushEntry {
+                    timestamp: "2019-08-05T10:13:20Z".to_string(),
+                    line: "line 1".to_string(),
+                },
+                PushEntry {
+                    timestamp: "2019-08-05T10:13:20.000000001Z".to_string(),
+                    line: "line 2".to_string(),
+                },
+            ]
+        );
+        assert_eq!(streams[1].labels["job"], "nginx");
+        assert_eq!(
+            stored_lines(&streams[1]),
+            vec![
+                r#"{"timestamp":"2019-08-05T10:00:00Z","labels":{"job":"nginx"},"line":"line 3"}"#
+            ]
+        );
+        assert!(parse_json_push(br#"{"streams": [{"values": []}]}"#).is_err());
+    }
+
+    fn proto_field(field: u8, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![field << 3 | 2, value.len() as u8];
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    #[test]
+    fn parse_protobuf_pushes() {
+        // timestamp { seconds: 1565000000 } followed by the line
+        let mut entry = proto_field(1, &[0x08, 0x80, 0xbd, 0xa0, 0xea, 0x05]);
+        entry.extend(proto_field(2, b"line 1"));
+        let mut stream = proto_field(1, br#"{job="varlogs"}"#);
+        stream.extend(proto_field(2, &entry));
+        let request = proto_field(1, &stream);
+        let compressed = snap::Encoder::new().compress_vec(&request).unwrap();
+        let streams = parse_protobuf_push(&compressed).unwrap();
+        assert_eq!(
+            streams,
+            vec![PushStream {
+                labels: parse_labels(r#"{job="varlogs"}"#).unwrap(),
+                entries: vec![PushEntry {
+                    timestamp: "2019-08-05T10:13:20Z".to_string(),
+                    line: "line 1".to_string(),
+                }],
+            }]
+        );
+        // truncated pushes are refused
+        let truncated = snap::Encoder::new()
+            .compress_vec(&request[..request.len() - 2])
+            .unwrap();
+        assert!(parse_protobuf_push(&truncated).is_err());
+    }
+
+    #[test]
+    fn partial_pushes_succeed() {
+        let response = |status: StatusCode| {
+            Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let stored = |statuses: Vec<StatusCode>| {
+            push_response(
+                statuses
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, status)| (format!("log{}", i), response(status)))
+                    .collect(),
+            )
+            .status()
+        };
+        assert_eq!(stored(vec![StatusCode::OK]), StatusCode::NO_CONTENT);
+        // nothing was stored, the push can be retried
+        assert_eq!(
+            stored(vec![StatusCode::SERVICE_UNAVAILABLE]),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // retrying would store the lines of the first log twice
+        assert_eq!(
+            stored(vec![StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn map_streams_to_logs() {
+        let mut cfg = Config::new(Server::default());
+        cfg.log.insert("varlogs".to_string(), Log::default());
+        let mut loki_labels = HashMap::new();
+        loki_labels.insert("app".to_string(), "nginx".to_string());
+        cfg.log.insert(
+            "weblogs".to_string(),
+            Log {
+                loki_labels,
+                ..Default::default()
+            },
+        );
+        let labels = parse_labels(r#"{job="varlogs"}"#).unwrap();
+        assert_eq!(log_for_labels(&cfg, &labels), Some("varlogs".to_string()));
+        let labels = parse_labels(r#"{job="varlogs", app="nginx"}"#).unwrap();
+        assert_eq!(log_for_labels(&cfg, &labels), Some("weblogs".to_string()));
+        let labels = parse_labels(r#"{job="syslog", app="postgres"}"#).unwrap();
+        assert_eq!(log_for_labels(&cfg, &labels), None);
+    }
+}
@@ -14,11 +14,34 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::env;
+use std::process;
+
 use minsql::bootstrap;
+use minsql::fixtures::gen_fixtures_command;
+use minsql::preflight::preflight_command;
 
 fn main() {
     pretty_env_logger::init();
 
+    // Developer commands run instead of the server
+    if env::args().nth(1).as_ref().map(|arg| arg.as_str()) == Some("gen-fixtures") {
+        if let Err(e) = gen_fixtures_command(env::args().skip(1)) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Checks the deployment and exits, for CI/CD before rolling a new configuration
+    if env::args().nth(1).as_ref().map(|arg| arg.as_str()) == Some("preflight") {
+        if let Err(e) = preflight_command(env::args().skip(1)) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     // Load configuration and start MinSQL
     bootstrap();
 }
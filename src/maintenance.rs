@@ -0,0 +1,1156 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::future::Either;
+use futures::{future, stream, Future, Stream};
+use log::{error, info};
+use serde_json::json;
+use sqlparser::ast::Statement;
+use tokio::sync::mpsc;
+use tokio::timer::Delay;
+use uuid::Uuid;
+
+use crate::bloom::{bloom_key, logical_lines, BloomFilter};
+use crate::compression::{decompress, Compression};
+use crate::config::{Config, DataStore, Log};
+use crate::constants::{
+    COMPACT_MAX_BYTES, ENCODING_BASE64, ENCODING_METADATA, RECEIVED_FIRST_METADATA,
+    RECEIVED_LAST_METADATA, REINDEX_CURSOR_EVERY, REINDEX_PREFIX,
+};
+use crate::ingest::{delete_oldest_objects, evicts_oldest, Ingest, IngestBuffers};
+use crate::meta::ds_for_metabucket;
+use crate::naming::ObjectNaming;
+use crate::pagination::{moved_key, MovedLines};
+use crate::query::{decode_lines, matching_lines, QueryParsing};
+use crate::storage::{
+    delete_object, get_object, get_object_metabucket, is_base64_object, list_msl_bucket_objects,
+    put_object, read_bloom_filter, write_object_metabucket, LogObject,
+};
+use crate::tiering::{cold_tier, migrate_datastore};
+
+type MaintenanceFuture = Box<dyn Future<Item = u64, Error = ()> + Send>;
+
+/// Background jobs of a log that can be run on demand
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaintenanceAction {
+    // merges the objects written in the same hour
+    Compact,
+    // enforces the quota and tiering policies
+    Expire,
+    // writes the missing bloom filters, or every one of them
+    Reindex,
+}
+
+impl MaintenanceAction {
+    pub fn parse(action: &str) -> Option<MaintenanceAction> {
+        match action {
+            "compact" => Some(MaintenanceAction::Compact),
+            "expire" => Some(MaintenanceAction::Expire),
+            "reindex" => Some(MaintenanceAction::Reindex),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaintenanceAction::Compact => "compact",
+            MaintenanceAction::Expire => "expire",
+            MaintenanceAction::Reindex => "reindex",
+        }
+    }
+
+    /// Why the action can't run on the log, if it can't
+    pub fn refused_for(&self, log: &Log, now: &DateTime<Utc>) -> Option<String> {
+        match self {
+            // merged objects are named apart on every datastore, so they'd no longer be replicas
+            MaintenanceAction::Compact if log.replicated => {
+                Some("The objects of a replicated log aren't compacted".to_string())
+            }
+            // both delete objects of the log
+            MaintenanceAction::Compact | MaintenanceAction::Expire => log
+                .held_until(now)
+                .map(|until| format!("The log is under legal hold until {}", until.to_rfc3339())),
+            MaintenanceAction::Reindex if !log.bloom_filters => {
+                Some("The log has bloom filters disabled".to_string())
+            }
+            MaintenanceAction::Reindex => None,
+        }
+    }
+}
+
+/// Sends the progress events of a maintenance action as JSON lines
+#[derive(Clone)]
+pub struct Progress {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl Progress {
+    pub fn new(tx: mpsc::UnboundedSender<String>) -> Progress {
+        Progress { tx }
+    }
+
+    /// Sends an event, the action goes on if nobody listens anymore
+    fn send(&self, event: serde_json::Value) {
+        let _ = self.tx.clone().try_send(event.to_string() + "\n");
+    }
+
+    fn object(&self, key: &str, datastore: &DataStore, result: &str) {
+        self.send(json!({
+            "object": key,
+            "datastore": datastore.name,
+            "result": result,
+        }));
+    }
+
+    fn purged(&self, key: &str, datastore: &DataStore, result: &str, lines: u64) {
+        self.send(json!({
+            "object": key,
+            "datastore": datastore.name,
+            "result": result,
+            "lines": lines,
+        }));
+    }
+
+    fn failed(&self, key: &str, datastore: &DataStore, error: &str) {
+        self.send(json!({
+            "object": key,
+            "datastore": datastore.name,
+            "result": "failed",
+            "error": error,
+        }));
+    }
+}
+
+pub struct Maintenance {
+    config: Arc<RwLock<Config>>,
+}
+
+impl Maintenance {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> Maintenance {
+        Maintenance { config: cfg }
+    }
+
+    /// Runs an action on a log right away, sending an event for every object it handles and a
+    /// last one with the count of objects once it's done. `reindex_options` only apply to a
+    /// reindex.
+    pub fn run(
+        &self,
+        log_name: &str,
+        action: MaintenanceAction,
+        reindex_options: ReindexOptions,
+        ingest_buffers: Arc<IngestBuffers>,
+        progress: Progress,
+    ) -> impl Future<Item = (), Error = ()> {
+        let read_cfg = self.config.read().unwrap();
+        let log = match read_cfg.log.get(log_name) {
+            Some(log) => log.clone(),
+            None => return Either::B(future::ok(())),
+        };
+        let datastores: Vec<DataStore> = log
+            .all_datastores()
+            .iter()
+            .filter_map(|ds_name| read_cfg.datastore.get(ds_name).cloned())
+            .collect();
+        let log_name = log_name.to_string();
+        info!("Running {} on {}", action.name(), log_name);
+        let job: MaintenanceFuture = match action {
+            MaintenanceAction::Compact => Box::new(compact(
+                log_name.clone(),
+                datastores,
+                log.bloom_filters,
+                Compression::for_log(&log),
+                ObjectNaming::for_log(&log),
+                ingest_buffers,
+                progress.clone(),
+            )),
+            MaintenanceAction::Expire => expire(
+                &read_cfg,
+                log,
+                log_name.clone(),
+                datastores,
+                ingest_buffers,
+                progress.clone(),
+            ),
+            MaintenanceAction::Reindex => {
+                let naming = ObjectNaming::for_log(&log);
+                Box::new(reindex(
+                    Arc::clone(&self.config),
+                    log_name.clone(),
+                    datastores,
+                    naming,
+                    reindex_options,
+                    progress.clone(),
+                ))
+            }
+        };
+        Either::A(job.map(move |objects| {
+            info!("Done running {} on {}", action.name(), log_name);
+            progress.send(json!({
+                "action": action.name(),
+                "done": true,
+                "objects": objects,
+            }));
+        }))
+    }
+
+    /// Removes the lines of a log matching the statement planned by `Query::plan_purge`, once its
+    /// ingest buffer is flushed. Objects left without lines are deleted, the others rewritten
+    /// along with their bloom filter. Sends an event for every object it changes and a last one
+    /// with the count of objects and lines, the purge fails if any object couldn't be changed.
+    pub fn purge(
+        &self,
+        log_name: &str,
+        statement: Statement,
+        q_parse: QueryParsing,
+        ingest_buffers: Arc<IngestBuffers>,
+        progress: Progress,
+    ) -> impl Future<Item = (), Error = ()> {
+        let read_cfg = self.config.read().unwrap();
+        let log = match read_cfg.log.get(log_name) {
+            Some(log) => log.clone(),
+            None => return Either::B(future::ok(())),
+        };
+        let datastores: Vec<DataStore> = log
+            .all_datastores()
+            .iter()
+            .filter_map(|ds_name| read_cfg.datastore.get(ds_name).cloned())
+            .collect();
+        drop(read_cfg);
+        let naming = ObjectNaming::for_log(&log);
+        let log_name = log_name.to_string();
+        let done_log_name = log_name.clone();
+        info!("Purging lines of {}", log_name);
+        let matcher = Arc::new(Mutex::new((statement, q_parse)));
+        // lines acknowledged before the purge must be on the datastores to be removed
+        let flush = Ingest::new(Arc::clone(&self.config)).flush_and_wait(&log_name, ingest_buffers);
+        Either::A(
+            flush
+                .and_then(move |_| {
+                    purge_lines(
+                        log_name,
+                        datastores,
+                        naming,
+                        log.bloom_filters,
+                        Compression::for_log(&log),
+                        matcher,
+                        progress.clone(),
+                    )
+                    .map(move |counts| (counts, progress))
+                })
+                .and_then(move |((objects, lines, failed), progress)| {
+                    info!("Done purging lines of {}", done_log_name);
+                    progress.send(json!({
+                        "action": "purge",
+                        "done": true,
+                        "objects": objects,
+                        "lines": lines,
+                        "failed": failed,
+                    }));
+                    if failed > 0 {
+                        return Err(());
+                    }
+                    Ok(())
+                }),
+        )
+    }
+}
+
+/// Deletes the oldest objects of a log over quota and moves its old objects to the cold tier,
+/// as the ingest and the tiering lifecycle task would.
+fn expire(
+    cfg: &Config,
+    log: Log,
+    log_name: String,
+    datastores: Vec<DataStore>,
+    ingest_buffers: Arc<IngestBuffers>,
+    progress: Progress,
+) -> MaintenanceFuture {
+    let now = Utc::now();
+    let evict = if evicts_oldest(&log, &now) {
+        let progress = progress.clone();
+        Either::A(
+            delete_oldest_objects(log.clone(), log_name.clone(), datastores, ingest_buffers).map(
+                move |deleted| {
+                    for (ds, obj) in &deleted {
+                        progress.object(&obj.key, ds, "deleted");
+                    }
+                    deleted.len() as u64
+                },
+            ),
+        )
+    } else {
+        Either::B(future::ok(0))
+    };
+    let migrations: Vec<_> = match cold_tier(cfg, &log, &now) {
+        Some((cutoff, cold)) => log
+            .datastores
+            .iter()
+            .filter_map(|ds_name| cfg.datastore.get(ds_name).cloned())
+            .map(|hot| {
+                let progress = progress.clone();
+                let hot_c = hot.clone();
+                migrate_datastore(
+                    log_name.clone(),
+                    hot,
+                    cold.clone(),
+                    cutoff,
+                    log.bloom_filters,
+                    ObjectNaming::for_log(&log),
+                )
+                .map(move |moved| {
+                    for key in &moved {
+                        progress.object(key, &hot_c, "moved to the cold tier");
+                    }
+                    moved.len() as u64
+                })
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    Box::new(evict.and_then(move |deleted| {
+        future::join_all(migrations).map(move |moved| deleted + moved.iter().sum::<u64>())
+    }))
+}
+
+/// How a reindex goes over the objects of a log
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReindexOptions {
+    // rewrites every bloom filter from the lines of its object, not only the missing or unreadable
+    pub rebuild: bool,
+    // skips the objects a previous reindex of the log went through, ie: one that was cancelled
+    pub resume: bool,
+    // objects read per second, unbounded if not set
+    pub objects_per_sec: Option<u32>,
+}
+
+impl ReindexOptions {
+    /// Reads the options from the body of a maintenance request, ie:
+    /// `{"action": "reindex", "rebuild": true, "objects_per_sec": 20}`
+    pub fn from_json(body: &serde_json::Value) -> Result<ReindexOptions, String> {
+        let flag = |name: &str| match body.get(name) {
+            None => Ok(false),
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| format!("`{}` must be true or false", name)),
+        };
+        let objects_per_sec = match body.get("objects_per_sec") {
+            None => None,
+            Some(value) => match value.as_u64() {
+                Some(rate) if rate > 0 && rate <= u64::from(std::u32::MAX) => Some(rate as u32),
+                _ => return Err("`objects_per_sec` must be a positive number".to_string()),
+            },
+        };
+        Ok(ReindexOptions {
+            rebuild: flag("rebuild")?,
+            resume: flag("resume")?,
+            objects_per_sec,
+        })
+    }
+}
+
+/// The last object a reindex of a log went through on each of its datastores, kept on the
+/// metabucket until the reindex is done
+type ReindexCursor = HashMap<String, String>;
+
+fn reindex_cursor_key(log_name: &str) -> String {
+    format!("{}{}", REINDEX_PREFIX, log_name)
+}
+
+fn read_reindex_cursor(
+    cfg: Arc<RwLock<Config>>,
+    log_name: &str,
+) -> impl Future<Item = ReindexCursor, Error = ()> {
+    let err_log_name = log_name.to_string();
+    get_object_metabucket(cfg, reindex_cursor_key(log_name))
+        .map_err(move |e| {
+            error!(
+                "Could not read the reindex cursor of {}: {:?}",
+                err_log_name, e
+            )
+        })
+        .map(|body| {
+            body.and_then(|body| serde_json::from_str(&body).ok())
+                .unwrap_or_default()
+        })
+}
+
+/// Saving the cursor is best effort, a reindex resumed from an older one reads some objects twice
+fn write_reindex_cursor(
+    cfg: Arc<RwLock<Config>>,
+    log_name: &str,
+    cursor: &ReindexCursor,
+) -> impl Future<Item = (), Error = ()> {
+    let err_log_name = log_name.to_string();
+    let body = serde_json::to_string(cursor).unwrap();
+    write_object_metabucket(cfg, reindex_cursor_key(log_name), body).then(move |res| {
+        if let Err(e) = res {
+            error!(
+                "Could not save the reindex cursor of {}: {}",
+                err_log_name,
+                e.reason()
+            );
+        }
+        Ok(())
+    })
+}
+
+/// Whether a datastore's object is past where a previous reindex stopped
+fn past_cursor(cursor: &ReindexCursor, ds_name: &str, key: &str) -> bool {
+    cursor.get(ds_name).map_or(true, |last| key > last.as_str())
+}
+
+/// Writes the bloom filters of the objects of a log that have none or an unreadable one, ie:
+/// written before bloom filters were enabled or whose filter couldn't be written, or of every
+/// object on a rebuild. Datastores are gone through one at a time, saving how far it got on the
+/// metabucket so a cancelled reindex can be resumed.
+fn reindex(
+    cfg: Arc<RwLock<Config>>,
+    log_name: String,
+    datastores: Vec<DataStore>,
+    naming: ObjectNaming,
+    options: ReindexOptions,
+    progress: Progress,
+) -> impl Future<Item = u64, Error = ()> {
+    let cursor = if options.resume {
+        Either::A(read_reindex_cursor(Arc::clone(&cfg), &log_name))
+    } else {
+        Either::B(future::ok(ReindexCursor::new()))
+    };
+    let pause = options
+        .objects_per_sec
+        .map(|rate| Duration::from_secs(1) / rate);
+    let rebuild = options.rebuild;
+    let done_cfg = Arc::clone(&cfg);
+    let done_log_name = log_name.clone();
+    cursor
+        .and_then(move |cursor| {
+            let cursor = Arc::new(Mutex::new(cursor));
+            stream::iter_ok::<_, ()>(datastores)
+                .and_then(move |ds| {
+                    let progress = progress.clone();
+                    let cfg = Arc::clone(&cfg);
+                    let cursor = Arc::clone(&cursor);
+                    let ds_name = ds.name.clone().unwrap_or_default();
+                    let cursor_log_name = log_name.clone();
+                    let err_log_name = log_name.clone();
+                    let skip_cursor = Arc::clone(&cursor);
+                    let skip_ds_name = ds_name.clone();
+                    list_msl_bucket_objects(&log_name, &ds, &naming)
+                        .map_err(move |e| {
+                            error!("Could not list {} to reindex: {:?}", err_log_name, e)
+                        })
+                        .filter(move |obj| {
+                            past_cursor(&skip_cursor.lock().unwrap(), &skip_ds_name, &obj.key)
+                        })
+                        .fold((0u64, 0u64), move |(indexed, seen), obj| {
+                            let progress = progress.clone();
+                            let event_ds = ds.clone();
+                            let key = obj.key.clone();
+                            let cfg = Arc::clone(&cfg);
+                            let cursor = Arc::clone(&cursor);
+                            let ds_name = ds_name.clone();
+                            let cursor_log_name = cursor_log_name.clone();
+                            let throttle = match pause {
+                                Some(pause) if seen > 0 => {
+                                    Either::A(Delay::new(Instant::now() + pause).map_err(|_| ()))
+                                }
+                                _ => Either::B(future::ok(())),
+                            };
+                            let index_ds = ds.clone();
+                            throttle
+                                .and_then(move |_| {
+                                    index_object(index_ds, obj.key, rebuild).then(Ok::<_, ()>)
+                                })
+                                .and_then(move |res| {
+                                    let indexed = match res {
+                                        Ok(true) => {
+                                            progress.object(&key, &event_ds, "indexed");
+                                            indexed + 1
+                                        }
+                                        Ok(false) => indexed,
+                                        Err(e) => {
+                                            error!("Could not reindex {}: {}", key, e);
+                                            progress.failed(&key, &event_ds, &e);
+                                            indexed
+                                        }
+                                    };
+                                    let seen = seen + 1;
+                                    let mut cursor = cursor.lock().unwrap();
+                                    cursor.insert(ds_name, key);
+                                    if seen % REINDEX_CURSOR_EVERY == 0 {
+                                        Either::A(
+                                            write_reindex_cursor(cfg, &cursor_log_name, &cursor)
+                                                .map(move |_| (indexed, seen)),
+                                        )
+                                    } else {
+                                        Either::B(future::ok((indexed, seen)))
+                                    }
+                                })
+                        })
+                        .map(|(indexed, _)| indexed)
+                })
+                .fold(0u64, |total, indexed| Ok::<_, ()>(total + indexed))
+        })
+        .and_then(move |indexed| {
+            // a finished reindex leaves nothing to resume
+            let ds = ds_for_metabucket(done_cfg);
+            delete_object(&ds, reindex_cursor_key(&done_log_name)).then(move |_| Ok(indexed))
+        })
+}
+
+/// Writes the bloom filter of an object from its lines, unless it has a readable one and it's not
+/// a rebuild. Returns whether a filter was written.
+fn index_object(
+    ds: DataStore,
+    key: String,
+    rebuild: bool,
+) -> impl Future<Item = bool, Error = String> {
+    let existing = if rebuild {
+        Either::A(future::ok(None))
+    } else {
+        Either::B(read_bloom_filter(&key, &ds).map_err(|e| e.to_string()))
+    };
+    existing.and_then(move |filter| match filter {
+        Some(_) => Either::A(future::ok(false)),
+        None => Either::B(
+            get_log_object(&ds, key.clone())
+                .and_then(move |(body, metadata)| {
+                    let filter = BloomFilter::from_lines(&indexed_lines(&body, &metadata));
+                    put_object(&ds, bloom_key(&key), filter.to_bytes(), None)
+                        .map_err(|e| e.to_string())
+                })
+                .map(|_| true),
+        ),
+    })
+}
+
+/// Removes the matching lines from every object of a log, one object at a time. Returns how
+/// many objects were changed, how many lines were removed and how many objects failed.
+fn purge_lines(
+    log_name: String,
+    datastores: Vec<DataStore>,
+    naming: ObjectNaming,
+    bloom_filters: bool,
+    compression: Option<Compression>,
+    matcher: Arc<Mutex<(Statement, QueryParsing)>>,
+    progress: Progress,
+) -> impl Future<Item = (u64, u64, u64), Error = ()> {
+    stream::iter_ok::<_, ()>(datastores)
+        .and_then(move |ds| {
+            let progress = progress.clone();
+            let matcher = Arc::clone(&matcher);
+            let err_log_name = log_name.clone();
+            list_msl_bucket_objects(&log_name, &ds, &naming)
+                .map_err(move |e| error!("Could not list {} to purge lines: {:?}", err_log_name, e))
+                .fold((0u64, 0u64, 0u64), move |(objects, lines, failed), obj| {
+                    let progress = progress.clone();
+                    let event_ds = ds.clone();
+                    let key = obj.key.clone();
+                    purge_object(
+                        ds.clone(),
+                        obj.key,
+                        bloom_filters,
+                        compression,
+                        Arc::clone(&matcher),
+                    )
+                    .then(move |res| match res {
+                        Ok(Some((result, removed))) => {
+                            progress.purged(&key, &event_ds, result, removed);
+                            Ok::<_, ()>((objects + 1, lines + removed, failed))
+                        }
+                        Ok(None) => Ok((objects, lines, failed)),
+                        Err(e) => {
+                            error!("Could not purge lines of {}: {}", key, e);
+                            progress.failed(&key, &event_ds, &e);
+                            Ok((objects, lines, failed + 1))
+                        }
+                    })
+                })
+        })
+        .fold((0, 0, 0), |total, ds_total| {
+            Ok::<_, ()>((
+                total.0 + ds_total.0,
+                total.1 + ds_total.1,
+                total.2 + ds_total.2,
+            ))
+        })
+}
+
+/// Rewrites an object without the lines matching the purge, or deletes it if none is left.
+/// Returns what was done to the object and how many lines were removed, `None` if it had no
+/// matching lines.
+fn purge_object(
+    ds: DataStore,
+    key: String,
+    bloom_filters: bool,
+    compression: Option<Compression>,
+    matcher: Arc<Mutex<(Statement, QueryParsing)>>,
+) -> impl Future<Item = Option<(&'static str, u64)>, Error = String> {
+    get_log_object(&ds, key.clone()).and_then(move |(body, metadata)| {
+        let stored = lines_of(&body);
+        // matching happens on the lines as they were sent, they are kept as stored
+        let base64_lines = is_base64_object(&metadata);
+        let scanned = if base64_lines {
+            decode_lines(stored.clone())
+        } else {
+            stored.clone()
+        };
+        let matches = {
+            let mut matcher = matcher.lock().unwrap();
+            let (statement, q_parse) = &mut *matcher;
+            matching_lines(statement, q_parse, &scanned)
+        };
+        let kept = remaining_lines(stored, &matches);
+        let removed = (scanned.len() - kept.len()) as u64;
+        let indexed = remaining_lines(scanned, &matches);
+        if removed == 0 {
+            return Either::A(future::ok(None));
+        }
+        let bloom_ds = ds.clone();
+        let bloom = bloom_key(&key);
+        if kept.is_empty() {
+            return Either::B(Either::A(
+                delete_object(&ds, key)
+                    .map_err(|e| e.to_string())
+                    // the bloom filter may not exist, deleting it is best effort
+                    .and_then(move |_| {
+                        delete_object(&bloom_ds, bloom)
+                            .then(move |_| Ok(Some(("deleted", removed))))
+                    }),
+            ));
+        }
+        let filter = if bloom_filters {
+            Some(BloomFilter::from_lines(&indexed))
+        } else {
+            None
+        };
+        let mut body = kept.join("\n").into_bytes();
+        body.push(b'\n');
+        let body = match compressed(body, compression) {
+            Ok(body) => body,
+            Err(e) => return Either::A(future::err(e)),
+        };
+        Either::B(Either::B(
+            put_object(&ds, key, body, metadata)
+                .map_err(|e| e.to_string())
+                .and_then(move |_| match filter {
+                    Some(filter) => Either::A(
+                        put_object(&bloom_ds, bloom, filter.to_bytes(), None)
+                            .map_err(|e| e.to_string()),
+                    ),
+                    None => Either::B(future::ok(())),
+                })
+                .map(move |_| Some(("rewritten", removed))),
+        ))
+    })
+}
+
+/// The lines not matching the purge
+fn remaining_lines(lines: Vec<String>, matches: &[bool]) -> Vec<String> {
+    lines
+        .into_iter()
+        .zip(matches)
+        .filter(|(_, matched)| !**matched)
+        .map(|(line, _)| line)
+        .collect()
+}
+
+/// Merges the objects of a log written within the same hour on each datastore into as few
+/// objects as `COMPACT_MAX_BYTES` allows, so queries issue fewer reads.
+fn compact(
+    log_name: String,
+    datastores: Vec<DataStore>,
+    bloom_filters: bool,
+    compression: Option<Compression>,
+    naming: ObjectNaming,
+    ingest_buffers: Arc<IngestBuffers>,
+    progress: Progress,
+) -> impl Future<Item = u64, Error = ()> {
+    let compactions = datastores.into_iter().map(move |ds| {
+        let progress = progress.clone();
+        let ingest_buffers = Arc::clone(&ingest_buffers);
+        let err_log_name = log_name.clone();
+        let log_name = log_name.clone();
+        list_msl_bucket_objects(&log_name, &ds, &naming)
+            .collect()
+            .map_err(move |e| error!("Could not list {} to compact: {:?}", err_log_name, e))
+            .and_then(move |objects| {
+                stream::iter_ok::<_, ()>(compaction_batches(objects, COMPACT_MAX_BYTES)).fold(
+                    0u64,
+                    move |compacted, (hour, batch)| {
+                        let progress = progress.clone();
+                        let ingest_buffers = Arc::clone(&ingest_buffers);
+                        let log_name = log_name.clone();
+                        let event_ds = ds.clone();
+                        let merged_bytes = batch.iter().map(|obj| obj.size).sum();
+                        let merged_objects = batch.len() as u64;
+                        compact_batch(ds.clone(), hour, batch, bloom_filters, compression).then(
+                            move |res| match res {
+                                Ok((key, written_bytes)) => {
+                                    if let Some(ingest_buffer) = ingest_buffers.get(&log_name[..]) {
+                                        ingest_buffer.lock().unwrap().record_compacted(
+                                            merged_bytes,
+                                            merged_objects,
+                                            written_bytes,
+                                        );
+                                    }
+                                    progress.object(&key, &event_ds, "compacted");
+                                    Ok::<_, ()>(compacted + merged_objects)
+                                }
+                                Err((key, e)) => {
+                                    error!("Could not compact into {}: {}", key, e);
+                                    progress.failed(&key, &event_ds, &e);
+                                    Ok(compacted)
+                                }
+                            },
+                        )
+                    },
+                )
+            })
+    });
+    future::join_all(compactions).map(|compacted| compacted.iter().sum())
+}
+
+/// Writes the objects of a batch as a single object of the same hour, then deletes them along
+/// with their bloom filters. A marker is left where each of them was, so continuation tokens
+/// issued on them resume on the merged object. Returns the key and size of the new object.
+fn compact_batch(
+    ds: DataStore,
+    hour: String,
+    mut batch: Vec<LogObject>,
+    bloom_filters: bool,
+    compression: Option<Compression>,
+) -> impl Future<Item = (String, u64), Error = (String, String)> {
+    // the parts are merged in key order under a key next to the first one, so paged searches,
+    // which read objects in key order, never skip lines resuming on the merged object
+    batch.sort_by(|a, b| a.key.cmp(&b.key));
+    let key = merged_key(&hour, &batch[0].key);
+    let err_key = key.clone();
+    let reads: Vec<_> = batch
+        .iter()
+        .map(|obj| get_log_object(&ds, obj.key.clone()))
+        .collect();
+    future::join_all(reads)
+        .and_then(move |parts| {
+            let (body, metadata, starts) = merge_objects(parts);
+            let filter = if bloom_filters {
+                Some(BloomFilter::from_lines(&indexed_lines(&body, &metadata)))
+            } else {
+                None
+            };
+            // the parts are merged as plain lines, compressed parts can't be concatenated as is
+            let body = compressed(body, compression)?;
+            Ok((body, metadata, filter, starts))
+        })
+        .and_then(move |(body, metadata, filter, starts)| {
+            let written_bytes = body.len() as u64;
+            let bloom_ds = ds.clone();
+            let bloom_key_c = bloom_key(&key);
+            let marked_ds = ds.clone();
+            let markers: Vec<(String, Vec<u8>)> = batch
+                .iter()
+                .zip(starts)
+                .map(|(obj, offset)| {
+                    let moved = MovedLines {
+                        key: key.clone(),
+                        offset,
+                    };
+                    (moved_key(&obj.key), serde_json::to_vec(&moved).unwrap())
+                })
+                .collect();
+            put_object(&ds, key.clone(), body, metadata)
+                .map_err(|e| e.to_string())
+                .and_then(move |_| match filter {
+                    // the originals are kept until the merged data is fully in place
+                    Some(filter) => Either::A(
+                        put_object(&bloom_ds, bloom_key_c, filter.to_bytes(), None)
+                            .map_err(|e| e.to_string()),
+                    ),
+                    None => Either::B(future::ok(())),
+                })
+                .and_then(move |_| {
+                    stream::iter_ok::<_, String>(markers).for_each(move |(marker_key, marker)| {
+                        put_object(&marked_ds, marker_key, marker, None).map_err(|e| e.to_string())
+                    })
+                })
+                .and_then(move |_| {
+                    stream::iter_ok::<_, String>(batch).for_each(move |obj| {
+                        let bloom_ds = ds.clone();
+                        delete_object(&ds, obj.key.clone())
+                            .map_err(|e| e.to_string())
+                            .and_then(move |_| {
+                                // the bloom filter may not exist, deleting it is best effort
+                                delete_object(&bloom_ds, bloom_key(&obj.key)).then(|_| Ok(()))
+                            })
+                    })
+                })
+                .map(move |_| (key, written_bytes))
+        })
+        .map_err(move |e| (err_key, e))
+}
+
+/// Key of the object merging a batch, named after the first object of the batch so it's listed
+/// right next to where that object was, ie: `{hour}/{first}~{uuid}.log`
+fn merged_key(hour: &str, first_key: &str) -> String {
+    let name = first_key.rsplit('/').next().unwrap_or_default();
+    let name = name.trim_end_matches(".log");
+    // an object merged before is named after its own first object already
+    let name = name.split('~').next().unwrap_or_default();
+    format!("{}/{}~{}.log", hour, name, Uuid::new_v4().to_simple())
+}
+
+/// Groups the objects written within the same hour into batches of at most `max_bytes`, leaving
+/// out the objects that have nothing to be merged with. Batches come with their hour prefix.
+fn compaction_batches(objects: Vec<LogObject>, max_bytes: u64) -> Vec<(String, Vec<LogObject>)> {
+    let mut hours: BTreeMap<String, Vec<LogObject>> = BTreeMap::new();
+    for obj in objects {
+        let hour = match obj.key.rfind('/') {
+            Some(i) => obj.key[..i].to_string(),
+            None => continue,
+        };
+        hours.entry(hour).or_insert_with(Vec::new).push(obj);
+    }
+    let mut batches = Vec::new();
+    for (hour, mut objects) in hours {
+        objects.sort_by(|a, b| a.last_modified.cmp(&b.last_modified));
+        let mut batch: Vec<LogObject> = Vec::new();
+        let mut batch_bytes = 0;
+        for obj in objects {
+            if !batch.is_empty() && batch_bytes + obj.size > max_bytes {
+                if batch.len() > 1 {
+                    batches.push((hour.clone(), batch));
+                }
+                batch = Vec::new();
+                batch_bytes = 0;
+            }
+            batch_bytes += obj.size;
+            batch.push(obj);
+        }
+        if batch.len() > 1 {
+            batches.push((hour, batch));
+        }
+    }
+    batches
+}
+
+/// Concatenates the bodies of objects, keeping the receive time range of their data if all of
+/// them recorded it. Returns the offset each part starts at on the merged body as well. If any
+/// part is in base64 the merged object is too, the plain lines of the others are wrapped.
+fn merge_objects(
+    parts: Vec<(Vec<u8>, Option<HashMap<String, String>>)>,
+) -> (Vec<u8>, Option<HashMap<String, String>>, Vec<u64>) {
+    let base64_merge = parts.iter().any(|(_, metadata)| is_base64_object(metadata));
+    let mut body = Vec::new();
+    let mut starts = Vec::new();
+    let mut first: Option<String> = None;
+    let mut last: Option<String> = None;
+    let mut all_stamped = true;
+    for (part, metadata) in parts {
+        starts.push(body.len() as u64);
+        if base64_merge && !is_base64_object(&metadata) {
+            for line in part.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+                body.extend_from_slice(base64::encode(line).as_bytes());
+                body.push(b'\n');
+            }
+        } else {
+            body.extend_from_slice(&part);
+        }
+        if !body.is_empty() && !body.ends_with(b"\n") {
+            body.push(b'\n');
+        }
+        let range = metadata.as_ref().and_then(|m| {
+            Some((
+                m.get(RECEIVED_FIRST_METADATA)?,
+                m.get(RECEIVED_LAST_METADATA)?,
+            ))
+        });
+        match range {
+            Some((part_first, part_last)) => {
+                // the timestamps are RFC 3339 in UTC, so they sort as strings
+                if first.as_ref().map_or(true, |f| part_first < f) {
+                    first = Some(part_first.clone());
+                }
+                if last.as_ref().map_or(true, |l| part_last > l) {
+                    last = Some(part_last.clone());
+                }
+            }
+            None => all_stamped = false,
+        }
+    }
+    let mut metadata = match (all_stamped, first, last) {
+        (true, Some(first), Some(last)) => {
+            let mut metadata = HashMap::new();
+            metadata.insert(RECEIVED_FIRST_METADATA.to_string(), first);
+            metadata.insert(RECEIVED_LAST_METADATA.to_string(), last);
+            Some(metadata)
+        }
+        _ => None,
+    };
+    if base64_merge {
+        metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(ENCODING_METADATA.to_string(), ENCODING_BASE64.to_string());
+    }
+    (body, metadata, starts)
+}
+
+/// Reads an object of a log, decoded if it was written compressed
+fn get_log_object(
+    ds: &DataStore,
+    key: String,
+) -> impl Future<Item = (Vec<u8>, Option<HashMap<String, String>>), Error = String> {
+    get_object(ds, key)
+        .map_err(|e| e.to_string())
+        .and_then(|(body, metadata)| {
+            decompress(body)
+                .map(|body| (body, metadata))
+                .map_err(|e| format!("Could not decompress object: {}", e))
+        })
+}
+
+/// A rewritten object body, compressed as the log writes its new objects
+fn compressed(body: Vec<u8>, compression: Option<Compression>) -> Result<Vec<u8>, String> {
+    match compression {
+        Some(compression) => compression
+            .compress(&body)
+            .map_err(|e| format!("Could not compress object: {}", e)),
+        None => Ok(body),
+    }
+}
+
+/// The lines of an object as stored
+fn lines_of(body: &[u8]) -> Vec<String> {
+    logical_lines(&String::from_utf8_lossy(body))
+}
+
+/// The lines of an object as indexed by its bloom filter, the way they were sent
+fn indexed_lines(body: &[u8], metadata: &Option<HashMap<String, String>>) -> Vec<String> {
+    if is_base64_object(metadata) {
+        decode_lines(lines_of(body))
+    } else {
+        lines_of(body)
+    }
+}
+
+#[cfg(test)]
+mod maintenance_tests {
+    use super::*;
+
+    fn object(key: &str, size: u64, last_modified: &str) -> LogObject {
+        LogObject {
+            key: key.to_string(),
+            size,
+            last_modified: last_modified.to_string(),
+        }
+    }
+
+    #[test]
+    fn batches_group_objects_by_hour() {
+        let objects = vec![
+            object("minsql/mylog/2019/7/1/10/b.log", 10, "2019-07-01T10:20:00Z"),
+            object("minsql/mylog/2019/7/1/10/a.log", 10, "2019-07-01T10:10:00Z"),
+            object("minsql/mylog/2019/7/1/11/c.log", 10, "2019-07-01T11:10:00Z"),
+            object("minsql/mylog/2019/7/1/12/d.log", 10, "2019-07-01T12:10:00Z"),
+            object("minsql/mylog/2019/7/1/12/e.log", 10, "2019-07-01T12:20:00Z"),
+        ];
+        let batches = compaction_batches(objects, 100);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].0, "minsql/mylog/2019/7/1/10");
+        let keys: Vec<&str> = batches[0].1.iter().map(|o| &o.key[..]).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "minsql/mylog/2019/7/1/10/a.log",
+                "minsql/mylog/2019/7/1/10/b.log"
+            ]
+        );
+        assert_eq!(batches[1].0, "minsql/mylog/2019/7/1/12");
+    }
+
+    #[test]
+    fn batches_are_bounded() {
+        let objects = vec![
+            object("minsql/mylog/2019/7/1/10/a.log", 40, "2019-07-01T10:10:00Z"),
+            object("minsql/mylog/2019/7/1/10/b.log", 40, "2019-07-01T10:20:00Z"),
+            object("minsql/mylog/2019/7/1/10/c.log", 40, "2019-07-01T10:30:00Z"),
+        ];
+        let batches = compaction_batches(objects, 100);
+        // the last object has nothing left to be merged with
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].1.len(), 2);
+    }
+
+    #[test]
+    fn merged_objects_are_named_after_their_first_part() {
+        let hour = "minsql/mylog/2019/7/1/10";
+        let key = merged_key(hour, "minsql/mylog/2019/7/1/10/a.log");
+        assert!(key.starts_with("minsql/mylog/2019/7/1/10/a~"));
+        assert!(key.ends_with(".log"));
+        // merging it again doesn't grow its name
+        let again = merged_key(hour, &key);
+        assert!(again.starts_with("minsql/mylog/2019/7/1/10/a~"));
+        assert_eq!(again.matches('~').count(), 1);
+    }
+
+    #[test]
+    fn merge_keeps_received_range() {
+        let mut first = HashMap::new();
+        first.insert(
+            "minsql-received-first".to_string(),
+            "2019-07-01T10:00:00.000Z".to_string(),
+        );
+        first.insert(
+            "minsql-received-last".to_string(),
+            "2019-07-01T10:05:00.000Z".to_string(),
+        );
+        let mut second = HashMap::new();
+        second.insert(
+            "minsql-received-first".to_string(),
+            "2019-07-01T10:06:00.000Z".to_string(),
+        );
+        second.insert(
+            "minsql-received-last".to_string(),
+            "2019-07-01T10:09:00.000Z".to_string(),
+        );
+        let (body, metadata, starts) = merge_objects(vec![
+            (b"line 1\nline 2".to_vec(), Some(first)),
+            (b"line 3\n".to_vec(), Some(second)),
+        ]);
+        assert_eq!(body, b"line 1\nline 2\nline 3\n".to_vec());
+        assert_eq!(starts, vec![0, 14]);
+        let metadata = metadata.unwrap();
+        assert_eq!(
+            metadata["minsql-received-first"],
+            "2019-07-01T10:00:00.000Z"
+        );
+        assert_eq!(metadata["minsql-received-last"], "2019-07-01T10:09:00.000Z");
+    }
+
+    #[test]
+    fn merge_drops_partial_received_range() {
+        let mut stamped = HashMap::new();
+        stamped.insert(
+            "minsql-received-first".to_string(),
+            "2019-07-01T10:00:00.000Z".to_string(),
+        );
+        stamped.insert(
+            "minsql-received-last".to_string(),
+            "2019-07-01T10:05:00.000Z".to_string(),
+        );
+        let (_, metadata, _) = merge_objects(vec![
+            (b"line 1\n".to_vec(), Some(stamped)),
+            (b"line 2\n".to_vec(), None),
+        ]);
+        assert!(metadata.is_none());
+    }
+
+    #[test]
+    fn merge_wraps_plain_parts_of_base64_objects() {
+        let mut encoded = HashMap::new();
+        encoded.insert(ENCODING_METADATA.to_string(), ENCODING_BASE64.to_string());
+        let (body, metadata, starts) = merge_objects(vec![
+            (b"plain line\n".to_vec(), None),
+            (b"d3JhcHBlZCBsaW5l\n".to_vec(), Some(encoded)),
+        ]);
+        assert_eq!(body, b"cGxhaW4gbGluZQ==\nd3JhcHBlZCBsaW5l\n".to_vec());
+        assert_eq!(starts, vec![0, 17]);
+        assert!(is_base64_object(&metadata));
+        assert_eq!(
+            indexed_lines(&body, &metadata),
+            vec!["plain line", "wrapped line"]
+        );
+        // plain objects are merged and indexed as they are
+        let (body, metadata, _) = merge_objects(vec![(b"cGxhaW4gbGluZQ==\n".to_vec(), None)]);
+        assert_eq!(indexed_lines(&body, &metadata), vec!["cGxhaW4gbGluZQ=="]);
+    }
+
+    #[test]
+    fn purge_keeps_unmatched_lines() {
+        let lines = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(
+            remaining_lines(lines, &[false, true, false]),
+            vec!["a".to_string(), "c".to_string()]
+        );
+    }
+
+    #[test]
+    fn actions_refused_on_held_logs() {
+        let now = Utc::now();
+        let mut log = Log::default();
+        log.locked_until = Some("2999-01-01T00:00:00Z".to_string());
+        assert!(MaintenanceAction::Compact.refused_for(&log, &now).is_some());
+        assert!(MaintenanceAction::Expire.refused_for(&log, &now).is_some());
+        log.bloom_filters = true;
+        assert!(MaintenanceAction::Reindex.refused_for(&log, &now).is_none());
+        assert_eq!(MaintenanceAction::parse("vacuum"), None);
+    }
+
+    #[test]
+    fn replicated_logs_are_not_compacted() {
+        let now = Utc::now();
+        let mut log = Log::default();
+        assert!(MaintenanceAction::Compact.refused_for(&log, &now).is_none());
+        log.replicated = true;
+        assert!(MaintenanceAction::Compact.refused_for(&log, &now).is_some());
+        assert!(MaintenanceAction::Expire.refused_for(&log, &now).is_none());
+    }
+
+    #[test]
+    fn reindex_options_and_cursor() {
+        let body = json!({"action": "reindex", "rebuild": true, "objects_per_sec": 20});
+        assert_eq!(
+            ReindexOptions::from_json(&body),
+            Ok(ReindexOptions {
+                rebuild: true,
+                resume: false,
+                objects_per_sec: Some(20),
+            })
+        );
+        assert!(ReindexOptions::from_json(&json!({"objects_per_sec": 0})).is_err());
+        assert!(ReindexOptions::from_json(&json!({"resume": "yes"})).is_err());
+
+        let mut cursor = ReindexCursor::new();
+        cursor.insert(
+            "ds1".to_string(),
+            "minsql/mylog/2019/7/1/10/b.log".to_string(),
+        );
+        assert!(!past_cursor(
+            &cursor,
+            "ds1",
+            "minsql/mylog/2019/7/1/10/a.log"
+        ));
+        assert!(!past_cursor(
+            &cursor,
+            "ds1",
+            "minsql/mylog/2019/7/1/10/b.log"
+        ));
+        assert!(past_cursor(
+            &cursor,
+            "ds1",
+            "minsql/mylog/2019/7/1/11/a.log"
+        ));
+        assert!(past_cursor(
+            &cursor,
+            "ds2",
+            "minsql/mylog/2019/7/1/10/a.log"
+        ));
+    }
+}
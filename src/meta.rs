@@ -15,20 +15,31 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::process;
+use std::str;
+use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
 
 use futures::future::Future;
 use futures::stream;
 use futures::Stream;
+use lazy_static::lazy_static;
 use log::{error, info};
 use minio_rs::minio;
 use minio_rs::minio::Credentials;
 use rusoto_s3::{GetObjectRequest, ListObjectsRequest, S3};
 
-use crate::config::{Config, DataStore, Log, LogAuth, Token};
+use crate::changesets::is_changeset_key;
+use crate::config::{Config, DataStore, Log, LogAuth, SmartPattern, Token};
+use crate::history::is_history_key;
+use crate::hyperscan::{pattern_id_for_name, valid_pattern_expression};
 use crate::storage;
+use crate::supervisor;
+
+lazy_static! {
+    static ref META_PROBLEMS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+}
 
 pub struct Meta {
     config: Arc<RwLock<Config>>,
@@ -95,6 +106,9 @@ impl Meta {
                                 .map(|x| x.key.unwrap())
                                 // Avoid loading models
                                 .filter(|file_key| file_key.contains("/models/") == false)
+                                // nor the history of the configuration and pending changesets
+                                .filter(|file_key| !is_history_key(file_key))
+                                .filter(|file_key| !is_changeset_key(file_key))
                                 .collect();
 
                             (objs, list_objects.next_marker)
@@ -128,43 +142,7 @@ impl Meta {
                             error!("concatenating body: {:?}", e);
                             ()
                         })
-                        .map(move |bytes| {
-                            let result = match String::from_utf8(bytes.to_vec()) {
-                                Ok(d) => d,
-                                Err(e) => {
-                                    println!("error!{:?}", e);
-                                    return MetaConfigObject::Unknown;
-                                }
-                            };
-                            let parts: Vec<&str> = file_key_clone
-                                .trim_start_matches("minsql/meta/")
-                                .split("/")
-                                .collect();
-                            let meta_obj = match (parts.len(), parts[0]) {
-                                (2, "logs") => match serde_json::from_str(&result) {
-                                    Ok(t) => MetaConfigObject::Log(t),
-                                    Err(_) => MetaConfigObject::Unknown,
-                                },
-                                (2, "datastores") => match serde_json::from_str(&result) {
-                                    Ok(t) => MetaConfigObject::DataStore(t),
-                                    Err(_) => MetaConfigObject::Unknown,
-                                },
-                                (2, "tokens") => match serde_json::from_str(&result) {
-                                    Ok(t) => MetaConfigObject::Token(t),
-                                    Err(_) => MetaConfigObject::Unknown,
-                                },
-                                (3, "auth") => match serde_json::from_str(&result) {
-                                    Ok(t) => MetaConfigObject::LogAuth((
-                                        parts[1].to_string(),
-                                        parts[2].to_string(),
-                                        t,
-                                    )),
-                                    Err(_) => MetaConfigObject::Unknown,
-                                },
-                                _ => MetaConfigObject::Unknown,
-                            };
-                            meta_obj
-                        })
+                        .map(move |bytes| parse_meta_object(&file_key_clone, &bytes))
                 })
         })
         .buffer_unordered(5)
@@ -190,6 +168,13 @@ impl Meta {
                     };
                     auth_logs.insert(log_name, log_auth);
                 }
+                MetaConfigObject::Pattern((name, pattern)) => {
+                    cfg_write.patterns.insert(name, pattern);
+                }
+                MetaConfigObject::Invalid((key, e)) => {
+                    error!("Ignoring invalid configuration {}: {}", key, e);
+                    record_problem(key, e);
+                }
                 _ => (),
             }
 
@@ -201,20 +186,24 @@ impl Meta {
     }
 
     pub fn monitor_metabucket(&self) {
-        let read_cfg = self.config.read().unwrap();
-
-        let metadata_bucket = read_cfg.server.metadata_bucket.clone();
-        let metadata_endpoint = read_cfg.server.metadata_endpoint.clone();
-        let access_key = read_cfg.server.access_key.clone();
-        let secret_key = read_cfg.server.secret_key.clone();
-        drop(read_cfg);
+        let cfg = Arc::clone(&self.config);
+        // Listen again if the notifications are ever interrupted, else configuration changes
+        // would stop being picked up. The keys are read on every start, they may have been
+        // rotated since.
+        supervisor::spawn_supervised("Metabucket monitor".to_string(), move || {
+            let read_cfg = cfg.read().unwrap();
+            let metadata_bucket = read_cfg.server.metadata_bucket.clone();
+            let metadata_endpoint = read_cfg.server.metadata_endpoint.clone();
+            let access_key = read_cfg.server.access_key.clone();
+            let secret_key = read_cfg.server.secret_key.clone();
+            drop(read_cfg);
 
-        let mut c = minio::Client::new(&metadata_endpoint).expect("Could not connect metabucket");
-        c.set_credentials(Credentials::new(&access_key, &secret_key));
+            let mut c =
+                minio::Client::new(&metadata_endpoint).expect("Could not connect metabucket");
+            c.set_credentials(Credentials::new(&access_key, &secret_key));
 
-        let cfg = Arc::clone(&self.config);
-        let task = c
-            .listen_bucket_notification(
+            let cfg = Arc::clone(&cfg);
+            c.listen_bucket_notification(
                 &metadata_bucket,
                 None,
                 None,
@@ -223,7 +212,7 @@ impl Meta {
                     "s3:ObjectRemoved:*".to_string(),
                 ],
             )
-            .map_err(|_| ())
+            .map_err(|e| error!("Metabucket notifications errored: {:?}", e))
             .for_each(move |x| {
                 for record in x.records {
                     let cfg = Arc::clone(&cfg);
@@ -236,9 +225,8 @@ impl Meta {
                     }
                 }
                 Ok(())
-            });
-
-        hyper::rt::spawn(task);
+            })
+        });
     }
 }
 
@@ -274,75 +262,151 @@ fn load_config_for_key(cfg: Arc<RwLock<Config>>, object_key: String) {
                     ()
                 })
                 .and_then(move |bytes| {
-                    let result = String::from_utf8(bytes.to_vec()).unwrap();
-
-                    let parts: Vec<&str> = file_key_clone
-                        .trim_start_matches("minsql/meta/")
-                        .split("/")
-                        .collect();
-                    match (parts.len(), parts[0]) {
-                        (2, "logs") => match serde_json::from_str(&result) {
-                            Ok(log) => {
-                                let mut cfg_write = cfg2.write().unwrap();
-                                info!("Loading log: {}", &parts[1]);
-                                cfg_write.log.insert(parts[1].to_string(), log);
-                                drop(cfg_write);
-                            }
-                            Err(e) => {
-                                error!("error loading log configuration {}", e);
-                            }
-                        },
-                        (2, "datastores") => match serde_json::from_str(&result) {
-                            Ok(datastore) => {
-                                let mut cfg_write = cfg2.write().unwrap();
-                                info!("Loading datastore: {}", &parts[1]);
-                                cfg_write.datastore.insert(parts[1].to_string(), datastore);
-                                drop(cfg_write);
-                            }
-                            Err(e) => {
-                                error!("error loading datastore configuration {}", e);
-                            }
-                        },
-                        (2, "tokens") => match serde_json::from_str(&result) {
-                            Ok(token) => {
-                                let mut cfg_write = cfg2.write().unwrap();
-                                info!("Loading token: {}", &parts[1]);
-                                cfg_write.tokens.insert(parts[1].to_string(), token);
-                                drop(cfg_write);
-                            }
-                            Err(e) => {
-                                error!("error loading datastore configuration {}", e);
-                            }
-                        },
-                        (3, "auth") => match serde_json::from_str(&result) {
-                            Ok(log_auth) => {
-                                let mut cfg_write = cfg2.write().unwrap();
-                                info!("Loading auth: {}", &parts[1]);
-                                let auth_logs = match cfg_write.auth.entry(parts[1].to_string()) {
-                                    Entry::Occupied(o) => o.into_mut(),
-                                    Entry::Vacant(v) => v.insert(HashMap::new()),
-                                };
-                                auth_logs.insert(parts[2].to_string(), log_auth);
-                                drop(cfg_write);
-                            }
-                            Err(e) => {
-                                error!("error loading auth configuration {}", e);
-                            }
-                        },
-                        _ => (),
-                    };
+                    apply_config_object(&cfg2, &file_key_clone, &bytes);
                     Ok(())
                 })
         });
     hyper::rt::spawn(sub_task);
 }
 
+/// Applies a configuration object of the metabucket to the configuration, as it's loaded when
+/// created and by the admin API once it wrote it.
+pub fn apply_config_object(cfg: &Arc<RwLock<Config>>, object_key: &str, payload: &[u8]) {
+    // on an invalid object the previous version of the configuration is kept
+    let mco = parse_meta_object(object_key, payload);
+    let mut cfg_write = cfg.write().unwrap();
+    match mco {
+        MetaConfigObject::Log(log) => {
+            let name = log.name.clone().unwrap();
+            info!("Loading log: {}", &name);
+            cfg_write.log.insert(name, log);
+        }
+        MetaConfigObject::DataStore(datastore) => {
+            let name = datastore.name.clone().unwrap();
+            info!("Loading datastore: {}", &name);
+            cfg_write.datastore.insert(name, datastore);
+        }
+        MetaConfigObject::Token(token) => {
+            info!("Loading token: {}", &token.access_key);
+            cfg_write.tokens.insert(token.access_key.clone(), token);
+        }
+        // patterns are compiled on every query, so the next queries pick up the
+        // new expression
+        MetaConfigObject::Pattern((name, pattern)) => {
+            info!("Loading pattern: {}", &name);
+            cfg_write.patterns.insert(name, pattern);
+        }
+        MetaConfigObject::LogAuth((token, log_name, log_auth)) => {
+            info!("Loading auth: {}", &token);
+            let auth_logs = match cfg_write.auth.entry(token) {
+                Entry::Occupied(o) => o.into_mut(),
+                Entry::Vacant(v) => v.insert(HashMap::new()),
+            };
+            auth_logs.insert(log_name, log_auth);
+        }
+        MetaConfigObject::Invalid((key, e)) => {
+            error!(
+                "Keeping the previous configuration, {} is invalid: {}",
+                key, e
+            );
+            record_problem(key, e);
+        }
+        MetaConfigObject::Unknown => (),
+    };
+}
+
+/// Validates a smart field pattern override, it must be for a known smart field and compile.
+fn validate_smart_pattern(name: &str, pattern: &SmartPattern) -> Result<(), String> {
+    if pattern_id_for_name(name).is_none() {
+        return Err(format!("unknown smart field {}", name));
+    }
+    if !valid_pattern_expression(&pattern.expression) {
+        return Err("expression doesn't compile".to_string());
+    }
+    Ok(())
+}
+
+/// Parses a configuration object of the metabucket into its type, objects that don't match their
+/// type are `Invalid` with the reason. A valid object clears the problems reported for its key.
+fn parse_meta_object(object_key: &str, payload: &[u8]) -> MetaConfigObject {
+    let payload = match str::from_utf8(payload) {
+        Ok(p) => p,
+        Err(e) => {
+            let e = format!("not valid UTF-8: {}", e);
+            return MetaConfigObject::Invalid((object_key.to_string(), e));
+        }
+    };
+    match typed_meta_object(object_key, payload) {
+        Ok(MetaConfigObject::Invalid(problem)) => MetaConfigObject::Invalid(problem),
+        Ok(mco) => {
+            clear_problem(object_key);
+            mco
+        }
+        // serde reports the field along with the line and column
+        Err(e) => MetaConfigObject::Invalid((object_key.to_string(), e.to_string())),
+    }
+}
+
+fn typed_meta_object(
+    object_key: &str,
+    payload: &str,
+) -> Result<MetaConfigObject, serde_json::Error> {
+    let parts = meta_key_parts(object_key);
+    match (parts.len(), parts[0]) {
+        // logs and datastores are named by their key, which is what removing them relies on
+        (2, "logs") => serde_json::from_str::<Log>(payload).map(|mut log| {
+            log.name = Some(parts[1].to_string());
+            MetaConfigObject::Log(log)
+        }),
+        (2, "datastores") => serde_json::from_str::<DataStore>(payload).map(|mut ds| {
+            ds.name = Some(parts[1].to_string());
+            MetaConfigObject::DataStore(ds)
+        }),
+        (2, "tokens") => serde_json::from_str(payload).map(MetaConfigObject::Token),
+        (2, "patterns") => {
+            let pattern: SmartPattern = serde_json::from_str(payload)?;
+            Ok(match validate_smart_pattern(parts[1], &pattern) {
+                Ok(()) => MetaConfigObject::Pattern((parts[1].to_string(), pattern)),
+                Err(e) => MetaConfigObject::Invalid((object_key.to_string(), e)),
+            })
+        }
+        (3, "auth") => serde_json::from_str(payload).map(|log_auth| {
+            let (token, log_name) = (parts[1].to_string(), parts[2].to_string());
+            MetaConfigObject::LogAuth((token, log_name, log_auth))
+        }),
+        _ => Ok(MetaConfigObject::Unknown),
+    }
+}
+
+/// Records why a configuration object of the metabucket couldn't be loaded
+fn record_problem(object_key: String, error: String) {
+    META_PROBLEMS.lock().unwrap().insert(object_key, error);
+}
+
+fn clear_problem(object_key: &str) {
+    META_PROBLEMS.lock().unwrap().remove(object_key);
+}
+
+/// Configuration objects of the metabucket that couldn't be loaded along with the reason, sorted
+/// by key
+pub fn meta_problems() -> BTreeMap<String, String> {
+    META_PROBLEMS.lock().unwrap().clone()
+}
+
+/// Splits a metabucket object key into the kind of configuration followed by its identifiers.
+/// Log names can contain `/`, so the last identifier is kept whole.
+fn meta_key_parts(object_key: &str) -> Vec<&str> {
+    let key = object_key.trim_start_matches("minsql/meta/");
+    match key.split("/").next() {
+        Some("auth") => key.splitn(3, "/").collect(),
+        _ => key.splitn(2, "/").collect(),
+    }
+}
+
 /// Attemps to remove a configuration by object key
-fn remove_config_for_key(cfg: Arc<RwLock<Config>>, object_key: String) {
-    let parts: Vec<&str> = object_key
-        .trim_start_matches("minsql/meta/")
-        .split("/")
-        .collect();
+pub fn remove_config_for_key(cfg: Arc<RwLock<Config>>, object_key: String) {
+    clear_problem(&object_key);
+    let parts = meta_key_parts(&object_key);
     match (parts.len(), parts[0]) {
         (2, "logs") => {
             let mut cfg_write = cfg.write().unwrap();
@@ -356,6 +420,13 @@ fn remove_config_for_key(cfg: Arc<RwLock<Config>>, object_key: String) {
             cfg_write.datastore.remove(parts[1]);
             drop(cfg_write);
         }
+        (2, "patterns") => {
+            // falls back to the built-in expression
+            let mut cfg_write = cfg.write().unwrap();
+            info!("Removing pattern: {}", &parts[1]);
+            cfg_write.patterns.remove(parts[1]);
+            drop(cfg_write);
+        }
         (3, "auth") => {
             let mut cfg_write = cfg.write().unwrap();
             info!("Removing auth: {}", &parts[1]);
@@ -381,6 +452,8 @@ pub fn ds_for_metabucket(cfg: Arc<RwLock<Config>>) -> DataStore {
         bucket: read_cfg.server.metadata_bucket.clone(),
         prefix: "".to_owned(),
         name: Some("metabucket".to_owned()),
+        max_upload_bytes_per_sec: None,
+        max_download_bytes_per_sec: None,
     }
 }
 
@@ -390,5 +463,58 @@ enum MetaConfigObject {
     DataStore(DataStore),
     LogAuth((String, String, LogAuth)),
     Token(Token),
+    Pattern((String, SmartPattern)),
+    // the key of an object that couldn't be parsed and why
+    Invalid((String, String)),
     Unknown,
 }
+
+#[cfg(test)]
+mod meta_tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_log_named_by_key() {
+        let payload = br#"{"datastores": ["ds1"], "commit_window": "5s"}"#;
+        match parse_meta_object("minsql/meta/logs/team/service", payload) {
+            MetaConfigObject::Log(log) => assert_eq!(log.name, Some("team/service".to_string())),
+            mco => panic!("Expected a log, got {:?}", mco),
+        }
+    }
+
+    #[test]
+    fn parse_invalid_objects_report_the_problem() {
+        // `commit_window` is missing
+        let payload = br#"{"name": "mylog", "datastores": ["ds1"]}"#;
+        match parse_meta_object("minsql/meta/logs/mylog", payload) {
+            MetaConfigObject::Invalid((key, e)) => {
+                assert_eq!(key, "minsql/meta/logs/mylog");
+                assert!(e.contains("commit_window"), "{}", e);
+                assert!(e.contains("line 1"), "{}", e);
+            }
+            mco => panic!("Expected a problem, got {:?}", mco),
+        }
+
+        match parse_meta_object("minsql/meta/patterns/zipcode", br#"{"expression": "\\d+"}"#) {
+            MetaConfigObject::Invalid((_, e)) => assert!(e.contains("unknown smart field")),
+            mco => panic!("Expected a problem, got {:?}", mco),
+        }
+        match parse_meta_object("minsql/meta/tokens/TOKEN1", &[0xff, 0xfe]) {
+            MetaConfigObject::Invalid((_, e)) => assert!(e.contains("UTF-8")),
+            mco => panic!("Expected a problem, got {:?}", mco),
+        }
+    }
+
+    #[test]
+    fn valid_object_clears_its_problem() {
+        let key = "minsql/meta/datastores/problematic";
+        record_problem(key.to_string(), "expected value".to_string());
+        assert!(meta_problems().contains_key(key));
+        let payload = br#"{"endpoint": "http://localhost:9000", "access_key": "a", "secret_key": "b", "bucket": "b1", "prefix": ""}"#;
+        match parse_meta_object(key, payload) {
+            MetaConfigObject::DataStore(_) => (),
+            mco => panic!("Expected a datastore, got {:?}", mco),
+        }
+        assert!(!meta_problems().contains_key(key));
+    }
+}
@@ -0,0 +1,116 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use regex::Regex;
+
+use crate::config::MultilineRule;
+use crate::constants::MULTILINE_JOINER;
+
+/// Compiled `MultilineRule` of a log
+pub struct MultilineJoiner {
+    continuation: Option<Regex>,
+    indented: bool,
+}
+
+impl MultilineJoiner {
+    pub fn new(rule: &MultilineRule) -> Result<MultilineJoiner, String> {
+        let continuation = match &rule.continuation {
+            Some(expr) => Some(
+                Regex::new(expr)
+                    .map_err(|e| format!("Invalid multiline continuation regex: {}", e))?,
+            ),
+            None => None,
+        };
+        if continuation.is_none() && !rule.indented {
+            return Err("Multiline rule needs a continuation regex or indented".to_string());
+        }
+        Ok(MultilineJoiner {
+            continuation,
+            indented: rule.indented,
+        })
+    }
+
+    /// Whether the line continues the record of the previous line
+    pub fn continues(&self, line: &str) -> bool {
+        (self.indented && line.starts_with(|c: char| c == ' ' || c == '\t'))
+            || self
+                .continuation
+                .as_ref()
+                .map_or(false, |re| re.is_match(line))
+    }
+
+    /// Joins the continuation lines of the payload into the line starting their record, so every
+    /// record is stored as a single line. Empty lines end a record and are kept as they are.
+    pub fn join(&self, payload: &str) -> String {
+        let mut records: Vec<String> = Vec::new();
+        let mut open = false;
+        for line in payload.split('\n') {
+            if open && !line.is_empty() && self.continues(line) {
+                let record = records.last_mut().unwrap();
+                record.push_str(MULTILINE_JOINER);
+                record.push_str(line);
+            } else {
+                open = !line.is_empty();
+                records.push(line.to_string());
+            }
+        }
+        records.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod multiline_tests {
+    use super::*;
+
+    fn joiner(continuation: Option<&str>, indented: bool) -> MultilineJoiner {
+        MultilineJoiner::new(&MultilineRule {
+            continuation: continuation.map(|s| s.to_string()),
+            indented,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn join_indented_lines() {
+        let payload =
+            "Exception in thread main\n\tat a.b(C.java:1)\n\tat a.d(C.java:2)\nnext record\n";
+        assert_eq!(
+            joiner(None, true).join(payload),
+            "Exception in thread main\\n\tat a.b(C.java:1)\\n\tat a.d(C.java:2)\nnext record\n"
+        );
+    }
+
+    #[test]
+    fn join_continuation_regex() {
+        let payload =
+            "Traceback (most recent call last):\nValueError: bad\nCaused by: x\nok\n\nCaused by: y";
+        assert_eq!(
+            joiner(Some("^Caused by:"), false).join(payload),
+            "Traceback (most recent call last):\nValueError: bad\\nCaused by: x\nok\n\nCaused by: y"
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let rule = |continuation: Option<&str>, indented| MultilineRule {
+            continuation: continuation.map(|s| s.to_string()),
+            indented,
+        };
+        assert!(MultilineJoiner::new(&rule(None, false)).is_err());
+        assert!(MultilineJoiner::new(&rule(Some("(unclosed"), false)).is_err());
+        assert!(MultilineJoiner::new(&rule(Some("^\\s"), false)).is_ok());
+    }
+}
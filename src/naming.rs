@@ -0,0 +1,439 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use hyper::{Body, Request};
+use uuid::Uuid;
+
+use crate::config::Log;
+use crate::constants::{
+    DEFAULT_OBJECT_KEY, GENERATION_SEGMENT_PREFIX, HEADER_PARTITION, PARTITION_MAX_LEN,
+};
+
+// `{partition}` first, the rest only resolve once an object is written
+const PLACEHOLDERS: [&str; 6] = [
+    "{partition}",
+    "{year}",
+    "{month}",
+    "{day}",
+    "{hour}",
+    "{uuid}",
+];
+
+/// Times lines were stored at, from `from` on and before `until`, unbounded on a missing end
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimeRange {
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl TimeRange {
+    /// The times on both ranges
+    pub fn intersect(self, other: TimeRange) -> TimeRange {
+        TimeRange {
+            from: self.from.max(other.from),
+            until: match (self.until, other.until) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+
+    /// Whether `time` is on the range
+    pub fn contains(&self, time: &DateTime<Utc>) -> bool {
+        self.from.map_or(true, |from| *time >= from)
+            && self.until.map_or(true, |until| *time < until)
+    }
+
+    /// Whether any time from `start` and before `end` is on the range
+    pub fn overlaps(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> bool {
+        self.from.map_or(true, |from| *end > from)
+            && self.until.map_or(true, |until| *start < until)
+    }
+}
+
+/// How the objects of a log are named under `minsql/{log}/`, from the `object_key` template of
+/// the log. Restricted to a partition it only locates the objects of that partition, and within
+/// a time range the objects written on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectNaming {
+    segments: Vec<String>,
+    partition: Option<String>,
+    within: Option<TimeRange>,
+}
+
+impl ObjectNaming {
+    /// Parses a template of `/` separated segments. The last segment must be `{uuid}` so
+    /// every object gets its own key, and `{partition}` can only be combined with literal text.
+    pub fn parse(template: &str) -> Result<ObjectNaming, String> {
+        let segments: Vec<String> = template.split('/').map(String::from).collect();
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err("Object key template can't have empty segments".to_string());
+        }
+        if segments.last().map(|s| s.as_str()) != Some("{uuid}") {
+            return Err("Object key template must end with a `{uuid}` segment".to_string());
+        }
+        for segment in &segments {
+            let mut rest = segment.clone();
+            for placeholder in PLACEHOLDERS.iter() {
+                rest = rest.replace(placeholder, "");
+            }
+            if rest.contains('{') || rest.contains('}') {
+                return Err(format!(
+                    "Unknown placeholder on object key template segment `{}`",
+                    segment
+                ));
+            }
+            let placeholders = PLACEHOLDERS.iter().filter(|p| segment.contains(*p)).count();
+            if segment.contains("{partition}") && placeholders > 1 {
+                return Err(format!(
+                    "`{{partition}}` can't be combined with other placeholders on segment `{}`",
+                    segment
+                ));
+            }
+        }
+        Ok(ObjectNaming {
+            segments,
+            partition: None,
+            within: None,
+        })
+    }
+
+    /// Naming of the objects of a log, the default layout if it has no valid template
+    pub fn for_log(log: &Log) -> ObjectNaming {
+        log.object_key
+            .as_ref()
+            .and_then(|template| ObjectNaming::parse(template).ok())
+            .unwrap_or_else(|| ObjectNaming::parse(DEFAULT_OBJECT_KEY).unwrap())
+    }
+
+    /// Restricts the naming to the objects of a partition, the partition is ignored if the
+    /// template doesn't use it
+    pub fn in_partition(mut self, partition: Option<&str>) -> ObjectNaming {
+        self.partition = match partition {
+            Some(p) if self.has_partition() => Some(p.to_string()),
+            _ => None,
+        };
+        self
+    }
+
+    /// Restricts the naming to the objects written within a time range
+    pub fn within(mut self, range: Option<TimeRange>) -> ObjectNaming {
+        self.within = range;
+        self
+    }
+
+    /// Whether objects are written per partition
+    pub fn has_partition(&self) -> bool {
+        self.segments.iter().any(|s| s.contains("{partition}"))
+    }
+
+    /// Key of a new object written at `now`
+    pub fn key(&self, log_name: &str, now: &DateTime<Utc>, uuid: &Uuid) -> String {
+        let date = now.date();
+        let resolved: Vec<String> = self
+            .segments
+            .iter()
+            .map(|segment| {
+                segment
+                    .replace(
+                        "{partition}",
+                        self.partition.as_ref().map_or("", |p| p.as_str()),
+                    )
+                    .replace("{year}", &date.year().to_string())
+                    .replace("{month}", &date.month().to_string())
+                    .replace("{day}", &date.day().to_string())
+                    .replace("{hour}", &now.hour().to_string())
+                    .replace("{uuid}", &uuid.to_string())
+            })
+            .collect();
+        format!("minsql/{}/{}.log", log_name, resolved.join("/"))
+    }
+
+    /// Prefix all the objects located are under, as long as the leading segments of the
+    /// template are known, which time segments are when the whole time range shares them
+    pub fn prefix(&self, log_name: &str) -> String {
+        let mut prefix = format!("minsql/{}/", log_name);
+        for segment in &self.segments {
+            match self
+                .resolve_static(segment)
+                .or_else(|| self.resolve_time(segment))
+            {
+                Some(resolved) => {
+                    prefix.push_str(&resolved);
+                    prefix.push('/');
+                }
+                None => break,
+            }
+        }
+        prefix
+    }
+
+    /// Whether a key relative to `minsql/{log}/` is an object with this naming, keys of logs
+    /// nested under the log have more segments. Objects whose write time can't be told from
+    /// their key are always on the time range.
+    pub fn matches(&self, relative_key: &str) -> bool {
+        let parts: Vec<&str> = relative_key.split('/').collect();
+        if parts.len() != self.segments.len() || !relative_key.ends_with(".log") {
+            return false;
+        }
+        let in_partition = match &self.partition {
+            Some(_) => self
+                .segments
+                .iter()
+                .zip(&parts)
+                .filter(|(segment, _)| segment.contains("{partition}"))
+                .all(|(segment, part)| {
+                    self.resolve_static(segment).as_ref().map(|s| s.as_str()) == Some(*part)
+                }),
+            None => true,
+        };
+        in_partition
+            && match (&self.within, self.written_within(&parts)) {
+                (Some(range), Some((start, end))) => range.overlaps(&start, &end),
+                _ => true,
+            }
+    }
+
+    /// Span of time an object was written on, from the time segments of its key. It narrows
+    /// from the year down for as long as the month, day and hour follow each other, segments
+    /// mixing a time placeholder with other text are not read.
+    fn written_within(&self, parts: &[&str]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let mut values: [Option<u32>; 4] = [None; 4];
+        for (segment, part) in self.segments.iter().zip(parts) {
+            if let Some(unit) = PLACEHOLDERS[1..5].iter().position(|p| segment == p) {
+                values[unit] = Some(part.parse().ok()?);
+            }
+        }
+        let year = values[0]? as i32;
+        match (values[1], values[2], values[3]) {
+            (Some(month), Some(day), Some(hour)) => {
+                let start = Utc
+                    .ymd_opt(year, month, day)
+                    .single()?
+                    .and_hms_opt(hour, 0, 0)?;
+                Some((start, start + Duration::hours(1)))
+            }
+            (Some(month), Some(day), None) => {
+                let start = Utc.ymd_opt(year, month, day).single()?.and_hms(0, 0, 0);
+                Some((start, start + Duration::days(1)))
+            }
+            (Some(month), None, _) => {
+                let start = Utc.ymd_opt(year, month, 1).single()?.and_hms(0, 0, 0);
+                let end = match month {
+                    12 => Utc.ymd(year + 1, 1, 1),
+                    _ => Utc.ymd(year, month + 1, 1),
+                };
+                Some((start, end.and_hms(0, 0, 0)))
+            }
+            (None, _, _) => Some((
+                Utc.ymd_opt(year, 1, 1).single()?.and_hms(0, 0, 0),
+                Utc.ymd_opt(year + 1, 1, 1).single()?.and_hms(0, 0, 0),
+            )),
+        }
+    }
+
+    /// Resolves a time segment to the value every time on the time range has for it, ie: the
+    /// `{day}` of a range within a single day
+    fn resolve_time(&self, segment: &str) -> Option<String> {
+        let unit = PLACEHOLDERS[1..5].iter().position(|p| segment == *p)?;
+        let range = self.within?;
+        let first = range.from?;
+        let last = range.until? - Duration::nanoseconds(1);
+        if first > last {
+            return None;
+        }
+        let units = |t: &DateTime<Utc>| [t.year() as u32, t.month(), t.day(), t.hour()];
+        let (first, last) = (units(&first), units(&last));
+        if first[..=unit] != last[..=unit] {
+            return None;
+        }
+        Some(first[unit].to_string())
+    }
+
+    /// Resolves a segment without time or uuid placeholders
+    fn resolve_static(&self, segment: &str) -> Option<String> {
+        if PLACEHOLDERS[1..].iter().any(|p| segment.contains(p)) {
+            return None;
+        }
+        if segment.contains("{partition}") {
+            let partition = self.partition.as_ref()?;
+            return Some(segment.replace("{partition}", partition));
+        }
+        Some(segment.to_string())
+    }
+}
+
+/// An object key template under a literal segment of its own, for a log created with the name
+/// of a log whose objects are still around
+pub fn new_generation(template: Option<&String>) -> String {
+    format!(
+        "{}{}/{}",
+        GENERATION_SEGMENT_PREFIX,
+        &Uuid::new_v4().to_simple().to_string()[..8],
+        template.map_or(DEFAULT_OBJECT_KEY, |t| t.as_str())
+    )
+}
+
+/// Whether a partition sent by a client can be part of an object key
+pub fn valid_partition(partition: &str) -> bool {
+    !partition.is_empty()
+        && partition.len() <= PARTITION_MAX_LEN
+        && partition != "."
+        && partition != ".."
+        && partition
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Partition sent on the `MINSQL-PARTITION` header of a request, if any
+pub fn partition_header(req: &Request<Body>) -> Result<Option<String>, String> {
+    let value = match req.headers().get(HEADER_PARTITION) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.to_str() {
+        Ok(partition) if valid_partition(partition) => Ok(Some(partition.to_string())),
+        _ => Err(format!(
+            "Invalid partition, it can have up to {} letters, digits, `-`, `_` or `.`",
+            PARTITION_MAX_LEN
+        )),
+    }
+}
+
+#[cfg(test)]
+mod naming_tests {
+    use super::*;
+
+    fn tenant_naming() -> ObjectNaming {
+        ObjectNaming::parse("tenant={partition}/{year}/{month}/{day}/{hour}/{uuid}").unwrap()
+    }
+
+    #[test]
+    fn default_key_layout() {
+        let naming = ObjectNaming::parse(DEFAULT_OBJECT_KEY).unwrap();
+        let now = Utc.ymd(2019, 7, 24).and_hms(9, 30, 0);
+        let uuid = Uuid::nil();
+        assert_eq!(
+            naming.key("mylog", &now, &uuid),
+            format!("minsql/mylog/2019/7/24/9/{}.log", uuid)
+        );
+        assert_eq!(naming.prefix("mylog"), "minsql/mylog/");
+        assert!(!naming.has_partition());
+    }
+
+    #[test]
+    fn partitioned_keys_are_pruned_by_prefix() {
+        let naming = tenant_naming().in_partition(Some("acme"));
+        let now = Utc.ymd(2019, 7, 24).and_hms(9, 30, 0);
+        let key = naming.key("applogs", &now, &Uuid::nil());
+        assert!(key.starts_with("minsql/applogs/tenant=acme/2019/7/24/9/"));
+        assert_eq!(naming.prefix("applogs"), "minsql/applogs/tenant=acme/");
+        assert_eq!(tenant_naming().prefix("applogs"), "minsql/applogs/");
+    }
+
+    #[test]
+    fn matches_partition_anywhere() {
+        let naming = ObjectNaming::parse("{year}/{partition}/{uuid}").unwrap();
+        assert!(naming.matches("2019/acme/a.log"));
+        assert!(!naming.matches("2019/acme/nested/a.log"));
+        assert!(!naming.matches("2019/acme/a.log.bloom"));
+        let acme = naming.in_partition(Some("acme"));
+        assert_eq!(acme.prefix("applogs"), "minsql/applogs/");
+        assert!(acme.matches("2019/acme/a.log"));
+        assert!(!acme.matches("2019/globex/a.log"));
+    }
+
+    #[test]
+    fn objects_within_a_time_range() {
+        let naming = ObjectNaming::parse(DEFAULT_OBJECT_KEY).unwrap();
+        let range = TimeRange {
+            from: Some(Utc.ymd(2019, 7, 24).and_hms(9, 30, 0)),
+            until: Some(Utc.ymd(2019, 7, 24).and_hms(11, 0, 0)),
+        };
+        let within = naming.clone().within(Some(range));
+        assert_eq!(within.prefix("mylog"), "minsql/mylog/2019/7/24/");
+        assert!(within.matches("2019/7/24/9/a.log"));
+        assert!(within.matches("2019/7/24/10/a.log"));
+        assert!(!within.matches("2019/7/24/11/a.log"));
+        assert!(!within.matches("2019/7/23/10/a.log"));
+        // a range over midnight shares the month only
+        let overnight = naming.clone().within(Some(TimeRange {
+            from: Some(Utc.ymd(2019, 7, 24).and_hms(23, 0, 0)),
+            until: Some(Utc.ymd(2019, 7, 25).and_hms(1, 0, 0)),
+        }));
+        assert_eq!(overnight.prefix("mylog"), "minsql/mylog/2019/7/");
+        assert!(overnight.matches("2019/7/25/0/a.log"));
+        let since = naming.within(Some(TimeRange {
+            from: Some(Utc.ymd(2019, 7, 24).and_hms(0, 0, 0)),
+            until: None,
+        }));
+        assert_eq!(since.prefix("mylog"), "minsql/mylog/");
+        assert!(since.matches("2020/1/1/0/a.log"));
+        assert!(!since.matches("2019/6/30/23/a.log"));
+    }
+
+    #[test]
+    fn coarse_time_segments() {
+        let range = TimeRange {
+            from: Some(Utc.ymd(2019, 12, 31).and_hms(22, 0, 0)),
+            until: None,
+        };
+        let monthly = ObjectNaming::parse("{year}/{month}/{uuid}")
+            .unwrap()
+            .within(Some(range));
+        assert!(monthly.matches("2019/12/a.log"));
+        assert!(!monthly.matches("2019/11/a.log"));
+        // keys without a readable time are always listed
+        let untimed = ObjectNaming::parse("{partition}/{uuid}")
+            .unwrap()
+            .within(Some(range));
+        assert!(untimed.matches("acme/a.log"));
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(ObjectNaming::parse("{year}/{month}").is_err());
+        assert!(ObjectNaming::parse("{year}//{uuid}").is_err());
+        assert!(ObjectNaming::parse("{tenant}/{uuid}").is_err());
+        assert!(ObjectNaming::parse("{partition}-{year}/{uuid}").is_err());
+        assert!(ObjectNaming::parse("p-{partition}/{uuid}").is_ok());
+    }
+
+    #[test]
+    fn partition_values() {
+        assert!(valid_partition("acme-corp_1.eu"));
+        assert!(!valid_partition(""));
+        assert!(!valid_partition(".."));
+        assert!(!valid_partition("acme/eu"));
+        assert!(!valid_partition(&"a".repeat(PARTITION_MAX_LEN + 1)));
+    }
+
+    #[test]
+    fn generations() {
+        let template = "tenant={partition}/{uuid}".to_string();
+        let recreated = new_generation(Some(&template));
+        assert!(recreated.starts_with("gen-"));
+        assert!(recreated.ends_with("/tenant={partition}/{uuid}"));
+        let naming = ObjectNaming::parse(&recreated).unwrap();
+        assert_eq!(
+            naming.prefix("applogs"),
+            format!("minsql/applogs/{}/", &recreated[..12])
+        );
+        assert_ne!(new_generation(None), new_generation(None));
+        assert!(ObjectNaming::parse(&new_generation(None)).is_ok());
+    }
+}
@@ -0,0 +1,240 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future::{self, Loop};
+use futures::Future;
+use hyper::{Body, Request};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::DataStore;
+use crate::constants::{
+    HEADER_CONTINUATION, HEADER_PAGE_SIZE, MOVED_MAX_HOPS, MOVED_SUFFIX, SEARCH_MAX_PAGE_SIZE,
+};
+use crate::signing::hex;
+use crate::storage::{get_object, GetObjectError, StorageError};
+
+/// Where a paged search stopped: the line following the last row it returned. Clients get it
+/// base64 encoded on the `MINSQL-CONTINUATION` header and send it back as is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContinuationToken {
+    // digest of the query the token was issued for, see `query_digest`
+    pub query: String,
+    pub datastore: String,
+    pub key: String,
+    // byte offset of the object the next line starts at
+    pub offset: u64,
+    // rows returned by the pages so far, counted against the `LIMIT` of the query
+    pub returned: u64,
+}
+
+impl ContinuationToken {
+    pub fn encode(&self) -> String {
+        base64::encode_config(&serde_json::to_vec(self).unwrap(), base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(token: &str) -> Result<ContinuationToken, String> {
+        let invalid = || "Invalid continuation token".to_string();
+        let bytes = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid())
+    }
+
+    /// Whether the token resumes a search of the query `sql`
+    pub fn resumes(&self, sql: &str) -> bool {
+        self.query == query_digest(sql)
+    }
+}
+
+/// Digest tying a continuation token to the query it was issued for
+pub fn query_digest(sql: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(sql.as_bytes());
+    hex(&hasher.result()[..16])
+}
+
+/// The page a search asks for with the `MINSQL-PAGE-SIZE` header, along with where to resume
+/// from when it sends a `MINSQL-CONTINUATION` token
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageRequest {
+    pub size: usize,
+    pub token: Option<ContinuationToken>,
+}
+
+/// Reads the paging headers of a search, `None` if it's not paged
+pub fn page_request(req: &Request<Body>) -> Result<Option<PageRequest>, String> {
+    let token = match req.headers().get(HEADER_CONTINUATION) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| "Invalid continuation token".to_string())
+                .and_then(|token| ContinuationToken::decode(token.trim()))?,
+        ),
+        None => None,
+    };
+    let size = match req.headers().get(HEADER_PAGE_SIZE) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|size| size.trim().parse::<usize>().ok())
+            .filter(|size| *size > 0 && *size <= SEARCH_MAX_PAGE_SIZE)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid page size, it must be between 1 and {}",
+                    SEARCH_MAX_PAGE_SIZE
+                )
+            })?,
+        None if token.is_some() => {
+            return Err(format!(
+                "A continuation token needs the {} header",
+                HEADER_PAGE_SIZE
+            ))
+        }
+        None => return Ok(None),
+    };
+    Ok(Some(PageRequest { size, token }))
+}
+
+/// Left by compaction in place of each object it merged: the merged object and the offset the
+/// lines of the original start at in it. Tokens issued before the compaction resume from there.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MovedLines {
+    pub key: String,
+    pub offset: u64,
+}
+
+impl MovedLines {
+    /// Where an offset of the original object is on the merged one
+    pub fn resumed_at(&self, offset: u64) -> (String, u64) {
+        (self.key.clone(), self.offset + offset)
+    }
+}
+
+/// Key of the marker left where an object was compacted, it's not listed as an object of the log
+pub fn moved_key(object_key: &str) -> String {
+    format!("{}{}", object_key, MOVED_SUFFIX)
+}
+
+/// Where the line at `offset` of an object is now, following the markers of the compactions the
+/// object went through. An object without a marker is where it was.
+pub fn resolve_moved(
+    datastore: &DataStore,
+    key: String,
+    offset: u64,
+) -> impl Future<Item = (String, u64), Error = String> {
+    let datastore = datastore.clone();
+    future::loop_fn((key, offset, 0), move |(key, offset, hops)| {
+        get_object(&datastore, moved_key(&key)).then(move |res| match res {
+            Ok((body, _)) => {
+                let moved: MovedLines = serde_json::from_slice(&body)
+                    .map_err(|e| format!("Invalid marker for moved object {}: {}", key, e))?;
+                let (key, offset) = moved.resumed_at(offset);
+                if hops + 1 >= MOVED_MAX_HOPS {
+                    return Ok(Loop::Break((key, offset)));
+                }
+                Ok(Loop::Continue((key, offset, hops + 1)))
+            }
+            Err(StorageError::Operation(GetObjectError::NoSuchKey(_))) => {
+                Ok(Loop::Break((key, offset)))
+            }
+            Err(e) => Err(format!("Could not read the marker of {}: {:?}", key, e)),
+        })
+    })
+}
+
+#[cfg(test)]
+mod pagination_tests {
+    use super::*;
+
+    fn token() -> ContinuationToken {
+        ContinuationToken {
+            query: query_digest("SELECT * FROM mylog"),
+            datastore: "ds1".to_string(),
+            key: "minsql/mylog/2019/07/24/09/b7c1".to_string(),
+            offset: 4096,
+            returned: 100,
+        }
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::post("/search");
+        for (name, value) in headers {
+            req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let encoded = token().encode();
+        assert!(!encoded.contains('='));
+        assert_eq!(ContinuationToken::decode(&encoded), Ok(token()));
+        assert!(ContinuationToken::decode("not a token").is_err());
+        assert!(ContinuationToken::decode(&base64::encode("{}")).is_err());
+    }
+
+    #[test]
+    fn moved_lines_resume_on_the_merged_object() {
+        let moved = MovedLines {
+            key: "minsql/mylog/2019/07/24/09/a1~5d0c.log".to_string(),
+            offset: 1000,
+        };
+        assert_eq!(
+            moved_key("minsql/mylog/2019/07/24/09/b7c1.log"),
+            "minsql/mylog/2019/07/24/09/b7c1.log.moved"
+        );
+        assert_eq!(
+            moved.resumed_at(24),
+            ("minsql/mylog/2019/07/24/09/a1~5d0c.log".to_string(), 1024)
+        );
+    }
+
+    #[test]
+    fn tokens_resume_their_query() {
+        assert!(token().resumes("SELECT * FROM mylog"));
+        assert!(!token().resumes("SELECT * FROM mylog LIMIT 10"));
+    }
+
+    #[test]
+    fn page_headers() {
+        assert_eq!(page_request(&request(&[])), Ok(None));
+        assert_eq!(
+            page_request(&request(&[(HEADER_PAGE_SIZE, "500")])),
+            Ok(Some(PageRequest {
+                size: 500,
+                token: None
+            }))
+        );
+        let encoded = token().encode();
+        assert_eq!(
+            page_request(&request(&[
+                (HEADER_PAGE_SIZE, "500"),
+                (HEADER_CONTINUATION, &encoded)
+            ])),
+            Ok(Some(PageRequest {
+                size: 500,
+                token: Some(token())
+            }))
+        );
+        assert!(page_request(&request(&[(HEADER_PAGE_SIZE, "0")])).is_err());
+        assert!(page_request(&request(&[(HEADER_PAGE_SIZE, "100000")])).is_err());
+        assert!(page_request(&request(&[(HEADER_CONTINUATION, &encoded)])).is_err());
+        assert!(page_request(&request(&[
+            (HEADER_PAGE_SIZE, "500"),
+            (HEADER_CONTINUATION, "zz")
+        ]))
+        .is_err());
+    }
+}
@@ -0,0 +1,184 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use serde_derive::Deserialize;
+use serde_json::Value as JsonValue;
+
+/// Search body sent as JSON, so values can be bound to the placeholders of the query, ie:
+/// `{"query": "SELECT * FROM mylog WHERE $ip = :ip", "params": {"ip": "10.0.0.1"}}`
+#[derive(Deserialize)]
+struct ParameterizedQuery {
+    query: String,
+    #[serde(default)]
+    params: HashMap<String, JsonValue>,
+}
+
+/// Splits a search body into the SQL and its parameters. Bodies that are not a JSON object are
+/// plain SQL.
+pub fn parse_search_body(payload: &str) -> Result<(String, HashMap<String, JsonValue>), String> {
+    if !payload.trim_start().starts_with('{') {
+        return Ok((payload.to_string(), HashMap::new()));
+    }
+    match serde_json::from_str::<ParameterizedQuery>(payload) {
+        Ok(pq) => Ok((pq.query, pq.params)),
+        Err(e) => Err(format!("Could not parse parameterized query: {}", e)),
+    }
+}
+
+/// Replaces the `:name` placeholders of a query with their values as SQL literals. Placeholders
+/// within quotes are left untouched, names are case insensitive.
+pub fn bind_parameters(sql: &str, params: &HashMap<String, JsonValue>) -> Result<String, String> {
+    let params: HashMap<String, &JsonValue> = params
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), value))
+        .collect();
+    let chars: Vec<char> = sql.chars().collect();
+    let mut bound = String::with_capacity(sql.len());
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+                bound.push(c);
+                i += 1;
+            }
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                bound.push(c);
+                i += 1;
+            }
+            None if c == ':' && i + 1 < chars.len() && is_name_start(chars[i + 1]) => {
+                let start = i + 1;
+                let mut end = start;
+                while end < chars.len() && is_name_char(chars[end]) {
+                    end += 1;
+                }
+                let name: String = chars[start..end].iter().collect();
+                match params.get(&name.to_lowercase()) {
+                    Some(value) => bound.push_str(&sql_literal(value)?),
+                    None => return Err(format!("Missing value for parameter :{}", name)),
+                }
+                i = end;
+            }
+            None => {
+                bound.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(bound)
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// SQL literal of a parameter value, strings are quoted so they can't alter the query
+fn sql_literal(value: &JsonValue) -> Result<String, String> {
+    match value {
+        JsonValue::String(s) => Ok(format!("'{}'", s.replace('\'', "''"))),
+        JsonValue::Number(n) => Ok(n.to_string()),
+        JsonValue::Bool(b) => Ok(b.to_string()),
+        JsonValue::Null => Ok("NULL".to_string()),
+        _ => Err("Parameters must be strings, numbers, booleans or null".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod params_tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn params(value: JsonValue) -> HashMap<String, JsonValue> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn bind_values_as_literals() {
+        let bound = bind_parameters(
+            "SELECT * FROM mylog WHERE $ip = :target_ip AND $4 = :Status LIMIT :n",
+            &params(json!({"target_ip": "10.0.0.1", "status": 404, "n": 10})),
+        )
+        .unwrap();
+        assert_eq!(
+            bound,
+            "SELECT * FROM mylog WHERE $ip = '10.0.0.1' AND $4 = 404 LIMIT 10"
+        );
+    }
+
+    #[test]
+    fn bind_escapes_quotes() {
+        let bound = bind_parameters(
+            "SELECT * FROM mylog WHERE $email = :email",
+            &params(json!({"email": "x' OR '1'='1"})),
+        )
+        .unwrap();
+        assert_eq!(
+            bound,
+            "SELECT * FROM mylog WHERE $email = 'x'' OR ''1''=''1'"
+        );
+    }
+
+    #[test]
+    fn placeholders_in_quotes_are_kept() {
+        let bound = bind_parameters(
+            "SELECT * FROM mylog WHERE $line LIKE '%10:30:%' AND $quoted = :q",
+            &params(json!({"q": "a"})),
+        )
+        .unwrap();
+        assert_eq!(
+            bound,
+            "SELECT * FROM mylog WHERE $line LIKE '%10:30:%' AND $quoted = 'a'"
+        );
+    }
+
+    #[test]
+    fn missing_and_invalid_parameters() {
+        assert!(bind_parameters("SELECT * FROM mylog WHERE $ip = :ip", &HashMap::new()).is_err());
+        assert!(bind_parameters(
+            "SELECT * FROM mylog WHERE $ip = :ip",
+            &params(json!({"ip": ["10.0.0.1"]}))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_plain_and_json_bodies() {
+        let (sql, params) = parse_search_body("SELECT * FROM mylog").unwrap();
+        assert_eq!(sql, "SELECT * FROM mylog");
+        assert!(params.is_empty());
+
+        let (sql, params) = parse_search_body(
+            r#"{"query": "SELECT * FROM mylog WHERE $ip = :ip", "params": {"ip": "10.0.0.1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(sql, "SELECT * FROM mylog WHERE $ip = :ip");
+        assert_eq!(params["ip"], "10.0.0.1");
+
+        assert!(parse_search_body("{not json").is_err());
+    }
+}
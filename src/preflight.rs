@@ -0,0 +1,280 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::TcpListener;
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use native_tls::Identity;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use crate::config::{load_configuration_from, Config, DataStore};
+use crate::constants::PREFLIGHT_PREFIX;
+use crate::hyperscan::{hyperscan_supported, hyperscan_version};
+use crate::meta::{ds_for_metabucket, meta_problems, Meta};
+use crate::storage::{can_reach_datastore, delete_object, get_object, put_object};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Ok,
+    // the server runs, degraded
+    Warn,
+    Fail,
+}
+
+/// Outcome of one of the checks of `minsql preflight`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, status: CheckStatus, detail: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            status,
+            detail: detail.to_string(),
+        }
+    }
+
+    fn from_result(name: &str, res: Result<String, String>) -> Check {
+        match res {
+            Ok(detail) => Check::new(name, CheckStatus::Ok, &detail),
+            Err(detail) => Check::new(name, CheckStatus::Fail, &detail),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            CheckStatus::Ok => " ok ",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "FAIL",
+        };
+        write!(f, "[{}] {}: {}", status, self.name, self.detail)
+    }
+}
+
+/// The report printed by `minsql preflight`, a line per check followed by a summary
+pub fn render_report(checks: &[Check]) -> String {
+    let mut report: String = checks.iter().map(|check| format!("{}\n", check)).collect();
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Fail)
+        .count();
+    let warned = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Warn)
+        .count();
+    report.push_str(&format!(
+        "{} checks, {} failed, {} warnings\n",
+        checks.len(),
+        failed,
+        warned
+    ));
+    report
+}
+
+/// `minsql preflight`, checks the deployment the server would start on without starting it.
+/// Takes the same arguments as the server and fails if any check fails.
+pub fn preflight_command<I: IntoIterator<Item = String>>(args: I) -> Result<(), String> {
+    let checks = run_checks(args);
+    print!("{}", render_report(&checks));
+    if checks.iter().any(|check| check.status == CheckStatus::Fail) {
+        return Err("Preflight failed".to_string());
+    }
+    Ok(())
+}
+
+fn run_checks<I: IntoIterator<Item = String>>(args: I) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let cfg = match load_configuration_from(args) {
+        Ok(cfg) => {
+            checks.push(Check::new("server config", CheckStatus::Ok, "loaded"));
+            Arc::new(RwLock::new(cfg))
+        }
+        Err(e) => {
+            // nothing else can be checked without it
+            checks.push(Check::new(
+                "server config",
+                CheckStatus::Fail,
+                &e.to_string(),
+            ));
+            return checks;
+        }
+    };
+    checks.push(Check::from_result("tls", check_tls(&cfg.read().unwrap())));
+    checks.push(check_hyperscan());
+    checks.push(Check::from_result("port", check_port(&cfg.read().unwrap())));
+
+    let metabucket = check_reachable(&ds_for_metabucket(Arc::clone(&cfg)));
+    let reachable = metabucket.is_ok();
+    checks.push(Check::from_result("metabucket", metabucket));
+    if !reachable {
+        return checks;
+    }
+    let meta_c = Meta::new(Arc::clone(&cfg));
+    tokio::run(future::lazy(move || meta_c.load_config_from_metabucket()));
+    let problems = meta_problems();
+    if problems.is_empty() {
+        checks.push(Check::new(
+            "metabucket config",
+            CheckStatus::Ok,
+            "every object loaded",
+        ));
+    }
+    for (key, error) in problems {
+        checks.push(Check::new(
+            &format!("metabucket config {}", key),
+            CheckStatus::Fail,
+            &error,
+        ));
+    }
+
+    let mut datastores: Vec<DataStore> = cfg.read().unwrap().datastore.values().cloned().collect();
+    datastores.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            checks.push(Check::new(
+                "datastores",
+                CheckStatus::Fail,
+                &format!("Could not start the runtime: {}", e),
+            ));
+            return checks;
+        }
+    };
+    for ds in datastores {
+        let name = format!("datastore {}", ds.name.clone().unwrap_or_default());
+        let res = check_reachable(&ds).and_then(|_| runtime.block_on(probe_datastore(&ds)));
+        checks.push(Check::from_result(&name, res));
+    }
+    checks
+}
+
+/// Both the certificate and its password are needed, and the certificate has to open with it
+fn check_tls(cfg: &Config) -> Result<String, String> {
+    let (cert, password) = match (&cfg.server.pkcs12_cert, &cfg.server.pkcs12_password) {
+        (None, None) => return Ok("not configured, serving plain HTTP".to_string()),
+        (Some(cert), Some(password)) => (cert, password),
+        _ => return Err("PKCS12 cert or password is missing".to_string()),
+    };
+    let mut der = Vec::new();
+    File::open(cert)
+        .and_then(|mut file| file.read_to_end(&mut der))
+        .map_err(|e| format!("Could not read {}: {}", cert, e))?;
+    Identity::from_pkcs12(&der, password)
+        .map(|_| format!("{} opens", cert))
+        .map_err(|e| format!("Could not open {}: {}", cert, e))
+}
+
+fn check_hyperscan() -> Check {
+    if hyperscan_supported() {
+        Check::new("hyperscan", CheckStatus::Ok, &hyperscan_version())
+    } else {
+        Check::new(
+            "hyperscan",
+            CheckStatus::Warn,
+            "not supported on this machine, smart fields use the slower regex engine",
+        )
+    }
+}
+
+/// Whether the server address can be bound, which it can't if another process listens on it
+fn check_port(cfg: &Config) -> Result<String, String> {
+    let address = &cfg.server.address;
+    TcpListener::bind(address)
+        .map(|_| format!("{} is free", address))
+        .map_err(|e| format!("Could not bind {}: {}", address, e))
+}
+
+fn check_reachable(ds: &DataStore) -> Result<String, String> {
+    match can_reach_datastore(ds) {
+        Ok(true) => Ok(format!("bucket {} reachable", ds.bucket)),
+        Ok(false) => Err(format!("{} is not reachable", ds.endpoint)),
+        Err(e) => Err(format!("bucket {} is not reachable: {:?}", ds.bucket, e)),
+    }
+}
+
+/// Writes, reads back and deletes an object, the permissions the server needs on a datastore
+fn probe_datastore(ds: &DataStore) -> impl Future<Item = String, Error = String> {
+    let key = format!("{}{}", PREFLIGHT_PREFIX, Uuid::new_v4());
+    let body = key.clone().into_bytes();
+    let read_ds = ds.clone();
+    let delete_ds = ds.clone();
+    let read_key = key.clone();
+    let delete_key = key.clone();
+    put_object(ds, key, body.clone(), None)
+        .map_err(|e| format!("write failed: {}", e.reason()))
+        .and_then(move |_| {
+            get_object(&read_ds, read_key)
+                .map_err(|e| format!("read failed: {:?}", e))
+                .and_then(move |(read, _)| {
+                    if read == body {
+                        Ok(())
+                    } else {
+                        Err("read back a different object than written".to_string())
+                    }
+                })
+        })
+        .and_then(move |_| {
+            delete_object(&delete_ds, delete_key)
+                .map_err(|e| format!("delete failed: {}", e.reason()))
+        })
+        .map(|_| "write, read and delete probe passed".to_string())
+}
+
+#[cfg(test)]
+mod preflight_tests {
+    use super::*;
+    use crate::config::Server;
+
+    #[test]
+    fn preflight_report() {
+        let checks = vec![
+            Check::new("server config", CheckStatus::Ok, "loaded"),
+            Check::new("hyperscan", CheckStatus::Warn, "not supported"),
+            Check::new("datastore ds1", CheckStatus::Fail, "write failed: denied"),
+        ];
+        assert_eq!(
+            render_report(&checks),
+            "[ ok ] server config: loaded\n\
+             [warn] hyperscan: not supported\n\
+             [FAIL] datastore ds1: write failed: denied\n\
+             3 checks, 1 failed, 1 warnings\n"
+        );
+    }
+
+    #[test]
+    fn tls_needs_cert_and_password() {
+        let mut cfg = Config::new(Server::default());
+        assert!(check_tls(&cfg).is_ok());
+        cfg.server.pkcs12_cert = Some("/nonexistent/cert.p12".to_string());
+        assert_eq!(
+            check_tls(&cfg),
+            Err("PKCS12 cert or password is missing".to_string())
+        );
+        cfg.server.pkcs12_password = Some("secret".to_string());
+        assert!(check_tls(&cfg).unwrap_err().starts_with("Could not read"));
+    }
+}
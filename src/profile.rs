@@ -0,0 +1,216 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Poll, Stream};
+use serde_json::json;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    // listing the objects of the log
+    List,
+    // reading the objects, from the request until their last line arrives
+    Fetch,
+    Decode,
+    // matching the smart field patterns, with Hyperscan or the regex fallback
+    Hyperscan,
+    Filter,
+    Serialize,
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct StageTimes {
+    pub list: Duration,
+    pub fetch: Duration,
+    pub decode: Duration,
+    pub hyperscan: Duration,
+    pub filter: Duration,
+    pub serialize: Duration,
+}
+
+impl StageTimes {
+    pub fn add(&mut self, stage: Stage, elapsed: Duration) {
+        let total = match stage {
+            Stage::List => &mut self.list,
+            Stage::Fetch => &mut self.fetch,
+            Stage::Decode => &mut self.decode,
+            Stage::Hyperscan => &mut self.hyperscan,
+            Stage::Filter => &mut self.filter,
+            Stage::Serialize => &mut self.serialize,
+        };
+        *total += elapsed;
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "list_ms": millis(self.list),
+            "fetch_ms": millis(self.fetch),
+            "decode_ms": millis(self.decode),
+            "hyperscan_ms": millis(self.hyperscan),
+            "filter_ms": millis(self.filter),
+            "serialize_ms": millis(self.serialize),
+        })
+    }
+}
+
+fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_micros() as f64 / 1000.0
+}
+
+/// Time a search spent on each stage, per datastore the lines came from. Listing and fetching
+/// are wall times of concurrent requests, the other stages are the time spent processing the
+/// lines.
+#[derive(Default)]
+pub struct QueryProfile {
+    sources: Mutex<BTreeMap<String, StageTimes>>,
+}
+
+impl QueryProfile {
+    pub fn record(&self, source: &str, stage: Stage, elapsed: Duration) {
+        let mut sources = self.sources.lock().unwrap();
+        if !sources.contains_key(source) {
+            sources.insert(source.to_string(), StageTimes::default());
+        }
+        sources.get_mut(source).unwrap().add(stage, elapsed);
+    }
+
+    pub fn record_all(&self, source: &str, times: &StageTimes) {
+        for (stage, elapsed) in &[
+            (Stage::List, times.list),
+            (Stage::Fetch, times.fetch),
+            (Stage::Decode, times.decode),
+            (Stage::Hyperscan, times.hyperscan),
+            (Stage::Filter, times.filter),
+            (Stage::Serialize, times.serialize),
+        ] {
+            self.record(source, *stage, *elapsed);
+        }
+    }
+
+    /// Line sent after the results of a profiled search
+    pub fn trailer(&self) -> String {
+        let sources: serde_json::Map<String, serde_json::Value> = self
+            .sources
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(source, times)| (source.clone(), times.to_json()))
+            .collect();
+        json!({ "$profile": sources }).to_string() + "\n"
+    }
+}
+
+/// Records the wall time of a stream as a stage, from its first poll until it ends or is dropped
+pub struct Timed<S> {
+    inner: S,
+    profile: Option<Arc<QueryProfile>>,
+    source: String,
+    stage: Stage,
+    started: Option<Instant>,
+}
+
+/// Times `inner` if the search is being profiled
+pub fn timed<S: Stream>(
+    inner: S,
+    profile: Option<Arc<QueryProfile>>,
+    source: &str,
+    stage: Stage,
+) -> Timed<S> {
+    Timed {
+        inner,
+        profile,
+        source: source.to_string(),
+        stage,
+        started: None,
+    }
+}
+
+impl<S> Timed<S> {
+    fn finish(&mut self) {
+        if let (Some(profile), Some(started)) = (self.profile.take(), self.started) {
+            profile.record(&self.source, self.stage, started.elapsed());
+        }
+    }
+}
+
+impl<S: Stream> Stream for Timed<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if self.profile.is_some() && self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+        let res = self.inner.poll();
+        match res {
+            Ok(Async::Ready(None)) | Err(_) => self.finish(),
+            _ => (),
+        }
+        res
+    }
+}
+
+impl<S> Drop for Timed<S> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use futures::{stream, Future};
+
+    use super::*;
+
+    #[test]
+    fn stages_add_up_per_source() {
+        let profile = QueryProfile::default();
+        profile.record("ds1", Stage::Decode, Duration::from_micros(1500));
+        profile.record("ds1", Stage::Decode, Duration::from_micros(500));
+        profile.record("ds2", Stage::Filter, Duration::from_millis(3));
+        let trailer: serde_json::Value = serde_json::from_str(&profile.trailer()).unwrap();
+        assert_eq!(trailer["$profile"]["ds1"]["decode_ms"], json!(2.0));
+        assert_eq!(trailer["$profile"]["ds1"]["filter_ms"], json!(0.0));
+        assert_eq!(trailer["$profile"]["ds2"]["filter_ms"], json!(3.0));
+    }
+
+    #[test]
+    fn timed_streams_record_when_done() {
+        let profile = Arc::new(QueryProfile::default());
+        let lines = timed(
+            stream::iter_ok::<_, ()>(vec![1, 2, 3]),
+            Some(Arc::clone(&profile)),
+            "ds1",
+            Stage::List,
+        );
+        assert_eq!(lines.collect().wait(), Ok(vec![1, 2, 3]));
+        assert_eq!(profile.sources.lock().unwrap().len(), 1);
+
+        // a search stopping at its limit drops the stream before it ends
+        let profile = Arc::new(QueryProfile::default());
+        let lines = timed(
+            stream::iter_ok::<_, ()>(vec![1, 2, 3]),
+            Some(Arc::clone(&profile)),
+            "ds1",
+            Stage::Fetch,
+        );
+        assert_eq!(lines.take(1).collect().wait(), Ok(vec![1]));
+        assert_eq!(profile.sources.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::{SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use log::info;
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+lazy_static! {
+    // Searches running on this process, keyed by id
+    static ref QUERIES: Mutex<HashMap<String, RegisteredQuery>> = Mutex::new(HashMap::new());
+}
+
+/// A running search as reported by `GET /api/queries`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RunningQuery {
+    pub id: String,
+    pub sql: String,
+    // access key of the token running it
+    pub token: String,
+    pub started_at: String,
+    pub bytes_scanned: u64,
+    // asked to stop, it ends once its readers notice
+    pub cancelled: bool,
+}
+
+struct RegisteredQuery {
+    sql: String,
+    token: String,
+    started_at: String,
+    scanned: Arc<AtomicUsize>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RegisteredQuery {
+    fn report(&self, id: &str) -> RunningQuery {
+        RunningQuery {
+            id: id.to_string(),
+            sql: self.sql.clone(),
+            token: self.token.clone(),
+            started_at: self.started_at.clone(),
+            bytes_scanned: self.scanned.load(Ordering::SeqCst) as u64,
+            cancelled: self.cancelled.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A search listed on the registry for as long as the handle lives, it's removed once dropped
+pub struct QueryHandle {
+    id: String,
+    scanned: Arc<AtomicUsize>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl QueryHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Accounts the bytes of lines read by the search
+    pub fn scanned(&self, bytes: usize) {
+        self.scanned.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Whether the search was asked to stop, its readers stop reading once it is
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for QueryHandle {
+    fn drop(&mut self) {
+        QUERIES.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Lists a search started by `access_token` on the registry
+pub fn register_query(sql: &str, access_token: &str) -> QueryHandle {
+    let id = Uuid::new_v4().to_string();
+    let scanned = Arc::new(AtomicUsize::new(0));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    QUERIES.lock().unwrap().insert(
+        id.clone(),
+        RegisteredQuery {
+            sql: sql.to_string(),
+            token: access_token[..16].to_string(),
+            started_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            scanned: Arc::clone(&scanned),
+            cancelled: Arc::clone(&cancelled),
+        },
+    );
+    QueryHandle {
+        id,
+        scanned,
+        cancelled,
+    }
+}
+
+/// Searches running on this process, the longest running first
+pub fn running_queries() -> Vec<RunningQuery> {
+    let mut queries: Vec<RunningQuery> = QUERIES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, registered)| registered.report(id))
+        .collect();
+    queries.sort_by(|a, b| a.started_at.cmp(&b.started_at).then(a.id.cmp(&b.id)));
+    queries
+}
+
+/// Asks a search of this process to stop, `None` if there's no such search
+pub fn cancel_query(id: &str) -> Option<RunningQuery> {
+    let queries = QUERIES.lock().unwrap();
+    let registered = queries.get(id)?;
+    if !registered.cancelled.swap(true, Ordering::SeqCst) {
+        info!("Cancelling query {}", id);
+    }
+    Some(registered.report(id))
+}
+
+#[cfg(test)]
+mod queries_tests {
+    use super::*;
+
+    const TOKEN: &str = "TOKEN1TOKEN1TOKE0123456789abcdef0123456789abcdef";
+
+    fn listed(id: &str) -> Option<RunningQuery> {
+        running_queries().into_iter().find(|query| query.id == id)
+    }
+
+    #[test]
+    fn queries_are_listed_while_running() {
+        let handle = register_query("SELECT * FROM mylog", TOKEN);
+        handle.scanned(1024);
+        handle.scanned(512);
+        let query = listed(handle.id()).unwrap();
+        assert_eq!(query.sql, "SELECT * FROM mylog");
+        assert_eq!(query.token, "TOKEN1TOKEN1TOKE");
+        assert_eq!(query.bytes_scanned, 1536);
+        assert!(!query.cancelled);
+        let id = handle.id().to_string();
+        drop(handle);
+        assert_eq!(listed(&id), None);
+    }
+
+    #[test]
+    fn cancelled_queries() {
+        let handle = register_query("SELECT $ip FROM mylog", TOKEN);
+        assert!(!handle.is_cancelled());
+        let cancelled = cancel_query(handle.id()).unwrap();
+        assert!(cancelled.cancelled);
+        assert!(handle.is_cancelled());
+        // cancelling again is harmless, the query is gone once it stops
+        assert!(cancel_query(handle.id()).is_some());
+        let id = handle.id().to_string();
+        drop(handle);
+        assert_eq!(cancel_query(&id), None);
+    }
+}
@@ -14,45 +14,101 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error;
 use std::error::Error;
 use std::fmt;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use chrono::{FixedOffset, SecondsFormat, Utc};
+use futures::future::Either;
 use futures::sink::Sink;
-use futures::{stream, Future, Stream};
-use hyper::{Body, Chunk, Request, Response};
+use futures::{future, stream, Async, Future, Poll, Stream};
+use hyper::{header, Body, Chunk, Method, Request, Response, StatusCode};
 use log::{error, info};
 use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
-use sqlparser::ast::{BinaryOperator, Expr, SelectItem, SetExpr, Statement, Value};
+use sqlparser::ast::{
+    BinaryOperator, Expr, OrderByExpr, SelectItem, SetExpr, Statement, UnaryOperator, Value,
+};
 use sqlparser::parser::Parser;
 use sqlparser::parser::ParserError;
+use sqlparser::tokenizer::{Token, Tokenizer};
 use tokio::sync::mpsc;
 
 use lazy_static::lazy_static;
 
-use crate::auth::Auth;
+use crate::aggregate::{
+    compare_values, is_aggregate_projection, parse_aggregate, AggregateColumn, Aggregation,
+    GroupColumn, GroupTable,
+};
+use crate::api::etag_for;
+use crate::auth::{access_key_of, Auth, LogAccess};
+use crate::caches::Cache;
 use crate::combinators::take_from_iterable::TakeFromIterable;
-use crate::config::Config;
+use crate::combinators::take_within_budget::{OutputBudget, TakeWithinBudget};
+use crate::computed::resolve_computed_fields;
+use crate::config::{Config, DataStore, Log, SmartPattern};
 use crate::constants;
-use crate::constants::{SF_USER_AGENT, SMART_FIELDS_RAW_RE};
+use crate::constants::{
+    AGGREGATE_MAX_GROUPS, APP_JSON, APP_NDJSON, CONSISTENCY_STRONG, ENCODING_BASE64,
+    ESTIMATE_DEFAULT_LATENCY_MS, ESTIMATE_SCAN_BYTES_PER_SEC, HEADER_CONTINUATION,
+    HEADER_ELAPSED_MS, HEADER_HIGHLIGHT, HEADER_MATCHED_LINES, HEADER_OBJECTS_SCANNED,
+    HEADER_OBJECTS_TOTAL, HEADER_SAVED_SEARCH, HEADER_TIMEZONE, ORDER_BY_MAX_ROWS,
+    PARAM_HEADER_PREFIX, PROFILE_BUFFERED_SOURCE, QUERY_PLANS_MAX_ENTRIES, SAVED_SEARCH_MAX_LEN,
+    SF_DATE, SF_IP, SF_JSON, SF_KV, SF_USER_AGENT, SMART_FIELDS_RAW_RE, STAMP_PREPEND, TEXT_CSV,
+    TEXT_EVENT_STREAM, USER_AGENT_SUBFIELDS,
+};
 use crate::dialect::MinSQLDialect;
-use crate::filter::line_fails_query_conditions;
+use crate::federation::{remote_search, remote_statement};
+use crate::filter::{
+    has_date_predicate, line_fails_query_conditions, matched_spans, received_range,
+    required_literals, rewrite_like_escapes, time_range,
+};
+use crate::functions::{
+    evaluate_projection, is_computed_projection, parse_projection_expr, parse_timezone, ColumnType,
+    ComputedColumn,
+};
 use crate::http::GenericError;
 use crate::http::ResponseFuture;
-use crate::http::{return_400, return_401};
+use crate::http::{bool_header, return_400, return_403, return_404, return_500};
 use crate::hyperscan::{
-    build_hs_db, found_patterns_in_line, HSLineScanner, HSPatternMatch, HSPatternMatchResults,
+    build_hs_db, found_patterns_in_line, kv_value, HSLineScanner, HSPatternMatch,
+    HSPatternMatchResults, PatternDb, ScanFlags,
+};
+use crate::ingest::{received_at, Ingest, IngestBuffers};
+use crate::latency::{fastest_replicas, latency_table};
+use crate::naming::{partition_header, valid_partition, ObjectNaming, TimeRange};
+use crate::pagination::{
+    page_request, query_digest, resolve_moved, ContinuationToken, PageRequest,
 };
-use crate::storage::{list_msl_bucket_files, read_file_line_by_line};
-use hyperscan::BlockDatabase;
+use crate::params::{bind_parameters, parse_search_body};
+use crate::profile::{timed, QueryProfile, Stage, StageTimes};
+use crate::queries::{register_query, QueryHandle};
+use crate::signing::{verify_results, ManifestLine, ResultsDigest};
+use crate::storage::{
+    list_msl_bucket_files, list_msl_bucket_objects, read_bloom_filter, read_file_line_by_line,
+    read_file_offset_lines, read_from_replicas, read_received_range, ListObjectsError,
+    StorageError,
+};
+use crate::supervisor;
+use crate::tiering::range_reaches_cold_tier;
+use crate::trace::request_trace;
+use crate::usage::record_scanned;
+use crate::watch::watch;
 
 lazy_static! {
     static ref SMART_FIELDS_RE: Regex = Regex::new(SMART_FIELDS_RAW_RE).unwrap();
+    // where the tokenizer stopped, as it reports it in its errors
+    static ref TOKENIZER_LOCATION_RE: Regex = Regex::new(r"Line: (\d+), Column:? (\d+)").unwrap();
+    // Planned statements of saved searches, keyed by access key, saved search and statement
+    pub static ref QUERY_PLANS: Cache<SharedPlan> =
+        Cache::bounded("query_plans", QUERY_PLANS_MAX_ENTRIES);
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -73,6 +129,24 @@ struct SmartColumn {
     subfield: Option<String>,
 }
 
+/// A statement reads a log missing from the configuration
+#[derive(Debug)]
+pub struct UnknownLogError {
+    log_name: String,
+}
+
+impl fmt::Display for UnknownLogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Log {} not found", self.log_name)
+    }
+}
+
+impl error::Error for UnknownLogError {
+    fn description(&self) -> &str {
+        "Log not found"
+    }
+}
+
 #[derive(Debug)]
 pub struct ParseSqlError;
 
@@ -93,14 +167,203 @@ impl error::Error for ParseSqlError {
     }
 }
 
+/// A query rejected by the SQL tokenizer or parser, with where it went wrong so clients can
+/// point at it
+#[derive(Debug, PartialEq)]
+pub struct SqlSyntaxError {
+    pub message: String,
+    // offset of the offending token in the query, in characters
+    pub position: Option<usize>,
+    // 1-based line and column of `position`
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    // the offending token, `None` when the query ended too early
+    pub token: Option<String>,
+}
+
+impl SqlSyntaxError {
+    fn new(message: String, sql: &str, position: Option<usize>, token: Option<String>) -> Self {
+        let (line, column) = match position {
+            Some(position) => {
+                let before: String = sql.chars().take(position).collect();
+                let line = before.matches('\n').count() + 1;
+                let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+        SqlSyntaxError {
+            message,
+            position,
+            line,
+            column,
+            token,
+        }
+    }
+}
+
+impl fmt::Display for SqlSyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                write!(f, "{} (line {}, column {})", self.message, line, column)
+            }
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl error::Error for SqlSyntaxError {
+    fn description(&self) -> &str {
+        "Error parsing sql"
+    }
+}
+
+/// Locates the token a parse error is about. The parser doesn't report positions, so the
+/// statements are replayed up to the failure and the tokens it didn't get to tell where it
+/// stopped.
+fn locate_syntax_error(sql: &str, error: ParserError) -> SqlSyntaxError {
+    let message = match error {
+        ParserError::TokenizerError(message) => {
+            let position = TOKENIZER_LOCATION_RE
+                .captures(&message)
+                .and_then(|c| Some((c[1].parse::<usize>().ok()?, c[2].parse::<usize>().ok()?)))
+                .map(|(line, column)| char_offset(sql, line, column));
+            return SqlSyntaxError::new(message, sql, position, None);
+        }
+        ParserError::ParserError(message) => message,
+    };
+    let dialect = MinSQLDialect {};
+    let tokens = match Tokenizer::new(&dialect, sql).tokenize() {
+        Ok(tokens) => tokens,
+        Err(_) => return SqlSyntaxError::new(message, sql, None, None),
+    };
+    // tokens print as they are written, so they are found one after the other in the query
+    let mut cursor = 0;
+    let mut words = Vec::new();
+    for token in &tokens {
+        let text = token.to_string();
+        let start = sql[cursor..].find(&text).map(|i| cursor + i);
+        if let Some(start) = start {
+            cursor = start + text.len();
+        }
+        if let Token::Whitespace(_) = token {
+            continue;
+        }
+        words.push((start.map(|start| sql[..start].chars().count()), text));
+    }
+    let mut parser = Parser::new(tokens);
+    let mut expecting_delimiter = false;
+    loop {
+        while parser.consume_token(&Token::SemiColon) {
+            expecting_delimiter = false;
+        }
+        if parser.peek_token().is_none() || expecting_delimiter {
+            break;
+        }
+        if parser.parse_statement().is_err() {
+            break;
+        }
+        expecting_delimiter = true;
+    }
+    let mut remaining = 0;
+    while let Some(token) = parser.next_token() {
+        if let Token::Whitespace(_) = token {
+            continue;
+        }
+        remaining += 1;
+    }
+    // the offending token was either peeked at or already taken by the parser
+    let next = words.len() - remaining;
+    let named = |i: usize| {
+        words
+            .get(i)
+            .filter(|(_, text)| message.ends_with(&format!("found: {}", text)))
+    };
+    let offending = named(next)
+        .or_else(|| next.checked_sub(1).and_then(named))
+        .or_else(|| words.get(next));
+    match offending {
+        Some((position, text)) => {
+            let (position, text) = (*position, text.clone());
+            SqlSyntaxError::new(message, sql, position, Some(text))
+        }
+        None => {
+            let end = sql.trim_end().chars().count();
+            SqlSyntaxError::new(message, sql, Some(end), None)
+        }
+    }
+}
+
+/// Offset in characters of a 1-based line and column of the query, clamped to its length
+fn char_offset(sql: &str, line: usize, column: usize) -> usize {
+    let before: usize = sql
+        .split('\n')
+        .take(line.saturating_sub(1))
+        .map(|l| l.chars().count() + 1)
+        .sum();
+    (before + column.saturating_sub(1)).min(sql.chars().count())
+}
+
+/// Responds to a query that couldn't be parsed with where it went wrong
+/// The response to a query that couldn't be planned
+fn return_processing_error(e: ProcessingQueryError) -> Response<Body> {
+    match e {
+        ProcessingQueryError::Fail(s) => return_400(&s),
+        ProcessingQueryError::UnsupportedQuery(s) => return_400(&s),
+        ProcessingQueryError::NoTableFound(s) => return_400(&s),
+        ProcessingQueryError::UnknownLog(_s) => return_404(),
+        ProcessingQueryError::Forbidden(_s) => return_403(),
+    }
+}
+
+fn return_syntax_error(e: &SqlSyntaxError) -> Response<Body> {
+    let output = json!({
+        "message": format!("Bad request: {}", e.message),
+        "position": e.position,
+        "line": e.line,
+        "column": e.column,
+        "token": e.token,
+    });
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .body(Body::from(output.to_string()))
+        .unwrap()
+}
+
 #[derive(Debug)]
 pub enum QueryError {
     Underlying(String),
+    // the lines buffered by the query went over the limit, in bytes
+    MemoryLimitExceeded(usize),
+    // the query stopped taking results
+    Closed,
+    // the query went over the groups it may hold
+    GroupLimitExceeded(usize),
+    // the query went over the rows it may hold to sort them
+    SortLimitExceeded(usize),
+    // the query was cancelled from the admin API
+    Cancelled,
 }
 
 impl fmt::Display for QueryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            QueryError::MemoryLimitExceeded(limit) => {
+                write!(f, "Query exceeded its memory limit of {} bytes", limit)
+            }
+            QueryError::GroupLimitExceeded(limit) => {
+                write!(f, "Query exceeded its limit of {} groups", limit)
+            }
+            QueryError::SortLimitExceeded(limit) => write!(
+                f,
+                "Query exceeded its limit of {} rows to sort, add a LIMIT",
+                limit
+            ),
+            QueryError::Cancelled => write!(f, "Query was cancelled"),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -114,6 +377,145 @@ pub struct Query {
     config: Arc<RwLock<Config>>,
 }
 
+/// Reads the query parameters sent as `MINSQL-PARAM-<name>` headers
+fn param_headers(req: &Request<Body>) -> HashMap<String, serde_json::Value> {
+    let mut params = HashMap::new();
+    for (name, value) in req.headers() {
+        let name = name.as_str();
+        if name.len() > PARAM_HEADER_PREFIX.len()
+            && name[..PARAM_HEADER_PREFIX.len()].eq_ignore_ascii_case(PARAM_HEADER_PREFIX)
+        {
+            match value.to_str() {
+                Ok(v) => {
+                    params.insert(
+                        name[PARAM_HEADER_PREFIX.len()..].to_string(),
+                        serde_json::Value::String(v.to_string()),
+                    );
+                }
+                Err(e) => error!("Could not parse parameter header {}: {}", name, e),
+            }
+        }
+    }
+    params
+}
+
+/// The UTC offset on the `MINSQL-TIMEZONE` header of a search, UTC without it
+fn timezone_header(req: &Request<Body>) -> Result<FixedOffset, String> {
+    match req.headers().get(HEADER_TIMEZONE) {
+        Some(value) => match value.to_str() {
+            Ok(timezone) => parse_timezone(timezone),
+            Err(_) => Err("Invalid timezone, it must be an offset like +02:00 or UTC".to_string()),
+        },
+        None => Ok(FixedOffset::east(0)),
+    }
+}
+
+/// The id on the `MINSQL-SAVED-SEARCH` header of a search, `None` without it
+fn saved_search_header(req: &Request<Body>) -> Result<Option<String>, String> {
+    let value = match req.headers().get(HEADER_SAVED_SEARCH) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.to_str() {
+        Ok(id) if valid_partition(id) && id.len() <= SAVED_SEARCH_MAX_LEN => {
+            Ok(Some(id.to_string()))
+        }
+        _ => Err(format!(
+            "Invalid saved search, it can have up to {} letters, digits, `-`, `_` or `.`",
+            SAVED_SEARCH_MAX_LEN
+        )),
+    }
+}
+
+/// The hot datastores of a log a search reads, each along with the replicas it falls back to.
+/// Every datastore holds objects of its own, unless the log is replicated: it's then read once,
+/// from the fastest healthy datastore.
+fn hot_replica_sets(log: &Log, cfg: &Config) -> Vec<Vec<String>> {
+    let datastores: Vec<String> = log
+        .datastores
+        .iter()
+        .filter(|ds_name| cfg.datastore.contains_key(*ds_name))
+        .cloned()
+        .collect();
+    if !log.replicated {
+        return datastores
+            .into_iter()
+            .map(|ds_name| vec![ds_name])
+            .collect();
+    }
+    if datastores.is_empty() {
+        return Vec::new();
+    }
+    vec![fastest_replicas(&datastores)]
+}
+
+/// Whether an object holds lines received on `range`, from the receive times it records. Objects
+/// recording none or whose metadata can't be read are read.
+fn received_on_range(
+    key: String,
+    ds: &DataStore,
+    range: Option<TimeRange>,
+) -> impl Future<Item = (String, bool), Error = QueryError> {
+    let range = match range {
+        Some(range) => range,
+        None => return Either::A(future::ok((key, true))),
+    };
+    Either::B(read_received_range(&key, ds).then(move |res| {
+        let on_range = match res {
+            // the times are recorded to the millisecond
+            Ok(Some((first, last))) => {
+                range.overlaps(&first, &(last + chrono::Duration::milliseconds(1)))
+            }
+            _ => true,
+        };
+        Ok((key, on_range))
+    }))
+}
+
+/// Whether a line of a log prepending receive times was received on `range`, lines without one
+/// are kept
+fn stamped_on_range(line: &str, range: &TimeRange) -> bool {
+    received_at(line).map_or(true, |received| range.contains(&received))
+}
+
+/// Maps a table of a query to the log name, hierarchical logs must be quoted, ie:
+/// `SELECT * FROM "team/service"`
+fn log_name_for_table(table: &str) -> String {
+    if table.len() > 1 && table.starts_with('"') && table.ends_with('"') {
+        table[1..table.len() - 1].to_string()
+    } else {
+        table.to_string()
+    }
+}
+
+/// Name of the log a statement queries
+pub fn statement_log_name(query: &Statement) -> Result<String, ProcessingQueryError> {
+    let some_table = match query {
+        Statement::Query(ref q) => {
+            match q.body {
+                SetExpr::Select(ref bodyselect) => {
+                    // TODO: Validate a single table
+                    Some(bodyselect.from[0].relation.clone())
+                }
+                _ => {
+                    return Err(ProcessingQueryError::Fail("No Table Found".to_string()));
+                }
+            }
+        }
+        _ => {
+            return Err(ProcessingQueryError::UnsupportedQuery(
+                "Unsupported query".to_string(),
+            ));
+        }
+    };
+    match some_table {
+        Some(table) => Ok(log_name_for_table(&table.to_string())),
+        None => Err(ProcessingQueryError::NoTableFound(
+            "No table was found in the query statement".to_string(),
+        )),
+    }
+}
+
 impl Query {
     pub fn new(cfg: Arc<RwLock<Config>>) -> Query {
         Query { config: cfg }
@@ -122,12 +524,24 @@ impl Query {
     pub fn parse_query(&self, payload: String) -> Result<Vec<Statement>, GenericError> {
         // attempt to parse the payload
         let dialect = MinSQLDialect {};
+        let payload = match rewrite_like_escapes(&payload) {
+            Ok(rewritten) => rewritten,
+            Err((message, position)) => {
+                let token = Some("ESCAPE".to_string());
+                return Err(Box::new(SqlSyntaxError::new(
+                    message,
+                    &payload,
+                    Some(position),
+                    token,
+                )));
+            }
+        };
 
         match Parser::parse_sql(&dialect, payload.clone()) {
             Ok(q) => Ok(q),
             Err(e) => {
                 // Unable to parse query, match reason
-                match e {
+                match &e {
                     ParserError::TokenizerError(s) => {
                         error!("Failed to tokenize query `{}`: {}", payload.clone(), s);
                     }
@@ -135,12 +549,20 @@ impl Query {
                         error!("Failed to parse query `{}`: {}", payload.clone(), s);
                     }
                 }
-                // TODO: Design a more informative error message
-                Err(ParseSqlError.into())
+                Err(Box::new(locate_syntax_error(&payload, e)))
             }
         }
     }
 
+    /// Name of the log a statement on `log_name` reads, which may be one of its aliases. Unknown
+    /// names are left as they are.
+    pub fn resolve_log_name(&self, log_name: String) -> String {
+        match self.config.read().unwrap().resolve_log_name(&log_name) {
+            Some(resolved) => resolved,
+            None => log_name,
+        }
+    }
+
     pub fn validate_logs(&self, ast: &Vec<Statement>) -> Option<GenericError> {
         let cfg = self.config.read().unwrap();
         // Validate all the tables for all the  queries, we don't want to start serving content
@@ -168,17 +590,22 @@ impl Query {
                 error!("No table found");
                 return Some(ParseSqlError.into());
             }
-            let table = some_table.unwrap().to_string();
-            let loggy = cfg.get_log(&table);
-            if loggy.is_none() {
-                return Some(ParseSqlError.into());
+            let table = log_name_for_table(&some_table.unwrap().to_string());
+            // queries on an alias read the log having it
+            if cfg.resolve_log_name(&table).is_none() {
+                return Some(Box::new(UnknownLogError { log_name: table }));
             }
         }
         None
     }
 
     // performs a query on a log
-    pub fn api_log_search(&self, req: Request<Body>, access_token: &String) -> ResponseFuture {
+    pub fn api_log_search(
+        &self,
+        req: Request<Body>,
+        access_token: &String,
+        log_ingest_buffers: Arc<IngestBuffers>,
+    ) -> ResponseFuture {
         let access_token = access_token.clone();
         let cfg = Arc::clone(&self.config);
         let query_c = Query::new(cfg);
@@ -219,8 +646,149 @@ impl Query {
             None => false,
         };
 
+        // Output shaping headers, see `OutputShape`
+        let output_shape = OutputShape {
+            strip_prefix: bool_header(&req, "MINSQL-OUTPUT-STRIP-PREFIX"),
+            omit_nulls: bool_header(&req, "MINSQL-OUTPUT-OMIT-NULLS"),
+            nest_subfields: bool_header(&req, "MINSQL-OUTPUT-NESTED"),
+            escape_control: bool_header(&req, "MINSQL-OUTPUT-ESCAPE-CONTROL"),
+        };
+
+        // Check for `MINSQL-PARTITION` header, logs partitioning their objects only list the
+        // objects of that partition
+        let partition = match partition_header(&req) {
+            Ok(partition) => partition,
+            Err(e) => return Box::new(future::ok(return_400(&e))),
+        };
+
+        // Check for `MINSQL-SAVED-SEARCH` header, the plan of a saved search is cached so its next
+        // runs skip parsing and compiling
+        let saved_search = match saved_search_header(&req) {
+            Ok(saved_search) => saved_search,
+            Err(e) => return Box::new(future::ok(return_400(&e))),
+        };
+
+        // The rows are returned as JSON lines unless another format is asked for
+        let output_format = match output_format(&req) {
+            Ok(format) => format,
+            Err(e) => return Box::new(future::ok(return_400(&e))),
+        };
+
+        // Check for `MINSQL-PAGE-SIZE` header, the results are returned a page at a time along
+        // with a `MINSQL-CONTINUATION` token the next page is asked for with
+        let page = match page_request(&req) {
+            Ok(page) => page,
+            Err(e) => return Box::new(future::ok(return_400(&e))),
+        };
+        let paged = page.is_some();
+        let continuation = Arc::new(Mutex::new(None));
+        let final_continuation = Arc::clone(&continuation);
+
+        // Check for `MINSQL-HIGHLIGHT: true` header, every row has the ranges of the line that
+        // matched the conditions
+        let highlight = bool_header(&req, HEADER_HIGHLIGHT);
+
+        // the trace of the request follows the searches forwarded to remote servers
+        let trace_headers = request_trace(&req)
+            .map(|trace| trace.headers())
+            .unwrap_or_default();
+
+        // Check for `MINSQL-TIMEZONE` header, times are bucketed on UTC otherwise
+        let timezone = match timezone_header(&req) {
+            Ok(timezone) => timezone,
+            Err(e) => return Box::new(future::ok(return_400(&e))),
+        };
+
         let query_state_holder = Arc::new(RwLock::new(StateHolder::new()));
         let query_state_holder = Arc::clone(&query_state_holder);
+        // Check for `MINSQL-PROFILE: true` header, the time spent on every stage of the search is
+        // sent after the results
+        let profile = if bool_header(&req, "MINSQL-PROFILE") {
+            Some(Arc::new(QueryProfile::default()))
+        } else {
+            None
+        };
+        query_state_holder.write().unwrap().profile = profile.clone();
+        let memory_limit = self.config.read().unwrap().server.query_memory_limit;
+        // queries with neither a `LIMIT` nor a `$date` predicate are cut at these limits
+        let unbounded_budget = {
+            let server = &self.config.read().unwrap().server;
+            OutputBudget {
+                max_rows: server.unbounded_query_max_rows,
+                max_bytes: server.unbounded_query_max_bytes,
+            }
+        };
+        let truncated = Arc::new(AtomicBool::new(false));
+        let final_truncated = Arc::clone(&truncated);
+        // Check for `MINSQL-PARTIAL-RESULTS: true` header, datastores that fail to be read are
+        // skipped instead of failing the query
+        let partial_results = bool_header(&req, "MINSQL-PARTIAL-RESULTS");
+        // Check for `MINSQL-PROGRESS: true` header, progress events are interleaved with the results
+        let show_progress = bool_header(&req, "MINSQL-PROGRESS");
+        // Check for `MINSQL-INCLUDE-BUFFERED: true` header, lines received but not flushed yet are
+        // searched as well
+        let include_buffered = bool_header(&req, "MINSQL-INCLUDE-BUFFERED");
+        // Check for `MINSQL-CONSISTENCY: strong` header, the ingest buffer of the log is flushed
+        // before it's read so everything acknowledged so far is found
+        let strong_consistency = match req.headers().get("MINSQL-CONSISTENCY") {
+            Some(val) => val
+                .to_str()
+                .map(|v| v.eq_ignore_ascii_case(CONSISTENCY_STRONG))
+                .unwrap_or(false),
+            None => false,
+        };
+        // Values for the query placeholders sent as `MINSQL-PARAM-<name>` headers
+        let header_params = param_headers(&req);
+        // Check for `MINSQL-SIGN: true` header, a signed manifest of the results is sent after them
+        let signing_key = if bool_header(&req, "MINSQL-SIGN") {
+            match &self.config.read().unwrap().server.signing_key {
+                Some(key) => Some(key.clone()),
+                None => {
+                    return Box::new(future::ok(return_400(
+                        "Results signing is not configured on this server",
+                    )))
+                }
+            }
+        } else {
+            None
+        };
+        // Check for `MINSQL-COLUMN-TYPES: true` header, the columns of every query and the type
+        // of their values are sent ahead of the results
+        let show_column_types = bool_header(&req, "MINSQL-COLUMN-TYPES");
+        // CSV has no room for the JSON lines sent along the rows
+        if output_format == OutputFormat::Csv
+            && (show_progress || profile.is_some() || signing_key.is_some() || show_column_types)
+        {
+            return Box::new(future::ok(return_400(
+                "CSV output can't carry progress events, profiles, signatures or column types",
+            )));
+        }
+        let results_digest = Arc::new(Mutex::new(ResultsDigest::new()));
+        let final_digest = Arc::clone(&results_digest);
+        // `HEAD /search` or the `MINSQL-COUNT-ONLY: true` header run the search but only send
+        // how many lines matched and what was scanned, on the headers
+        let count_only = req.method() == Method::HEAD || bool_header(&req, "MINSQL-COUNT-ONLY");
+        // a page is read from the datastores alone, and held until it's over
+        if paged
+            && (count_only
+                || preview_query
+                || include_buffered
+                || show_progress
+                || profile.is_some()
+                || signing_key.is_some())
+        {
+            return Box::new(future::ok(return_400(
+                "Paged searches can't be counted, previewed, include buffered lines or carry progress events, profiles or signatures",
+            )));
+        }
+        let matched_lines = Arc::new(AtomicUsize::new(0));
+        let count_stats = SearchCountStats {
+            started: Instant::now(),
+            matched_lines: Arc::clone(&matched_lines),
+            progress: Arc::clone(&query_state_holder.read().unwrap().progress),
+            failure: Arc::new(Mutex::new(None)),
+        };
+        let failure = Arc::clone(&count_stats.failure);
         // A web api to run against
         Box::new(
             req.into_body()
@@ -233,36 +801,112 @@ impl Query {
                             return Ok(return_400("Could not understand request"));
                         }
                     };
+                    // bind the placeholders, values on the body take precedence over headers
+                    let (sql, mut params) = match parse_search_body(&payload) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return Ok(return_400(&e));
+                        }
+                    };
+                    for (name, value) in header_params {
+                        params.entry(name).or_insert(value);
+                    }
+                    let payload = match bind_parameters(&sql, &params) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return Ok(return_400(&e));
+                        }
+                    };
+                    // the query as run is signed along with its results
+                    let query_text = payload.clone();
                     let ast = match query_c.parse_query(payload) {
                         Ok(v) => v,
                         Err(e) => {
-                            return Ok(return_400(format!("{:?}", e).as_str()));
+                            return Ok(match e.downcast_ref::<SqlSyntaxError>() {
+                                Some(e) => return_syntax_error(e),
+                                None => return_400(format!("{:?}", e).as_str()),
+                            });
                         }
                     };
-                    if let Some(_) = query_c.validate_logs(&ast) {
-                        return Ok(return_400("invalid log name"));
+                    if let Some(e) = query_c.validate_logs(&ast) {
+                        return Ok(match e.downcast_ref::<UnknownLogError>() {
+                            Some(_) => return_404(),
+                            None => return_400("invalid log name"),
+                        });
                     };
 
                     // Translate the SQL AST into a `QueryParsing`
                     // that has all the elements needed to continue
-                    let parsed_queries = match query_c.process_sql(&access_token, ast, explore_query) {
+                    let mut parsed_queries = match query_c.process_saved_sql(
+                        &access_token,
+                        ast,
+                        explore_query,
+                        saved_search.as_ref().map(String::as_str),
+                    ) {
                         Ok(v) => v,
-                        Err(e) => {
-                            return match e {
-                                ProcessingQueryError::Fail(s) => Ok(return_400(s.clone().as_str())),
-                                ProcessingQueryError::UnsupportedQuery(s) => {
-                                    Ok(return_400(s.clone().as_str()))
-                                }
-                                ProcessingQueryError::NoTableFound(s) => {
-                                    Ok(return_400(s.clone().as_str()))
-                                }
-                                ProcessingQueryError::Unauthorized(_s) => Ok(return_401()),
-                            };
+                        Err(e) => return Ok(return_processing_error(e)),
+                    };
+                    for (query, q_parse) in parsed_queries.iter_mut() {
+                        q_parse.output_shape = output_shape.clone();
+                        q_parse.partition = partition.clone();
+                        q_parse.highlight = highlight;
+                        if let Err(e) = q_parse.set_timezone(query, timezone) {
+                            return Ok(return_400(&e));
+                        }
+                    }
+                    // every row of a CSV has the columns of its header row
+                    let csv_columns = match output_format {
+                        OutputFormat::Csv if parsed_queries.len() > 1 => {
+                            return Ok(return_400("CSV output takes a single query"));
                         }
+                        OutputFormat::Csv => csv_columns(&parsed_queries[0].1),
+                        _ => Vec::new(),
+                    };
+                    let csv_header = match output_format {
+                        OutputFormat::Csv => Some(Chunk::from(csv_header_row(&csv_columns))),
+                        _ => None,
+                    };
+                    let columns_header = if show_column_types {
+                        let lines: String = parsed_queries
+                            .iter()
+                            .map(|(_, q_parse)| columns_line(q_parse))
+                            .collect();
+                        Some(Chunk::from(lines))
+                    } else {
+                        None
                     };
+                    // the search can be seen and cancelled from the admin API while it runs
+                    let running = Arc::new(register_query(&query_text, &access_token));
+                    if let Some(page) = page {
+                        let rows = match query_c.paged_rows(
+                            parsed_queries,
+                            page,
+                            &query_text,
+                            &access_token,
+                            continuation,
+                            running,
+                        ) {
+                            Ok(rows) => rows,
+                            Err(e) => return Ok(return_400(&e)),
+                        };
+                        let body_str = rows.map(move |s: Vec<String>| {
+                            Chunk::from(format_rows(&s, output_format, &csv_columns))
+                        });
+                        let body_str = stream::iter_ok::<_, QueryError>(csv_header)
+                            .chain(stream::iter_ok(columns_header))
+                            .chain(body_str);
+                        return Ok(Response::new(Body::wrap_stream(end_on_error(body_str))));
+                    }
+                    let guarded = !count_only
+                        && !preview_query
+                        && unbounded_budget != OutputBudget::unlimited()
+                        && parsed_queries
+                            .iter()
+                            .any(|(_, q_parse)| q_parse.limit.is_none() && !q_parse.date_predicate);
                     let total_querys = parsed_queries.len();
                     let mut writable_state = query_state_holder.write().unwrap();
                     writable_state.query_parsing = parsed_queries;
+                    writable_state.running = Some(Arc::clone(&running));
                     //release lock
                     drop(writable_state);
 
@@ -270,70 +914,228 @@ impl Query {
 
                     let cfg = Arc::clone(&query_c.config);
 
+                    let progress = Arc::clone(&query_state_holder.read().unwrap().progress);
+                    let final_progress = Arc::clone(&progress);
                     let query_state_holder = Arc::clone(&query_state_holder);
 
+                    let signing_key_c = signing_key.clone();
+                    let digest_key = signing_key.clone();
+                    let output_digest = Arc::clone(&results_digest);
+                    let flush_cfg = Arc::clone(&query_c.config);
+                    let flush_state_holder = Arc::clone(&query_state_holder);
+                    let flush_buffers = Arc::clone(&log_ingest_buffers);
+                    let usage_token = access_token.clone();
+
                     let body_str = stream::iter_ok::<_, QueryError>(0..total_querys)
+                        .and_then(move |query_index| {
+                            let log_name = flush_state_holder.read().unwrap().query_parsing
+                                [query_index]
+                                .1
+                                .log_name
+                                .clone();
+                            if !strong_consistency || !flush_buffers.contains_key(&log_name[..]) {
+                                return Either::A(future::ok(query_index));
+                            }
+                            let ingest_c = Ingest::new(Arc::clone(&flush_cfg));
+                            Either::B(
+                                ingest_c
+                                    .flush_and_wait(&log_name, Arc::clone(&flush_buffers))
+                                    .map(move |_| query_index)
+                                    .map_err(move |_| {
+                                        QueryError::Underlying(format!(
+                                            "Could not flush log {}",
+                                            log_name
+                                        ))
+                                    }),
+                            )
+                        })
                         .map(move |query_index| {
                             // for each query parse, read from all datasources for the log
                             let read_state_holder = query_state_holder.read().unwrap();
                             let q_parse = &read_state_holder.query_parsing[query_index].1;
                             let cfg_read = cfg.read().unwrap();
                             let log = cfg_read.get_log(&q_parse.log_name).unwrap();
+                            let q_parse_log_name = q_parse.log_name.clone();
+                            let q_parse_partition = q_parse.partition.clone();
+                            // the lines read are accounted to the log and the token
+                            let usage_log = q_parse_log_name.clone();
+                            let scan_running = Arc::clone(&running);
+                            let usage_token = usage_token.clone();
+                            let base64_lines = log.encoding.as_ref().map(|s| s.as_str())
+                                == Some(ENCODING_BASE64);
                             let log_datastores = &log.datastores;
+                            let hot_replicas = hot_replica_sets(log, &cfg_read);
+                            // the cold tier holds no line of ranges more recent than `cold_after`
+                            let cold_datastores: Vec<String> =
+                                if range_reaches_cold_tier(log, q_parse.time_range, &Utc::now()) {
+                                    log.cold_datastores
+                                        .iter()
+                                        .filter(|ds_name| cfg_read.datastore.contains_key(*ds_name))
+                                        .cloned()
+                                        .collect()
+                                } else {
+                                    Vec::new()
+                                };
+                            // logs held by another server get its results merged with the local
+                            let remote_query = log.remote.as_ref().map(|remote| {
+                                let remote_log = remote.log.as_ref().unwrap_or(&q_parse_log_name);
+                                let statement = &read_state_holder.query_parsing[query_index].0;
+                                let mut headers = output_shape_headers(&q_parse.output_shape);
+                                headers.push((HEADER_TIMEZONE, q_parse.timezone.to_string()));
+                                headers.push((HEADER_HIGHLIGHT, q_parse.highlight.to_string()));
+                                headers.extend(trace_headers.clone());
+                                (
+                                    remote.clone(),
+                                    remote_statement(statement, remote_log).to_string(),
+                                    headers,
+                                )
+                            });
 
                             let mut limit = q_parse.limit.unwrap_or(std::u64::MAX);
                             if preview_query {
                                 limit = 20 as u64;
                             }
+                            let budget = if guarded
+                                && q_parse.limit.is_none()
+                                && !q_parse.date_predicate
+                            {
+                                unbounded_budget
+                            } else {
+                                OutputBudget::unlimited()
+                            };
+                            let truncated = Arc::clone(&truncated);
+                            // sorted queries hold no more rows than they may return, one more when
+                            // the budget has to tell they were cut short
+                            let keep = if limit != std::u64::MAX {
+                                Some(limit as usize)
+                            } else if budget.max_rows > 0 {
+                                Some(budget.max_rows + 1)
+                            } else {
+                                None
+                            };
+                            let sorter = q_parse.row_sorter(keep);
+                            // aggregating queries return their groups once every line is read
+                            let groups = q_parse.group_table().map(|table| Arc::new(Mutex::new(table)));
+                            let final_groups = groups.clone();
                             //drop the read lock
                             drop(read_state_holder);
 
-                            let logs_ds_len = log_datastores.len();
-
                             // prepare copies to go into the next future
                             let cfg = Arc::clone(&cfg);
                             let query_state_holder = Arc::clone(&query_state_holder);
                             let query_state_holder3 = Arc::clone(&query_state_holder);
-
-                            let (tx, rx) = mpsc::unbounded_channel::<Vec<String>>();
-                            // For each datastore in the log we are going to spawn a task to read the
-                            // logs stored in given datastore.
-                            for i in 0..logs_ds_len {
-                                let ds_name = &log_datastores[i];
-                                if cfg_read.datastore.contains_key(ds_name) {
-                                    let cfg2 = Arc::clone(&cfg);
-                                    let query_state_holder2 = Arc::clone(&query_state_holder);
-                                    let tx = tx.clone();
-                                    // Task that will read all the logs for a given datastore
-                                    let task = stream::iter_ok(i..i + 1)
-                                        .map(move |log_ds_index| {
-                                            let cfg2 = Arc::clone(&cfg2);
-                                            let query_state_holder2 =
-                                                Arc::clone(&query_state_holder2);
-                                            // let log_ds_index = log_ds_index.clone();
-                                            Query::read_logs_from_datastore(
-                                                cfg2,
-                                                query_state_holder2,
-                                                query_index,
-                                                log_ds_index,
-                                            )
-                                        })
-                                        .flatten()
-                                        .fold(tx, |tx, lines| {
-                                            tx.send(lines).map_err(|e| {
-                                                QueryError::Underlying(format!("{:?}", e))
-                                            })
-                                        })
-                                        .map_err(|_| ())
-                                        .map(|_| ());
-                                    tokio::spawn(task);
-                                } else {
+                            let groups_state_holder = Arc::clone(&query_state_holder);
+
+                            // Lines read but not scanned yet are accounted per query, readers stop
+                            // with an error once they go over the limit.
+                            let memory = Arc::new(QueryMemory::new(memory_limit));
+                            let (tx, rx) = mpsc::unbounded_channel::<Result<LinesBatch, QueryError>>();
+                            for ds_name in log_datastores {
+                                if !cfg_read.datastore.contains_key(ds_name) {
                                     error!("Log `{:?}` references datastore `{}` which is not present in the configuration.", &log.name, &ds_name);
                                 }
                             }
+                            // For each hot datastore in the log we are going to spawn a task to read
+                            // the logs stored in given datastore, replicated logs are read once.
+                            for replicas in hot_replicas {
+                                Query::spawn_datastore_read(
+                                    Arc::clone(&cfg),
+                                    Arc::clone(&query_state_holder),
+                                    query_index,
+                                    replicas,
+                                    Arc::clone(&memory),
+                                    partial_results,
+                                    tx.clone(),
+                                );
+                            }
+                            drop(tx);
+                            // The cold tier is only read once every line of the hot tier went
+                            // through the query and only if it still takes lines, so queries
+                            // satisfied by the recent data, or whose client went away, never
+                            // touch it.
+                            let cold_lines: Box<dyn Stream<Item = Result<LinesBatch, QueryError>, Error = QueryError> + Send> =
+                                if cold_datastores.is_empty() {
+                                    Box::new(stream::empty())
+                                } else {
+                                    let cfg2 = Arc::clone(&cfg);
+                                    let query_state_holder2 = Arc::clone(&query_state_holder);
+                                    let memory2 = Arc::clone(&memory);
+                                    Box::new(
+                                        future::lazy(move || -> Result<_, QueryError> {
+                                            let (cold_tx, cold_rx) =
+                                                mpsc::unbounded_channel::<Result<LinesBatch, QueryError>>();
+                                            for ds_name in cold_datastores {
+                                                Query::spawn_datastore_read(
+                                                    Arc::clone(&cfg2),
+                                                    Arc::clone(&query_state_holder2),
+                                                    query_index,
+                                                    vec![ds_name],
+                                                    Arc::clone(&memory2),
+                                                    partial_results,
+                                                    cold_tx.clone(),
+                                                );
+                                            }
+                                            Ok(cold_rx.map_err(|e| QueryError::Underlying(format!("{:?}", e))))
+                                        })
+                                        .flatten_stream(),
+                                    )
+                                };
+
+                            // The buffered lines go through the same scanning as the stored ones,
+                            // ahead of them since they are the most recent.
+                            let buffered = if include_buffered {
+                                buffered_lines(
+                                    &log_ingest_buffers,
+                                    &q_parse_log_name,
+                                    q_parse_partition.as_ref().map(|p| p.as_str()),
+                                )
+                            } else {
+                                Vec::new()
+                            };
+                            let buffered = if buffered.is_empty() {
+                                None
+                            } else if memory.reserve(lines_size(&buffered)) {
+                                Some(Ok((PROFILE_BUFFERED_SOURCE.to_string(), buffered)))
+                            } else {
+                                Some(Err(QueryError::MemoryLimitExceeded(memory.limit)))
+                            };
 
-                            rx.map_err(|e| QueryError::Underlying(format!("{:?}", e))) //temporarely remove error, we need to adress this
-                                .map(move |lines| {
+                            let remote_rows: Box<dyn Stream<Item = Vec<String>, Error = QueryError> + Send> =
+                                match remote_query {
+                                    Some((remote, query, headers)) => Box::new(
+                                        remote_search(&remote, query, headers).then(move |rows| {
+                                            match rows {
+                                                Ok(rows) => Ok(rows),
+                                                Err(e) if partial_results => {
+                                                    error!("Skipping remote results: {}", e);
+                                                    Ok(Vec::new())
+                                                }
+                                                Err(e) => Err(QueryError::Underlying(e)),
+                                            }
+                                        }),
+                                    ),
+                                    None => Box::new(stream::empty()),
+                                };
+
+                            let rows = stream::iter_ok::<_, QueryError>(buffered)
+                                .chain(rx.map_err(|e| QueryError::Underlying(format!("{:?}", e)))) //temporarely remove error, we need to adress this
+                                .chain(cold_lines)
+                                .and_then(|lines| lines)
+                                .and_then(move |(source, lines)| {
+                                    memory.release(lines_size(&lines));
+                                    record_scanned(&usage_log, &usage_token, lines_size(&lines) as u64);
+                                    scan_running.scanned(lines_size(&lines));
+                                    let mut times = StageTimes::default();
+                                    let started = Instant::now();
+                                    // stored lines are unwrapped as they're read, the buffered
+                                    // ones are still as the log keeps them
+                                    let from_buffer = source == PROFILE_BUFFERED_SOURCE;
+                                    let lines = if base64_lines && from_buffer {
+                                        decode_lines(lines)
+                                    } else {
+                                        lines
+                                    };
+                                    times.add(Stage::Decode, started.elapsed());
                                     // Perform scan via Hyperscan
                                     // TODO: Remove the lock around the DB as this is definetively a problem
                                     let query_state_holder4 = Arc::clone(&query_state_holder3);
@@ -345,22 +1147,9 @@ impl Query {
                                         .get_mut(query_index)
                                         .unwrap();
 
-                                    let pattern_match_results: HSPatternMatchResults =
-                                        match q_parse.hs_db.take() {
-                                            Some(mut db) => {
-                                                //                                            let bdb = q_parse.hs_db.take();
-                                                //                                            let mut db = bdb.unwrap();
-
-                                                let mut ls = HSLineScanner::new(&lines);
-                                                let pattern_match_results = ls.scan(&mut db);
-                                                // drop ls so the borrow on lines is returned
-                                                drop(ls);
-
-                                                q_parse.hs_db = Some(db);
-                                                pattern_match_results
-                                            }
-                                            None => Arc::new(RwLock::new(HashMap::new())),
-                                        };
+                                    let started = Instant::now();
+                                    let pattern_match_results = scan_lines(q_parse, &lines);
+                                    times.add(Stage::Hyperscan, started.elapsed());
                                     // Drop the write lock
                                     drop(write_state_holder);
 
@@ -370,83 +1159,615 @@ impl Query {
                                     let (ref query, ref query_data) =
                                         *(&read_state_holder.query_parsing[query_index]);
 
-                                    let res = lines
-                                        .into_iter()
-                                        .enumerate()
-                                        .filter_map(|(line_index, line)| {
-                                            let pattern_match_results2 =
-                                                Arc::clone(&pattern_match_results);
-                                            evaluate_query_on_line(
+                                    let res = match &groups {
+                                        Some(groups) => {
+                                            let started = Instant::now();
+                                            let res = aggregate_lines(
                                                 query,
                                                 query_data,
-                                                line_index,
-                                                line,
-                                                pattern_match_results2,
-                                            )
-                                        })
-                                        .collect::<Vec<String>>();
+                                                lines,
+                                                pattern_match_results,
+                                                &mut groups.lock().unwrap(),
+                                            );
+                                            times.add(Stage::Filter, started.elapsed());
+                                            res.map(|_| Vec::new())
+                                        }
+                                        None => Ok(evaluate_lines_timed(
+                                            query,
+                                            query_data,
+                                            lines,
+                                            pattern_match_results,
+                                            &mut times,
+                                        )),
+                                    };
+                                    if let Some(profile) = &read_state_holder.profile {
+                                        profile.record_all(&source, &times);
+                                    }
                                     drop(read_state_holder);
 
                                     res
                                 })
+                                .chain(match final_groups {
+                                    Some(groups) => Either::A(stream::once(Ok(())).map(move |_| {
+                                        let read_state_holder = groups_state_holder.read().unwrap();
+                                        let query_data = &read_state_holder.query_parsing[query_index].1;
+                                        group_rows(query_data, &groups.lock().unwrap())
+                                    })),
+                                    None => Either::B(stream::empty()),
+                                })
+                                .select(remote_rows);
+                            sorted_rows(rows, sorter)
                                 .take_from_iterable(limit)
+                                .take_within_budget(budget, truncated)
                         })
                         .flatten()
-                        .map(|s: Vec<String>| Chunk::from(s.join("\n") + &"\n"));
-                    Ok(Response::new(Body::wrap_stream(body_str)))
+                        .map(move |s: Vec<String>| {
+                            matched_lines.fetch_add(
+                                s.iter().filter(|row| !row.is_empty()).count(),
+                                Ordering::SeqCst,
+                            );
+                            if signing_key_c.is_some() {
+                                let rows = s.iter().filter(|row| !row.is_empty()).count();
+                                results_digest.lock().unwrap().add_rows(rows as u64);
+                            }
+                            let mut chunk = format_rows(&s, output_format, &csv_columns);
+                            if show_progress {
+                                if let Some(event) = progress.event() {
+                                    chunk.push_str(&event);
+                                }
+                            }
+                            Chunk::from(chunk)
+                        });
+                    let body_str = stream::iter_ok::<_, QueryError>(csv_header)
+                        .chain(stream::iter_ok(columns_header))
+                        .chain(body_str)
+                        .map(move |chunk: Chunk| {
+                            // everything sent ahead of the manifest is signed, not just the rows
+                            if digest_key.is_some() {
+                                output_digest.lock().unwrap().add_output(chunk.as_ref());
+                            }
+                            chunk
+                        });
+                    if count_only {
+                        // the results are drained to count them, an error fails the count
+                        let body_str = body_str.map_err(move |e| {
+                            *failure.lock().unwrap() = Some(e.to_string());
+                            e
+                        });
+                        return Ok(Response::new(Body::wrap_stream(end_on_error(body_str))));
+                    }
+                    if !show_progress && signing_key.is_none() && profile.is_none() && !guarded {
+                        return Ok(Response::new(Body::wrap_stream(end_on_error(body_str))));
+                    }
+                    // the last event and the manifest are sent once every query is done
+                    let body_str = body_str.chain(stream::once(Ok(())).map(move |_| {
+                        let mut chunk = String::new();
+                        if show_progress {
+                            chunk.push_str(&final_progress.final_event());
+                        }
+                        if let Some(profile) = &profile {
+                            chunk.push_str(&profile.trailer());
+                        }
+                        if final_truncated.load(Ordering::SeqCst) && output_format != OutputFormat::Csv {
+                            chunk.push_str(&stats_trailer(&unbounded_budget));
+                        }
+                        if let Some(key) = &signing_key {
+                            let generated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+                            let mut digest = final_digest.lock().unwrap();
+                            digest.add_output(chunk.as_bytes());
+                            let manifest = digest.sign(key, &query_text, &generated_at);
+                            chunk.push_str(&serde_json::to_string(&ManifestLine { manifest }).unwrap());
+                            chunk.push('\n');
+                        }
+                        Chunk::from(chunk)
+                    }));
+                    if !show_progress {
+                        return Ok(Response::new(Body::wrap_stream(end_on_error(body_str))));
+                    }
+                    // hint proxies to pass every chunk through as soon as it's written
+                    Ok(Response::builder()
+                        .header(header::CACHE_CONTROL, "no-cache")
+                        .header("X-Accel-Buffering", "no")
+                        .body(Body::wrap_stream(end_on_error(body_str)))
+                        .unwrap())
+                })
+                .and_then(move |mut response| {
+                    if !count_only || response.status() != StatusCode::OK {
+                        if let (StatusCode::OK, Some(content_type)) =
+                            (response.status(), output_format.content_type())
+                        {
+                            response.headers_mut().insert(
+                                header::CONTENT_TYPE,
+                                header::HeaderValue::from_static(content_type),
+                            );
+                        }
+                        if !paged || response.status() != StatusCode::OK {
+                            return Either::A(future::ok(response));
+                        }
+                        // the token is only known once the page is over, so it's held until then
+                        let (parts, body) = response.into_parts();
+                        return Either::B(Either::A(body.concat2().from_err().map(move |page| {
+                            let mut response = Response::from_parts(parts, Body::from(page));
+                            if let Some(token) = final_continuation.lock().unwrap().take() {
+                                response.headers_mut().insert(
+                                    HEADER_CONTINUATION,
+                                    header::HeaderValue::from_str(&token).unwrap(),
+                                );
+                            }
+                            response
+                        })));
+                    }
+                    Either::B(Either::B(
+                        response
+                            .into_body()
+                            .for_each(|_| Ok(()))
+                            .from_err()
+                            .map(move |_| count_stats.response()),
+                    ))
                 }),
         )
     }
 
-    fn process_statement(
-        &self,
-        access_token: &String,
-        query: Statement,
-        explore_data: bool,
-    ) -> Result<(Statement, QueryParsing), ProcessingQueryError> {
-        // find the table they want to query
-        let some_table = match query {
-            Statement::Query(ref q) => {
-                match q.body {
-                    SetExpr::Select(ref bodyselect) => {
-                        // TODO: Validate a single table
-                        Some(bodyselect.from[0].relation.clone())
-                    }
-                    _ => {
-                        return Err(ProcessingQueryError::Fail("No Table Found".to_string()));
-                    }
-                }
-            }
-            _ => {
-                return Err(ProcessingQueryError::UnsupportedQuery(
-                    "Unsupported query".to_string(),
-                ));
+    /// Checks the output of a signed search, sent as is on the body, against the signing key of
+    /// the server.
+    pub fn api_verify_results(&self, req: Request<Body>) -> ResponseFuture {
+        let signing_key = match &self.config.read().unwrap().server.signing_key {
+            Some(key) => key.clone(),
+            None => {
+                return Box::new(future::ok(return_400(
+                    "Results signing is not configured on this server",
+                )))
             }
         };
-        if some_table == None {
-            return Err(ProcessingQueryError::NoTableFound(
-                "No table was found in the query statement".to_string(),
-            ));
-        }
-        let log_name = some_table.unwrap().to_string().clone();
+        Box::new(
+            req.into_body()
+                .concat2()
+                .from_err()
+                .and_then(move |entire_body| {
+                    let output = String::from_utf8_lossy(&entire_body);
+                    let body = match verify_results(&signing_key, &output) {
+                        Ok(manifest) => json!({"valid": true, "rows": manifest.rows}),
+                        Err(e) => json!({"valid": false, "error": e}),
+                    };
+                    Ok(Response::builder()
+                        .header(header::CONTENT_TYPE, APP_JSON)
+                        .body(Body::from(body.to_string()))
+                        .unwrap())
+                }),
+        )
+    }
 
-        // check if we have access for the requested table
-        let cfg = Arc::clone(&self.config);
-        let auth_c = Auth::new(cfg);
-        if !auth_c.token_has_access_to_log(&access_token[..], &log_name[..]) {
-            return Err(ProcessingQueryError::Unauthorized(
-                "Unauthorized".to_string(),
-            ));
+    /// Rows of a paged search, from where its continuation token stopped on. The datastores of
+    /// the log are read one after the other, hot before cold, and their objects in key order, so
+    /// pages never overlap. Once the page is full, the token resuming after its last row is set
+    /// on `continuation`.
+    fn paged_rows(
+        &self,
+        mut parsed_queries: Vec<(Statement, QueryParsing)>,
+        page: PageRequest,
+        query_text: &str,
+        access_token: &str,
+        continuation: Arc<Mutex<Option<String>>>,
+        running: Arc<QueryHandle>,
+    ) -> Result<impl Stream<Item = Vec<String>, Error = QueryError>, String> {
+        if parsed_queries.len() != 1 {
+            return Err("Paged searches take a single query".to_string());
         }
-
-        // determine our read strategy
-        let read_all = match query {
-            Statement::Query(ref q) => match q.body {
-                SetExpr::Select(ref bodyselect) => {
-                    let mut is_wildcard = false;
-                    for projection in &bodyselect.projection {
-                        if *projection == SelectItem::Wildcard {
-                            is_wildcard = true
+        let (statement, q_parse) = parsed_queries.remove(0);
+        if q_parse.aggregation.is_some() || !q_parse.order_by.is_empty() {
+            return Err("Paged searches can't be sorted or aggregated".to_string());
+        }
+        if let Some(token) = &page.token {
+            if !token.resumes(query_text) {
+                return Err("The continuation token was issued for another query".to_string());
+            }
+        }
+        let cfg_read = self.config.read().unwrap();
+        let log = cfg_read.get_log(&q_parse.log_name).unwrap();
+        if log.remote.is_some() {
+            return Err(
+                "Paged searches are not supported on logs held by a remote server".to_string(),
+            );
+        }
+        // a replicated log is paged through a single replica, the one the token stopped on
+        let hot_datastores: Vec<String> = hot_replica_sets(log, &cfg_read)
+            .into_iter()
+            .filter_map(|replicas| {
+                let resumed = page
+                    .token
+                    .as_ref()
+                    .map(|token| &token.datastore)
+                    .filter(|ds_name| replicas.contains(ds_name))
+                    .cloned();
+                resumed.or_else(|| replicas.into_iter().next())
+            })
+            .collect();
+        let datastores: Vec<DataStore> = hot_datastores
+            .iter()
+            .chain(log.cold_datastores.iter())
+            .filter_map(|ds_name| cfg_read.datastore.get(ds_name))
+            .cloned()
+            .collect();
+        // the datastores before the one the token stopped on were read already
+        let first = match &page.token {
+            Some(token) => datastores
+                .iter()
+                .position(|ds| ds.name.as_ref() == Some(&token.datastore))
+                .ok_or_else(|| {
+                    format!(
+                        "Datastore {} of the continuation token no longer holds the log",
+                        token.datastore
+                    )
+                })?,
+            None => 0,
+        };
+        // pages stop at the `LIMIT` of the query, counting the rows of the pages before
+        let returned = page.token.as_ref().map_or(0, |token| token.returned);
+        let remaining = match q_parse.limit {
+            Some(limit) => limit.saturating_sub(returned),
+            None => std::u64::MAX,
+        };
+        let page_rows = cmp::min(page.size as u64, remaining);
+        let naming = ObjectNaming::for_log(log)
+            .in_partition(q_parse.partition.as_ref().map(|p| p.as_str()))
+            .within(q_parse.time_range);
+        let log_name = q_parse.log_name.clone();
+        let lossy_decoding = log.lossy_decoding;
+        let received_range = q_parse.received_range;
+        let stamped_range = received_range
+            .filter(|_| log.stamp.as_ref().map(|s| s.as_str()) == Some(STAMP_PREPEND));
+        drop(cfg_read);
+
+        let usage_log = log_name.clone();
+        let usage_token = access_token.to_string();
+        let digest = query_digest(query_text);
+        let start = page.token;
+        let lines = stream::iter_ok::<_, QueryError>(datastores.into_iter().skip(first))
+            .map(move |ds| {
+                let ds_name = ds.name.clone().unwrap_or_default();
+                // the object the token stopped on may have been compacted since, its lines are
+                // then resumed on the object they were merged into
+                let resolved = match start.clone().filter(|token| token.datastore == ds_name) {
+                    Some(token) => Either::A(
+                        resolve_moved(&ds, token.key, token.offset)
+                            .map(Some)
+                            .map_err(QueryError::Underlying),
+                    ),
+                    None => Either::B(future::ok(None)),
+                };
+                let naming = naming.clone();
+                let log_name = log_name.clone();
+                let received_ds = ds.clone();
+                resolved
+                    .map(move |start| {
+                        // the objects before the one the token stopped on were read already
+                        let listed_start = start.clone();
+                        list_msl_bucket_files(&log_name, &ds, &naming)
+                            .map_err(|e| QueryError::Underlying(format!("{:?}", e)))
+                            .filter(move |key| {
+                                listed_start
+                                    .as_ref()
+                                    .map_or(true, |(start_key, _)| key >= start_key)
+                            })
+                            .and_then(move |key| {
+                                received_on_range(key, &received_ds, received_range)
+                            })
+                            .filter(|(_, on_range)| *on_range)
+                            .map(move |(key, _)| {
+                                let offset = match &start {
+                                    Some((start_key, offset)) if *start_key == key => *offset,
+                                    _ => 0,
+                                };
+                                let ds_name = ds_name.clone();
+                                read_file_offset_lines(&key, &ds, lossy_decoding, offset)
+                                    .map_err(|e| QueryError::Underlying(format!("{:?}", e)))
+                                    .map(move |lines| {
+                                        // the offsets of the lines kept still resume after them
+                                        let lines = match &stamped_range {
+                                            Some(range) => lines
+                                                .into_iter()
+                                                .filter(|(line, _)| stamped_on_range(line, range))
+                                                .collect(),
+                                            None => lines,
+                                        };
+                                        (ds_name.clone(), key.clone(), lines)
+                                    })
+                            })
+                            .flatten()
+                    })
+                    .flatten_stream()
+            })
+            .flatten();
+
+        let query_data = Arc::new(Mutex::new(q_parse));
+        let mut page_returned = 0;
+        let rows = lines
+            .and_then(move |batch| {
+                if running.is_cancelled() {
+                    return Err(QueryError::Cancelled);
+                }
+                Ok((batch, Arc::clone(&running)))
+            })
+            .map(move |((ds_name, key, lines), running)| {
+                let (lines, offsets): (Vec<String>, Vec<u64>) = lines.into_iter().unzip();
+                record_scanned(&usage_log, &usage_token, lines_size(&lines) as u64);
+                running.scanned(lines_size(&lines));
+                let mut query_data = query_data.lock().unwrap();
+                let pattern_match_results = scan_lines(&mut query_data, &lines);
+                // every row goes along with the offset of the line following it
+                let rows: Vec<(String, String, String, u64)> = lines
+                    .into_iter()
+                    .zip(offsets)
+                    .enumerate()
+                    .filter_map(|(line_index, (line, offset))| {
+                        evaluate_query_on_line(
+                            &statement,
+                            &query_data,
+                            line_index,
+                            line,
+                            Arc::clone(&pattern_match_results),
+                        )
+                        .map(|row| (row, ds_name.clone(), key.clone(), offset))
+                    })
+                    .collect();
+                stream::iter_ok::<_, QueryError>(rows)
+            })
+            .flatten()
+            .take(page_rows)
+            .map(move |(row, datastore, key, offset)| {
+                page_returned += 1;
+                // a full page resumes after its last row, unless the `LIMIT` was reached
+                if page_returned == page_rows && page_returned < remaining {
+                    let token = ContinuationToken {
+                        query: digest.clone(),
+                        datastore,
+                        key,
+                        offset,
+                        returned: returned + page_returned,
+                    };
+                    *continuation.lock().unwrap() = Some(token.encode());
+                }
+                vec![row]
+            });
+        Ok(rows)
+    }
+
+    /// Evaluates a query on the lines of its log as they're ingested, ahead of them being flushed.
+    /// Matching rows are sent as server-sent events, a comment every `WATCH_HEARTBEAT_SECS` when
+    /// there are none, until the client goes away or the `LIMIT` of the query is reached.
+    pub fn api_log_watch(&self, req: Request<Body>, access_token: &String) -> ResponseFuture {
+        let access_token = access_token.clone();
+        let query_c = Query::new(Arc::clone(&self.config));
+        // Output shaping headers, see `OutputShape`
+        let output_shape = OutputShape {
+            strip_prefix: bool_header(&req, "MINSQL-OUTPUT-STRIP-PREFIX"),
+            omit_nulls: bool_header(&req, "MINSQL-OUTPUT-OMIT-NULLS"),
+            nest_subfields: bool_header(&req, "MINSQL-OUTPUT-NESTED"),
+            escape_control: bool_header(&req, "MINSQL-OUTPUT-ESCAPE-CONTROL"),
+        };
+        // Check for `MINSQL-TIMEZONE` header, times are bucketed on UTC otherwise
+        let timezone = match timezone_header(&req) {
+            Ok(timezone) => timezone,
+            Err(e) => return Box::new(future::ok(return_400(&e))),
+        };
+        Box::new(
+            req.into_body()
+                .concat2()
+                .from_err()
+                .and_then(move |entire_body| {
+                    let payload: String = match String::from_utf8(entire_body.to_vec()) {
+                        Ok(str) => str,
+                        Err(_) => {
+                            return Ok(return_400("Could not understand request"));
+                        }
+                    };
+                    let ast = match query_c.parse_query(payload) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return Ok(match e.downcast_ref::<SqlSyntaxError>() {
+                                Some(e) => return_syntax_error(e),
+                                None => return_400(format!("{:?}", e).as_str()),
+                            });
+                        }
+                    };
+                    if let Some(e) = query_c.validate_logs(&ast) {
+                        return Ok(match e.downcast_ref::<UnknownLogError>() {
+                            Some(_) => return_404(),
+                            None => return_400("invalid log name"),
+                        });
+                    };
+                    let mut parsed_queries = match query_c.process_sql(&access_token, ast, false) {
+                        Ok(v) => v,
+                        Err(e) => return Ok(return_processing_error(e)),
+                    };
+                    for (query, q_parse) in parsed_queries.iter_mut() {
+                        q_parse.output_shape = output_shape.clone();
+                        if let Err(e) = q_parse.set_timezone(query, timezone) {
+                            return Ok(return_400(&e));
+                        }
+                    }
+                    let events = match query_c.watched_rows(parsed_queries) {
+                        Ok(rows) => rows.map(|rows| {
+                            if rows.is_empty() {
+                                return Chunk::from(": heartbeat\n\n");
+                            }
+                            let events: String = rows
+                                .iter()
+                                .map(|row| format!("data: {}\n\n", row))
+                                .collect();
+                            Chunk::from(events)
+                        }),
+                        Err(e) => return Ok(return_400(&e)),
+                    };
+                    Ok(Response::builder()
+                        .header(header::CONTENT_TYPE, TEXT_EVENT_STREAM)
+                        .header(header::CACHE_CONTROL, "no-cache")
+                        .body(Body::wrap_stream(events))
+                        .unwrap())
+                }),
+        )
+    }
+
+    /// The rows of a watched query, a batch for every batch of lines ingested on its log. Empty
+    /// batches are heartbeats, sent whether or not lines were ingested.
+    fn watched_rows(
+        &self,
+        mut parsed_queries: Vec<(Statement, QueryParsing)>,
+    ) -> Result<impl Stream<Item = Vec<String>, Error = QueryError>, String> {
+        if parsed_queries.len() != 1 {
+            return Err("Watches take a single query".to_string());
+        }
+        let (statement, mut q_parse) = parsed_queries.remove(0);
+        if q_parse.aggregation.is_some() || !q_parse.order_by.is_empty() {
+            return Err("Watched queries can't be sorted or aggregated".to_string());
+        }
+        if let Some(log) = self.config.read().unwrap().get_log(&q_parse.log_name) {
+            if log.remote.is_some() {
+                return Err("Logs held by a remote server can't be watched".to_string());
+            }
+        }
+        let limit = q_parse.limit.unwrap_or(std::u64::MAX);
+        let rows = watch(&q_parse.log_name)
+            .map(move |lines| {
+                if lines.is_empty() {
+                    return Vec::new();
+                }
+                let pattern_match_results = scan_lines(&mut q_parse, &lines);
+                evaluate_lines(&statement, &q_parse, lines, pattern_match_results)
+            })
+            .map_err(|_| QueryError::Underlying("The watch was dropped".to_string()))
+            // the watch ends as soon as the `LIMIT` is reached
+            .take_from_iterable(limit);
+        Ok(rows)
+    }
+
+    fn process_statement(
+        &self,
+        access_token: &String,
+        query: Statement,
+        explore_data: bool,
+        plan_key: Option<String>,
+    ) -> Result<(Statement, QueryParsing), ProcessingQueryError> {
+        // find the table they want to query
+        let log_name = self.resolve_log_name(statement_log_name(&query)?);
+
+        // check if we have access for the requested table
+        let cfg = Arc::clone(&self.config);
+        let auth_c = Auth::new(cfg);
+        match auth_c.log_access(&access_token[..], &log_name[..]) {
+            LogAccess::Granted => (),
+            LogAccess::Forbidden => {
+                return Err(ProcessingQueryError::Forbidden("Forbidden".to_string()));
+            }
+            LogAccess::UnknownLog => {
+                return Err(ProcessingQueryError::UnknownLog(format!(
+                    "Log {} not found",
+                    log_name
+                )));
+            }
+        }
+
+        // tokens are configured by their access key
+        let redact = access_key_of(access_token)
+            .and_then(|access_key| {
+                let cfg = self.config.read().unwrap();
+                cfg.tokens.get(access_key).map(|token| token.redact.clone())
+            })
+            .unwrap_or_default();
+        // rows of another server come back as they are output, so there's nothing left to mask
+        let remote = self
+            .config
+            .read()
+            .unwrap()
+            .get_log(&log_name)
+            .map_or(false, |log| log.remote.is_some());
+        if remote && !redact.is_empty() {
+            return Err(ProcessingQueryError::Forbidden(format!(
+                "Log {} is held by another server, it can't be searched with redacted fields",
+                log_name
+            )));
+        }
+        let (query, mut q_parse) = match plan_key {
+            Some(plan_key) => self.cached_plan(plan_key, query, log_name, explore_data)?,
+            None => self.plan_statement(query, log_name, explore_data)?,
+        };
+        if !redact.is_empty() {
+            q_parse.redact_fields(redact, &self.config.read().unwrap().patterns);
+        }
+        Ok((query, q_parse))
+    }
+
+    /// Plans a statement of a saved search once per version of the log's configuration. The next
+    /// runs of the search take a copy of its plan along with one of its compiled pattern
+    /// databases, so scheduled runs and dashboard refreshes skip parsing and compiling. The plan
+    /// is made again once the log's configuration or the statement saved under `plan_key` change.
+    fn cached_plan(
+        &self,
+        plan_key: String,
+        query: Statement,
+        log_name: String,
+        explore_data: bool,
+    ) -> Result<(Statement, QueryParsing), ProcessingQueryError> {
+        let version = {
+            let cfg = self.config.read().unwrap();
+            cfg.get_log(&log_name)
+                .map(|log| etag_for(&(log, &cfg.patterns)))
+        };
+        let version = match version {
+            Some(version) => version,
+            None => return self.plan_statement(query, log_name, explore_data),
+        };
+        let sql = query.to_string();
+        let cached = QUERY_PLANS.get(&plan_key).filter(|plan| {
+            let plan = plan.lock().unwrap();
+            plan.version == version && plan.sql == sql && plan.explore_data == explore_data
+        });
+        if let Some(plan) = cached {
+            let mut cached = plan.lock().unwrap();
+            let query = cached.query.clone();
+            let mut q_parse = cached.parsing.copy_plan();
+            q_parse.hs_db = match cached.hs_dbs.pop() {
+                Some(db) => Some(db),
+                None => build_hs_db(&q_parse.scan_flags, &self.config.read().unwrap().patterns),
+            };
+            drop(cached);
+            q_parse.plan = Some(plan);
+            return Ok((query, q_parse));
+        }
+        let (query, mut q_parse) = self.plan_statement(query, log_name, explore_data)?;
+        let plan = Arc::new(Mutex::new(QueryPlan {
+            version,
+            sql,
+            explore_data,
+            query: query.clone(),
+            parsing: q_parse.copy_plan(),
+            hs_dbs: Vec::new(),
+        }));
+        QUERY_PLANS.insert(plan_key, Arc::clone(&plan));
+        q_parse.plan = Some(plan);
+        Ok((query, q_parse))
+    }
+
+    /// Translates a statement over `log_name` into a `QueryParsing`, access to the log is
+    /// checked by the caller.
+    pub fn plan_statement(
+        &self,
+        mut query: Statement,
+        log_name: String,
+        explore_data: bool,
+    ) -> Result<(Statement, QueryParsing), ProcessingQueryError> {
+        // computed fields of the log are read as the expressions they stand for
+        if let Some(log) = self.config.read().unwrap().get_log(&log_name) {
+            resolve_computed_fields(&mut query, log).map_err(ProcessingQueryError::Fail)?;
+        }
+        // determine our read strategy
+        let read_all = match query {
+            Statement::Query(ref q) => match q.body {
+                SetExpr::Select(ref bodyselect) => {
+                    let mut is_wildcard = false;
+                    for projection in &bodyselect.projection {
+                        if *projection == SelectItem::Wildcard {
+                            is_wildcard = true
                         }
                     }
                     is_wildcard
@@ -474,30 +1795,157 @@ impl Query {
         let mut smart_fields: Vec<SmartColumn> = Vec::new();
         let mut smart_fields_set: HashSet<String> = HashSet::new();
         let mut projections_ordered: Vec<String> = Vec::new();
+        let mut computed_fields: Vec<ComputedColumn> = Vec::new();
+        let mut aggregates: Vec<AggregateColumn> = Vec::new();
+        // projections other than aggregates along with the key they are returned as, which have
+        // to be grouped by on aggregating queries
+        let mut plain_projections: Vec<(Expr, String)> = Vec::new();
+        let mut aggregate_projections: Vec<(Expr, String)> = Vec::new();
         for proj in &projections {
-            match proj {
-                SelectItem::UnnamedExpr(ref ast) => {
-                    // we have an identifier
-                    match detect_field_for_ast(ast) {
-                        FieldFound::PositionalField(positional) => {
-                            projections_ordered.push(positional.alias.clone());
-                            positional_fields.push(positional);
-                        }
-                        FieldFound::SmartField(smart) => {
-                            // we use this set to keep track of active smart fields
-                            smart_fields_set.insert(smart.typed.clone());
-                            // record the order or extraction
-                            projections_ordered.push(smart.alias.clone());
-                            // track the smartfield
-                            smart_fields.push(smart);
-                        }
-                        _ => (),
+            let (ast, column_alias) = match proj {
+                SelectItem::UnnamedExpr(ref ast) => (ast, None),
+                // `SELECT $ip AS client_ip`, the alias will be used as the output key
+                SelectItem::ExprWithAlias {
+                    ref expr,
+                    ref alias,
+                } => (expr, Some(alias.clone())),
+                _ => continue, // for now let's not do anything on other Variances
+            };
+            // `SELECT COUNT(*)`, the fields the aggregate reads are extracted too
+            if is_aggregate_projection(ast) {
+                let mut fields: Vec<Expr> = Vec::new();
+                let (function, arg) = match parse_aggregate(ast, &mut fields) {
+                    Ok(aggregate) => aggregate,
+                    Err(e) => return Err(ProcessingQueryError::Fail(e)),
+                };
+                extract_fields_of(
+                    &fields,
+                    &mut positional_fields,
+                    &mut smart_fields,
+                    &mut smart_fields_set,
+                );
+                let alias = column_alias.unwrap_or_else(|| ast.to_string());
+                projections_ordered.push(alias.clone());
+                aggregate_projections.push((ast.clone(), alias.clone()));
+                aggregates.push(AggregateColumn {
+                    alias,
+                    function,
+                    arg,
+                });
+                continue;
+            }
+            let projected = projections_ordered.len();
+            // `SELECT LOWER($quoted)`, the fields the functions read are extracted too
+            if is_computed_projection(ast) {
+                let mut fields: Vec<Expr> = Vec::new();
+                let expr = match parse_projection_expr(ast, &mut fields) {
+                    Ok(expr) => expr,
+                    Err(e) => return Err(ProcessingQueryError::Fail(e)),
+                };
+                extract_fields_of(
+                    &fields,
+                    &mut positional_fields,
+                    &mut smart_fields,
+                    &mut smart_fields_set,
+                );
+                let alias = column_alias.unwrap_or_else(|| ast.to_string());
+                projections_ordered.push(alias.clone());
+                plain_projections.push((ast.clone(), alias.clone()));
+                computed_fields.push(ComputedColumn { alias, expr });
+                continue;
+            }
+            // we have an identifier
+            match detect_field_for_ast(ast) {
+                FieldFound::PositionalField(mut positional) => {
+                    if let Some(alias) = column_alias {
+                        positional.alias = alias;
+                    }
+                    projections_ordered.push(positional.alias.clone());
+                    positional_fields.push(positional);
+                }
+                FieldFound::SmartField(mut smart) => {
+                    if let Some(alias) = column_alias {
+                        smart.alias = alias;
+                    }
+                    // we use this set to keep track of active smart fields
+                    smart_fields_set.insert(smart.typed.clone());
+                    // record the order or extraction
+                    projections_ordered.push(smart.alias.clone());
+                    // track the smartfield
+                    smart_fields.push(smart);
+                }
+                _ => (),
+            }
+            if projections_ordered.len() > projected {
+                plain_projections.push((ast.clone(), projections_ordered[projected].clone()));
+            }
+        }
+
+        // `GROUP BY` and aggregate functions fold the matching lines into a row per group
+        let aggregation = match query {
+            Statement::Query(ref q) => match q.body {
+                SetExpr::Select(ref bodyselect)
+                    if !aggregates.is_empty() || !bodyselect.group_by.is_empty() =>
+                {
+                    if read_all {
+                        return Err(ProcessingQueryError::Fail(
+                            "SELECT * can't be combined with GROUP BY or aggregate functions"
+                                .to_string(),
+                        ));
                     }
+                    if bodyselect.having.is_some() {
+                        return Err(ProcessingQueryError::UnsupportedQuery(
+                            "HAVING is not supported".to_string(),
+                        ));
+                    }
+                    let group_by = plan_group_by(&bodyselect.group_by, &plain_projections)
+                        .map_err(ProcessingQueryError::Fail)?;
+                    for group in &bodyselect.group_by {
+                        let mut fields: Vec<Expr> = Vec::new();
+                        let _ = parse_projection_expr(group, &mut fields);
+                        extract_fields_of(
+                            &fields,
+                            &mut positional_fields,
+                            &mut smart_fields,
+                            &mut smart_fields_set,
+                        );
+                    }
+                    Some(Aggregation {
+                        group_by,
+                        aggregates,
+                    })
                 }
-                _ => {} // for now let's not do anything on other Variances
+                _ => None,
+            },
+            _ => None,
+        };
+        if aggregation.is_some() {
+            let remote = match self.config.read().unwrap().get_log(&log_name) {
+                Some(log) => log.remote.is_some(),
+                None => false,
+            };
+            if remote {
+                return Err(ProcessingQueryError::UnsupportedQuery(
+                    "GROUP BY and aggregate functions are not supported on logs held by a remote server"
+                        .to_string(),
+                ));
             }
         }
 
+        // `ORDER BY` sorts on the output columns, named by expression or by alias
+        let order_by = match query {
+            Statement::Query(ref q) => {
+                let projected: Vec<(Expr, String)> = plain_projections
+                    .iter()
+                    .chain(aggregate_projections.iter())
+                    .cloned()
+                    .collect();
+                plan_order_by(&q.order_by, &projected, read_all)
+                    .map_err(ProcessingQueryError::Fail)?
+            }
+            _ => Vec::new(),
+        };
+
         // see which fields in the conditions were not requested in the projections and extract them too
         let limit = match query {
             Statement::Query(ref q) => {
@@ -529,31 +1977,66 @@ impl Query {
         };
 
         // Build the parsing flags used by scanlog
-        let mut scan_flags: constants::ScanFlags = constants::ScanFlags::NONE;
+        let mut scan_flags = ScanFlags::empty();
         for sfield_type in smart_fields_set {
-            let flag = match sfield_type.as_ref() {
-                "$ip" => constants::ScanFlags::IP,
-                "$email" => constants::ScanFlags::EMAIL,
-                "$date" => constants::ScanFlags::DATE,
-                "$quoted" => constants::ScanFlags::QUOTED,
-                "$url" => constants::ScanFlags::URL,
-                "$phone" => constants::ScanFlags::PHONE,
-                "$user_agent" => constants::ScanFlags::USER_AGENT,
-                _ => constants::ScanFlags::NONE,
-            };
-            if scan_flags == constants::ScanFlags::NONE {
-                scan_flags = flag;
-            } else {
-                scan_flags = scan_flags | flag;
-            }
+            scan_flags.union(&ScanFlags::for_field(&sfield_type));
         }
         // if we are reading and exploring all, turn all flags on
         if read_all && explore_data {
-            //            scan_flags = scan_flags.all();
-            scan_flags = constants::ScanFlags::all();
+            scan_flags = ScanFlags::all();
         }
 
-        let hs_db: Option<BlockDatabase> = build_hs_db(&scan_flags);
+        let hs_db: Option<PatternDb> =
+            build_hs_db(&scan_flags, &self.config.read().unwrap().patterns);
+
+        // literals every matching line contains, used to skip objects via their bloom filters
+        let bloom_literals = match query {
+            Statement::Query(ref q) => match q.body {
+                SetExpr::Select(ref bodyselect) => match &bodyselect.selection {
+                    Some(selection) => required_literals(selection),
+                    None => Vec::new(),
+                },
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        // `$time` narrows the objects listed to the ones written on a time range
+        let time_range = match query {
+            Statement::Query(ref q) => match q.body {
+                SetExpr::Select(ref bodyselect) => match &bodyselect.selection {
+                    // on UTC until the query's time zone is known, see `set_timezone`
+                    Some(selection) => time_range(selection, &FixedOffset::east(0))
+                        .map_err(ProcessingQueryError::Fail)?,
+                    None => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        // `$received` skips the objects recording no line received on a time range
+        let received_range = match query {
+            Statement::Query(ref q) => match q.body {
+                SetExpr::Select(ref bodyselect) => match &bodyselect.selection {
+                    Some(selection) => received_range(selection, &FixedOffset::east(0))
+                        .map_err(ProcessingQueryError::Fail)?,
+                    None => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        let date_predicate = time_range.is_some()
+            || received_range.is_some()
+            || match query {
+                Statement::Query(ref q) => match q.body {
+                    SetExpr::Select(ref bodyselect) => match &bodyselect.selection {
+                        Some(selection) => has_date_predicate(selection),
+                        None => false,
+                    },
+                    _ => false,
+                },
+                _ => false,
+            };
 
         // we keep track of the parsing of the queries via their signature.
         Ok((
@@ -564,14 +2047,133 @@ impl Query {
                 scan_flags,
                 positional_fields,
                 smart_fields,
+                computed_fields,
                 projections_ordered,
                 limit,
+                date_predicate,
+                time_range,
+                received_range,
+                aggregation,
+                order_by,
                 hs_db,
                 explore_data,
+                output_shape: OutputShape::default(),
+                bloom_literals,
+                partition: None,
+                redact: Vec::new(),
+                timezone: FixedOffset::east(0),
+                highlight: false,
+                plan: None,
             },
         ))
     }
 
+    /// Estimates how many objects and bytes a query over `log_name` would read, and roughly how
+    /// long it would take, from the listing of its datastores and their observed latency.
+    pub fn estimate(
+        &self,
+        log_name: &str,
+        sql: String,
+    ) -> Result<impl Future<Item = QueryEstimate, Error = String>, String> {
+        let mut ast = self.parse_query(sql).map_err(|e| format!("{:?}", e))?;
+        if ast.len() != 1 {
+            return Err("Only a single query can be estimated".to_string());
+        }
+        let statement = ast.remove(0);
+        let query_log = statement_log_name(&statement).map_err(|e| format!("{:?}", e))?;
+        let query_log = self.resolve_log_name(query_log);
+        if query_log != log_name {
+            return Err(format!("The query must be on log {}", log_name));
+        }
+        let (_, q_parse) = self
+            .plan_statement(statement, query_log, false)
+            .map_err(|e| format!("{:?}", e))?;
+
+        let cfg_read = self.config.read().unwrap();
+        let log = match cfg_read.get_log(&log_name.to_string()) {
+            Some(log) => log,
+            None => return Err(format!("Unknown log {}", log_name)),
+        };
+        let in_flight = (cfg_read.server.prefetch_depth + 1) as u64;
+        let naming = ObjectNaming::for_log(log).within(q_parse.time_range);
+        // the replicas of a replicated log hold the same objects, a single one is read
+        let hot_datastores: Vec<String> = hot_replica_sets(log, &cfg_read)
+            .into_iter()
+            .filter_map(|replicas| replicas.into_iter().next())
+            .collect();
+        let mut latencies = latency_table();
+        let mut listing = |ds_names: &Vec<String>| -> Vec<_> {
+            ds_names
+                .iter()
+                .filter_map(|ds_name| cfg_read.datastore.get(ds_name))
+                .map(|ds| {
+                    let ds_name = ds.name.clone().unwrap_or_default();
+                    let latency_ms = match latencies.remove(&ds_name) {
+                        Some(latency) if latency.samples > 0 => latency.avg_ms as u64,
+                        _ => ESTIMATE_DEFAULT_LATENCY_MS,
+                    };
+                    list_msl_bucket_objects(log_name, ds, &naming)
+                        .fold((0, 0), |(objects, bytes), obj| {
+                            Ok::<_, StorageError<ListObjectsError>>((objects + 1, bytes + obj.size))
+                        })
+                        .map(move |(objects, bytes)| {
+                            let ms = estimate_read_ms(objects, bytes, latency_ms, in_flight);
+                            (objects, bytes, ms)
+                        })
+                        .map_err(move |_| format!("Could not list datastore {}", ds_name))
+                })
+                .collect()
+        };
+        let hot = listing(&hot_datastores);
+        let cold = listing(&log.cold_datastores);
+        let bloom_filtered = log.bloom_filters && !q_parse.bloom_literals.is_empty();
+        let full_scan = q_parse.limit.is_none();
+        Ok(future::join_all(hot)
+            .join(future::join_all(cold))
+            .map(move |(hot, cold)| {
+                // the datastores of a tier are read at once, the cold tier after the hot one
+                let slowest = |tier: &Vec<(u64, u64, u64)>| tier.iter().map(|t| t.2).max();
+                let estimated_ms = slowest(&hot).unwrap_or(0) + slowest(&cold).unwrap_or(0);
+                QueryEstimate {
+                    objects: hot.iter().chain(cold.iter()).map(|t| t.0).sum(),
+                    bytes: hot.iter().chain(cold.iter()).map(|t| t.1).sum(),
+                    bloom_filtered,
+                    full_scan,
+                    estimated_ms,
+                }
+            }))
+    }
+
+    /// Plans the statement selecting the lines of `log_name` matching `condition`, a `WHERE`
+    /// clause, for `matching_lines`.
+    pub fn plan_condition(
+        &self,
+        log_name: &str,
+        condition: &str,
+    ) -> Result<(Statement, QueryParsing), String> {
+        let sql = format!("SELECT * FROM \"{}\" WHERE {}", log_name, condition);
+        let mut ast = self.parse_query(sql).map_err(|e| e.to_string())?;
+        if ast.len() != 1 {
+            return Err("The condition must be a single WHERE clause".to_string());
+        }
+        self.plan_statement(ast.remove(0), log_name.to_string(), false)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    /// Plans the statement selecting the lines of `log_name` matching `condition`, the `WHERE`
+    /// clause of a purge.
+    pub fn plan_purge(
+        &self,
+        log_name: &str,
+        condition: &str,
+    ) -> Result<(Statement, QueryParsing), String> {
+        let (statement, q_parse) = self.plan_condition(log_name, condition)?;
+        if q_parse.limit.is_some() {
+            return Err("A purge can't be limited".to_string());
+        }
+        Ok((statement, q_parse))
+    }
+
     /// Parses a vector sql statements and returns a parsed summary
     /// structure for each.
     pub fn process_sql(
@@ -580,93 +2182,343 @@ impl Query {
         ast: Vec<Statement>,
         explore_data: bool,
     ) -> Result<Vec<(Statement, QueryParsing)>, ProcessingQueryError> {
+        self.process_saved_sql(access_token, ast, explore_data, None)
+    }
+
+    /// `process_sql` for the statements of a search saved under `saved_search`, their plans are
+    /// cached for the access key of the token, see `cached_plan`
+    pub fn process_saved_sql(
+        &self,
+        access_token: &String,
+        ast: Vec<Statement>,
+        explore_data: bool,
+        saved_search: Option<&str>,
+    ) -> Result<Vec<(Statement, QueryParsing)>, ProcessingQueryError> {
+        let account = access_key_of(access_token).unwrap_or("");
         ast.into_iter()
-            .map(|q| self.process_statement(&access_token, q, explore_data))
+            .enumerate()
+            .map(|(i, q)| {
+                let plan_key = saved_search.map(|id| format!("{}\n{}\n{}", account, id, i));
+                self.process_statement(&access_token, q, explore_data, plan_key)
+            })
             .collect()
     }
 
     /// Reads all the log files for a given `QueryParse` in marked `DataSource`
+    /// Spawns a task reading all the logs of the query stored on a datastore into `tx`. The
+    /// datastore is the first of `replicas`, the others hold the same objects and are read from
+    /// when it fails to return one.
+    fn spawn_datastore_read(
+        cfg: Arc<RwLock<Config>>,
+        query_state_holder: Arc<RwLock<StateHolder>>,
+        query_index: usize,
+        replicas: Vec<String>,
+        memory: Arc<QueryMemory>,
+        partial_results: bool,
+        tx: mpsc::UnboundedSender<Result<LinesBatch, QueryError>>,
+    ) {
+        let ds_name = replicas[0].clone();
+        let context = format!("Query read of datastore {}", ds_name);
+        let err_ds_name = ds_name.clone();
+        let err_tx = tx.clone();
+        // Task that will read all the logs for a given datastore
+        let source = ds_name.clone();
+        let task =
+            future::lazy(move || {
+                Query::read_logs_from_datastore(cfg, query_state_holder, query_index, replicas)
+                    .fold(tx, move |tx, lines| {
+                        if memory.reserve(lines_size(&lines)) {
+                            Either::A(
+                                // the query stops taking lines once its limit is reached
+                                tx.send(Ok((source.clone(), lines)))
+                                    .map_err(|_| QueryError::Closed),
+                            )
+                        } else {
+                            // report it once to the query and stop reading
+                            let exceeded = QueryError::MemoryLimitExceeded(memory.limit);
+                            let _ = tx.send(Err(QueryError::MemoryLimitExceeded(memory.limit)));
+                            Either::B(future::err(exceeded))
+                        }
+                    })
+            })
+            .then(move |res| {
+                match res {
+                    // already reported by the reader or nobody is listening anymore
+                    Ok(_) | Err(QueryError::MemoryLimitExceeded(_)) | Err(QueryError::Closed) => (),
+                    Err(QueryError::Cancelled) => {
+                        let _ = err_tx.send(Err(QueryError::Cancelled));
+                    }
+                    Err(e) => {
+                        error!("Could not read datastore {}: {}", err_ds_name, e);
+                        if !partial_results {
+                            let _ = err_tx.send(Err(QueryError::Underlying(format!(
+                                "Could not read datastore {}",
+                                err_ds_name
+                            ))));
+                        }
+                    }
+                }
+                Ok(())
+            });
+        // a panicking reader drops `tx`, so the query still ends
+        supervisor::spawn_isolated(context, task);
+    }
+
     fn read_logs_from_datastore(
         cfg: Arc<RwLock<Config>>,
         query_state_holder: Arc<RwLock<StateHolder>>,
         query_index: usize,
-        log_ds_index: usize,
+        replicas: Vec<String>,
     ) -> impl Stream<Item = Vec<String>, Error = QueryError> {
         let cfg_read = cfg.read().unwrap();
         let read_state_holder = query_state_holder.read().unwrap();
 
-        // Get the `QueryParse` and the `Log` from the indexes provided
+        // Get the `QueryParse` and the `Log` from the index provided
         let q_parse = &read_state_holder.query_parsing[query_index].1;
+        let progress = Arc::clone(&read_state_holder.progress);
+        let listed_progress = Arc::clone(&progress);
+        let skipped_progress = Arc::clone(&progress);
+        let profile = read_state_holder.profile.clone();
+        let fetch_profile = profile.clone();
+        let running = read_state_holder.running.clone();
         let log = cfg_read.get_log(&q_parse.log_name).unwrap();
+        let log_name = log.name.clone().unwrap();
+        let lossy_decoding = log.lossy_decoding;
+        // objects being fetched at once, the one being scanned plus the ones prefetched
+        let in_flight = cfg_read.server.prefetch_depth + 1;
+        let bloom_literals = if log.bloom_filters {
+            q_parse.bloom_literals.clone()
+        } else {
+            Vec::new()
+        };
 
-        let ds_name = &log.datastores[log_ds_index];
+        let naming = ObjectNaming::for_log(log)
+            .in_partition(q_parse.partition.as_ref().map(|p| p.as_str()))
+            .within(q_parse.time_range);
+        let received_range = q_parse.received_range;
+        // lines prefixed with their receive time are matched on it one by one
+        let stamped_range = received_range
+            .filter(|_| log.stamp.as_ref().map(|s| s.as_str()) == Some(STAMP_PREPEND));
+        let received_progress = Arc::clone(&progress);
 
-        let log_name = cfg
-            .read()
-            .unwrap()
-            .get_log(&q_parse.log_name)
-            .unwrap()
-            .name
-            .clone()
-            .unwrap();
         // If the log has a reference to an invalid datastore panic out.
-        let ds = cfg_read.datastore.get(ds_name.as_str()).unwrap();
-        let cfg2 = Arc::clone(&cfg);
-        let query_state_holder2 = Arc::clone(&query_state_holder);
-        // Returns Result<(ds, files), error>. Need to stop on error.
-        // TODO: Stop on error
-        list_msl_bucket_files(log_name.as_str(), &ds)
-            .map(move |obj_key| (query_index.clone(), log_ds_index.clone(), obj_key))
-            .map_err(|e| QueryError::Underlying(format!("{:?}", e))) //temporarely remove error, we need to adress this
-            .map(move |(query_index, log_ds_index, obj_key)| {
-                let read_state_holder = query_state_holder2.read().unwrap();
-                let q_parse = &read_state_holder.query_parsing[query_index].1;
-
-                let cfg_read = cfg2.read().unwrap();
-                let log = cfg_read.get_log(&q_parse.log_name).unwrap();
-                drop(read_state_holder);
-
-                let ds_name = &log.datastores[log_ds_index];
-                let ds = cfg_read.datastore.get(ds_name).unwrap();
-
-                read_file_line_by_line(&obj_key, &ds)
+        let replicas: Vec<DataStore> = replicas
+            .iter()
+            .map(|ds_name| cfg_read.datastore.get(ds_name.as_str()).unwrap().clone())
+            .collect();
+        let ds_name = replicas[0].name.clone().unwrap_or_default();
+        let received_ds = replicas[0].clone();
+        let bloom_ds = replicas[0].clone();
+        // Listing and reading errors end the stream, the reader reports them to the query
+        let listing = read_from_replicas(replicas.clone(), move |ds| {
+            list_msl_bucket_files(log_name.as_str(), ds, &naming)
+        });
+        timed(listing, profile, &ds_name, Stage::List)
+            .map_err(|e| QueryError::Underlying(format!("{:?}", e)))
+            .inspect(move |_| listed_progress.object_listed())
+            .and_then(move |obj_key| received_on_range(obj_key, &received_ds, received_range))
+            .filter(move |(_, on_range)| {
+                // objects skipped by their receive times count as scanned
+                if !*on_range {
+                    received_progress.object_scanned();
+                }
+                *on_range
+            })
+            .and_then(move |(obj_key, _)| {
+                if bloom_literals.is_empty() {
+                    return Either::A(future::ok((obj_key, true)));
+                }
+                let literals = bloom_literals.clone();
+                // objects without a readable filter are always read
+                Either::B(read_bloom_filter(&obj_key, &bloom_ds).then(move |res| {
+                    let may_match = match res {
+                        Ok(Some(filter)) => {
+                            literals.iter().all(|l| filter.may_contain_substring(l))
+                        }
+                        _ => true,
+                    };
+                    Ok((obj_key, may_match))
+                }))
+            })
+            .filter(move |(_, may_match)| {
+                // objects skipped by their filter count as scanned
+                if !*may_match {
+                    skipped_progress.object_scanned();
+                }
+                *may_match
+            })
+            .map(move |(obj_key, _)| {
+                let progress = Arc::clone(&progress);
+                let object_lines = read_from_replicas(replicas.clone(), move |ds| {
+                    read_file_line_by_line(&obj_key, ds, lossy_decoding)
+                });
+                // resolves once the first lines of the object arrive, so the next objects are
+                // requested while the current one is scanned
+                timed(object_lines, fetch_profile.clone(), &ds_name, Stage::Fetch)
                     .map_err(|e| QueryError::Underlying(format!("{:?}", e)))
+                    .map(move |lines| match &stamped_range {
+                        Some(range) => lines
+                            .into_iter()
+                            .filter(|line| stamped_on_range(line, range))
+                            .collect(),
+                        None => lines,
+                    })
+                    .chain(stream::poll_fn(
+                        move || -> Poll<Option<Vec<String>>, QueryError> {
+                            progress.object_scanned();
+                            Ok(Async::Ready(None))
+                        },
+                    ))
+                    .into_future()
+                    .map(|(first, rest)| stream::iter_ok(first).chain(rest))
+                    .map_err(|(e, _)| e)
             })
+            .buffered(in_flight)
             .flatten()
+            // a cancelled search stops listing and reading objects
+            .and_then(move |lines| match &running {
+                Some(running) if running.is_cancelled() => Err(QueryError::Cancelled),
+                _ => Ok(lines),
+            })
     }
 }
 
-fn process_fields_for_ast(
-    ast_node: &Expr,
+/// Extracts the fields read by a function from the lines
+fn extract_fields_of(
+    fields: &[Expr],
     positional_fields: &mut Vec<PositionalColumn>,
     smart_fields: &mut Vec<SmartColumn>,
     smart_fields_set: &mut HashSet<String>,
 ) {
-    match ast_node {
-        Expr::Nested(nested_ast) => {
-            process_fields_for_ast(
-                nested_ast,
-                positional_fields,
-                smart_fields,
-                smart_fields_set,
-            );
-        }
-        Expr::IsNotNull(ast) => {
-            match detect_field_for_ast(&**ast) {
-                FieldFound::PositionalField(positional) => {
-                    positional_fields.push(positional);
-                }
-                FieldFound::SmartField(smart) => {
-                    // we use this set to keep track of active smart fields
-                    smart_fields_set.insert(smart.typed.clone());
-                    // track the smartfield
-                    smart_fields.push(smart);
-                }
-                _ => (),
+    for field in fields {
+        match detect_field_for_ast(field) {
+            FieldFound::PositionalField(positional) => {
+                positional_fields.push(positional);
+            }
+            FieldFound::SmartField(smart) => {
+                smart_fields_set.insert(smart.typed.clone());
+                smart_fields.push(smart);
             }
+            _ => (),
         }
-        Expr::IsNull(ast) => {
-            match detect_field_for_ast(&**ast) {
+    }
+}
+
+/// Pairs the `ORDER BY` expressions with the output columns they sort on, by expression or by
+/// alias, ie: `ORDER BY hits` for `COUNT(*) AS hits`. Only projected columns can be sorted on,
+/// and `$line` when every line is returned.
+fn plan_order_by(
+    order_by: &[OrderByExpr],
+    projected: &[(Expr, String)],
+    read_all: bool,
+) -> Result<Vec<OrderColumn>, String> {
+    let mut columns = Vec::new();
+    for order in order_by {
+        let projection = projected.iter().find(|(ast, alias)| {
+            *ast == order.expr
+                || match &order.expr {
+                    Expr::Identifier(identifier) => identifier == alias,
+                    _ => false,
+                }
+        });
+        let alias = match (projection, &order.expr) {
+            (Some((_, alias)), _) => alias.clone(),
+            (None, Expr::Identifier(identifier)) if read_all && identifier == "$line" => {
+                identifier.clone()
+            }
+            (None, expr) => {
+                return Err(format!(
+                    "`{}` must be projected to ORDER BY it",
+                    expr.to_string()
+                ))
+            }
+        };
+        columns.push(OrderColumn {
+            alias,
+            descending: order.asc == Some(false),
+        });
+    }
+    Ok(columns)
+}
+
+/// Pairs the `GROUP BY` expressions with the projections returning them, by expression or by
+/// alias, ie: `GROUP BY bucket` for `TIME_BUCKET($date, '5m') AS bucket`. Every projection that
+/// is not an aggregate has to be grouped by.
+fn plan_group_by(
+    group_by: &[Expr],
+    plain_projections: &[(Expr, String)],
+) -> Result<Vec<GroupColumn>, String> {
+    let mut groups = Vec::new();
+    let mut grouped: HashSet<String> = HashSet::new();
+    for group in group_by {
+        if is_aggregate_projection(group) {
+            return Err(format!("Can't GROUP BY an aggregate function, `{}`", group));
+        }
+        let projection = plain_projections.iter().find(|(ast, alias)| {
+            ast == group
+                || match group {
+                    Expr::Identifier(identifier) => identifier == alias,
+                    _ => false,
+                }
+        });
+        let (expr_ast, alias) = match projection {
+            Some((ast, alias)) => (ast, Some(alias.clone())),
+            None => (group, None),
+        };
+        let expr = parse_projection_expr(expr_ast, &mut Vec::new())?;
+        if let Some(alias) = &alias {
+            grouped.insert(alias.clone());
+        }
+        groups.push(GroupColumn { alias, expr });
+    }
+    for (ast, alias) in plain_projections {
+        if !grouped.contains(alias) {
+            return Err(format!(
+                "`{}` must be on GROUP BY or used in an aggregate function",
+                ast
+            ));
+        }
+    }
+    Ok(groups)
+}
+
+fn process_fields_for_ast(
+    ast_node: &Expr,
+    positional_fields: &mut Vec<PositionalColumn>,
+    smart_fields: &mut Vec<SmartColumn>,
+    smart_fields_set: &mut HashSet<String>,
+) {
+    match ast_node {
+        Expr::Nested(nested_ast) => {
+            process_fields_for_ast(
+                nested_ast,
+                positional_fields,
+                smart_fields,
+                smart_fields_set,
+            );
+        }
+        Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => {
+            process_fields_for_ast(expr, positional_fields, smart_fields, smart_fields_set);
+        }
+        Expr::IsNotNull(ast) => {
+            match detect_field_for_ast(&**ast) {
+                FieldFound::PositionalField(positional) => {
+                    positional_fields.push(positional);
+                }
+                FieldFound::SmartField(smart) => {
+                    // we use this set to keep track of active smart fields
+                    smart_fields_set.insert(smart.typed.clone());
+                    // track the smartfield
+                    smart_fields.push(smart);
+                }
+                _ => (),
+            }
+        }
+        Expr::IsNull(ast) => {
+            match detect_field_for_ast(&**ast) {
                 FieldFound::PositionalField(positional) => {
                     positional_fields.push(positional);
                 }
@@ -758,6 +2610,8 @@ pub fn extract_smart_fields(
     found_vals: &HashMap<String, Vec<Option<HSPatternMatch>>>,
 ) {
     if query_data.smart_fields.len() > 0 {
+        // the line parsed as JSON, once and only if a `$json` subfield is asked for
+        let mut json_line: Option<Option<serde_json::Value>> = None;
         // Use HS patterns in line if a HSPatternMatchResults is passed
         for smt in &query_data.smart_fields {
             let key = smt.alias.clone();
@@ -773,130 +2627,40 @@ pub fn extract_smart_fields(
                                 &smt.typed[..],
                                 &smt.subfield.as_ref().map_or(None, |m| Some(m.as_str())),
                             ) {
-                                (SF_USER_AGENT, Some("name")) => {
-                                    // TODO: Cache this parsing
-                                    let parser = woothee::parser::Parser::new();
-                                    match parser
-                                        .parse(&line[value.from as usize..value.to as usize])
-                                    {
-                                        Some(r) => {
-                                            projection_values.insert(
-                                                key,
-                                                Some(PatternValue::RichData(r.name.to_string())),
-                                            );
-                                        }
-                                        None => {
-                                            projection_values.insert(key, None);
-                                        }
-                                    }
-                                }
-                                (SF_USER_AGENT, Some("category")) => {
-                                    // TODO: Cache this parsing
-                                    let parser = woothee::parser::Parser::new();
-                                    match parser
-                                        .parse(&line[value.from as usize..value.to as usize])
-                                    {
-                                        Some(r) => {
-                                            projection_values.insert(
-                                                key,
-                                                Some(PatternValue::RichData(
-                                                    r.category.to_string(),
-                                                )),
-                                            );
-                                        }
-                                        None => {
-                                            projection_values.insert(key, None);
-                                        }
-                                    }
-                                }
-                                (SF_USER_AGENT, Some("browser_type")) => {
-                                    // TODO: Cache this parsing
-                                    let parser = woothee::parser::Parser::new();
-                                    match parser
-                                        .parse(&line[value.from as usize..value.to as usize])
-                                    {
-                                        Some(r) => {
-                                            projection_values.insert(
-                                                key,
-                                                Some(PatternValue::RichData(
-                                                    r.browser_type.to_string(),
-                                                )),
-                                            );
-                                        }
-                                        None => {
-                                            projection_values.insert(key, None);
-                                        }
-                                    }
-                                }
-                                (SF_USER_AGENT, Some("os")) => {
+                                (SF_USER_AGENT, Some(subfield))
+                                    if USER_AGENT_SUBFIELDS.contains(&subfield) =>
+                                {
                                     // TODO: Cache this parsing
                                     let parser = woothee::parser::Parser::new();
-                                    match parser
+                                    let parsed = parser
                                         .parse(&line[value.from as usize..value.to as usize])
-                                    {
-                                        Some(r) => {
-                                            projection_values.insert(
-                                                key,
-                                                Some(PatternValue::RichData(r.os.to_string())),
-                                            );
-                                        }
-                                        None => {
-                                            projection_values.insert(key, None);
-                                        }
-                                    }
+                                        .map(|r| user_agent_subfield(&r, subfield));
+                                    projection_values
+                                        .insert(key, parsed.map(PatternValue::RichData));
                                 }
-                                (SF_USER_AGENT, Some("os_version")) => {
-                                    // TODO: Cache this parsing
-                                    let parser = woothee::parser::Parser::new();
-                                    match parser
-                                        .parse(&line[value.from as usize..value.to as usize])
-                                    {
-                                        Some(r) => {
-                                            projection_values.insert(
-                                                key,
-                                                Some(PatternValue::RichData(
-                                                    r.os_version.to_string(),
-                                                )),
-                                            );
-                                        }
-                                        None => {
-                                            projection_values.insert(key, None);
-                                        }
-                                    }
+                                // `$kv.level` is the value of the `level=` pair of the line
+                                (SF_KV, Some(kv_key)) => {
+                                    projection_values.insert(
+                                        key,
+                                        kv_value(line, kv_key).map(PatternValue::RichData),
+                                    );
                                 }
-                                (SF_USER_AGENT, Some("version")) => {
-                                    // TODO: Cache this parsing
-                                    let parser = woothee::parser::Parser::new();
-                                    match parser
-                                        .parse(&line[value.from as usize..value.to as usize])
-                                    {
-                                        Some(r) => {
-                                            projection_values.insert(
-                                                key,
-                                                Some(PatternValue::RichData(r.version.to_string())),
-                                            );
-                                        }
-                                        None => {
-                                            projection_values.insert(key, None);
-                                        }
-                                    }
+                                // `$json.request.status` is the value at that path of the line
+                                (SF_JSON, Some(path)) => {
+                                    let parsed = json_line
+                                        .get_or_insert_with(|| serde_json::from_str(line).ok());
+                                    let found = parsed
+                                        .as_ref()
+                                        .and_then(|document| json_path_value(document, path));
+                                    projection_values
+                                        .insert(key, found.map(PatternValue::RichData));
                                 }
-                                (SF_USER_AGENT, Some("vendor")) => {
-                                    // TODO: Cache this parsing
-                                    let parser = woothee::parser::Parser::new();
-                                    match parser
-                                        .parse(&line[value.from as usize..value.to as usize])
-                                    {
-                                        Some(r) => {
-                                            projection_values.insert(
-                                                key,
-                                                Some(PatternValue::RichData(r.vendor.to_string())),
-                                            );
-                                        }
-                                        None => {
-                                            projection_values.insert(key, None);
-                                        }
-                                    }
+                                // `$json` alone is the whole line, not only the start matched
+                                (SF_JSON, None) => {
+                                    projection_values.insert(
+                                        key,
+                                        Some(PatternValue::RichData(line.trim().to_string())),
+                                    );
                                 }
                                 (_, _) => {
                                     projection_values
@@ -918,63 +2682,214 @@ pub fn extract_smart_fields(
     }
 }
 
+/// Value at a dotted path of a JSON document, ie: `request.status`. Strings are unquoted, objects
+/// and arrays are returned as JSON and `null` is the same as a missing value.
+fn json_path_value(document: &serde_json::Value, path: &str) -> Option<String> {
+    let mut value = document;
+    for step in path.split('.') {
+        value = match value {
+            serde_json::Value::Object(fields) => fields.get(step)?,
+            _ => return None,
+        };
+    }
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(text) => Some(text.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// Value of a `$user_agent` subfield, `subfield` is one of `USER_AGENT_SUBFIELDS`
+fn user_agent_subfield(parsed: &woothee::parser::WootheeResult, subfield: &str) -> String {
+    match subfield {
+        "name" => parsed.name.to_string(),
+        "category" => parsed.category.to_string(),
+        "browser_type" => parsed.browser_type.to_string(),
+        "os" => parsed.os.to_string(),
+        "os_version" => parsed.os_version.to_string(),
+        "version" => parsed.version.to_string(),
+        "vendor" => parsed.vendor.to_string(),
+        _ => unreachable!("unknown user agent subfield {}", subfield),
+    }
+}
+
+/// Evaluates the projections computed with functions on a line
+fn evaluate_computed_fields(
+    projection_values: &HashMap<String, Option<PatternValue>>,
+    query_data: &QueryParsing,
+    line: &str,
+) -> HashMap<String, Option<serde_json::Value>> {
+    query_data
+        .computed_fields
+        .iter()
+        .map(|computed| {
+            (
+                computed.alias.clone(),
+                evaluate_projection(
+                    &computed.expr,
+                    projection_values,
+                    line,
+                    &query_data.timezone,
+                ),
+            )
+        })
+        .collect()
+}
+
 /// Builds the resulting line output, this function will consume the projection values map
 fn make_output(
     mut projection_values: HashMap<String, Option<PatternValue>>,
+    mut computed_values: HashMap<String, Option<serde_json::Value>>,
     query_data: &QueryParsing,
     line: String,
     found_vals: HashMap<String, Vec<Option<HSPatternMatch>>>,
+    matches: Option<Vec<(usize, usize)>>,
 ) -> Option<String> {
+    let mut fields: Vec<(String, serde_json::Value)> = Vec::new();
+    // build the result iterate over the ordered resulting projections, when mixed with a
+    // wildcard (`SELECT *, $ip`) these are returned next to `$line`
+    for i in 0..query_data.projections_ordered.len() {
+        let proj = &query_data.projections_ordered[i];
+        if let Some(computed) = computed_values.remove(proj) {
+            // computed values keep their type, ie: `CAST($4 AS INT)` is a number
+            fields.push((
+                proj.to_string(),
+                computed.unwrap_or(serde_json::Value::Null),
+            ));
+        } else if projection_values.contains_key(proj) {
+            if let Some(v) = projection_values.remove(proj) {
+                match v {
+                    Some(val) => match val {
+                        PatternValue::RichData(s) => {
+                            fields.push((proj.to_string(), serde_json::Value::String(s)));
+                        }
+                        PatternValue::LineData(ld) => {
+                            fields.push((
+                                proj.to_string(),
+                                serde_json::Value::String(
+                                    line[ld.from as usize..ld.to as usize].to_string(),
+                                ),
+                            ));
+                        }
+                    },
+                    None => {
+                        fields.push((proj.to_string(), serde_json::Value::Null));
+                    }
+                }
+            }
+        } else {
+            fields.push((proj.to_string(), serde_json::Value::Null));
+        }
+    }
     if query_data.read_all {
+        fields.insert(0, ("$line".to_string(), serde_json::Value::String(line)));
         // if we are doing extras
         if query_data.explore_data {
             let extras = build_meta_extras(found_vals);
-            let output_obj = json!({
-            "$line": line,
-            "_meta": extras,
-            });
-            let outstring = serde_json::to_string(&output_obj).unwrap();
-            Some(outstring)
-        } else {
-            let output_obj = json!({
-            "$line": line,
-            });
-            let outstring = serde_json::to_string(&output_obj).unwrap();
-            Some(outstring)
+            fields.push(("_meta".to_string(), json!(extras)));
         }
-    } else {
-        // build the result iterate over the ordered resulting projections
-        let mut mappy: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
-        for i in 0..query_data.projections_ordered.len() {
-            let proj = &query_data.projections_ordered[i];
-            if projection_values.contains_key(proj) {
-                if let Some(v) = projection_values.remove(proj) {
-                    match v {
-                        Some(val) => match val {
-                            PatternValue::RichData(s) => {
-                                mappy.insert(proj.to_string(), serde_json::Value::String(s));
-                            }
-                            PatternValue::LineData(ld) => {
-                                mappy.insert(
-                                    proj.to_string(),
-                                    serde_json::Value::String(
-                                        line[ld.from as usize..ld.to as usize].to_string(),
-                                    ),
-                                );
-                            }
-                        },
-                        None => {
-                            mappy.insert(proj.to_string(), serde_json::Value::Null);
-                        }
-                    }
-                }
-            } else {
-                mappy.insert(proj.to_string(), serde_json::Value::Null);
+    }
+    if let Some(matches) = matches {
+        fields.push(("$matches".to_string(), json!(matches)));
+    }
+
+    let mappy = shape_output(fields, &query_data.output_shape);
+    let outstring = serde_json::to_string(&mappy).unwrap();
+    Some(outstring)
+}
+
+/// Controls how the keys and values of an output line are laid out, set via the
+/// `MINSQL-OUTPUT-*` headers on a search request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputShape {
+    // `$ip` is returned as `ip`
+    pub strip_prefix: bool,
+    // projections without a value are left out instead of returned as `null`
+    pub omit_nulls: bool,
+    // `$user_agent.name` is returned as `{"user_agent": {"name": ...}}`
+    pub nest_subfields: bool,
+    // control characters on values are returned as a visible `\xNN` escape
+    pub escape_control: bool,
+}
+
+/// The `MINSQL-OUTPUT-*` headers requesting an output shape, for searches forwarded to remote
+/// servers
+fn output_shape_headers(shape: &OutputShape) -> Vec<(&'static str, String)> {
+    vec![
+        ("MINSQL-OUTPUT-STRIP-PREFIX", shape.strip_prefix.to_string()),
+        ("MINSQL-OUTPUT-OMIT-NULLS", shape.omit_nulls.to_string()),
+        ("MINSQL-OUTPUT-NESTED", shape.nest_subfields.to_string()),
+        (
+            "MINSQL-OUTPUT-ESCAPE-CONTROL",
+            shape.escape_control.to_string(),
+        ),
+    ]
+}
+
+/// Takes the ordered list of (key, value) of an output line and builds the JSON object that will
+/// be returned to the client according to the requested `OutputShape`.
+fn shape_output(
+    fields: Vec<(String, serde_json::Value)>,
+    shape: &OutputShape,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut mappy: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
+    for (key, value) in fields {
+        if shape.omit_nulls && value.is_null() {
+            continue;
+        }
+        let value = match value {
+            serde_json::Value::String(ref v) if shape.escape_control => {
+                serde_json::Value::String(escape_control_chars(v))
             }
+            v => v,
+        };
+        let key = if shape.strip_prefix {
+            key.trim_start_matches('$').to_string()
+        } else {
+            key
+        };
+        if shape.nest_subfields && key.contains('.') {
+            let parts: Vec<&str> = key.split('.').collect();
+            insert_nested(&mut mappy, &parts, value);
+        } else {
+            mappy.insert(key, value);
+        }
+    }
+    mappy
+}
+
+/// Replaces the control characters of a string, other than tab, with a `\xNN` escape
+fn escape_control_chars(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_control() && c != '\t' {
+            escaped.push_str(&format!("\\x{:02x}", c as u32));
+        } else {
+            escaped.push(c);
         }
+    }
+    escaped
+}
 
-        let outstring = serde_json::to_string(&mappy).unwrap();
-        Some(outstring)
+/// Inserts a value on a nested path of objects, creating the intermediate objects as needed. If
+/// an intermediate key already holds a non-object value it gets replaced.
+fn insert_nested(
+    mappy: &mut serde_json::Map<String, serde_json::Value>,
+    path: &[&str],
+    value: serde_json::Value,
+) {
+    if path.len() == 1 {
+        mappy.insert(path[0].to_string(), value);
+        return;
+    }
+    let entry = mappy
+        .entry(path[0].to_string())
+        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+    if !entry.is_object() {
+        *entry = serde_json::Value::Object(serde_json::Map::new());
+    }
+    if let serde_json::Value::Object(inner) = entry {
+        insert_nested(inner, &path[1..], value);
     }
 }
 
@@ -987,9 +2902,129 @@ pub enum PatternType {
     Quoted,
     Url,
     UserAgent,
+    Kv,
+    Json,
     Unknown,
 }
 
+/// Finds the smart field patterns of the query on a batch of lines, the results are only valid
+/// for that batch
+pub fn scan_lines(query_data: &mut QueryParsing, lines: &Vec<String>) -> HSPatternMatchResults {
+    match query_data.hs_db.take() {
+        Some(mut db) => {
+            let mut ls = HSLineScanner::new(lines);
+            let pattern_match_results = ls.scan(&mut db);
+            // drop ls so the borrow on lines is returned
+            drop(ls);
+            query_data.hs_db = Some(db);
+            pattern_match_results
+        }
+        None => Arc::new(RwLock::new(HashMap::new())),
+    }
+}
+
+/// The output of the lines of a scanned batch matching the query
+pub fn evaluate_lines(
+    query: &Statement,
+    query_data: &QueryParsing,
+    lines: Vec<String>,
+    pattern_match_results: HSPatternMatchResults,
+) -> Vec<String> {
+    let mut times = StageTimes::default();
+    evaluate_lines_timed(query, query_data, lines, pattern_match_results, &mut times)
+}
+
+/// Adds the lines of a scanned batch matching the query to its groups
+pub fn aggregate_lines(
+    query: &Statement,
+    query_data: &QueryParsing,
+    lines: Vec<String>,
+    pattern_match_results: HSPatternMatchResults,
+    groups: &mut GroupTable,
+) -> Result<(), QueryError> {
+    for (line_index, line) in lines.into_iter().enumerate() {
+        let found_vals =
+            found_patterns_in_line(Arc::clone(&pattern_match_results), &line_index, query_data);
+        // redacted values are not grouped by either
+        let (line, projection_values) = line_values(query_data, line, &found_vals);
+        if line_fails_query_conditions(&line, query, &projection_values) {
+            continue;
+        }
+        groups
+            .add(&projection_values, &line, &query_data.timezone)
+            .map_err(QueryError::GroupLimitExceeded)?;
+    }
+    Ok(())
+}
+
+/// The output of the groups of an aggregating query, once every line was added
+pub fn group_rows(query_data: &QueryParsing, groups: &GroupTable) -> Vec<String> {
+    groups
+        .rows()
+        .into_iter()
+        .map(|mut row| {
+            let fields: Vec<(String, serde_json::Value)> = query_data
+                .projections_ordered
+                .iter()
+                .map(|proj| {
+                    let value = row.remove(proj).unwrap_or(serde_json::Value::Null);
+                    (proj.clone(), value)
+                })
+                .collect();
+            serde_json::to_string(&shape_output(fields, &query_data.output_shape)).unwrap()
+        })
+        .collect()
+}
+
+/// Whether each line of a batch matches the conditions of the query, regardless of what it
+/// projects
+pub fn matching_lines(
+    query: &Statement,
+    query_data: &mut QueryParsing,
+    lines: &Vec<String>,
+) -> Vec<bool> {
+    let pattern_match_results = scan_lines(query_data, lines);
+    lines
+        .iter()
+        .enumerate()
+        .map(|(line_index, line)| {
+            let found_vals =
+                found_patterns_in_line(Arc::clone(&pattern_match_results), &line_index, query_data);
+            let (line, projection_values) = line_values(query_data, line.clone(), &found_vals);
+            !line_fails_query_conditions(&line, query, &projection_values)
+        })
+        .collect()
+}
+
+/// `evaluate_lines`, adding the time spent filtering and serializing the lines to `times`
+fn evaluate_lines_timed(
+    query: &Statement,
+    query_data: &QueryParsing,
+    lines: Vec<String>,
+    pattern_match_results: HSPatternMatchResults,
+    times: &mut StageTimes,
+) -> Vec<String> {
+    let started = Instant::now();
+    let mut serialize = Duration::from_secs(0);
+    let output = lines
+        .into_iter()
+        .enumerate()
+        .filter_map(|(line_index, line)| {
+            evaluate_line(
+                query,
+                query_data,
+                line_index,
+                line,
+                Arc::clone(&pattern_match_results),
+                &mut serialize,
+            )
+        })
+        .collect();
+    times.add(Stage::Filter, started.elapsed() - serialize);
+    times.add(Stage::Serialize, serialize);
+    output
+}
+
 fn evaluate_query_on_line(
     query: &Statement,
     query_data: &QueryParsing,
@@ -997,19 +3032,39 @@ fn evaluate_query_on_line(
     line: String,
     pattern_match_results: HSPatternMatchResults,
 ) -> Option<String> {
-    let mut projection_values: HashMap<String, Option<PatternValue>> = HashMap::new();
-    let found_vals =
-        found_patterns_in_line(pattern_match_results, &(line_index as u16), query_data);
+    let mut serialize = Duration::from_secs(0);
+    evaluate_line(
+        query,
+        query_data,
+        line_index,
+        line,
+        pattern_match_results,
+        &mut serialize,
+    )
+}
 
-    extract_positional_fields(&mut projection_values, query_data, &line);
-    extract_smart_fields(&mut projection_values, query_data, &line, &found_vals);
+fn evaluate_line(
+    query: &Statement,
+    query_data: &QueryParsing,
+    line_index: usize,
+    line: String,
+    pattern_match_results: HSPatternMatchResults,
+    serialize: &mut Duration,
+) -> Option<String> {
+    let found_vals = found_patterns_in_line(pattern_match_results, &line_index, query_data);
+    let (line, projection_values) = line_values(query_data, line, &found_vals);
+    let computed_values = evaluate_computed_fields(&projection_values, query_data, &line);
 
     // we can skip the line all together if we gonna project an empty line
     if query_data.read_all == false {
         let mut total_nones = 0;
         for i in 0..query_data.projections_ordered.len() {
             let proj = &query_data.projections_ordered[i];
-            if projection_values.contains_key(proj) {
+            if let Some(computed) = computed_values.get(proj) {
+                if computed.is_none() {
+                    total_nones = total_nones + 1;
+                }
+            } else if projection_values.contains_key(proj) {
                 let val = projection_values.get(proj).unwrap();
                 if val.is_none() {
                     total_nones = total_nones + 1;
@@ -1027,56 +3082,820 @@ fn evaluate_query_on_line(
     // filter the line
     let skip_line = line_fails_query_conditions(&line, &query, &projection_values);
     if !skip_line {
-        make_output(projection_values, query_data, line, found_vals)
+        let started = Instant::now();
+        let matches = if query_data.highlight {
+            Some(matched_spans(&line, &query, &projection_values))
+        } else {
+            None
+        };
+        let output = make_output(
+            projection_values,
+            computed_values,
+            query_data,
+            line,
+            found_vals,
+            matches,
+        );
+        *serialize += started.elapsed();
+        output
     } else {
         None
     }
 }
 
+/// The values of the fields of a query on a line. The redacted smart fields are masked on the
+/// line first, so the conditions see what the output shows and can't tell a redacted value
+/// either.
+fn line_values(
+    query_data: &QueryParsing,
+    line: String,
+    found_vals: &HashMap<String, Vec<Option<HSPatternMatch>>>,
+) -> (String, HashMap<String, Option<PatternValue>>) {
+    let mut projection_values: HashMap<String, Option<PatternValue>> = HashMap::new();
+    extract_positional_fields(&mut projection_values, query_data, &line);
+    extract_smart_fields(&mut projection_values, query_data, &line, found_vals);
+    let line = if query_data.redact.is_empty() {
+        line
+    } else {
+        redact_line(line, &mut projection_values, query_data, found_vals)
+    };
+    (line, projection_values)
+}
+
+/// Masks the values of the redacted smart fields on a line, along with the fields read from
+/// them. Values are masked byte for byte so the offsets of the other fields still hold.
+fn redact_line(
+    line: String,
+    projection_values: &mut HashMap<String, Option<PatternValue>>,
+    query_data: &QueryParsing,
+    found_vals: &HashMap<String, Vec<Option<HSPatternMatch>>>,
+) -> String {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for field in &query_data.redact {
+        for found in found_vals.get(field).into_iter().flatten().flatten() {
+            // spans are widened to whole characters so the line stays valid UTF-8
+            let mut to = (found.to as usize).min(line.len());
+            let mut from = (found.from as usize).min(to);
+            while !line.is_char_boundary(from) {
+                from -= 1;
+            }
+            while !line.is_char_boundary(to) {
+                to += 1;
+            }
+            spans.push((from, to));
+        }
+    }
+    let mut bytes = line.into_bytes();
+    for (from, to) in spans {
+        for byte in &mut bytes[from..to] {
+            *byte = b'*';
+        }
+    }
+    let line = String::from_utf8(bytes).unwrap();
+    // positional fields are copies of parts of the line
+    extract_positional_fields(projection_values, query_data, &line);
+    // `$kv` and `$json` values are read from the line as well, they are read again from the
+    // masked one
+    let mut json_line: Option<Option<serde_json::Value>> = None;
+    for smt in &query_data.smart_fields {
+        let value = match projection_values.get_mut(&smt.alias) {
+            Some(value) if value.is_some() => value,
+            _ => continue,
+        };
+        match (&smt.typed[..], &smt.subfield) {
+            (SF_KV, Some(kv_key)) => *value = kv_value(&line, kv_key).map(PatternValue::RichData),
+            (SF_JSON, Some(path)) => {
+                let parsed = json_line.get_or_insert_with(|| serde_json::from_str(&line).ok());
+                *value = parsed
+                    .as_ref()
+                    .and_then(|document| json_path_value(document, path))
+                    .map(PatternValue::RichData);
+            }
+            (SF_JSON, None) => *value = Some(PatternValue::RichData(line.trim().to_string())),
+            _ => (),
+        }
+    }
+    // `$user_agent` subfields are not read from the line
+    for smt in &query_data.smart_fields {
+        if query_data.redact.contains(&smt.typed) {
+            if let Some(Some(PatternValue::RichData(value))) = projection_values.get_mut(&smt.alias)
+            {
+                *value = "*".repeat(value.chars().count());
+            }
+        }
+    }
+    line
+}
+
 /// This struct represents the reading and filtering parameters that MinSQL uses to filter and
 /// format the returned data.
 #[derive(Debug)]
 pub struct QueryParsing {
     log_name: String,
     read_all: bool,
-    pub scan_flags: constants::ScanFlags,
+    pub scan_flags: ScanFlags,
     positional_fields: Vec<PositionalColumn>,
     smart_fields: Vec<SmartColumn>,
+    computed_fields: Vec<ComputedColumn>,
     projections_ordered: Vec<String>,
     limit: Option<u64>,
-    pub hs_db: Option<BlockDatabase>,
+    // the conditions narrow the query to a time range, see `has_date_predicate`
+    date_predicate: bool,
+    // only the objects written on this range are read, see `time_range`
+    time_range: Option<TimeRange>,
+    // only the objects holding lines received on this range are read, see `received_range`
+    received_range: Option<TimeRange>,
+    // the matching lines are grouped and aggregated instead of returned one by one
+    aggregation: Option<Aggregation>,
+    // the rows are returned sorted on these columns once every line is read
+    order_by: Vec<OrderColumn>,
+    pub hs_db: Option<PatternDb>,
     explore_data: bool,
+    pub output_shape: OutputShape,
+    bloom_literals: Vec<String>,
+    // partition of the log the objects are listed from, all of them if `None`
+    pub partition: Option<String>,
+    // smart fields masked on the output, as the token running the query has them redacted
+    redact: Vec<String>,
+    // offset times are read and bucketed on
+    pub timezone: FixedOffset,
+    // the ranges of `$line` that made each line match are returned with it
+    pub highlight: bool,
+    // cached plan the pattern database is handed back to once the search is done
+    plan: Option<SharedPlan>,
 }
 
+/// A planned statement of a saved search along with the pattern databases compiled for it no
+/// search is using, see `QUERY_PLANS`
 #[derive(Debug)]
-pub enum ProcessingQueryError {
-    Fail(String),
-    UnsupportedQuery(String),
-    NoTableFound(String),
-    Unauthorized(String),
+pub struct QueryPlan {
+    // entity tag of the log's configuration it was planned on
+    version: String,
+    // statement as it was saved, before computed fields were resolved
+    sql: String,
+    explore_data: bool,
+    query: Statement,
+    // without a pattern database
+    parsing: QueryParsing,
+    hs_dbs: Vec<PatternDb>,
 }
 
-struct StateHolder {
-    query_parsing: Vec<(Statement, QueryParsing)>,
-}
+/// A cached plan, locked while a search takes a copy of it or hands its pattern database back
+pub type SharedPlan = Arc<Mutex<QueryPlan>>;
 
-impl StateHolder {
-    fn new() -> StateHolder {
-        StateHolder {
-            query_parsing: Vec::new(),
+impl Drop for QueryParsing {
+    fn drop(&mut self) {
+        if let (Some(plan), Some(db)) = (self.plan.take(), self.hs_db.take()) {
+            plan.lock().unwrap().hs_dbs.push(db);
         }
     }
 }
 
-enum FieldFound {
-    SmartField(SmartColumn),
-    PositionalField(PositionalColumn),
-    Unknown,
-}
+impl QueryParsing {
+    /// Copy of the plan for another search of the statement, without a pattern database
+    fn copy_plan(&self) -> QueryParsing {
+        QueryParsing {
+            log_name: self.log_name.clone(),
+            read_all: self.read_all,
+            scan_flags: self.scan_flags.clone(),
+            positional_fields: self.positional_fields.clone(),
+            smart_fields: self.smart_fields.clone(),
+            computed_fields: self.computed_fields.clone(),
+            projections_ordered: self.projections_ordered.clone(),
+            limit: self.limit,
+            date_predicate: self.date_predicate,
+            time_range: self.time_range,
+            received_range: self.received_range,
+            aggregation: self.aggregation.clone(),
+            order_by: self.order_by.clone(),
+            hs_db: None,
+            explore_data: self.explore_data,
+            output_shape: self.output_shape.clone(),
+            bloom_literals: self.bloom_literals.clone(),
+            partition: self.partition.clone(),
+            redact: self.redact.clone(),
+            timezone: self.timezone,
+            highlight: self.highlight,
+            plan: None,
+        }
+    }
 
-fn detect_field_for_ast(ast: &Expr) -> FieldFound {
-    match ast {
-        Expr::Identifier(ref identifier) => {
+    /// Masks the values of the `fields` smart fields on the output, scanning the lines for them
+    /// even if the query doesn't read them so they are masked on `$line` too
+    fn redact_fields(&mut self, fields: Vec<String>, patterns: &HashMap<String, SmartPattern>) {
+        let mut scan_flags = self.scan_flags.clone();
+        for field in &fields {
+            scan_flags.union(&ScanFlags::for_field(field));
+        }
+        if scan_flags != self.scan_flags {
+            // the database of the cached plan doesn't scan for them, it's handed back right away
+            if let (Some(plan), Some(db)) = (self.plan.take(), self.hs_db.take()) {
+                plan.lock().unwrap().hs_dbs.push(db);
+            }
+            self.scan_flags = scan_flags;
+            self.hs_db = build_hs_db(&self.scan_flags, patterns);
+        }
+        self.redact = fields;
+    }
+
+    pub fn log_name(&self) -> &str {
+        &self.log_name
+    }
+
+    /// Reads the times of `query` on `timezone`, the ones `$time` and `$received` are compared
+    /// with included
+    pub fn set_timezone(&mut self, query: &Statement, timezone: FixedOffset) -> Result<(), String> {
+        if let Statement::Query(ref q) = query {
+            if let SetExpr::Select(ref bodyselect) = q.body {
+                if let Some(ref selection) = bodyselect.selection {
+                    self.time_range = time_range(selection, &timezone)?;
+                    self.received_range = received_range(selection, &timezone)?;
+                }
+            }
+        }
+        self.timezone = timezone;
+        Ok(())
+    }
+
+    /// Rows the query returns at most, `None` if it has no `LIMIT`
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// A sorter for the rows of the query keeping the first `keep` of them, `None` unless it has
+    /// an `ORDER BY`
+    pub fn row_sorter(&self, keep: Option<usize>) -> Option<RowSorter> {
+        if self.order_by.is_empty() {
+            return None;
+        }
+        Some(RowSorter::new(&self.order_by, &self.output_shape, keep))
+    }
+
+    /// A table for the groups of the query, `None` unless it aggregates its lines
+    pub fn group_table(&self) -> Option<GroupTable> {
+        self.aggregation
+            .as_ref()
+            .map(|aggregation| GroupTable::new(aggregation.clone(), AGGREGATE_MAX_GROUPS))
+    }
+}
+
+/// A column the rows of a query are sorted on, by the key it's returned as
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderColumn {
+    alias: String,
+    descending: bool,
+}
+
+/// Sorts the rows of a query on its `ORDER BY` columns. When only the first `keep` rows are
+/// returned, ie: the query has a `LIMIT`, just those are held instead of every row.
+pub struct RowSorter {
+    // path to the value of each column on the rows, as laid out by the output shape
+    columns: Vec<(Vec<String>, bool)>,
+    keep: Option<usize>,
+    rows: Vec<(Vec<serde_json::Value>, String)>,
+}
+
+impl RowSorter {
+    pub fn new(order_by: &[OrderColumn], shape: &OutputShape, keep: Option<usize>) -> RowSorter {
+        RowSorter {
+            columns: order_by
+                .iter()
+                .map(|column| (output_path(&column.alias, shape), column.descending))
+                .collect(),
+            keep,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Adds a batch of rows, failing if holding them all would go over `ORDER_BY_MAX_ROWS`
+    pub fn add(&mut self, rows: Vec<String>) -> Result<(), QueryError> {
+        for row in rows.into_iter().filter(|row| !row.is_empty()) {
+            let parsed: serde_json::Value =
+                serde_json::from_str(&row).unwrap_or(serde_json::Value::Null);
+            let key = self
+                .columns
+                .iter()
+                .map(|(path, _)| {
+                    path.iter()
+                        .fold(Some(&parsed), |value, key| value.and_then(|v| v.get(key)))
+                        .cloned()
+                        .unwrap_or(serde_json::Value::Null)
+                })
+                .collect();
+            self.rows.push((key, row));
+        }
+        match self.keep {
+            // the rows past the first `keep` are dropped every so often, so at most twice as
+            // many are held
+            Some(keep) if self.rows.len() >= cmp::max(keep, 1) * 2 => self.sort_and_truncate(),
+            None if self.rows.len() > ORDER_BY_MAX_ROWS => {
+                return Err(QueryError::SortLimitExceeded(ORDER_BY_MAX_ROWS));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// The rows sorted, rows that compare equal keep the order they were added in
+    pub fn into_rows(mut self) -> Vec<String> {
+        self.sort_and_truncate();
+        self.rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    fn sort_and_truncate(&mut self) {
+        let columns = &self.columns;
+        self.rows.sort_by(|(a, _), (b, _)| {
+            for ((a, b), (_, descending)) in a.iter().zip(b).zip(columns) {
+                let ordering = compare_sort_values(a, b, *descending);
+                if ordering != cmp::Ordering::Equal {
+                    return ordering;
+                }
+            }
+            cmp::Ordering::Equal
+        });
+        if let Some(keep) = self.keep {
+            self.rows.truncate(keep);
+        }
+    }
+}
+
+/// Compares the values of a sort column, rows without a value go last in either direction
+fn compare_sort_values(
+    a: &serde_json::Value,
+    b: &serde_json::Value,
+    descending: bool,
+) -> cmp::Ordering {
+    match (a.is_null(), b.is_null()) {
+        (true, true) => cmp::Ordering::Equal,
+        (true, false) => cmp::Ordering::Greater,
+        (false, true) => cmp::Ordering::Less,
+        (false, false) if descending => compare_values(b, a),
+        (false, false) => compare_values(a, b),
+    }
+}
+
+/// Path to the value of an output column on a row laid out by `shape_output`
+fn output_path(alias: &str, shape: &OutputShape) -> Vec<String> {
+    let key = if shape.strip_prefix {
+        alias.trim_start_matches('$')
+    } else {
+        alias
+    };
+    if shape.nest_subfields && key.contains('.') {
+        key.split('.').map(|part| part.to_string()).collect()
+    } else {
+        vec![key.to_string()]
+    }
+}
+
+/// Format the rows of a search are returned in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    // a JSON object per row, as the rows come
+    JsonLines,
+    // a JSON object per row, served as `application/x-ndjson`
+    Ndjson,
+    // a header row with the projections and a row per result
+    Csv,
+}
+
+impl OutputFormat {
+    fn content_type(self) -> Option<&'static str> {
+        match self {
+            OutputFormat::JsonLines => None,
+            OutputFormat::Ndjson => Some(APP_NDJSON),
+            OutputFormat::Csv => Some(TEXT_CSV),
+        }
+    }
+}
+
+/// The output format asked for with the `format` query parameter, `json`, `ndjson` or `csv`,
+/// else with the `Accept` header. Media types other than CSV and NDJSON get JSON lines.
+fn output_format(req: &Request<Body>) -> Result<OutputFormat, String> {
+    if let Some(query) = req.uri().query() {
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            if key == "format" {
+                return match &value.to_lowercase()[..] {
+                    "json" => Ok(OutputFormat::JsonLines),
+                    "ndjson" => Ok(OutputFormat::Ndjson),
+                    "csv" => Ok(OutputFormat::Csv),
+                    _ => Err("format must be `json`, `ndjson` or `csv`".to_string()),
+                };
+            }
+        }
+    }
+    let accept = match req.headers().get(header::ACCEPT) {
+        Some(value) => value.to_str().unwrap_or("").to_lowercase(),
+        None => return Ok(OutputFormat::JsonLines),
+    };
+    for media_type in accept.split(',') {
+        match media_type.split(';').next().unwrap_or("").trim() {
+            TEXT_CSV => return Ok(OutputFormat::Csv),
+            APP_NDJSON => return Ok(OutputFormat::Ndjson),
+            APP_JSON | "*/*" => return Ok(OutputFormat::JsonLines),
+            _ => (),
+        }
+    }
+    Ok(OutputFormat::JsonLines)
+}
+
+/// The columns of a CSV output, named as the keys of the JSON rows, along with the path of
+/// their value on those rows
+fn csv_columns(query_data: &QueryParsing) -> Vec<(String, Vec<String>)> {
+    let mut aliases: Vec<&str> = Vec::new();
+    if query_data.read_all {
+        aliases.push("$line");
+    }
+    aliases.extend(query_data.projections_ordered.iter().map(|p| p.as_str()));
+    if query_data.read_all && query_data.explore_data {
+        aliases.push("_meta");
+    }
+    if query_data.highlight {
+        aliases.push("$matches");
+    }
+    aliases
+        .into_iter()
+        .map(|alias| {
+            let path = output_path(alias, &query_data.output_shape);
+            (path.join("."), path)
+        })
+        .collect()
+}
+
+/// The output columns of a query along with the type of their values, in the order they are
+/// returned and named as the keys of the rows
+fn column_types(query_data: &QueryParsing) -> Vec<(String, ColumnType)> {
+    let mut fields: HashMap<&str, ColumnType> = HashMap::new();
+    for positional in &query_data.positional_fields {
+        fields.insert(&positional.alias, ColumnType::String);
+    }
+    for smart in &query_data.smart_fields {
+        fields.insert(&smart.alias, smart_field_type(smart));
+    }
+    let field_type = |key: &str| fields.get(key).cloned().unwrap_or(ColumnType::String);
+    let mut types: HashMap<&str, ColumnType> = HashMap::new();
+    for computed in &query_data.computed_fields {
+        types.insert(&computed.alias, computed.expr.column_type(&field_type));
+    }
+    if let Some(aggregation) = &query_data.aggregation {
+        for aggregate in &aggregation.aggregates {
+            types.insert(&aggregate.alias, aggregate.column_type(&field_type));
+        }
+    }
+    let mut columns: Vec<(&str, ColumnType)> = Vec::new();
+    if query_data.read_all {
+        columns.push(("$line", ColumnType::String));
+    }
+    for alias in &query_data.projections_ordered {
+        let column_type = match types.get(alias.as_str()) {
+            Some(column_type) => *column_type,
+            None => field_type(alias),
+        };
+        columns.push((alias.as_str(), column_type));
+    }
+    columns
+        .into_iter()
+        .map(|(alias, column_type)| {
+            let name = output_path(alias, &query_data.output_shape).join(".");
+            (name, column_type)
+        })
+        .collect()
+}
+
+/// Type of the values of a smart field, its subfields are text
+fn smart_field_type(smart: &SmartColumn) -> ColumnType {
+    match (smart.typed.as_str(), &smart.subfield) {
+        (SF_IP, None) => ColumnType::Ip,
+        (SF_DATE, None) => ColumnType::Timestamp,
+        _ => ColumnType::String,
+    }
+}
+
+/// The line sent ahead of the results with the columns of a query and their types
+fn columns_line(query_data: &QueryParsing) -> String {
+    let columns: Vec<serde_json::Value> = column_types(query_data)
+        .into_iter()
+        .map(|(name, column_type)| json!({ "name": name, "type": column_type }))
+        .collect();
+    json!({ "$columns": columns }).to_string() + "\n"
+}
+
+fn csv_header_row(columns: &[(String, Vec<String>)]) -> String {
+    let fields: Vec<String> = columns.iter().map(|(name, _)| csv_field(name)).collect();
+    fields.join(",") + "\r\n"
+}
+
+/// Lays out a JSON row on the columns of a CSV output. Missing values and nulls are left
+/// empty, objects and arrays are written as JSON.
+fn csv_row(row: &str, columns: &[(String, Vec<String>)]) -> String {
+    let row: serde_json::Value = serde_json::from_str(row).unwrap_or(serde_json::Value::Null);
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|(_, path)| {
+            let value = path.iter().fold(Some(&row), |value, key| value?.get(key));
+            match value {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(s)) => csv_field(s),
+                Some(value) => csv_field(&value.to_string()),
+            }
+        })
+        .collect();
+    fields.join(",") + "\r\n"
+}
+
+/// Quotes a CSV field holding a separator, a quote or a line break, doubling its quotes
+fn csv_field(value: &str) -> String {
+    if value.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Returns the rows of `rows` sorted once it ends, or as they come if there is no sorter
+pub fn sorted_rows<S>(
+    rows: S,
+    sorter: Option<RowSorter>,
+) -> impl Stream<Item = Vec<String>, Error = QueryError>
+where
+    S: Stream<Item = Vec<String>, Error = QueryError>,
+{
+    match sorter {
+        Some(sorter) => Either::A(
+            rows.fold(sorter, |mut sorter, rows| sorter.add(rows).map(|_| sorter))
+                .map(|sorter| sorter.into_rows())
+                .into_stream(),
+        ),
+        None => Either::B(rows),
+    }
+}
+
+/// What a query would read, see `Query::estimate`
+#[derive(Serialize, Debug)]
+pub struct QueryEstimate {
+    objects: u64,
+    bytes: u64,
+    // the log skips the objects that can't match via their bloom filters, so fewer may be read
+    bloom_filtered: bool,
+    // queries without a `LIMIT` read every object, the others may stop earlier
+    full_scan: bool,
+    estimated_ms: u64,
+}
+
+/// Time to read the objects of a datastore, fetching `in_flight` of them at once
+fn estimate_read_ms(objects: u64, bytes: u64, latency_ms: u64, in_flight: u64) -> u64 {
+    objects * latency_ms / in_flight.max(1) + bytes * 1000 / ESTIMATE_SCAN_BYTES_PER_SEC
+}
+
+#[derive(Debug)]
+pub enum ProcessingQueryError {
+    Fail(String),
+    UnsupportedQuery(String),
+    NoTableFound(String),
+    UnknownLog(String),
+    Forbidden(String),
+}
+
+/// Accounts the bytes of the lines a query has read from its datastores but not scanned yet,
+/// which pile up when the datastores are read faster than the client takes the results.
+struct QueryMemory {
+    limit: usize,
+    buffered: AtomicUsize,
+}
+
+impl QueryMemory {
+    fn new(limit: usize) -> QueryMemory {
+        QueryMemory {
+            limit,
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    /// Accounts `bytes` more, returns `false` without accounting them if that goes over the limit
+    fn reserve(&self, bytes: usize) -> bool {
+        let buffered = self.buffered.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if buffered > self.limit {
+            self.buffered.fetch_sub(bytes, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    fn release(&self, bytes: usize) {
+        self.buffered.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+/// Snapshot of the lines still in the ingest buffer of a log. The buffer and the datastores are
+/// not read atomically, so lines flushed meanwhile may be missed or read twice.
+fn buffered_lines(
+    log_ingest_buffers: &IngestBuffers,
+    log_name: &str,
+    partition: Option<&str>,
+) -> Vec<String> {
+    match log_ingest_buffers.get(log_name) {
+        Some(buffer) => buffer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .buffered_lines(partition),
+        None => Vec::new(),
+    }
+}
+
+/// Unwraps lines wrapped in base64 so they are scanned as they were sent, bytes that aren't
+/// valid UTF-8 are replaced. Only lines known to be wrapped are passed, a line that doesn't
+/// decode anyway is kept as it is rather than dropped.
+pub fn decode_lines(lines: Vec<String>) -> Vec<String> {
+    lines
+        .into_iter()
+        .map(|line| match base64::decode(&line) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(_) => line,
+        })
+        .collect()
+}
+
+fn lines_size(lines: &[String]) -> usize {
+    lines.iter().map(|line| line.len()).sum()
+}
+
+/// A batch of rows written on the output format of the search
+fn format_rows(
+    rows: &[String],
+    format: OutputFormat,
+    csv_columns: &[(String, Vec<String>)],
+) -> String {
+    match format {
+        OutputFormat::JsonLines => rows.join("\n") + &"\n",
+        OutputFormat::Ndjson => rows
+            .iter()
+            .filter(|row| !row.is_empty())
+            .map(|row| format!("{}\n", row))
+            .collect(),
+        OutputFormat::Csv => rows
+            .iter()
+            .filter(|row| !row.is_empty())
+            .map(|row| csv_row(row, csv_columns))
+            .collect(),
+    }
+}
+
+/// Ends the results of a search on the first error, which is sent as the last line of the
+/// response since the status was sent with the first results.
+fn end_on_error<S>(results: S) -> impl Stream<Item = Chunk, Error = QueryError>
+where
+    S: Stream<Item = Chunk, Error = QueryError>,
+{
+    let failed = AtomicBool::new(false);
+    results
+        .then(|res| match res {
+            Ok(chunk) => Ok::<_, QueryError>((chunk, false)),
+            Err(e) => {
+                error!("Query failed: {}", e);
+                let line = json!({ "error": e.to_string() }).to_string() + "\n";
+                Ok((Chunk::from(line), true))
+            }
+        })
+        .take_while(move |(_, is_error)| Ok(!failed.swap(*is_error, Ordering::SeqCst)))
+        .map(|(chunk, _)| chunk)
+}
+
+/// The line sent after the results of a search cut by the unbounded query limits
+fn stats_trailer(budget: &OutputBudget) -> String {
+    json!({ "$stats": {
+        "truncated": true,
+        "max_rows": budget.max_rows,
+        "max_bytes": budget.max_bytes,
+    }})
+    .to_string()
+        + "\n"
+}
+
+/// Lines read for a query along with where they were read from, a datastore or the buffered lines
+type LinesBatch = (String, Vec<String>);
+
+struct StateHolder {
+    query_parsing: Vec<(Statement, QueryParsing)>,
+    progress: Arc<QueryProgress>,
+    // stage timings of a search sent with `MINSQL-PROFILE: true`
+    profile: Option<Arc<QueryProfile>>,
+    // entry of the search on the registry of running queries
+    running: Option<Arc<QueryHandle>>,
+}
+
+impl StateHolder {
+    fn new() -> StateHolder {
+        StateHolder {
+            query_parsing: Vec::new(),
+            progress: Arc::new(QueryProgress::default()),
+            profile: None,
+            running: None,
+        }
+    }
+}
+
+/// What a count only search matched and scanned, sent on the headers once it's over
+struct SearchCountStats {
+    started: Instant,
+    matched_lines: Arc<AtomicUsize>,
+    progress: Arc<QueryProgress>,
+    // the error that ended the search early, if any
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl SearchCountStats {
+    fn response(&self) -> Response<Body> {
+        if let Some(e) = self.failure.lock().unwrap().take() {
+            return return_500(&e);
+        }
+        Response::builder()
+            .header(
+                HEADER_MATCHED_LINES,
+                self.matched_lines.load(Ordering::SeqCst).to_string(),
+            )
+            .header(
+                HEADER_OBJECTS_SCANNED,
+                self.progress
+                    .objects_scanned
+                    .load(Ordering::SeqCst)
+                    .to_string(),
+            )
+            .header(
+                HEADER_OBJECTS_TOTAL,
+                self.progress
+                    .objects_total
+                    .load(Ordering::SeqCst)
+                    .to_string(),
+            )
+            .header(
+                HEADER_ELAPSED_MS,
+                (self.started.elapsed().as_millis() as u64).to_string(),
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+/// Objects listed and scanned so far by the queries of a search. The total grows while the
+/// datastores are being listed.
+#[derive(Default)]
+struct QueryProgress {
+    objects_total: AtomicUsize,
+    objects_scanned: AtomicUsize,
+    // objects scanned on the last event sent
+    reported: AtomicUsize,
+}
+
+impl QueryProgress {
+    fn object_listed(&self) {
+        self.objects_total.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn object_scanned(&self) {
+        self.objects_scanned.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Progress event line, `None` if no objects were scanned since the last one
+    fn event(&self) -> Option<String> {
+        let scanned = self.objects_scanned.load(Ordering::SeqCst);
+        if self.reported.swap(scanned, Ordering::SeqCst) == scanned {
+            return None;
+        }
+        Some(self.event_line(scanned, false))
+    }
+
+    /// Progress event line sent once the search is over
+    fn final_event(&self) -> String {
+        self.event_line(self.objects_scanned.load(Ordering::SeqCst), true)
+    }
+
+    fn event_line(&self, scanned: usize, done: bool) -> String {
+        let total = self.objects_total.load(Ordering::SeqCst);
+        let percent = match total {
+            0 if done => 100,
+            0 => 0,
+            total => scanned * 100 / total,
+        };
+        json!({"$progress": {
+            "objects_scanned": scanned,
+            "objects_total": total,
+            "percent": percent,
+            "done": done,
+        }})
+        .to_string()
+            + "\n"
+    }
+}
+
+enum FieldFound {
+    SmartField(SmartColumn),
+    PositionalField(PositionalColumn),
+    Unknown,
+}
+
+fn detect_field_for_ast(ast: &Expr) -> FieldFound {
+    match ast {
+        Expr::Identifier(ref identifier) => {
             let id_name = &identifier[1..];
             let position = id_name.parse::<i32>().unwrap_or(-1);
             // if we were able to parse identifier as an i32 it's a positional
@@ -1163,7 +3982,7 @@ fn build_meta_extras(
 
 #[cfg(test)]
 mod query_tests {
-    use crate::config::{Config, Log, LogAuth, Server, Token};
+    use crate::config::{Config, Log, LogAuth, RemoteLog, Server, SmartPattern, Token};
 
     use super::*;
 
@@ -1179,6 +3998,7 @@ mod query_tests {
                 name: Some(log_name.clone()),
                 datastores: Vec::new(),
                 commit_window: "5s".to_string(),
+                ..Default::default()
             },
         );
 
@@ -1206,23 +4026,21 @@ mod query_tests {
                 is_admin: false,
                 enabled: true,
                 api_access: false,
+                ip_allowlist: Vec::new(),
+                redact: Vec::new(),
             },
         );
 
         let cfg = Config {
             server: Server {
-                address: "".to_string(),
-                metadata_endpoint: "".to_string(),
-                metadata_bucket: "".to_string(),
-                access_key: "".to_string(),
-                secret_key: "".to_string(),
-                pkcs12_cert: None,
-                pkcs12_password: None,
+                query_memory_limit: 1024,
+                ..Server::default()
             },
             datastore: HashMap::new(),
             tokens: tokens,
             log: log_map,
             auth: auth,
+            patterns: HashMap::new(),
         };
         cfg
     }
@@ -1390,11 +4208,9 @@ mod query_tests {
                     vec!["$ip".to_string(), "$email".to_string()],
                     "Order of fields is incorrect"
                 );
-                assert_eq!(
-                    mqp.scan_flags,
-                    constants::ScanFlags::IP | constants::ScanFlags::EMAIL,
-                    "Scan flags don't match"
-                );
+                let mut scan_flags = ScanFlags::for_field("$ip");
+                scan_flags.union(&ScanFlags::for_field("$email"));
+                assert_eq!(mqp.scan_flags, scan_flags, "Scan flags don't match");
                 match mqp.limit {
                     Some(l) => assert_eq!(l, 10),
                     None => panic!("NO LIMIT FOUND"),
@@ -1459,89 +4275,241 @@ mod query_tests {
     }
 
     #[test]
-    #[should_panic]
-    fn process_invalid_query() {
+    fn process_aliased_fields_select() {
         let access_token = VALID_TOKEN.to_string();
 
         let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
         let cfg = Arc::new(RwLock::new(cfg));
         let query_c = Query::new(cfg);
 
-        let query = "INSERT INTO mylog ($line) VALES ('line')".to_string();
-        if let Err(_) = query_c.parse_query(query.clone()) {
-            panic!("Expected invalid query");
+        let query = "SELECT $ip AS client_ip, $4 AS ts FROM mylog".to_string();
+        let ast = query_c.parse_query(query.clone()).unwrap();
+        let queries_parse = query_c.process_sql(&access_token, ast, false);
+
+        match queries_parse {
+            Ok(pq) => {
+                let mqp = &pq[0].1;
+                assert_eq!(
+                    mqp.smart_fields,
+                    vec![SmartColumn {
+                        typed: "$ip".to_string(),
+                        position: 1,
+                        alias: "client_ip".to_string(),
+                        subfield: None,
+                    }]
+                );
+                assert_eq!(
+                    mqp.positional_fields,
+                    vec![PositionalColumn {
+                        position: 4,
+                        alias: "ts".to_string(),
+                    }]
+                );
+                assert_eq!(
+                    mqp.projections_ordered,
+                    vec!["client_ip".to_string(), "ts".to_string()],
+                    "Order of fields is incorrect"
+                );
+            }
+            e => panic!("error parsing query: {:?}", e),
         }
     }
 
     #[test]
-    fn process_simple_select_invalid_access() {
-        let provided_access_token = VALID_TOKEN2.to_string();
+    fn column_types_of_projections() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let types_of = |sql: &str| {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            let pq = query_c.process_sql(&access_token, ast, false).unwrap();
+            column_types(&pq[0].1)
+        };
+        let columns = |expected: &[(&str, ColumnType)]| -> Vec<(String, ColumnType)> {
+            expected
+                .iter()
+                .map(|(name, column_type)| (name.to_string(), *column_type))
+                .collect()
+        };
+
+        assert_eq!(
+            types_of(
+                "SELECT $ip AS client_ip, $4, $date, $user_agent.name, CAST($5 AS INT) AS bytes \
+                 FROM mylog"
+            ),
+            columns(&[
+                ("client_ip", ColumnType::Ip),
+                ("$4", ColumnType::String),
+                ("$date", ColumnType::Timestamp),
+                ("$user_agent.name", ColumnType::String),
+                ("bytes", ColumnType::Int),
+            ])
+        );
+        assert_eq!(
+            types_of("SELECT $ip, COUNT(*) AS hits, MAX($date) FROM mylog GROUP BY $ip"),
+            columns(&[
+                ("$ip", ColumnType::Ip),
+                ("hits", ColumnType::Int),
+                ("MAX($date)", ColumnType::Timestamp),
+            ])
+        );
+        assert_eq!(
+            types_of("SELECT * FROM mylog"),
+            columns(&[("$line", ColumnType::String)])
+        );
+    }
+
+    #[test]
+    fn process_wildcard_and_projections_select() {
         let access_token = VALID_TOKEN.to_string();
 
         let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
         let cfg = Arc::new(RwLock::new(cfg));
         let query_c = Query::new(cfg);
 
-        let query = "SELECT * FROM mylog".to_string();
+        let query = "SELECT *, $ip FROM mylog".to_string();
         let ast = query_c.parse_query(query.clone()).unwrap();
-        let queries_parse = query_c.process_sql(&provided_access_token, ast, false);
+        let queries_parse = query_c.process_sql(&access_token, ast, false);
 
         match queries_parse {
             Ok(pq) => {
                 let mqp = &pq[0].1;
-                assert_eq!(mqp.log_name, "mylog");
                 assert_eq!(mqp.read_all, true);
+                assert_eq!(mqp.projections_ordered, vec!["$ip".to_string()]);
+                assert_eq!(mqp.scan_flags, ScanFlags::for_field("$ip"));
             }
-            Err(e) => match e {
-                ProcessingQueryError::Unauthorized(_) => assert!(true),
-                _ => panic!("Incorrect error"),
-            },
+            e => panic!("error parsing query: {:?}", e),
         }
     }
 
     #[test]
-    fn process_simple_select_invalid_table() {
-        let provided_access_token = VALID_TOKEN2.to_string();
+    fn process_hierarchical_log_select() {
         let access_token = VALID_TOKEN.to_string();
 
-        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let cfg = get_ds_log_auth_config_for("team/service".to_string(), &access_token);
         let cfg = Arc::new(RwLock::new(cfg));
         let query_c = Query::new(cfg);
 
-        let query = "SELECT * FROM incorrect_log".to_string();
+        let query = "SELECT * FROM \"team/service\"".to_string();
         let ast = query_c.parse_query(query.clone()).unwrap();
-        let queries_parse = query_c.process_sql(&provided_access_token, ast, false);
+        assert!(query_c.validate_logs(&ast).is_none());
+        let queries_parse = query_c.process_sql(&access_token, ast, false);
 
         match queries_parse {
-            Ok(pq) => {
-                let mqp = &pq[0].1;
-                assert_eq!(mqp.log_name, "mylog");
-                assert_eq!(mqp.read_all, true);
-            }
-            Err(e) => match e {
-                ProcessingQueryError::Unauthorized(_) => assert!(true),
-                _ => panic!("Incorrect error"),
-            },
+            Ok(pq) => assert_eq!(pq[0].1.log_name, "team/service"),
+            e => panic!("error parsing query: {:?}", e),
         }
     }
 
     #[test]
-    fn validate_invalid_table() {
+    fn process_aliased_log_select() {
         let access_token = VALID_TOKEN.to_string();
 
-        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let mut cfg = get_ds_log_auth_config_for("team/service".to_string(), &access_token);
+        cfg.log.get_mut("team/service").unwrap().aliases = vec!["service".to_string()];
         let cfg = Arc::new(RwLock::new(cfg));
         let query_c = Query::new(cfg);
 
-        let query = "SELECT * FROM incorrect_log".to_string();
+        let query = "SELECT * FROM service".to_string();
         let ast = query_c.parse_query(query.clone()).unwrap();
-        match query_c.validate_logs(&ast) {
-            None => panic!("Should have reported an error"),
-            Some(_) => assert!(true),
-        }
-    }
+        assert!(query_c.validate_logs(&ast).is_none());
+        let queries_parse = query_c.process_sql(&access_token, ast, false);
 
-    struct ParseMatchTestCase {
+        match queries_parse {
+            Ok(pq) => assert_eq!(pq[0].1.log_name, "team/service"),
+            e => panic!("error parsing query: {:?}", e),
+        }
+
+        let ast = query_c
+            .parse_query("SELECT * FROM other".to_string())
+            .unwrap();
+        assert!(query_c.validate_logs(&ast).is_some());
+    }
+
+    #[test]
+    #[should_panic]
+    fn process_invalid_query() {
+        let access_token = VALID_TOKEN.to_string();
+
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let cfg = Arc::new(RwLock::new(cfg));
+        let query_c = Query::new(cfg);
+
+        let query = "INSERT INTO mylog ($line) VALES ('line')".to_string();
+        if let Err(_) = query_c.parse_query(query.clone()) {
+            panic!("Expected invalid query");
+        }
+    }
+
+    #[test]
+    fn process_simple_select_invalid_access() {
+        let provided_access_token = VALID_TOKEN2.to_string();
+        let access_token = VALID_TOKEN.to_string();
+
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let cfg = Arc::new(RwLock::new(cfg));
+        let query_c = Query::new(cfg);
+
+        let query = "SELECT * FROM mylog".to_string();
+        let ast = query_c.parse_query(query.clone()).unwrap();
+        let queries_parse = query_c.process_sql(&provided_access_token, ast, false);
+
+        match queries_parse {
+            Ok(pq) => {
+                let mqp = &pq[0].1;
+                assert_eq!(mqp.log_name, "mylog");
+                assert_eq!(mqp.read_all, true);
+            }
+            Err(e) => match e {
+                ProcessingQueryError::Forbidden(_) => assert!(true),
+                _ => panic!("Incorrect error"),
+            },
+        }
+    }
+
+    #[test]
+    fn process_simple_select_invalid_table() {
+        let provided_access_token = VALID_TOKEN2.to_string();
+        let access_token = VALID_TOKEN.to_string();
+
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let cfg = Arc::new(RwLock::new(cfg));
+        let query_c = Query::new(cfg);
+
+        let query = "SELECT * FROM incorrect_log".to_string();
+        let ast = query_c.parse_query(query.clone()).unwrap();
+        let queries_parse = query_c.process_sql(&provided_access_token, ast, false);
+
+        match queries_parse {
+            Ok(pq) => {
+                let mqp = &pq[0].1;
+                assert_eq!(mqp.log_name, "mylog");
+                assert_eq!(mqp.read_all, true);
+            }
+            Err(e) => match e {
+                ProcessingQueryError::UnknownLog(_) => assert!(true),
+                _ => panic!("Incorrect error"),
+            },
+        }
+    }
+
+    #[test]
+    fn validate_invalid_table() {
+        let access_token = VALID_TOKEN.to_string();
+
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let cfg = Arc::new(RwLock::new(cfg));
+        let query_c = Query::new(cfg);
+
+        let query = "SELECT * FROM incorrect_log".to_string();
+        let ast = query_c.parse_query(query.clone()).unwrap();
+        match query_c.validate_logs(&ast) {
+            None => panic!("Should have reported an error"),
+            Some(e) => assert!(e.downcast_ref::<UnknownLogError>().is_some()),
+        }
+    }
+
+    struct ParseMatchTestCase {
         log_name: String,
         query: String,
         log_line: String,
@@ -1602,6 +4570,53 @@ mod query_tests {
      };
     );
 
+    #[test]
+    fn shape_output_strip_prefix_and_omit_nulls() {
+        let fields = vec![
+            ("$ip".to_string(), json!("10.0.0.1")),
+            ("$email".to_string(), serde_json::Value::Null),
+        ];
+        let shape = OutputShape {
+            strip_prefix: true,
+            omit_nulls: true,
+            nest_subfields: false,
+            escape_control: false,
+        };
+        let output = serde_json::Value::Object(shape_output(fields, &shape));
+        assert_eq!(output, json!({"ip": "10.0.0.1"}));
+    }
+
+    #[test]
+    fn shape_output_nested_subfields() {
+        let fields = vec![
+            ("$user_agent.name".to_string(), json!("Chrome")),
+            ("$user_agent.os".to_string(), json!("Windows 10")),
+            ("$ip".to_string(), serde_json::Value::Null),
+        ];
+        let shape = OutputShape {
+            strip_prefix: true,
+            omit_nulls: false,
+            nest_subfields: true,
+            escape_control: false,
+        };
+        let output = serde_json::Value::Object(shape_output(fields, &shape));
+        assert_eq!(
+            output,
+            json!({"user_agent": {"name": "Chrome", "os": "Windows 10"}, "ip": null})
+        );
+    }
+
+    #[test]
+    fn shape_output_escape_control() {
+        let fields = vec![("$line".to_string(), json!("a\u{1b}[31mb\tc"))];
+        let shape = OutputShape {
+            escape_control: true,
+            ..Default::default()
+        };
+        let output = serde_json::Value::Object(shape_output(fields, &shape));
+        assert_eq!(output, json!({"$line": "a\\x1b[31mb\tc"}));
+    }
+
     #[test]
     fn sf_phone_parse_and_match() {
         let tc = ParseMatchTestCase {
@@ -1634,4 +4649,868 @@ mod query_tests {
         };
         run_parse_and_match_case(tc);
     }
+
+    #[test]
+    fn sf_alias_parse_and_match() {
+        let tc = ParseMatchTestCase {
+            log_name: "mylog".to_string(),
+            query: "SELECT $email AS contact FROM mylog".to_string(),
+            log_line: "xx valid@emaildomain.com xx".to_string(),
+            expected: map! {"contact".to_string() =>"valid@emaildomain.com".to_string()},
+        };
+        run_parse_and_match_case(tc);
+    }
+
+    #[test]
+    fn wildcard_and_sf_parse_and_match() {
+        let tc = ParseMatchTestCase {
+            log_name: "mylog".to_string(),
+            query: "SELECT *, $email FROM mylog".to_string(),
+            log_line: "xx valid@emaildomain.com xx".to_string(),
+            expected: map! {
+                "$line".to_string() => "xx valid@emaildomain.com xx".to_string(),
+                "$email".to_string() =>"valid@emaildomain.com".to_string()
+            },
+        };
+        run_parse_and_match_case(tc);
+    }
+
+    #[test]
+    fn sf_pattern_override_parse_and_match() {
+        let access_token = VALID_TOKEN.to_string();
+
+        let mut cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        // phones separated by dots instead of the built-in format
+        cfg.patterns.insert(
+            "phone".to_string(),
+            SmartPattern {
+                expression: "(\\d{3})\\.(\\d{3})\\.(\\d{4})".to_string(),
+            },
+        );
+        let cfg = Arc::new(RwLock::new(cfg));
+        let query_c = Query::new(cfg);
+
+        let ast = query_c
+            .parse_query("SELECT $phone FROM mylog".to_string())
+            .unwrap();
+        let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+        let (ref the_query, ref mut query_data) = queries_parse[0];
+
+        let log_line = "xx 555.555.5555 xx".to_string();
+        let lines: Vec<String> = vec![log_line.clone()];
+        let mut db = query_data.hs_db.take().unwrap();
+        let mut ls = HSLineScanner::new(&lines);
+        let pattern_match_results = ls.scan(&mut db);
+        drop(ls);
+
+        let payload =
+            evaluate_query_on_line(the_query, query_data, 0, log_line, pattern_match_results)
+                .unwrap();
+        let res_json: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(res_json["$phone"], "555.555.5555");
+    }
+
+    #[test]
+    fn query_memory_limit() {
+        let memory = QueryMemory::new(100);
+        assert!(memory.reserve(60));
+        // over the limit, nothing is accounted
+        assert!(!memory.reserve(60));
+        memory.release(60);
+        assert!(memory.reserve(100));
+        assert!(!memory.reserve(1));
+    }
+
+    #[test]
+    fn results_end_on_error() {
+        let results = stream::iter_result(vec![
+            Ok(Chunk::from("a\n")),
+            Err(QueryError::MemoryLimitExceeded(100)),
+            Ok(Chunk::from("b\n")),
+        ]);
+        let chunks: Vec<Chunk> = end_on_error(results).collect().wait().unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(&chunks[0][..], b"a\n");
+        assert_eq!(
+            &chunks[1][..],
+            &b"{\"error\":\"Query exceeded its memory limit of 100 bytes\"}\n"[..]
+        );
+    }
+
+    #[test]
+    fn computed_projections_parse_and_match() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+
+        let ast = query_c
+            .parse_query(
+                "SELECT CAST($4 AS INT) AS status, SUBSTR($ip, 0, 4) AS net, \
+                 COALESCE($email, 'anonymous') AS user FROM mylog"
+                    .to_string(),
+            )
+            .unwrap();
+        let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+        let (ref the_query, ref mut query_data) = queries_parse[0];
+        assert_eq!(query_data.computed_fields.len(), 3);
+
+        let log_line = "10.0.0.1 - - 404 GET".to_string();
+        let lines: Vec<String> = vec![log_line.clone()];
+        let mut db = query_data.hs_db.take().unwrap();
+        let mut ls = HSLineScanner::new(&lines);
+        let pattern_match_results = ls.scan(&mut db);
+        drop(ls);
+
+        let payload =
+            evaluate_query_on_line(the_query, query_data, 0, log_line, pattern_match_results)
+                .unwrap();
+        let res_json: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(res_json["status"], 404);
+        assert_eq!(res_json["net"], "10.0");
+        assert_eq!(res_json["user"], "anonymous");
+    }
+
+    #[test]
+    fn unsupported_function_projection() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+
+        let ast = query_c
+            .parse_query("SELECT MEDIAN($4) FROM mylog".to_string())
+            .unwrap();
+        match query_c.process_sql(&access_token, ast, false) {
+            Err(ProcessingQueryError::Fail(_)) => (),
+            _ => panic!("Expected the function to be rejected"),
+        }
+    }
+
+    #[test]
+    fn aggregate_queries_plan() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let plan = |sql: &str| {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            query_c.process_sql(&access_token, ast, false)
+        };
+
+        let queries = plan(
+            "SELECT TIME_BUCKET($date, '5m') AS bucket, $ip, COUNT(*), SUM($4) AS bytes \
+             FROM mylog GROUP BY bucket, $ip",
+        )
+        .unwrap();
+        let aggregation = queries[0].1.aggregation.as_ref().unwrap();
+        assert_eq!(
+            aggregation
+                .group_by
+                .iter()
+                .map(|group| group.alias.clone())
+                .collect::<Vec<_>>(),
+            vec![Some("bucket".to_string()), Some("$ip".to_string())]
+        );
+        assert_eq!(aggregation.aggregates.len(), 2);
+        assert_eq!(
+            queries[0].1.projections_ordered,
+            vec!["bucket", "$ip", "COUNT(*)", "bytes"]
+        );
+        // the fields read by the groups and the aggregates are extracted
+        assert!(queries[0]
+            .1
+            .positional_fields
+            .iter()
+            .any(|positional| positional.alias == "$4"));
+
+        assert!(plan("SELECT $ip FROM mylog").unwrap()[0]
+            .1
+            .aggregation
+            .is_none());
+        match plan("SELECT $ip, COUNT(*) FROM mylog") {
+            Err(ProcessingQueryError::Fail(e)) => {
+                assert_eq!(
+                    e,
+                    "`$ip` must be on GROUP BY or used in an aggregate function"
+                )
+            }
+            _ => panic!("Expected the projection to be rejected"),
+        }
+        for sql in vec![
+            "SELECT *, COUNT(*) FROM mylog",
+            "SELECT COUNT(*) FROM mylog GROUP BY COUNT(*)",
+        ] {
+            match plan(sql) {
+                Err(ProcessingQueryError::Fail(_)) => (),
+                _ => panic!("Expected {} to be rejected", sql),
+            }
+        }
+        match plan("SELECT $ip, COUNT(*) FROM mylog GROUP BY $ip HAVING COUNT(*) = 2") {
+            Err(ProcessingQueryError::UnsupportedQuery(_)) => (),
+            _ => panic!("Expected HAVING to be rejected"),
+        }
+    }
+
+    #[test]
+    fn order_by_queries_plan() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let plan = |sql: &str| {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            query_c.process_sql(&access_token, ast, false)
+        };
+
+        let queries = plan("SELECT $ip, $4 AS bytes FROM mylog ORDER BY $4 DESC, $ip").unwrap();
+        assert_eq!(
+            queries[0].1.order_by,
+            vec![
+                OrderColumn {
+                    alias: "bytes".to_string(),
+                    descending: true,
+                },
+                OrderColumn {
+                    alias: "$ip".to_string(),
+                    descending: false,
+                },
+            ]
+        );
+        let queries =
+            plan("SELECT $ip, COUNT(*) AS hits FROM mylog GROUP BY $ip ORDER BY hits DESC")
+                .unwrap();
+        assert_eq!(queries[0].1.order_by[0].alias, "hits");
+        let queries = plan("SELECT * FROM mylog ORDER BY $line").unwrap();
+        assert_eq!(queries[0].1.order_by[0].alias, "$line");
+
+        match plan("SELECT $ip FROM mylog ORDER BY $4") {
+            Err(ProcessingQueryError::Fail(e)) => {
+                assert_eq!(e, "`$4` must be projected to ORDER BY it")
+            }
+            _ => panic!("Expected the order to be rejected"),
+        }
+    }
+
+    #[test]
+    fn computed_fields_plan() {
+        let access_token = VALID_TOKEN.to_string();
+        let mut cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        cfg.log
+            .get_mut("mylog")
+            .unwrap()
+            .computed_fields
+            .insert("latency_ms".to_string(), "CAST($9 AS INT)".to_string());
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let plan = |sql: &str| {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            query_c.process_sql(&access_token, ast, false)
+        };
+
+        let queries = plan("SELECT $ip, latency_ms FROM mylog ORDER BY latency_ms").unwrap();
+        assert_eq!(queries[0].1.projections_ordered, vec!["$ip", "latency_ms"]);
+        assert_eq!(queries[0].1.order_by[0].alias, "latency_ms");
+        let queries =
+            plan("SELECT $ip, AVG(latency_ms) AS latency FROM mylog GROUP BY $ip").unwrap();
+        assert!(queries[0].1.aggregation.is_some());
+        assert_eq!(queries[0].1.positional_fields.len(), 1);
+
+        match plan("SELECT * FROM mylog WHERE latency_ms = '5'") {
+            Err(ProcessingQueryError::Fail(_)) => (),
+            _ => panic!("Expected the condition to be rejected"),
+        }
+    }
+
+    #[test]
+    fn plans_are_cached() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("plannedlog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let plan = |sql: &str, saved_search: Option<&str>| {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            query_c
+                .process_saved_sql(&access_token, ast, false, saved_search)
+                .unwrap()
+        };
+        let sql = "SELECT $ip FROM plannedlog WHERE $4 = '200'";
+
+        let first = plan(sql, Some("errors-dashboard"));
+        let cached = Arc::clone(first[0].1.plan.as_ref().unwrap());
+        assert!(cached.lock().unwrap().hs_dbs.is_empty());
+        // the pattern database is handed back once the search is done
+        drop(first);
+        assert_eq!(cached.lock().unwrap().hs_dbs.len(), 1);
+
+        let second = plan(sql, Some("errors-dashboard"));
+        assert!(Arc::ptr_eq(second[0].1.plan.as_ref().unwrap(), &cached));
+        assert!(second[0].1.hs_db.is_some());
+        assert!(cached.lock().unwrap().hs_dbs.is_empty());
+        assert_eq!(second[0].1.projections_ordered, vec!["$ip"]);
+
+        // searches that aren't saved are planned every time
+        assert!(plan(sql, None)[0].1.plan.is_none());
+        // a saved search whose statement changed is planned again
+        let edited = plan("SELECT $ip FROM plannedlog", Some("errors-dashboard"));
+        assert!(!Arc::ptr_eq(edited[0].1.plan.as_ref().unwrap(), &cached));
+    }
+
+    #[test]
+    fn time_range_queries_plan() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let plan = |sql: &str| {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            query_c.process_sql(&access_token, ast, false)
+        };
+
+        let queries = plan("SELECT * FROM mylog WHERE $time >= '2019-07-24T09:00:00Z'").unwrap();
+        assert!(queries[0].1.time_range.unwrap().from.is_some());
+        // a time range bounds the query as much as a `$date` predicate
+        assert!(queries[0].1.date_predicate);
+        let queries = plan("SELECT * FROM mylog").unwrap();
+        assert_eq!(queries[0].1.time_range, None);
+        assert_eq!(queries[0].1.received_range, None);
+        // the receive time has a range of its own, objects aren't narrowed on their keys by it
+        let queries = plan("SELECT * FROM mylog WHERE $received < '2019-07-24T09:00:00Z'").unwrap();
+        assert!(queries[0].1.received_range.unwrap().until.is_some());
+        assert_eq!(queries[0].1.time_range, None);
+        assert!(queries[0].1.date_predicate);
+
+        match plan("SELECT * FROM mylog WHERE $time > '2019-07-24T09:00:00Z' OR $ip IS NULL") {
+            Err(ProcessingQueryError::Fail(_)) => (),
+            _ => panic!("Expected the time range to be rejected"),
+        }
+    }
+
+    #[test]
+    fn stamped_lines_on_a_received_range() {
+        use chrono::TimeZone;
+
+        let range = TimeRange {
+            from: Some(Utc.ymd(2019, 7, 1).and_hms(10, 0, 0)),
+            until: Some(Utc.ymd(2019, 7, 1).and_hms(11, 0, 0)),
+        };
+        assert!(stamped_on_range("2019-07-01T10:30:00.005Z GET /", &range));
+        assert!(!stamped_on_range("2019-07-01T11:00:00.000Z GET /", &range));
+        assert!(!stamped_on_range("2019-07-01T09:59:59.999Z GET /", &range));
+        // lines stored before the log stamped them are kept
+        assert!(stamped_on_range("GET /", &range));
+    }
+
+    #[test]
+    fn csv_output() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let ast = query_c
+            .parse_query("SELECT *, $ip, $4 AS status FROM mylog".to_string())
+            .unwrap();
+        let mut queries = query_c.process_sql(&access_token, ast, false).unwrap();
+        queries[0].1.output_shape.strip_prefix = true;
+        let columns = csv_columns(&queries[0].1);
+        assert_eq!(csv_header_row(&columns), "line,ip,status\r\n");
+        assert_eq!(
+            csv_row(
+                r#"{"line":"GET \"/\", 200","ip":"10.0.0.1","status":null}"#,
+                &columns
+            ),
+            "\"GET \"\"/\"\", 200\",10.0.0.1,\r\n"
+        );
+        let columns = vec![("n".to_string(), vec!["n".to_string()])];
+        assert_eq!(csv_row(r#"{"n":5}"#, &columns), "5\r\n");
+    }
+
+    #[test]
+    fn output_format_requested() {
+        let request = |uri: &str, accept: Option<&str>| {
+            let mut builder = Request::builder();
+            builder.uri(uri);
+            if let Some(accept) = accept {
+                builder.header(header::ACCEPT, accept);
+            }
+            output_format(&builder.body(Body::empty()).unwrap())
+        };
+        assert_eq!(request("/search", None), Ok(OutputFormat::JsonLines));
+        assert_eq!(
+            request("/search", Some("text/csv; charset=utf-8")),
+            Ok(OutputFormat::Csv)
+        );
+        assert_eq!(
+            request("/search", Some("application/x-ndjson, */*")),
+            Ok(OutputFormat::Ndjson)
+        );
+        assert_eq!(
+            request("/search", Some("text/html")),
+            Ok(OutputFormat::JsonLines)
+        );
+        assert_eq!(
+            request("/search?format=csv", Some(APP_NDJSON)),
+            Ok(OutputFormat::Csv)
+        );
+        assert!(request("/search?format=xml", None).is_err());
+    }
+
+    #[test]
+    fn sort_rows_keeping_the_top() {
+        let order_by = vec![OrderColumn {
+            alias: "$4".to_string(),
+            descending: true,
+        }];
+        let rows = |values: &[&str]| -> Vec<String> {
+            values
+                .iter()
+                .map(|value| json!({ "$4": value }).to_string())
+                .collect()
+        };
+
+        let mut sorter = RowSorter::new(&order_by, &OutputShape::default(), Some(2));
+        sorter.add(rows(&["10", "300"])).unwrap();
+        sorter.add(rows(&["9", "1000", "20"])).unwrap();
+        // the rows past the top are dropped as they are added
+        assert!(sorter.rows.len() <= 4);
+        assert_eq!(sorter.into_rows(), rows(&["1000", "300"]));
+
+        // rows without the column go last, keys follow the output shape
+        let shape = OutputShape {
+            strip_prefix: true,
+            ..OutputShape::default()
+        };
+        let mut sorter = RowSorter::new(&order_by, &shape, None);
+        sorter
+            .add(vec![
+                "{\"4\":null}".to_string(),
+                "{\"4\":\"5\"}".to_string(),
+                "{\"4\":\"text\"}".to_string(),
+                "{\"4\":\"50\"}".to_string(),
+            ])
+            .unwrap();
+        assert_eq!(
+            sorter.into_rows(),
+            vec![
+                "{\"4\":\"text\"}",
+                "{\"4\":\"50\"}",
+                "{\"4\":\"5\"}",
+                "{\"4\":null}"
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregate_lines_into_groups() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let ast = query_c
+            .parse_query(
+                "SELECT $ip, COUNT(*) AS hits, AVG($4) FROM mylog WHERE $2 = 'GET' GROUP BY $ip"
+                    .to_string(),
+            )
+            .unwrap();
+        let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+        let (ref the_query, ref mut query_data) = queries_parse[0];
+        let lines: Vec<String> = vec![
+            "10.0.0.1 GET / 200".to_string(),
+            "10.0.0.2 GET / 404".to_string(),
+            "10.0.0.1 POST / 500".to_string(),
+            "10.0.0.1 GET / 300".to_string(),
+        ];
+        let pattern_match_results = scan_lines(query_data, &lines);
+        let mut groups = query_data.group_table().unwrap();
+        aggregate_lines(
+            the_query,
+            query_data,
+            lines,
+            pattern_match_results,
+            &mut groups,
+        )
+        .unwrap();
+        assert_eq!(
+            group_rows(query_data, &groups),
+            vec![
+                "{\"$ip\":\"10.0.0.1\",\"AVG($4)\":250.0,\"hits\":2}",
+                "{\"$ip\":\"10.0.0.2\",\"AVG($4)\":404.0,\"hits\":1}"
+            ]
+        );
+    }
+
+    #[test]
+    fn sf_user_agent_subfields_parse_and_match() {
+        let tc = ParseMatchTestCase {
+            log_name: "mylog".to_string(),
+            query: "SELECT $user_agent.name, $user_agent.os, $user_agent.category FROM mylog"
+                .to_string(),
+            log_line: "xx \"Mozilla/5.0 (Windows NT 10.0; Win64; x64)AppleWebKit/537.36 (KHTML, like Gecko) Chrome/66.0.3359.181 Safari/537.36\" xx".to_string(),
+            expected: map! {
+                "$user_agent.name".to_string() => "Chrome".to_string(),
+                "$user_agent.os".to_string() => "Windows 10".to_string(),
+                "$user_agent.category".to_string() => "pc".to_string()
+            },
+        };
+        run_parse_and_match_case(tc);
+    }
+
+    #[test]
+    fn sf_kv_subfields_parse_and_match() {
+        let tc = ParseMatchTestCase {
+            log_name: "mylog".to_string(),
+            query: "SELECT $kv.msg, $kv.user FROM mylog WHERE $kv.level = 'error'".to_string(),
+            log_line: "ts=2019-07-24T09:30:00Z level=error msg=\"boom at \\\"db\\\"\" user=42"
+                .to_string(),
+            expected: map! {
+                "$kv.msg".to_string() => "boom at \"db\"".to_string(),
+                "$kv.user".to_string() => "42".to_string()
+            },
+        };
+        run_parse_and_match_case(tc);
+    }
+
+    #[test]
+    fn sf_json_subfields_parse_and_match() {
+        let tc = ParseMatchTestCase {
+            log_name: "applog".to_string(),
+            query:
+                "SELECT $json.request.status, $json.tags FROM applog WHERE $json.level = 'error'"
+                    .to_string(),
+            log_line:
+                r#"{"level":"error","request":{"method":"GET","status":502},"tags":["a","b"]}"#
+                    .to_string(),
+            expected: map! {
+                "$json.request.status".to_string() => "502".to_string(),
+                "$json.tags".to_string() => r#"["a","b"]"#.to_string()
+            },
+        };
+        run_parse_and_match_case(tc);
+    }
+
+    #[test]
+    fn json_paths() {
+        let document: serde_json::Value = serde_json::from_str(
+            r#"{"msg":"boom","user":{"id":42,"admin":false,"groups":["ops"]},"trace":null}"#,
+        )
+        .unwrap();
+        assert_eq!(json_path_value(&document, "msg"), Some("boom".to_string()));
+        assert_eq!(
+            json_path_value(&document, "user.id"),
+            Some("42".to_string())
+        );
+        assert_eq!(
+            json_path_value(&document, "user.admin"),
+            Some("false".to_string())
+        );
+        assert_eq!(
+            json_path_value(&document, "user.groups"),
+            Some(r#"["ops"]"#.to_string())
+        );
+        assert_eq!(json_path_value(&document, "user.groups.0"), None);
+        assert_eq!(json_path_value(&document, "msg.length"), None);
+        assert_eq!(json_path_value(&document, "trace"), None);
+        assert_eq!(json_path_value(&document, "missing"), None);
+    }
+
+    #[test]
+    fn progress_events() {
+        let progress = QueryProgress::default();
+        assert_eq!(progress.event(), None);
+        progress.object_listed();
+        progress.object_listed();
+        progress.object_listed();
+        progress.object_listed();
+        progress.object_scanned();
+        let event: serde_json::Value = serde_json::from_str(&progress.event().unwrap()).unwrap();
+        assert_eq!(event["$progress"]["objects_scanned"], 1);
+        assert_eq!(event["$progress"]["objects_total"], 4);
+        assert_eq!(event["$progress"]["percent"], 25);
+        // nothing new was scanned
+        assert_eq!(progress.event(), None);
+
+        let event: serde_json::Value = serde_json::from_str(&progress.final_event()).unwrap();
+        assert_eq!(event["$progress"]["done"], true);
+        let empty: serde_json::Value =
+            serde_json::from_str(&QueryProgress::default().final_event()).unwrap();
+        assert_eq!(empty["$progress"]["percent"], 100);
+    }
+
+    #[test]
+    fn decode_base64_lines() {
+        let lines = vec![
+            "cGxhaW4gbGluZQ==".to_string(),
+            "//4gYmluYXJ5".to_string(),
+            "not base64!".to_string(),
+        ];
+        assert_eq!(
+            decode_lines(lines),
+            vec!["plain line", "\u{FFFD}\u{FFFD} binary", "not base64!"]
+        );
+    }
+
+    #[test]
+    fn estimate_read_time() {
+        // 10 objects at 50ms, 2 at once, plus 50MB at 100MB/s
+        assert_eq!(estimate_read_ms(10, 50 * 1024 * 1024, 50, 2), 250 + 500);
+        assert_eq!(estimate_read_ms(0, 0, 50, 0), 0);
+    }
+
+    #[test]
+    fn count_only_stats_on_headers() {
+        let stats = SearchCountStats {
+            started: Instant::now(),
+            matched_lines: Arc::new(AtomicUsize::new(7)),
+            progress: Arc::new(QueryProgress::default()),
+            failure: Arc::new(Mutex::new(None)),
+        };
+        stats.progress.object_listed();
+        stats.progress.object_listed();
+        stats.progress.object_scanned();
+        let response = stats.response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[HEADER_MATCHED_LINES], "7");
+        assert_eq!(response.headers()[HEADER_OBJECTS_SCANNED], "1");
+        assert_eq!(response.headers()[HEADER_OBJECTS_TOTAL], "2");
+        *stats.failure.lock().unwrap() = Some("Could not read datastore ds1".to_string());
+        assert_eq!(stats.response().status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn syntax_error_points_at_token() {
+        let sql = "SELECT $ip FROM mylog\nWHERE $ip = = '1'";
+        let e = locate_syntax_error(
+            sql,
+            Parser::parse_sql(&MinSQLDialect {}, sql.to_string()).unwrap_err(),
+        );
+        assert_eq!(e.position, Some(34));
+        assert_eq!((e.line, e.column), (Some(2), Some(13)));
+        assert_eq!(e.token, Some("=".to_string()));
+    }
+
+    #[test]
+    fn syntax_error_at_end_of_query() {
+        let sql = "SELECT $ip FROM ";
+        let e = locate_syntax_error(
+            sql,
+            Parser::parse_sql(&MinSQLDialect {}, sql.to_string()).unwrap_err(),
+        );
+        assert_eq!(e.position, Some(15));
+        assert_eq!(e.token, None);
+    }
+
+    #[test]
+    fn char_offset_of_line_and_column() {
+        let sql = "SELECT $ip\nFROM mylog";
+        assert_eq!(char_offset(sql, 1, 1), 0);
+        assert_eq!(char_offset(sql, 2, 6), 16);
+        assert_eq!(char_offset(sql, 3, 1), 21);
+    }
+
+    #[test]
+    fn redacted_fields_are_masked_on_output() {
+        let access_token = VALID_TOKEN.to_string();
+        let mut cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        cfg.tokens.get_mut(&access_token[0..16]).unwrap().redact = vec!["$email".to_string()];
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let line = "jane@example.com signed up from 10.0.0.1".to_string();
+
+        for (sql, expected) in vec![
+            (
+                "SELECT * FROM mylog",
+                json!({"$line": "**************** signed up from 10.0.0.1"}),
+            ),
+            (
+                "SELECT $email, $1, $ip FROM mylog WHERE $ip = '10.0.0.1'",
+                json!({"$email": "****************", "$1": "****************", "$ip": "10.0.0.1"}),
+            ),
+        ] {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+            let (ref the_query, ref mut query_data) = queries_parse[0];
+            let lines = vec![line.clone()];
+            let pattern_match_results = scan_lines(query_data, &lines);
+            let res = evaluate_query_on_line(
+                the_query,
+                query_data,
+                0,
+                line.clone(),
+                pattern_match_results,
+            );
+            let res: serde_json::Value = serde_json::from_str(&res.unwrap()).unwrap();
+            assert_eq!(res, expected);
+        }
+
+        // conditions see the masked values, so they can't tell a redacted value
+        for sql in vec![
+            "SELECT $ip FROM mylog WHERE $email = 'jane@example.com'",
+            "SELECT $ip FROM mylog WHERE $1 = 'jane@example.com'",
+            "SELECT $ip FROM mylog WHERE $line LIKE '%jane@%'",
+        ] {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+            let (ref the_query, ref mut query_data) = queries_parse[0];
+            let lines = vec![line.clone()];
+            let pattern_match_results = scan_lines(query_data, &lines);
+            let res = evaluate_query_on_line(
+                the_query,
+                query_data,
+                0,
+                line.clone(),
+                pattern_match_results,
+            );
+            assert_eq!(res, None, "{}", sql);
+        }
+
+        // fields read from the line don't reveal the redacted values either
+        for (line, sql, expected) in vec![
+            (
+                "level=info user=jane@example.com msg=signup",
+                "SELECT $kv.user, $kv.msg FROM mylog",
+                json!({"$kv.user": "****************", "$kv.msg": "signup"}),
+            ),
+            (
+                r#"{"user":{"email":"jane@example.com"},"msg":"signup"}"#,
+                "SELECT $json.user.email, $json.msg FROM mylog",
+                json!({"$json.user.email": "****************", "$json.msg": "signup"}),
+            ),
+            (
+                r#"{"user":"jane@example.com"}"#,
+                "SELECT $json FROM mylog",
+                json!({"$json": r#"{"user":"****************"}"#}),
+            ),
+        ] {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+            let (ref the_query, ref mut query_data) = queries_parse[0];
+            let lines = vec![line.to_string()];
+            let pattern_match_results = scan_lines(query_data, &lines);
+            let res = evaluate_query_on_line(
+                the_query,
+                query_data,
+                0,
+                line.to_string(),
+                pattern_match_results,
+            );
+            let res: serde_json::Value = serde_json::from_str(&res.unwrap()).unwrap();
+            assert_eq!(res, expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn remote_logs_refuse_redacted_tokens() {
+        let access_token = VALID_TOKEN.to_string();
+        let mut cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        cfg.log.get_mut("mylog").unwrap().remote = Some(RemoteLog {
+            endpoint: "https://minsql.eu-west.example.com:9999".to_string(),
+            token: "".to_string(),
+            log: None,
+        });
+        let cfg = Arc::new(RwLock::new(cfg));
+        let query_c = Query::new(Arc::clone(&cfg));
+        let ast = query_c
+            .parse_query("SELECT * FROM mylog".to_string())
+            .unwrap();
+        assert!(query_c.process_sql(&access_token, ast, false).is_ok());
+
+        cfg.write()
+            .unwrap()
+            .tokens
+            .get_mut(&access_token[0..16])
+            .unwrap()
+            .redact = vec!["$email".to_string()];
+        let ast = query_c
+            .parse_query("SELECT * FROM mylog".to_string())
+            .unwrap();
+        assert!(query_c.process_sql(&access_token, ast, false).is_err());
+    }
+
+    #[test]
+    fn highlighted_rows_have_their_matches() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+        let line = "jane@example.com signed up from 10.0.0.1".to_string();
+
+        for (sql, highlight, expected) in vec![
+            (
+                "SELECT $ip FROM mylog WHERE $ip = '10.0.0.1' AND $line LIKE '%signed%'",
+                true,
+                json!({"$ip": "10.0.0.1", "$matches": [[17, 23], [32, 40]]}),
+            ),
+            (
+                "SELECT $ip FROM mylog WHERE $ip = '10.0.0.1'",
+                false,
+                json!({"$ip": "10.0.0.1"}),
+            ),
+        ] {
+            let ast = query_c.parse_query(sql.to_string()).unwrap();
+            let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+            let (ref the_query, ref mut query_data) = queries_parse[0];
+            query_data.highlight = highlight;
+            let lines = vec![line.clone()];
+            let pattern_match_results = scan_lines(query_data, &lines);
+            let res = evaluate_query_on_line(
+                the_query,
+                query_data,
+                0,
+                line.clone(),
+                pattern_match_results,
+            );
+            let res: serde_json::Value = serde_json::from_str(&res.unwrap()).unwrap();
+            assert_eq!(res, expected);
+        }
+    }
+
+    #[test]
+    fn purge_matches_lines_on_conditions() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let query_c = Query::new(Arc::new(RwLock::new(cfg)));
+
+        let (statement, mut q_parse) = query_c
+            .plan_purge("mylog", "$email = 'jane@example.com' OR $1 = 'drop'")
+            .unwrap();
+        let lines = vec![
+            "10.0.0.1 signup jane@example.com".to_string(),
+            "10.0.0.2 signup john@example.com".to_string(),
+            "drop me".to_string(),
+        ];
+        assert_eq!(
+            matching_lines(&statement, &mut q_parse, &lines),
+            vec![true, false, true]
+        );
+
+        assert!(query_c.plan_purge("mylog", "$1 = 'a' LIMIT 1").is_err());
+        assert!(query_c
+            .plan_purge("mylog", "$1 = 'a'; SELECT * FROM mylog")
+            .is_err());
+    }
+
+    #[test]
+    fn batches_past_u16_lines_keep_their_matches() {
+        let access_token = VALID_TOKEN.to_string();
+        let cfg = get_ds_log_auth_config_for("mylog".to_string(), &access_token);
+        let cfg = Arc::new(RwLock::new(cfg));
+        let query_c = Query::new(cfg);
+
+        let ast = query_c
+            .parse_query("SELECT $ip FROM mylog".to_string())
+            .unwrap();
+        let mut queries_parse = query_c.process_sql(&access_token, ast, false).unwrap();
+        let (ref the_query, ref mut query_data) = queries_parse[0];
+
+        // the index of the last line would wrap around to the first one on a u16
+        let mut lines: Vec<String> = vec!["no address here".to_string(); 65537];
+        lines[0] = "from 10.0.0.1".to_string();
+        lines[65536] = "from 10.0.0.2".to_string();
+        let pattern_match_results = scan_lines(query_data, &lines);
+
+        let res = evaluate_lines(
+            the_query,
+            query_data,
+            lines,
+            Arc::clone(&pattern_match_results),
+        );
+        let ips: Vec<serde_json::Value> = res
+            .iter()
+            .map(|payload| {
+                serde_json::from_str::<serde_json::Value>(payload).unwrap()["$ip"].clone()
+            })
+            .collect();
+        assert_eq!(ips, vec![json!("10.0.0.1"), json!("10.0.0.2")]);
+        // every line took its matches out of the results of the batch
+        assert!(pattern_match_results.read().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,491 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{future, Future, Stream};
+use lazy_static::lazy_static;
+use log::{error, info};
+use regex::Regex;
+use serde_derive::Serialize;
+use sqlparser::ast::Statement;
+use tokio::timer::Interval;
+use url::Url;
+
+use crate::api::etag_for;
+use crate::caches::Cache;
+use crate::config::{Config, Log, LogReport};
+use crate::constants::{REPORT_INTERVAL_SECS, REPORT_MAX_DISTINCT, REPORT_TOP_VALUES};
+use crate::hyperscan::{default_patterns, regex_pattern, P_IP, P_URL, P_USER_AGENT};
+use crate::jobs::spawn_job;
+use crate::query::{matching_lines, Query, QueryParsing};
+use crate::sinks::{sink_for, webhook_sink, OutputSink, SinkFuture};
+use crate::supervisor;
+
+/// The planned `error_condition` of a report, locked while lines are matched against it
+type ErrorCondition = Arc<Mutex<(Statement, QueryParsing)>>;
+
+// Smart fields the report counts the most frequent values of
+const COUNTED_FIELDS: &[(usize, &str)] =
+    &[(P_IP, "ip"), (P_URL, "url"), (P_USER_AGENT, "user_agent")];
+
+lazy_static! {
+    // Stats of every log, each behind a lock of its own so the ingests of different logs don't
+    // wait on each other
+    static ref REPORT_STATS: Mutex<HashMap<String, Arc<Mutex<ReportStats>>>> =
+        Mutex::new(HashMap::new());
+    // Compiled expressions, keyed by expression
+    pub static ref REPORT_REGEXES: Cache<Option<Regex>> = Cache::new("report_regexes");
+    // Planned error conditions, keyed by log, version of its configuration and condition
+    pub static ref REPORT_CONDITIONS: Cache<Option<ErrorCondition>> =
+        Cache::new("report_conditions");
+}
+
+/// What was ingested on a log since its last report
+#[derive(Default, Debug)]
+struct ReportStats {
+    since: Option<DateTime<Utc>>,
+    lines: u64,
+    bytes: u64,
+    errors: u64,
+    ips: HashMap<String, u64>,
+    paths: HashMap<String, u64>,
+    user_agents: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct TopValue {
+    value: String,
+    count: u64,
+}
+
+/// Summary delivered to the webhook or sink of a log
+#[derive(Serialize, Debug)]
+struct DailyReport {
+    log: String,
+    from: Option<String>,
+    to: String,
+    lines: u64,
+    bytes: u64,
+    // lines matching the `error_condition` of the report
+    errors: u64,
+    top_ips: Vec<TopValue>,
+    top_paths: Vec<TopValue>,
+    top_user_agents: Vec<TopValue>,
+}
+
+pub struct Reports {
+    config: Arc<RwLock<Config>>,
+}
+
+impl Reports {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> Reports {
+        Reports { config: cfg }
+    }
+
+    /// Starts the task that delivers the daily report of every log with one configured, at
+    /// midnight UTC.
+    pub fn start_report_task(&self) {
+        let cfg = Arc::clone(&self.config);
+        supervisor::spawn_supervised("Daily report task".to_string(), move || {
+            let cfg = Arc::clone(&cfg);
+            let now = Utc::now();
+            let midnight = (now.date() + chrono::Duration::days(1)).and_hms(0, 0, 0);
+            let wait = (midnight - now)
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            Interval::new(
+                Instant::now() + wait,
+                Duration::from_secs(REPORT_INTERVAL_SECS),
+            )
+            .map_err(|e| error!("report interval errored; err={:?}", e))
+            .for_each(move |_| {
+                let reports_c = Reports::new(Arc::clone(&cfg));
+                reports_c.send_reports();
+                Ok(())
+            })
+        });
+    }
+
+    /// Spawns the delivery of the report of every log with one configured, to its sink or else
+    /// its webhook
+    fn send_reports(&self) {
+        let read_cfg = self.config.read().unwrap();
+        let now = Utc::now();
+        for (log_name, log) in &read_cfg.log {
+            let sink = match &log.report {
+                Some(report) => report_sink(report),
+                None => continue,
+            };
+            let stats = take_stats(log_name, now);
+            let report = build_report(log_name, stats, now);
+            let body = serde_json::to_string(&report).unwrap();
+            let err_log_name = log_name.clone();
+            let delivery: SinkFuture = match sink {
+                Ok(sink) => {
+                    sink.deliver(Arc::clone(&self.config), &report_name(log_name, now), body)
+                }
+                Err(e) => Box::new(future::err(e)),
+            };
+            info!("Sending the daily report of {}", log_name);
+            spawn_job(
+                Arc::clone(&self.config),
+                "report",
+                log_name,
+                delivery.map_err(move |e| {
+                    error!(
+                        "Could not deliver the daily report of {}: {}",
+                        err_log_name, e
+                    )
+                }),
+            );
+        }
+    }
+}
+
+impl ReportStats {
+    /// Adds the stats of a batch of lines
+    fn merge(&mut self, batch: ReportStats) {
+        self.lines += batch.lines;
+        self.bytes += batch.bytes;
+        self.errors += batch.errors;
+        for (value, count) in batch.ips {
+            add_count(&mut self.ips, &value, count);
+        }
+        for (value, count) in batch.paths {
+            add_count(&mut self.paths, &value, count);
+        }
+        for (value, count) in batch.user_agents {
+            add_count(&mut self.user_agents, &value, count);
+        }
+    }
+}
+
+/// Accounts the payload ingested on a log for its next report. The lines are evaluated on their
+/// own, the stats of the log are only locked to add them up.
+pub fn record_ingested(cfg: &Config, log: &Log, report: &LogReport, payload: &str) {
+    let log_name = log.name.clone().unwrap_or_default();
+    let ip_re = cached_regex(&field_expression(cfg, P_IP, "ip"));
+    let url_re = cached_regex(&field_expression(cfg, P_URL, "url"));
+    let user_agent_re = cached_regex(&field_expression(cfg, P_USER_AGENT, "user_agent"));
+    let lines: Vec<String> = payload
+        .split('\n')
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+
+    let mut batch = ReportStats::default();
+    if let Some(condition) = report
+        .error_condition
+        .as_ref()
+        .and_then(|condition| cached_condition(cfg, log, condition))
+    {
+        let mut condition = condition.lock().unwrap();
+        let (statement, q_parse) = &mut *condition;
+        batch.errors = matching_lines(statement, q_parse, &lines)
+            .iter()
+            .filter(|matched| **matched)
+            .count() as u64;
+    }
+    for line in &lines {
+        batch.lines += 1;
+        batch.bytes += line.len() as u64;
+        if let Some(ip) = ip_re.as_ref().and_then(|re| re.find(line)) {
+            count_value(&mut batch.ips, ip.as_str());
+        }
+        if let Some(url) = url_re.as_ref().and_then(|re| re.find(line)) {
+            if let Ok(url) = Url::parse(url.as_str()) {
+                count_value(&mut batch.paths, url.path());
+            }
+        }
+        if let Some(caps) = user_agent_re.as_ref().and_then(|re| re.captures(line)) {
+            // the user agent without its surrounding quotes
+            let user_agent = caps.get(1).unwrap_or_else(|| caps.get(0).unwrap());
+            count_value(&mut batch.user_agents, user_agent.as_str());
+        }
+    }
+    log_stats(&log_name).lock().unwrap().merge(batch);
+}
+
+/// Validates the `error_condition` of the report of a log and the smart field expressions it
+/// counts the values of, before the log is saved
+pub fn validate_report_condition(cfg: &Config, log: &Log) -> Result<(), String> {
+    let report = match &log.report {
+        Some(report) => report,
+        None => return Ok(()),
+    };
+    if let Some(condition) = &report.error_condition {
+        plan_error_condition(cfg, log, condition)
+            .map_err(|e| format!("Report error_condition is invalid: {}", e))?;
+    }
+    for (id, name) in COUNTED_FIELDS {
+        if regex_pattern(&field_expression(cfg, *id, name)).is_err() {
+            return Err(format!(
+                "The `{}` pattern can't be used by reports, it doesn't compile as a regex",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The expression of a smart field, its override if it has one
+fn field_expression(cfg: &Config, id: usize, name: &str) -> String {
+    match cfg.patterns.get(name) {
+        Some(smart_pattern) => smart_pattern.expression.clone(),
+        None => default_patterns().remove(&id).unwrap(),
+    }
+}
+
+/// Plans the error condition of a report against the log as it's configured
+fn plan_error_condition(
+    cfg: &Config,
+    log: &Log,
+    condition: &str,
+) -> Result<(Statement, QueryParsing), String> {
+    let log_name = log.name.clone().unwrap_or_default();
+    // the log may not be saved yet, it's planned against a copy of the configuration with it
+    let mut planned_cfg = cfg.clone();
+    planned_cfg.log.insert(log_name.clone(), log.clone());
+    Query::new(Arc::new(RwLock::new(planned_cfg))).plan_condition(&log_name, condition)
+}
+
+/// Plans an error condition once for each version of the log and the smart field patterns,
+/// conditions that can't be planned count no errors
+fn cached_condition(cfg: &Config, log: &Log, condition: &str) -> Option<ErrorCondition> {
+    let key = format!(
+        "{}\n{}\n{}",
+        log.name.as_ref().map(|n| n.as_str()).unwrap_or_default(),
+        etag_for(&(log, &cfg.patterns)),
+        condition
+    );
+    REPORT_CONDITIONS.get_or_insert_with(&key, || match plan_error_condition(cfg, log, condition) {
+        Ok(planned) => Some(Arc::new(Mutex::new(planned))),
+        Err(e) => {
+            error!(
+                "Ignoring the report error_condition of {:?}: {}",
+                log.name, e
+            );
+            None
+        }
+    })
+}
+
+/// Compiles an expression once, expressions the regex engine can't compile are skipped
+fn cached_regex(expression: &str) -> Option<Regex> {
+    REPORT_REGEXES.get_or_insert_with(expression, || regex_pattern(expression).ok())
+}
+
+/// The stats of a log
+fn log_stats(log_name: &str) -> Arc<Mutex<ReportStats>> {
+    let mut table = REPORT_STATS.lock().unwrap();
+    Arc::clone(
+        table
+            .entry(log_name.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(ReportStats::default()))),
+    )
+}
+
+/// Counts a value, once `REPORT_MAX_DISTINCT` values are tracked only those are counted
+fn count_value(counts: &mut HashMap<String, u64>, value: &str) {
+    add_count(counts, value, 1)
+}
+
+fn add_count(counts: &mut HashMap<String, u64>, value: &str, count: u64) {
+    if let Some(counted) = counts.get_mut(value) {
+        *counted += count;
+    } else if counts.len() < REPORT_MAX_DISTINCT {
+        counts.insert(value.to_string(), count);
+    }
+}
+
+/// Takes the stats of a log, starting a new period at `now`
+fn take_stats(log_name: &str, now: DateTime<Utc>) -> ReportStats {
+    let stats = log_stats(log_name);
+    let mut stats = stats.lock().unwrap();
+    let mut taken = ReportStats {
+        since: Some(now),
+        ..Default::default()
+    };
+    mem::swap(&mut *stats, &mut taken);
+    taken
+}
+
+fn build_report(log_name: &str, stats: ReportStats, now: DateTime<Utc>) -> DailyReport {
+    DailyReport {
+        log: log_name.to_string(),
+        from: stats
+            .since
+            .map(|since| since.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        to: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        lines: stats.lines,
+        bytes: stats.bytes,
+        errors: stats.errors,
+        top_ips: top_values(stats.ips),
+        top_paths: top_values(stats.paths),
+        top_user_agents: top_values(stats.user_agents),
+    }
+}
+
+/// Where the report of a log is delivered, its `sink` if it has one
+fn report_sink(report: &LogReport) -> Result<Box<dyn OutputSink>, String> {
+    match &report.sink {
+        Some(sink) => sink_for(sink),
+        None => Ok(webhook_sink(&report.webhook)),
+    }
+}
+
+/// Name of the report of a log for the day ending at `now`, ie: `mylog/2019-08-01`
+fn report_name(log_name: &str, now: DateTime<Utc>) -> String {
+    let day = now - chrono::Duration::days(1);
+    format!("{}/{}", log_name, day.format("%Y-%m-%d"))
+}
+
+/// The `REPORT_TOP_VALUES` most counted values, ties sorted by value
+fn top_values(counts: HashMap<String, u64>) -> Vec<TopValue> {
+    let mut values: Vec<TopValue> = counts
+        .into_iter()
+        .map(|(value, count)| TopValue { value, count })
+        .collect();
+    values.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    values.truncate(REPORT_TOP_VALUES);
+    values
+}
+
+#[cfg(test)]
+mod reports_tests {
+    use crate::config::Server;
+
+    use super::*;
+
+    fn config_with(log: &Log) -> Config {
+        let mut logs = HashMap::new();
+        logs.insert(log.name.clone().unwrap(), log.clone());
+        Config {
+            server: Server::default(),
+            datastore: HashMap::new(),
+            log: logs,
+            tokens: HashMap::new(),
+            auth: HashMap::new(),
+            patterns: HashMap::new(),
+        }
+    }
+
+    fn report_log(error_condition: &str) -> Log {
+        Log {
+            name: Some("reports_test_log".to_string()),
+            commit_window: "5s".to_string(),
+            report: Some(LogReport {
+                webhook: "http://localhost/report".to_string(),
+                error_condition: Some(error_condition.to_string()),
+                sink: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn summarize_ingested_lines() {
+        let log = report_log("$line LIKE '%\" 5__ %'");
+        let cfg = config_with(&log);
+        let report = log.report.clone().unwrap();
+        let payload = "\
+10.0.0.1 - - \"GET https://example.com/info.php HTTP/1.1\" 200 10 \"Mozilla/5.0 (X11; Linux x86_64)\"
+10.0.0.2 - - \"GET https://example.com/index.html HTTP/1.1\" 500 10 \"Mozilla/5.0 (X11; Linux x86_64)\"
+10.0.0.1 - - \"GET https://example.com/info.php HTTP/1.1\" 200 10 \"-\"
+";
+        record_ingested(&cfg, &log, &report, payload);
+        let now = Utc::now();
+        let daily = build_report("reports_test_log", take_stats("reports_test_log", now), now);
+        assert_eq!(daily.lines, 3);
+        assert_eq!(daily.errors, 1);
+        assert_eq!(
+            daily.top_ips,
+            vec![
+                TopValue {
+                    value: "10.0.0.1".to_string(),
+                    count: 2
+                },
+                TopValue {
+                    value: "10.0.0.2".to_string(),
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(daily.top_paths[0].value, "/info.php");
+        assert_eq!(daily.top_paths[0].count, 2);
+        assert_eq!(daily.top_user_agents[0].count, 2);
+        // the next period starts empty
+        let next = take_stats("reports_test_log", Utc::now());
+        assert_eq!(next.lines, 0);
+        assert_eq!(next.since, Some(now));
+    }
+
+    #[test]
+    fn invalid_error_conditions_rejected() {
+        let log = report_log("$line LIKE");
+        assert!(validate_report_condition(&config_with(&log), &log).is_err());
+        let log = report_log("$ip = '10.0.0.1' AND $line NOT LIKE '%health%'");
+        assert!(validate_report_condition(&config_with(&log), &log).is_ok());
+    }
+
+    #[test]
+    fn merged_batches_add_up() {
+        let mut stats = ReportStats::default();
+        let mut batch = ReportStats {
+            lines: 2,
+            bytes: 20,
+            errors: 1,
+            ..Default::default()
+        };
+        count_value(&mut batch.ips, "10.0.0.1");
+        count_value(&mut batch.ips, "10.0.0.1");
+        stats.merge(batch);
+        let mut batch = ReportStats {
+            lines: 1,
+            bytes: 10,
+            ..Default::default()
+        };
+        count_value(&mut batch.ips, "10.0.0.1");
+        stats.merge(batch);
+        assert_eq!((stats.lines, stats.bytes, stats.errors), (3, 30, 1));
+        assert_eq!(stats.ips["10.0.0.1"], 3);
+    }
+
+    #[test]
+    fn distinct_values_are_capped() {
+        let mut counts = HashMap::new();
+        for i in 0..REPORT_MAX_DISTINCT + 10 {
+            count_value(&mut counts, &i.to_string());
+        }
+        count_value(&mut counts, "0");
+        assert_eq!(counts.len(), REPORT_MAX_DISTINCT);
+        assert_eq!(counts["0"], 2);
+        assert_eq!(top_values(counts)[0].value, "0");
+    }
+
+    #[test]
+    fn reports_named_after_their_day() {
+        let now = DateTime::parse_from_rfc3339("2019-08-02T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(report_name("mylog", now), "mylog/2019-08-01");
+    }
+}
@@ -0,0 +1,564 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{future, Future, Stream};
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+
+use crate::constants::S3STUB_MAX_KEYS;
+
+/// An object held by the stub
+#[derive(Debug, Clone)]
+struct StubObject {
+    body: Vec<u8>,
+    // user metadata, sent and returned as `x-amz-meta-*` headers
+    metadata: Vec<(String, String)>,
+    last_modified: DateTime<Utc>,
+}
+
+/// In memory S3 compatible backend, answering the calls MinSQL makes on its datastores and its
+/// metabucket: listing, getting, putting and deleting objects. Every bucket exists and
+/// signatures are not checked, it's meant to run the server on tests only.
+#[derive(Default)]
+struct S3Stub {
+    // by bucket and key
+    objects: Mutex<BTreeMap<(String, String), StubObject>>,
+}
+
+/// Starts the stub on a local port, on a thread of its own so it outlives the runtimes the
+/// server starts and stops while booting, and returns its address.
+pub fn start() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let stub = Arc::new(S3Stub::default());
+    let server = Server::from_tcp(listener)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .serve(move || {
+            let stub = Arc::clone(&stub);
+            service_fn(move |req: Request<Body>| handle(Arc::clone(&stub), req))
+        })
+        .map_err(|e| error!("S3 stub error: {}", e));
+    thread::spawn(move || tokio::run(server));
+    info!("Test backend listening on {}", addr);
+    Ok(addr)
+}
+
+fn handle(
+    stub: Arc<S3Stub>,
+    req: Request<Body>,
+) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    let (bucket, key) = split_path(req.uri().path());
+    let params: HashMap<String, String> = match req.uri().query() {
+        Some(query) => url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+        None => HashMap::new(),
+    };
+    let method = req.method().clone();
+    match (&method, key) {
+        (&Method::GET, None) if params.contains_key("object-lock") => {
+            Box::new(future::ok(error_response(
+                StatusCode::NOT_FOUND,
+                "ObjectLockConfigurationNotFoundError",
+            )))
+        }
+        (&Method::GET, None) => Box::new(future::ok(stub.list(&bucket, &params))),
+        (&Method::HEAD, None) => Box::new(future::ok(Response::new(Body::empty()))),
+        (&Method::GET, Some(key)) | (&Method::HEAD, Some(key)) => {
+            let head = method == Method::HEAD;
+            let range = req
+                .headers()
+                .get(header::RANGE)
+                .and_then(|range| range.to_str().ok());
+            Box::new(future::ok(stub.get(&bucket, &key, head, range)))
+        }
+        (&Method::PUT, Some(key)) => {
+            let metadata = user_metadata(&req);
+            Box::new(
+                req.into_body()
+                    .concat2()
+                    .map(move |body| stub.put(bucket, key, body.to_vec(), metadata)),
+            )
+        }
+        (&Method::DELETE, Some(key)) => {
+            stub.objects.lock().unwrap().remove(&(bucket, key));
+            Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap(),
+            ))
+        }
+        _ => Box::new(future::ok(error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "NotImplemented",
+        ))),
+    }
+}
+
+impl S3Stub {
+    /// `ListObjects`, in key order, starting after `marker`
+    fn list(&self, bucket: &str, params: &HashMap<String, String>) -> Response<Body> {
+        let prefix = params.get("prefix").cloned().unwrap_or_default();
+        let marker = params.get("marker").cloned().unwrap_or_default();
+        let max_keys = params
+            .get("max-keys")
+            .and_then(|m| m.parse::<usize>().ok())
+            .unwrap_or(S3STUB_MAX_KEYS)
+            .min(S3STUB_MAX_KEYS);
+        let objects = self.objects.lock().unwrap();
+        let mut matching = objects
+            .iter()
+            .filter(|((b, k), _)| b == bucket && k.starts_with(&prefix) && *k > marker)
+            .map(|((_, k), obj)| (k, obj));
+        let page: Vec<(&String, &StubObject)> = matching.by_ref().take(max_keys).collect();
+        let truncated = matching.next().is_some();
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+             <Name>{}</Name><Prefix>{}</Prefix><Marker>{}</Marker><MaxKeys>{}</MaxKeys>\
+             <IsTruncated>{}</IsTruncated>",
+            escape_xml(bucket),
+            escape_xml(&prefix),
+            escape_xml(&marker),
+            max_keys,
+            truncated
+        );
+        for (key, obj) in page {
+            xml.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>\"{}\"</ETag>\
+                 <Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                escape_xml(key),
+                obj.last_modified
+                    .to_rfc3339_opts(SecondsFormat::Millis, true),
+                etag(&obj.body),
+                obj.body.len()
+            ));
+        }
+        xml.push_str("</ListBucketResult>");
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(Body::from(xml))
+            .unwrap()
+    }
+
+    /// `GetObject` and `HeadObject`, of the bytes a `Range` header asks for if there is one
+    fn get(&self, bucket: &str, key: &str, head: bool, range: Option<&str>) -> Response<Body> {
+        let objects = self.objects.lock().unwrap();
+        let obj = match objects.get(&(bucket.to_string(), key.to_string())) {
+            Some(obj) => obj,
+            None => return error_response(StatusCode::NOT_FOUND, "NoSuchKey"),
+        };
+        let len = obj.body.len();
+        let (start, end) = match range.map(|range| byte_range(range, len)) {
+            Some(Some(bounds)) => bounds,
+            Some(None) => {
+                let mut response =
+                    error_response(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange");
+                response.headers_mut().insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes */{}", len).parse().unwrap(),
+                );
+                return response;
+            }
+            None => (0, len),
+        };
+        let mut response = Response::builder();
+        response
+            .header(header::CONTENT_LENGTH, (end - start).to_string())
+            .header(header::ETAG, format!("\"{}\"", etag(&obj.body)))
+            .header(header::LAST_MODIFIED, obj.last_modified.to_rfc2822());
+        if range.is_some() {
+            response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, len),
+            );
+        }
+        for (name, value) in &obj.metadata {
+            response.header(&format!("x-amz-meta-{}", name)[..], &value[..]);
+        }
+        let body = if head {
+            Body::empty()
+        } else {
+            Body::from(obj.body[start..end].to_vec())
+        };
+        response.body(body).unwrap()
+    }
+
+    fn put(
+        &self,
+        bucket: String,
+        key: String,
+        body: Vec<u8>,
+        metadata: Vec<(String, String)>,
+    ) -> Response<Body> {
+        let etag = etag(&body);
+        let obj = StubObject {
+            body,
+            metadata,
+            last_modified: Utc::now(),
+        };
+        self.objects.lock().unwrap().insert((bucket, key), obj);
+        Response::builder()
+            .header(header::ETAG, format!("\"{}\"", etag))
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+/// Bucket and key of a path style request, ie: `/bucket/some/key`, decoded
+fn split_path(path: &str) -> (String, Option<String>) {
+    let mut parts = path.trim_start_matches('/').splitn(2, '/');
+    let bucket = percent_decode(parts.next().unwrap_or(""));
+    let key = parts
+        .next()
+        .filter(|key| !key.is_empty())
+        .map(percent_decode);
+    (bucket, key)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 3 <= bytes.len() {
+            let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn user_metadata(req: &Request<Body>) -> Vec<(String, String)> {
+    req.headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.as_str();
+            if !name.starts_with("x-amz-meta-") {
+                return None;
+            }
+            let value = value.to_str().ok()?;
+            Some((name["x-amz-meta-".len()..].to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The bytes a `Range` header asks for out of `len`, from the first up to the one before the
+/// second, ie: `bytes=0-3`, `bytes=7-` or `bytes=-4`. `None` if none of them are in the object.
+fn byte_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let spec = range.trim().trim_start_matches("bytes=");
+    let mut bounds = spec.splitn(2, '-');
+    let (first, last) = (bounds.next()?.trim(), bounds.next()?.trim());
+    let (start, end) = match (first.is_empty(), last.is_empty()) {
+        // the last bytes of the object
+        (true, false) => {
+            let suffix = last.parse::<usize>().ok()?;
+            (len.saturating_sub(suffix), len)
+        }
+        (false, true) => (first.parse::<usize>().ok()?, len),
+        (false, false) => {
+            let start = first.parse::<usize>().ok()?;
+            let last = last.parse::<usize>().ok()?;
+            if last < start {
+                return None;
+            }
+            (start, (last + 1).min(len))
+        }
+        (true, true) => return None,
+    };
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn etag(body: &[u8]) -> String {
+    format!("{:x}", md5::compute(body))
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn error_response(status: StatusCode, code: &str) -> Response<Body> {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Error><Code>{}</Code><Message>{}</Message></Error>",
+        code, code
+    );
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(Body::from(xml))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod s3stub_tests {
+    use std::sync::RwLock;
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::config::{Config, DataStore, Log, LogAuth, Server, Token};
+    use crate::constants::{TEST_BACKEND_ACCESS_KEY, TEST_BACKEND_SECRET_KEY};
+    use crate::ingest::{Ingest, IngestBuffers};
+    use crate::query::Query;
+
+    fn request(method: Method, uri: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-amz-meta-minsql-received-from", "2019-07-01T10:00:00Z")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn body_of(response: Response<Body>) -> String {
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn split_paths() {
+        assert_eq!(
+            split_path("/minsql-meta"),
+            ("minsql-meta".to_string(), None)
+        );
+        assert_eq!(
+            split_path("/minsql-meta/"),
+            ("minsql-meta".to_string(), None)
+        );
+        assert_eq!(
+            split_path("/logs/minsql/team%2Fservice/2019/7/1/10/a%20b.log"),
+            (
+                "logs".to_string(),
+                Some("minsql/team/service/2019/7/1/10/a b.log".to_string())
+            )
+        );
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn put_get_list_delete() {
+        let stub = Arc::new(S3Stub::default());
+        for key in &["mylog/1.log", "mylog/2.log", "otherlog/1.log"] {
+            let uri = format!("/logs/minsql/{}", key);
+            let response = handle(Arc::clone(&stub), request(Method::PUT, &uri, key))
+                .wait()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = handle(
+            Arc::clone(&stub),
+            request(Method::GET, "/logs/minsql/mylog/1.log", ""),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(
+            response.headers()["x-amz-meta-minsql-received-from"],
+            "2019-07-01T10:00:00Z"
+        );
+        assert_eq!(body_of(response), "mylog/1.log");
+
+        let response = handle(
+            Arc::clone(&stub),
+            request(Method::GET, "/logs?prefix=minsql%2Fmylog%2F&max-keys=1", ""),
+        )
+        .wait()
+        .unwrap();
+        let listing = body_of(response);
+        assert!(listing.contains("<Key>minsql/mylog/1.log</Key>"));
+        assert!(!listing.contains("<Key>minsql/mylog/2.log</Key>"));
+        assert!(listing.contains("<IsTruncated>true</IsTruncated>"));
+        let response = handle(
+            Arc::clone(&stub),
+            request(
+                Method::GET,
+                "/logs?prefix=minsql%2Fmylog%2F&marker=minsql%2Fmylog%2F1.log",
+                "",
+            ),
+        )
+        .wait()
+        .unwrap();
+        let listing = body_of(response);
+        assert!(listing.contains("<Key>minsql/mylog/2.log</Key>"));
+        assert!(listing.contains("<IsTruncated>false</IsTruncated>"));
+
+        handle(
+            Arc::clone(&stub),
+            request(Method::DELETE, "/logs/minsql/mylog/1.log", ""),
+        )
+        .wait()
+        .unwrap();
+        let response = handle(
+            Arc::clone(&stub),
+            request(Method::GET, "/logs/minsql/mylog/1.log", ""),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn no_object_lock() {
+        let stub = Arc::new(S3Stub::default());
+        let response = handle(
+            Arc::clone(&stub),
+            request(Method::GET, "/logs?object-lock", ""),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn ranged_gets() {
+        let stub = Arc::new(S3Stub::default());
+        handle(
+            Arc::clone(&stub),
+            request(Method::PUT, "/logs/minsql/mylog/1.log", "line 1\nline 2\n"),
+        )
+        .wait()
+        .unwrap();
+        let get = |range: &str| {
+            let mut req = request(Method::GET, "/logs/minsql/mylog/1.log", "");
+            req.headers_mut()
+                .insert(header::RANGE, range.parse().unwrap());
+            handle(Arc::clone(&stub), req).wait().unwrap()
+        };
+        // the first bytes, to detect the compression
+        let response = get("bytes=0-3");
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-3/14");
+        assert_eq!(body_of(response), "line");
+        // resuming a read from an offset
+        let response = get("bytes=6-");
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 6-13/14");
+        assert_eq!(body_of(response), "\nline 2\n");
+        assert_eq!(body_of(get("bytes=-2")), "2\n");
+        assert_eq!(body_of(get("bytes=7-100")), "line 2\n");
+        let response = get("bytes=14-");
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */14");
+    }
+
+    #[test]
+    fn ingest_and_search() {
+        let token = "STUBTOKEN0000000STUBSECRET0000000000000000000000";
+        let addr = start().unwrap();
+        let mut cfg = Config::new(Server::default());
+        cfg.datastore.insert(
+            "ds1".to_string(),
+            DataStore {
+                name: Some("ds1".to_string()),
+                endpoint: format!("http://{}", addr),
+                access_key: TEST_BACKEND_ACCESS_KEY.to_string(),
+                secret_key: TEST_BACKEND_SECRET_KEY.to_string(),
+                bucket: "logs".to_string(),
+                prefix: "".to_string(),
+                max_upload_bytes_per_sec: None,
+                max_download_bytes_per_sec: None,
+            },
+        );
+        cfg.log.insert(
+            "mylog".to_string(),
+            Log {
+                name: Some("mylog".to_string()),
+                datastores: vec!["ds1".to_string()],
+                commit_window: "0".to_string(),
+                ..Default::default()
+            },
+        );
+        let mut log_auth = HashMap::new();
+        log_auth.insert(
+            "mylog".to_string(),
+            LogAuth {
+                log_name: "mylog".to_string(),
+                api: Vec::new(),
+                expire: "".to_string(),
+                status: "".to_string(),
+            },
+        );
+        cfg.auth.insert(token[0..16].to_string(), log_auth);
+        cfg.tokens.insert(
+            token[0..16].to_string(),
+            Token {
+                access_key: token[0..16].to_string(),
+                secret_key: token[16..48].to_string(),
+                description: None,
+                is_admin: false,
+                enabled: true,
+                api_access: false,
+                ip_allowlist: Vec::new(),
+                redact: Vec::new(),
+            },
+        );
+        let cfg = Arc::new(RwLock::new(cfg));
+        let buffers = Arc::new(IngestBuffers::new());
+        let mut runtime = Runtime::new().unwrap();
+
+        // a log without a commit window writes its lines to the stub right away
+        let stored = runtime
+            .block_on(Ingest::new(Arc::clone(&cfg)).store_payload(
+                b"GET /index.html 200\nGET /login 404\n",
+                Arc::clone(&buffers),
+                "mylog".to_string(),
+                token,
+                None,
+                true,
+                None,
+            ))
+            .unwrap();
+        assert!(stored.status().is_success());
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/search")
+            .body(Body::from("SELECT * FROM mylog"))
+            .unwrap();
+        let response = runtime
+            .block_on(Query::new(Arc::clone(&cfg)).api_log_search(req, &token.to_string(), buffers))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = runtime.block_on(response.into_body().concat2()).unwrap();
+        let rows = String::from_utf8(body.to_vec()).unwrap();
+        assert!(rows.contains("GET /index.html 200"));
+        assert!(rows.contains("GET /login 404"));
+    }
+}
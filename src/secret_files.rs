@@ -0,0 +1,199 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use log::{error, info};
+use native_tls::Identity;
+use tokio::timer::Interval;
+
+use crate::config::Config;
+use crate::constants::SECRET_FILES_POLL_SECS;
+use crate::supervisor;
+
+/// Reads a secret mounted as a file, ie: a Kubernetes secret, without the trailing line break
+/// editors and `kubectl create secret --from-file` leave behind
+pub fn read_secret_file(path: &str) -> Result<String, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let secret = contents.trim_end_matches(|c| c == '\n' || c == '\r');
+    if secret.is_empty() {
+        return Err(format!("{} is empty", path));
+    }
+    Ok(secret.to_string())
+}
+
+/// The TLS acceptor of a PKCS12 certificate and its password
+pub fn tls_acceptor(der: &[u8], password: &str) -> Result<tokio_tls::TlsAcceptor, String> {
+    let identity = Identity::from_pkcs12(der, password)
+        .map_err(|e| format!("Could not unlock the PKCS12 certificate: {}", e))?;
+    native_tls::TlsAcceptor::builder(identity)
+        .build()
+        .map(tokio_tls::TlsAcceptor::from)
+        .map_err(|e| format!("Could not use the PKCS12 certificate: {}", e))
+}
+
+/// A PKCS12 certificate along with its password
+type Certificate = (Vec<u8>, String);
+
+/// Starts the task reading the secrets set with a `_FILE` environment variable again once in a
+/// while, so rotated secrets are picked up without a restart. With TLS, the certificate is
+/// loaded again when either it or its password changes, new connections are accepted with it.
+pub fn start_secret_files_task(
+    cfg: Arc<RwLock<Config>>,
+    tls: Option<Arc<RwLock<tokio_tls::TlsAcceptor>>>,
+) {
+    let watched = {
+        let read_cfg = cfg.read().unwrap();
+        let files = &read_cfg.server.secret_files;
+        files.access_key.is_some() || files.secret_key.is_some() || files.pkcs12_password.is_some()
+    };
+    if !watched && tls.is_none() {
+        return;
+    }
+    // the certificate the server started with
+    let started = match &tls {
+        Some(_) => current_certificate(&cfg).map(|(_, der, password)| (der, password)),
+        None => None,
+    };
+    supervisor::spawn_supervised("Secret files task".to_string(), move || {
+        let cfg = Arc::clone(&cfg);
+        let tls = tls.clone();
+        // the certificate the acceptor was last built with, or failed to be
+        let mut seen = started.clone();
+        let every = Duration::from_secs(SECRET_FILES_POLL_SECS);
+        Interval::new(Instant::now() + every, every)
+            .map_err(|e| error!("secret files interval errored; err={:?}", e))
+            .for_each(move |_| {
+                reload_secrets(&cfg);
+                if let Some(tls) = &tls {
+                    reload_certificate(&cfg, tls, &mut seen);
+                }
+                Ok(())
+            })
+    });
+}
+
+/// Sets the secrets whose file changed on the configuration, the metabucket is reached with
+/// them from then on
+fn reload_secrets(cfg: &Arc<RwLock<Config>>) {
+    let files = cfg.read().unwrap().server.secret_files.clone();
+    let read = |path: &Option<String>| match path {
+        Some(path) => match read_secret_file(path) {
+            Ok(secret) => Some(secret),
+            Err(e) => {
+                // the file may be in the middle of being replaced, the secret is kept until then
+                error!("Could not read secret file {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let access_key = read(&files.access_key);
+    let secret_key = read(&files.secret_key);
+    let pkcs12_password = read(&files.pkcs12_password);
+    let mut cfg_write = cfg.write().unwrap();
+    let server = &mut cfg_write.server;
+    if let Some(access_key) = access_key {
+        if access_key != server.access_key {
+            info!(
+                "Metabucket access key changed on {}",
+                files.access_key.unwrap()
+            );
+            server.access_key = access_key;
+        }
+    }
+    if let Some(secret_key) = secret_key {
+        if secret_key != server.secret_key {
+            info!(
+                "Metabucket secret key changed on {}",
+                files.secret_key.unwrap()
+            );
+            server.secret_key = secret_key;
+        }
+    }
+    if let Some(pkcs12_password) = pkcs12_password {
+        if server.pkcs12_password.as_ref() != Some(&pkcs12_password) {
+            info!(
+                "PKCS12 password changed on {}",
+                files.pkcs12_password.unwrap()
+            );
+            server.pkcs12_password = Some(pkcs12_password);
+        }
+    }
+}
+
+/// Builds the acceptor again if the certificate or its password changed. A certificate that
+/// can't be used, ie: replaced ahead of its password, leaves the current one in place.
+fn reload_certificate(
+    cfg: &Arc<RwLock<Config>>,
+    tls: &Arc<RwLock<tokio_tls::TlsAcceptor>>,
+    seen: &mut Option<Certificate>,
+) {
+    let (cert, der, password) = match current_certificate(cfg) {
+        Some(certificate) => certificate,
+        None => return,
+    };
+    let current = (der, password);
+    if seen.as_ref() == Some(&current) {
+        return;
+    }
+    let acceptor = tls_acceptor(&current.0, &current.1);
+    *seen = Some(current);
+    match acceptor {
+        Ok(acceptor) => {
+            *tls.write().unwrap() = acceptor;
+            info!("Loaded PKCS12 certificate {} again", cert);
+        }
+        Err(e) => error!("{}, keeping the current one", e),
+    }
+}
+
+/// The path and contents of the PKCS12 certificate along with its password
+fn current_certificate(cfg: &Arc<RwLock<Config>>) -> Option<(String, Vec<u8>, String)> {
+    let read_cfg = cfg.read().unwrap();
+    let cert = read_cfg.server.pkcs12_cert.clone()?;
+    let password = read_cfg.server.pkcs12_password.clone()?;
+    drop(read_cfg);
+    match fs::read(&cert) {
+        Ok(der) => Some((cert, der, password)),
+        Err(e) => {
+            error!("Could not read PKCS12 certificate {}: {}", cert, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod secret_files_tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn secret_files_are_trimmed() {
+        let path = env::temp_dir().join(format!("minsql-secret-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "minio123\r\n").unwrap();
+        assert_eq!(read_secret_file(&path), Ok("minio123".to_string()));
+        fs::write(&path, "\n").unwrap();
+        assert!(read_secret_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(read_secret_file(&path).is_err());
+    }
+}
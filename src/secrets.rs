@@ -0,0 +1,115 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::iter;
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::constants::{SECRET_HASH_PREFIX, SECRET_SALT_LEN};
+
+/// Hashes a token secret with a random salt, as `$sha256$<salt>$<hex digest>`. Token secrets
+/// are 32 random characters, so a single round of a salted hash is as good as a slow one.
+pub fn hash_secret(secret: &str) -> String {
+    let mut rng = thread_rng();
+    let salt = iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .take(SECRET_SALT_LEN)
+        .collect::<String>();
+    format!("{}{}${}", SECRET_HASH_PREFIX, salt, digest(&salt, secret))
+}
+
+/// Whether a stored secret is a hash made by `hash_secret`, else it's a legacy plaintext secret
+pub fn is_hashed(stored: &str) -> bool {
+    split_hash(stored).is_some()
+}
+
+/// Whether the candidate matches the stored secret, either hashed or plaintext, comparing in
+/// constant time
+pub fn verify_secret(stored: &str, candidate: &str) -> bool {
+    match split_hash(stored) {
+        Some((salt, expected)) => {
+            constant_time_eq(expected.as_bytes(), digest(salt, candidate).as_bytes())
+        }
+        None => constant_time_eq(stored.as_bytes(), candidate.as_bytes()),
+    }
+}
+
+/// The salt and the digest of a hashed secret
+fn split_hash(stored: &str) -> Option<(&str, &str)> {
+    if !stored.starts_with(SECRET_HASH_PREFIX) {
+        return None;
+    }
+    let mut parts = stored[SECRET_HASH_PREFIX.len()..].splitn(2, '$');
+    match (parts.next(), parts.next()) {
+        (Some(salt), Some(hex)) if salt.len() == SECRET_SALT_LEN && hex.len() == 64 => {
+            Some((salt, hex))
+        }
+        _ => None,
+    }
+}
+
+fn digest(salt: &str, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(salt.as_bytes());
+    hasher.input(secret.as_bytes());
+    hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compares without returning early on the first difference, so the time taken doesn't tell how
+/// much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod secrets_tests {
+    use super::*;
+
+    #[test]
+    fn hashed_secrets_verify() {
+        let secret = "abcdefghijklmnopqrstuvwxyz012345";
+        let hashed = hash_secret(secret);
+        assert!(is_hashed(&hashed));
+        assert!(!hashed.contains(secret));
+        assert!(verify_secret(&hashed, secret));
+        assert!(!verify_secret(&hashed, "abcdefghijklmnopqrstuvwxyz012346"));
+        // every hash is salted differently
+        assert_ne!(hashed, hash_secret(secret));
+    }
+
+    #[test]
+    fn legacy_plaintext_secrets_verify() {
+        let secret = "abcdefghijklmnopqrstuvwxyz012345";
+        assert!(!is_hashed(secret));
+        assert!(verify_secret(secret, secret));
+        assert!(!verify_secret(secret, "abcdefghijklmnopqrstuvwxyz01234"));
+        assert!(!verify_secret(secret, ""));
+        // looking like a hash isn't enough
+        assert!(!is_hashed("$sha256$short$abc"));
+    }
+}
@@ -0,0 +1,224 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::constants::SIGNING_ALGORITHM;
+
+/// Running digest of the output of a query, in the order it's sent, and count of its rows
+pub struct ResultsDigest {
+    hasher: Sha256,
+    rows: u64,
+}
+
+impl ResultsDigest {
+    pub fn new() -> ResultsDigest {
+        ResultsDigest {
+            hasher: Sha256::new(),
+            rows: 0,
+        }
+    }
+
+    /// Accounts output as it's sent, rows as well as the progress events, headers and trailers
+    /// around them, so none can be added or removed without the digest changing
+    pub fn add_output(&mut self, output: &[u8]) {
+        self.hasher.input(output);
+    }
+
+    pub fn add_rows(&mut self, rows: u64) {
+        self.rows += rows;
+    }
+
+    /// Signs the output seen so far along with the query and the time they were generated at
+    pub fn sign(&self, key: &str, query: &str, generated_at: &str) -> Manifest {
+        let mut manifest = Manifest {
+            algorithm: SIGNING_ALGORITHM.to_string(),
+            query: query.to_string(),
+            generated_at: generated_at.to_string(),
+            rows: self.rows,
+            digest: hex(&self.hasher.clone().result()),
+            signature: String::new(),
+        };
+        manifest.signature = hex(&mac(key, &manifest).result().code());
+        manifest
+    }
+}
+
+/// Trailer of a signed search, what auditors check the results against. It's sent as the last
+/// line of the results, as `{"$manifest": {...}}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    pub algorithm: String,
+    pub query: String,
+    pub generated_at: String,
+    pub rows: u64,
+    // hex SHA-256 of the whole output before the manifest line
+    pub digest: String,
+    // hex HMAC-SHA256 of the other fields, see `signed_payload`
+    pub signature: String,
+}
+
+impl Manifest {
+    /// Whether the manifest was signed with the key and none of its fields changed since
+    pub fn verify(&self, key: &str) -> bool {
+        let signature = match unhex(&self.signature) {
+            Some(signature) => signature,
+            None => return false,
+        };
+        // `verify` compares in constant time
+        mac(key, self).verify(&signature).is_ok()
+    }
+}
+
+/// Checks the whole output of a signed search, everything it sent up to and including its
+/// manifest line, against the key. Nothing is left out of the digest, so lines can't be added
+/// or removed by passing them for progress events.
+pub fn verify_results(key: &str, output: &str) -> Result<Manifest, String> {
+    let trimmed = output.trim_end_matches('\n');
+    let (before, manifest_line) = match trimmed.rfind('\n') {
+        Some(i) => (&trimmed[..=i], &trimmed[i + 1..]),
+        None => ("", trimmed),
+    };
+    if manifest_line.is_empty() {
+        return Err("The results have no manifest".to_string());
+    }
+    let manifest = match serde_json::from_str::<ManifestLine>(manifest_line) {
+        Ok(line) => line.manifest,
+        Err(_) => return Err("The last line of the results is not a manifest".to_string()),
+    };
+    if !manifest.verify(key) {
+        return Err("The manifest signature is not valid".to_string());
+    }
+    let mut digest = ResultsDigest::new();
+    digest.add_output(before.as_bytes());
+    digest.add_rows(manifest.rows);
+    let expected = digest.sign(key, &manifest.query, &manifest.generated_at);
+    if expected.digest != manifest.digest {
+        return Err("The results don't match the manifest".to_string());
+    }
+    Ok(manifest)
+}
+
+/// The trailer line of a signed search
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestLine {
+    #[serde(rename = "$manifest")]
+    pub manifest: Manifest,
+}
+
+/// The fields of a manifest that are signed, each prefixed with its length, ie: `5:query`, so
+/// no two different manifests sign the same payload whatever their fields hold
+fn signed_payload(manifest: &Manifest) -> String {
+    [
+        manifest.algorithm.clone(),
+        manifest.query.clone(),
+        manifest.generated_at.clone(),
+        manifest.rows.to_string(),
+        manifest.digest.clone(),
+    ]
+    .iter()
+    .map(|field| format!("{}:{}", field.len(), field))
+    .collect()
+}
+
+fn mac(key: &str, manifest: &Manifest) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).unwrap();
+    mac.input(signed_payload(manifest).as_bytes());
+    mac
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod signing_tests {
+    use super::*;
+
+    fn signed(output: &str, rows: u64) -> Manifest {
+        let mut digest = ResultsDigest::new();
+        digest.add_output(output.as_bytes());
+        digest.add_rows(rows);
+        digest.sign("signingkey", "SELECT * FROM mylog", "2019-07-01T00:00:00Z")
+    }
+
+    #[test]
+    fn manifests_verify() {
+        let manifest = signed("line 1\nline 2\n", 2);
+        assert_eq!(manifest.rows, 2);
+        assert!(manifest.verify("signingkey"));
+        assert!(!manifest.verify("otherkey"));
+        // the same output gives the same digest
+        assert_eq!(manifest.digest, signed("line 1\nline 2\n", 2).digest);
+    }
+
+    #[test]
+    fn tampered_manifests_fail() {
+        let mut manifest = signed("line 1\nline 2\n", 2);
+        manifest.digest = signed("line 1\n", 1).digest;
+        assert!(!manifest.verify("signingkey"));
+        let mut manifest = signed("line 1\nline 2\n", 2);
+        manifest.query = "SELECT * FROM otherlog".to_string();
+        assert!(!manifest.verify("signingkey"));
+        let mut manifest = signed("line 1\nline 2\n", 2);
+        manifest.signature = "zz".to_string();
+        assert!(!manifest.verify("signingkey"));
+    }
+
+    #[test]
+    fn fields_cannot_shift_into_each_other() {
+        let mut manifest = signed("line 1\n", 1);
+        manifest.query = "SELECT * FROM mylog\n2019".to_string();
+        manifest.generated_at = "07-01T00:00:00Z".to_string();
+        let shifted = signed_payload(&manifest);
+        manifest.query = "SELECT * FROM mylog".to_string();
+        manifest.generated_at = "2019\n07-01T00:00:00Z".to_string();
+        assert_ne!(signed_payload(&manifest), shifted);
+    }
+
+    #[test]
+    fn verify_whole_results() {
+        let before = "line 1\n{\"$progress\": {\"done\": false}}\nline 2\n";
+        let line = ManifestLine {
+            manifest: signed(before, 2),
+        };
+        let manifest = serde_json::to_string(&line).unwrap();
+        let output = format!("{}{}\n", before, manifest);
+        assert_eq!(verify_results("signingkey", &output), Ok(line.manifest));
+        // progress events are part of the digest, they can't be added or removed either
+        let output = format!("line 1\nline 2\n{}\n", manifest);
+        assert!(verify_results("signingkey", &output).is_err());
+        let output = format!("{}{{\"$progress\": {{}}}}\n{}\n", before, manifest);
+        assert!(verify_results("signingkey", &output).is_err());
+        let output = format!("line 1\nline 3\n{}\n", manifest);
+        assert!(verify_results("signingkey", &output).is_err());
+        assert!(verify_results("signingkey", "line 1\nline 2\n").is_err());
+        assert!(verify_results("signingkey", "").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+
+use crate::config::{Config, OutputSinkConfig};
+use crate::constants::{DEFAULT_PARTITION, SINK_LOG, SINK_S3, SINK_WEBHOOK};
+use crate::naming::ObjectNaming;
+use crate::storage::{put_object, write_to_datastore};
+use crate::webhook::{self, valid_webhook_url};
+
+pub type SinkFuture = Box<dyn Future<Item = (), Error = String> + Send>;
+
+/// Somewhere the outputs of a scheduled task, such as a daily report, are delivered to. `name`
+/// tells one output from another, ie: `mylog/2019-08-01`.
+pub trait OutputSink: Send + Sync {
+    fn deliver(&self, cfg: Arc<RwLock<Config>>, name: &str, body: String) -> SinkFuture;
+}
+
+/// POSTs every output to a url
+pub struct WebhookSink {
+    url: String,
+}
+
+impl OutputSink for WebhookSink {
+    fn deliver(&self, _cfg: Arc<RwLock<Config>>, _name: &str, body: String) -> SinkFuture {
+        Box::new(webhook::post_json(&self.url, body))
+    }
+}
+
+/// Writes every output as a `{prefix}{name}.json` object on a datastore
+pub struct S3Sink {
+    datastore: String,
+    prefix: String,
+}
+
+impl OutputSink for S3Sink {
+    fn deliver(&self, cfg: Arc<RwLock<Config>>, name: &str, body: String) -> SinkFuture {
+        let datastore = match cfg.read().unwrap().datastore.get(&self.datastore) {
+            Some(datastore) => datastore.clone(),
+            None => {
+                return Box::new(future::err(format!(
+                    "Sink datastore {} does not exist",
+                    self.datastore
+                )))
+            }
+        };
+        let key = format!("{}{}.json", self.prefix, name);
+        Box::new(
+            put_object(&datastore, key.clone(), body.into_bytes(), None)
+                .map_err(move |e| format!("Could not write {}: {}", key, e.reason())),
+        )
+    }
+}
+
+/// Stores every output as a line on a MinSQL log, so it can be searched like any other line.
+/// The line is committed right away, regardless of the commit window of the log.
+pub struct LogSink {
+    log: String,
+}
+
+impl OutputSink for LogSink {
+    fn deliver(&self, cfg: Arc<RwLock<Config>>, _name: &str, body: String) -> SinkFuture {
+        let partition = {
+            let read_cfg = cfg.read().unwrap();
+            let stored = |ds_name: &String| read_cfg.datastore.contains_key(ds_name);
+            match read_cfg.log.get(&self.log) {
+                Some(log) if !log.datastores.is_empty() && log.datastores.iter().all(stored) => {
+                    if ObjectNaming::for_log(log).has_partition() {
+                        Some(DEFAULT_PARTITION)
+                    } else {
+                        None
+                    }
+                }
+                _ => {
+                    return Box::new(future::err(format!(
+                        "Sink log {} does not exist or has no datastore to write to",
+                        self.log
+                    )))
+                }
+            }
+        };
+        let line = body.replace('\n', " ") + "\n";
+        let len = line.len() as i64;
+        let log_name = self.log.clone();
+        Box::new(
+            write_to_datastore(cfg, &self.log, vec![line], partition, len, None)
+                .map(|_| ())
+                .map_err(move |e| format!("Could not store on {}: {}", log_name, e.reason())),
+        )
+    }
+}
+
+/// Checks the settings a sink of its kind needs are there and valid
+pub fn validate_sink(config: &OutputSinkConfig) -> Result<(), String> {
+    match &config.kind[..] {
+        SINK_WEBHOOK => match &config.url {
+            Some(url) if valid_webhook_url(url) => Ok(()),
+            _ => Err("A webhook sink needs an http or https `url`".to_string()),
+        },
+        SINK_S3 => match &config.datastore {
+            Some(datastore) if !datastore.is_empty() => Ok(()),
+            _ => Err("An s3 sink needs a `datastore`".to_string()),
+        },
+        SINK_LOG => match &config.log {
+            Some(log) if !log.is_empty() => Ok(()),
+            _ => Err("A log sink needs a `log`".to_string()),
+        },
+        _ => Err("Sink type must be `webhook`, `s3` or `log`".to_string()),
+    }
+}
+
+/// The sink a configuration describes
+pub fn sink_for(config: &OutputSinkConfig) -> Result<Box<dyn OutputSink>, String> {
+    validate_sink(config)?;
+    Ok(match &config.kind[..] {
+        SINK_WEBHOOK => Box::new(WebhookSink {
+            url: config.url.clone().unwrap(),
+        }),
+        SINK_S3 => Box::new(S3Sink {
+            datastore: config.datastore.clone().unwrap(),
+            prefix: config.prefix.clone().unwrap_or_default(),
+        }),
+        _ => Box::new(LogSink {
+            log: config.log.clone().unwrap(),
+        }),
+    })
+}
+
+/// A webhook sink, for the outputs configured with a plain url
+pub fn webhook_sink(url: &str) -> Box<dyn OutputSink> {
+    Box::new(WebhookSink {
+        url: url.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod sinks_tests {
+    use super::*;
+
+    fn sink(kind: &str) -> OutputSinkConfig {
+        OutputSinkConfig {
+            kind: kind.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sink_settings() {
+        assert!(validate_sink(&sink("webhook")).is_err());
+        assert!(validate_sink(&OutputSinkConfig {
+            url: Some("https://hooks.example.com/minsql".to_string()),
+            ..sink("webhook")
+        })
+        .is_ok());
+        assert!(validate_sink(&OutputSinkConfig {
+            url: Some("ftp://example.com/report".to_string()),
+            ..sink("webhook")
+        })
+        .is_err());
+        assert!(validate_sink(&sink("s3")).is_err());
+        assert!(validate_sink(&OutputSinkConfig {
+            datastore: Some("ds1".to_string()),
+            ..sink("s3")
+        })
+        .is_ok());
+        assert!(validate_sink(&OutputSinkConfig {
+            log: Some("".to_string()),
+            ..sink("log")
+        })
+        .is_err());
+        assert!(sink_for(&OutputSinkConfig {
+            log: Some("rollups".to_string()),
+            ..sink("log")
+        })
+        .is_ok());
+        assert!(sink_for(&sink("kafka")).is_err());
+    }
+}
@@ -14,18 +14,25 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::str;
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
-use chrono::{Datelike, Timelike, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use futures::future::result;
+use futures::future::Either;
 use futures::future::FutureResult;
+use futures::future::Loop;
 use futures::Poll;
-use futures::{stream, Future, Stream};
-use log::error;
+use futures::{future, stream, Future, Stream};
+use lazy_static::lazy_static;
+use log::{error, warn};
 use rand::Rng;
+use rusoto_core::ByteStream;
 use rusoto_core::HttpClient;
 use rusoto_core::Region;
 use rusoto_core::RusotoError;
@@ -33,15 +40,39 @@ use rusoto_credential::AwsCredentials;
 use rusoto_credential::CredentialsError;
 use rusoto_credential::ProvideAwsCredentials;
 use rusoto_s3::{
-    DeleteObjectOutput, DeleteObjectRequest, GetObjectRequest, ListObjectsRequest, PutObjectOutput,
-    PutObjectRequest, S3Client, S3,
+    DeleteObjectOutput, DeleteObjectRequest, GetObjectLockConfigurationRequest, GetObjectRequest,
+    HeadObjectRequest, ListObjectsRequest, PutObjectOutput, PutObjectRequest, S3Client, S3,
 };
-use tokio_codec::{FramedRead, LinesCodec};
+use serde_derive::Serialize;
+use tokio_codec::{Decoder, FramedRead};
 use uuid::Uuid;
 
+use crate::bloom::{bloom_key, logical_lines, BloomFilter};
+use crate::caches::Cache;
+use crate::compression::{decompress, Compression};
+use crate::concurrency::ConcurrencyLimit;
 use crate::config::{Config, DataStore};
+use crate::constants::{
+    DEFAULT_MAX_CONCURRENT_LISTINGS, DEFAULT_OBJECT_KEY, ENCODING_BASE64, ENCODING_METADATA,
+    OBJECT_LOCK_MODE, RECEIVED_FIRST_METADATA, RECEIVED_LAST_METADATA,
+};
+#[cfg(feature = "fault-injection")]
+use crate::faults;
+use crate::history::record_config_change;
+use crate::latency::{record_datastore_error, record_get_latency};
 use crate::meta::ds_for_metabucket;
-use bytes::Bytes;
+use crate::naming::ObjectNaming;
+use crate::query::decode_lines;
+use crate::throttle::{throttle_download, throttle_upload};
+use bytes::{Bytes, BytesMut};
+
+lazy_static! {
+    // Whether object lock is enabled, by datastore endpoint and bucket
+    pub static ref OBJECT_LOCK_BUCKETS: Cache<bool> = Cache::new("object_lock_buckets");
+    // Listing calls in flight across every datastore
+    static ref LISTING_LIMIT: ConcurrencyLimit =
+        ConcurrencyLimit::new(DEFAULT_MAX_CONCURRENT_LISTINGS);
+}
 
 #[derive(Debug)]
 pub enum StorageError<E> {
@@ -75,6 +106,20 @@ where
     }
 }
 
+impl<T> StorageError<T>
+where
+    T: fmt::Display,
+{
+    /// What went wrong, to be reported back to the client
+    pub fn reason(&self) -> String {
+        match self {
+            StorageError::Operation(e) => e.to_string(),
+            StorageError::Validation(e) => e.clone(),
+            StorageError::Unhandled => "Unhandled storage error".to_string(),
+        }
+    }
+}
+
 impl<T> Error for StorageError<T>
 where
     T: std::fmt::Debug,
@@ -121,6 +166,28 @@ impl ProvideAwsCredentials for CustomCredentialsProvider {
     }
 }
 
+/// Fault injected before an operation on a datastore, fails as the datastore would
+#[cfg(feature = "fault-injection")]
+fn injected_fault<E>(ds_name: &str) -> impl Future<Item = (), Error = RusotoError<E>> {
+    faults::inject(ds_name).map_err(RusotoError::Validation)
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn injected_fault<E>(_: &str) -> FutureResult<(), RusotoError<E>> {
+    future::ok(())
+}
+
+/// Body of an object read from a datastore, cut short if faults are injected
+#[cfg(feature = "fault-injection")]
+fn read_body(ds_name: &str, body: ByteStream) -> ByteStream {
+    faults::truncate(ds_name, body)
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn read_body(_: &str, body: ByteStream) -> ByteStream {
+    body
+}
+
 pub fn client_for_datastore(datastore: &DataStore) -> S3Client {
     // Create a credentials holder, for our provider to provide into the s3 client
     let credentials = AwsCredentials::new(
@@ -184,60 +251,401 @@ pub enum PutObjectError {
     Write(String),
 }
 
+impl fmt::Display for PutObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PutObjectError::Write(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Location of an object written for a log
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredObject {
+    pub datastore: String,
+    pub key: String,
+}
+
+/// Writes the payload as a new object of the log on one of its datastores, returning where the
+/// object was stored. Replicated logs have the object written under the same key on every one of
+/// their datastores, the write fails unless they all stored it.
 pub fn write_to_datastore(
     cfg: Arc<RwLock<Config>>,
     log_name: &str,
     payload: Vec<String>,
+    partition: Option<&str>,
     length: i64,
-) -> impl Future<Item = (), Error = StorageError<PutObjectError>> {
+    mut metadata: Option<HashMap<String, String>>,
+) -> impl Future<Item = StoredObject, Error = StorageError<PutObjectError>> {
     let start = Instant::now();
     let read_cfg = cfg.read().unwrap();
-    // Select a datastore at random to write to
-    let datastore = rand_datastore(&read_cfg, &log_name).unwrap();
+    // Select a datastore at random to write to, or all of them for replicated logs
+    let datastores: Vec<DataStore> = match read_cfg.log.get(log_name) {
+        Some(log) if log.replicated => log
+            .datastores
+            .iter()
+            .filter_map(|ds_name| read_cfg.datastore.get(ds_name))
+            .cloned()
+            .collect(),
+        _ => vec![rand_datastore(&read_cfg, &log_name).unwrap().clone()],
+    };
+    // Prepare the name of the object, as the log lays them out
+    let now = Utc::now();
+    let destination = match read_cfg.log.get(log_name) {
+        Some(log) => ObjectNaming::for_log(log),
+        None => ObjectNaming::parse(DEFAULT_OBJECT_KEY).unwrap(),
+    }
+    .in_partition(partition)
+    .key(log_name, &now, &Uuid::new_v4());
+    let stored = StoredObject {
+        datastore: datastores[0].name.clone().unwrap_or_default(),
+        key: destination.clone(),
+    };
+    // the object records the lines are wrapped in base64, so they're unwrapped when read
+    let base64_lines = read_cfg
+        .log
+        .get(log_name)
+        .and_then(|log| log.encoding.as_ref())
+        .map(|encoding| encoding.as_str())
+        == Some(ENCODING_BASE64);
+    if base64_lines {
+        metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(ENCODING_METADATA.to_string(), ENCODING_BASE64.to_string());
+    }
+    // index the lines of the payload before the body takes it, if the log wants bloom filters,
+    // as they were sent so they're found by what queries look for
+    let bloom = match read_cfg.log.get(log_name) {
+        Some(log) if log.bloom_filters => {
+            let lines: Vec<String> = payload.iter().flat_map(|p| logical_lines(p)).collect();
+            let lines = if base64_lines {
+                decode_lines(lines)
+            } else {
+                lines
+            };
+            Some(BloomFilter::from_lines(&lines).to_bytes())
+        }
+        _ => None,
+    };
+    // logs with compression have their payload compressed whole, objects written before the
+    // setting changed are still read as they are
+    let compression = read_cfg.log.get(log_name).and_then(Compression::for_log);
+    let (chunks, length) = match compression {
+        Some(compression) => {
+            let body = compression
+                .compress(payload.concat().as_bytes())
+                .expect("compressing to memory doesn't fail");
+            let length = body.len() as i64;
+            (vec![Bytes::from(body)], length)
+        }
+        None => (
+            payload
+                .into_iter()
+                .map(|s| Bytes::from(s.into_bytes()))
+                .collect::<Vec<Bytes>>(),
+            length,
+        ),
+    };
+    // objects of a log under legal hold are retained by the datastore too, if it supports it
+    let retain_until = read_cfg
+        .log
+        .get(log_name)
+        .and_then(|log| log.held_until(&now));
+    // object lock requests need the MD5 of the body
+    let content_md5 = retain_until.map(|_| {
+        let mut context = md5::Context::new();
+        for chunk in &chunks {
+            context.consume(&chunk[..]);
+        }
+        base64::encode(&context.compute().0)
+    });
+    let upload = LogObjectUpload {
+        destination,
+        chunks,
+        length,
+        metadata,
+        bloom,
+        retain_until,
+        content_md5,
+    };
+    let writes = datastores
+        .into_iter()
+        .map(move |datastore| put_log_object(datastore, upload.clone()));
+    future::join_all(writes).map(move |_| {
+        //TODO: Remove this metric
+        let duration = start.elapsed();
+        println!("Writing to minio: {:?}", duration);
+        stored
+    })
+}
+
+/// An object of a log ready to be put on its datastores
+#[derive(Clone)]
+struct LogObjectUpload {
+    destination: String,
+    chunks: Vec<Bytes>,
+    length: i64,
+    metadata: Option<HashMap<String, String>>,
+    // serialized bloom filter of the lines, for logs with bloom filters
+    bloom: Option<Vec<u8>>,
+    // the datastore retains the object until then, if it has object lock enabled
+    retain_until: Option<DateTime<Utc>>,
+    content_md5: Option<String>,
+}
+
+/// Puts an object of a log on a datastore, along with its bloom filter if there's one
+fn put_log_object(
+    datastore: DataStore,
+    upload: LogObjectUpload,
+) -> impl Future<Item = (), Error = StorageError<PutObjectError>> {
+    let LogObjectUpload {
+        destination,
+        chunks,
+        length,
+        metadata,
+        bloom,
+        retain_until,
+        content_md5,
+    } = upload;
     // Get the Object Storage client
     let s3_client = client_for_datastore(&datastore);
-    // Prepare the name of the log
-    let now = Utc::now();
-    let my_uuid = Uuid::new_v4();
-    let target_file = format!(
-        "{log}/{year}/{month}/{day}/{hour}/{ts}.log",
-        log = log_name,
-        year = now.date().year(),
-        month = now.date().month(),
-        day = now.date().day(),
-        hour = now.hour(),
-        ts = my_uuid
-    );
-    let destination = format!("minsql/{}", target_file);
+    let bloom_bucket = datastore.bucket.clone();
+    let bloom_datastore = datastore.clone();
+    let bloom_destination = bloom_key(&destination);
+    let lock_check = match retain_until {
+        Some(_) => Either::A(object_lock_enabled(&datastore)),
+        None => Either::B(future::ok(false)),
+    };
+    let bucket = datastore.bucket.clone();
+    let fault = injected_fault(&datastore.name.clone().unwrap_or_default());
+    let put_client = s3_client.clone();
     // turn the payload into a streaming body
-    let stream_of_bytes = stream::iter_ok(payload).map(|s| Bytes::from(s.into_bytes()));
-    let streaming_body = rusoto_s3::StreamingBody::new(stream_of_bytes);
+    let stream_of_bytes = stream::iter_ok(chunks);
+    let streaming_body = throttle_upload(&datastore, stream_of_bytes);
     // save the payload
+    lock_check
+        .then(move |enabled| {
+            let (lock_mode, content_md5, retain_until) = match (enabled, retain_until) {
+                (Ok(true), Some(until)) => (
+                    Some(OBJECT_LOCK_MODE.to_string()),
+                    content_md5,
+                    Some(until.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                ),
+                _ => (None, None, None),
+            };
+            fault.and_then(move |_| {
+                put_client.put_object(PutObjectRequest {
+                    bucket: bucket,
+                    key: destination,
+                    body: Some(streaming_body),
+                    content_length: Some(length),
+                    content_md5: content_md5,
+                    metadata: metadata,
+                    object_lock_mode: lock_mode,
+                    object_lock_retain_until_date: retain_until,
+                    ..Default::default()
+                })
+            })
+        })
+        .map_err(|e| {
+            StorageError::Operation(PutObjectError::Write(format!(
+                "Could not write to datastore: {}",
+                e
+            )))
+        })
+        .and_then(move |_| match bloom {
+            Some(bytes) => {
+                let len = bytes.len() as i64;
+                let stream_of_bytes = stream::iter_ok(vec![Bytes::from(bytes)]);
+                // the data is already stored, a missing filter only means the object is
+                // always read
+                let res = s3_client
+                    .put_object(PutObjectRequest {
+                        bucket: bloom_bucket,
+                        key: bloom_destination,
+                        body: Some(throttle_upload(&bloom_datastore, stream_of_bytes)),
+                        content_length: Some(len),
+                        ..Default::default()
+                    })
+                    .then(|res| {
+                        if let Err(e) = res {
+                            error!("Could not write bloom filter: {}", e);
+                        }
+                        Ok(())
+                    });
+                Either::A(res)
+            }
+            None => Either::B(future::ok(())),
+        })
+}
+
+/// Whether the bucket of a datastore has object lock enabled, asked once per bucket
+fn object_lock_enabled(datastore: &DataStore) -> impl Future<Item = bool, Error = ()> {
+    let bucket_key = format!("{}/{}", datastore.endpoint, datastore.bucket);
+    if let Some(enabled) = OBJECT_LOCK_BUCKETS.get(&bucket_key) {
+        return Either::A(future::ok(enabled));
+    }
+    let s3_client = client_for_datastore(datastore);
+    Either::B(
+        s3_client
+            .get_object_lock_configuration(GetObjectLockConfigurationRequest {
+                bucket: datastore.bucket.clone(),
+                ..Default::default()
+            })
+            .then(move |res| {
+                // buckets without object lock answer with an error
+                let enabled = match res {
+                    Ok(output) => output
+                        .object_lock_configuration
+                        .and_then(|c| c.object_lock_enabled)
+                        .map_or(false, |enabled| enabled == "Enabled"),
+                    Err(_) => false,
+                };
+                OBJECT_LOCK_BUCKETS.insert(bucket_key, enabled);
+                Ok(enabled)
+            }),
+    )
+}
+
+/// Reads the bloom filter stored for an object, `None` if the object has no valid filter
+pub fn read_bloom_filter(
+    key: &str,
+    datastore: &DataStore,
+) -> impl Future<Item = Option<BloomFilter>, Error = StorageError<GetObjectError>> {
+    let s3_client = client_for_datastore(datastore);
+    let read_datastore = datastore.clone();
     s3_client
-        .put_object(PutObjectRequest {
+        .get_object(GetObjectRequest {
             bucket: datastore.bucket.clone(),
-            key: destination,
-            body: Some(streaming_body),
-            content_length: Some(length),
+            key: bloom_key(key),
             ..Default::default()
         })
+        .then(move |res| match res {
+            Ok(object_output) => Either::A(
+                throttle_download(&read_datastore, object_output.body.unwrap())
+                    .concat2()
+                    .map(|body| BloomFilter::from_bytes(&body))
+                    .map_err(|e| {
+                        StorageError::Operation(GetObjectError::IOError(format!("{:?}", e)))
+                    }),
+            ),
+            Err(RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))) => {
+                Either::B(future::ok(None))
+            }
+            Err(e) => Either::B(future::err(StorageError::Operation(
+                GetObjectError::IOError(format!("{:?}", e)),
+            ))),
+        })
+}
+
+/// Reads the range of times the lines of an object were received at from its metadata, `None`
+/// if the object doesn't record it, ie: it was written before its log stamped lines
+pub fn read_received_range(
+    key: &str,
+    datastore: &DataStore,
+) -> impl Future<Item = Option<(DateTime<Utc>, DateTime<Utc>)>, Error = StorageError<GetObjectError>>
+{
+    let s3_client = client_for_datastore(datastore);
+    s3_client
+        .head_object(HeadObjectRequest {
+            bucket: datastore.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        })
+        .map(|object_output| {
+            let metadata = object_output.metadata?;
+            let received = |key: &str| {
+                DateTime::parse_from_rfc3339(metadata.get(key)?)
+                    .ok()
+                    .map(|time| time.with_timezone(&Utc))
+            };
+            Some((
+                received(RECEIVED_FIRST_METADATA)?,
+                received(RECEIVED_LAST_METADATA)?,
+            ))
+        })
+        .map_err(|e| StorageError::Operation(GetObjectError::IOError(format!("{:?}", e))))
+}
+
+/// Reads a whole object of a datastore along with its metadata
+pub fn get_object(
+    datastore: &DataStore,
+    key: String,
+) -> impl Future<Item = (Vec<u8>, Option<HashMap<String, String>>), Error = StorageError<GetObjectError>>
+{
+    let s3_client = client_for_datastore(datastore);
+    let read_datastore = datastore.clone();
+    let ds_name = datastore.name.clone().unwrap_or_default();
+    let bucket = datastore.bucket.clone();
+    injected_fault(&ds_name)
+        .and_then(move |_| {
+            s3_client.get_object(GetObjectRequest {
+                bucket,
+                key,
+                ..Default::default()
+            })
+        })
+        .map_err(StorageError::from)
+        .and_then(move |object_output| {
+            let metadata = object_output.metadata;
+            let body = read_body(&ds_name, object_output.body.unwrap());
+            throttle_download(&read_datastore, body)
+                .concat2()
+                .map(move |body| (body.to_vec(), metadata))
+                .map_err(|e| StorageError::Operation(GetObjectError::IOError(format!("{:?}", e))))
+        })
+}
+
+/// Writes a whole object to a datastore
+pub fn put_object(
+    datastore: &DataStore,
+    key: String,
+    body: Vec<u8>,
+    metadata: Option<HashMap<String, String>>,
+) -> impl Future<Item = (), Error = StorageError<PutObjectError>> {
+    let s3_client = client_for_datastore(datastore);
+    let len = body.len() as i64;
+    let stream_of_bytes = stream::iter_ok(vec![Bytes::from(body)]);
+    let streaming_body = throttle_upload(datastore, stream_of_bytes);
+    let bucket = datastore.bucket.clone();
+    injected_fault(&datastore.name.clone().unwrap_or_default())
+        .and_then(move |_| {
+            s3_client.put_object(PutObjectRequest {
+                bucket,
+                key,
+                body: Some(streaming_body),
+                content_length: Some(len),
+                metadata,
+                ..Default::default()
+            })
+        })
         .map_err(|e| {
             StorageError::Operation(PutObjectError::Write(format!(
                 "Could not write to datastore: {}",
                 e
             )))
         })
-        .map(move |_| {
-            //TODO: Remove this metric
-            let duration = start.elapsed();
-            println!("Writing to minio: {:?}", duration);
-        })
+        .map(|_| ())
 }
 
+/// Writes a configuration object to the metabucket, recording the change on the configuration
+/// history first.
 pub fn put_object_metabucket(
     cfg: Arc<RwLock<Config>>,
     key: String,
     payload: String,
+) -> impl Future<Item = PutObjectOutput, Error = StorageError<PutObjectError>> {
+    let write_cfg = Arc::clone(&cfg);
+    record_config_change(cfg, key.clone(), Some(payload.clone()))
+        .map_err(|e| StorageError::Operation(PutObjectError::Write(e)))
+        .and_then(move |_| write_object_metabucket(write_cfg, key, payload))
+}
+
+/// Writes an object to the metabucket as is
+pub fn write_object_metabucket(
+    cfg: Arc<RwLock<Config>>,
+    key: String,
+    payload: String,
 ) -> impl Future<Item = PutObjectOutput, Error = StorageError<PutObjectError>> {
     // Represent the metabucket as a datastore
     let datastore = ds_for_metabucket(cfg);
@@ -267,66 +675,342 @@ pub fn put_object_metabucket(
         .map(move |x| x)
 }
 
+/// Reads an object from the metabucket, `None` if it doesn't exist
+pub fn get_object_metabucket(
+    cfg: Arc<RwLock<Config>>,
+    key: String,
+) -> impl Future<Item = Option<String>, Error = StorageError<GetObjectError>> {
+    let datastore = ds_for_metabucket(cfg);
+    let s3_client = client_for_datastore(&datastore);
+    s3_client
+        .get_object(GetObjectRequest {
+            bucket: datastore.bucket.clone(),
+            key,
+            ..Default::default()
+        })
+        .then(|res| match res {
+            Ok(object_output) => Either::A(
+                object_output
+                    .body
+                    .unwrap()
+                    .concat2()
+                    .map_err(|e| {
+                        StorageError::Operation(GetObjectError::IOError(format!("{:?}", e)))
+                    })
+                    .and_then(|body| match String::from_utf8(body.to_vec()) {
+                        Ok(payload) => Ok(Some(payload)),
+                        Err(e) => Err(StorageError::Operation(GetObjectError::IOError(format!(
+                            "{:?}",
+                            e
+                        )))),
+                    }),
+            ),
+            Err(RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))) => {
+                Either::B(future::ok(None))
+            }
+            Err(e) => Either::B(future::err(StorageError::Operation(
+                GetObjectError::IOError(format!("{:?}", e)),
+            ))),
+        })
+}
+
+/// Lists every key under `prefix` on the metabucket, following the pages of the listing
+pub fn list_metabucket_keys(
+    cfg: Arc<RwLock<Config>>,
+    prefix: String,
+) -> impl Future<Item = Vec<String>, Error = StorageError<ListObjectsError>> {
+    let datastore = ds_for_metabucket(cfg);
+    let s3_client = client_for_datastore(&datastore);
+    let bucket = datastore.bucket.clone();
+    future::loop_fn(
+        (Vec::new(), None),
+        move |(mut keys, marker): (Vec<String>, Option<String>)| {
+            let list = s3_client
+                .list_objects(ListObjectsRequest {
+                    bucket: bucket.clone(),
+                    prefix: Some(prefix.clone()),
+                    marker,
+                    ..Default::default()
+                })
+                .map_err(|e| {
+                    StorageError::Operation(ListObjectsError::List(format!(
+                        "Could not list in metabucket: {}",
+                        e
+                    )))
+                });
+            capped_listing(list).map(move |objects| {
+                let truncated = objects.is_truncated.unwrap_or(false);
+                keys.extend(
+                    objects
+                        .contents
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|o| o.key),
+                );
+                // the next page starts after the last key of this one
+                match (truncated, keys.last().cloned()) {
+                    (true, Some(last)) => Loop::Continue((keys, Some(last))),
+                    _ => Loop::Break(keys),
+                }
+            })
+        },
+    )
+}
+
 #[derive(Debug)]
 pub enum DeleteObjectError {
-    Unknown,
+    Delete(String),
 }
 
+impl fmt::Display for DeleteObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeleteObjectError::Delete(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Deletes a configuration object from the metabucket, recording the change on the configuration
+/// history first.
 pub fn delete_object_metabucket(
     cfg: Arc<RwLock<Config>>,
     key: String,
 ) -> impl Future<Item = DeleteObjectOutput, Error = StorageError<DeleteObjectError>> {
     // Represent the metabucket as a datastore
-    let datastore = ds_for_metabucket(cfg);
+    let datastore = ds_for_metabucket(Arc::clone(&cfg));
+    record_config_change(cfg, key.clone(), None)
+        .map_err(|e| {
+            error!("{}", e);
+            StorageError::Operation(DeleteObjectError::Delete(e))
+        })
+        .and_then(move |_| delete_object(&datastore, key))
+}
 
+/// Deletes a single object from a datastore
+pub fn delete_object(
+    datastore: &DataStore,
+    key: String,
+) -> impl Future<Item = DeleteObjectOutput, Error = StorageError<DeleteObjectError>> {
     // Get the Object Storage client
-    let s3_client = client_for_datastore(&datastore);
+    let s3_client = client_for_datastore(datastore);
     s3_client
         .delete_object(DeleteObjectRequest {
             bucket: datastore.bucket.clone(),
             key: key,
             ..Default::default()
         })
-        .map_err(|_| StorageError::Operation(DeleteObjectError::Unknown))
+        .map_err(|e| {
+            StorageError::Operation(DeleteObjectError::Delete(format!(
+                "Could not delete from datastore: {}",
+                e
+            )))
+        })
         .map(move |x| x)
 }
 
+#[derive(Debug)]
+pub enum MoveObjectError {
+    Read(String),
+    Write(String),
+    Delete(String),
+}
+
+/// Moves an object to another datastore, which may be on another endpoint, keeping its key and
+/// metadata.
+pub fn move_object(
+    source: &DataStore,
+    destination: &DataStore,
+    key: String,
+) -> impl Future<Item = (), Error = StorageError<MoveObjectError>> {
+    let source_client = client_for_datastore(source);
+    let destination_client = client_for_datastore(destination);
+    let read_datastore = source.clone();
+    let write_datastore = destination.clone();
+    let source_bucket = source.bucket.clone();
+    let destination_bucket = destination.bucket.clone();
+    let put_key = key.clone();
+    let delete_key = key.clone();
+    source_client
+        .get_object(GetObjectRequest {
+            bucket: source_bucket.clone(),
+            key: key,
+            ..Default::default()
+        })
+        .map_err(|e| {
+            StorageError::Operation(MoveObjectError::Read(format!(
+                "Could not read from datastore: {}",
+                e
+            )))
+        })
+        .and_then(move |object_output| {
+            let metadata = object_output.metadata;
+            throttle_download(&read_datastore, object_output.body.unwrap())
+                .concat2()
+                .map(move |body| (body, metadata))
+                .map_err(|e| {
+                    StorageError::Operation(MoveObjectError::Read(format!(
+                        "Could not read from datastore: {}",
+                        e
+                    )))
+                })
+        })
+        .and_then(move |(body, metadata)| {
+            let len = body.len() as i64;
+            let stream_of_bytes = stream::iter_ok(vec![Bytes::from(body.to_vec())]);
+            destination_client
+                .put_object(PutObjectRequest {
+                    bucket: destination_bucket,
+                    key: put_key,
+                    body: Some(throttle_upload(&write_datastore, stream_of_bytes)),
+                    content_length: Some(len),
+                    metadata: metadata,
+                    ..Default::default()
+                })
+                .map_err(|e| {
+                    StorageError::Operation(MoveObjectError::Write(format!(
+                        "Could not write to datastore: {}",
+                        e
+                    )))
+                })
+        })
+        .and_then(move |_| {
+            // only remove the original once the copy is in place
+            source_client
+                .delete_object(DeleteObjectRequest {
+                    bucket: source_bucket,
+                    key: delete_key,
+                    ..Default::default()
+                })
+                .map_err(|e| {
+                    StorageError::Operation(MoveObjectError::Delete(format!(
+                        "Could not delete from datastore: {}",
+                        e
+                    )))
+                })
+        })
+        .map(|_| ())
+}
+
 #[derive(Debug)]
 pub enum ListObjectsError {
     List(String),
 }
 
+/// An object holding data of a log
+#[derive(Debug, Clone)]
+pub struct LogObject {
+    pub key: String,
+    pub size: u64,
+    // ISO 8601 timestamp as reported by the datastore
+    pub last_modified: String,
+}
+
 /// List all the files for a bucket
 /// returns a stream of file names
 pub fn list_msl_bucket_files(
     logname: &str,
     datastore: &DataStore,
+    naming: &ObjectNaming,
 ) -> impl Stream<Item = String, Error = StorageError<ListObjectsError>> {
+    list_msl_bucket_objects(logname, datastore, naming).map(|o| o.key)
+}
+
+/// Caps the listing calls in flight across every datastore, `0` lifts the cap
+pub fn set_max_concurrent_listings(limit: usize) {
+    LISTING_LIMIT.set_limit(limit);
+}
+
+/// Runs a listing call once the cap on listing calls allows it
+fn capped_listing<F, T>(list: F) -> impl Future<Item = T, Error = StorageError<ListObjectsError>>
+where
+    F: Future<Item = T, Error = StorageError<ListObjectsError>>,
+{
+    LISTING_LIMIT
+        .acquire()
+        .map_err(|_| StorageError::Unhandled)
+        // the permit is held until the call is answered
+        .and_then(move |permit| {
+            list.then(move |res| {
+                drop(permit);
+                res
+            })
+        })
+}
+
+/// List all the objects of a log on a datastore along with their size and modification time,
+/// following the pages of the listing. Only the objects of the partition of `naming` are listed
+/// if it's restricted to one.
+pub fn list_msl_bucket_objects(
+    logname: &str,
+    datastore: &DataStore,
+    naming: &ObjectNaming,
+) -> impl Stream<Item = LogObject, Error = StorageError<ListObjectsError>> {
+    // objects are laid out under `minsql/{log}/` as the naming of the log says
+    let prefix_len = format!("minsql/{}/", logname).len();
+    let prefix = naming.prefix(logname);
+    let naming = naming.clone();
+    let ds_name = datastore.name.clone().unwrap_or_default();
+    let bucket = datastore.bucket.clone();
     let s3_client = client_for_datastore(datastore);
-    s3_client
-        .list_objects(ListObjectsRequest {
-            bucket: datastore.bucket.clone(),
-            prefix: Some(format!("minsql/{}", logname)),
+    // `None` once the last page was listed
+    stream::unfold(Some(None), move |marker: Option<Option<String>>| {
+        let marker = marker?;
+        let ds_name = ds_name.clone();
+        let naming = naming.clone();
+        let s3_client = s3_client.clone();
+        let request = ListObjectsRequest {
+            bucket: bucket.clone(),
+            prefix: Some(prefix.clone()),
+            marker,
             ..Default::default()
-        })
-        .map_err(|e| {
-            StorageError::Operation(ListObjectsError::List(format!(
-                "Could not list in datastore: {}",
-                e
-            )))
-        })
-        .map(|objects| {
-            stream::iter_ok::<_, StorageError<ListObjectsError>>(
-                objects
-                    .contents
-                    .unwrap_or(Vec::new())
-                    .into_iter()
-                    .map(|f| f.key.unwrap())
-                    .filter(|f| f.ends_with(".log"))
-                    .collect::<Vec<String>>(),
+        };
+        let list = injected_fault(&ds_name)
+            .and_then(move |_| s3_client.list_objects(request))
+            .map_err(move |e| {
+                record_datastore_error(&ds_name);
+                StorageError::Operation(ListObjectsError::List(format!(
+                    "Could not list in datastore: {}",
+                    e
+                )))
+            });
+        Some(capped_listing(list).map(move |objects| {
+            let truncated = objects.is_truncated.unwrap_or(false);
+            let contents = objects.contents.unwrap_or(Vec::new());
+            // the next page starts after the last key of this one
+            let next_marker = match (truncated, contents.last()) {
+                (true, Some(last)) => last.key.clone().map(Some),
+                _ => None,
+            };
+            let page = contents
+                .into_iter()
+                .filter_map(|f| match f.key {
+                    Some(key) => Some(LogObject {
+                        key,
+                        size: f.size.unwrap_or(0) as u64,
+                        last_modified: f.last_modified.unwrap_or_default(),
+                    }),
+                    None => None,
+                })
+                // skip the objects of logs nested under this one
+                .filter(|f| naming.matches(&f.key[prefix_len..]))
+                .collect::<Vec<LogObject>>();
+            (
+                stream::iter_ok::<_, StorageError<ListObjectsError>>(page),
+                next_marker,
             )
-        })
-        .flatten_stream()
+        }))
+    })
+    .flatten()
+}
+
+/// Whether an object was written by a log with the `base64` encoding, every line of it is then
+/// wrapped in base64
+pub fn is_base64_object(metadata: &Option<HashMap<String, String>>) -> bool {
+    metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get(ENCODING_METADATA))
+        .map(|encoding| encoding.as_str())
+        == Some(ENCODING_BASE64)
 }
 
 #[derive(Debug)]
@@ -340,32 +1024,333 @@ pub enum GetObjectError {
 pub fn read_file_line_by_line(
     key: &String,
     datastore: &DataStore,
+    lossy_decoding: bool,
 ) -> impl Stream<Item = Vec<String>, Error = StorageError<GetObjectError>> {
+    read_file_lines_from(key, datastore, lossy_decoding, 0).map(|(lines, _)| lines)
+}
+
+/// Reads from the first of the `replicas` of a replicated log, going on with the next one when a
+/// read fails before it returns anything, ie: the GET of an object. A read failing midway isn't
+/// taken up by another replica, which would return its first items again.
+pub fn read_from_replicas<T, E, S, F>(
+    mut replicas: Vec<DataStore>,
+    read: F,
+) -> Box<dyn Stream<Item = T, Error = E> + Send>
+where
+    T: Send + 'static,
+    E: fmt::Display + Send + 'static,
+    S: Stream<Item = T, Error = E> + Send + 'static,
+    F: Fn(&DataStore) -> S + Send + 'static,
+{
+    let replica = replicas.remove(0);
+    let stream = read(&replica);
+    if replicas.is_empty() {
+        return Box::new(stream);
+    }
+    Box::new(
+        stream
+            .into_future()
+            .then(move |res| {
+                Ok::<_, E>(match res {
+                    Ok((first, rest)) => Box::new(stream::iter_ok(first).chain(rest))
+                        as Box<dyn Stream<Item = T, Error = E> + Send>,
+                    Err((e, _)) => {
+                        warn!(
+                            "Could not read from datastore {}, reading from {}: {}",
+                            replica.name.clone().unwrap_or_default(),
+                            replicas[0].name.clone().unwrap_or_default(),
+                            e
+                        );
+                        read_from_replicas(replicas, read)
+                    }
+                })
+            })
+            .flatten_stream(),
+    )
+}
+
+/// Reads the lines of an object from byte `offset` onwards with a ranged GET. Every batch of
+/// lines comes with the offset following its last line, a later read can resume from there. An
+/// offset in the middle of a line resumes on the next line.
+pub fn read_file_lines_from(
+    key: &String,
+    datastore: &DataStore,
+    lossy_decoding: bool,
+    offset: u64,
+) -> impl Stream<Item = (Vec<String>, u64), Error = StorageError<GetObjectError>> {
+    read_file_offset_lines(key, datastore, lossy_decoding, offset).map(|batch| {
+        let next_offset = batch.last().map(|(_, next)| *next).unwrap_or(0);
+        let lines = batch.into_iter().map(|(line, _)| line).collect();
+        (lines, next_offset)
+    })
+}
+
+/// `read_file_lines_from`, with every line along with the offset following it. Lines of objects
+/// written in base64 are unwrapped, the offsets stay those of the stored lines.
+pub fn read_file_offset_lines(
+    key: &String,
+    datastore: &DataStore,
+    lossy_decoding: bool,
+    offset: u64,
+) -> impl Stream<Item = Vec<(String, u64)>, Error = StorageError<GetObjectError>> {
+    let codec_key = key.clone();
+    // the byte before the offset is read too, to tell whether the offset starts a line
+    let skip = offset.saturating_sub(1);
+    // a compressed object can't be read from the middle, it's read whole and decoded instead
+    let whole = if offset > 0 {
+        Either::A(is_compressed(key, datastore))
+    } else {
+        Either::B(future::ok(true))
+    };
+    let ds_name = datastore.name.clone().unwrap_or_default();
+    let started = Instant::now();
     let s3_client = client_for_datastore(datastore);
-    s3_client
-        .get_object(GetObjectRequest {
-            bucket: datastore.bucket.clone(),
-            key: key.clone(),
-            ..Default::default()
+    let read_datastore = datastore.clone();
+    let bucket = datastore.bucket.clone();
+    let get_key = key.clone();
+    let body_ds_name = ds_name.clone();
+    whole
+        .and_then(move |whole| {
+            let request = GetObjectRequest {
+                bucket,
+                key: get_key,
+                range: if whole {
+                    None
+                } else {
+                    Some(format!("bytes={}-", skip))
+                },
+                ..Default::default()
+            };
+            injected_fault(&ds_name)
+                .and_then(move |_| s3_client.get_object(request))
+                .then(move |res| {
+                    // a missing object is an answer from the datastore, not a failure
+                    let ok = match &res {
+                        Ok(_) => true,
+                        Err(RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))) => true,
+                        Err(_) => false,
+                    };
+                    record_get_latency(&ds_name, started.elapsed(), ok);
+                    res
+                })
+                .map_err(get_object_error)
+                .map(move |f| (f, whole))
         })
-        .map_err(|e| match e {
-            rusoto_core::RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(key)) => {
-                StorageError::Operation(GetObjectError::NoSuchKey(key))
+        .and_then(move |(f, whole)| {
+            let base64_lines = is_base64_object(&f.metadata);
+            let body =
+                throttle_download(&read_datastore, read_body(&body_ds_name, f.body.unwrap()));
+            if whole {
+                Either::A(decoded_body(body, skip as usize).map(move |body| (body, base64_lines)))
+            } else {
+                Either::B(future::ok((body, base64_lines)))
             }
-            e_ => StorageError::Operation(GetObjectError::IOError(format!("{:?}", e_))),
         })
-        .map(|f| {
+        .map(move |(body, base64_lines)| {
             FramedRead::new(
-                f.body.unwrap().into_async_read(),
+                body.into_async_read(),
                 // max line length of 1MiB
-                LinesCodec::new_with_max_length(1024 * 1024),
+                LogLinesCodec::starting_at(codec_key, 1024 * 1024, lossy_decoding, offset),
             )
             .chunks(4096)
             .map_err(|e| StorageError::Operation(GetObjectError::IOError(format!("{:?}", e))))
+            .map(move |batch| {
+                if !base64_lines {
+                    return batch;
+                }
+                let (lines, offsets): (Vec<String>, Vec<u64>) = batch.into_iter().unzip();
+                decode_lines(lines).into_iter().zip(offsets).collect()
+            })
         })
         .flatten_stream()
 }
 
+fn get_object_error(e: RusotoError<rusoto_s3::GetObjectError>) -> StorageError<GetObjectError> {
+    match e {
+        rusoto_core::RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(key)) => {
+            StorageError::Operation(GetObjectError::NoSuchKey(key))
+        }
+        e_ => StorageError::Operation(GetObjectError::IOError(format!("{:?}", e_))),
+    }
+}
+
+/// Whether an object was written compressed, from its first bytes
+fn is_compressed(
+    key: &String,
+    datastore: &DataStore,
+) -> impl Future<Item = bool, Error = StorageError<GetObjectError>> {
+    let s3_client = client_for_datastore(datastore);
+    let request = GetObjectRequest {
+        bucket: datastore.bucket.clone(),
+        key: key.clone(),
+        range: Some("bytes=0-3".to_string()),
+        ..Default::default()
+    };
+    injected_fault(&datastore.name.clone().unwrap_or_default())
+        .and_then(move |_| s3_client.get_object(request))
+        .map_err(get_object_error)
+        .and_then(|f| {
+            f.body
+                .unwrap()
+                .concat2()
+                .map_err(|e| StorageError::Operation(GetObjectError::IOError(format!("{:?}", e))))
+        })
+        .map(|head| Compression::detect(&head).is_some())
+}
+
+/// The body of an object read whole, decoded if it was written compressed. The first `skip`
+/// bytes of a compressed object are left out, a plain body is passed on as it's streamed.
+fn decoded_body(
+    body: ByteStream,
+    skip: usize,
+) -> impl Future<Item = ByteStream, Error = StorageError<GetObjectError>> {
+    let io_error =
+        |e: io::Error| StorageError::Operation(GetObjectError::IOError(format!("{:?}", e)));
+    body.into_future()
+        .map_err(move |(e, _)| io_error(e))
+        .and_then(move |(first, rest)| {
+            let compressed = first
+                .as_ref()
+                .map(|chunk| Compression::detect(chunk).is_some())
+                .unwrap_or(false);
+            let body = stream::iter_ok(first).chain(rest);
+            if !compressed {
+                return Either::A(future::ok(ByteStream::new(body)));
+            }
+            Either::B(
+                body.concat2()
+                    .map_err(io_error)
+                    .and_then(move |compressed| {
+                        decompress(compressed.to_vec())
+                            .map(|decoded| {
+                                let rest = decoded.get(skip..).unwrap_or(&[]).to_vec();
+                                ByteStream::from(rest)
+                            })
+                            .map_err(io_error)
+                    }),
+            )
+        })
+}
+
+/// Splits an object body into lines. Unlike `LinesCodec`, a line with invalid UTF-8 doesn't fail
+/// the whole stream, it's either decoded lossily or dropped with a warning. Lines are decoded
+/// along with the object offset following them.
+pub struct LogLinesCodec {
+    // object key, used to report dropped lines
+    key: String,
+    max_length: usize,
+    lossy: bool,
+    // where to resume looking for a new line on the buffer
+    next_index: usize,
+    // object offset of the start of the buffer
+    position: u64,
+    // the buffer starts mid line, skip up to the next line
+    resync: bool,
+}
+
+impl LogLinesCodec {
+    pub fn new(key: String, max_length: usize, lossy: bool) -> LogLinesCodec {
+        LogLinesCodec::starting_at(key, max_length, lossy, 0)
+    }
+
+    /// Codec for a body read from `offset - 1`, so the first byte tells if `offset` starts a line
+    pub fn starting_at(key: String, max_length: usize, lossy: bool, offset: u64) -> LogLinesCodec {
+        LogLinesCodec {
+            key,
+            max_length,
+            lossy,
+            next_index: 0,
+            position: offset.saturating_sub(1),
+            resync: offset > 0,
+        }
+    }
+
+    /// Decodes a single line (without the new line character) to a `String`
+    fn decode_line(&self, line: &[u8]) -> Option<String> {
+        let line = if line.ends_with(b"\r") {
+            &line[..line.len() - 1]
+        } else {
+            line
+        };
+        match str::from_utf8(line) {
+            Ok(l) => Some(l.to_string()),
+            Err(e) => {
+                if self.lossy {
+                    Some(String::from_utf8_lossy(line).into_owned())
+                } else {
+                    warn!("Dropping line with invalid UTF-8 on {}: {}", &self.key, e);
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Decoder for LogLinesCodec {
+    type Item = (String, u64);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(String, u64)>, io::Error> {
+        if self.resync {
+            match buf.iter().position(|b| *b == b'\n') {
+                Some(newline_index) => {
+                    buf.split_to(newline_index + 1);
+                    self.position += newline_index as u64 + 1;
+                    self.resync = false;
+                }
+                None => {
+                    self.position += buf.len() as u64;
+                    buf.clear();
+                    return Ok(None);
+                }
+            }
+        }
+        loop {
+            let newline_offset = buf[self.next_index..].iter().position(|b| *b == b'\n');
+            match newline_offset {
+                Some(offset) => {
+                    let newline_index = offset + self.next_index;
+                    self.next_index = 0;
+                    let line = buf.split_to(newline_index + 1);
+                    self.position += line.len() as u64;
+                    if let Some(l) = self.decode_line(&line[..line.len() - 1]) {
+                        return Ok(Some((l, self.position)));
+                    }
+                    // the line was dropped, keep looking
+                }
+                None => {
+                    if buf.len() > self.max_length {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("line length limit exceeded on {}", &self.key),
+                        ));
+                    }
+                    self.next_index = buf.len();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<(String, u64)>, io::Error> {
+        match self.decode(buf)? {
+            Some(line) => Ok(Some(line)),
+            None => {
+                self.next_index = 0;
+                if buf.is_empty() {
+                    Ok(None)
+                } else {
+                    // last line without a new line character
+                    let line = buf.take();
+                    self.position += line.len() as u64;
+                    let position = self.position;
+                    Ok(self.decode_line(&line[..]).map(|l| (l, position)))
+                }
+            }
+        }
+    }
+}
+
 /// Selects a datastore at random. Will return `None` if the log_name
 /// doesn't match a valid `Log` name in the `Config`.
 fn rand_datastore<'a>(cfg: &'a Config, log_name: &str) -> Option<&'a DataStore> {
@@ -382,8 +1367,6 @@ fn rand_datastore<'a>(cfg: &'a Config, log_name: &str) -> Option<&'a DataStore>
 
 #[cfg(test)]
 mod storage_tests {
-    use std::collections::HashMap;
-
     use crate::config::{Log, Server};
 
     use super::*;
@@ -401,6 +1384,8 @@ mod storage_tests {
                     secret_key: "".to_string(),
                     bucket: "".to_string(),
                     prefix: "".to_string(),
+                    max_upload_bytes_per_sec: None,
+                    max_download_bytes_per_sec: None,
                 },
             );
         }
@@ -412,23 +1397,20 @@ mod storage_tests {
                 name: Some(log_name.clone()),
                 datastores: datastore_list.clone(),
                 commit_window: "5s".to_string(),
+                ..Default::default()
             },
         );
 
         let cfg = Config {
             server: Server {
-                address: "".to_string(),
-                metadata_endpoint: "".to_string(),
-                metadata_bucket: "".to_string(),
-                access_key: "".to_string(),
-                secret_key: "".to_string(),
-                pkcs12_cert: None,
-                pkcs12_password: None,
+                query_memory_limit: 1024,
+                ..Server::default()
             },
             datastore: datastore_map,
             tokens: HashMap::new(),
             log: log_map,
             auth: HashMap::new(),
+            patterns: HashMap::new(),
         };
         cfg
     }
@@ -449,6 +1431,72 @@ mod storage_tests {
         assert_eq!(ds_in_list, true)
     }
 
+    fn decode_all(codec: &mut LogLinesCodec, data: &[u8]) -> Vec<String> {
+        decode_all_with_offsets(codec, data)
+            .into_iter()
+            .map(|(line, _)| line)
+            .collect()
+    }
+
+    fn decode_all_with_offsets(codec: &mut LogLinesCodec, data: &[u8]) -> Vec<(String, u64)> {
+        let mut buf = BytesMut::from(data);
+        let mut lines = Vec::new();
+        while let Some(line) = codec.decode(&mut buf).unwrap() {
+            lines.push(line);
+        }
+        while let Some(line) = codec.decode_eof(&mut buf).unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn codec_drops_invalid_utf8_lines() {
+        let mut codec = LogLinesCodec::new("key".to_string(), 1024, false);
+        let lines = decode_all(&mut codec, b"first\n\xff\xfebad\r\nlast");
+        assert_eq!(lines, vec!["first".to_string(), "last".to_string()]);
+    }
+
+    #[test]
+    fn codec_lossy_decodes_invalid_utf8_lines() {
+        let mut codec = LogLinesCodec::new("key".to_string(), 1024, true);
+        let lines = decode_all(&mut codec, b"first\n\xffbad\nlast\n");
+        assert_eq!(
+            lines,
+            vec![
+                "first".to_string(),
+                "\u{FFFD}bad".to_string(),
+                "last".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn codec_reports_offsets_after_lines() {
+        let mut codec = LogLinesCodec::new("key".to_string(), 1024, false);
+        let lines = decode_all_with_offsets(&mut codec, b"first\n\xffbad\nlast");
+        assert_eq!(
+            lines,
+            vec![("first".to_string(), 6), ("last".to_string(), 15)]
+        );
+    }
+
+    #[test]
+    fn codec_resumes_at_offset() {
+        let object = b"first\nsecond\nthird\n";
+        // offset 6 starts `second`, the body is read from the byte before it
+        let mut codec = LogLinesCodec::starting_at("key".to_string(), 1024, false, 6);
+        let lines = decode_all_with_offsets(&mut codec, &object[5..]);
+        assert_eq!(
+            lines,
+            vec![("second".to_string(), 13), ("third".to_string(), 19)]
+        );
+        // an offset in the middle of `second` resumes on the following line
+        let mut codec = LogLinesCodec::starting_at("key".to_string(), 1024, false, 8);
+        let lines = decode_all_with_offsets(&mut codec, &object[7..]);
+        assert_eq!(lines, vec![("third".to_string(), 19)]);
+    }
+
     #[test]
     fn fail_random_datastore_selected() {
         let ds_list = vec!["ds1".to_string(), "ds2".to_string()];
@@ -462,4 +1510,14 @@ mod storage_tests {
             "Select random datastore from incorrect log should have failed."
         )
     }
+
+    #[test]
+    fn storage_error_reason_has_the_underlying_error() {
+        let err = StorageError::Operation(PutObjectError::Write(
+            "Could not write to datastore: Access Denied".to_string(),
+        ));
+        assert_eq!(err.reason(), "Could not write to datastore: Access Denied");
+        let err: StorageError<DeleteObjectError> = StorageError::Unhandled;
+        assert_eq!(err.reason(), "Unhandled storage error");
+    }
 }
@@ -0,0 +1,103 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Loop};
+use futures::Future;
+use log::error;
+use tokio::timer::Delay;
+
+use crate::constants::SUPERVISOR_RESTART_DELAY_SECS;
+
+/// Spawns `task`, logging with `context` if it panics instead of letting it die quietly.
+pub fn spawn_isolated<F>(context: String, task: F)
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    hyper::rt::spawn(AssertUnwindSafe(task).catch_unwind().then(move |res| {
+        if let Err(panic) = res {
+            error!("{} panicked: {}", context, panic_message(&*panic));
+        }
+        Ok(())
+    }));
+}
+
+/// Spawns the long running task built by `make_task`, and builds and spawns it again whenever it
+/// panics, errors or ends, so loops such as the buffer flushing survive failures.
+pub fn spawn_supervised<M, F>(context: String, make_task: M)
+where
+    M: Fn() -> F + Send + Sync + 'static,
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let make_task = Arc::new(make_task);
+    let supervisor = future::loop_fn((), move |_| {
+        let make_task = Arc::clone(&make_task);
+        let context = context.clone();
+        // build the task lazily so a panic building it is caught as well
+        AssertUnwindSafe(future::lazy(move || make_task()))
+            .catch_unwind()
+            .then(move |res| {
+                match res {
+                    Ok(Ok(())) => error!("{} ended, restarting it", context),
+                    Ok(Err(())) => error!("{} failed, restarting it", context),
+                    Err(panic) => error!(
+                        "{} panicked, restarting it: {}",
+                        context,
+                        panic_message(&*panic)
+                    ),
+                };
+                Delay::new(Instant::now() + Duration::from_secs(SUPERVISOR_RESTART_DELAY_SECS))
+                    .then(|_| Ok(Loop::Continue(())))
+            })
+    });
+    hyper::rt::spawn(supervisor);
+}
+
+/// Message a panic was raised with, payloads are usually a `&str` or a `String`
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod supervisor_tests {
+    use super::*;
+
+    #[test]
+    fn panic_messages() {
+        let res = AssertUnwindSafe(future::lazy(|| -> Result<(), ()> { panic!("static") }))
+            .catch_unwind()
+            .wait();
+        assert_eq!(panic_message(&*res.unwrap_err()), "static");
+
+        let log_name = "mylog";
+        let res = AssertUnwindSafe(future::lazy(|| -> Result<(), ()> {
+            panic!("flushing {}", log_name)
+        }))
+        .catch_unwind()
+        .wait();
+        assert_eq!(panic_message(&*res.unwrap_err()), "flushing mylog");
+    }
+}
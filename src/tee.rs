@@ -0,0 +1,190 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use regex::Regex;
+use serde_derive::Serialize;
+use tokio::timer::Interval;
+
+use crate::caches::Cache;
+use crate::config::TeeRule;
+use crate::constants::{TEE_BATCH_LINES, TEE_INTERVAL_MILLIS, TEE_MAX_IN_FLIGHT};
+use crate::supervisor;
+use crate::webhook;
+
+lazy_static! {
+    // Lines waiting to be forwarded, keyed by log and webhook
+    static ref TEE_BATCHES: Mutex<HashMap<(String, String), Vec<String>>> =
+        Mutex::new(HashMap::new());
+    // Compiled patterns, keyed by pattern
+    pub static ref TEE_REGEXES: Cache<Option<Regex>> = Cache::new("tee_regexes");
+}
+
+// Batches being POSTed to their webhooks
+static TEE_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Body POSTed to the webhook of a tee rule
+#[derive(Serialize, Debug)]
+struct TeeBatch {
+    log: String,
+    lines: Vec<String>,
+}
+
+/// Starts the task forwarding the lines batched by the tee rules of the logs, so lines of quiet
+/// logs don't wait for a full batch.
+pub fn start_tee_task() {
+    supervisor::spawn_supervised("Tee task".to_string(), || {
+        Interval::new(Instant::now(), Duration::from_millis(TEE_INTERVAL_MILLIS))
+            .map_err(|e| error!("tee interval errored; err={:?}", e))
+            .for_each(|_| {
+                let batches = mem::replace(&mut *TEE_BATCHES.lock().unwrap(), HashMap::new());
+                for ((log_name, webhook_url), lines) in batches {
+                    forward(log_name, webhook_url, lines);
+                }
+                Ok(())
+            })
+    });
+}
+
+/// Batches the lines of the payload matching the tee rules of a log, forwarding the batches
+/// that are full right away. Lines are matched before the batches are locked, so ingest on other
+/// logs doesn't wait on the patterns of this one.
+pub fn tee_lines(log_name: &str, rules: &[TeeRule], payload: &str) {
+    let matched: Vec<(String, Vec<&str>)> = rules
+        .iter()
+        .filter_map(|rule| {
+            let re = cached_regex(&rule.pattern)?;
+            let lines: Vec<&str> = payload
+                .split('\n')
+                .filter(|line| !line.is_empty() && re.is_match(line))
+                .collect();
+            Some((rule.webhook.clone(), lines))
+        })
+        .filter(|(_, lines)| !lines.is_empty())
+        .collect();
+    if matched.is_empty() {
+        return;
+    }
+    let mut full = Vec::new();
+    {
+        let mut batches = TEE_BATCHES.lock().unwrap();
+        for (webhook_url, lines) in matched {
+            let key = (log_name.to_string(), webhook_url);
+            for line in lines {
+                let batch = batches.entry(key.clone()).or_insert_with(Vec::new);
+                batch.push(line.to_string());
+                if batch.len() >= TEE_BATCH_LINES {
+                    full.push((key.clone(), mem::replace(batch, Vec::new())));
+                }
+            }
+        }
+    }
+    for ((log_name, webhook_url), lines) in full {
+        forward(log_name, webhook_url, lines);
+    }
+}
+
+/// POSTs a batch of lines to the webhook of a tee rule, a batch the webhook doesn't take is
+/// dropped since the lines are stored on the log anyway. So are batches past
+/// `TEE_MAX_IN_FLIGHT` being POSTed, slow webhooks don't pile up requests without bound.
+fn forward(log_name: String, webhook_url: String, lines: Vec<String>) {
+    let count = lines.len();
+    if TEE_IN_FLIGHT.fetch_add(1, Ordering::SeqCst) >= TEE_MAX_IN_FLIGHT {
+        TEE_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        warn!(
+            "Dropped {} lines of {} for {}, too many batches are being forwarded",
+            count, log_name, webhook_url
+        );
+        return;
+    }
+    let body = serde_json::to_string(&TeeBatch {
+        log: log_name.clone(),
+        lines,
+    })
+    .unwrap();
+    supervisor::spawn_isolated(
+        format!("Tee of {}", &log_name),
+        webhook::post_json(&webhook_url, body).then(move |res| {
+            TEE_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            res.map_err(move |e| error!("Could not forward {} lines of {}: {}", count, log_name, e))
+        }),
+    );
+}
+
+/// Compiles a pattern once, patterns the regex engine can't compile are skipped
+fn cached_regex(pattern: &str) -> Option<Regex> {
+    TEE_REGEXES.get_or_insert_with(pattern, || Regex::new(pattern).ok())
+}
+
+#[cfg(test)]
+mod tee_tests {
+    use super::*;
+
+    fn take_batch(log_name: &str, webhook_url: &str) -> Vec<String> {
+        TEE_BATCHES
+            .lock()
+            .unwrap()
+            .remove(&(log_name.to_string(), webhook_url.to_string()))
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn batches_matching_lines() {
+        let rules = vec![
+            TeeRule {
+                pattern: "\" 5\\d\\d ".to_string(),
+                webhook: "https://hooks.example.com/errors".to_string(),
+            },
+            TeeRule {
+                pattern: "POST /login".to_string(),
+                webhook: "https://hooks.example.com/logins".to_string(),
+            },
+        ];
+        let payload = "10.0.0.1 \"GET /index.html\" 200 \n\
+                       10.0.0.2 \"GET /api\" 502 \n\
+                       10.0.0.3 \"POST /login\" 503 \n";
+        tee_lines("teelog", &rules, payload);
+        assert_eq!(
+            take_batch("teelog", "https://hooks.example.com/errors"),
+            vec![
+                "10.0.0.2 \"GET /api\" 502 ",
+                "10.0.0.3 \"POST /login\" 503 "
+            ]
+        );
+        assert_eq!(
+            take_batch("teelog", "https://hooks.example.com/logins"),
+            vec!["10.0.0.3 \"POST /login\" 503 "]
+        );
+    }
+
+    #[test]
+    fn invalid_patterns_are_skipped() {
+        let rules = vec![TeeRule {
+            pattern: "(unclosed".to_string(),
+            webhook: "https://hooks.example.com/errors".to_string(),
+        }];
+        tee_lines("invalidteelog", &rules, "(unclosed\n");
+        assert!(take_batch("invalidteelog", "https://hooks.example.com/errors").is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream};
+use lazy_static::lazy_static;
+use rusoto_core::ByteStream;
+use tokio::timer::Delay;
+
+use crate::config::DataStore;
+use crate::try_ready;
+
+lazy_static! {
+    // Buckets of the datastores with a bandwidth cap, keyed by datastore and direction
+    static ref BUCKETS: Mutex<HashMap<(String, Direction), Arc<Mutex<TokenBucket>>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Bytes a datastore may move per second, refilled continuously up to a second worth of them.
+/// Chunks are taken whole, a chunk larger than what's left puts the bucket in debt and whoever
+/// takes next waits for it to be paid.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_sec: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            bytes_per_sec,
+            tokens: bytes_per_sec as f64,
+            refilled: now,
+        }
+    }
+
+    /// Takes `bytes` from the bucket, returning how long to wait before moving them
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let wait = -self.tokens / rate;
+        Duration::new(wait.trunc() as u64, (wait.fract() * 1e9) as u32)
+    }
+
+    fn set_rate(&mut self, bytes_per_sec: u64) {
+        if self.bytes_per_sec != bytes_per_sec {
+            self.bytes_per_sec = bytes_per_sec;
+            self.tokens = self.tokens.min(bytes_per_sec as f64);
+        }
+    }
+}
+
+/// The bucket `direction` of a datastore is throttled with, `None` if it has no cap. Buckets
+/// follow the cap of the datastore as it's updated.
+fn bucket_for(datastore: &DataStore, direction: Direction) -> Option<Arc<Mutex<TokenBucket>>> {
+    let cap = match direction {
+        Direction::Upload => datastore.max_upload_bytes_per_sec,
+        Direction::Download => datastore.max_download_bytes_per_sec,
+    }?;
+    let name = datastore
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("{}/{}", datastore.endpoint, datastore.bucket));
+    let mut buckets = BUCKETS.lock().unwrap();
+    let bucket = buckets
+        .entry((name, direction))
+        .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(cap, Instant::now()))));
+    bucket.lock().unwrap().set_rate(cap);
+    Some(Arc::clone(bucket))
+}
+
+/// A stream of bytes held back to the bandwidth of its bucket
+pub struct Throttled<S> {
+    inner: S,
+    bucket: Arc<Mutex<TokenBucket>>,
+    // a chunk waiting for the bucket
+    pending: Option<(Bytes, Delay)>,
+}
+
+impl<S> Stream for Throttled<S>
+where
+    S: Stream<Item = Bytes, Error = io::Error>,
+{
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        if let Some((_, delay)) = self.pending.as_mut() {
+            try_ready!(delay
+                .poll()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+            return Ok(Async::Ready(self.pending.take().map(|(chunk, _)| chunk)));
+        }
+        let chunk = match try_ready!(self.inner.poll()) {
+            Some(chunk) => chunk,
+            None => return Ok(Async::Ready(None)),
+        };
+        let now = Instant::now();
+        let wait = self.bucket.lock().unwrap().take(chunk.len() as u64, now);
+        if wait == Duration::from_secs(0) {
+            return Ok(Async::Ready(Some(chunk)));
+        }
+        self.pending = Some((chunk, Delay::new(now + wait)));
+        self.poll()
+    }
+}
+
+/// Throttles the body of an object written to a datastore to its upload cap
+pub fn throttle_upload<S>(datastore: &DataStore, body: S) -> ByteStream
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    throttle(datastore, Direction::Upload, body)
+}
+
+/// Throttles the body of an object read from a datastore to its download cap
+pub fn throttle_download(datastore: &DataStore, body: ByteStream) -> ByteStream {
+    throttle(datastore, Direction::Download, body)
+}
+
+fn throttle<S>(datastore: &DataStore, direction: Direction, body: S) -> ByteStream
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    match bucket_for(datastore, direction) {
+        Some(bucket) => ByteStream::new(Throttled {
+            inner: body,
+            bucket,
+            pending: None,
+        }),
+        None => ByteStream::new(body),
+    }
+}
+
+#[cfg(test)]
+mod throttle_tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert_eq!(bucket.take(600, start), Duration::from_secs(0));
+        assert_eq!(bucket.take(400, start), Duration::from_secs(0));
+        // in debt for half a second of bytes
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+        assert_eq!(
+            bucket.take(250, start + Duration::from_millis(500)),
+            Duration::from_millis(250)
+        );
+        // never refilled past a second of bytes
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(1000, later), Duration::from_secs(0));
+        assert_eq!(bucket.take(100, later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn buckets_follow_the_cap() {
+        let mut datastore = DataStore {
+            name: Some("throttled".to_string()),
+            endpoint: "".to_string(),
+            access_key: "".to_string(),
+            secret_key: "".to_string(),
+            bucket: "".to_string(),
+            prefix: "".to_string(),
+            max_upload_bytes_per_sec: None,
+            max_download_bytes_per_sec: Some(1000),
+        };
+        assert!(bucket_for(&datastore, Direction::Upload).is_none());
+        let bucket = bucket_for(&datastore, Direction::Download).unwrap();
+        datastore.max_download_bytes_per_sec = Some(2000);
+        bucket_for(&datastore, Direction::Download);
+        assert_eq!(bucket.lock().unwrap().bytes_per_sec, 2000);
+    }
+}
@@ -0,0 +1,246 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::future::Either;
+use futures::{future, Future, Stream};
+use log::{error, info};
+use rand::Rng;
+use tokio::timer::Interval;
+
+use crate::bloom::bloom_key;
+use crate::config::{Config, DataStore, Log};
+use crate::constants::TIERING_INTERVAL_SECS;
+use crate::jobs::spawn_job;
+use crate::naming::{ObjectNaming, TimeRange};
+use crate::storage::{list_msl_bucket_objects, move_object, LogObject};
+use crate::supervisor;
+
+pub struct Tiering {
+    config: Arc<RwLock<Config>>,
+}
+
+impl Tiering {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> Tiering {
+        Tiering { config: cfg }
+    }
+
+    /// Starts the lifecycle task that periodically moves the objects of the logs from their hot
+    /// datastores to their cold datastores once they are older than `cold_after`.
+    pub fn start_lifecycle_task(&self) {
+        let cfg = Arc::clone(&self.config);
+        supervisor::spawn_supervised("Tiering lifecycle task".to_string(), move || {
+            let cfg = Arc::clone(&cfg);
+            Interval::new(Instant::now(), Duration::from_secs(TIERING_INTERVAL_SECS))
+                .map_err(|e| error!("tiering interval errored; err={:?}", e))
+                .for_each(move |_| {
+                    let tiering_c = Tiering::new(Arc::clone(&cfg));
+                    tiering_c.migrate_logs();
+                    Ok(())
+                })
+        });
+    }
+
+    /// Spawns a migration for every log with a cold tier
+    fn migrate_logs(&self) {
+        let read_cfg = self.config.read().unwrap();
+        let now = Utc::now();
+        for (log_name, log) in &read_cfg.log {
+            // moving deletes the objects from the hot tier
+            if let Some(until) = log.held_until(&now) {
+                info!("Not tiering {}, held until {}", log_name, until);
+                continue;
+            }
+            let (cutoff, cold) = match cold_tier(&read_cfg, log, &now) {
+                Some(tier) => tier,
+                None => continue,
+            };
+            for ds_name in &log.datastores {
+                if let Some(hot) = read_cfg.datastore.get(ds_name) {
+                    spawn_job(
+                        Arc::clone(&self.config),
+                        "tiering",
+                        &format!("{} on {}", log_name, ds_name),
+                        migrate_datastore(
+                            log_name.clone(),
+                            hot.clone(),
+                            cold.clone(),
+                            cutoff,
+                            log.bloom_filters,
+                            ObjectNaming::for_log(log),
+                        )
+                        .map(|_| ()),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The cutoff before which objects of a log belong on its cold tier along with the datastores of
+/// the tier, `None` if the log has no cold tier.
+pub fn cold_tier(
+    cfg: &Config,
+    log: &Log,
+    now: &DateTime<Utc>,
+) -> Option<(DateTime<Utc>, Vec<DataStore>)> {
+    let age = log
+        .cold_after
+        .as_ref()
+        .and_then(|a| Config::age_to_seconds(a))?;
+    let cold: Vec<DataStore> = log
+        .cold_datastores
+        .iter()
+        .filter_map(|ds_name| cfg.datastore.get(ds_name).cloned())
+        .collect();
+    if cold.is_empty() {
+        return None;
+    }
+    Some((*now - chrono::Duration::seconds(age as i64), cold))
+}
+
+/// Whether lines stored on `range` may be on the cold tier of a log. Objects are only moved there
+/// once they weren't modified for `cold_after`, so ranges starting later than that hold none.
+pub fn range_reaches_cold_tier(log: &Log, range: Option<TimeRange>, now: &DateTime<Utc>) -> bool {
+    let age = match log
+        .cold_after
+        .as_ref()
+        .and_then(|a| Config::age_to_seconds(a))
+    {
+        Some(age) => age,
+        None => return true,
+    };
+    match range.and_then(|range| range.from) {
+        Some(from) => from < *now - chrono::Duration::seconds(age as i64),
+        None => true,
+    }
+}
+
+/// Moves the objects of a log older than `cutoff` from a hot datastore to the cold datastores,
+/// one at a time, along with their bloom filters. Returns the keys of the moved objects.
+pub fn migrate_datastore(
+    log_name: String,
+    hot: DataStore,
+    cold: Vec<DataStore>,
+    cutoff: DateTime<Utc>,
+    bloom_filters: bool,
+    naming: ObjectNaming,
+) -> impl Future<Item = Vec<String>, Error = ()> {
+    let err_log_name = log_name.clone();
+    list_msl_bucket_objects(&log_name, &hot, &naming)
+        .map_err(move |e| error!("Could not list {} for tiering: {:?}", err_log_name, e))
+        .filter(move |obj| older_than(obj, &cutoff))
+        .fold(Vec::new(), move |mut moved, obj| {
+            let i = rand::thread_rng().gen_range(0, cold.len());
+            let key = obj.key.clone();
+            let (bloom_hot, bloom_cold) = (hot.clone(), cold[i].clone());
+            move_object(&hot, &cold[i], obj.key).then(move |res| {
+                match res {
+                    Ok(_) => info!("Moved {} to the cold tier", key),
+                    Err(e) => {
+                        error!("Could not move {} to the cold tier: {:?}", key, e);
+                        return Either::A(future::ok(moved));
+                    }
+                };
+                if !bloom_filters {
+                    moved.push(key);
+                    return Either::A(future::ok(moved));
+                }
+                // objects written before bloom filters were enabled have none to move
+                Either::B(
+                    move_object(&bloom_hot, &bloom_cold, bloom_key(&key)).then(move |_| {
+                        moved.push(key);
+                        future::ok(moved)
+                    }),
+                )
+            })
+        })
+}
+
+/// Whether an object was last modified before `cutoff`
+fn older_than(obj: &LogObject, cutoff: &DateTime<Utc>) -> bool {
+    match DateTime::parse_from_rfc3339(&obj.last_modified) {
+        Ok(last_modified) => last_modified.with_timezone(&Utc) < *cutoff,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tiering_tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn object_modified_at(last_modified: &str) -> LogObject {
+        LogObject {
+            key: "minsql/mylog/2019/7/1/10/a.log".to_string(),
+            size: 10,
+            last_modified: last_modified.to_string(),
+        }
+    }
+
+    #[test]
+    fn object_older_than_cutoff() {
+        let cutoff = Utc.ymd(2019, 7, 1).and_hms(12, 0, 0);
+        assert!(older_than(
+            &object_modified_at("2019-07-01T10:30:00.000Z"),
+            &cutoff
+        ));
+        assert!(!older_than(
+            &object_modified_at("2019-07-01T12:30:00.000Z"),
+            &cutoff
+        ));
+        // objects with an unknown age are left in place
+        assert!(!older_than(&object_modified_at(""), &cutoff));
+    }
+
+    #[test]
+    fn recent_ranges_skip_the_cold_tier() {
+        let now = Utc.ymd(2019, 7, 10).and_hms(12, 0, 0);
+        let mut log = Log {
+            cold_datastores: vec!["cold".to_string()],
+            cold_after: Some("7d".to_string()),
+            ..Log::default()
+        };
+        let since = |from| {
+            Some(TimeRange {
+                from: Some(from),
+                until: None,
+            })
+        };
+        assert!(!range_reaches_cold_tier(
+            &log,
+            since(Utc.ymd(2019, 7, 9).and_hms(0, 0, 0)),
+            &now
+        ));
+        assert!(range_reaches_cold_tier(
+            &log,
+            since(Utc.ymd(2019, 7, 1).and_hms(0, 0, 0)),
+            &now
+        ));
+        assert!(range_reaches_cold_tier(&log, None, &now));
+        // without an age objects may have been moved any time
+        log.cold_after = None;
+        assert!(range_reaches_cold_tier(
+            &log,
+            since(Utc.ymd(2019, 7, 9).and_hms(0, 0, 0)),
+            &now
+        ));
+    }
+}
@@ -0,0 +1,182 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use futures::Future;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request};
+use uuid::Uuid;
+
+use crate::constants::{HEADER_REQUEST_ID, HEADER_TRACEPARENT, REQUEST_ID_MAX_LEN};
+use crate::http::ResponseFuture;
+
+/// Identifies a request across services, from the `X-Request-ID` and `traceparent` headers it
+/// was sent with. Requests without a usable id get a generated one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestTrace {
+    pub request_id: String,
+    pub traceparent: Option<String>,
+}
+
+impl RequestTrace {
+    pub fn from_request(req: &Request<Body>) -> RequestTrace {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_string())
+        };
+        RequestTrace {
+            request_id: header(HEADER_REQUEST_ID)
+                .filter(|id| valid_request_id(id))
+                .unwrap_or_else(|| Uuid::new_v4().to_string()),
+            traceparent: header(HEADER_TRACEPARENT).filter(|tp| valid_traceparent(tp)),
+        }
+    }
+
+    /// The headers passing the trace along to another MinSQL server
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![(HEADER_REQUEST_ID, self.request_id.clone())];
+        if let Some(traceparent) = &self.traceparent {
+            headers.push((HEADER_TRACEPARENT, traceparent.clone()));
+        }
+        headers
+    }
+
+    /// User metadata of the objects written while serving the request
+    pub fn metadata(&self) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        metadata.insert("minsql-request-id".to_string(), self.request_id.clone());
+        if let Some(traceparent) = &self.traceparent {
+            metadata.insert("minsql-traceparent".to_string(), traceparent.clone());
+        }
+        metadata
+    }
+
+    /// How the request is told apart on the logs of the server
+    pub fn log_context(&self) -> String {
+        match &self.traceparent {
+            Some(traceparent) => {
+                format!("request_id={} traceparent={}", self.request_id, traceparent)
+            }
+            None => format!("request_id={}", self.request_id),
+        }
+    }
+}
+
+/// The trace of a request, as attached by the router
+pub fn request_trace(req: &Request<Body>) -> Option<RequestTrace> {
+    req.extensions().get::<RequestTrace>().cloned()
+}
+
+/// Request ids are kept when they are short and printable, since they are echoed back and logged
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= REQUEST_ID_MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Whether a value is a W3C `traceparent`, ie: `00-<trace id>-<parent id>-<flags>` with ids that
+/// are not all zeros
+fn valid_traceparent(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    if parts.len() != 4 {
+        return false;
+    }
+    let hex = |part: &str, len: usize| {
+        part.len() == len
+            && part
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    hex(parts[0], 2)
+        && parts[0] != "ff"
+        && hex(parts[1], 32)
+        && hex(parts[2], 16)
+        && hex(parts[3], 2)
+        && parts[1].bytes().any(|b| b != b'0')
+        && parts[2].bytes().any(|b| b != b'0')
+}
+
+/// Echoes the request id, and the trace context if any, on the response
+pub fn with_trace_headers(trace: RequestTrace, response: ResponseFuture) -> ResponseFuture {
+    Box::new(response.map(move |mut response| {
+        for (name, value) in trace.headers() {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        response
+    }))
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::*;
+
+    fn request(headers: Vec<(&str, &str)>) -> Request<Body> {
+        let mut builder = Request::builder();
+        for (name, value) in headers {
+            builder.header(name, value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn trace_from_headers() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let trace = RequestTrace::from_request(&request(vec![
+            ("X-Request-ID", "req-42"),
+            ("traceparent", traceparent),
+        ]));
+        assert_eq!(trace.request_id, "req-42");
+        assert_eq!(trace.traceparent, Some(traceparent.to_string()));
+        assert_eq!(
+            trace.log_context(),
+            format!("request_id=req-42 traceparent={}", traceparent)
+        );
+        assert_eq!(trace.metadata()["minsql-request-id"], "req-42");
+
+        // unusable values are replaced or dropped
+        let trace = RequestTrace::from_request(&request(vec![
+            ("X-Request-ID", "has spaces"),
+            (
+                "traceparent",
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            ),
+        ]));
+        assert!(Uuid::parse_str(&trace.request_id).is_ok());
+        assert_eq!(trace.traceparent, None);
+        assert_eq!(trace.headers().len(), 1);
+    }
+
+    #[test]
+    fn traceparents() {
+        assert!(valid_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        ));
+        assert!(!valid_traceparent(
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"
+        ));
+        assert!(!valid_traceparent(
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        ));
+        assert!(!valid_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01"
+        ));
+        assert!(!valid_traceparent("00-4bf92f35-00f067aa0ba902b7-01"));
+        assert!(!valid_traceparent("not a traceparent"));
+    }
+}
@@ -0,0 +1,507 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::Either;
+use futures::{future, stream, Future, Stream};
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::timer::Interval;
+use uuid::Uuid;
+
+use crate::bloom::bloom_key;
+use crate::config::{Config, DataStore, Log};
+use crate::constants::{
+    DELETED_LOGS_PREFIX, PURGE_INTERVAL_SECS, PURGE_LOCKS_PREFIX, PURGE_LOCK_TTL_SECS,
+};
+use crate::jobs::spawn_job;
+use crate::meta::ds_for_metabucket;
+use crate::naming::ObjectNaming;
+use crate::storage::{
+    delete_object, get_object_metabucket, list_metabucket_keys, list_msl_bucket_files,
+    write_object_metabucket,
+};
+use crate::supervisor;
+use crate::usage::PROCESS_ID;
+
+/// A deleted log, kept on the metabucket under `minsql/meta/_trash/logs/{log}@{ts}-{id}` until
+/// its grace period is over. Until then the log can be restored along with its data.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeletedLog {
+    pub log: Log,
+    pub deleted_at: String,
+    // whether the objects of the log are deleted once the grace period is over
+    pub purge: bool,
+}
+
+impl DeletedLog {
+    pub fn new(log: Log, now: &DateTime<Utc>, purge: bool) -> DeletedLog {
+        DeletedLog {
+            log,
+            deleted_at: now.to_rfc3339_opts(SecondsFormat::Millis, true),
+            purge,
+        }
+    }
+
+    /// Whether the grace period of the log is over at `now`, logs with an unknown deletion date
+    /// are kept
+    pub fn expired(&self, grace_secs: u64, now: &DateTime<Utc>) -> bool {
+        match DateTime::parse_from_rfc3339(&self.deleted_at) {
+            Ok(deleted_at) => {
+                deleted_at.with_timezone(&Utc) + chrono::Duration::seconds(grace_secs as i64)
+                    <= *now
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Who is purging a deleted log, and until when
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct PurgeLock {
+    owner: String,
+    expires_at: i64,
+}
+
+impl PurgeLock {
+    fn new(owner: &str, now: &DateTime<Utc>) -> PurgeLock {
+        PurgeLock {
+            owner: owner.to_string(),
+            expires_at: now.timestamp() + PURGE_LOCK_TTL_SECS,
+        }
+    }
+
+    fn held(&self, now: &DateTime<Utc>) -> bool {
+        self.expires_at > now.timestamp()
+    }
+}
+
+/// A new key of the trash for a log, every deletion gets its own so deleting a log created with
+/// the name of a log still on the trash doesn't replace it
+pub fn deleted_log_key(log_name: &str) -> String {
+    format!(
+        "{}{}@{}-{}",
+        DELETED_LOGS_PREFIX,
+        log_name,
+        Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+        Uuid::new_v4()
+    )
+}
+
+/// The name of the log deleted under a key of the trash
+pub fn deleted_log_name(key: &str) -> Option<&str> {
+    if !key.starts_with(DELETED_LOGS_PREFIX) {
+        return None;
+    }
+    let entry = &key[DELETED_LOGS_PREFIX.len()..];
+    let at = entry.rfind('@')?;
+    if entry[at + 1..].contains('/') {
+        return None;
+    }
+    Some(&entry[..at])
+}
+
+/// The keys of the trash a log was deleted under, oldest first
+pub fn deleted_log_keys(
+    cfg: Arc<RwLock<Config>>,
+    log_name: &str,
+) -> impl Future<Item = Vec<String>, Error = String> {
+    let log_name = log_name.to_string();
+    list_metabucket_keys(cfg, format!("{}{}@", DELETED_LOGS_PREFIX, log_name))
+        .map_err(|e| format!("Could not list the trash: {:?}", e))
+        .map(move |keys| {
+            let mut keys: Vec<String> = keys
+                .into_iter()
+                .filter(|key| deleted_log_name(key) == Some(log_name.as_str()))
+                .collect();
+            keys.sort();
+            keys
+        })
+}
+
+/// Stores a deleted log on the trash of the metabucket
+pub fn write_deleted_log(
+    cfg: Arc<RwLock<Config>>,
+    deleted: &DeletedLog,
+) -> impl Future<Item = (), Error = String> {
+    let log_name = deleted.log.name.clone().unwrap_or_default();
+    write_object_metabucket(
+        cfg,
+        deleted_log_key(&log_name),
+        serde_json::to_string(deleted).unwrap(),
+    )
+    .map(|_| ())
+    .map_err(|e| format!("Could not move the log to the trash: {}", e.reason()))
+}
+
+/// The deleted log stored under a key of the trash, `None` if there's no such key
+pub fn read_deleted_log(
+    cfg: Arc<RwLock<Config>>,
+    key: String,
+) -> impl Future<Item = Option<DeletedLog>, Error = String> {
+    get_object_metabucket(cfg, key.clone())
+        .map_err(|e| format!("Could not read the trash: {:?}", e))
+        .and_then(move |payload| match payload {
+            Some(payload) => serde_json::from_str::<DeletedLog>(&payload)
+                .map(Some)
+                .map_err(|e| format!("Invalid deleted log {}: {}", key, e)),
+            None => Ok(None),
+        })
+}
+
+/// The last deletion of a log still on the trash along with its key, `None` if there's none
+pub fn latest_deleted_log(
+    cfg: Arc<RwLock<Config>>,
+    log_name: &str,
+) -> impl Future<Item = Option<(String, DeletedLog)>, Error = String> {
+    deleted_log_keys(Arc::clone(&cfg), log_name).and_then(move |keys| match keys.last() {
+        Some(key) => {
+            let key = key.clone();
+            Either::A(read_deleted_log(cfg, key.clone()).map(move |d| d.map(|d| (key, d))))
+        }
+        None => Either::B(future::ok(None)),
+    })
+}
+
+/// Removes a deleted log from the trash of the metabucket
+pub fn remove_deleted_log(
+    cfg: Arc<RwLock<Config>>,
+    key: String,
+) -> impl Future<Item = (), Error = String> {
+    let datastore = ds_for_metabucket(cfg);
+    delete_object(&datastore, key)
+        .map(|_| ())
+        .map_err(|e| format!("Could not remove the log from the trash: {}", e.reason()))
+}
+
+fn purge_lock_key(key: &str) -> String {
+    format!(
+        "{}{}",
+        PURGE_LOCKS_PREFIX,
+        &key[DELETED_LOGS_PREFIX.len().min(key.len())..]
+    )
+}
+
+fn read_purge_lock(
+    cfg: Arc<RwLock<Config>>,
+    lock_key: String,
+) -> impl Future<Item = Option<PurgeLock>, Error = String> {
+    get_object_metabucket(cfg, lock_key)
+        .map(|payload| payload.and_then(|p| serde_json::from_str::<PurgeLock>(&p).ok()))
+        .map_err(|e| format!("Could not read the purge lock: {:?}", e))
+}
+
+/// Takes the lock on purging a deleted log for this process, `false` if any process holds it,
+/// this one included. The lock is read back once written so that of two processes writing it at
+/// once only the last one purges, at worst both purge and the second finds less to delete.
+fn acquire_purge_lock(
+    cfg: Arc<RwLock<Config>>,
+    key: &str,
+) -> impl Future<Item = bool, Error = String> {
+    let lock_key = purge_lock_key(key);
+    read_purge_lock(Arc::clone(&cfg), lock_key.clone()).and_then(move |current| {
+        if current.map_or(false, |lock| lock.held(&Utc::now())) {
+            return Either::A(future::ok(false));
+        }
+        let lock = PurgeLock::new(&PROCESS_ID, &Utc::now());
+        let read_cfg = Arc::clone(&cfg);
+        let read_key = lock_key.clone();
+        Either::B(
+            write_object_metabucket(cfg, lock_key, serde_json::to_string(&lock).unwrap())
+                .map_err(|e| format!("Could not write the purge lock: {}", e.reason()))
+                .and_then(move |_| read_purge_lock(read_cfg, read_key))
+                .map(move |written| written == Some(lock)),
+        )
+    })
+}
+
+fn release_purge_lock(cfg: Arc<RwLock<Config>>, key: &str) -> impl Future<Item = (), Error = ()> {
+    let lock_key = purge_lock_key(key);
+    delete_object(&ds_for_metabucket(cfg), lock_key.clone())
+        .map(|_| ())
+        .map_err(move |e| error!("Could not release {}: {:?}", lock_key, e))
+}
+
+pub struct Trash {
+    config: Arc<RwLock<Config>>,
+}
+
+impl Trash {
+    pub fn new(cfg: Arc<RwLock<Config>>) -> Trash {
+        Trash { config: cfg }
+    }
+
+    /// Starts the task that periodically empties the trash of the logs whose grace period is
+    /// over, deleting their objects if they were deleted with `purge`.
+    pub fn start_purge_task(&self) {
+        let cfg = Arc::clone(&self.config);
+        supervisor::spawn_supervised("Trash purge task".to_string(), move || {
+            let cfg = Arc::clone(&cfg);
+            Interval::new(Instant::now(), Duration::from_secs(PURGE_INTERVAL_SECS))
+                .map_err(|e| error!("purge interval errored; err={:?}", e))
+                .for_each(move |_| {
+                    let trash_c = Trash::new(Arc::clone(&cfg));
+                    trash_c.empty_expired()
+                })
+        });
+    }
+
+    /// Spawns a purge job for every deleted log past its grace period that no process is
+    /// purging already
+    fn empty_expired(&self) -> impl Future<Item = (), Error = ()> {
+        let cfg = Arc::clone(&self.config);
+        list_metabucket_keys(Arc::clone(&cfg), DELETED_LOGS_PREFIX.to_string())
+            .map_err(|e| error!("Could not list the trash: {:?}", e))
+            .and_then(move |keys| {
+                let read_cfg = Arc::clone(&cfg);
+                let reads = keys.into_iter().map(move |key| {
+                    read_deleted_log(Arc::clone(&read_cfg), key.clone()).then(
+                        move |res| match res {
+                            Ok(deleted) => Ok(deleted.map(|deleted| (key, deleted))),
+                            Err(e) => {
+                                error!("{}", e);
+                                Ok(None)
+                            }
+                        },
+                    )
+                });
+                future::join_all(reads).and_then(move |entries| {
+                    let entries: Vec<(String, DeletedLog)> =
+                        entries.into_iter().filter_map(|entry| entry).collect();
+                    let read_cfg = cfg.read().unwrap();
+                    let grace_secs = read_cfg.server.deleted_log_grace_secs;
+                    let now = Utc::now();
+                    let mut expired = Vec::new();
+                    for (key, deleted) in &entries {
+                        let log_name = match deleted_log_name(key) {
+                            Some(log_name) => log_name.to_string(),
+                            None => continue,
+                        };
+                        if deleted.expired(grace_secs, &now) {
+                            let kept = other_generations(&read_cfg, &log_name, key, &entries);
+                            expired.push((key.clone(), log_name, deleted.clone(), kept));
+                        }
+                    }
+                    drop(read_cfg);
+                    let purges = expired
+                        .into_iter()
+                        .map(move |(key, log_name, deleted, kept)| {
+                            let cfg = Arc::clone(&cfg);
+                            // a large purge may still be running from the last pass, here or on
+                            // another process
+                            acquire_purge_lock(Arc::clone(&cfg), &key).then(move |res| {
+                                match res {
+                                    Ok(true) => {
+                                        let release_cfg = Arc::clone(&cfg);
+                                        let release_key = key.clone();
+                                        let task =
+                                            purge_deleted_log(Arc::clone(&cfg), key, deleted, kept)
+                                                .then(move |res| {
+                                                    release_purge_lock(release_cfg, &release_key)
+                                                        .then(move |_| res)
+                                                });
+                                        spawn_job(cfg, "purge", &log_name, task);
+                                    }
+                                    Ok(false) => (),
+                                    Err(e) => error!("{}", e),
+                                }
+                                Ok(())
+                            })
+                        });
+                    future::join_all(purges).map(|_| ())
+                })
+            })
+    }
+}
+
+/// How the objects of the other generations of a log are named: the log created with its name
+/// since and the other times it was deleted. Their objects are left alone by a purge.
+fn other_generations(
+    cfg: &Config,
+    log_name: &str,
+    key: &str,
+    entries: &[(String, DeletedLog)],
+) -> Vec<ObjectNaming> {
+    let deleted_logs = entries
+        .iter()
+        .filter(|(other, _)| other != key && deleted_log_name(other) == Some(log_name))
+        .map(|(_, deleted)| &deleted.log);
+    cfg.log
+        .get(log_name)
+        .into_iter()
+        .chain(deleted_logs)
+        .map(ObjectNaming::for_log)
+        .collect()
+}
+
+/// Whether an object of a log is one of the objects of another generation of the log
+fn of_generation(log_name: &str, object_key: &str, namings: &[ObjectNaming]) -> bool {
+    let base = format!("minsql/{}/", log_name);
+    namings.iter().any(|naming| {
+        object_key.starts_with(&naming.prefix(log_name))
+            && object_key.starts_with(&base)
+            && naming.matches(&object_key[base.len()..])
+    })
+}
+
+/// Deletes the objects of a deleted log if it was deleted with `purge`, then removes it from the
+/// trash. The objects of a log created since with the same name, or deleted another time, are
+/// kept, which are all of them if it names its objects the same way.
+fn purge_deleted_log(
+    cfg: Arc<RwLock<Config>>,
+    key: String,
+    deleted: DeletedLog,
+    kept: Vec<ObjectNaming>,
+) -> impl Future<Item = (), Error = ()> {
+    let log_name = deleted_log_name(&key).unwrap_or_default().to_string();
+    let read_cfg = cfg.read().unwrap();
+    let datastores: Vec<DataStore> = deleted
+        .log
+        .all_datastores()
+        .iter()
+        .filter_map(|ds_name| read_cfg.datastore.get(ds_name).cloned())
+        .collect();
+    drop(read_cfg);
+    let naming = ObjectNaming::for_log(&deleted.log);
+    if deleted.purge && kept.contains(&naming) {
+        warn!(
+            "Not deleting the objects of {}, another log with the same name names its objects the same way",
+            log_name
+        );
+    }
+    let delete_objects = if deleted.purge && !kept.contains(&naming) {
+        Either::A(delete_log_objects(
+            log_name.clone(),
+            datastores,
+            naming,
+            kept,
+        ))
+    } else {
+        Either::B(future::ok(0))
+    };
+    delete_objects.and_then(move |deleted_objects| {
+        info!(
+            "Emptying {} from the trash, {} objects deleted",
+            log_name, deleted_objects
+        );
+        remove_deleted_log(cfg, key).map_err(|e| error!("{}", e))
+    })
+}
+
+/// Deletes every object of a log along with its bloom filter, but the objects of its other
+/// generations, returning how many were deleted. Fails if an object couldn't be listed or
+/// deleted, so the purge is tried again later.
+fn delete_log_objects(
+    log_name: String,
+    datastores: Vec<DataStore>,
+    naming: ObjectNaming,
+    kept: Vec<ObjectNaming>,
+) -> impl Future<Item = u64, Error = ()> {
+    stream::iter_ok::<_, ()>(datastores)
+        .and_then(move |ds| {
+            let err_log_name = log_name.clone();
+            let kept_log_name = log_name.clone();
+            let kept = kept.clone();
+            list_msl_bucket_files(&log_name, &ds, &naming)
+                .map_err(move |e| error!("Could not list {} to purge it: {:?}", err_log_name, e))
+                .filter(move |key| !of_generation(&kept_log_name, key, &kept))
+                .and_then(move |key| {
+                    let bloom_ds = ds.clone();
+                    let bloom = bloom_key(&key);
+                    delete_object(&ds, key.clone())
+                        .map_err(move |e| error!("Could not purge {}: {:?}", key, e))
+                        // objects without a bloom filter have nothing to delete
+                        .and_then(move |_| delete_object(&bloom_ds, bloom).then(|_| Ok(())))
+                })
+                .fold(0, |deleted, _| Ok::<u64, ()>(deleted + 1))
+        })
+        .fold(0, |total, deleted| Ok::<u64, ()>(total + deleted))
+}
+
+#[cfg(test)]
+mod trash_tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn grace_period() {
+        let deleted_at = Utc.ymd(2019, 7, 24).and_hms(9, 30, 0);
+        let deleted = DeletedLog::new(Log::default(), &deleted_at, true);
+        assert_eq!(deleted.deleted_at, "2019-07-24T09:30:00.000Z");
+        let grace_secs = 24 * 60 * 60;
+        assert!(!deleted.expired(grace_secs, &Utc.ymd(2019, 7, 25).and_hms(9, 29, 59)));
+        assert!(deleted.expired(grace_secs, &Utc.ymd(2019, 7, 25).and_hms(9, 30, 0)));
+
+        let unknown = DeletedLog {
+            deleted_at: "".to_string(),
+            ..deleted
+        };
+        assert!(!unknown.expired(0, &Utc.ymd(2019, 7, 25).and_hms(9, 30, 0)));
+    }
+
+    #[test]
+    fn trash_keys() {
+        let first = deleted_log_key("team/applogs");
+        let second = deleted_log_key("team/applogs");
+        assert!(first.starts_with("minsql/meta/_trash/logs/team/applogs@"));
+        assert_ne!(first, second);
+        assert_eq!(deleted_log_name(&first), Some("team/applogs"));
+        assert_eq!(deleted_log_name(&deleted_log_key("a@b")), Some("a@b"));
+        assert_eq!(
+            deleted_log_name("minsql/meta/_trash/logs/team/applogs"),
+            None
+        );
+        assert_eq!(deleted_log_name("minsql/meta/logs/applogs@x"), None);
+    }
+
+    #[test]
+    fn purge_locks() {
+        let now = Utc.ymd(2019, 7, 24).and_hms(9, 30, 0);
+        let lock = PurgeLock::new("process", &now);
+        assert!(lock.held(&now));
+        assert!(!lock.held(&(now + chrono::Duration::seconds(PURGE_LOCK_TTL_SECS))));
+        assert_eq!(
+            purge_lock_key("minsql/meta/_trash/logs/applogs@20190724T093000.000000Z-id"),
+            "minsql/meta/_locks/purge/applogs@20190724T093000.000000Z-id"
+        );
+    }
+
+    #[test]
+    fn other_generations_are_kept() {
+        let old =
+            ObjectNaming::parse("tenant={partition}/{year}/{month}/{day}/{hour}/{uuid}").unwrap();
+        let recreated = ObjectNaming::parse(
+            "gen-1a2b3c4d/tenant={partition}/{year}/{month}/{day}/{hour}/{uuid}",
+        )
+        .unwrap();
+        let kept = vec![recreated];
+        assert!(of_generation(
+            "applogs",
+            "minsql/applogs/gen-1a2b3c4d/tenant=a/2019/07/24/09/id.log",
+            &kept
+        ));
+        assert!(!of_generation(
+            "applogs",
+            "minsql/applogs/tenant=a/2019/07/24/09/id.log",
+            &kept
+        ));
+        assert!(old.matches("gen-1a2b3c4d/2019/07/24/09/id.log"));
+    }
+}
@@ -0,0 +1,384 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDate, Utc};
+use futures::{future, stream, Future, Stream};
+use lazy_static::lazy_static;
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use tokio::timer::Interval;
+use uuid::Uuid;
+
+use crate::auth::access_key_of;
+use crate::config::Config;
+use crate::constants::{USAGE_PERSIST_SECS, USAGE_PREFIX};
+use crate::storage::{get_object_metabucket, list_metabucket_keys, write_object_metabucket};
+use crate::supervisor;
+
+lazy_static! {
+    // Usage is stored per process so processes never overwrite each other's counters, it also
+    // tells the purge locks of this process apart
+    pub static ref PROCESS_ID: String = Uuid::new_v4().to_string();
+    static ref USAGE: Mutex<UsageLedger> = Mutex::new(UsageLedger::default());
+}
+
+/// Cumulative bytes a log or a token accounted for
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    // bytes of the lines read by searches
+    pub bytes_scanned: u64,
+    // bytes of the lines accepted for storage
+    pub bytes_stored: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.bytes_scanned += other.bytes_scanned;
+        self.bytes_stored += other.bytes_stored;
+    }
+}
+
+/// Usage of a single day, as stored on the metabucket
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UsageDay {
+    pub logs: HashMap<String, Usage>,
+    pub tokens: HashMap<String, Usage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsageGroup {
+    Log,
+    Token,
+}
+
+impl UsageGroup {
+    pub fn parse(value: &str) -> Option<UsageGroup> {
+        match value {
+            "log" => Some(UsageGroup::Log),
+            "token" => Some(UsageGroup::Token),
+            _ => None,
+        }
+    }
+}
+
+/// Usage of a log or token over a period, as listed by `GET /api/usage`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub name: String,
+    pub bytes_scanned: u64,
+    pub bytes_stored: u64,
+}
+
+/// Usage of this process keyed by UTC day, days already written and not changed since are
+/// dropped.
+#[derive(Default)]
+struct UsageLedger {
+    days: HashMap<String, UsageDay>,
+    // days changed since they were last written
+    dirty: Vec<String>,
+}
+
+impl UsageLedger {
+    fn record(&mut self, day: &str, log_name: &str, access_token: &str, usage: &Usage) {
+        let entry = self.days.entry(day.to_string()).or_default();
+        entry
+            .logs
+            .entry(log_name.to_string())
+            .or_default()
+            .add(usage);
+        entry
+            .tokens
+            .entry(usage_account(access_token))
+            .or_default()
+            .add(usage);
+        if !self.dirty.iter().any(|d| d == day) {
+            self.dirty.push(day.to_string());
+        }
+    }
+
+    /// Takes the days to write, forgetting the past ones as they won't change anymore
+    fn take_dirty(&mut self, today: &str) -> Vec<(String, UsageDay)> {
+        let dirty: Vec<String> = self.dirty.drain(..).collect();
+        let days = dirty
+            .iter()
+            .filter_map(|day| self.days.get(day).map(|usage| (day.clone(), usage.clone())))
+            .collect();
+        self.days.retain(|day, _| day.as_str() >= today);
+        days
+    }
+
+    /// Puts back a day that could not be written, today's usage kept counting meanwhile
+    fn requeue(&mut self, day: String, usage: UsageDay) {
+        if !self.dirty.contains(&day) {
+            self.dirty.push(day.clone());
+        }
+        self.days.entry(day).or_insert(usage);
+    }
+
+    fn days_in(&self, period: &str) -> Vec<UsageDay> {
+        self.days
+            .iter()
+            .filter(|(day, _)| day.starts_with(period))
+            .map(|(_, usage)| usage.clone())
+            .collect()
+    }
+}
+
+/// Name the usage of a token is accounted to, its access key so the secret never makes it to the
+/// metabucket. Names that aren't tokens, ie: the one of load tests, are cut just as long.
+fn usage_account(access_token: &str) -> String {
+    match access_key_of(access_token) {
+        Some(access_key) => access_key.to_string(),
+        None => access_token.chars().take(16).collect(),
+    }
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+/// Accounts the bytes of the lines a search on `log_name` read for `access_token`
+pub fn record_scanned(log_name: &str, access_token: &str, bytes: u64) {
+    let usage = Usage {
+        bytes_scanned: bytes,
+        bytes_stored: 0,
+    };
+    USAGE
+        .lock()
+        .unwrap()
+        .record(&today(), log_name, access_token, &usage);
+}
+
+/// Accounts the bytes of the lines `access_token` stored on `log_name`
+pub fn record_stored(log_name: &str, access_token: &str, bytes: u64) {
+    let usage = Usage {
+        bytes_scanned: 0,
+        bytes_stored: bytes,
+    };
+    USAGE
+        .lock()
+        .unwrap()
+        .record(&today(), log_name, access_token, &usage);
+}
+
+/// Whether `period` is a day, `2019-08-01`, or a month, `2019-08`
+pub fn valid_period(period: &str) -> bool {
+    match period.len() {
+        10 => NaiveDate::parse_from_str(period, "%Y-%m-%d").is_ok(),
+        7 => NaiveDate::parse_from_str(&format!("{}-01", period), "%Y-%m-%d").is_ok(),
+        _ => false,
+    }
+}
+
+/// The current month, the period reported when none is asked for
+pub fn current_period() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+/// Sums the usage of several days per log or per token, ordered by name
+pub fn group_usage(days: &[UsageDay], group: UsageGroup) -> Vec<UsageRow> {
+    let mut totals: HashMap<&String, Usage> = HashMap::new();
+    for day in days {
+        let entries = match group {
+            UsageGroup::Log => &day.logs,
+            UsageGroup::Token => &day.tokens,
+        };
+        for (name, usage) in entries {
+            totals.entry(name).or_default().add(usage);
+        }
+    }
+    let mut rows: Vec<UsageRow> = totals
+        .into_iter()
+        .map(|(name, usage)| UsageRow {
+            name: name.clone(),
+            bytes_scanned: usage.bytes_scanned,
+            bytes_stored: usage.bytes_stored,
+        })
+        .collect();
+    rows.sort_by(|a, b| a.name.cmp(&b.name));
+    rows
+}
+
+fn usage_key(day: &str, process: &str) -> String {
+    format!("{}{}/{}", USAGE_PREFIX, day, process)
+}
+
+/// Usage of every process over `period`. The days this process still holds replace what it
+/// stored for them, objects that can't be read are skipped.
+pub fn usage_in_period(
+    cfg: Arc<RwLock<Config>>,
+    period: String,
+) -> impl Future<Item = Vec<UsageDay>, Error = String> {
+    list_metabucket_keys(Arc::clone(&cfg), format!("{}{}", USAGE_PREFIX, period))
+        .map_err(|e| format!("Could not list the usage: {:?}", e))
+        .and_then(move |keys| {
+            let (held_keys, local) = {
+                let ledger = USAGE.lock().unwrap();
+                let held_keys: Vec<String> = ledger
+                    .days
+                    .keys()
+                    .map(|day| usage_key(day, &PROCESS_ID))
+                    .collect();
+                (held_keys, ledger.days_in(&period))
+            };
+            let reads = keys
+                .into_iter()
+                .filter(move |key| !held_keys.contains(key))
+                .map(move |key| {
+                    get_object_metabucket(Arc::clone(&cfg), key)
+                        .map(|payload| {
+                            payload.and_then(|p| serde_json::from_str::<UsageDay>(&p).ok())
+                        })
+                        .or_else(|_| Ok(None))
+                });
+            future::join_all(reads).map(move |days| {
+                let mut days: Vec<UsageDay> = days.into_iter().filter_map(|d| d).collect();
+                days.extend(local);
+                days
+            })
+        })
+}
+
+/// Writes the days this process changed since the last pass
+fn persist_usage(cfg: Arc<RwLock<Config>>) -> impl Future<Item = (), Error = ()> {
+    let days = USAGE.lock().unwrap().take_dirty(&today());
+    stream::iter_ok::<_, ()>(days).for_each(move |(day, usage)| {
+        let key = usage_key(&day, &PROCESS_ID);
+        let payload = serde_json::to_string(&usage).unwrap();
+        write_object_metabucket(Arc::clone(&cfg), key, payload)
+            .map(|_| ())
+            .or_else(move |e| {
+                // written again on the next pass
+                error!("Could not store the usage of {}: {}", day, e.reason());
+                USAGE.lock().unwrap().requeue(day, usage);
+                Ok(())
+            })
+    })
+}
+
+/// Periodically writes the usage of this process to the metabucket
+pub fn start_usage_task(cfg: Arc<RwLock<Config>>) {
+    supervisor::spawn_supervised("Usage persist task".to_string(), move || {
+        let cfg = Arc::clone(&cfg);
+        Interval::new(Instant::now(), Duration::from_secs(USAGE_PERSIST_SECS))
+            .map_err(|e| error!("usage interval errored; err={:?}", e))
+            .for_each(move |_| persist_usage(Arc::clone(&cfg)))
+    });
+}
+
+#[cfg(test)]
+mod usage_tests {
+    use super::*;
+
+    // access key followed by the secret, as tokens are sent on requests
+    const TOKEN1: &str = "TOKEN1TOKEN1TOKESECRET1SECRET1SECRET1SECRET1SECR";
+    const TOKEN2: &str = "TOKEN2TOKEN2TOKESECRET2SECRET2SECRET2SECRET2SECR";
+
+    fn stored(bytes: u64) -> Usage {
+        Usage {
+            bytes_scanned: 0,
+            bytes_stored: bytes,
+        }
+    }
+
+    fn scanned(bytes: u64) -> Usage {
+        Usage {
+            bytes_scanned: bytes,
+            bytes_stored: 0,
+        }
+    }
+
+    #[test]
+    fn usage_grouped_by_log_and_token() {
+        let mut ledger = UsageLedger::default();
+        ledger.record("2019-08-01", "mylog", TOKEN1, &stored(100));
+        ledger.record("2019-08-01", "mylog", TOKEN2, &scanned(40));
+        ledger.record("2019-08-02", "otherlog", TOKEN1, &scanned(10));
+        ledger.record("2019-09-01", "mylog", TOKEN1, &stored(1000));
+
+        let august = ledger.days_in("2019-08");
+        assert_eq!(
+            group_usage(&august, UsageGroup::Log),
+            vec![
+                UsageRow {
+                    name: "mylog".to_string(),
+                    bytes_scanned: 40,
+                    bytes_stored: 100,
+                },
+                UsageRow {
+                    name: "otherlog".to_string(),
+                    bytes_scanned: 10,
+                    bytes_stored: 0,
+                },
+            ]
+        );
+        assert_eq!(
+            group_usage(&august, UsageGroup::Token),
+            vec![
+                UsageRow {
+                    name: "TOKEN1TOKEN1TOKE".to_string(),
+                    bytes_scanned: 10,
+                    bytes_stored: 100,
+                },
+                UsageRow {
+                    name: "TOKEN2TOKEN2TOKE".to_string(),
+                    bytes_scanned: 40,
+                    bytes_stored: 0,
+                },
+            ]
+        );
+        let day = ledger.days_in("2019-08-02");
+        assert_eq!(group_usage(&day, UsageGroup::Log).len(), 1);
+        // the secrets of the tokens are not kept
+        assert!(!serde_json::to_string(&august).unwrap().contains("SECRET"));
+    }
+
+    #[test]
+    fn written_past_days_are_forgotten() {
+        let mut ledger = UsageLedger::default();
+        ledger.record("2019-08-01", "mylog", TOKEN1, &stored(100));
+        ledger.record("2019-08-02", "mylog", TOKEN1, &stored(50));
+        ledger.record("2019-08-02", "mylog", TOKEN1, &stored(50));
+
+        let mut written = ledger.take_dirty("2019-08-02");
+        written.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[1].1.logs["mylog"], stored(100));
+        // today keeps counting from where it was
+        assert!(ledger.take_dirty("2019-08-02").is_empty());
+        assert_eq!(ledger.days_in("2019-08").len(), 1);
+
+        // a day that failed to be written is kept until the next pass
+        let (day, usage) = written.remove(0);
+        ledger.requeue(day, usage);
+        assert_eq!(ledger.days_in("2019-08").len(), 2);
+        assert_eq!(ledger.take_dirty("2019-08-02").len(), 1);
+    }
+
+    #[test]
+    fn usage_periods() {
+        assert!(valid_period("2019-08"));
+        assert!(valid_period("2019-08-01"));
+        assert!(!valid_period("2019-13"));
+        assert!(!valid_period("2019-08-32"));
+        assert!(!valid_period("2019"));
+        assert!(!valid_period("../../x"));
+    }
+}
@@ -0,0 +1,127 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde_derive::Serialize;
+
+use crate::config::Config;
+use crate::hyperscan::{hyperscan_supported, hyperscan_version};
+
+// Set by the build script
+const GIT_COMMIT: &str = env!("MINSQL_GIT_COMMIT");
+const BUILD_TIMESTAMP: &str = env!("MINSQL_BUILD_TIMESTAMP");
+
+/// What `GET /api/version` reports, to tell apart the builds running across a fleet
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub build_date: String,
+    pub features: Features,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Features {
+    // whether the server is serving HTTPS
+    pub tls: bool,
+    // version of Hyperscan, noting when the smart fields fall back to the regex engine
+    pub hyperscan: String,
+    // where logs are stored and ingested from, and the identity providers users log in with
+    pub backends: Vec<&'static str>,
+}
+
+/// Build of the running server along with the features its configuration enables
+pub fn build_info(cfg: &Config) -> BuildInfo {
+    let mut backends = vec!["s3", "elasticsearch", "loki"];
+    if let Some(providers) = &cfg.server.auth_providers {
+        if providers.ldap.is_some() {
+            backends.push("ldap");
+        }
+        if providers.oidc.is_some() {
+            backends.push("oidc");
+        }
+    }
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: GIT_COMMIT,
+        build_date: build_date(BUILD_TIMESTAMP),
+        features: Features {
+            tls: cfg.server.pkcs12_cert.is_some() && cfg.server.pkcs12_password.is_some(),
+            hyperscan: if hyperscan_supported() {
+                hyperscan_version()
+            } else {
+                format!("{} (unsupported, regex fallback)", hyperscan_version())
+            },
+            backends,
+        },
+    }
+}
+
+/// Logged as the server starts
+pub fn banner(info: &BuildInfo) -> String {
+    format!(
+        "MinSQL {} (commit {}, built {}), tls: {}, hyperscan: {}, backends: {}",
+        info.version,
+        info.commit,
+        info.build_date,
+        if info.features.tls {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        info.features.hyperscan,
+        info.features.backends.join(", ")
+    )
+}
+
+/// RFC 3339 date of a build from its unix timestamp
+fn build_date(timestamp: &str) -> String {
+    match timestamp.parse::<i64>() {
+        Ok(secs) => Utc
+            .timestamp(secs, 0)
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod version_tests {
+    use super::*;
+
+    #[test]
+    fn build_dates() {
+        assert_eq!(build_date("1563960600"), "2019-07-24T09:30:00Z");
+        assert_eq!(build_date(""), "unknown");
+    }
+
+    #[test]
+    fn banner_lists_features() {
+        let info = BuildInfo {
+            version: "0.1.0",
+            commit: "bf5fe26a1b2c",
+            build_date: "2019-07-24T09:30:00Z".to_string(),
+            features: Features {
+                tls: true,
+                hyperscan: "5.1.1 2019-07-24".to_string(),
+                backends: vec!["s3", "ldap"],
+            },
+        };
+        assert_eq!(
+            banner(&info),
+            "MinSQL 0.1.0 (commit bf5fe26a1b2c, built 2019-07-24T09:30:00Z), tls: enabled, hyperscan: 5.1.1 2019-07-24, backends: s3, ldap"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc;
+use futures::Stream;
+use lazy_static::lazy_static;
+use log::error;
+use tokio::timer::Interval;
+
+use crate::constants::{WATCH_HEARTBEAT_SECS, WATCH_QUEUE_BATCHES};
+use crate::supervisor;
+
+lazy_static! {
+    // Queues of the clients watching a log, keyed by log
+    static ref WATCHERS: Mutex<HashMap<String, Vec<mpsc::Sender<Vec<String>>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Starts the task sending an empty batch to every watcher once in a while. Watchers turn it into
+/// a heartbeat, so the ones whose client went away are noticed even on quiet logs.
+pub fn start_watch_task() {
+    supervisor::spawn_supervised("Watch task".to_string(), || {
+        let every = Duration::from_secs(WATCH_HEARTBEAT_SECS);
+        Interval::new(Instant::now() + every, every)
+            .map_err(|e| error!("watch interval errored; err={:?}", e))
+            .for_each(|_| {
+                let logs: Vec<String> = WATCHERS.lock().unwrap().keys().cloned().collect();
+                for log_name in logs {
+                    send(&log_name, Vec::new());
+                }
+                Ok(())
+            })
+    });
+}
+
+/// Receives the lines of a log as they're ingested, ahead of being flushed to its datastores.
+/// A watcher falling more than `WATCH_QUEUE_BATCHES` batches behind is dropped, its receiver
+/// then ends.
+pub fn watch(log_name: &str) -> mpsc::Receiver<Vec<String>> {
+    let (tx, rx) = mpsc::channel(WATCH_QUEUE_BATCHES);
+    WATCHERS
+        .lock()
+        .unwrap()
+        .entry(log_name.to_string())
+        .or_insert_with(Vec::new)
+        .push(tx);
+    rx
+}
+
+/// Hands the lines of a payload ingested on a log to its watchers, if any
+pub fn publish_lines(log_name: &str, payload: &str) {
+    if !WATCHERS.lock().unwrap().contains_key(log_name) {
+        return;
+    }
+    let lines: Vec<String> = payload
+        .split('\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect();
+    if !lines.is_empty() {
+        send(log_name, lines);
+    }
+}
+
+/// Sends a batch to the watchers of a log, forgetting the ones that are gone or too far behind
+fn send(log_name: &str, lines: Vec<String>) {
+    let mut watchers = WATCHERS.lock().unwrap();
+    let queues = match watchers.get_mut(log_name) {
+        Some(queues) => queues,
+        None => return,
+    };
+    let mut kept = Vec::new();
+    for mut tx in queues.drain(..) {
+        if tx.try_send(lines.clone()).is_ok() {
+            kept.push(tx);
+        }
+    }
+    *queues = kept;
+    if queues.is_empty() {
+        watchers.remove(log_name);
+    }
+}
+
+/// How many clients watch a log
+pub fn watchers(log_name: &str) -> usize {
+    WATCHERS
+        .lock()
+        .unwrap()
+        .get(log_name)
+        .map_or(0, |queues| queues.len())
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    #[test]
+    fn watchers_get_the_lines() {
+        let first = watch("watched");
+        let second = watch("watched");
+        publish_lines("watched", "line 1\n\nline 2\n");
+        publish_lines("unwatched", "line 3\n");
+        assert_eq!(watchers("watched"), 2);
+        assert_eq!(watchers("unwatched"), 0);
+        let expected = vec!["line 1".to_string(), "line 2".to_string()];
+        assert_eq!(first.wait().next(), Some(Ok(expected.clone())));
+        assert_eq!(second.wait().next(), Some(Ok(expected)));
+    }
+
+    #[test]
+    fn gone_watchers_are_forgotten() {
+        let gone = watch("abandoned");
+        drop(gone);
+        publish_lines("abandoned", "line 1\n");
+        assert_eq!(watchers("abandoned"), 0);
+    }
+
+    #[test]
+    fn slow_watchers_are_dropped() {
+        let slow = watch("busy");
+        for _ in 0..WATCH_QUEUE_BATCHES + 2 {
+            publish_lines("busy", "line\n");
+        }
+        assert_eq!(watchers("busy"), 0);
+        let received = slow.wait().count();
+        assert!(received >= WATCH_QUEUE_BATCHES && received < WATCH_QUEUE_BATCHES + 2);
+    }
+}
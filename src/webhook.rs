@@ -0,0 +1,77 @@
+// This file is part of MinSQL
+// Copyright (c) 2019 MinIO, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future::Either;
+use futures::{future, Future};
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use url::Url;
+
+use crate::constants::APP_JSON;
+
+lazy_static! {
+    // Shared so connections to the same webhooks are reused
+    static ref WEBHOOK_CLIENT: Option<Client<HttpsConnector<HttpConnector>>> =
+        HttpsConnector::new(1)
+            .map(|https| Client::builder().build(https))
+            .ok();
+}
+
+/// Whether the url can be used as a webhook, only `http` and `https` are supported
+pub fn valid_webhook_url(url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(url) => url.scheme() == "http" || url.scheme() == "https",
+        Err(_) => false,
+    }
+}
+
+/// POSTs a JSON body to a webhook, the webhook has to answer with a success status
+pub fn post_json(url: &str, body: String) -> impl Future<Item = (), Error = String> {
+    let client = match WEBHOOK_CLIENT.as_ref() {
+        Some(client) => client,
+        None => return Either::B(future::err("Could not set up TLS".to_string())),
+    };
+    let req = match Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, APP_JSON)
+        .body(Body::from(body))
+    {
+        Ok(req) => req,
+        Err(e) => return Either::B(future::err(format!("Invalid webhook {}: {}", url, e))),
+    };
+    let url = url.to_string();
+    Either::A(client.request(req).then(move |res| match res {
+        Ok(resp) if resp.status().is_success() => Ok(()),
+        Ok(resp) => Err(format!("Webhook {} answered {}", url, resp.status())),
+        Err(e) => Err(format!("Could not reach webhook {}: {}", url, e)),
+    }))
+}
+
+#[cfg(test)]
+mod webhook_tests {
+    use super::*;
+
+    #[test]
+    fn webhook_urls() {
+        assert!(valid_webhook_url("https://hooks.example.com/minsql"));
+        assert!(valid_webhook_url("http://10.0.0.1:8080/report"));
+        assert!(!valid_webhook_url("ftp://example.com/report"));
+        assert!(!valid_webhook_url("not a url"));
+    }
+}